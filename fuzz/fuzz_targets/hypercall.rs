@@ -0,0 +1,25 @@
+//! Hypercalls of an aarch64 guest: SVC and HVC exits with the syndrome in
+//! ESR and the arguments in `x0`-`x30`.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+#[path = "../../guestaspace/src/aarch64/hvc.rs"]
+mod hvc;
+
+use hvc::GuestMessage;
+
+fuzz_target!(|input: (u64, [u64; 31])| {
+    let (esr, gprs) = input;
+    let ec = (esr >> 26) & 0x3F;
+    match GuestMessage::from_esr_and_regs(esr, &gprs) {
+        Ok(message) => {
+            assert!(ec == 0x15 || ec == 0x16);
+            if let GuestMessage::Unknown(func) = message {
+                assert_eq!(func, gprs[0]);
+            }
+        }
+        Err(_) => assert!(ec != 0x15 && ec != 0x16),
+    }
+});
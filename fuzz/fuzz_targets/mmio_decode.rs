@@ -0,0 +1,59 @@
+//! Trapped loads and stores of riscv64 and aarch64 guests: the instruction
+//! or transformed instruction of a guest page fault, and the syndrome and
+//! instruction of a data abort.
+
+#![no_main]
+
+extern crate alloc;
+
+use libfuzzer_sys::fuzz_target;
+
+#[allow(dead_code)]
+#[path = "../../guestaspace/src/exitlog.rs"]
+mod exitlog;
+#[allow(dead_code)]
+#[path = "../../guestaspace/src/gmem.rs"]
+mod gmem;
+#[allow(dead_code)]
+#[path = "../../guestaspace/src/gspace_host.rs"]
+mod gspace;
+#[allow(dead_code)]
+#[path = "../../guestaspace/src/migrate.rs"]
+mod migrate;
+#[allow(dead_code)]
+#[path = "../../guestaspace/src/devices/mmio.rs"]
+mod mmio;
+
+use mmio::MmioAccess;
+
+/// Checks what the run loops rely on before they touch a register.
+fn check(access: &MmioAccess, value: u64) {
+    assert!(matches!(access.width, 1 | 2 | 4 | 8));
+    assert!(access.reg < 32);
+    assert!(matches!(access.insn_len, 2 | 4));
+    assert!(!(access.is_write && access.sign_extend));
+    let mask = u64::MAX >> (64 - 8 * access.width);
+    assert_eq!(access.store_value(value) & !mask, 0);
+    access.load_value(value);
+}
+
+fuzz_target!(|input: (u32, u64, usize, u64)| {
+    let (insn, esr, addr, value) = input;
+    if let Some(access) = mmio::decode_riscv_insn(insn, addr) {
+        check(&access, value);
+    }
+    if let Some(access) = mmio::decode_htinst(insn as usize, addr) {
+        check(&access, value);
+    }
+    if let Some(access) = mmio::decode_esr(esr, addr) {
+        check(&access, value);
+    }
+    if let Some(decoded) = mmio::decode_a64(insn, addr) {
+        check(&decoded.access, value);
+        assert!(decoded.reg2.is_none_or(|reg| reg < 32));
+        assert!(decoded.writeback.is_none_or(|(base, _)| base < 32));
+        for access in decoded.accesses() {
+            check(&access, value);
+        }
+    }
+});
@@ -0,0 +1,32 @@
+//! SBI calls of a riscv64 guest: `a0`-`a7` at an ECALL from VS-mode.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+#[allow(dead_code, unused_imports)]
+#[path = "../../guestaspace/src/sbi/mod.rs"]
+mod sbi;
+
+use sbi::{HartMask, SbiMessage};
+
+fuzz_target!(|regs: [usize; 8]| {
+    // Any register state decodes to a message or an error the guest can be
+    // given.
+    match SbiMessage::from_regs(&regs) {
+        Ok(SbiMessage::RemoteFence(fence)) => {
+            let harts = fence.hart_mask();
+            harts.is_within(regs[7]);
+            harts.contains(regs[6]);
+        }
+        Ok(_) => {}
+        Err(e) => assert!(matches!(
+            e.code(),
+            sbi::SBI_ERR_NOT_SUPPORTED | sbi::SBI_ERR_INAVLID_PARAM
+        )),
+    }
+    let harts = HartMask::new(regs[0], regs[1]);
+    if harts.is_within(regs[2]) {
+        assert!(!harts.contains(regs[2]) || regs[1] == usize::MAX);
+    }
+});
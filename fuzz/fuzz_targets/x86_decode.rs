@@ -0,0 +1,31 @@
+//! Instructions behind x86_64 exits: MMIO and local APIC accesses, and the
+//! CR and INVLPG intercepts of shadow paging.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+#[path = "../../guestaspace/src/x86_64/decode.rs"]
+mod decode;
+
+/// Longest x86 instruction, as many bytes as the hypervisor fetches.
+const MAX_INSN_LEN: usize = 15;
+
+fuzz_target!(|input: (&[u8], u64, u64)| {
+    let (bytes, old, value) = input;
+    let bytes = &bytes[..bytes.len().min(MAX_INSN_LEN)];
+    if let Some(mov) = decode::decode_mov_mem(bytes) {
+        assert!(mov.len as usize <= bytes.len());
+        assert!(matches!(mov.width, 1 | 2 | 4 | 8));
+        assert!(mov.reg < 16 && mov.reg_width >= mov.width);
+        mov.load_value(old, value);
+        mov.store_value(value);
+    }
+    if let Some(mov) = decode::decode_mov_cr(bytes) {
+        assert!(mov.len as usize <= bytes.len());
+        assert!(mov.cr < 16 && mov.gpr < 16);
+    }
+    if let Some(len) = decode::invlpg_len(bytes) {
+        assert!(len as usize <= bytes.len());
+    }
+});
@@ -0,0 +1,151 @@
+//! Lazy FP/SIMD switching between the host and aarch64 guests.
+//!
+//! `CPACR_EL1.FPEN` traps FP/SIMD accesses from EL0 only, so the guest's
+//! first FP/SIMD instruction after an entry exits to the hypervisor (EC
+//! 0x07) while the hypervisor itself keeps access. The hypervisor then saves
+//! the host's registers, loads the guest's, stops trapping and resumes the
+//! guest at the same instruction. At the next exit, the guest's registers
+//! are saved and the host's restored. Guests that do not use FP/SIMD never
+//! pay for the switch.
+
+use core::arch::asm;
+
+/// `CPACR_EL1.FPEN`.
+const CPACR_FPEN: u64 = 3 << 20;
+/// `CPACR_EL1.FPEN` value that traps EL0 accesses only.
+const CPACR_FPEN_TRAP_EL0: u64 = 1 << 20;
+
+/// Makes FP/SIMD accesses from EL0 trap. Called once, before any guest
+/// runs.
+pub fn trap_guest_access() {
+    set_fpen(CPACR_FPEN_TRAP_EL0);
+}
+
+fn set_fpen(fpen: u64) {
+    unsafe {
+        asm!(
+            "mrs {tmp}, cpacr_el1",
+            "bic {tmp}, {tmp}, {mask}",
+            "orr {tmp}, {tmp}, {fpen}",
+            "msr cpacr_el1, {tmp}",
+            "isb",
+            tmp = out(reg) _,
+            mask = in(reg) CPACR_FPEN,
+            fpen = in(reg) fpen,
+        );
+    }
+}
+
+/// The FP/SIMD registers of the guest or of the host.
+#[derive(Default)]
+#[repr(C, align(16))]
+struct FpRegs {
+    v: [u128; 32],
+    fpcr: u64,
+    fpsr: u64,
+}
+
+impl FpRegs {
+    fn save(&mut self) {
+        unsafe {
+            asm!(
+                ".arch_extension fp",
+                ".arch_extension simd",
+                "stp q0, q1, [{0}, #0x000]",
+                "stp q2, q3, [{0}, #0x020]",
+                "stp q4, q5, [{0}, #0x040]",
+                "stp q6, q7, [{0}, #0x060]",
+                "stp q8, q9, [{0}, #0x080]",
+                "stp q10, q11, [{0}, #0x0a0]",
+                "stp q12, q13, [{0}, #0x0c0]",
+                "stp q14, q15, [{0}, #0x0e0]",
+                "stp q16, q17, [{0}, #0x100]",
+                "stp q18, q19, [{0}, #0x120]",
+                "stp q20, q21, [{0}, #0x140]",
+                "stp q22, q23, [{0}, #0x160]",
+                "stp q24, q25, [{0}, #0x180]",
+                "stp q26, q27, [{0}, #0x1a0]",
+                "stp q28, q29, [{0}, #0x1c0]",
+                "stp q30, q31, [{0}, #0x1e0]",
+                "mrs {1}, fpcr",
+                "mrs {2}, fpsr",
+                in(reg) self.v.as_mut_ptr(),
+                out(reg) self.fpcr,
+                out(reg) self.fpsr,
+            );
+        }
+    }
+
+    fn restore(&self) {
+        unsafe {
+            asm!(
+                ".arch_extension fp",
+                ".arch_extension simd",
+                "ldp q0, q1, [{0}, #0x000]",
+                "ldp q2, q3, [{0}, #0x020]",
+                "ldp q4, q5, [{0}, #0x040]",
+                "ldp q6, q7, [{0}, #0x060]",
+                "ldp q8, q9, [{0}, #0x080]",
+                "ldp q10, q11, [{0}, #0x0a0]",
+                "ldp q12, q13, [{0}, #0x0c0]",
+                "ldp q14, q15, [{0}, #0x0e0]",
+                "ldp q16, q17, [{0}, #0x100]",
+                "ldp q18, q19, [{0}, #0x120]",
+                "ldp q20, q21, [{0}, #0x140]",
+                "ldp q22, q23, [{0}, #0x160]",
+                "ldp q24, q25, [{0}, #0x180]",
+                "ldp q26, q27, [{0}, #0x1a0]",
+                "ldp q28, q29, [{0}, #0x1c0]",
+                "ldp q30, q31, [{0}, #0x1e0]",
+                "msr fpcr, {1}",
+                "msr fpsr, {2}",
+                in(reg) self.v.as_ptr(),
+                in(reg) self.fpcr,
+                in(reg) self.fpsr,
+            );
+        }
+    }
+}
+
+/// The FP/SIMD state of one guest, and the host's while the guest's is
+/// loaded.
+#[derive(Default)]
+pub struct GuestFp {
+    guest: FpRegs,
+    host: FpRegs,
+    /// The guest's registers are in the register file.
+    loaded: bool,
+}
+
+impl GuestFp {
+    /// Creates a guest state with all registers zero.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Loads the guest's registers after a trapped FP/SIMD access and stops
+    /// trapping; the guest then retries the instruction.
+    ///
+    /// If they are loaded already, another VM ran since the trap and put its
+    /// host registers (ours) back, but trapping is on again.
+    pub fn load_guest(&mut self) {
+        if !self.loaded {
+            self.host.save();
+            self.guest.restore();
+            self.loaded = true;
+        }
+        set_fpen(CPACR_FPEN);
+    }
+
+    /// Saves the guest's registers, if loaded, and gives the host back its
+    /// own after an exit. The guest's next FP/SIMD access traps again.
+    pub fn put_guest(&mut self) {
+        if !self.loaded {
+            return;
+        }
+        self.guest.save();
+        self.host.restore();
+        self.loaded = false;
+        set_fpen(CPACR_FPEN_TRAP_EL0);
+    }
+}
@@ -11,8 +11,8 @@ const ESR_EC_SVC64: u64 = 0x15;
 const ESR_EC_HVC64: u64 = 0x16;
 
 /// PSCI function IDs (SMC32 calling convention)
-const PSCI_SYSTEM_OFF: u64 = 0x84000008;
-const PSCI_SYSTEM_RESET: u64 = 0x84000009;
+const PSCI_SYSTEM_OFF: u64 = hvcall_abi::psci::SYSTEM_OFF;
+const PSCI_SYSTEM_RESET: u64 = hvcall_abi::psci::SYSTEM_RESET;
 
 /// Guest message parsed from registers on VM exit.
 #[derive(Clone, Copy, Debug)]
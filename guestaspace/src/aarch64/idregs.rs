@@ -0,0 +1,61 @@
+//! ID register reads of aarch64 guests.
+//!
+//! An MRS of an ID register at EL0 traps to EL1 (EC 0x18, with FEAT_IDST)
+//! instead of returning the host's value. The hypervisor reads the register
+//! itself and reports what the guest's CPU model allows
+//! ([`crate::cpumodel`]).
+
+use core::arch::asm;
+
+use crate::cpumodel::aarch64::*;
+
+/// `ESR_EL1.EC` of a trapped MSR, MRS or system instruction.
+pub const ESR_EC_SYSREG: u64 = 0x18;
+
+/// A trapped MSR or MRS, decoded from the ISS of `ESR_EL1`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SysregAccess {
+    /// The register, encoded as in [`sysreg`].
+    pub reg: u32,
+    /// The general-purpose register read or written (31: XZR).
+    pub rt: usize,
+    /// MRS (a read) rather than MSR.
+    pub read: bool,
+}
+
+impl SysregAccess {
+    /// Decodes the ISS of `esr`, which must have EC 0x18.
+    pub fn decode(esr: u64) -> Self {
+        let iss = esr as u32 & 0x1FF_FFFF;
+        let (op0, op2, op1) = (iss >> 20 & 3, iss >> 17 & 7, iss >> 14 & 7);
+        let (crn, crm) = (iss >> 10 & 0xF, iss >> 1 & 0xF);
+        Self {
+            reg: sysreg(op0, op1, crn, crm, op2),
+            rt: (iss >> 5 & 0x1F) as usize,
+            read: iss & 1 != 0,
+        }
+    }
+}
+
+/// Reads the host's value of ID register `reg`; zero for registers the
+/// guest's CPU model does not report.
+pub fn host_value(reg: u32) -> u64 {
+    let value: u64;
+    unsafe {
+        match reg {
+            MIDR_EL1 => asm!("mrs {}, midr_el1", out(reg) value),
+            REVIDR_EL1 => asm!("mrs {}, revidr_el1", out(reg) value),
+            ID_AA64PFR0_EL1 => asm!("mrs {}, id_aa64pfr0_el1", out(reg) value),
+            ID_AA64PFR1_EL1 => asm!("mrs {}, id_aa64pfr1_el1", out(reg) value),
+            ID_AA64DFR0_EL1 => asm!("mrs {}, id_aa64dfr0_el1", out(reg) value),
+            ID_AA64ISAR0_EL1 => asm!("mrs {}, id_aa64isar0_el1", out(reg) value),
+            ID_AA64ISAR1_EL1 => asm!("mrs {}, id_aa64isar1_el1", out(reg) value),
+            ID_AA64ISAR2_EL1 => asm!("mrs {}, s3_0_c0_c6_2", out(reg) value),
+            ID_AA64MMFR0_EL1 => asm!("mrs {}, id_aa64mmfr0_el1", out(reg) value),
+            ID_AA64MMFR1_EL1 => asm!("mrs {}, id_aa64mmfr1_el1", out(reg) value),
+            ID_AA64MMFR2_EL1 => asm!("mrs {}, s3_0_c0_c7_2", out(reg) value),
+            _ => value = 0,
+        }
+    }
+    value
+}
@@ -0,0 +1,6 @@
+pub mod fpu;
+pub mod hvc;
+pub mod idregs;
+pub mod regs;
+pub mod vcpu;
+pub mod vtimer;
@@ -28,6 +28,11 @@ impl Default for HostState {
     }
 }
 
+/// Interrupt line of the guest's virtual timer (GIC INTID of its PPI).
+pub const IRQ_VTIMER: u32 = 27;
+/// Interrupt line the device models assert (GIC INTID of the first SPI).
+pub const IRQ_DEVICES: u32 = 32;
+
 /// Guest (EL0) state.
 #[repr(C)]
 pub struct GuestState {
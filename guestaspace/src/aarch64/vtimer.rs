@@ -0,0 +1,152 @@
+//! Generic timer of aarch64 guests.
+//!
+//! The hypervisor runs at EL1, so `CNTVOFF_EL2` and `CNTHCTL_EL2` are out of
+//! reach. Instead, `CNTKCTL_EL1` makes the guest's EL0 accesses to the
+//! counters and the virtual timer trap (EC 0x18), and they are emulated
+//! here, while the host keeps the EL1 physical timer for its own tick. The
+//! guest's counter is the host's virtual counter minus a per-VM offset
+//! taken when the VM boots, so it starts at zero; both `CNTVCT_EL0` and
+//! `CNTPCT_EL0` read it.
+//!
+//! The guest's `CNTV_CTL_EL0` and `CNTV_CVAL_EL0` only live here: the
+//! hardware timer is not used for guests. A guest at EL0 takes no
+//! interrupts, so the timer interrupt is not injected. The guest waits for
+//! its timer with WFI and polls `CNTV_CTL_EL0.ISTATUS`: the hypervisor idles
+//! the guest until the timer condition is met.
+
+use core::arch::asm;
+use core::time::Duration;
+
+use axhal::time::ticks_to_nanos;
+
+use crate::cpumodel::aarch64::sysreg;
+
+/// `CNTKCTL_EL1.EL0PCTEN`: EL0 reads of `CNTPCT_EL0`.
+const CNTKCTL_EL0PCTEN: u64 = 1 << 0;
+/// `CNTKCTL_EL1.EL0VCTEN`: EL0 reads of `CNTVCT_EL0`. With `EL0PCTEN`
+/// clear as well, EL0 reads of `CNTFRQ_EL0` trap too.
+const CNTKCTL_EL0VCTEN: u64 = 1 << 1;
+/// `CNTKCTL_EL1.EL0VTEN`: EL0 access to the `CNTV_*` registers.
+const CNTKCTL_EL0VTEN: u64 = 1 << 8;
+/// `CNTKCTL_EL1.EL0PTEN`: EL0 access to the `CNTP_*` registers.
+const CNTKCTL_EL0PTEN: u64 = 1 << 9;
+/// `CNTV_CTL_EL0.ENABLE`.
+const CNTV_CTL_ENABLE: u64 = 1 << 0;
+/// `CNTV_CTL_EL0.IMASK`.
+const CNTV_CTL_IMASK: u64 = 1 << 1;
+/// `CNTV_CTL_EL0.ISTATUS`: the timer condition is met (read-only).
+const CNTV_CTL_ISTATUS: u64 = 1 << 2;
+
+const CNTFRQ_EL0: u32 = sysreg(3, 3, 14, 0, 0);
+const CNTPCT_EL0: u32 = sysreg(3, 3, 14, 0, 1);
+const CNTVCT_EL0: u32 = sysreg(3, 3, 14, 0, 2);
+const CNTV_TVAL_EL0: u32 = sysreg(3, 3, 14, 3, 0);
+const CNTV_CTL_EL0: u32 = sysreg(3, 3, 14, 3, 1);
+const CNTV_CVAL_EL0: u32 = sysreg(3, 3, 14, 3, 2);
+
+/// Makes EL0 accesses to the counters and timers trap. Called once, before
+/// any guest runs.
+pub fn trap_guest_access() {
+    unsafe {
+        asm!(
+            "mrs {tmp}, cntkctl_el1",
+            "bic {tmp}, {tmp}, {bits}",
+            "msr cntkctl_el1, {tmp}",
+            "isb",
+            tmp = out(reg) _,
+            bits = in(reg) CNTKCTL_EL0PCTEN | CNTKCTL_EL0VCTEN | CNTKCTL_EL0VTEN | CNTKCTL_EL0PTEN,
+        );
+    }
+}
+
+/// Reads the host's virtual counter.
+fn counter() -> u64 {
+    let cnt: u64;
+    unsafe { asm!("isb", "mrs {}, cntvct_el0", out(reg) cnt) };
+    cnt
+}
+
+/// Reads the counter frequency.
+fn frequency() -> u64 {
+    let freq: u64;
+    unsafe { asm!("mrs {}, cntfrq_el0", out(reg) freq) };
+    freq
+}
+
+/// The counter and virtual timer of one guest.
+pub struct GuestTimer {
+    /// The host counter value at which the guest's counter was zero.
+    offset: u64,
+    /// `CNTV_CTL_EL0` without `ISTATUS`.
+    ctl: u64,
+    /// `CNTV_CVAL_EL0`, in guest counter ticks.
+    cval: u64,
+}
+
+impl Default for GuestTimer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl GuestTimer {
+    /// Creates a disabled timer, with the guest's counter starting at zero
+    /// now.
+    pub fn new() -> Self {
+        Self {
+            offset: counter(),
+            ctl: 0,
+            cval: 0,
+        }
+    }
+
+    /// Returns the guest's counter.
+    pub fn now(&self) -> u64 {
+        counter().wrapping_sub(self.offset)
+    }
+
+    /// Emulates an MRS of timer register `reg`; `None` if it is not one.
+    pub fn read(&self, reg: u32) -> Option<u64> {
+        Some(match reg {
+            CNTFRQ_EL0 => frequency(),
+            CNTPCT_EL0 | CNTVCT_EL0 => self.now(),
+            CNTV_CTL_EL0 if self.pending() => self.ctl | CNTV_CTL_ISTATUS,
+            CNTV_CTL_EL0 => self.ctl,
+            CNTV_CVAL_EL0 => self.cval,
+            // The low 32 bits of `cval - now`, sign-extended.
+            CNTV_TVAL_EL0 => self.cval.wrapping_sub(self.now()) as i32 as i64 as u64,
+            _ => return None,
+        })
+    }
+
+    /// Emulates an MSR of `value` to timer register `reg`; `false` if it is
+    /// not one the guest may write.
+    pub fn write(&mut self, reg: u32, value: u64) -> bool {
+        match reg {
+            CNTV_CTL_EL0 => self.ctl = value & (CNTV_CTL_ENABLE | CNTV_CTL_IMASK),
+            CNTV_CVAL_EL0 => self.cval = value,
+            CNTV_TVAL_EL0 => self.cval = self.now().wrapping_add(value as i32 as i64 as u64),
+            _ => return false,
+        }
+        true
+    }
+
+    /// Returns the guest counter value at which the timer fires, if it is
+    /// enabled.
+    pub fn deadline(&self) -> Option<u64> {
+        (self.ctl & CNTV_CTL_ENABLE != 0).then_some(self.cval)
+    }
+
+    /// Checks whether the timer condition is met (`ISTATUS`).
+    pub fn pending(&self) -> bool {
+        self.deadline().is_some_and(|cval| self.now() >= cval)
+    }
+
+    /// Returns how long it takes until the timer fires, if it is enabled.
+    pub fn time_until(&self) -> Option<Duration> {
+        let cval = self.deadline()?;
+        Some(Duration::from_nanos(ticks_to_nanos(
+            cval.saturating_sub(self.now()),
+        )))
+    }
+}
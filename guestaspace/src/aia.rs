@@ -0,0 +1,195 @@
+//! Direct interrupt delivery to riscv64 guests through AIA guest interrupt
+//! files.
+//!
+//! On a host whose harts have an IMSIC with guest interrupt files (GEILEN,
+//! the number of writable `hgeie` bits, is not zero), a VM with a single
+//! hart gets a file of the host hart its task runs on, and the task is
+//! pinned to that hart:
+//!
+//! - `hstatus.VGEIN` selects the file, so the guest's `siselect`/`sireg`
+//!   and `stopei` reach it without exits, and it raises the guest's
+//!   external interrupt in hardware instead of `hvip`;
+//! - the file's MMIO page is mapped at the guest's IMSIC address, so the
+//!   guest sends itself MSIs without exits as well;
+//! - the guest's wired device interrupts go through an emulated APLIC in
+//!   MSI mode ([`crate::devices::aplic`]), whose MSIs the hypervisor sets
+//!   pending in the file through `vsiselect`/`vsireg`;
+//! - `hgeip` tells whether the file has an interrupt for the guest, which
+//!   wakes the hart from WFI.
+//!
+//! Other hosts and VMs keep the PLIC and `hvip` injection. The layout of
+//! the host's IMSIC is that of the QEMU virt machine (`aia=aplic-imsic`).
+
+use alloc::vec::Vec;
+
+use axhal::mem::PhysAddr;
+use axstd::sync::Mutex;
+
+use crate::csrs::{CSR, RiscvCsrTrait};
+use crate::devices::aplic::{Aplic, Msi};
+
+/// S-level IMSIC of the QEMU virt machine: the supervisor file of hart `h`
+/// is at `HOST_IMSIC_BASE + h * stride`, followed by its guest files.
+const HOST_IMSIC_BASE: usize = 0x2800_0000;
+const IMSIC_PAGE: usize = 0x1000;
+
+/// Interrupt file registers, selected with `vsiselect`.
+const ISELECT_EIDELIVERY: usize = 0x70;
+const ISELECT_EITHRESHOLD: usize = 0x72;
+const ISELECT_EIP0: usize = 0x80;
+const ISELECT_EIE0: usize = 0xC0;
+/// Interrupt identities a file has at most: `eip0`-`eip6` and
+/// `eie0`-`eie6` on RV64 (even numbers only).
+const MAX_IDS: usize = 256;
+
+const HSTATUS_VGEIN_SHIFT: usize = 12;
+const HSTATUS_VGEIN_MASK: usize = 0x3F << HSTATUS_VGEIN_SHIFT;
+
+/// Guest files in use, as (host hart, file number).
+static FILES: Mutex<Vec<(usize, usize)>> = Mutex::new(Vec::new());
+
+/// Number of guest interrupt files of each host hart.
+fn geilen() -> usize {
+    // hgeie bits 1 to GEILEN are writable. No task switch may observe the
+    // probe value.
+    let irqs_were_enabled = axhal::asm::irqs_enabled();
+    axhal::asm::disable_irqs();
+    let saved = CSR.hgeie.atomic_replace(!0);
+    let probed = CSR.hgeie.atomic_replace(saved);
+    if irqs_were_enabled {
+        axhal::asm::enable_irqs();
+    }
+    (probed >> 1).count_ones() as usize
+}
+
+/// A guest interrupt file of a host hart, owned by one VM and released on
+/// drop.
+#[derive(Debug)]
+pub struct GuestFile {
+    hart: usize,
+    number: usize,
+    geilen: usize,
+}
+
+impl GuestFile {
+    /// Allocates the lowest free file of the current host hart and pins the
+    /// calling task to the hart. `None` on hosts without guest files or
+    /// once all are in use.
+    pub fn alloc() -> Option<Self> {
+        let geilen = geilen();
+        let mut used = FILES.lock();
+        let hart = axhal::percpu::this_cpu_id();
+        let number = (1..=geilen).find(|&n| !used.contains(&(hart, n)))?;
+        if !axtask::set_current_affinity(axtask::AxCpuMask::one_shot(hart)) {
+            return None;
+        }
+        used.push((hart, number));
+        drop(used);
+
+        let file = Self {
+            hart,
+            number,
+            geilen,
+        };
+        // Leave nothing from the file's previous owner.
+        file.with_file(|| {
+            for iselect in [ISELECT_EIDELIVERY, ISELECT_EITHRESHOLD] {
+                CSR.vsiselect.write_value(iselect);
+                CSR.vsireg.write_value(0);
+            }
+            for reg in (0..MAX_IDS / 32).step_by(2) {
+                for base in [ISELECT_EIP0, ISELECT_EIE0] {
+                    CSR.vsiselect.write_value(base + reg);
+                    CSR.vsireg.write_value(0);
+                }
+            }
+        });
+        Some(file)
+    }
+
+    /// The value of `hstatus.VGEIN` that selects the file.
+    pub fn vgein(&self) -> usize {
+        self.number << HSTATUS_VGEIN_SHIFT
+    }
+
+    /// Host physical address of the file's MMIO page.
+    pub fn page(&self) -> PhysAddr {
+        let stride = (self.geilen + 1).next_power_of_two() * IMSIC_PAGE;
+        PhysAddr::from(HOST_IMSIC_BASE + self.hart * stride + self.number * IMSIC_PAGE)
+    }
+
+    /// Sets external interrupt `eiid` pending in the file.
+    pub fn inject(&self, eiid: u32) {
+        let eiid = eiid as usize;
+        if eiid == 0 || eiid >= MAX_IDS {
+            return;
+        }
+        self.with_file(|| {
+            // eipN holds identities 32 * N to 32 * N + 63 on RV64.
+            CSR.vsiselect.write_value(ISELECT_EIP0 + eiid / 64 * 2);
+            CSR.vsireg.read_and_set_bits(1 << (eiid % 64));
+        });
+    }
+
+    /// Checks whether the file has an interrupt for the guest: pending,
+    /// enabled and above its threshold.
+    pub fn pending(&self) -> bool {
+        CSR.hgeip.get_value() >> self.number & 1 != 0
+    }
+
+    /// Runs `f` with `vsiselect`/`vsireg` reaching the file, leaving the
+    /// guest's `vsiselect` as it was.
+    fn with_file(&self, f: impl FnOnce()) {
+        let irqs_were_enabled = axhal::asm::irqs_enabled();
+        axhal::asm::disable_irqs();
+        let hstatus = CSR.hstatus.get_value();
+        CSR.hstatus
+            .write_value(hstatus & !HSTATUS_VGEIN_MASK | self.vgein());
+        let vsiselect = CSR.vsiselect.get_value();
+        f();
+        CSR.vsiselect.write_value(vsiselect);
+        CSR.hstatus.write_value(hstatus);
+        if irqs_were_enabled {
+            axhal::asm::enable_irqs();
+        }
+    }
+}
+
+impl Drop for GuestFile {
+    fn drop(&mut self) {
+        FILES
+            .lock()
+            .retain(|&file| file != (self.hart, self.number));
+    }
+}
+
+/// The AIA of a VM: its APLIC and the guest file that the APLIC's MSIs go
+/// to.
+pub struct GuestAia {
+    pub aplic: Aplic,
+    pub file: GuestFile,
+}
+
+impl GuestAia {
+    pub fn new(aplic_base: usize, file: GuestFile) -> Self {
+        Self {
+            aplic: Aplic::new(aplic_base),
+            file,
+        }
+    }
+
+    /// Drives the APLIC sources with the device interrupt lines, as
+    /// (source, level), and delivers the MSIs it forwards to the hart.
+    pub fn update(&mut self, lines: impl Iterator<Item = (usize, bool)>) {
+        for (irq, level) in lines {
+            self.aplic.set_input(irq, level);
+        }
+        let file = &self.file;
+        self.aplic.forward(|msi: Msi| {
+            // The VM's only hart has the only interrupt file.
+            if msi.hart == 0 && msi.guest == 0 {
+                file.inject(msi.eiid);
+            }
+        });
+    }
+}
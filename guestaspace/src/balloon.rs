@@ -0,0 +1,97 @@
+//! Memory ballooning: a cooperative guest gives unused RAM back to the host.
+//!
+//! The guest passes a page-aligned guest physical range it no longer uses
+//! to the BALLOON_RELEASE hypercall; the frames backing it are unmapped and
+//! freed ([`GuestSpace::discard`]), and the range reads as zeros (or as the
+//! image, for copy-on-write pages) when the guest touches it again. With
+//! guest RAM backed on demand (a memory limit, see `mem=`), this lets more
+//! VMs share the host's memory than their RAM sizes add up to.
+//!
+//! The call is SBI extension [`SBI_EXT_HYPERVISOR`] function
+//! [`SBI_FID_BALLOON_RELEASE`] with `a0`/`a1` = start/size on riscv64, and
+//! function [`HYPERCALL_BALLOON_RELEASE`] (`x8` with `x0`/`x1` on aarch64,
+//! `RAX[7:0]` with `RBX`/`RCX` on x86_64) elsewhere. It returns the bytes
+//! freed.
+//!
+//! For a VM whose working set is sampled (`wss=`), BALLOON_TARGET
+//! ([`SBI_FID_BALLOON_TARGET`], [`HYPERCALL_BALLOON_TARGET`]) returns the
+//! bytes of backed RAM beyond the working set that the guest should
+//! release (see `WorkingSet::balloon_target`); 0 without an estimate.
+//!
+//! [`GuestSpace::discard`]: crate::gspace::GuestSpace::discard
+//! [`SBI_EXT_HYPERVISOR`]: crate::boot::SBI_EXT_HYPERVISOR
+
+#![allow(dead_code)]
+
+use axerrno::AxResult;
+use memory_addr::PAGE_SIZE_4K;
+
+use crate::dirty::DirtyLog;
+use crate::gspace::GuestSpace;
+
+/// Hypercall function ID (aarch64 `x8`, x86_64 `RAX[7:0]`) of
+/// BALLOON_RELEASE.
+pub const HYPERCALL_BALLOON_RELEASE: u64 = hvcall_abi::BALLOON_RELEASE;
+/// Function ID of BALLOON_RELEASE in the riscv64 hypervisor SBI extension.
+pub const SBI_FID_BALLOON_RELEASE: usize = hvcall_abi::sbi::FID_BALLOON_RELEASE;
+/// Hypercall function ID (aarch64 `x8`, x86_64 `RAX[7:0]`) of
+/// BALLOON_TARGET.
+pub const HYPERCALL_BALLOON_TARGET: u64 = hvcall_abi::BALLOON_TARGET;
+/// Function ID of BALLOON_TARGET in the riscv64 hypervisor SBI extension.
+pub const SBI_FID_BALLOON_TARGET: usize = hvcall_abi::sbi::FID_BALLOON_TARGET;
+
+/// The balloon of one VM.
+pub struct Balloon {
+    vm_id: usize,
+    /// Bytes of RAM frames given back to the host.
+    released: usize,
+    /// Calls served.
+    calls: usize,
+}
+
+impl Balloon {
+    /// Creates an empty balloon for VM `vm_id`.
+    pub fn new(vm_id: usize) -> Self {
+        Self {
+            vm_id,
+            released: 0,
+            calls: 0,
+        }
+    }
+
+    /// Serves BALLOON_RELEASE for `[gpa, gpa + size)`: frees its frames and
+    /// marks its pages dirty in `dirty` (their contents changed). Returns
+    /// the bytes freed; the caller flushes the guest TLB for the range.
+    pub fn release(
+        &mut self,
+        space: &mut GuestSpace,
+        dirty: &mut DirtyLog,
+        gpa: usize,
+        size: usize,
+    ) -> AxResult<usize> {
+        let freed = space.discard(gpa.into(), size)?;
+        for page in (gpa..gpa + size).step_by(PAGE_SIZE_4K) {
+            dirty.record_write(page);
+        }
+        self.released += freed;
+        self.calls += 1;
+        Ok(freed)
+    }
+
+    /// Returns the bytes given back to the host so far.
+    pub fn released(&self) -> usize {
+        self.released
+    }
+
+    /// Prints how much memory the guest gave back, if it used the balloon.
+    pub fn report(&self) {
+        if self.calls > 0 {
+            vm_println!(
+                self.vm_id,
+                "Balloon: {} KB given back to the host in {} calls",
+                self.released / 1024,
+                self.calls
+            );
+        }
+    }
+}
@@ -0,0 +1,245 @@
+//! Boot protocols of guest kernels.
+//!
+//! A guest image is either a flat binary entered at its load address (the
+//! tutorial payloads) or a Linux kernel image, recognized by its header.
+//! For Linux the header decides where the kernel goes in guest RAM, and the
+//! boot CPU starts with the registers the Linux boot protocol of the
+//! architecture prescribes.
+
+#![allow(dead_code)]
+
+use axerrno::AxResult;
+
+use crate::gmem::GuestMemory;
+#[cfg(any(target_arch = "riscv64", target_arch = "x86_64"))]
+use crate::gspace::SharedPages;
+
+/// Hypercall function ID (aarch64 `x8`, x86_64 `RAX[7:0]`) that copies the
+/// kernel command line to a guest buffer, see [`copy_cmdline`].
+pub const HYPERCALL_GET_CMDLINE: u64 = hvcall_abi::GET_CMDLINE;
+/// SBI extension ID of the hypervisor's own calls on riscv64, from the
+/// firmware-specific range.
+pub const SBI_EXT_HYPERVISOR: usize = hvcall_abi::sbi::EXT_HYPERVISOR;
+/// Function ID of GET_CMDLINE in the riscv64 hypervisor SBI extension.
+pub const SBI_FID_GET_CMDLINE: usize = hvcall_abi::sbi::FID_GET_CMDLINE;
+
+/// Serves GET_CMDLINE for paravirt guests: copies at most `len` bytes of
+/// `cmdline` (without terminating NUL) to guest physical address `buf` and
+/// returns the full length, so a guest can retry with a larger buffer.
+pub fn copy_cmdline(
+    mem: &mut impl GuestMemory,
+    cmdline: &str,
+    buf: usize,
+    len: usize,
+) -> AxResult<usize> {
+    let n = cmdline.len().min(len);
+    mem.copy_to_guest(buf, &cmdline.as_bytes()[..n])?;
+    Ok(cmdline.len())
+}
+
+/// Largest device tree passed to a guest.
+pub const FDT_MAX_SIZE: usize = 0x1_0000;
+
+/// Guest physical address of the device tree: the last 2M of guest RAM,
+/// away from where kernels place themselves and their early allocations.
+pub fn fdt_gpa(ram_start: usize, ram_size: usize) -> usize {
+    ram_start + ram_size - 0x20_0000
+}
+
+/// Guest physical address of an initrd of `size` bytes placed as high as
+/// possible below `limit`, page aligned, or `None` if it would reach below
+/// `floor` (the end of the kernel).
+pub fn initrd_gpa(floor: usize, limit: usize, size: usize) -> Option<usize> {
+    let start = limit.checked_sub(size)? & !0xFFF;
+    (start >= floor).then_some(start)
+}
+
+/// The header fields of a Linux kernel `Image` that matter for loading it.
+#[derive(Clone, Copy, Debug)]
+pub struct LinuxImage {
+    /// Offset of the kernel from the 2M aligned start of RAM.
+    pub text_offset: usize,
+    /// Size of the kernel in memory, including its BSS.
+    pub image_size: usize,
+}
+
+/// Parses the header of a Linux riscv64 `Image` (Documentation/arch/riscv/
+/// boot-image-header.rst).
+///
+/// Returns `None` for images without the "RSC\x05" magic.
+#[cfg(target_arch = "riscv64")]
+pub fn riscv_image_header(image: &SharedPages) -> Option<LinuxImage> {
+    const MAGIC2: u32 = u32::from_le_bytes(*b"RSC\x05");
+    let mut hdr = [0u8; 64];
+    image.read(0, &mut hdr);
+    let field = |offset: usize| u64::from_le_bytes(hdr[offset..offset + 8].try_into().unwrap());
+    if u32::from_le_bytes(hdr[56..60].try_into().unwrap()) != MAGIC2 {
+        return None;
+    }
+    Some(LinuxImage {
+        text_offset: field(8) as usize,
+        // Zero in headers older than version 0.2: assume the file size.
+        image_size: match field(16) as usize {
+            0 => image.size(),
+            size => size,
+        },
+    })
+}
+
+impl LinuxImage {
+    /// Returns the guest physical address the kernel is loaded at for RAM
+    /// starting at `ram_start`, or `None` if the kernel does not fit below
+    /// `limit` (header fields that overflow the address space included).
+    pub fn load_address(&self, ram_start: usize, limit: usize) -> Option<usize> {
+        let base = ram_start
+            .next_multiple_of(0x20_0000)
+            .checked_add(self.text_offset)?;
+        (base.checked_add(self.image_size)? <= limit).then_some(base)
+    }
+}
+
+/// Kernel command line passed to x86 Linux guests without a configured one.
+pub const DEFAULT_CMDLINE: &str = "console=ttyS0 earlyprintk=serial";
+
+/// A Linux x86 `bzImage`, as described by its setup header
+/// (Documentation/arch/x86/boot.rst).
+#[cfg(target_arch = "x86_64")]
+#[derive(Clone, Debug)]
+pub struct BzImage {
+    /// Boot protocol version.
+    pub version: u16,
+    /// File offset of the protected-mode kernel.
+    pub kernel_offset: usize,
+    /// Memory the kernel needs from its load address until it runs its own
+    /// memory management (decompression buffer included).
+    pub init_size: usize,
+    /// Largest address the initrd may end at.
+    pub initrd_addr_max: usize,
+    /// Largest command line length, without the terminating NUL.
+    pub cmdline_size: usize,
+    /// The setup header (offset 0x1F1 to its end), copied into `boot_params`.
+    setup_header: alloc::vec::Vec<u8>,
+}
+
+/// Offsets in `struct boot_params` (the "zero page").
+#[cfg(target_arch = "x86_64")]
+mod zero_page {
+    pub const E820_ENTRIES: usize = 0x1E8;
+    pub const SETUP_HEADER: usize = 0x1F1;
+    pub const SETUP_SECTS: usize = 0x1F1;
+    pub const VID_MODE: usize = 0x1FA;
+    pub const JUMP: usize = 0x200;
+    pub const HEADER: usize = 0x202;
+    pub const VERSION: usize = 0x206;
+    pub const TYPE_OF_LOADER: usize = 0x210;
+    pub const LOADFLAGS: usize = 0x211;
+    pub const RAMDISK_IMAGE: usize = 0x218;
+    pub const RAMDISK_SIZE: usize = 0x21C;
+    pub const CMD_LINE_PTR: usize = 0x228;
+    pub const INITRD_ADDR_MAX: usize = 0x22C;
+    pub const CMDLINE_SIZE: usize = 0x238;
+    pub const INIT_SIZE: usize = 0x260;
+    pub const E820_TABLE: usize = 0x2D0;
+    pub const E820_MAX: usize = 128;
+    pub const SIZE: usize = 0x1000;
+}
+
+/// `loadflags`: the protected-mode code is loaded at 0x100000.
+#[cfg(target_arch = "x86_64")]
+const LOADED_HIGH: u8 = 1;
+
+/// e820 type of usable RAM.
+pub const E820_RAM: u32 = 1;
+/// e820 type of reserved memory.
+pub const E820_RESERVED: u32 = 2;
+
+/// One entry of the e820 memory map.
+#[derive(Clone, Copy, Debug)]
+pub struct E820Entry {
+    /// Start address.
+    pub addr: u64,
+    /// Size in bytes.
+    pub size: u64,
+    /// [`E820_RAM`] or [`E820_RESERVED`].
+    pub kind: u32,
+}
+
+/// Parses the setup header of a Linux x86 `bzImage`.
+///
+/// Returns `None` for other images and for kernels older than boot
+/// protocol 2.06 or not loaded high (zImage).
+#[cfg(target_arch = "x86_64")]
+pub fn bzimage_header(image: &SharedPages) -> Option<BzImage> {
+    use zero_page::*;
+
+    let mut hdr = [0u8; 0x270];
+    image.read(0, &mut hdr);
+    let u16_at = |off: usize| u16::from_le_bytes(hdr[off..off + 2].try_into().unwrap());
+    let u32_at = |off: usize| u32::from_le_bytes(hdr[off..off + 4].try_into().unwrap());
+    if &hdr[HEADER..HEADER + 4] != b"HdrS" || hdr[0x1FE..0x200] != [0x55, 0xAA] {
+        return None;
+    }
+    let version = u16_at(VERSION);
+    if version < 0x0206 || hdr[LOADFLAGS] & LOADED_HIGH == 0 {
+        return None;
+    }
+    // The header ends where the jump at 0x200 lands.
+    let header_end = (JUMP + 2 + hdr[JUMP + 1] as usize).min(hdr.len());
+    let setup_sects = match hdr[SETUP_SECTS] {
+        0 => 4,
+        n => n as usize,
+    };
+    Some(BzImage {
+        version,
+        kernel_offset: (setup_sects + 1) * 512,
+        init_size: if version >= 0x020A {
+            u32_at(INIT_SIZE) as usize
+        } else {
+            0
+        },
+        initrd_addr_max: u32_at(INITRD_ADDR_MAX) as usize,
+        cmdline_size: u32_at(CMDLINE_SIZE) as usize,
+        setup_header: hdr[SETUP_HEADER..header_end].to_vec(),
+    })
+}
+
+#[cfg(target_arch = "x86_64")]
+impl BzImage {
+    /// Guest physical address of the protected-mode kernel, which is also
+    /// its 32-bit entry point.
+    pub const LOAD_ADDRESS: usize = 0x10_0000;
+
+    /// Builds the `boot_params` page for the kernel: the setup header with
+    /// the loader fields filled in, the command line at `cmdline_gpa`, the
+    /// initrd at `[start, end)` if there is one, and the e820 memory map.
+    pub fn boot_params(
+        &self,
+        cmdline_gpa: usize,
+        initrd: Option<(usize, usize)>,
+        e820: &[E820Entry],
+    ) -> alloc::vec::Vec<u8> {
+        use zero_page::*;
+
+        let mut bp = alloc::vec![0u8; SIZE];
+        bp[SETUP_HEADER..SETUP_HEADER + self.setup_header.len()]
+            .copy_from_slice(&self.setup_header);
+        bp[VID_MODE..VID_MODE + 2].copy_from_slice(&0xFFFFu16.to_le_bytes()); // "normal"
+        bp[TYPE_OF_LOADER] = 0xFF; // undefined boot loader
+        bp[CMD_LINE_PTR..CMD_LINE_PTR + 4].copy_from_slice(&(cmdline_gpa as u32).to_le_bytes());
+        if let Some((start, end)) = initrd {
+            bp[RAMDISK_IMAGE..RAMDISK_IMAGE + 4].copy_from_slice(&(start as u32).to_le_bytes());
+            bp[RAMDISK_SIZE..RAMDISK_SIZE + 4]
+                .copy_from_slice(&((end - start) as u32).to_le_bytes());
+        }
+
+        let n = e820.len().min(E820_MAX);
+        bp[E820_ENTRIES] = n as u8;
+        for (i, e) in e820[..n].iter().enumerate() {
+            let off = E820_TABLE + 20 * i;
+            bp[off..off + 8].copy_from_slice(&e.addr.to_le_bytes());
+            bp[off + 8..off + 16].copy_from_slice(&e.size.to_le_bytes());
+            bp[off + 16..off + 20].copy_from_slice(&e.kind.to_le_bytes());
+        }
+        bp
+    }
+}
@@ -0,0 +1,319 @@
+//! Guest VM configuration.
+//!
+//! The list of guests is read from [`VM_CONFIG_PATH`] on the root
+//! filesystem, one guest per line:
+//!
+//! ```text
+//! image [disk] [cpus=N] [mem=SIZE] [shares=N] [initrd=PATH] [trace=SPEC] [tsc=MODE] [harden=on] [fault_around=N] [serial=on] [pin=CPU] [watchdog=SECS] [record=PATH] [sha256=HEX] [wss=MS] [migrate=PATH[@SECS]] [incoming=PATH] [cmdline=ARGS...]
+//! ```
+//!
+//! `image` is the guest image path and `disk` the path of a disk image
+//! served to the guest as a virtio-blk device. `cpus=` sets the number of
+//! vCPUs (riscv64 guests bring up the secondary ones through SBI HSM; other
+//! architectures run one vCPU). `mem=` caps the host memory the guest's RAM
+//! may take (bytes, or with a `K`, `M` or `G` suffix); RAM is then backed on
+//! first access and a guest that touches more is shut down. `shares=` is the
+//! VM's CPU share relative to the default of [`DEFAULT_CPU_SHARES`]: VMs
+//! competing for a host CPU get time in proportion to their shares.
+//! `initrd=` names an initial ramdisk loaded into guest memory for Linux
+//! guests. `trace=` sets how the VM's exits are traced (see
+//! [`TraceConfig::parse`]). `tsc=` sets how an x86_64 guest's TSC is
+//! virtualized (see [`TscMode::parse`]). `harden=on` maps the guest's
+//! memory W^X and turns on the host's protections against user mappings
+//! (see the `harden` module). `fault_around=` sets how many 4K pages
+//! (default [`DEFAULT_FAULT_AROUND`], 1 to disable) are backed together
+//! when a fault in lazily backed RAM cannot take a huge page. `serial=on`
+//! gives the VM the host's secondary serial port (see the `serial`
+//! module). `pin=` runs the VM's task on that host CPU (see the `hostcpu`
+//! module). `watchdog=` stops a guest that shows no sign of life for
+//! that many seconds (see [`WatchdogConfig::parse`]). `record=` writes
+//! every exit of the VM to a trace file at that path (see the `exitlog`
+//! module). `sha256=` only boots the VM if its image has that SHA-256
+//! digest (see the `measure` module). `wss=` samples the guest's working
+//! set every that many milliseconds (see the `wss` module). `migrate=`
+//! migrates the VM out through a stream at that path, and `incoming=`
+//! resumes the VM from one instead of booting it (see the `migrate`
+//! module). `cmdline=` takes the rest of the line as the guest's kernel
+//! command line. Blank lines and lines starting with `#` are ignored. The
+//! line number (counting guests only) becomes the VM id.
+//!
+//! Without a config file, a boot specification in `/boot` selects a
+//! single guest, in the style of the `--kernel`/`--initrd`/`--append`
+//! options of kvmtool or crosvm (`cargo xtask run --kernel ...` writes it):
+//! the image [`BOOT_KERNEL_PATH`] and, if present, the initrd
+//! [`BOOT_INITRD_PATH`], the command line in [`BOOT_CMDLINE_PATH`] and the
+//! virtio-blk disk [`BOOT_DISK_PATH`]. Without either, a single VM running
+//! [`DEFAULT_GUEST_IMAGE`] is created.
+
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use axstd::fs::File;
+use axstd::io::Read;
+
+use crate::migrate::MigrateConfig;
+use crate::trace::TraceConfig;
+use crate::watchdog::WatchdogConfig;
+use crate::wss::WorkingSet;
+
+/// Path of the VM list on the root filesystem.
+pub const VM_CONFIG_PATH: &str = "/etc/vms.conf";
+
+/// Guest image of the boot specification, used when no VM list is present.
+pub const BOOT_KERNEL_PATH: &str = "/boot/kernel";
+/// Initial ramdisk of the boot specification (optional).
+pub const BOOT_INITRD_PATH: &str = "/boot/initrd";
+/// Kernel command line of the boot specification (optional).
+pub const BOOT_CMDLINE_PATH: &str = "/boot/cmdline";
+/// virtio-blk disk image of the boot specification (optional).
+pub const BOOT_DISK_PATH: &str = "/boot/disk";
+
+/// Guest image used when neither a VM list nor a boot specification is
+/// present.
+pub const DEFAULT_GUEST_IMAGE: &str = "/sbin/gkernel";
+
+/// Contents of the guests' emulated pflash, if present on the root filesystem.
+pub const PFLASH_IMAGE_PATH: &str = "/etc/pflash.img";
+
+/// CPU share of a VM without `shares=`, the weight of a nice 0 task.
+pub const DEFAULT_CPU_SHARES: usize = 1024;
+
+/// Weights of the CFS nice levels -20 to 19 (as in Linux).
+const NICE_WEIGHTS: [usize; 40] = [
+    88761, 71755, 56483, 46273, 36291, 29154, 23254, 18705, 14949, 11916, 9548, 7620, 6100, 4904,
+    3906, 3121, 2501, 1991, 1586, 1277, 1024, 820, 655, 526, 423, 335, 272, 215, 172, 137, 110, 87,
+    70, 56, 45, 36, 29, 23, 18, 15,
+];
+
+/// Pages backed per fault in lazily backed RAM without `fault_around=`.
+pub const DEFAULT_FAULT_AROUND: usize = 16;
+/// Largest `fault_around=` window, one 2M block.
+pub const MAX_FAULT_AROUND: usize = 512;
+
+/// Default guest TSC frequency of `tsc=intercept`, in kHz.
+pub const DEFAULT_TSC_KHZ: u64 = 1_000_000;
+
+/// How an x86_64 guest's TSC is virtualized.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum TscMode {
+    /// RDTSC runs natively, shifted by the VMCB TSC offset.
+    #[default]
+    Offset,
+    /// RDTSC and RDTSCP exit and return the guest time scaled to `khz`.
+    Intercept { khz: u64 },
+}
+
+impl TscMode {
+    /// Parses a `tsc=` value: `offset`, or `intercept` with an optional
+    /// `:MHZ` frequency ([`DEFAULT_TSC_KHZ`] without).
+    pub fn parse(spec: &str) -> Option<Self> {
+        match spec.split_once(':') {
+            None if spec == "offset" => Some(Self::Offset),
+            None if spec == "intercept" => Some(Self::Intercept {
+                khz: DEFAULT_TSC_KHZ,
+            }),
+            Some(("intercept", mhz)) => match mhz.parse::<u64>() {
+                Ok(mhz @ 1..=100_000) => Some(Self::Intercept { khz: mhz * 1000 }),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+}
+
+/// Static configuration of one guest VM.
+#[derive(Clone, Debug)]
+pub struct VmConfig {
+    /// VM id, used to tag console output and hypervisor messages.
+    pub id: usize,
+    /// Path of the guest image on the root filesystem.
+    pub image: String,
+    /// Path of the guest's virtio-blk disk image, if it has one.
+    pub disk: Option<String>,
+    /// Number of vCPUs, at least one.
+    pub cpus: usize,
+    /// Path of the initial ramdisk, if the guest has one.
+    pub initrd: Option<String>,
+    /// Kernel command line, if one is configured.
+    pub cmdline: Option<String>,
+    /// Most bytes of host memory the guest's RAM may take, if capped.
+    pub mem_limit: Option<usize>,
+    /// CPU share relative to [`DEFAULT_CPU_SHARES`].
+    pub cpu_shares: usize,
+    /// Tracing of the VM's exits.
+    pub trace: TraceConfig,
+    /// Virtualization of the guest TSC (x86_64).
+    pub tsc: TscMode,
+    /// W^X guest memory and host protections (`harden=on`).
+    pub harden: bool,
+    /// 4K pages backed per fault in lazily backed RAM, at least one.
+    pub fault_around: usize,
+    /// The secondary host serial port is the guest's (`serial=on`).
+    pub serial: bool,
+    /// Host CPU the VM's task is pinned to, if configured.
+    pub pin: Option<usize>,
+    /// The guest's watchdog, if it has one.
+    pub watchdog: Option<WatchdogConfig>,
+    /// Path of the trace file the VM's exits are recorded in, if any.
+    pub record: Option<String>,
+    /// SHA-256 digest (hex) the guest image must have, if checked. Kept as
+    /// written: a malformed digest fails the VM's setup instead of turning
+    /// the check off.
+    pub sha256: Option<String>,
+    /// Working set sampling period in milliseconds, if sampled.
+    pub wss_period_ms: Option<u64>,
+    /// The migration of the VM out, if configured.
+    pub migrate: Option<MigrateConfig>,
+    /// Path of the migration stream the VM resumes from, if it is migrated
+    /// in.
+    pub incoming: Option<String>,
+}
+
+impl VmConfig {
+    /// Returns the configuration of VM `id` running `image` with every
+    /// option at its default.
+    pub fn new(id: usize, image: &str) -> Self {
+        Self {
+            id,
+            image: image.to_string(),
+            disk: None,
+            cpus: 1,
+            initrd: None,
+            cmdline: None,
+            mem_limit: None,
+            cpu_shares: DEFAULT_CPU_SHARES,
+            trace: TraceConfig::default(),
+            tsc: TscMode::default(),
+            harden: false,
+            fault_around: DEFAULT_FAULT_AROUND,
+            serial: false,
+            pin: None,
+            watchdog: None,
+            record: None,
+            sha256: None,
+            wss_period_ms: None,
+            migrate: None,
+            incoming: None,
+        }
+    }
+
+    /// Returns the nice value of the VM task whose CFS weight is closest to
+    /// the VM's CPU share.
+    pub fn nice(&self) -> isize {
+        let (idx, _) = NICE_WEIGHTS
+            .iter()
+            .enumerate()
+            .min_by_key(|&(_, &w)| w.abs_diff(self.cpu_shares))
+            .unwrap();
+        idx as isize - 20
+    }
+}
+
+/// Reads the VM list, falling back to the boot specification and then to a
+/// single default VM.
+pub fn load_vm_configs() -> Vec<VmConfig> {
+    let configs = read_file(VM_CONFIG_PATH)
+        .map(|text| parse_vm_configs(&text))
+        .unwrap_or_default();
+    if !configs.is_empty() {
+        return configs;
+    }
+    if let Some(cfg) = boot_spec_config() {
+        return alloc::vec![cfg];
+    }
+    alloc::vec![VmConfig::new(0, DEFAULT_GUEST_IMAGE)]
+}
+
+/// Builds VM 0 from the boot specification in `/boot`, if there is a
+/// kernel.
+fn boot_spec_config() -> Option<VmConfig> {
+    File::open(BOOT_KERNEL_PATH).ok()?;
+    let mut cfg = VmConfig::new(0, BOOT_KERNEL_PATH);
+    let exists = |path: &str| File::open(path).is_ok().then(|| path.to_string());
+    cfg.initrd = exists(BOOT_INITRD_PATH);
+    cfg.disk = exists(BOOT_DISK_PATH);
+    cfg.cmdline = read_file(BOOT_CMDLINE_PATH)
+        .map(|text| text.trim().to_string())
+        .filter(|cmdline| !cmdline.is_empty());
+    Some(cfg)
+}
+
+/// Reads the text file at `path`, if it exists and is readable.
+fn read_file(path: &str) -> Option<String> {
+    let mut text = String::new();
+    File::open(path).ok()?.read_to_string(&mut text).ok()?;
+    Some(text)
+}
+
+fn parse_vm_configs(text: &str) -> Vec<VmConfig> {
+    text.lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .enumerate()
+        .map(|(id, line)| {
+            // The command line may contain spaces: it extends to the end of
+            // the line.
+            let (line, cmdline) = match line.find("cmdline=") {
+                Some(pos) if pos == 0 || line[..pos].ends_with(char::is_whitespace) => {
+                    (&line[..pos], Some(line[pos + "cmdline=".len()..].trim()))
+                }
+                _ => (line, None),
+            };
+            let mut cfg = VmConfig::new(id, "");
+            cfg.cmdline = cmdline.map(str::to_string);
+            for field in line.split_whitespace() {
+                if let Some(cpus) = field.strip_prefix("cpus=") {
+                    cfg.cpus = cpus.parse().unwrap_or(1).max(1);
+                } else if let Some(size) = field.strip_prefix("mem=") {
+                    cfg.mem_limit = parse_size(size);
+                } else if let Some(shares) = field.strip_prefix("shares=") {
+                    cfg.cpu_shares = shares.parse().unwrap_or(DEFAULT_CPU_SHARES).max(1);
+                } else if let Some(spec) = field.strip_prefix("trace=") {
+                    cfg.trace = TraceConfig::parse(spec).unwrap_or_default();
+                } else if let Some(spec) = field.strip_prefix("tsc=") {
+                    cfg.tsc = TscMode::parse(spec).unwrap_or_default();
+                } else if let Some(value) = field.strip_prefix("harden=") {
+                    cfg.harden = matches!(value, "on" | "1" | "yes");
+                } else if let Some(pages) = field.strip_prefix("fault_around=") {
+                    cfg.fault_around = pages
+                        .parse()
+                        .unwrap_or(DEFAULT_FAULT_AROUND)
+                        .clamp(1, MAX_FAULT_AROUND);
+                } else if let Some(value) = field.strip_prefix("serial=") {
+                    cfg.serial = matches!(value, "on" | "1" | "yes");
+                } else if let Some(cpu) = field.strip_prefix("pin=") {
+                    cfg.pin = cpu.parse().ok();
+                } else if let Some(spec) = field.strip_prefix("watchdog=") {
+                    cfg.watchdog = WatchdogConfig::parse(spec);
+                } else if let Some(path) = field.strip_prefix("record=") {
+                    cfg.record = Some(path.to_string());
+                } else if let Some(hex) = field.strip_prefix("sha256=") {
+                    cfg.sha256 = Some(hex.to_string());
+                } else if let Some(ms) = field.strip_prefix("wss=") {
+                    cfg.wss_period_ms = WorkingSet::parse_period(ms);
+                } else if let Some(spec) = field.strip_prefix("migrate=") {
+                    cfg.migrate = MigrateConfig::parse(spec);
+                } else if let Some(path) = field.strip_prefix("incoming=") {
+                    cfg.incoming = Some(path.to_string());
+                } else if let Some(path) = field.strip_prefix("initrd=") {
+                    cfg.initrd = Some(path.to_string());
+                } else if cfg.image.is_empty() {
+                    cfg.image = field.to_string();
+                } else if cfg.disk.is_none() {
+                    cfg.disk = Some(field.to_string());
+                }
+            }
+            cfg
+        })
+        .collect()
+}
+
+/// Parses a size in bytes with an optional `K`, `M` or `G` suffix.
+fn parse_size(text: &str) -> Option<usize> {
+    let (digits, shift) = match text.as_bytes().last()? {
+        b'K' | b'k' => (&text[..text.len() - 1], 10),
+        b'M' | b'm' => (&text[..text.len() - 1], 20),
+        b'G' | b'g' => (&text[..text.len() - 1], 30),
+        _ => (text, 0),
+    };
+    digits.parse::<usize>().ok()?.checked_mul(1 << shift)
+}
@@ -0,0 +1,148 @@
+//! Per-VM console output.
+//!
+//! Characters written by a guest through its putchar hypercall are buffered
+//! per VM and emitted one line at a time, tagged with the VM id, so that the
+//! output of concurrently running guests stays readable. The console of the
+//! VM owning the secondary serial port writes there instead, raw.
+//!
+//! Host console input reaches guests through [`read_host_input`], which
+//! takes out hypervisor console commands: `Ctrl-A t` prints the exit trace
+//! rings of all VMs, `Ctrl-A p` and `Ctrl-A r` pause and resume all VMs,
+//! `Ctrl-A Ctrl-A` passes a `Ctrl-A` on to the guest.
+
+use alloc::collections::VecDeque;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use axstd::sync::Mutex;
+
+use crate::serial::{self, HostSerial};
+
+/// Prefix of a console command (Ctrl-A).
+const COMMAND_ESCAPE: u8 = 0x01;
+
+/// The last input character was [`COMMAND_ESCAPE`].
+static ESCAPED: AtomicBool = AtomicBool::new(false);
+
+/// Guest input read by [`poll_commands`], for the next [`read_host_input`].
+static HELD_INPUT: Mutex<VecDeque<u8>> = Mutex::new(VecDeque::new());
+
+/// Most bytes of guest input held.
+const HELD_INPUT_LEN: usize = 4096;
+
+/// Prints a hypervisor message tagged with a VM id.
+macro_rules! vm_println {
+    ($vm_id:expr, $($arg:tt)*) => {
+        ax_println!("[vm{}] {}", $vm_id, format_args!($($arg)*))
+    };
+}
+
+/// Line-buffered console of one guest.
+pub struct VmConsole {
+    vm_id: usize,
+    line: Vec<u8>,
+    /// The secondary serial port, if the VM owns it.
+    serial: Option<HostSerial>,
+}
+
+impl VmConsole {
+    /// Creates an empty console for VM `vm_id`, on the secondary serial
+    /// port if the VM owns it.
+    pub fn new(vm_id: usize) -> Self {
+        Self {
+            vm_id,
+            line: Vec::new(),
+            serial: serial::owned_by(vm_id),
+        }
+    }
+
+    /// Writes one guest character; complete lines are printed immediately.
+    pub fn putchar(&mut self, ch: u8) {
+        if let Some(port) = &self.serial {
+            port.write(ch);
+            return;
+        }
+        match ch {
+            b'\n' => self.emit_line(),
+            b'\r' => {}
+            _ => self.line.push(ch),
+        }
+    }
+
+    /// Prints any buffered partial line.
+    pub fn flush(&mut self) {
+        if !self.line.is_empty() {
+            self.emit_line();
+        }
+    }
+
+    fn emit_line(&mut self) {
+        let line = core::str::from_utf8(&self.line).unwrap_or("<invalid utf-8>");
+        vm_println!(self.vm_id, "{}", line);
+        self.line.clear();
+    }
+}
+
+impl Drop for VmConsole {
+    fn drop(&mut self) {
+        self.flush();
+    }
+}
+
+/// Reads host console input into `buf` and returns the number of bytes
+/// for the guest, after running and removing console commands.
+pub fn read_host_input(buf: &mut [u8]) -> usize {
+    {
+        let mut held = HELD_INPUT.lock();
+        if !held.is_empty() {
+            let len = buf.len().min(held.len());
+            for (dst, src) in buf.iter_mut().zip(held.drain(..len)) {
+                *dst = src;
+            }
+            return len;
+        }
+    }
+    let n = axhal::console::read_bytes(buf);
+    run_commands(buf, n)
+}
+
+/// Runs the console commands in host input while no guest reads it (all
+/// VMs paused), holding the rest for the guest.
+pub fn poll_commands() {
+    let mut buf = [0u8; 64];
+    let n = axhal::console::read_bytes(&mut buf);
+    let len = run_commands(&mut buf, n);
+    let mut held = HELD_INPUT.lock();
+    let room = HELD_INPUT_LEN.saturating_sub(held.len());
+    held.extend(&buf[..len.min(room)]);
+}
+
+/// Runs and removes the console commands in the first `n` bytes of `buf`.
+/// Returns the number of bytes left, moved to the front of `buf`.
+fn run_commands(buf: &mut [u8], n: usize) -> usize {
+    let mut len = 0;
+    for i in 0..n {
+        let ch = buf[i];
+        if ESCAPED.swap(false, Ordering::Relaxed) {
+            match ch {
+                b't' => crate::trace::dump_rings(),
+                b'p' => crate::pause::pause_all(),
+                b'r' => crate::pause::resume_all(),
+                COMMAND_ESCAPE => {
+                    buf[len] = ch;
+                    len += 1;
+                }
+                _ => ax_println!(
+                    "Console commands: Ctrl-A t (exit trace), Ctrl-A p (pause VMs), \
+                     Ctrl-A r (resume VMs), Ctrl-A Ctrl-A (Ctrl-A)"
+                ),
+            }
+        } else if ch == COMMAND_ESCAPE {
+            ESCAPED.store(true, Ordering::Relaxed);
+        } else {
+            buf[len] = ch;
+            len += 1;
+        }
+    }
+    len
+}
@@ -0,0 +1,408 @@
+//! The CPU a guest is told it runs on.
+//!
+//! A [`GuestCpuModel`] lists the optional features a VM gets, and
+//! everything the guest learns about its CPU is derived from it: the
+//! `riscv,isa` string of a riscv64 guest's device tree, the ID registers an
+//! aarch64 guest reads, and the CPUID leaves of an x86_64 guest. A feature
+//! is only in the model if the hypervisor switches the state it brings
+//! between guests and the host, so a guest never enables an extension
+//! whose registers another VM could clobber; the host's own values only
+//! ever narrow it further.
+//!
+//! The aarch64 and x86_64 reports start from the host's value of the
+//! register or leaf and keep the fields the model allows (a whitelist), so
+//! fields a newer CPU adds stay hidden until they are known to be safe.
+
+#![allow(dead_code)]
+
+use alloc::string::String;
+
+/// An optional feature of a guest CPU.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Feature {
+    /// Scalar floating point: riscv64 F and D, aarch64 FP. x86_64 guests
+    /// always have x87 and SSE2, which long mode requires.
+    Fp,
+    /// 128-bit SIMD: aarch64 AdvSIMD, x86_64 SSE3 to SSE4.2.
+    Simd,
+    /// SIMD registers wider than 128 bits: riscv64 V, aarch64 SVE and SME,
+    /// x86_64 XSAVE and AVX. No architecture switches them yet.
+    WideSimd,
+    /// Atomic instructions: riscv64 A, aarch64 LSE.
+    Atomics,
+    /// Cryptography on the SIMD registers: aarch64 AES, SHA and SM, x86_64
+    /// AES-NI, PCLMULQDQ and SHA.
+    Crypto,
+    /// riscv64 guest `vstimecmp` (Sstc).
+    Sstc,
+    /// riscv64 page-based memory types (Svpbmt).
+    Svpbmt,
+    /// riscv64 guest interrupt file (Ssaia).
+    Ssaia,
+}
+
+/// The optional features of a guest CPU.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct GuestCpuModel {
+    features: u32,
+}
+
+impl GuestCpuModel {
+    /// A CPU without optional features.
+    pub const fn baseline() -> Self {
+        Self { features: 0 }
+    }
+
+    /// Adds `feature`.
+    pub const fn with(self, feature: Feature) -> Self {
+        Self {
+            features: self.features | 1 << feature as u32,
+        }
+    }
+
+    /// Adds `feature` if `cond` holds, e.g. if the host has it.
+    pub const fn with_if(self, feature: Feature, cond: bool) -> Self {
+        if cond { self.with(feature) } else { self }
+    }
+
+    /// Checks whether the model has `feature`.
+    pub const fn has(&self, feature: Feature) -> bool {
+        self.features & 1 << feature as u32 != 0
+    }
+
+    /// Returns the riscv64 ISA string of the guest's harts, for its device
+    /// tree.
+    pub fn riscv_isa(&self) -> String {
+        let mut isa = String::from("rv64im");
+        if self.has(Feature::Atomics) {
+            isa.push('a');
+        }
+        if self.has(Feature::Fp) {
+            isa.push_str("fd");
+        }
+        isa.push('c');
+        if self.has(Feature::WideSimd) {
+            isa.push('v');
+        }
+        // Multi-letter extensions, in alphabetical order.
+        for (feature, name) in [
+            (Feature::Ssaia, "ssaia"),
+            (Feature::Sstc, "sstc"),
+            (Feature::Svpbmt, "svpbmt"),
+        ] {
+            if self.has(feature) {
+                isa.push('_');
+                isa.push_str(name);
+            }
+        }
+        isa
+    }
+}
+
+/// aarch64 ID registers, encoded as in [`sysreg`].
+pub mod aarch64 {
+    use super::{Feature, GuestCpuModel};
+
+    /// Encodes the system register (op0, op1, CRn, CRm, op2) as the
+    /// ESR_ELx ISS of a trapped MRS orders the fields: `op0 << 14 | op1 <<
+    /// 11 | CRn << 7 | CRm << 3 | op2`.
+    pub const fn sysreg(op0: u32, op1: u32, crn: u32, crm: u32, op2: u32) -> u32 {
+        op0 << 14 | op1 << 11 | crn << 7 | crm << 3 | op2
+    }
+
+    pub const MIDR_EL1: u32 = sysreg(3, 0, 0, 0, 0);
+    pub const MPIDR_EL1: u32 = sysreg(3, 0, 0, 0, 5);
+    pub const REVIDR_EL1: u32 = sysreg(3, 0, 0, 0, 6);
+    pub const ID_AA64PFR0_EL1: u32 = sysreg(3, 0, 0, 4, 0);
+    pub const ID_AA64PFR1_EL1: u32 = sysreg(3, 0, 0, 4, 1);
+    pub const ID_AA64ZFR0_EL1: u32 = sysreg(3, 0, 0, 4, 4);
+    pub const ID_AA64DFR0_EL1: u32 = sysreg(3, 0, 0, 5, 0);
+    pub const ID_AA64ISAR0_EL1: u32 = sysreg(3, 0, 0, 6, 0);
+    pub const ID_AA64ISAR1_EL1: u32 = sysreg(3, 0, 0, 6, 1);
+    pub const ID_AA64ISAR2_EL1: u32 = sysreg(3, 0, 0, 6, 2);
+    pub const ID_AA64MMFR0_EL1: u32 = sysreg(3, 0, 0, 7, 0);
+    pub const ID_AA64MMFR1_EL1: u32 = sysreg(3, 0, 0, 7, 1);
+    pub const ID_AA64MMFR2_EL1: u32 = sysreg(3, 0, 0, 7, 2);
+
+    /// Checks whether `reg` is in the ID register space (op0 3, op1 0,
+    /// CRn 0), all of which a guest may read.
+    pub const fn is_id_reg(reg: u32) -> bool {
+        reg >> 7 == sysreg(3, 0, 0, 0, 0) >> 7
+    }
+
+    /// Mask of the 4-bit ID register fields `fields`.
+    const fn fields(fields: &[u32]) -> u64 {
+        let mut mask = 0;
+        let mut i = 0;
+        while i < fields.len() {
+            mask |= 0xF << (fields[i] * 4);
+            i += 1;
+        }
+        mask
+    }
+
+    /// A feature the model lacks reads as "not implemented": 0xF for FP
+    /// and AdvSIMD, zero elsewhere.
+    const PFR0_FP: u64 = fields(&[4]);
+    const PFR0_ADVSIMD: u64 = fields(&[5]);
+    /// EL0, EL1, DIT, CSV2 and CSV3.
+    const PFR0_KEEP: u64 = fields(&[0, 1, 12, 14, 15]);
+    /// BT and SSBS.
+    const PFR1_KEEP: u64 = fields(&[0, 1]);
+    /// CRC32, TS and RNDR.
+    const ISAR0_KEEP: u64 = fields(&[4, 13, 15]);
+    const ISAR0_ATOMIC: u64 = fields(&[5]);
+    /// RDM, DP and FHM.
+    const ISAR0_SIMD: u64 = fields(&[7, 11, 12]);
+    /// AES, SHA1, SHA2, SHA3, SM3 and SM4.
+    const ISAR0_CRYPTO: u64 = fields(&[1, 2, 3, 8, 9, 10]);
+    /// Pointer authentication (APA, API, GPA, GPI): its keys are not
+    /// switched.
+    const ISAR1_PAUTH: u64 = fields(&[1, 2, 6, 7]);
+    /// GPA3 and APA3.
+    const ISAR2_PAUTH: u64 = fields(&[2, 3]);
+    /// DebugVer.
+    const DFR0_KEEP: u64 = fields(&[0]);
+    /// MPIDR_EL1: RES1 bit 31 and U (uniprocessor), affinity 0.
+    const MPIDR_UNIPROCESSOR: u64 = 1 << 31 | 1 << 30;
+
+    impl GuestCpuModel {
+        /// Returns the value the guest reads from ID register `reg`, given
+        /// the host's value. Registers of the ID space not listed (SVE, SME
+        /// and the auxiliary feature registers among them) read as zero.
+        pub fn aarch64_id_reg(&self, reg: u32, host: u64) -> u64 {
+            let keep = |mask: u64, feature: Feature| if self.has(feature) { mask } else { 0 };
+            match reg {
+                MIDR_EL1 | REVIDR_EL1 => host,
+                MPIDR_EL1 => MPIDR_UNIPROCESSOR,
+                ID_AA64PFR0_EL1 => {
+                    let mut value = host & PFR0_KEEP;
+                    for (mask, feature) in [(PFR0_FP, Feature::Fp), (PFR0_ADVSIMD, Feature::Simd)] {
+                        value |= if self.has(feature) { host & mask } else { mask };
+                    }
+                    value
+                }
+                ID_AA64PFR1_EL1 => host & PFR1_KEEP,
+                ID_AA64ISAR0_EL1 => {
+                    host & (ISAR0_KEEP
+                        | keep(ISAR0_ATOMIC, Feature::Atomics)
+                        | keep(ISAR0_SIMD, Feature::Simd)
+                        | keep(ISAR0_CRYPTO, Feature::Crypto))
+                }
+                ID_AA64ISAR1_EL1 => host & !ISAR1_PAUTH,
+                ID_AA64ISAR2_EL1 => host & !ISAR2_PAUTH,
+                ID_AA64DFR0_EL1 => host & DFR0_KEEP,
+                ID_AA64MMFR0_EL1 | ID_AA64MMFR1_EL1 | ID_AA64MMFR2_EL1 => host,
+                _ => 0,
+            }
+        }
+    }
+}
+
+/// x86_64 CPUID leaves.
+pub mod x86 {
+    use super::{Feature, GuestCpuModel};
+
+    /// Highest basic leaf reported: leaves the model does not know
+    /// (thermal, performance monitoring, topology, XSAVE, ...) are beyond
+    /// it or read as zero.
+    const MAX_BASIC_LEAF: u32 = 0xD;
+    /// Highest extended leaf reported.
+    const MAX_EXTENDED_LEAF: u32 = 0x8000_0008;
+    /// Leaf of the hypervisor signature.
+    pub const HYPERVISOR_LEAF: u32 = 0x4000_0000;
+    /// Hypervisor signature, in EBX, ECX and EDX of its leaf.
+    pub const SIGNATURE: &[u8; 12] = b"ArceOSHVisor";
+
+    const fn bits(bits: &[u32]) -> u32 {
+        let mut mask = 0;
+        let mut i = 0;
+        while i < bits.len() {
+            mask |= 1 << bits[i];
+            i += 1;
+        }
+        mask
+    }
+
+    /// Leaf 1 ECX: CX16, MOVBE, POPCNT, RDRAND.
+    const L1_ECX_KEEP: u32 = bits(&[13, 22, 23, 30]);
+    /// Leaf 1 ECX: SSE3, SSSE3, SSE4.1, SSE4.2.
+    const L1_ECX_SIMD: u32 = bits(&[0, 9, 19, 20]);
+    /// Leaf 1 ECX: PCLMULQDQ, AES.
+    const L1_ECX_CRYPTO: u32 = bits(&[1, 25]);
+    /// Leaf 1 ECX: XSAVE, AVX, F16C, FMA (OSXSAVE follows XSAVE).
+    const L1_ECX_WIDE: u32 = bits(&[12, 26, 28, 29]);
+    /// Leaf 1 ECX: running under a hypervisor.
+    const L1_ECX_HYPERVISOR: u32 = 1 << 31;
+    /// Leaf 1 EDX: FPU, VME, DE, PSE, TSC, MSR, PAE, CX8, APIC, SEP, MTRR,
+    /// PGE, CMOV, PAT, PSE36, CLFSH, MMX, FXSR, SSE, SSE2.
+    const L1_EDX_KEEP: u32 = bits(&[
+        0, 1, 2, 3, 4, 5, 6, 8, 9, 11, 12, 13, 15, 16, 17, 19, 23, 24, 25, 26,
+    ]);
+    /// Leaf 1 EBX: brand index and CLFLUSH line size; a single logical
+    /// processor with APIC ID 0.
+    const L1_EBX_KEEP: u32 = 0xFFFF;
+    const L1_EBX_ONE_CPU: u32 = 1 << 16;
+    /// Leaf 7.0 EBX: FSGSBASE, BMI1, SMEP, BMI2, ERMS, RDSEED, ADX, SMAP,
+    /// CLFLUSHOPT, CLWB.
+    const L7_EBX_KEEP: u32 = bits(&[0, 3, 7, 8, 9, 18, 19, 20, 23, 24]);
+    /// Leaf 7.0 EBX: SHA.
+    const L7_EBX_CRYPTO: u32 = bits(&[29]);
+    /// Leaf 7.0 EBX: AVX2.
+    const L7_EBX_WIDE: u32 = bits(&[5]);
+    /// Leaf 7.0 EDX: fast short REP MOVSB.
+    const L7_EDX_KEEP: u32 = bits(&[4]);
+    /// Leaf 4 EAX: cache type, level and properties, without the core and
+    /// thread counts.
+    const L4_EAX_KEEP: u32 = 0x3FFF;
+    /// Leaf 8000_0001h ECX: LAHF, ABM, misaligned SSE, PREFETCHW.
+    const E1_ECX_KEEP: u32 = bits(&[0, 5, 7, 8]);
+    /// Leaf 8000_0001h ECX: SSE4A.
+    const E1_ECX_SIMD: u32 = bits(&[6]);
+    /// Leaf 8000_0001h EDX: the leaf 1 bits AMD mirrors, SYSCALL, NX,
+    /// MMXEXT, FFXSR, 1 GB pages, RDTSCP and long mode.
+    const E1_EDX_KEEP: u32 = L1_EDX_KEEP | bits(&[11, 20, 22, 25, 26, 27, 29]);
+    /// Leaf 8000_0007h EDX: invariant TSC.
+    const E7_EDX_KEEP: u32 = bits(&[8]);
+
+    impl GuestCpuModel {
+        /// Returns the guest's CPUID leaf `leaf`, subleaf `subleaf`, as
+        /// EAX, EBX, ECX and EDX, given the host's.
+        pub fn x86_cpuid(&self, leaf: u32, subleaf: u32, host: [u32; 4]) -> [u32; 4] {
+            let keep = |mask: u32, feature: Feature| if self.has(feature) { mask } else { 0 };
+            let simd = self.has(Feature::Simd);
+            let [eax, ebx, ecx, edx] = host;
+            match leaf {
+                0 => [eax.min(MAX_BASIC_LEAF), ebx, ecx, edx],
+                1 => {
+                    let ecx = ecx
+                        & (L1_ECX_KEEP
+                            | keep(L1_ECX_SIMD, Feature::Simd)
+                            | if simd {
+                                keep(L1_ECX_CRYPTO, Feature::Crypto)
+                            } else {
+                                0
+                            }
+                            | if simd {
+                                keep(L1_ECX_WIDE, Feature::WideSimd)
+                            } else {
+                                0
+                            });
+                    [
+                        eax,
+                        ebx & L1_EBX_KEEP | L1_EBX_ONE_CPU,
+                        ecx | L1_ECX_HYPERVISOR,
+                        edx & L1_EDX_KEEP,
+                    ]
+                }
+                2 => host,
+                4 => [eax & L4_EAX_KEEP, ebx, ecx, edx],
+                7 if subleaf == 0 => {
+                    let ebx = ebx
+                        & (L7_EBX_KEEP
+                            | if simd {
+                                keep(L7_EBX_CRYPTO, Feature::Crypto)
+                            } else {
+                                0
+                            }
+                            | if simd {
+                                keep(L7_EBX_WIDE, Feature::WideSimd)
+                            } else {
+                                0
+                            });
+                    [0, ebx, 0, edx & L7_EDX_KEEP]
+                }
+                HYPERVISOR_LEAF => {
+                    let word =
+                        |i: usize| u32::from_le_bytes(SIGNATURE[i * 4..][..4].try_into().unwrap());
+                    [HYPERVISOR_LEAF, word(0), word(1), word(2)]
+                }
+                0x8000_0000 if eax >= 0x8000_0000 => [eax.min(MAX_EXTENDED_LEAF), ebx, ecx, edx],
+                0x8000_0001 => [
+                    eax,
+                    ebx,
+                    ecx & (E1_ECX_KEEP | keep(E1_ECX_SIMD, Feature::Simd)),
+                    edx & E1_EDX_KEEP,
+                ],
+                // Brand string, L1 and L2/L3 cache and TLB descriptions.
+                0x8000_0002..=0x8000_0006 => host,
+                0x8000_0007 => [0, 0, 0, edx & E7_EDX_KEEP],
+                // Address sizes, of a single core.
+                0x8000_0008 => [eax, 0, 0, 0],
+                _ => [0; 4],
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::aarch64::*;
+    use super::x86::*;
+    use super::*;
+
+    #[test]
+    fn riscv_isa_string() {
+        let model = GuestCpuModel::baseline()
+            .with(Feature::Atomics)
+            .with(Feature::Fp);
+        assert_eq!(model.riscv_isa(), "rv64imafdc");
+        let model = model
+            .with(Feature::Sstc)
+            .with_if(Feature::Svpbmt, false)
+            .with(Feature::Ssaia);
+        assert_eq!(model.riscv_isa(), "rv64imafdc_ssaia_sstc");
+    }
+
+    #[test]
+    fn aarch64_id_registers() {
+        let model = GuestCpuModel::baseline().with(Feature::Fp);
+        // Host: EL0/EL1 AArch64, FP and AdvSIMD, GICv3 sysregs, SVE.
+        let pfr0 = 0x1_0100_0011;
+        // AdvSIMD reads as not implemented, the GIC and SVE as absent.
+        assert_eq!(model.aarch64_id_reg(ID_AA64PFR0_EL1, pfr0), 0xF0_0011);
+        // AES and atomics are dropped without SIMD and LSE; CRC32 stays.
+        let isar0 = 0x21_0000 | 0x20;
+        assert_eq!(model.aarch64_id_reg(ID_AA64ISAR0_EL1, isar0), 0x1_0000);
+        let model = model
+            .with(Feature::Simd)
+            .with(Feature::Crypto)
+            .with(Feature::Atomics);
+        assert_eq!(model.aarch64_id_reg(ID_AA64ISAR0_EL1, isar0), isar0);
+        // No pointer authentication; SVE and unknown ID registers read zero.
+        assert_eq!(model.aarch64_id_reg(ID_AA64ISAR1_EL1, 0x11_0011), 0x11_0001);
+        assert_eq!(model.aarch64_id_reg(ID_AA64ZFR0_EL1, !0), 0);
+        assert!(is_id_reg(sysreg(3, 0, 0, 3, 7)));
+        assert!(!is_id_reg(sysreg(3, 3, 14, 0, 2)));
+    }
+
+    #[test]
+    fn x86_cpuid_leaves() {
+        let model = GuestCpuModel::baseline()
+            .with(Feature::Fp)
+            .with(Feature::Simd)
+            .with(Feature::Crypto);
+        // Host leaf 1: SSE3, AES, x2APIC, XSAVE, OSXSAVE, AVX; 8 CPUs.
+        let ecx = 1 | 1 << 25 | 1 << 21 | 1 << 26 | 1 << 27 | 1 << 28;
+        let [_, ebx, ecx, edx] = model.x86_cpuid(1, 0, [0x800f12, 0x0308_0800, ecx, 1 << 28 | 1]);
+        assert_eq!(ebx, 0x0001_0800);
+        assert_eq!(ecx, 1 | 1 << 25 | 1 << 31);
+        // No HTT.
+        assert_eq!(edx, 1);
+        // Leaf 7: AVX2 and AVX-512F go, BMI1 stays.
+        assert_eq!(
+            model.x86_cpuid(7, 0, [2, 1 << 5 | 1 << 16 | 1 << 3, 0, 0]),
+            [0, 1 << 3, 0, 0]
+        );
+        assert_eq!(model.x86_cpuid(7, 1, [0, !0, !0, !0]), [0; 4]);
+        // XSAVE state and SVM are not reported.
+        assert_eq!(model.x86_cpuid(0xD, 0, [7, 0x340, 0x340, 0]), [0; 4]);
+        assert_eq!(model.x86_cpuid(0x8000_000A, 0, [1, 0x8000, 0, !0]), [0; 4]);
+        assert_eq!(model.x86_cpuid(0, 0, [0x16, 1, 2, 3]), [0xD, 1, 2, 3]);
+        let [max, b, c, d] = model.x86_cpuid(HYPERVISOR_LEAF, 0, [0; 4]);
+        assert_eq!(max, HYPERVISOR_LEAF);
+        let sig: alloc::vec::Vec<u8> = [b, c, d].iter().flat_map(|w| w.to_le_bytes()).collect();
+        assert_eq!(&sig[..], SIGNATURE);
+    }
+}
@@ -11,7 +11,18 @@ pub struct CSR {
     pub hedeleg: ReadWriteCsr<hedeleg::Register, CSR_HEDELEG>,
     pub hideleg: ReadWriteCsr<hideleg::Register, CSR_HIDELEG>,
     pub hcounteren: ReadWriteCsr<hcounteren::Register, CSR_HCOUNTEREN>,
+    pub htimedelta: ReadWriteCsr<htimedelta::Register, CSR_HTIMEDELTA>,
     pub hvip: ReadWriteCsr<hvip::Register, CSR_HVIP>,
+    pub henvcfg: ReadWriteCsr<(), CSR_HENVCFG>,
+    // Guest access to optional ISA state (Smstateen only).
+    pub hstateen0: ReadWriteCsr<(), CSR_HSTATEEN0>,
+    // Guest timer compare register (Sstc only).
+    pub vstimecmp: ReadWriteCsr<(), CSR_VSTIMECMP>,
+    // AIA guest interrupt files (Smaia/Ssaia only).
+    pub hgeie: ReadWriteCsr<(), CSR_HGEIE>,
+    pub hgeip: ReadWriteCsr<(), CSR_HGEIP>,
+    pub vsiselect: ReadWriteCsr<(), CSR_VSISELECT>,
+    pub vsireg: ReadWriteCsr<(), CSR_VSIREG>,
 }
 
 #[allow(clippy::identity_op, clippy::erasing_op)]
@@ -21,7 +32,15 @@ pub const CSR: &CSR = &CSR {
     hedeleg: ReadWriteCsr::new(),
     hideleg: ReadWriteCsr::new(),
     hcounteren: ReadWriteCsr::new(),
+    htimedelta: ReadWriteCsr::new(),
     hvip: ReadWriteCsr::new(),
+    henvcfg: ReadWriteCsr::new(),
+    hstateen0: ReadWriteCsr::new(),
+    vstimecmp: ReadWriteCsr::new(),
+    hgeie: ReadWriteCsr::new(),
+    hgeip: ReadWriteCsr::new(),
+    vsiselect: ReadWriteCsr::new(),
+    vsireg: ReadWriteCsr::new(),
 };
 
 /// Trait defining the possible operations on a RISC-V CSR.
@@ -56,6 +75,12 @@ impl<R: RegisterLongName, const V: u16> ReadWriteCsr<R, V> {
     }
 }
 
+impl<R: RegisterLongName, const V: u16> Default for ReadWriteCsr<R, V> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl<R: RegisterLongName, const V: u16> RiscvCsrTrait for ReadWriteCsr<R, V> {
     type R = R;
 
@@ -162,6 +187,7 @@ pub mod defs {
     pub const CSR_HGEIE: u16 = 0x607;
     pub const CSR_HVICTL: u16 = 0x609;
     pub const CSR_HENVCFG: u16 = 0x60a;
+    pub const CSR_HSTATEEN0: u16 = 0x60c;
     pub const CSR_HTVAL: u16 = 0x643;
     pub const CSR_HIP: u16 = 0x644;
     pub const CSR_HVIP: u16 = 0x645;
@@ -261,6 +287,13 @@ pub mod defs {
     ]
     ];
 
+    // Offset of the guest's `time` from the host's.
+    register_bitfields![usize,
+    pub htimedelta [
+        delta OFFSET(0) NUMBITS(64) [],
+    ]
+    ];
+
     // Hypervisor virtual interrupt pending.
     register_bitfields![usize,
     pub hvip [
@@ -310,3 +343,201 @@ pub mod traps {
         pub const STORE_GUEST_PAGE_FAULT: usize = 1 << 23;
     }
 }
+
+/// Which guest traps are delegated to the guest (taken in VS-mode without a
+/// VM exit) through `hedeleg` and `hideleg`, and which ones the hypervisor
+/// observes.
+///
+/// Only the traps a guest can handle itself may be delegated: ECALLs from
+/// VS-mode, guest-page faults and virtual instruction exceptions always
+/// exit to the hypervisor, and the corresponding bits are dropped.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TrapDelegation {
+    exceptions: usize,
+    interrupts: usize,
+}
+
+impl TrapDelegation {
+    /// Exceptions `hedeleg` can delegate.
+    const DELEGABLE_EXCEPTIONS: usize = traps::exception::INST_ADDR_MISALIGN
+        | traps::exception::INST_ACCESSS_FAULT
+        | traps::exception::ILLEGAL_INST
+        | traps::exception::BREAKPOINT
+        | traps::exception::LOAD_ADDR_MISALIGNED
+        | traps::exception::LOAD_ACCESS_FAULT
+        | traps::exception::STORE_ADDR_MISALIGNED
+        | traps::exception::STORE_ACCESS_FAULT
+        | traps::exception::ENV_CALL_FROM_U_OR_VU
+        | traps::exception::INST_PAGE_FAULT
+        | traps::exception::LOAD_PAGE_FAULT
+        | traps::exception::STORE_PAGE_FAULT;
+    /// Interrupts `hideleg` can delegate.
+    const DELEGABLE_INTERRUPTS: usize = traps::interrupt::VIRTUAL_SUPERVISOR_SOFT
+        | traps::interrupt::VIRTUAL_SUPERVISOR_TIMER
+        | traps::interrupt::VIRTUAL_SUPERVISOR_EXTERNAL;
+
+    /// Delegates nothing: every guest trap exits to the hypervisor.
+    pub const fn none() -> Self {
+        Self {
+            exceptions: 0,
+            interrupts: 0,
+        }
+    }
+
+    /// The delegation guests run with: system calls of the guest's user
+    /// mode, its own page faults, illegal instructions, breakpoints and
+    /// misaligned fetches go to the guest kernel, as do the three VS-level
+    /// interrupts the hypervisor injects through `hvip`.
+    pub const fn guest_default() -> Self {
+        Self::none()
+            .delegate_exception(
+                traps::exception::INST_ADDR_MISALIGN
+                    | traps::exception::BREAKPOINT
+                    | traps::exception::ENV_CALL_FROM_U_OR_VU
+                    | traps::exception::INST_PAGE_FAULT
+                    | traps::exception::LOAD_PAGE_FAULT
+                    | traps::exception::STORE_PAGE_FAULT
+                    | traps::exception::ILLEGAL_INST,
+            )
+            .delegate_interrupt(Self::DELEGABLE_INTERRUPTS)
+    }
+
+    /// Delegates the exceptions in the `traps::exception` mask `causes`.
+    pub const fn delegate_exception(mut self, causes: usize) -> Self {
+        self.exceptions |= causes & Self::DELEGABLE_EXCEPTIONS;
+        self
+    }
+
+    /// Makes the exceptions in `causes` exit to the hypervisor.
+    pub const fn observe_exception(mut self, causes: usize) -> Self {
+        self.exceptions &= !causes;
+        self
+    }
+
+    /// Delegates the interrupts in the `traps::interrupt` mask `causes`.
+    pub const fn delegate_interrupt(mut self, causes: usize) -> Self {
+        self.interrupts |= causes & Self::DELEGABLE_INTERRUPTS;
+        self
+    }
+
+    /// Makes the interrupts in `causes` exit to the hypervisor.
+    pub const fn observe_interrupt(mut self, causes: usize) -> Self {
+        self.interrupts &= !causes;
+        self
+    }
+
+    /// Returns the `hedeleg` value.
+    pub const fn exceptions(&self) -> usize {
+        self.exceptions
+    }
+
+    /// Returns the `hideleg` value.
+    pub const fn interrupts(&self) -> usize {
+        self.interrupts
+    }
+
+    /// Checks whether exception `cause` (an `scause` code) is delegated.
+    pub const fn delegates_exception(&self, cause: usize) -> bool {
+        cause < usize::BITS as usize && self.exceptions >> cause & 1 != 0
+    }
+
+    /// Programs `hedeleg` and `hideleg` on this hart.
+    pub fn apply(&self) {
+        CSR.hedeleg.write_value(self.exceptions);
+        CSR.hideleg.write_value(self.interrupts);
+    }
+}
+
+/// The optional ISA state guests get, programmed in `henvcfg` and, on
+/// hosts with Smstateen, `hstateen0` rather than left at the hart's reset
+/// values (which differ between QEMU versions).
+///
+/// `henvcfg` enables what the hart implements of the cache-block
+/// operations (Zicbom, with `cbo.inval` performed as a flush so a guest
+/// cannot discard data it does not own, and Zicboz), Svpbmt page types in
+/// the guest's page tables and the Sstc timer; a field the hart lacks reads
+/// back as zero, so [`probe`](Self::probe) learns which ones stuck. Pointer
+/// masking, hardware A/D updates and I/O fence ordering stay off.
+///
+/// `hstateen0` grants the state of the guest's AIA interrupt file (see
+/// `aia.rs`) and nothing else: `senvcfg`, `sstateen0`, `scontext` and the
+/// other state it guards trap as virtual instructions, which the guest
+/// sees as illegal instructions.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct GuestEnv {
+    henvcfg: usize,
+    hstateen0: Option<usize>,
+}
+
+impl GuestEnv {
+    /// `henvcfg.CBIE` = 01: `cbo.inval` flushes.
+    const HENVCFG_CBIE_FLUSH: usize = 1 << 4;
+    const HENVCFG_CBCFE: usize = 1 << 6;
+    const HENVCFG_CBZE: usize = 1 << 7;
+    const HENVCFG_PBMTE: usize = 1 << 62;
+    const HENVCFG_STCE: usize = 1 << 63;
+    /// `hstateen0` bits of the AIA state: the IMSIC (`stopei`, interrupt
+    /// file registers), the rest of Ssaia and `siselect`/`sireg`.
+    const HSTATEEN0_AIA: usize = (1 << 58) | (1 << 59) | (1 << 60);
+
+    /// Finds out what this hart offers guests, `smstateen` telling whether
+    /// it has `hstateen0`, and leaves it programmed. The AIA state starts
+    /// denied.
+    pub fn probe(smstateen: bool) -> Self {
+        CSR.henvcfg.write_value(
+            Self::HENVCFG_CBIE_FLUSH
+                | Self::HENVCFG_CBCFE
+                | Self::HENVCFG_CBZE
+                | Self::HENVCFG_PBMTE
+                | Self::HENVCFG_STCE,
+        );
+        let mut henvcfg = CSR.henvcfg.get_value();
+        // CBIE only matters with Zicbom, which a CBCFE that stuck tells.
+        if henvcfg & Self::HENVCFG_CBCFE == 0 {
+            henvcfg &= !Self::HENVCFG_CBIE_FLUSH;
+        }
+        let env = Self {
+            henvcfg,
+            hstateen0: smstateen.then_some(0),
+        };
+        env.apply();
+        env
+    }
+
+    /// Grants the AIA state, for a guest with its own interrupt file. On a
+    /// hart without Smstateen, the guest has it anyway.
+    pub fn with_aia(mut self) -> Self {
+        if let Some(hstateen0) = &mut self.hstateen0 {
+            *hstateen0 |= Self::HSTATEEN0_AIA;
+        }
+        self
+    }
+
+    /// Checks whether guests program `vstimecmp` themselves (Sstc).
+    pub fn sstc(&self) -> bool {
+        self.henvcfg & Self::HENVCFG_STCE != 0
+    }
+
+    /// Checks whether guests may flush and clean cache blocks (Zicbom).
+    pub fn zicbom(&self) -> bool {
+        self.henvcfg & Self::HENVCFG_CBCFE != 0
+    }
+
+    /// Checks whether guests may zero cache blocks (Zicboz).
+    pub fn zicboz(&self) -> bool {
+        self.henvcfg & Self::HENVCFG_CBZE != 0
+    }
+
+    /// Checks whether guests may use Svpbmt page types.
+    pub fn svpbmt(&self) -> bool {
+        self.henvcfg & Self::HENVCFG_PBMTE != 0
+    }
+
+    /// Programs `henvcfg` and `hstateen0` on this hart.
+    pub fn apply(&self) {
+        CSR.henvcfg.write_value(self.henvcfg);
+        if let Some(hstateen0) = self.hstateen0 {
+            CSR.hstateen0.write_value(hstateen0);
+        }
+    }
+}
@@ -0,0 +1,378 @@
+//! RISC-V APLIC in MSI delivery mode, the wired-interrupt half of the AIA
+//! a riscv64 guest gets on hosts with guest interrupt files.
+//!
+//! The guest sees a supervisor-level interrupt domain with
+//! [`NUM_SOURCES`] sources. The run loop drives the sources with the
+//! interrupt lines of the emulated devices ([`Aplic::set_input`]) and turns
+//! the interrupts the domain forwards into writes to the guest's IMSIC
+//! interrupt file ([`Aplic::forward`]). Sources are edge- or
+//! level-triggered as the guest configures them; pending bits follow the
+//! AIA rules for MSI delivery mode, so a level-triggered source that is
+//! still asserted after its handler is set pending again by the guest's
+//! write to `setipnum` rather than by the APLIC.
+//!
+//! Only 32-bit accesses are supported; the MSI address registers are the
+//! machine level's business and read as zero.
+
+#![allow(dead_code)]
+
+/// Size of the register window.
+pub const APLIC_SIZE: usize = 0x8000;
+/// Interrupt sources, numbered from 1 as in the PLIC node this replaces.
+pub const NUM_SOURCES: usize = 95;
+
+const REG_DOMAINCFG: usize = 0x0000;
+const REG_SOURCECFG: usize = 0x0004;
+const REG_SETIP: usize = 0x1C00;
+const REG_SETIPNUM: usize = 0x1CDC;
+const REG_IN_CLRIP: usize = 0x1D00;
+const REG_CLRIPNUM: usize = 0x1DDC;
+const REG_SETIE: usize = 0x1E00;
+const REG_SETIENUM: usize = 0x1EDC;
+const REG_CLRIE: usize = 0x1F00;
+const REG_CLRIENUM: usize = 0x1FDC;
+const REG_SETIPNUM_LE: usize = 0x2000;
+const REG_GENMSI: usize = 0x3000;
+const REG_TARGET: usize = 0x3004;
+
+/// `domaincfg`: the top byte reads as 0x80; IE enables the domain, DM
+/// selects MSI delivery (fixed here).
+const DOMAINCFG_RO: u32 = 0x8000_0000;
+const DOMAINCFG_IE: u32 = 1 << 8;
+const DOMAINCFG_DM: u32 = 1 << 2;
+
+/// `sourcecfg` source modes.
+const SM_INACTIVE: u32 = 0;
+const SM_DETACHED: u32 = 1;
+const SM_EDGE1: u32 = 4;
+const SM_EDGE0: u32 = 5;
+const SM_LEVEL1: u32 = 6;
+const SM_LEVEL0: u32 = 7;
+
+/// Fields of a `target` register in MSI delivery mode.
+const TARGET_HART_SHIFT: u32 = 18;
+const TARGET_GUEST_SHIFT: u32 = 12;
+const TARGET_GUEST_MASK: u32 = 0x3F;
+const TARGET_EIID_MASK: u32 = 0x7FF;
+
+/// An MSI the domain sends: external interrupt `eiid` of interrupt file
+/// `guest` (0 for the supervisor file) of hart `hart`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Msi {
+    pub hart: usize,
+    pub guest: usize,
+    pub eiid: u32,
+}
+
+impl Msi {
+    fn from_target(target: u32) -> Self {
+        Self {
+            hart: (target >> TARGET_HART_SHIFT) as usize,
+            guest: (target >> TARGET_GUEST_SHIFT & TARGET_GUEST_MASK) as usize,
+            eiid: target & TARGET_EIID_MASK,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+struct Source {
+    /// Source mode (`sourcecfg.SM`).
+    mode: u32,
+    /// The device's interrupt line.
+    input: bool,
+    pending: bool,
+    enabled: bool,
+    target: u32,
+}
+
+impl Source {
+    fn active(&self) -> bool {
+        self.mode != SM_INACTIVE
+    }
+
+    /// The input as the source mode sees it: inverted for the active-low
+    /// modes, always low for a detached source.
+    fn rectified(&self) -> bool {
+        match self.mode {
+            SM_EDGE1 | SM_LEVEL1 => self.input,
+            SM_EDGE0 | SM_LEVEL0 => !self.input,
+            _ => false,
+        }
+    }
+
+    fn is_level(&self) -> bool {
+        matches!(self.mode, SM_LEVEL1 | SM_LEVEL0)
+    }
+
+    /// A write to `setip` or `setipnum`: level-triggered sources only
+    /// become pending while asserted.
+    fn set_pending(&mut self) {
+        if self.active() && (!self.is_level() || self.rectified()) {
+            self.pending = true;
+        }
+    }
+}
+
+/// An emulated APLIC domain.
+pub struct Aplic {
+    base: usize,
+    domaincfg: u32,
+    /// Sources 1 to [`NUM_SOURCES`]; source 0 does not exist.
+    sources: [Source; NUM_SOURCES + 1],
+    /// An MSI requested through `genmsi`, sent at the next forward.
+    genmsi: Option<u32>,
+}
+
+impl Aplic {
+    pub fn new(base: usize) -> Self {
+        Self {
+            base,
+            domaincfg: 0,
+            sources: [Source::default(); NUM_SOURCES + 1],
+            genmsi: None,
+        }
+    }
+
+    pub fn base(&self) -> usize {
+        self.base
+    }
+
+    /// Checks whether `addr` falls in the register window.
+    pub fn contains(&self, addr: usize) -> bool {
+        addr.wrapping_sub(self.base) < APLIC_SIZE
+    }
+
+    /// Sets the level of the interrupt line of source `irq`.
+    pub fn set_input(&mut self, irq: usize, level: bool) {
+        let Some(source) = self.sources.get_mut(irq).filter(|_| irq != 0) else {
+            return;
+        };
+        let was = source.rectified();
+        source.input = level;
+        let now = source.rectified();
+        if now && !was {
+            source.pending = true;
+        } else if source.is_level() && !now {
+            source.pending = false;
+        }
+    }
+
+    /// Sends an MSI for every pending and enabled source, which stops being
+    /// pending, if the domain is enabled.
+    pub fn forward(&mut self, mut send: impl FnMut(Msi)) {
+        if let Some(target) = self.genmsi.take() {
+            send(Msi::from_target(target));
+        }
+        if self.domaincfg & DOMAINCFG_IE == 0 {
+            return;
+        }
+        for source in &mut self.sources[1..] {
+            if source.active() && source.pending && source.enabled {
+                source.pending = false;
+                let msi = Msi::from_target(source.target);
+                // EIID 0 is no interrupt.
+                if msi.eiid != 0 {
+                    send(msi);
+                }
+            }
+        }
+    }
+
+    /// Emulates a read of `width` bytes at `offset` into the window.
+    pub fn read(&mut self, offset: usize, width: usize) -> u64 {
+        if width != 4 || !offset.is_multiple_of(4) {
+            return 0;
+        }
+        let value = match offset {
+            REG_DOMAINCFG => DOMAINCFG_RO | self.domaincfg | DOMAINCFG_DM,
+            REG_SETIP..REG_SETIPNUM => self.bits(offset - REG_SETIP, |s| s.pending),
+            REG_IN_CLRIP..REG_CLRIPNUM => self.bits(offset - REG_IN_CLRIP, |s| s.rectified()),
+            REG_SETIE..REG_SETIENUM => self.bits(offset - REG_SETIE, |s| s.enabled),
+            _ => match self.source_reg(offset) {
+                Some((REG_SOURCECFG, source)) => source.mode,
+                Some((REG_TARGET, source)) => source.target,
+                _ => 0,
+            },
+        };
+        value as u64
+    }
+
+    /// Emulates a write of `width` bytes at `offset` into the window.
+    pub fn write(&mut self, offset: usize, width: usize, value: u64) {
+        if width != 4 || !offset.is_multiple_of(4) {
+            return;
+        }
+        let value = value as u32;
+        match offset {
+            REG_DOMAINCFG => self.domaincfg = value & DOMAINCFG_IE,
+            REG_SETIP..REG_SETIPNUM => {
+                self.set_bits(offset - REG_SETIP, value, Source::set_pending)
+            }
+            REG_SETIPNUM | REG_SETIPNUM_LE => self.with_source(value, Source::set_pending),
+            REG_IN_CLRIP..REG_CLRIPNUM => {
+                self.set_bits(offset - REG_IN_CLRIP, value, |s| s.pending = false)
+            }
+            REG_CLRIPNUM => self.with_source(value, |s| s.pending = false),
+            REG_SETIE..REG_SETIENUM => {
+                self.set_bits(offset - REG_SETIE, value, |s| s.enabled = true)
+            }
+            REG_SETIENUM => self.with_source(value, |s| s.enabled = true),
+            REG_CLRIE..REG_CLRIENUM => {
+                self.set_bits(offset - REG_CLRIE, value, |s| s.enabled = false)
+            }
+            REG_CLRIENUM => self.with_source(value, |s| s.enabled = false),
+            // The hart index and EIID fields, without the busy bit.
+            REG_GENMSI => self.genmsi = Some(value & !(TARGET_GUEST_MASK << TARGET_GUEST_SHIFT)),
+            _ => match self.source_reg(offset) {
+                Some((REG_SOURCECFG, source)) => {
+                    // No delegation to child domains; reserved modes are
+                    // inactive.
+                    source.mode = match value & 7 {
+                        mode @ (SM_DETACHED | SM_EDGE1..=SM_LEVEL0) => mode,
+                        _ => SM_INACTIVE,
+                    };
+                    if !source.active() {
+                        *source = Source {
+                            input: source.input,
+                            ..Source::default()
+                        };
+                    } else if source.is_level() {
+                        source.pending = source.rectified();
+                    }
+                }
+                Some((REG_TARGET, source)) if source.active() => {
+                    source.target = value
+                        & (!0 << TARGET_HART_SHIFT
+                            | TARGET_GUEST_MASK << TARGET_GUEST_SHIFT
+                            | TARGET_EIID_MASK);
+                }
+                _ => {}
+            },
+        }
+    }
+
+    /// Returns the `sourcecfg` or `target` array and the source that
+    /// `offset` addresses.
+    fn source_reg(&mut self, offset: usize) -> Option<(usize, &mut Source)> {
+        let array = [REG_SOURCECFG, REG_TARGET]
+            .into_iter()
+            .find(|&array| (array..array + NUM_SOURCES * 4).contains(&offset))?;
+        let irq = (offset - array) / 4 + 1;
+        Some((array, &mut self.sources[irq]))
+    }
+
+    /// Reads word `offset / 4` of a bit array: bit `i` of word `k` is
+    /// source `32 * k + i`.
+    fn bits(&self, offset: usize, bit: impl Fn(&Source) -> bool) -> u32 {
+        let first = offset / 4 * 32;
+        (0..32)
+            .filter(|i| {
+                self.sources
+                    .get(first + i)
+                    .is_some_and(|s| first + i != 0 && s.active() && bit(s))
+            })
+            .fold(0, |word, i| word | 1 << i)
+    }
+
+    /// Applies `f` to the active sources selected by `value`, word
+    /// `offset / 4` of a bit array.
+    fn set_bits(&mut self, offset: usize, value: u32, f: impl Fn(&mut Source)) {
+        let first = offset / 4 * 32;
+        for i in (0..32).filter(|i| value >> i & 1 != 0) {
+            self.with_source((first + i) as u32, &f);
+        }
+    }
+
+    /// Applies `f` to active source `irq`.
+    fn with_source(&mut self, irq: u32, f: impl Fn(&mut Source)) {
+        if let Some(source) = self
+            .sources
+            .get_mut(irq as usize)
+            .filter(|s| irq != 0 && s.active())
+        {
+            f(source);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn forwarded(aplic: &mut Aplic) -> Vec<Msi> {
+        let mut msis = Vec::new();
+        aplic.forward(|msi| msis.push(msi));
+        msis
+    }
+
+    /// Sets up source `irq` with mode `mode`, delivering EIID `eiid` to
+    /// hart 0, and enables it.
+    fn route(aplic: &mut Aplic, irq: usize, mode: u32, eiid: u32) {
+        aplic.write(REG_SOURCECFG + (irq - 1) * 4, 4, mode as u64);
+        aplic.write(REG_TARGET + (irq - 1) * 4, 4, eiid as u64);
+        aplic.write(REG_SETIENUM, 4, irq as u64);
+    }
+
+    #[test]
+    fn level_triggered_source() {
+        let mut aplic = Aplic::new(0x0d00_0000);
+        assert_eq!(aplic.read(REG_DOMAINCFG, 4), 0x8000_0004);
+        route(&mut aplic, 3, SM_LEVEL1, 33);
+        aplic.set_input(3, true);
+        // Nothing goes out until the domain is enabled.
+        assert!(forwarded(&mut aplic).is_empty());
+        assert_eq!(aplic.read(REG_SETIP, 4), 1 << 3);
+        aplic.write(REG_DOMAINCFG, 4, DOMAINCFG_IE as u64);
+        let msi = Msi {
+            hart: 0,
+            guest: 0,
+            eiid: 33,
+        };
+        assert_eq!(forwarded(&mut aplic), [msi]);
+        // Still asserted: only the guest's retrigger makes it pending again.
+        assert!(forwarded(&mut aplic).is_empty());
+        aplic.write(REG_SETIPNUM_LE, 4, 3);
+        assert_eq!(forwarded(&mut aplic), [msi]);
+        // Deasserted: the retrigger is ignored.
+        aplic.set_input(3, false);
+        aplic.write(REG_SETIPNUM_LE, 4, 3);
+        assert!(forwarded(&mut aplic).is_empty());
+        assert_eq!(aplic.read(REG_IN_CLRIP, 4), 0);
+    }
+
+    #[test]
+    fn edge_triggered_source() {
+        let mut aplic = Aplic::new(0);
+        aplic.write(REG_DOMAINCFG, 4, DOMAINCFG_IE as u64);
+        route(&mut aplic, 40, SM_EDGE1, 7);
+        aplic.set_input(40, true);
+        aplic.set_input(40, false);
+        assert_eq!(forwarded(&mut aplic).len(), 1);
+        // Disabled sources stay pending.
+        aplic.write(REG_CLRIENUM, 4, 40);
+        aplic.write(REG_SETIPNUM, 4, 40);
+        assert!(forwarded(&mut aplic).is_empty());
+        assert_eq!(aplic.read(REG_SETIP + 4, 4), 1 << 8);
+        aplic.write(REG_SETIE + 4, 4, 1 << 8);
+        assert_eq!(forwarded(&mut aplic)[0].eiid, 7);
+    }
+
+    #[test]
+    fn inactive_sources() {
+        let mut aplic = Aplic::new(0);
+        aplic.write(REG_DOMAINCFG, 4, DOMAINCFG_IE as u64);
+        // Reserved mode 2 is inactive: no target, no pending bit.
+        route(&mut aplic, 1, 2, 5);
+        aplic.set_input(1, true);
+        aplic.write(REG_SETIPNUM, 4, 1);
+        assert!(forwarded(&mut aplic).is_empty());
+        assert_eq!(aplic.read(REG_SOURCECFG, 4), 0);
+        assert_eq!(aplic.read(REG_TARGET, 4), 0);
+        // Source 0 and sources past the last do not exist.
+        aplic.set_input(0, true);
+        aplic.set_input(NUM_SOURCES + 1, true);
+        aplic.write(REG_SETIENUM, 4, 0);
+        assert_eq!(aplic.read(REG_SETIE, 4), 0);
+        assert_eq!(aplic.read(REG_SOURCECFG + NUM_SOURCES * 4, 4), 0);
+    }
+}
@@ -0,0 +1,94 @@
+//! Boot information page of paravirt guests.
+//!
+//! Every VM gets a read-only page at [`BOOT_INFO_GPA`] describing it: RAM,
+//! vCPUs, the devices and their addresses and the hypercall ABI version
+//! (the [`BootInfo`] layout of `hvcall-abi`), so that an ArceOS-style
+//! guest can configure itself without a device tree parser. Guests find
+//! the page at its fixed address or through the GET_BOOT_INFO hypercall.
+//!
+//! The page is a host frame filled when the VM is built. The first read
+//! maps it read-only into the guest; writes trap and are ignored, as are
+//! reads that cannot be mapped, which [`MmioDevice::read`] serves.
+
+#![allow(dead_code)]
+
+use axerrno::{AxError, AxResult};
+use axhal::mem::phys_to_virt;
+use axhal::paging::{MappingFlags, PageSize};
+use hvcall_abi::bootinfo::{BootInfo, SIZE};
+use memory_addr::PhysAddr;
+
+use crate::devices::mmio::MmioDevice;
+use crate::gspace::{GuestSpace, alloc_frame, dealloc_frame};
+
+/// Guest physical address of the page.
+pub const BOOT_INFO_GPA: usize = hvcall_abi::bootinfo::GPA as usize;
+
+/// The boot information page of a VM.
+pub struct BootInfoPage {
+    /// The host frame holding the page.
+    frame: PhysAddr,
+    /// The frame is mapped into the guest.
+    mapped: bool,
+}
+
+impl BootInfoPage {
+    /// Creates the page holding `info`.
+    pub fn new(info: &BootInfo) -> AxResult<Self> {
+        let frame = alloc_frame(PageSize::Size4K).ok_or(AxError::NoMemory)?;
+        let bytes = info.as_bytes();
+        unsafe {
+            core::ptr::copy_nonoverlapping(
+                bytes.as_ptr(),
+                phys_to_virt(frame).as_mut_ptr(),
+                bytes.len(),
+            )
+        };
+        Ok(Self {
+            frame,
+            mapped: false,
+        })
+    }
+
+    fn bytes(&self) -> &[u8] {
+        unsafe { core::slice::from_raw_parts(phys_to_virt(self.frame).as_ptr(), SIZE) }
+    }
+}
+
+impl MmioDevice for BootInfoPage {
+    fn base(&self) -> usize {
+        BOOT_INFO_GPA
+    }
+
+    fn size(&self) -> usize {
+        SIZE
+    }
+
+    fn read(&mut self, _space: &mut GuestSpace, offset: usize, width: usize) -> u64 {
+        let bytes = self.bytes();
+        let mut value = 0u64;
+        for i in (0..width.min(8)).rev() {
+            value = value << 8 | bytes.get(offset + i).copied().unwrap_or(0) as u64;
+        }
+        value
+    }
+
+    fn write(&mut self, _space: &mut GuestSpace, _offset: usize, _width: usize, _value: u64) {}
+
+    fn map_on_fault(&mut self, space: &mut GuestSpace, _addr: usize, is_write: bool) -> bool {
+        if is_write || self.mapped {
+            return false;
+        }
+        let flags = MappingFlags::READ | MappingFlags::USER;
+        self.mapped = space
+            .map_linear(BOOT_INFO_GPA.into(), self.frame, SIZE, flags)
+            .is_ok();
+        self.mapped
+    }
+}
+
+impl Drop for BootInfoPage {
+    fn drop(&mut self) {
+        dealloc_frame(self.frame, PageSize::Size4K);
+    }
+}
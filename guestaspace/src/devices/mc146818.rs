@@ -0,0 +1,192 @@
+//! MC146818 CMOS real-time clock of the PC, at I/O ports `0x70`/`0x71`.
+//!
+//! The guest selects a register through the index port and reads or writes
+//! it through the data port. The time and date registers show the host
+//! wall-clock time ([`crate::wallclock`]) in BCD or binary and in 12 or 24
+//! hour mode, as status register B selects. Writing them while the SET bit
+//! is on and clearing it sets the guest's clock, kept as an offset to the
+//! host time. The update-in-progress flag never shows, the alarm and
+//! periodic interrupts are not raised (IRQ 8 is not wired up), and the
+//! registers past the clock are plain CMOS RAM, zero at start.
+
+#![allow(dead_code)]
+
+use crate::devices::mmio::MmioDevice;
+use crate::gspace::GuestSpace;
+use crate::wallclock::{self, DateTime};
+
+/// First I/O port: the index port, followed by the data port.
+pub const CMOS_PORT: usize = 0x70;
+/// Number of I/O ports.
+pub const CMOS_PORTS: usize = 2;
+
+const REG_SECONDS: usize = 0x00;
+const REG_MINUTES: usize = 0x02;
+const REG_HOURS: usize = 0x04;
+const REG_WEEKDAY: usize = 0x06;
+const REG_DAY: usize = 0x07;
+const REG_MONTH: usize = 0x08;
+const REG_YEAR: usize = 0x09;
+const REG_STATUS_A: usize = 0x0A;
+const REG_STATUS_B: usize = 0x0B;
+const REG_STATUS_C: usize = 0x0C;
+const REG_STATUS_D: usize = 0x0D;
+const REG_CENTURY: usize = 0x32;
+
+/// Status A: 32.768 kHz time base, 1024 Hz periodic rate.
+const STATUS_A_DEFAULT: u8 = 0x26;
+/// Status B: the clock is stopped for setting.
+const STATUS_B_SET: u8 = 0x80;
+/// Status B: binary instead of BCD values.
+const STATUS_B_BINARY: u8 = 0x04;
+/// Status B: 24-hour mode.
+const STATUS_B_24H: u8 = 0x02;
+/// Status D: the RAM and time are valid.
+const STATUS_D_VRT: u8 = 0x80;
+/// The hours register bit for PM in 12-hour mode.
+const HOURS_PM: u8 = 0x80;
+
+/// An emulated MC146818.
+pub struct Mc146818 {
+    /// Register selected through the index port.
+    index: usize,
+    ram: [u8; 128],
+    /// Guest time minus host time, in seconds.
+    offset: i64,
+    /// Time written while SET is on, applied when it is cleared.
+    staged: Option<DateTime>,
+}
+
+impl Mc146818 {
+    /// Creates an RTC showing the host time in BCD, 24-hour mode.
+    pub fn new() -> Self {
+        let mut ram = [0; 128];
+        ram[REG_STATUS_A] = STATUS_A_DEFAULT;
+        ram[REG_STATUS_B] = STATUS_B_24H;
+        Self {
+            index: 0,
+            ram,
+            offset: 0,
+            staged: None,
+        }
+    }
+
+    fn now(&self) -> DateTime {
+        let secs = wallclock::now().as_secs() as i64 + self.offset;
+        DateTime::from_unix(secs.max(0) as u64)
+    }
+
+    fn encode(&self, value: u32) -> u8 {
+        if self.ram[REG_STATUS_B] & STATUS_B_BINARY != 0 {
+            value as u8
+        } else {
+            (((value / 10) << 4) | (value % 10)) as u8
+        }
+    }
+
+    fn decode(&self, value: u8) -> u32 {
+        if self.ram[REG_STATUS_B] & STATUS_B_BINARY != 0 {
+            value as u32
+        } else {
+            (value >> 4) as u32 * 10 + (value & 0xF) as u32
+        }
+    }
+
+    fn encode_hours(&self, hour: u32) -> u8 {
+        if self.ram[REG_STATUS_B] & STATUS_B_24H != 0 {
+            return self.encode(hour);
+        }
+        let pm = if hour >= 12 { HOURS_PM } else { 0 };
+        self.encode((hour + 11) % 12 + 1) | pm
+    }
+
+    fn decode_hours(&self, value: u8) -> u32 {
+        if self.ram[REG_STATUS_B] & STATUS_B_24H != 0 {
+            return self.decode(value);
+        }
+        let hour = self.decode(value & !HOURS_PM) % 12;
+        if value & HOURS_PM != 0 {
+            hour + 12
+        } else {
+            hour
+        }
+    }
+
+    fn read_reg(&mut self, reg: usize) -> u8 {
+        let time = self.staged.unwrap_or_else(|| self.now());
+        match reg {
+            REG_SECONDS => self.encode(time.second),
+            REG_MINUTES => self.encode(time.minute),
+            REG_HOURS => self.encode_hours(time.hour),
+            REG_WEEKDAY => self.encode(time.weekday()),
+            REG_DAY => self.encode(time.day),
+            REG_MONTH => self.encode(time.month),
+            REG_YEAR => self.encode(time.year % 100),
+            REG_CENTURY => self.encode(time.year / 100),
+            // No interrupt flags are ever raised.
+            REG_STATUS_C => 0,
+            REG_STATUS_D => STATUS_D_VRT,
+            _ => self.ram[reg],
+        }
+    }
+
+    fn write_reg(&mut self, reg: usize, value: u8) {
+        match reg {
+            REG_SECONDS | REG_MINUTES | REG_HOURS | REG_DAY | REG_MONTH | REG_YEAR
+            | REG_CENTURY => {
+                // Only a stopped clock can be set.
+                let Some(mut time) = self.staged else {
+                    return;
+                };
+                match reg {
+                    REG_SECONDS => time.second = self.decode(value),
+                    REG_MINUTES => time.minute = self.decode(value),
+                    REG_HOURS => time.hour = self.decode_hours(value),
+                    REG_DAY => time.day = self.decode(value),
+                    REG_MONTH => time.month = self.decode(value),
+                    REG_YEAR => time.year = time.year / 100 * 100 + self.decode(value),
+                    _ => time.year = self.decode(value) * 100 + time.year % 100,
+                }
+                self.staged = Some(time);
+            }
+            REG_STATUS_A => self.ram[reg] = value & 0x7F,
+            REG_STATUS_B => {
+                if value & STATUS_B_SET != 0 && self.staged.is_none() {
+                    self.staged = Some(self.now());
+                } else if value & STATUS_B_SET == 0
+                    && let Some(time) = self.staged.take()
+                {
+                    self.offset = time.to_unix() as i64 - wallclock::now().as_secs() as i64;
+                }
+                self.ram[reg] = value;
+            }
+            REG_WEEKDAY | REG_STATUS_C | REG_STATUS_D => {}
+            _ => self.ram[reg] = value,
+        }
+    }
+}
+
+impl MmioDevice for Mc146818 {
+    fn base(&self) -> usize {
+        CMOS_PORT
+    }
+
+    fn size(&self) -> usize {
+        CMOS_PORTS
+    }
+
+    fn read(&mut self, _space: &mut GuestSpace, offset: usize, _width: usize) -> u64 {
+        match offset {
+            0 => self.index as u64,
+            _ => self.read_reg(self.index) as u64,
+        }
+    }
+
+    fn write(&mut self, _space: &mut GuestSpace, offset: usize, _width: usize, value: u64) {
+        match offset {
+            // Bit 7 masks the NMI, which the guest does not have.
+            0 => self.index = value as usize & 0x7F,
+            _ => self.write_reg(self.index, value as u8),
+        }
+    }
+}
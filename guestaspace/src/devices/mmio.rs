@@ -0,0 +1,659 @@
+//! Trapped guest accesses to emulated device registers.
+//!
+//! Device models implement [`MmioDevice`] and are registered on an
+//! [`MmioBus`], one per VM. A stage-2 fault inside a device is decoded into
+//! an [`MmioAccess`] from the information the hardware reports about the
+//! faulting load or store:
+//!
+//! - riscv64: the transformed instruction in `htinst` or, if the hart left
+//!   it zero, the faulting instruction itself ([`decode_riscv_insn`]);
+//! - aarch64: the data abort syndrome (`ESR_EL1.ISS` with `ISV` set) or,
+//!   for the accesses it does not describe, the faulting instruction
+//!   ([`decode_a64`]).
+//!
+//! x86_64 reports no such information for nested page faults: the faulting
+//! instruction is fetched and decoded instead (`x86_64::mmio`). Most of its
+//! devices sit in the I/O port space, which SVM intercepts with the port,
+//! width and direction decoded; the same bus type serves that space with
+//! port numbers as addresses. (The local APIC decodes the faulting MOV
+//! itself: see `x86_64::lapic`.)
+
+#![allow(dead_code)]
+
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+
+use axerrno::{AxError, AxResult};
+
+use crate::exitlog::IoValue;
+use crate::gspace::GuestSpace;
+use crate::migrate::{MigrationError, StateReader, StateWriter};
+
+/// An emulated device occupying `[base, base + size)` of a bus.
+pub trait MmioDevice {
+    /// First address of the device.
+    fn base(&self) -> usize;
+    /// Size of the register window in bytes.
+    fn size(&self) -> usize;
+    /// Emulates a read of `width` bytes at `offset` into the window.
+    fn read(&mut self, space: &mut GuestSpace, offset: usize, width: usize) -> u64;
+    /// Emulates a write of `width` bytes at `offset` into the window.
+    fn write(&mut self, space: &mut GuestSpace, offset: usize, width: usize, value: u64);
+    /// Maps the page containing `addr` into `space` instead of trapping on
+    /// it, if the device allows that for this access. Returns `true` if the
+    /// page was mapped.
+    fn map_on_fault(&mut self, _space: &mut GuestSpace, _addr: usize, _is_write: bool) -> bool {
+        false
+    }
+    /// Updates the device state from the host side before a guest entry.
+    fn poll(&mut self, _space: &mut GuestSpace) {}
+    /// Checks whether the device has an interrupt pending.
+    fn irq_pending(&self) -> bool {
+        false
+    }
+    /// Appends the device's state to `out` for a migration. Returns `false`
+    /// if it has none to migrate.
+    fn save_state(&self, _out: &mut StateWriter) -> bool {
+        false
+    }
+    /// Loads the state the device saved on the migration source.
+    fn restore_state(&mut self, _state: &mut StateReader) -> Result<(), MigrationError> {
+        Err(MigrationError::Corrupt)
+    }
+}
+
+/// A decoded guest load or store.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct MmioAccess {
+    /// Guest physical address accessed.
+    pub addr: usize,
+    /// Access size in bytes: 1, 2, 4 or 8.
+    pub width: usize,
+    /// Store (`true`) or load.
+    pub is_write: bool,
+    /// Register written by a load or read by a store.
+    pub reg: usize,
+    /// A load sign-extends the value into the register.
+    pub sign_extend: bool,
+    /// A load writes only the low 32 bits of the register (zero-extended).
+    pub reg_32bit: bool,
+    /// Length of the faulting instruction in bytes.
+    pub insn_len: usize,
+}
+
+impl MmioAccess {
+    /// Converts a value read from a device into the register value a load
+    /// leaves behind.
+    pub fn load_value(&self, value: u64) -> u64 {
+        let bits = self.width * 8;
+        let mut value = if bits < 64 {
+            value & ((1 << bits) - 1)
+        } else {
+            value
+        };
+        if self.sign_extend && bits < 64 {
+            let shift = 64 - bits;
+            value = (((value << shift) as i64) >> shift) as u64;
+        }
+        if self.reg_32bit {
+            value &= 0xFFFF_FFFF;
+        }
+        value
+    }
+
+    /// Converts a register value into the value a store writes.
+    pub fn store_value(&self, reg: u64) -> u64 {
+        if self.width < 8 {
+            reg & ((1 << (self.width * 8)) - 1)
+        } else {
+            reg
+        }
+    }
+}
+
+/// The emulated devices of one VM on one bus.
+#[derive(Default)]
+pub struct MmioBus {
+    devices: Vec<Box<dyn MmioDevice>>,
+    /// The accesses emulated since the last [`MmioBus::take_io`], while the
+    /// VM's exits are recorded.
+    io_log: Option<Vec<IoValue>>,
+}
+
+impl MmioBus {
+    /// Creates an empty bus.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a device; its window must not overlap another device's.
+    pub fn add(&mut self, dev: Box<dyn MmioDevice>) -> AxResult {
+        let (base, end) = (dev.base(), dev.base() + dev.size());
+        if self
+            .devices
+            .iter()
+            .any(|d| base < d.base() + d.size() && d.base() < end)
+        {
+            return Err(AxError::AlreadyExists);
+        }
+        self.devices.push(dev);
+        Ok(())
+    }
+
+    /// Checks whether a device claims `addr`.
+    pub fn contains(&self, addr: usize) -> bool {
+        self.find(addr).is_some()
+    }
+
+    /// Lets the device at `addr` map the page instead of trapping, see
+    /// [`MmioDevice::map_on_fault`].
+    pub fn map_on_fault(&mut self, space: &mut GuestSpace, addr: usize, is_write: bool) -> bool {
+        match self.find(addr) {
+            Some(i) => self.devices[i].map_on_fault(space, addr, is_write),
+            None => false,
+        }
+    }
+
+    /// Emulates `access`. `reg` is the value of the accessed register (the
+    /// data of a store); returns the new register value for a load.
+    pub fn emulate(
+        &mut self,
+        space: &mut GuestSpace,
+        access: &MmioAccess,
+        reg: u64,
+    ) -> AxResult<Option<u64>> {
+        let i = self.find(access.addr).ok_or(AxError::BadAddress)?;
+        let dev = &mut self.devices[i];
+        let offset = access.addr - dev.base();
+        let value = if access.is_write {
+            let value = access.store_value(reg);
+            dev.write(space, offset, access.width, value);
+            value
+        } else {
+            dev.read(space, offset, access.width)
+        };
+        if let Some(log) = &mut self.io_log {
+            log.push(IoValue {
+                addr: access.addr as u64,
+                width: access.width as u8,
+                is_write: access.is_write,
+                value,
+            });
+        }
+        Ok((!access.is_write).then(|| access.load_value(value)))
+    }
+
+    /// Starts logging the emulated accesses with their values, for the
+    /// exit recorder.
+    pub fn log_io(&mut self) {
+        self.io_log = Some(Vec::new());
+    }
+
+    /// Takes the accesses logged since the last call.
+    pub fn take_io(&mut self) -> Vec<IoValue> {
+        self.io_log
+            .as_mut()
+            .map(core::mem::take)
+            .unwrap_or_default()
+    }
+
+    /// Polls every device, see [`MmioDevice::poll`].
+    pub fn poll(&mut self, space: &mut GuestSpace) {
+        self.devices.iter_mut().for_each(|d| d.poll(space));
+    }
+
+    /// Checks whether any device has an interrupt pending.
+    pub fn irq_pending(&self) -> bool {
+        self.devices.iter().any(|d| d.irq_pending())
+    }
+
+    /// Returns the interrupt line of every device, by base address.
+    pub fn irq_lines(&self) -> impl Iterator<Item = (usize, bool)> + '_ {
+        self.devices.iter().map(|d| (d.base(), d.irq_pending()))
+    }
+
+    /// Returns the state of every device that has one, by base address,
+    /// for a migration.
+    pub fn save_states(&self) -> Vec<(u64, Vec<u8>)> {
+        self.devices
+            .iter()
+            .filter_map(|d| {
+                let mut out = StateWriter::new();
+                d.save_state(&mut out)
+                    .then(|| (d.base() as u64, out.into_bytes()))
+            })
+            .collect()
+    }
+
+    /// Loads the state `data` that the device at `base` saved on the
+    /// migration source.
+    pub fn restore_state(&mut self, base: u64, data: &[u8]) -> Result<(), MigrationError> {
+        let dev = self
+            .devices
+            .iter_mut()
+            .find(|d| d.base() as u64 == base)
+            .ok_or(MigrationError::Device(base))?;
+        let mut state = StateReader::new(data);
+        match dev.restore_state(&mut state) {
+            Ok(()) if state.is_empty() => Ok(()),
+            _ => Err(MigrationError::Device(base)),
+        }
+    }
+
+    fn find(&self, addr: usize) -> Option<usize> {
+        self.devices
+            .iter()
+            .position(|d| addr >= d.base() && addr - d.base() < d.size())
+    }
+}
+
+/// Decodes the guest load or store that faulted at `addr` from the
+/// transformed instruction in `htinst`.
+///
+/// Returns `None` if `htinst` holds no transformed load or store: it may be
+/// zero on implementations that do not report it, or a pseudoinstruction
+/// for an implicit access of the guest's page table walk.
+#[cfg(any(target_arch = "riscv64", test, fuzzing))]
+pub fn decode_htinst(htinst: usize, addr: usize) -> Option<MmioAccess> {
+    // Bit 0 is set for transformed instructions; bit 1 is cleared if the
+    // original instruction was a compressed one.
+    if htinst & 1 == 0 {
+        return None;
+    }
+    let insn_len = if htinst & 2 != 0 { 4 } else { 2 };
+    decode_riscv_load_store(htinst as u32, addr, insn_len)
+}
+
+/// Decodes the guest load or store `insn`, fetched from guest memory, that
+/// faulted at `addr`: a 32-bit integer load or store, or one of the
+/// compressed C.LW, C.LD, C.SW, C.SD and their stack-pointer forms.
+///
+/// Returns `None` for any other instruction, such as floating-point or
+/// atomic accesses.
+#[cfg(any(target_arch = "riscv64", test, fuzzing))]
+pub fn decode_riscv_insn(insn: u32, addr: usize) -> Option<MmioAccess> {
+    const LOAD: u32 = 0b000_0011;
+    const STORE: u32 = 0b010_0011;
+    if insn & 3 == 3 {
+        return decode_riscv_load_store(insn, addr, 4);
+    }
+    // Expand the compressed access to the equivalent one; the offset does
+    // not matter, the address is known.
+    let insn = insn & 0xFFFF;
+    let funct3 = insn >> 13;
+    let width3 = funct3 & 3;
+    let reg_prime = ((insn >> 2) & 7) + 8;
+    let expanded = match (insn & 3, funct3) {
+        // C.LW, C.LD: rd' = bits 4:2.
+        (0b00, 0b010 | 0b011) => (width3 << 12) | (reg_prime << 7) | LOAD,
+        // C.SW, C.SD: rs2' = bits 4:2.
+        (0b00, 0b110 | 0b111) => (reg_prime << 20) | (width3 << 12) | STORE,
+        // C.LWSP, C.LDSP: rd = bits 11:7, not x0.
+        (0b10, 0b010 | 0b011) if (insn >> 7) & 0x1F != 0 => {
+            (width3 << 12) | (((insn >> 7) & 0x1F) << 7) | LOAD
+        }
+        // C.SWSP, C.SDSP: rs2 = bits 6:2.
+        (0b10, 0b110 | 0b111) => (((insn >> 2) & 0x1F) << 20) | (width3 << 12) | STORE,
+        _ => return None,
+    };
+    decode_riscv_load_store(expanded, addr, 2)
+}
+
+/// Decodes the integer load or store `insn`, whose length in guest memory
+/// is `insn_len`. Bits 1:0 are ignored, as `htinst` reuses them.
+#[cfg(any(target_arch = "riscv64", test, fuzzing))]
+fn decode_riscv_load_store(insn: u32, addr: usize, insn_len: usize) -> Option<MmioAccess> {
+    let funct3 = (insn >> 12) & 7;
+    let (is_write, reg) = match (insn >> 2) & 0x1F {
+        0b00000 => (false, (insn >> 7) & 0x1F),
+        0b01000 => (true, (insn >> 20) & 0x1F),
+        _ => return None,
+    };
+    if (is_write && funct3 > 3) || funct3 == 7 {
+        return None;
+    }
+    Some(MmioAccess {
+        addr,
+        width: 1 << (funct3 & 3),
+        is_write,
+        reg: reg as usize,
+        // LB/LH/LW sign-extend, LBU/LHU/LWU (funct3 4..6) do not.
+        sign_extend: !is_write && funct3 < 3,
+        reg_32bit: false,
+        insn_len,
+    })
+}
+
+/// Decodes the guest load or store that faulted at `addr` from the data
+/// abort syndrome `esr`.
+///
+/// Returns `None` if the syndrome is not valid (`ISV` clear), as for
+/// load/store pairs and accesses with register write-back.
+#[cfg(any(target_arch = "aarch64", test, fuzzing))]
+pub fn decode_esr(esr: u64, addr: usize) -> Option<MmioAccess> {
+    const ISV: u64 = 1 << 24;
+    if esr & ISV == 0 {
+        return None;
+    }
+    let is_write = esr & (1 << 6) != 0;
+    Some(MmioAccess {
+        addr,
+        width: 1 << ((esr >> 22) & 3),
+        is_write,
+        reg: ((esr >> 16) & 0x1F) as usize,
+        // SSE only applies to loads.
+        sign_extend: !is_write && esr & (1 << 21) != 0,
+        reg_32bit: esr & (1 << 15) == 0,
+        insn_len: if esr & (1 << 25) != 0 { 4 } else { 2 },
+    })
+}
+
+/// A load or store decoded from an A64 instruction, for data aborts whose
+/// syndrome does not describe it.
+#[cfg(any(target_arch = "aarch64", test, fuzzing))]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct A64LoadStore {
+    /// The access of the first (or only) register.
+    pub access: MmioAccess,
+    /// LDP/STP: the second register, accessed right after the first.
+    pub reg2: Option<usize>,
+    /// Pre- or post-index: the base register (31 is SP) and the offset
+    /// added to it.
+    pub writeback: Option<(usize, i64)>,
+}
+
+#[cfg(any(target_arch = "aarch64", test, fuzzing))]
+impl A64LoadStore {
+    /// Returns the accesses in order: the first register at `access.addr`,
+    /// then the second one of a pair after it.
+    pub fn accesses(&self) -> impl Iterator<Item = MmioAccess> + '_ {
+        core::iter::once(self.access).chain(self.reg2.map(|reg| MmioAccess {
+            addr: self.access.addr.wrapping_add(self.access.width),
+            reg,
+            ..self.access
+        }))
+    }
+}
+
+/// Decodes the A64 load or store `insn` that faulted at `addr`, the address
+/// of its first byte, when the syndrome has no valid ISS: general-purpose
+/// register loads and stores with pre- or post-index writeback
+/// (`LDR Xt, [Xn], #imm`, `STRB Wt, [Xn, #imm]!`, ...) and register pairs
+/// (`LDP`, `STP`, `LDPSW`), with or without writeback. Both registers of a
+/// pair must be in the device.
+///
+/// Returns `None` for any other instruction, such as SIMD&FP, exclusive or
+/// atomic accesses.
+#[cfg(any(target_arch = "aarch64", test, fuzzing))]
+pub fn decode_a64(insn: u32, addr: usize) -> Option<A64LoadStore> {
+    let rt = (insn & 0x1F) as usize;
+    let rn = ((insn >> 5) & 0x1F) as usize;
+    if insn & 0x3B20_0000 == 0x3800_0000 {
+        // Load/store register, imm9 (V = 0): only the indexed forms write
+        // back; the others have a valid syndrome.
+        if (insn >> 10) & 1 == 0 {
+            return None;
+        }
+        let size = insn >> 30;
+        let opc = (insn >> 22) & 3;
+        if size == 3 && opc >= 2 {
+            return None;
+        }
+        let imm = ((insn << 11) as i32 >> 23) as i64;
+        return Some(A64LoadStore {
+            access: MmioAccess {
+                addr,
+                width: 1 << size,
+                is_write: opc == 0,
+                reg: rt,
+                sign_extend: opc >= 2,
+                // LDRS into a W register (opc 0b11) is sign-extended to 32
+                // bits only.
+                reg_32bit: opc == 3,
+                insn_len: 4,
+            },
+            reg2: None,
+            writeback: Some((rn, imm)),
+        });
+    }
+    if insn & 0x3C00_0000 == 0x2800_0000 {
+        // Load/store register pair (V = 0): opc 00 is 32-bit, 01 LDPSW,
+        // 10 64-bit.
+        let opc = insn >> 30;
+        let is_load = insn & (1 << 22) != 0;
+        let width = match opc {
+            0b00 | 0b01 => 4,
+            0b10 => 8,
+            _ => return None,
+        };
+        if opc == 0b01 && !is_load {
+            return None;
+        }
+        let imm = ((insn << 10) as i32 >> 25) as i64 * width as i64;
+        let writeback = match (insn >> 23) & 3 {
+            0b01 | 0b11 => Some((rn, imm)),
+            _ => None,
+        };
+        return Some(A64LoadStore {
+            access: MmioAccess {
+                addr,
+                width,
+                is_write: !is_load,
+                reg: rt,
+                sign_extend: opc == 0b01,
+                reg_32bit: false,
+                insn_len: 4,
+            },
+            reg2: Some(((insn >> 10) & 0x1F) as usize),
+            writeback,
+        });
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use memory_addr::VirtAddr;
+
+    /// A device with one 64-bit register repeated over its window.
+    struct Reg {
+        base: usize,
+        value: u64,
+    }
+
+    impl MmioDevice for Reg {
+        fn base(&self) -> usize {
+            self.base
+        }
+        fn size(&self) -> usize {
+            0x100
+        }
+        fn read(&mut self, _space: &mut GuestSpace, _offset: usize, _width: usize) -> u64 {
+            self.value
+        }
+        fn write(&mut self, _space: &mut GuestSpace, _offset: usize, _width: usize, value: u64) {
+            self.value = value;
+        }
+    }
+
+    fn access(addr: usize, width: usize, is_write: bool) -> MmioAccess {
+        MmioAccess {
+            addr,
+            width,
+            is_write,
+            reg: 10,
+            sign_extend: false,
+            reg_32bit: false,
+            insn_len: 4,
+        }
+    }
+
+    #[test]
+    fn load_and_store_values() {
+        let byte = MmioAccess {
+            sign_extend: true,
+            ..access(0, 1, false)
+        };
+        assert_eq!(byte.load_value(0x1280), 0xFFFF_FFFF_FFFF_FF80);
+        let word = MmioAccess {
+            reg_32bit: true,
+            ..byte
+        };
+        assert_eq!(word.load_value(0x80), 0xFFFF_FF80);
+        assert_eq!(access(0, 4, false).load_value(u64::MAX), 0xFFFF_FFFF);
+        assert_eq!(access(0, 8, false).load_value(u64::MAX), u64::MAX);
+        assert_eq!(access(0, 2, true).store_value(0x1234_5678), 0x5678);
+        assert_eq!(access(0, 8, true).store_value(u64::MAX), u64::MAX);
+    }
+
+    #[test]
+    fn bus_routes_by_address() {
+        let mut space = GuestSpace::new(VirtAddr::from(0x8000_0000), 0x1000).unwrap();
+        let mut bus = MmioBus::new();
+        bus.add(Box::new(Reg {
+            base: 0x1000,
+            value: 0,
+        }))
+        .unwrap();
+        bus.add(Box::new(Reg {
+            base: 0x2000,
+            value: 7,
+        }))
+        .unwrap();
+        assert_eq!(
+            bus.add(Box::new(Reg {
+                base: 0x10F8,
+                value: 0
+            }))
+            .unwrap_err(),
+            AxError::AlreadyExists
+        );
+        assert!(bus.contains(0x10FF));
+        assert!(!bus.contains(0x1100));
+
+        let store = access(0x1008, 2, true);
+        assert_eq!(bus.emulate(&mut space, &store, 0xABCD_EF01).unwrap(), None);
+        let load = access(0x1000, 4, false);
+        assert_eq!(bus.emulate(&mut space, &load, 0).unwrap(), Some(0xEF01));
+        assert_eq!(
+            bus.emulate(&mut space, &access(0x2010, 8, false), 0)
+                .unwrap(),
+            Some(7)
+        );
+        assert_eq!(
+            bus.emulate(&mut space, &access(0x3000, 8, false), 0)
+                .unwrap_err(),
+            AxError::BadAddress
+        );
+    }
+
+    #[test]
+    fn bus_logs_io_values() {
+        let mut space = GuestSpace::new(VirtAddr::from(0x8000_0000), 0x1000).unwrap();
+        let mut bus = MmioBus::new();
+        bus.add(Box::new(Reg {
+            base: 0x1000,
+            value: 0,
+        }))
+        .unwrap();
+        bus.emulate(&mut space, &access(0x1000, 4, true), 1)
+            .unwrap();
+        assert!(bus.take_io().is_empty());
+
+        bus.log_io();
+        bus.emulate(&mut space, &access(0x1000, 1, true), 0x1FF)
+            .unwrap();
+        bus.emulate(&mut space, &access(0x1004, 4, false), 0)
+            .unwrap();
+        let io = bus.take_io();
+        assert_eq!(io.len(), 2);
+        assert_eq!((io[0].addr, io[0].width, io[0].is_write), (0x1000, 1, true));
+        assert_eq!(io[0].value, 0xFF);
+        assert_eq!(
+            (io[1].addr, io[1].is_write, io[1].value),
+            (0x1004, false, 0xFF)
+        );
+        assert!(bus.take_io().is_empty());
+    }
+
+    #[test]
+    fn riscv_loads_and_stores() {
+        // lw a0, 0(a1)
+        let lw = decode_riscv_insn(0x0005_A503, 0x1000).unwrap();
+        assert_eq!((lw.width, lw.is_write, lw.reg), (4, false, 10));
+        assert!(lw.sign_extend);
+        // lbu a0, 0(a1)
+        let lbu = decode_riscv_insn(0x0005_C503, 0x1000).unwrap();
+        assert_eq!((lbu.width, lbu.sign_extend), (1, false));
+        // sd a2, 8(a0)
+        let sd = decode_riscv_insn(0x00C5_3423, 0x1000).unwrap();
+        assert_eq!(
+            (sd.width, sd.is_write, sd.reg, sd.insn_len),
+            (8, true, 12, 4)
+        );
+        // flw fa0, 0(a1) is not an integer load.
+        assert_eq!(decode_riscv_insn(0x0005_A507, 0x1000), None);
+    }
+
+    #[test]
+    fn riscv_compressed_accesses() {
+        // c.lw a0, 0(a1)
+        let lw = decode_riscv_insn(0x4188, 0x1000).unwrap();
+        assert_eq!(
+            (lw.width, lw.is_write, lw.reg, lw.insn_len),
+            (4, false, 10, 2)
+        );
+        // c.sdsp ra, 0(sp)
+        let sdsp = decode_riscv_insn(0xE006, 0x1000).unwrap();
+        assert_eq!((sdsp.width, sdsp.is_write, sdsp.reg), (8, true, 1));
+        // c.lwsp with rd = x0 is reserved.
+        assert_eq!(decode_riscv_insn(0x4002, 0x1000), None);
+    }
+
+    #[test]
+    fn riscv_transformed_instructions() {
+        assert_eq!(decode_htinst(0, 0x1000), None);
+        assert_eq!(decode_htinst(0x0005_A503, 0x1000).unwrap().insn_len, 4);
+        // Bit 1 clear: the guest's instruction was compressed.
+        let compressed = decode_htinst(0x0005_A501, 0x1000).unwrap();
+        assert_eq!((compressed.reg, compressed.insn_len), (10, 2));
+    }
+
+    #[test]
+    fn aarch64_syndromes() {
+        const ISV: u64 = 1 << 24;
+        const IL: u64 = 1 << 25;
+        // ldr x1, [x0]: SAS = 3, SRT = 1, SF set.
+        let esr = ISV | IL | 3 << 22 | 1 << 16 | 1 << 15;
+        let ldr = decode_esr(esr, 0x1000).unwrap();
+        assert_eq!(
+            (ldr.width, ldr.is_write, ldr.reg, ldr.insn_len),
+            (8, false, 1, 4)
+        );
+        assert!(!ldr.reg_32bit);
+        // strh w2, [x0]: SAS = 1, SRT = 2, WnR set.
+        let strh = decode_esr(ISV | IL | 1 << 22 | 2 << 16 | 1 << 6, 0x1000).unwrap();
+        assert_eq!((strh.width, strh.is_write, strh.reg), (2, true, 2));
+        assert!(strh.reg_32bit);
+        assert_eq!(decode_esr(esr & !ISV, 0x1000), None);
+    }
+
+    #[test]
+    fn aarch64_writeback_and_pairs() {
+        // ldr x1, [x0], #8
+        let ldr = decode_a64(0xF840_8401, 0x1000).unwrap();
+        assert_eq!((ldr.access.width, ldr.access.is_write), (8, false));
+        assert_eq!((ldr.reg2, ldr.writeback), (None, Some((0, 8))));
+        // stp x1, x2, [sp, #-16]!
+        let stp = decode_a64(0xA9BF_0BE1, 0x1000).unwrap();
+        assert_eq!(stp.writeback, Some((31, -16)));
+        let accesses: Vec<_> = stp
+            .accesses()
+            .map(|a| (a.addr, a.reg, a.is_write))
+            .collect();
+        assert_eq!(accesses, [(0x1000, 1, true), (0x1008, 2, true)]);
+        // ldr x1, [x0] has a valid syndrome and is not decoded here.
+        assert_eq!(decode_a64(0xF940_0001, 0x1000), None);
+    }
+}
@@ -0,0 +1,24 @@
+//! Devices emulated by the hypervisor for its guests.
+//!
+//! The MMIO bus and the virtio transports and virtqueues are independent of
+//! the host and compile for host unit tests too, as does the APLIC model
+//! of riscv64 guests.
+
+#[cfg(any(all(feature = "axstd", target_arch = "riscv64"), test))]
+pub mod aplic;
+#[cfg(feature = "axstd")]
+pub mod bootinfo;
+#[cfg(feature = "axstd")]
+pub mod mc146818;
+pub mod mmio;
+#[cfg(feature = "axstd")]
+pub mod pci;
+#[cfg(feature = "axstd")]
+pub mod pflash;
+#[cfg(feature = "axstd")]
+pub mod pl031;
+#[cfg(feature = "axstd")]
+pub mod uart16550;
+pub mod virtio;
+#[cfg(feature = "axstd")]
+pub mod worker;
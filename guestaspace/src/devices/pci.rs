@@ -0,0 +1,291 @@
+//! A virtual PCI host bridge with configuration mechanism #1.
+//!
+//! The guest selects a register of bus 0 by writing its address to
+//! `CONFIG_ADDRESS` (port `0xCF8`) and accesses it through `CONFIG_DATA`
+//! (ports `0xCFC`-`0xCFF`), so a guest that enumerates PCI finds the host
+//! bridge at device 0 and the hypervisor's devices behind it, one function
+//! per device number. Every device has a single I/O BAR, allocated from the
+//! I/O window [`PCI_IO_WINDOW`] the way firmware would and left enabled,
+//! and shares the INTx line [`PCI_INTX_LINE`]. The guest can size the BAR,
+//! move it and turn off I/O decoding and INTx through the command register.
+//!
+//! The bridge also decodes the I/O window, where it forwards accesses to
+//! the device whose BAR they hit; others read as all ones. The hypervisor
+//! only intercepts the window, so a BAR moved out of it is not reached.
+//! There is no memory space, no capability list and no ECAM.
+
+#![allow(dead_code)]
+
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use core::ops::Range;
+
+use axerrno::{AxError, AxResult};
+
+use crate::devices::mmio::MmioDevice;
+use crate::gspace::GuestSpace;
+
+/// `CONFIG_ADDRESS`, followed by `CONFIG_DATA` at `0xCFC`.
+pub const PCI_CONFIG_PORT: usize = 0xCF8;
+/// Number of configuration ports.
+pub const PCI_CONFIG_PORTS: usize = 8;
+/// The I/O ports the bridge forwards to device BARs.
+pub const PCI_IO_WINDOW: Range<usize> = 0xC000..0x1_0000;
+/// The interrupt line reported to the guest for all devices.
+pub const PCI_INTX_LINE: u8 = 11;
+
+/// Device numbers on bus 0; number 0 is the host bridge.
+const MAX_DEVICES: usize = 32;
+/// `CONFIG_ADDRESS` enable bit.
+const CONFIG_ENABLE: u32 = 1 << 31;
+
+const REG_ID: usize = 0x00;
+const REG_COMMAND: usize = 0x04;
+const REG_CLASS: usize = 0x08;
+const REG_HEADER: usize = 0x0C;
+const REG_BAR0: usize = 0x10;
+const REG_SUBSYSTEM: usize = 0x2C;
+const REG_INTERRUPT: usize = 0x3C;
+
+/// Command: I/O space decoding.
+const COMMAND_IO: u16 = 1 << 0;
+/// Command: bus mastering (DMA).
+const COMMAND_MASTER: u16 = 1 << 2;
+/// Command: INTx disabled.
+const COMMAND_INTX_DISABLE: u16 = 1 << 10;
+/// Command bits the guest can change.
+const COMMAND_WRITABLE: u16 = COMMAND_IO | COMMAND_MASTER | COMMAND_INTX_DISABLE;
+/// Status: INTx asserted.
+const STATUS_INTERRUPT: u16 = 1 << 3;
+/// BAR: I/O space.
+const BAR_IO: u32 = 1;
+/// Interrupt pin INTA#.
+const PIN_INTA: u8 = 1;
+
+/// What a guest reads from a function's identification registers.
+#[derive(Clone, Copy, Debug)]
+pub struct PciIds {
+    pub vendor: u16,
+    pub device: u16,
+    pub subsystem_vendor: u16,
+    pub subsystem: u16,
+    /// Class, subclass and programming interface.
+    pub class: u32,
+    pub revision: u8,
+}
+
+/// The host bridge itself: Intel 440FX, as on QEMU's `pc` machine.
+const HOST_BRIDGE_IDS: PciIds = PciIds {
+    vendor: 0x8086,
+    device: 0x1237,
+    subsystem_vendor: 0,
+    subsystem: 0,
+    class: 0x06_0000,
+    revision: 2,
+};
+
+/// A device behind the bridge.
+struct Function {
+    ids: PciIds,
+    command: u16,
+    /// I/O BAR: port base and the I/O space bit.
+    bar: u32,
+    bar_size: usize,
+    interrupt_line: u8,
+    dev: Box<dyn MmioDevice>,
+}
+
+impl Function {
+    /// Returns the port range the BAR decodes, if I/O decoding is on.
+    fn io_range(&self) -> Option<Range<usize>> {
+        let base = (self.bar & !BAR_IO) as usize;
+        (self.command & COMMAND_IO != 0).then_some(base..base + self.bar_size)
+    }
+
+    fn irq_pending(&self) -> bool {
+        self.command & COMMAND_INTX_DISABLE == 0 && self.dev.irq_pending()
+    }
+}
+
+/// The emulated host bridge and the devices on its bus.
+pub struct PciHost {
+    config_address: u32,
+    /// Device `i + 1` is `functions[i]`.
+    functions: Vec<Function>,
+    /// Next free port of the I/O window.
+    next_io: usize,
+}
+
+impl PciHost {
+    /// Creates a bridge without devices.
+    pub fn new() -> Self {
+        Self {
+            config_address: 0,
+            functions: Vec::new(),
+            next_io: PCI_IO_WINDOW.start,
+        }
+    }
+
+    /// Plugs `dev` into the next device number, with an I/O BAR of
+    /// `bar_size` bytes (a power of two) allocated from the I/O window. The
+    /// device sees accesses as offsets into its BAR. Returns the port base
+    /// of the BAR.
+    pub fn add(
+        &mut self,
+        ids: PciIds,
+        bar_size: usize,
+        dev: Box<dyn MmioDevice>,
+    ) -> AxResult<usize> {
+        let base = self.next_io.next_multiple_of(bar_size);
+        if self.functions.len() + 1 >= MAX_DEVICES || base + bar_size > PCI_IO_WINDOW.end {
+            return Err(AxError::NoMemory);
+        }
+        self.next_io = base + bar_size;
+        self.functions.push(Function {
+            ids,
+            command: COMMAND_IO | COMMAND_MASTER,
+            bar: base as u32 | BAR_IO,
+            bar_size,
+            interrupt_line: PCI_INTX_LINE,
+            dev,
+        });
+        Ok(base)
+    }
+
+    /// Returns the function `CONFIG_ADDRESS` selects (`None` for the host
+    /// bridge) and the selected dword register, or `None` if it selects
+    /// nothing.
+    fn selected(&self) -> Option<(Option<usize>, usize)> {
+        let addr = self.config_address;
+        let (bus, device, function) = ((addr >> 16) & 0xFF, (addr >> 11) & 0x1F, (addr >> 8) & 7);
+        if addr & CONFIG_ENABLE == 0 || bus != 0 || function != 0 {
+            return None;
+        }
+        let reg = (addr & 0xFC) as usize;
+        match device as usize {
+            0 => Some((None, reg)),
+            d if d <= self.functions.len() => Some((Some(d - 1), reg)),
+            _ => None,
+        }
+    }
+
+    fn read_config(&self, func: Option<usize>, reg: usize) -> u32 {
+        let Some(i) = func else {
+            return match reg {
+                REG_COMMAND => (COMMAND_IO | COMMAND_MASTER) as u32,
+                _ => ids_register(&HOST_BRIDGE_IDS, reg),
+            };
+        };
+        let f = &self.functions[i];
+        match reg {
+            REG_COMMAND => {
+                let status = if f.dev.irq_pending() {
+                    STATUS_INTERRUPT
+                } else {
+                    0
+                };
+                ((status as u32) << 16) | f.command as u32
+            }
+            REG_BAR0 => f.bar,
+            REG_INTERRUPT => ((PIN_INTA as u32) << 8) | f.interrupt_line as u32,
+            _ => ids_register(&f.ids, reg),
+        }
+    }
+
+    /// Writes the bytes of `value` selected by `mask` to a register.
+    fn write_config(&mut self, func: Option<usize>, reg: usize, value: u32, mask: u32) {
+        // The host bridge has nothing to configure.
+        let Some(i) = func else {
+            return;
+        };
+        let f = &mut self.functions[i];
+        let merge = |old: u32| old & !mask | value & mask;
+        match reg {
+            REG_COMMAND => f.command = merge(f.command as u32) as u16 & COMMAND_WRITABLE,
+            // Writing all ones and reading back sizes the BAR.
+            REG_BAR0 => f.bar = merge(f.bar) & !(f.bar_size as u32 - 1) & 0xFFFF | BAR_IO,
+            REG_INTERRUPT => f.interrupt_line = merge(f.interrupt_line as u32) as u8,
+            _ => {}
+        }
+    }
+
+    /// Returns the function whose BAR decodes `port` and the offset into it.
+    fn decode_io(&mut self, port: usize) -> Option<(&mut Function, usize)> {
+        self.functions.iter_mut().find_map(|f| {
+            let range = f.io_range()?;
+            range.contains(&port).then_some((f, port - range.start))
+        })
+    }
+}
+
+/// Reads one of the identification registers of a type 0 header.
+fn ids_register(ids: &PciIds, reg: usize) -> u32 {
+    match reg {
+        REG_ID => ((ids.device as u32) << 16) | ids.vendor as u32,
+        REG_CLASS => (ids.class << 8) | ids.revision as u32,
+        // Single-function device, type 0 header.
+        REG_HEADER => 0,
+        REG_SUBSYSTEM => ((ids.subsystem as u32) << 16) | ids.subsystem_vendor as u32,
+        _ => 0,
+    }
+}
+
+impl MmioDevice for PciHost {
+    fn base(&self) -> usize {
+        PCI_CONFIG_PORT
+    }
+
+    /// The configuration ports and everything up to the end of the I/O
+    /// window; only those two ranges are intercepted.
+    fn size(&self) -> usize {
+        PCI_IO_WINDOW.end - PCI_CONFIG_PORT
+    }
+
+    fn read(&mut self, space: &mut GuestSpace, offset: usize, width: usize) -> u64 {
+        let port = PCI_CONFIG_PORT + offset;
+        let all_ones = (1u64 << (width * 8)) - 1;
+        match offset {
+            0 if width == 4 => self.config_address as u64,
+            4..8 => match self.selected() {
+                Some((func, reg)) => {
+                    let shift = (offset - 4) * 8;
+                    (self.read_config(func, reg) >> shift) as u64 & all_ones
+                }
+                None => all_ones,
+            },
+            _ if PCI_IO_WINDOW.contains(&port) => match self.decode_io(port) {
+                Some((f, offset)) => f.dev.read(space, offset, width),
+                None => all_ones,
+            },
+            _ => all_ones,
+        }
+    }
+
+    fn write(&mut self, space: &mut GuestSpace, offset: usize, width: usize, value: u64) {
+        let port = PCI_CONFIG_PORT + offset;
+        match offset {
+            0 if width == 4 => self.config_address = value as u32,
+            4..8 => {
+                if let Some((func, reg)) = self.selected() {
+                    let shift = (offset - 4) * 8;
+                    let mask = ((1u64 << (width * 8)) - 1) as u32;
+                    self.write_config(func, reg, (value as u32) << shift, mask << shift);
+                }
+            }
+            _ if PCI_IO_WINDOW.contains(&port) => {
+                if let Some((f, offset)) = self.decode_io(port) {
+                    f.dev.write(space, offset, width, value);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn poll(&mut self, space: &mut GuestSpace) {
+        self.functions.iter_mut().for_each(|f| f.dev.poll(space));
+    }
+
+    fn irq_pending(&self) -> bool {
+        self.functions.iter().any(|f| f.irq_pending())
+    }
+}
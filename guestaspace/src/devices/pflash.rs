@@ -0,0 +1,465 @@
+//! CFI parallel NOR flash (pflash) device.
+//!
+//! The demo guests read a magic value from the flash that QEMU's machines
+//! expose at a fixed address. Instead of identity-mapping whatever host
+//! physical memory happens to sit at that guest address, every VM gets its
+//! own flash device in one of two flavours:
+//!
+//! - **Emulated**: the contents come from [`PFLASH_IMAGE_PATH`] on the root
+//!   filesystem; pages past the end of the image read as erased (`0xFF`).
+//!   In read-array mode the pages are mapped read-only into the guest on
+//!   first access, so reads run at memory speed. A write leaves read-array
+//!   mode and unmaps them again, so that status, identifier and CFI query
+//!   reads trap and can be served by [`PFlash::mmio_read`].
+//! - **Passthrough**: the host's flash is mapped read-only into the guest as
+//!   one linear region when the VM is built.
+//!
+//! The command set is the Intel/Sharp one (CFI primary command set 0x0001)
+//! of QEMU's `pflash_cfi01`. Trapped accesses reach the device through its
+//! [`MmioDevice`] implementation.
+//!
+//! [`PFLASH_IMAGE_PATH`]: crate::config::PFLASH_IMAGE_PATH
+
+#![allow(dead_code)]
+
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+
+use axerrno::{AxError, AxResult};
+use axhal::mem::phys_to_virt;
+use axhal::paging::{MappingFlags, PageSize};
+use axstd::fs::File;
+use axstd::io::Read;
+use memory_addr::{PAGE_SIZE_4K, PhysAddr};
+
+use crate::config::PFLASH_IMAGE_PATH;
+use crate::devices::mmio::MmioDevice;
+use crate::gspace::{GuestSpace, alloc_frame, dealloc_frame};
+
+/// Erase block size (QEMU `virt` uses 256 KiB sectors).
+const BLOCK_SIZE: usize = 0x4_0000;
+/// Bytes per bus cycle; identifier and CFI query offsets are in units of it.
+const BANK_WIDTH: usize = 4;
+/// Manufacturer and device identifiers (Intel, as configured by QEMU `virt`).
+const IDENT: [u8; 2] = [0x89, 0x18];
+
+/// Status register: write state machine ready.
+const STATUS_READY: u8 = 0x80;
+/// Status register: erase or clear-lock error.
+const STATUS_ERASE_ERR: u8 = 0x20;
+/// Status register: program or set-lock error.
+const STATUS_PROGRAM_ERR: u8 = 0x10;
+/// Status register: operation aborted on a locked block.
+const STATUS_LOCKED: u8 = 0x02;
+
+/// Read mode of the device, selected by the last command written.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Mode {
+    ReadArray,
+    ReadStatus,
+    ReadId,
+    CfiQuery,
+    /// The next write is the data of a word program.
+    Program,
+    /// The next write must confirm (0xD0) a block erase.
+    EraseSetup,
+    /// The next write is a lock-bit command (accepted, locks are ignored).
+    LockSetup,
+}
+
+enum Backing {
+    /// Flash pages in host frames; missing pages are erased and share the
+    /// all-ones frame `erased`. `mapped` lists the page offsets currently
+    /// mapped into the guest.
+    Emulated {
+        pages: BTreeMap<usize, PhysAddr>,
+        erased: PhysAddr,
+        mapped: Vec<usize>,
+    },
+    /// The host flash at this physical address, mapped read-only.
+    Passthrough(PhysAddr),
+}
+
+/// A pflash device occupying `[base, base + size)` in guest physical memory.
+pub struct PFlash {
+    base: usize,
+    size: usize,
+    writable: bool,
+    mode: Mode,
+    status: u8,
+    backing: Backing,
+}
+
+impl PFlash {
+    /// Creates an emulated flash holding `data` at offset 0; the rest of the
+    /// device reads as erased. Program and erase commands fail with a locked
+    /// status unless `writable` is set; writes never reach `data`'s origin.
+    pub fn from_image(base: usize, size: usize, data: &[u8], writable: bool) -> AxResult<Self> {
+        if data.len() > size || !size.is_multiple_of(BLOCK_SIZE) {
+            return Err(AxError::InvalidInput);
+        }
+        let erased = alloc_frame(PageSize::Size4K).ok_or(AxError::NoMemory)?;
+        fill_frame(erased, 0xFF);
+        let mut flash = Self {
+            base,
+            size,
+            writable,
+            mode: Mode::ReadArray,
+            status: STATUS_READY,
+            backing: Backing::Emulated {
+                pages: BTreeMap::new(),
+                erased,
+                mapped: Vec::new(),
+            },
+        };
+        for (i, chunk) in data.chunks(PAGE_SIZE_4K).enumerate() {
+            if chunk.iter().all(|&b| b == 0xFF) {
+                continue;
+            }
+            let paddr = flash.page_mut(i * PAGE_SIZE_4K)?;
+            unsafe {
+                core::ptr::copy_nonoverlapping(
+                    chunk.as_ptr(),
+                    phys_to_virt(paddr).as_mut_ptr(),
+                    chunk.len(),
+                );
+            }
+        }
+        Ok(flash)
+    }
+
+    /// Creates a read-only passthrough of the host flash at `host`.
+    pub fn passthrough(base: usize, size: usize, host: PhysAddr) -> Self {
+        Self {
+            base,
+            size,
+            writable: false,
+            mode: Mode::ReadArray,
+            status: STATUS_READY,
+            backing: Backing::Passthrough(host),
+        }
+    }
+
+    /// Creates the flash of a VM: emulated from [`PFLASH_IMAGE_PATH`] if that
+    /// file exists, otherwise a passthrough of `host` if given, otherwise an
+    /// emulated, fully erased flash.
+    pub fn open(
+        base: usize,
+        size: usize,
+        host: Option<PhysAddr>,
+        writable: bool,
+    ) -> AxResult<Self> {
+        let mut data = Vec::new();
+        match File::open(PFLASH_IMAGE_PATH) {
+            Ok(mut file) => {
+                file.read_to_end(&mut data).map_err(|_| AxError::Io)?;
+            }
+            Err(_) => {
+                if let Some(host) = host {
+                    return Ok(Self::passthrough(base, size, host));
+                }
+            }
+        }
+        Self::from_image(base, size, &data, writable)
+    }
+
+    /// Returns the guest physical address of the device.
+    pub fn base(&self) -> usize {
+        self.base
+    }
+
+    /// Returns the size of the device in bytes.
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// Describes where the flash contents come from.
+    pub fn kind(&self) -> &'static str {
+        match self.backing {
+            Backing::Emulated { .. } => "emulated",
+            Backing::Passthrough(_) => "host passthrough",
+        }
+    }
+
+    /// Checks whether `gpa` lies inside the device.
+    pub fn contains(&self, gpa: usize) -> bool {
+        gpa >= self.base && gpa - self.base < self.size
+    }
+
+    /// Maps the device into `space`: the whole host flash for a passthrough
+    /// device, nothing for an emulated one (its pages are mapped on fault).
+    pub fn attach(&mut self, space: &mut GuestSpace) -> AxResult {
+        match self.backing {
+            Backing::Passthrough(host) => {
+                space.map_linear(self.base.into(), host, self.size, array_flags())
+            }
+            Backing::Emulated { .. } => Ok(()),
+        }
+    }
+
+    /// Handles a stage-2 fault at `gpa` inside the device.
+    ///
+    /// A read in read-array mode maps the page read-only and returns `true`;
+    /// the guest TLB entry for the page must then be flushed. Writes and
+    /// reads in any other mode return `false`: they have to be emulated
+    /// through [`Self::mmio_read`] / [`Self::mmio_write`].
+    pub fn handle_fault(&mut self, space: &mut GuestSpace, gpa: usize, is_write: bool) -> bool {
+        if is_write || self.mode != Mode::ReadArray || !self.contains(gpa) {
+            return false;
+        }
+        let offset = (gpa - self.base) & !(PAGE_SIZE_4K - 1);
+        let Backing::Emulated {
+            pages,
+            erased,
+            mapped,
+        } = &mut self.backing
+        else {
+            return false;
+        };
+        let paddr = pages.get(&offset).copied().unwrap_or(*erased);
+        if space
+            .map_linear(
+                (self.base + offset).into(),
+                paddr,
+                PAGE_SIZE_4K,
+                array_flags(),
+            )
+            .is_err()
+        {
+            return false;
+        }
+        mapped.push(offset);
+        true
+    }
+
+    /// Emulates a guest read of `width` bytes at `offset` into the device.
+    pub fn mmio_read(&self, offset: usize, width: usize) -> u64 {
+        match self.mode {
+            Mode::ReadArray => {
+                let mut value = 0u64;
+                for i in (0..width.min(8)).rev() {
+                    value = value << 8 | self.array_byte(offset + i) as u64;
+                }
+                value
+            }
+            Mode::ReadId => IDENT.get(offset / BANK_WIDTH).copied().unwrap_or(0) as u64,
+            Mode::CfiQuery => self.cfi_byte(offset / BANK_WIDTH) as u64,
+            Mode::ReadStatus | Mode::Program | Mode::EraseSetup | Mode::LockSetup => {
+                self.status as u64
+            }
+        }
+    }
+
+    /// Emulates a guest write of `width` bytes at `offset` into the device.
+    ///
+    /// Every write may change the read mode, so the read-array pages are
+    /// unmapped from `space` first; the caller must flush the guest TLB.
+    pub fn mmio_write(
+        &mut self,
+        space: &mut GuestSpace,
+        offset: usize,
+        width: usize,
+        value: u64,
+    ) -> AxResult {
+        if offset >= self.size {
+            return Err(AxError::InvalidInput);
+        }
+        let Backing::Emulated { mapped, .. } = &mut self.backing else {
+            // The host flash is read-only.
+            return Err(AxError::PermissionDenied);
+        };
+        for page in mapped.drain(..) {
+            space.unmap((self.base + page).into(), PAGE_SIZE_4K)?;
+        }
+
+        let cmd = value as u8;
+        self.mode = match self.mode {
+            Mode::Program => {
+                if self.writable {
+                    self.program(offset, width, value)?;
+                } else {
+                    self.status |= STATUS_PROGRAM_ERR | STATUS_LOCKED;
+                }
+                Mode::ReadStatus
+            }
+            Mode::EraseSetup if cmd == 0xD0 => {
+                if self.writable {
+                    self.erase_block(offset);
+                } else {
+                    self.status |= STATUS_ERASE_ERR | STATUS_LOCKED;
+                }
+                Mode::ReadStatus
+            }
+            Mode::EraseSetup => {
+                // Improper command sequence.
+                self.status |= STATUS_ERASE_ERR | STATUS_PROGRAM_ERR;
+                Mode::ReadStatus
+            }
+            Mode::LockSetup => Mode::ReadStatus,
+            _ => match cmd {
+                0x10 | 0x40 => Mode::Program,
+                0x20 => Mode::EraseSetup,
+                0x50 => {
+                    self.status = STATUS_READY;
+                    Mode::ReadArray
+                }
+                0x60 => Mode::LockSetup,
+                0x70 => Mode::ReadStatus,
+                0x90 => Mode::ReadId,
+                0x98 => Mode::CfiQuery,
+                // 0x00, 0xFF and unsupported commands: back to read array.
+                _ => Mode::ReadArray,
+            },
+        };
+        Ok(())
+    }
+
+    /// Programs `width` bytes at `offset`: flash cells can only go from 1 to 0.
+    fn program(&mut self, offset: usize, width: usize, value: u64) -> AxResult {
+        for i in 0..width.min(8) {
+            let off = offset + i;
+            if off >= self.size {
+                break;
+            }
+            let paddr = self.page_mut(off & !(PAGE_SIZE_4K - 1))?;
+            let byte = unsafe { &mut *phys_to_virt(paddr).as_mut_ptr().add(off % PAGE_SIZE_4K) };
+            *byte &= (value >> (i * 8)) as u8;
+        }
+        Ok(())
+    }
+
+    /// Erases the block containing `offset` back to all ones.
+    fn erase_block(&mut self, offset: usize) {
+        let Backing::Emulated { pages, .. } = &mut self.backing else {
+            return;
+        };
+        let block = offset & !(BLOCK_SIZE - 1);
+        let erased: Vec<usize> = pages
+            .range(block..block + BLOCK_SIZE)
+            .map(|(&off, _)| off)
+            .collect();
+        for off in erased {
+            if let Some(paddr) = pages.remove(&off) {
+                dealloc_frame(paddr, PageSize::Size4K);
+            }
+        }
+    }
+
+    /// Returns the private frame of the page at `offset`, allocating an
+    /// erased one if the page has none yet.
+    fn page_mut(&mut self, offset: usize) -> AxResult<PhysAddr> {
+        let Backing::Emulated { pages, .. } = &mut self.backing else {
+            return Err(AxError::PermissionDenied);
+        };
+        if let Some(&paddr) = pages.get(&offset) {
+            return Ok(paddr);
+        }
+        let paddr = alloc_frame(PageSize::Size4K).ok_or(AxError::NoMemory)?;
+        fill_frame(paddr, 0xFF);
+        pages.insert(offset, paddr);
+        Ok(paddr)
+    }
+
+    /// Returns the array contents at `offset`.
+    fn array_byte(&self, offset: usize) -> u8 {
+        if offset >= self.size {
+            return 0xFF;
+        }
+        let paddr = match &self.backing {
+            Backing::Emulated { pages, erased, .. } => pages
+                .get(&(offset & !(PAGE_SIZE_4K - 1)))
+                .copied()
+                .unwrap_or(*erased),
+            Backing::Passthrough(host) => *host + (offset & !(PAGE_SIZE_4K - 1)),
+        };
+        unsafe { *phys_to_virt(paddr).as_ptr().add(offset % PAGE_SIZE_4K) }
+    }
+
+    /// Returns byte `index` of the CFI query table.
+    fn cfi_byte(&self, index: usize) -> u8 {
+        let blocks = self.size / BLOCK_SIZE - 1;
+        let block_units = BLOCK_SIZE / 256;
+        match index {
+            0x10 => b'Q',
+            0x11 => b'R',
+            0x12 => b'Y',
+            // Primary command set: Intel/Sharp extended (0x0001).
+            0x13 => 0x01,
+            // Primary extended query table at 0x31.
+            0x15 => 0x31,
+            // Vcc min/max: 4.5 V / 5.5 V.
+            0x1B => 0x45,
+            0x1C => 0x55,
+            // Typical word program 2^7 us, block erase 2^10 ms.
+            0x1F => 0x07,
+            0x21 => 0x0A,
+            // Maximum timeouts: 2^4 times typical.
+            0x23 => 0x04,
+            0x25 => 0x04,
+            // Device size: 2^n bytes.
+            0x27 => self.size.trailing_zeros() as u8,
+            // Interface: x8/x16 asynchronous.
+            0x28 => 0x02,
+            // One erase block region of `blocks + 1` blocks of 256 * n bytes.
+            0x2C => 0x01,
+            0x2D => blocks as u8,
+            0x2E => (blocks >> 8) as u8,
+            0x2F => block_units as u8,
+            0x30 => (block_units >> 8) as u8,
+            // Primary extended query: "PRI", version 1.0.
+            0x31 => b'P',
+            0x32 => b'R',
+            0x33 => b'I',
+            0x34 => b'1',
+            0x35 => b'0',
+            _ => 0,
+        }
+    }
+}
+
+impl MmioDevice for PFlash {
+    fn base(&self) -> usize {
+        self.base
+    }
+
+    fn size(&self) -> usize {
+        self.size
+    }
+
+    fn read(&mut self, _space: &mut GuestSpace, offset: usize, width: usize) -> u64 {
+        self.mmio_read(offset, width)
+    }
+
+    fn write(&mut self, space: &mut GuestSpace, offset: usize, width: usize, value: u64) {
+        if let Err(e) = self.mmio_write(space, offset, width, value) {
+            warn!(
+                "pflash: write {:#x} at {:#x} failed: {:?}",
+                value, offset, e
+            );
+        }
+    }
+
+    fn map_on_fault(&mut self, space: &mut GuestSpace, addr: usize, is_write: bool) -> bool {
+        self.handle_fault(space, addr, is_write)
+    }
+}
+
+impl Drop for PFlash {
+    fn drop(&mut self) {
+        if let Backing::Emulated { pages, erased, .. } = &self.backing {
+            for &paddr in pages.values() {
+                dealloc_frame(paddr, PageSize::Size4K);
+            }
+            dealloc_frame(*erased, PageSize::Size4K);
+        }
+    }
+}
+
+/// Guest mapping flags of flash pages in read-array mode.
+fn array_flags() -> MappingFlags {
+    MappingFlags::READ | MappingFlags::USER
+}
+
+fn fill_frame(paddr: PhysAddr, byte: u8) {
+    unsafe { core::ptr::write_bytes(phys_to_virt(paddr).as_mut_ptr(), byte, PAGE_SIZE_4K) };
+}
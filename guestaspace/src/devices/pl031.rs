@@ -0,0 +1,137 @@
+//! ARM PL031 real-time clock.
+//!
+//! The guest reads the host wall-clock time ([`crate::wallclock`]) in whole
+//! seconds from the data register, so its kernel can set the time of day
+//! at boot. Loading a value sets the guest's clock: the difference to the
+//! host time is kept as the guest's offset. The match register and the
+//! interrupt status work, but no interrupt line is wired up (the device
+//! tree node has none), so a guest polls the alarm status.
+
+#![allow(dead_code)]
+
+use crate::devices::mmio::MmioDevice;
+use crate::gspace::GuestSpace;
+use crate::migrate::{MigrationError, StateReader, StateWriter};
+use crate::wallclock;
+
+/// Size of the register window.
+pub const PL031_SIZE: usize = 0x1000;
+
+const REG_DR: usize = 0x000;
+const REG_MR: usize = 0x004;
+const REG_LR: usize = 0x008;
+const REG_CR: usize = 0x00C;
+const REG_IMSC: usize = 0x010;
+const REG_RIS: usize = 0x014;
+const REG_MIS: usize = 0x018;
+const REG_ICR: usize = 0x01C;
+const REG_ID: usize = 0xFE0;
+
+/// PeriphID0-3 and PCellID0-3, as on QEMU's PL031.
+const ID: [u8; 8] = [0x31, 0x10, 0x14, 0x00, 0x0D, 0xF0, 0x05, 0xB1];
+
+/// An emulated PL031.
+pub struct Pl031 {
+    base: usize,
+    /// Guest time minus host time, in seconds.
+    offset: i64,
+    /// Last value loaded.
+    load: u32,
+    match_value: u32,
+    /// The match value was written and the alarm has not been cleared.
+    armed: bool,
+    /// Raw interrupt status latched when the alarm went off.
+    raised: bool,
+    imsc: u32,
+}
+
+impl Pl031 {
+    /// Creates a PL031 at `base` showing the host time.
+    pub fn new(base: usize) -> Self {
+        Self {
+            base,
+            offset: 0,
+            load: 0,
+            match_value: 0,
+            armed: false,
+            raised: false,
+            imsc: 0,
+        }
+    }
+
+    /// Returns the guest's time in seconds.
+    fn now(&self) -> u32 {
+        (wallclock::now().as_secs() as i64 + self.offset) as u32
+    }
+
+    fn ris(&mut self) -> u32 {
+        if self.armed && self.now() >= self.match_value {
+            self.raised = true;
+            self.armed = false;
+        }
+        self.raised as u32
+    }
+}
+
+impl MmioDevice for Pl031 {
+    fn base(&self) -> usize {
+        self.base
+    }
+
+    fn size(&self) -> usize {
+        PL031_SIZE
+    }
+
+    fn read(&mut self, _space: &mut GuestSpace, offset: usize, _width: usize) -> u64 {
+        let value = match offset {
+            REG_DR => self.now(),
+            REG_MR => self.match_value,
+            REG_LR => self.load,
+            // The clock always runs.
+            REG_CR => 1,
+            REG_IMSC => self.imsc,
+            REG_RIS => self.ris(),
+            REG_MIS => self.ris() & self.imsc,
+            REG_ID..0x1000 if offset.is_multiple_of(4) => ID[(offset - REG_ID) / 4] as u32,
+            _ => 0,
+        };
+        value as u64
+    }
+
+    fn write(&mut self, _space: &mut GuestSpace, offset: usize, _width: usize, value: u64) {
+        let value = value as u32;
+        match offset {
+            REG_MR => {
+                self.match_value = value;
+                self.armed = true;
+            }
+            REG_LR => {
+                self.load = value;
+                self.offset = value as i64 - wallclock::now().as_secs() as i64;
+            }
+            REG_IMSC => self.imsc = value & 1,
+            REG_ICR if value & 1 != 0 => self.raised = false,
+            _ => {}
+        }
+    }
+
+    fn save_state(&self, out: &mut StateWriter) -> bool {
+        out.varint(self.offset as u64);
+        for reg in [self.load, self.match_value, self.imsc] {
+            out.varint(reg.into());
+        }
+        out.byte(self.armed as u8 | (self.raised as u8) << 1);
+        true
+    }
+
+    fn restore_state(&mut self, state: &mut StateReader) -> Result<(), MigrationError> {
+        self.offset = state.varint()? as i64;
+        self.load = state.varint_into()?;
+        self.match_value = state.varint_into()?;
+        self.imsc = state.varint_into()?;
+        let flags = state.byte()?;
+        self.armed = flags & 1 != 0;
+        self.raised = flags & 2 != 0;
+        Ok(())
+    }
+}
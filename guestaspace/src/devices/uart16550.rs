@@ -0,0 +1,127 @@
+//! 16550 UART bound to the secondary host serial port.
+//!
+//! The guest of a VM owning the secondary serial port (`serial=on`, see
+//! [`crate::serial`]) finds a 16550 at its usual address (COM1 on x86_64)
+//! and drives it like a real one: transmitted bytes go straight to the host
+//! port, received bytes come from it. The divisor, line and modem control
+//! settings are kept for the guest to read back but do not affect the host
+//! port. The model has no interrupt line: the transmitter is always ready,
+//! and the guest polls the line status for input.
+
+#![allow(dead_code)]
+
+use crate::devices::mmio::MmioDevice;
+use crate::gspace::GuestSpace;
+use crate::serial::HostSerial;
+
+/// I/O port of COM1, where x86_64 guests look for their console.
+pub const COM1_BASE: usize = 0x3F8;
+/// Registers of a 16550, one byte each.
+pub const UART_SIZE: usize = 8;
+
+const REG_DATA: usize = 0;
+const REG_IER: usize = 1;
+const REG_IIR: usize = 2;
+const REG_LCR: usize = 3;
+const REG_MCR: usize = 4;
+const REG_LSR: usize = 5;
+const REG_MSR: usize = 6;
+const REG_SCR: usize = 7;
+
+const LCR_DLAB: u8 = 0x80;
+const MCR_LOOP: u8 = 0x10;
+const LSR_DATA_READY: u8 = 0x01;
+const LSR_THR_EMPTY: u8 = 0x20;
+const LSR_TX_EMPTY: u8 = 0x40;
+/// No interrupt pending, FIFOs enabled.
+const IIR_NONE_FIFO: u8 = 0xC1;
+/// CTS, DSR and DCD asserted.
+const MSR_CONNECTED: u8 = 0xB0;
+
+/// An emulated 16550 on the secondary host serial port.
+pub struct Uart16550 {
+    base: usize,
+    host: HostSerial,
+    /// A byte read from the host and not yet by the guest.
+    rx: Option<u8>,
+    ier: u8,
+    lcr: u8,
+    mcr: u8,
+    scr: u8,
+    divisor: u16,
+}
+
+impl Uart16550 {
+    /// Creates a UART with its registers at `base` (an I/O port or a guest
+    /// physical address) on the host port `host`.
+    pub fn new(base: usize, host: HostSerial) -> Self {
+        Self {
+            base,
+            host,
+            rx: None,
+            ier: 0,
+            lcr: 0,
+            mcr: 0,
+            scr: 0,
+            divisor: 1,
+        }
+    }
+
+    fn fill_rx(&mut self) {
+        if self.rx.is_none() {
+            self.rx = self.host.read();
+        }
+    }
+}
+
+impl MmioDevice for Uart16550 {
+    fn base(&self) -> usize {
+        self.base
+    }
+
+    fn size(&self) -> usize {
+        UART_SIZE
+    }
+
+    fn read(&mut self, _space: &mut GuestSpace, offset: usize, _width: usize) -> u64 {
+        let dlab = self.lcr & LCR_DLAB != 0;
+        let value = match offset {
+            REG_DATA if dlab => self.divisor as u8,
+            REG_IER if dlab => (self.divisor >> 8) as u8,
+            REG_DATA => {
+                self.fill_rx();
+                self.rx.take().unwrap_or(0)
+            }
+            REG_IER => self.ier,
+            REG_IIR => IIR_NONE_FIFO,
+            REG_LCR => self.lcr,
+            REG_MCR => self.mcr,
+            REG_LSR => {
+                self.fill_rx();
+                LSR_THR_EMPTY | LSR_TX_EMPTY | self.rx.map_or(0, |_| LSR_DATA_READY)
+            }
+            REG_MSR => MSR_CONNECTED,
+            REG_SCR => self.scr,
+            _ => 0,
+        };
+        value as u64
+    }
+
+    fn write(&mut self, _space: &mut GuestSpace, offset: usize, _width: usize, value: u64) {
+        let value = value as u8;
+        let dlab = self.lcr & LCR_DLAB != 0;
+        match offset {
+            REG_DATA if dlab => self.divisor = self.divisor & 0xFF00 | value as u16,
+            REG_IER if dlab => self.divisor = self.divisor & 0x00FF | (value as u16) << 8,
+            // Loopback mode: the byte comes back as input.
+            REG_DATA if self.mcr & MCR_LOOP != 0 => self.rx = Some(value),
+            REG_DATA => self.host.write(value),
+            REG_IER => self.ier = value & 0x0F,
+            REG_LCR => self.lcr = value,
+            REG_MCR => self.mcr = value & 0x1F,
+            REG_SCR => self.scr = value,
+            // FIFO control and the read-only registers.
+            _ => {}
+        }
+    }
+}
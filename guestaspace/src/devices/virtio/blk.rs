@@ -0,0 +1,336 @@
+//! virtio-blk device backed by a disk image file on the host filesystem.
+//!
+//! The file I/O runs on a worker task ([`crate::devices::worker`]): a
+//! notification only parses the requests on the queue and hands them over,
+//! and the used ring is filled when the transport polls the device after
+//! the worker has served them, in the order they were submitted.
+
+use alloc::collections::BTreeMap;
+use alloc::format;
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+
+use axerrno::{AxError, AxResult};
+use axstd::fs::File;
+use axstd::io::{Read, Seek, SeekFrom, Write};
+
+use super::queue::{DescChain, Virtqueue};
+use super::{VIRTIO_ID_BLOCK, VirtioDevice, read_config_bytes};
+use crate::devices::worker::{Backend, Worker};
+use crate::gmem::GuestMemory;
+use crate::gspace::GuestSpace;
+use crate::idle::IdleQueue;
+
+/// Sector size of the virtio-blk protocol.
+const SECTOR_SIZE: u64 = 512;
+
+/// Feature: `size_max` in the configuration space is valid.
+const VIRTIO_BLK_F_SIZE_MAX: u64 = 1 << 1;
+/// Feature: `seg_max` in the configuration space is valid.
+const VIRTIO_BLK_F_SEG_MAX: u64 = 1 << 2;
+/// Feature: the device is read-only.
+const VIRTIO_BLK_F_RO: u64 = 1 << 5;
+/// Feature: the device supports the flush command.
+const VIRTIO_BLK_F_FLUSH: u64 = 1 << 9;
+
+const VIRTIO_BLK_T_IN: u32 = 0;
+const VIRTIO_BLK_T_OUT: u32 = 1;
+const VIRTIO_BLK_T_FLUSH: u32 = 4;
+const VIRTIO_BLK_T_GET_ID: u32 = 8;
+
+const VIRTIO_BLK_S_OK: u8 = 0;
+const VIRTIO_BLK_S_IOERR: u8 = 1;
+const VIRTIO_BLK_S_UNSUPP: u8 = 2;
+
+/// Length of the request header: type, reserved, sector.
+const REQ_HEADER_LEN: usize = 16;
+/// Length of the device ID string returned by `VIRTIO_BLK_T_GET_ID`.
+const ID_LEN: usize = 20;
+/// Largest buffer of a request (`size_max`).
+const SEGMENT_LEN: usize = 4096;
+/// Most data buffers in a request (`seg_max`).
+const SEGMENTS: usize = 256;
+/// Longest data transfer of one request. The guest sets the buffer
+/// lengths, so longer requests fail before anything is allocated.
+const MAX_DATA_LEN: usize = SEGMENTS * SEGMENT_LEN;
+
+/// A block device serving a disk image file.
+pub struct VirtioBlk {
+    worker: Worker<DiskFile>,
+    /// Capacity in sectors.
+    capacity: u64,
+    read_only: bool,
+    /// Chains handed to the worker, by head index.
+    in_flight: BTreeMap<u16, DescChain>,
+    /// Bumped on reset; completions of older requests are dropped.
+    generation: u32,
+}
+
+impl VirtioBlk {
+    /// Opens the disk image at `path`, read-write if possible, and starts
+    /// its worker task for VM `vm_id`, which kicks `idle` when requests
+    /// complete. The capacity is the file size rounded down to whole
+    /// sectors.
+    pub fn open(path: &str, vm_id: usize, idle: Arc<IdleQueue>) -> AxResult<Self> {
+        let (file, read_only) = match File::options().read(true).write(true).open(path) {
+            Ok(file) => (file, false),
+            Err(_) => (File::open(path).map_err(|_| AxError::NotFound)?, true),
+        };
+        let size = file.metadata().map_err(|_| AxError::Io)?.len();
+        let capacity = size / SECTOR_SIZE;
+        let disk = DiskFile {
+            file,
+            capacity,
+            read_only,
+        };
+        Ok(Self {
+            worker: Worker::spawn(format!("vm{vm_id}-blk"), disk, idle),
+            capacity,
+            read_only,
+            in_flight: BTreeMap::new(),
+            generation: 0,
+        })
+    }
+
+    /// Returns the capacity in bytes.
+    pub fn size(&self) -> u64 {
+        self.capacity * SECTOR_SIZE
+    }
+
+    /// Checks whether the guest may only read the disk.
+    pub fn is_read_only(&self) -> bool {
+        self.read_only
+    }
+
+    /// Parses the request in `chain` for the worker. Requests beyond
+    /// `seg_max`, `size_max` or the disk capacity are refused.
+    fn parse(&self, space: &GuestSpace, chain: &DescChain) -> AxResult<BlkRequest> {
+        // The header, the data buffers and the status.
+        if chain.descs.len() > SEGMENTS + 2 || chain.descs.iter().any(|d| d.len > SEGMENT_LEN) {
+            return Err(AxError::InvalidData);
+        }
+        // The last device-writable byte is the status.
+        let data_len = chain
+            .writable_len()
+            .checked_sub(1)
+            .filter(|&len| len <= MAX_DATA_LEN)
+            .ok_or(AxError::InvalidData)?;
+        let req = chain.read_all(space, REQ_HEADER_LEN + MAX_DATA_LEN)?;
+        if req.len() < REQ_HEADER_LEN {
+            return Err(AxError::InvalidData);
+        }
+        let ty = u32::from_le_bytes(req[0..4].try_into().unwrap());
+        let sector = u64::from_le_bytes(req[8..16].try_into().unwrap());
+        let len = match ty {
+            VIRTIO_BLK_T_IN => data_len,
+            VIRTIO_BLK_T_OUT => req.len() - REQ_HEADER_LEN,
+            _ => 0,
+        };
+        if !in_range(self.capacity, sector, len) {
+            return Err(AxError::InvalidData);
+        }
+        Ok(BlkRequest {
+            generation: self.generation,
+            head: chain.head,
+            ty,
+            sector,
+            data: req[REQ_HEADER_LEN..].to_vec(),
+            data_len,
+        })
+    }
+}
+
+/// Checks whether `len` bytes from `sector` lie on a disk of `capacity`
+/// sectors.
+fn in_range(capacity: u64, sector: u64, len: usize) -> bool {
+    sector
+        .checked_mul(SECTOR_SIZE)
+        .and_then(|start| start.checked_add(len as u64))
+        .is_some_and(|end| end <= capacity * SECTOR_SIZE)
+}
+
+/// Fails `chain` at once: writes `VIRTIO_BLK_S_IOERR` to its status, the
+/// last device-writable byte, and returns the number of bytes written.
+fn fail(space: &mut GuestSpace, chain: &DescChain) -> u32 {
+    let Some(desc) = chain.descs.iter().rev().find(|d| d.write && d.len > 0) else {
+        return 0;
+    };
+    let written = desc
+        .addr
+        .checked_add(desc.len - 1)
+        .is_some_and(|status| space.copy_to_guest(status, &[VIRTIO_BLK_S_IOERR]).is_ok());
+    written as u32
+}
+
+/// A request handed to the worker.
+struct BlkRequest {
+    generation: u32,
+    head: u16,
+    ty: u32,
+    sector: u64,
+    /// The data to write (`VIRTIO_BLK_T_OUT`).
+    data: Vec<u8>,
+    /// Size of the device-writable buffers without the status byte.
+    data_len: usize,
+}
+
+/// A served request: the device-writable bytes, status last.
+struct BlkCompletion {
+    generation: u32,
+    head: u16,
+    resp: Vec<u8>,
+}
+
+/// The disk image file, owned by the worker.
+struct DiskFile {
+    file: File,
+    /// Capacity in sectors.
+    capacity: u64,
+    read_only: bool,
+}
+
+impl Backend for DiskFile {
+    type Request = BlkRequest;
+    type Completion = BlkCompletion;
+
+    fn handle(&mut self, req: BlkRequest) -> BlkCompletion {
+        let mut resp = vec![0u8; req.data_len];
+        let status = match req.ty {
+            VIRTIO_BLK_T_IN => self.read_sectors(req.sector, &mut resp),
+            VIRTIO_BLK_T_OUT => self.write_sectors(req.sector, &req.data),
+            VIRTIO_BLK_T_FLUSH => match self.file.flush() {
+                Ok(()) => VIRTIO_BLK_S_OK,
+                Err(_) => VIRTIO_BLK_S_IOERR,
+            },
+            VIRTIO_BLK_T_GET_ID => {
+                let id = b"arceos-vm-disk";
+                let n = req.data_len.min(ID_LEN).min(id.len());
+                resp[..n].copy_from_slice(&id[..n]);
+                VIRTIO_BLK_S_OK
+            }
+            _ => VIRTIO_BLK_S_UNSUPP,
+        };
+        resp.push(status);
+        BlkCompletion {
+            generation: req.generation,
+            head: req.head,
+            resp,
+        }
+    }
+}
+
+impl DiskFile {
+    fn read_sectors(&mut self, sector: u64, buf: &mut [u8]) -> u8 {
+        if !in_range(self.capacity, sector, buf.len()) {
+            return VIRTIO_BLK_S_IOERR;
+        }
+        let ok = self
+            .file
+            .seek(SeekFrom::Start(sector * SECTOR_SIZE))
+            .is_ok()
+            && self.file.read_exact(buf).is_ok();
+        if ok {
+            VIRTIO_BLK_S_OK
+        } else {
+            VIRTIO_BLK_S_IOERR
+        }
+    }
+
+    fn write_sectors(&mut self, sector: u64, data: &[u8]) -> u8 {
+        if self.read_only {
+            return VIRTIO_BLK_S_IOERR;
+        }
+        if !in_range(self.capacity, sector, data.len()) {
+            return VIRTIO_BLK_S_IOERR;
+        }
+        let ok = self
+            .file
+            .seek(SeekFrom::Start(sector * SECTOR_SIZE))
+            .is_ok()
+            && self.file.write_all(data).is_ok();
+        if ok {
+            VIRTIO_BLK_S_OK
+        } else {
+            VIRTIO_BLK_S_IOERR
+        }
+    }
+}
+
+impl VirtioDevice for VirtioBlk {
+    fn device_id(&self) -> u32 {
+        VIRTIO_ID_BLOCK
+    }
+
+    fn features(&self) -> u64 {
+        let mut features = VIRTIO_BLK_F_FLUSH | VIRTIO_BLK_F_SIZE_MAX | VIRTIO_BLK_F_SEG_MAX;
+        if self.read_only {
+            features |= VIRTIO_BLK_F_RO;
+        }
+        features
+    }
+
+    fn num_queues(&self) -> usize {
+        1
+    }
+
+    fn read_config(&self, offset: usize, width: usize) -> u64 {
+        // struct virtio_blk_config: `capacity`, `size_max` and `seg_max`.
+        let mut config = [0u8; 16];
+        config[0..8].copy_from_slice(&self.capacity.to_le_bytes());
+        config[8..12].copy_from_slice(&(SEGMENT_LEN as u32).to_le_bytes());
+        config[12..16].copy_from_slice(&(SEGMENTS as u32).to_le_bytes());
+        read_config_bytes(&config, offset, width)
+    }
+
+    fn notify(&mut self, space: &mut GuestSpace, _index: usize, queue: &mut Virtqueue) -> bool {
+        let mut used = false;
+        while let Ok(Some(chain)) = queue.pop(space) {
+            match self.parse(space, &chain) {
+                Ok(req) => {
+                    self.worker.submit(req);
+                    self.in_flight.insert(chain.head, chain);
+                }
+                Err(_) => {
+                    let written = fail(space, &chain);
+                    if queue.push_used(space, chain.head, written).is_err() {
+                        break;
+                    }
+                    used = true;
+                }
+            }
+        }
+        used
+    }
+
+    fn poll(&mut self, space: &mut GuestSpace, queues: &mut [Virtqueue]) -> bool {
+        let mut used = false;
+        for done in self.worker.completions() {
+            if done.generation != self.generation {
+                continue;
+            }
+            let Some(chain) = self.in_flight.remove(&done.head) else {
+                continue;
+            };
+            let written = chain.write_all(space, &done.resp).unwrap_or(0);
+            if queues[0]
+                .push_used(space, chain.head, written as u32)
+                .is_ok()
+            {
+                used = true;
+            }
+        }
+        used
+    }
+
+    fn reset(&mut self) {
+        self.in_flight.clear();
+        self.generation = self.generation.wrapping_add(1);
+    }
+}
+
+impl Drop for DiskFile {
+    fn drop(&mut self) {
+        let _ = self.file.flush();
+    }
+}
@@ -0,0 +1,174 @@
+//! virtio-console device: the guest's interactive console.
+//!
+//! Output the guest places on the transmit queue goes to its [`VmConsole`],
+//! tagged with the VM id like the putchar hypercall output. Input typed on
+//! the host console is handed to one VM only (several guests polling the
+//! same UART would each see a random subset of the keystrokes) and is
+//! copied into the buffers of the receive queue as they become available.
+//! A worker task ([`crate::devices::worker`]) waits for the input, so the
+//! VM's exit path does not poll the UART.
+
+use alloc::collections::VecDeque;
+use alloc::format;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::time::Duration;
+
+use super::queue::Virtqueue;
+use super::{VIRTIO_ID_CONSOLE, VirtioDevice, read_config_bytes};
+use crate::console::{VmConsole, read_host_input};
+use crate::devices::worker::{Backend, Worker};
+use crate::gspace::GuestSpace;
+use crate::idle::{IDLE_POLL_INTERVAL, IdleQueue};
+
+/// Feature: the driver may write single characters to `emerg_wr`.
+const VIRTIO_CONSOLE_F_EMERG_WRITE: u64 = 1 << 2;
+
+/// Index of receiveq0.
+const RX_QUEUE: usize = 0;
+/// Index of transmitq0.
+const TX_QUEUE: usize = 1;
+
+/// Offset of `emerg_wr` in `struct virtio_console_config`.
+const CONFIG_EMERG_WR: usize = 8;
+
+/// Host input kept while the guest has no receive buffer available.
+const INPUT_BUFFER_LEN: usize = 4096;
+
+/// Longest transmit buffer printed; longer ones are dropped.
+const MAX_OUTPUT_LEN: usize = 64 * 1024;
+
+/// A single-port console device.
+pub struct VirtioConsole {
+    output: VmConsole,
+    /// Reads the host console input, if it is routed to this device.
+    input: Option<Worker<HostInput>>,
+    pending: VecDeque<u8>,
+}
+
+impl VirtioConsole {
+    /// Creates the console of VM `vm_id`. If `input` is set, host console
+    /// input is delivered to it by a worker task, which kicks `idle` when
+    /// some arrives.
+    pub fn new(vm_id: usize, input: bool, idle: Arc<IdleQueue>) -> Self {
+        Self {
+            output: VmConsole::new(vm_id),
+            input: input.then(|| Worker::spawn(format!("vm{vm_id}-console"), HostInput, idle)),
+            pending: VecDeque::new(),
+        }
+    }
+
+    /// Moves the input the worker read into the pending buffer; input
+    /// beyond [`INPUT_BUFFER_LEN`] bytes is dropped.
+    fn take_host_input(&mut self) {
+        let Some(worker) = &self.input else {
+            return;
+        };
+        for chunk in worker.completions() {
+            let room = INPUT_BUFFER_LEN.saturating_sub(self.pending.len());
+            self.pending.extend(chunk.iter().take(room));
+        }
+    }
+
+    /// Copies pending input into the receive buffers. Returns `true` if any
+    /// buffer was used.
+    fn deliver(&mut self, space: &mut GuestSpace, queue: &mut Virtqueue) -> bool {
+        let mut used = false;
+        while !self.pending.is_empty() {
+            let Ok(Some(chain)) = queue.pop(space) else {
+                break;
+            };
+            let len = chain.writable_len().min(self.pending.len());
+            let data: Vec<u8> = self.pending.drain(..len).collect();
+            let written = chain.write_all(space, &data).unwrap_or(0);
+            if queue.push_used(space, chain.head, written as u32).is_err() {
+                break;
+            }
+            used = true;
+        }
+        used
+    }
+
+    /// Prints the buffers on the transmit queue.
+    fn transmit(&mut self, space: &mut GuestSpace, queue: &mut Virtqueue) -> bool {
+        let mut used = false;
+        while let Ok(Some(chain)) = queue.pop(space) {
+            if let Ok(data) = chain.read_all(space, MAX_OUTPUT_LEN) {
+                data.iter().for_each(|&ch| self.output.putchar(ch));
+            }
+            if queue.push_used(space, chain.head, 0).is_err() {
+                break;
+            }
+            used = true;
+        }
+        used
+    }
+}
+
+impl VirtioDevice for VirtioConsole {
+    fn device_id(&self) -> u32 {
+        VIRTIO_ID_CONSOLE
+    }
+
+    fn features(&self) -> u64 {
+        VIRTIO_CONSOLE_F_EMERG_WRITE
+    }
+
+    fn num_queues(&self) -> usize {
+        2
+    }
+
+    fn read_config(&self, offset: usize, width: usize) -> u64 {
+        // cols, rows and max_nr_ports are only valid with features not
+        // offered here; emerg_wr is write-only.
+        read_config_bytes(&[], offset, width)
+    }
+
+    fn write_config(&mut self, offset: usize, _width: usize, value: u64) {
+        if offset == CONFIG_EMERG_WR {
+            self.output.putchar(value as u8);
+        }
+    }
+
+    fn notify(&mut self, space: &mut GuestSpace, index: usize, queue: &mut Virtqueue) -> bool {
+        match index {
+            // New receive buffers: hand over input that was waiting for them.
+            RX_QUEUE => self.deliver(space, queue),
+            TX_QUEUE => self.transmit(space, queue),
+            _ => false,
+        }
+    }
+
+    fn poll(&mut self, space: &mut GuestSpace, queues: &mut [Virtqueue]) -> bool {
+        if self.input.is_none() {
+            return false;
+        }
+        self.take_host_input();
+        self.deliver(space, &mut queues[RX_QUEUE])
+    }
+
+    fn reset(&mut self) {
+        self.output.flush();
+        self.pending.clear();
+    }
+}
+
+/// The host console input, read on the worker task.
+struct HostInput;
+
+impl Backend for HostInput {
+    type Request = ();
+    type Completion = Vec<u8>;
+
+    const POLL_INTERVAL: Option<Duration> = Some(IDLE_POLL_INTERVAL);
+
+    fn handle(&mut self, _req: ()) -> Vec<u8> {
+        Vec::new()
+    }
+
+    fn poll(&mut self) -> Option<Vec<u8>> {
+        let mut buf = [0u8; 64];
+        let n = read_host_input(&mut buf);
+        (n > 0).then(|| buf[..n].to_vec())
+    }
+}
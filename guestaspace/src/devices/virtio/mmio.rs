@@ -0,0 +1,450 @@
+//! virtio-mmio transport (version 2 register layout).
+
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+
+use super::queue::{RING_FEATURES, VIRTIO_F_EVENT_IDX, Virtqueue};
+use super::{STATUS_FEATURES_OK, VIRTIO_F_VERSION_1, VirtioDevice};
+use crate::devices::mmio::MmioDevice;
+use crate::gspace::GuestSpace;
+use crate::migrate::{MigrationError, StateReader, StateWriter};
+
+/// Size of the register window of one device.
+pub const VIRTIO_MMIO_SIZE: usize = 0x200;
+
+/// Queue size offered to the driver.
+const QUEUE_NUM_MAX: u16 = 256;
+/// "virt" in little-endian.
+const MAGIC_VALUE: u32 = 0x7472_6976;
+/// Vendor ID reported by QEMU's virtio-mmio devices ("QEMU").
+const VENDOR_ID: u32 = 0x554D_4551;
+/// InterruptStatus: used buffer notification.
+const INT_USED_RING: u32 = 1;
+
+const REG_MAGIC: usize = 0x000;
+const REG_VERSION: usize = 0x004;
+const REG_DEVICE_ID: usize = 0x008;
+const REG_VENDOR_ID: usize = 0x00C;
+const REG_DEVICE_FEATURES: usize = 0x010;
+const REG_DEVICE_FEATURES_SEL: usize = 0x014;
+const REG_DRIVER_FEATURES: usize = 0x020;
+const REG_DRIVER_FEATURES_SEL: usize = 0x024;
+const REG_QUEUE_SEL: usize = 0x030;
+const REG_QUEUE_NUM_MAX: usize = 0x034;
+const REG_QUEUE_NUM: usize = 0x038;
+const REG_QUEUE_READY: usize = 0x044;
+const REG_QUEUE_NOTIFY: usize = 0x050;
+const REG_INTERRUPT_STATUS: usize = 0x060;
+const REG_INTERRUPT_ACK: usize = 0x064;
+const REG_STATUS: usize = 0x070;
+const REG_QUEUE_DESC_LOW: usize = 0x080;
+const REG_QUEUE_DESC_HIGH: usize = 0x084;
+const REG_QUEUE_DRIVER_LOW: usize = 0x090;
+const REG_QUEUE_DRIVER_HIGH: usize = 0x094;
+const REG_QUEUE_DEVICE_LOW: usize = 0x0A0;
+const REG_QUEUE_DEVICE_HIGH: usize = 0x0A4;
+const REG_CONFIG_GENERATION: usize = 0x0FC;
+const REG_CONFIG: usize = 0x100;
+
+/// A virtio device behind a virtio-mmio register window.
+pub struct VirtioMmio {
+    base: usize,
+    dev: Box<dyn VirtioDevice>,
+    queues: Vec<Virtqueue>,
+    device_features_sel: u32,
+    driver_features_sel: u32,
+    driver_features: u64,
+    queue_sel: u32,
+    status: u32,
+    interrupt_status: u32,
+}
+
+impl VirtioMmio {
+    /// Places `dev` at guest physical address `base`.
+    pub fn new(base: usize, dev: Box<dyn VirtioDevice>) -> Self {
+        let queues = (0..dev.num_queues())
+            .map(|_| Virtqueue::new(QUEUE_NUM_MAX))
+            .collect();
+        Self {
+            base,
+            dev,
+            queues,
+            device_features_sel: 0,
+            driver_features_sel: 0,
+            driver_features: 0,
+            queue_sel: 0,
+            status: 0,
+            interrupt_status: 0,
+        }
+    }
+
+    fn features(&self) -> u64 {
+        self.dev.features() | VIRTIO_F_VERSION_1 | RING_FEATURES
+    }
+
+    fn reset(&mut self) {
+        for queue in &mut self.queues {
+            *queue = Virtqueue::new(QUEUE_NUM_MAX);
+        }
+        self.dev.reset();
+        self.device_features_sel = 0;
+        self.driver_features_sel = 0;
+        self.driver_features = 0;
+        self.queue_sel = 0;
+        self.status = 0;
+        self.interrupt_status = 0;
+    }
+
+    fn queue(&mut self) -> Option<&mut Virtqueue> {
+        self.queues.get_mut(self.queue_sel as usize)
+    }
+}
+
+impl MmioDevice for VirtioMmio {
+    fn base(&self) -> usize {
+        self.base
+    }
+
+    fn size(&self) -> usize {
+        VIRTIO_MMIO_SIZE
+    }
+
+    fn read(&mut self, _space: &mut GuestSpace, offset: usize, width: usize) -> u64 {
+        if offset >= REG_CONFIG {
+            return self.dev.read_config(offset - REG_CONFIG, width);
+        }
+        let value = match offset {
+            REG_MAGIC => MAGIC_VALUE,
+            REG_VERSION => 2,
+            REG_DEVICE_ID => self.dev.device_id(),
+            REG_VENDOR_ID => VENDOR_ID,
+            REG_DEVICE_FEATURES => match self.device_features_sel {
+                0 => self.features() as u32,
+                1 => (self.features() >> 32) as u32,
+                _ => 0,
+            },
+            REG_QUEUE_NUM_MAX => {
+                if self.queue().is_some() {
+                    QUEUE_NUM_MAX as u32
+                } else {
+                    0
+                }
+            }
+            REG_QUEUE_READY => self.queue().map_or(0, |q| q.ready as u32),
+            REG_INTERRUPT_STATUS => self.interrupt_status,
+            REG_STATUS => self.status,
+            REG_CONFIG_GENERATION => 0,
+            _ => 0,
+        };
+        value as u64
+    }
+
+    fn write(&mut self, space: &mut GuestSpace, offset: usize, width: usize, value: u64) {
+        if offset >= REG_CONFIG {
+            self.dev.write_config(offset - REG_CONFIG, width, value);
+            return;
+        }
+        let value = value as u32;
+        match offset {
+            REG_DEVICE_FEATURES_SEL => self.device_features_sel = value,
+            REG_DRIVER_FEATURES_SEL => self.driver_features_sel = value,
+            REG_DRIVER_FEATURES => match self.driver_features_sel {
+                0 => self.driver_features = (self.driver_features & !0xFFFF_FFFF) | value as u64,
+                1 => {
+                    self.driver_features =
+                        (self.driver_features & 0xFFFF_FFFF) | (value as u64) << 32
+                }
+                _ => {}
+            },
+            REG_QUEUE_SEL => self.queue_sel = value,
+            REG_QUEUE_NUM => {
+                if let Some(q) = self.queue() {
+                    // Queue sizes must be powers of two no larger than the maximum.
+                    if value.is_power_of_two() && value <= QUEUE_NUM_MAX as u32 {
+                        q.size = value as u16;
+                    }
+                }
+            }
+            REG_QUEUE_READY => {
+                let event_idx = self.driver_features & VIRTIO_F_EVENT_IDX != 0;
+                if let Some(q) = self.queue() {
+                    q.ready = value & 1 != 0;
+                    q.event_idx = event_idx;
+                }
+            }
+            REG_QUEUE_DESC_LOW..=REG_QUEUE_DEVICE_HIGH if offset & 8 == 0 => {
+                if let Some(q) = self.queue() {
+                    let addr = match offset & !0xF {
+                        REG_QUEUE_DESC_LOW => &mut q.desc,
+                        REG_QUEUE_DRIVER_LOW => &mut q.avail,
+                        _ => &mut q.used,
+                    };
+                    // Each address is written as two 32-bit halves.
+                    *addr = if offset & 4 == 0 {
+                        (*addr & !0xFFFF_FFFF) | value as usize
+                    } else {
+                        (*addr & 0xFFFF_FFFF) | (value as usize) << 32
+                    };
+                }
+            }
+            REG_QUEUE_NOTIFY => {
+                let index = value as usize;
+                if let Some(queue) = self.queues.get_mut(index)
+                    && self.dev.notify(space, index, queue)
+                    && queue.needs_interrupt(space)
+                {
+                    self.interrupt_status |= INT_USED_RING;
+                }
+            }
+            REG_INTERRUPT_ACK => self.interrupt_status &= !value,
+            REG_STATUS => {
+                if value == 0 {
+                    self.reset();
+                } else if value & STATUS_FEATURES_OK != 0
+                    && self.driver_features & !self.features() != 0
+                {
+                    // Features the device does not offer: refuse FEATURES_OK.
+                    self.status = value & !STATUS_FEATURES_OK;
+                } else {
+                    if value & STATUS_FEATURES_OK != 0 && self.status & STATUS_FEATURES_OK == 0 {
+                        self.dev.ack_features(self.driver_features);
+                    }
+                    self.status = value;
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn poll(&mut self, space: &mut GuestSpace) {
+        if self.dev.poll(space, &mut self.queues)
+            && self
+                .queues
+                .iter_mut()
+                .fold(false, |irq, q| q.needs_interrupt(space) | irq)
+        {
+            self.interrupt_status |= INT_USED_RING;
+        }
+    }
+
+    fn irq_pending(&self) -> bool {
+        self.interrupt_status != 0
+    }
+
+    fn save_state(&self, out: &mut StateWriter) -> bool {
+        for reg in [
+            self.device_features_sel,
+            self.driver_features_sel,
+            self.queue_sel,
+            self.status,
+            self.interrupt_status,
+        ] {
+            out.varint(reg.into());
+        }
+        out.varint(self.driver_features);
+        out.varint(self.queues.len() as u64);
+        for queue in &self.queues {
+            queue.save_state(out);
+        }
+        true
+    }
+
+    fn restore_state(&mut self, state: &mut StateReader) -> Result<(), MigrationError> {
+        self.device_features_sel = state.varint_into()?;
+        self.driver_features_sel = state.varint_into()?;
+        self.queue_sel = state.varint_into()?;
+        self.status = state.varint_into()?;
+        self.interrupt_status = state.varint_into()?;
+        self.driver_features = state.varint()?;
+        if state.varint()? != self.queues.len() as u64 {
+            return Err(MigrationError::Corrupt);
+        }
+        for queue in &mut self.queues {
+            *queue = Virtqueue::restore_state(state)?;
+        }
+        // The device model learns the features the driver negotiated.
+        if self.status & STATUS_FEATURES_OK != 0 {
+            self.dev.ack_features(self.driver_features);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::rc::Rc;
+    use core::cell::Cell;
+
+    use memory_addr::VirtAddr;
+
+    use super::*;
+    use crate::gmem::GuestMemory;
+
+    const BASE: usize = 0x1000_1000;
+    const DESC: usize = 0x0;
+    const AVAIL: usize = 0x100;
+    const USED: usize = 0x200;
+    const BUF: usize = 0x1000;
+
+    /// Returns every buffer it receives, upper-cased, in its writable part.
+    struct Upper {
+        acked: Rc<Cell<u64>>,
+    }
+
+    impl VirtioDevice for Upper {
+        fn device_id(&self) -> u32 {
+            0x2A
+        }
+        fn features(&self) -> u64 {
+            1
+        }
+        fn num_queues(&self) -> usize {
+            1
+        }
+        fn read_config(&self, offset: usize, width: usize) -> u64 {
+            super::super::read_config_bytes(b"\x01\x02\x03\x04", offset, width)
+        }
+        fn ack_features(&mut self, features: u64) {
+            self.acked.set(features);
+        }
+        fn notify(&mut self, space: &mut GuestSpace, _index: usize, queue: &mut Virtqueue) -> bool {
+            let mut used = false;
+            while let Some(chain) = queue.pop(space).unwrap() {
+                let data = chain.read_all(space, 0x1000).unwrap().to_ascii_uppercase();
+                let len = chain.write_all(space, &data).unwrap();
+                queue.push_used(space, chain.head, len as u32).unwrap();
+                used = true;
+            }
+            used
+        }
+    }
+
+    fn setup() -> (GuestSpace, VirtioMmio, Rc<Cell<u64>>) {
+        let acked = Rc::new(Cell::new(0));
+        let dev = Upper {
+            acked: acked.clone(),
+        };
+        let space = GuestSpace::new(VirtAddr::from(0), 0x2000).unwrap();
+        (space, VirtioMmio::new(BASE, Box::new(dev)), acked)
+    }
+
+    /// Negotiates `features` and sets up queue 0 of 4 descriptors, as a
+    /// driver does.
+    fn init(space: &mut GuestSpace, mmio: &mut VirtioMmio, features: u64) {
+        mmio.write(space, REG_STATUS, 4, 3);
+        for sel in 0..2 {
+            mmio.write(space, REG_DRIVER_FEATURES_SEL, 4, sel);
+            mmio.write(space, REG_DRIVER_FEATURES, 4, features >> (32 * sel));
+        }
+        mmio.write(space, REG_STATUS, 4, 3 | STATUS_FEATURES_OK as u64);
+        mmio.write(space, REG_QUEUE_SEL, 4, 0);
+        mmio.write(space, REG_QUEUE_NUM, 4, 4);
+        for (reg, addr) in [
+            (REG_QUEUE_DESC_LOW, DESC),
+            (REG_QUEUE_DRIVER_LOW, AVAIL),
+            (REG_QUEUE_DEVICE_LOW, USED),
+        ] {
+            mmio.write(space, reg, 4, addr as u64);
+            mmio.write(space, reg + 4, 4, 0);
+        }
+        mmio.write(space, REG_QUEUE_READY, 4, 1);
+        mmio.write(space, REG_STATUS, 4, 0xF);
+    }
+
+    #[test]
+    fn identifies_the_device() {
+        let (mut space, mut mmio, _) = setup();
+        assert_eq!(mmio.read(&mut space, REG_MAGIC, 4), 0x7472_6976);
+        assert_eq!(mmio.read(&mut space, REG_VERSION, 4), 2);
+        assert_eq!(mmio.read(&mut space, REG_DEVICE_ID, 4), 0x2A);
+        assert_eq!(
+            mmio.read(&mut space, REG_DEVICE_FEATURES, 4),
+            1 | RING_FEATURES
+        );
+        mmio.write(&mut space, REG_DEVICE_FEATURES_SEL, 4, 1);
+        assert_eq!(
+            mmio.read(&mut space, REG_DEVICE_FEATURES, 4),
+            VIRTIO_F_VERSION_1 >> 32
+        );
+        assert_eq!(mmio.read(&mut space, REG_CONFIG + 1, 2), 0x0302);
+        assert_eq!(mmio.read(&mut space, REG_QUEUE_NUM_MAX, 4), 256);
+        mmio.write(&mut space, REG_QUEUE_SEL, 4, 1);
+        assert_eq!(mmio.read(&mut space, REG_QUEUE_NUM_MAX, 4), 0);
+    }
+
+    #[test]
+    fn negotiates_features() {
+        let (mut space, mut mmio, acked) = setup();
+        // Bit 1 is not offered: FEATURES_OK does not stick.
+        mmio.write(&mut space, REG_DRIVER_FEATURES, 4, 0b11);
+        mmio.write(&mut space, REG_STATUS, 4, STATUS_FEATURES_OK as u64);
+        assert_eq!(mmio.read(&mut space, REG_STATUS, 4), 0);
+        assert_eq!(acked.get(), 0);
+
+        mmio.write(&mut space, REG_STATUS, 4, 0);
+        init(&mut space, &mut mmio, 1 | VIRTIO_F_VERSION_1);
+        assert_eq!(acked.get(), 1 | VIRTIO_F_VERSION_1);
+        assert_eq!(mmio.read(&mut space, REG_STATUS, 4), 0xF);
+        assert_eq!(mmio.read(&mut space, REG_QUEUE_READY, 4), 1);
+
+        // A reset forgets the queues.
+        mmio.write(&mut space, REG_STATUS, 4, 0);
+        assert_eq!(mmio.read(&mut space, REG_QUEUE_READY, 4), 0);
+    }
+
+    #[test]
+    fn notify_processes_the_queue() {
+        let (mut space, mut mmio, _) = setup();
+        init(&mut space, &mut mmio, VIRTIO_F_VERSION_1);
+        // One chain: "hi" to read, 2 bytes to write.
+        space.write_obj(BUF, b"hi").unwrap();
+        space
+            .write_obj(DESC, &[BUF as u64, 2 | 1 << 32 | 1 << 48])
+            .unwrap();
+        space
+            .write_obj(DESC + 16, &[BUF as u64 + 0x10, 2 | 2 << 32])
+            .unwrap();
+        space.write_obj(AVAIL + 2, &[1u16, 0]).unwrap();
+
+        mmio.write(&mut space, REG_QUEUE_NOTIFY, 4, 0);
+        assert_eq!(space.read_obj::<[u8; 2]>(BUF + 0x10).unwrap(), *b"HI");
+        assert_eq!(space.read_obj::<[u32; 2]>(USED + 4).unwrap(), [0, 2]);
+        assert!(mmio.irq_pending());
+        assert_eq!(mmio.read(&mut space, REG_INTERRUPT_STATUS, 4), 1);
+        mmio.write(&mut space, REG_INTERRUPT_ACK, 4, 1);
+        assert!(!mmio.irq_pending());
+
+        // Notifying a queue the device does not have is ignored.
+        mmio.write(&mut space, REG_QUEUE_NOTIFY, 4, 3);
+        assert!(!mmio.irq_pending());
+    }
+
+    #[test]
+    fn migrates_transport_state() {
+        let (mut space, mut mmio, _) = setup();
+        init(&mut space, &mut mmio, 1 | VIRTIO_F_VERSION_1);
+        space.write_obj(DESC, &[BUF as u64, 0]).unwrap();
+        space.write_obj(AVAIL + 2, &[1u16, 0]).unwrap();
+        mmio.write(&mut space, REG_QUEUE_NOTIFY, 4, 0);
+        let mut out = StateWriter::new();
+        assert!(mmio.save_state(&mut out));
+        let data = out.into_bytes();
+
+        // The destination's device resumes where the source's stopped.
+        let (_, mut dest, acked) = setup();
+        dest.restore_state(&mut StateReader::new(&data)).unwrap();
+        assert_eq!(acked.get(), 1 | VIRTIO_F_VERSION_1);
+        assert_eq!(dest.read(&mut space, REG_STATUS, 4), 0xF);
+        assert_eq!(dest.read(&mut space, REG_QUEUE_READY, 4), 1);
+        assert!(dest.irq_pending());
+        space.write_obj(AVAIL + 4, &[0u16, 0]).unwrap();
+        space.write_obj(AVAIL + 2, &2u16).unwrap();
+        dest.write(&mut space, REG_QUEUE_NOTIFY, 4, 0);
+        assert_eq!(space.read_obj::<u16>(USED + 2).unwrap(), 2);
+
+        assert_eq!(
+            dest.restore_state(&mut StateReader::new(&data[..data.len() - 1])),
+            Err(MigrationError::Truncated)
+        );
+    }
+}
@@ -0,0 +1,80 @@
+//! Virtio devices exposed to the guests.
+//!
+//! A device model ([`VirtioDevice`]) only deals with its configuration space
+//! and the buffers the driver places on its virtqueues ([`queue`]). The
+//! transports make it visible to the guest:
+//!
+//! - [`mmio::VirtioMmio`]: virtio-mmio (version 2) registers in guest
+//!   physical memory, used on riscv64 and aarch64;
+//! - [`pci::VirtioPciLegacy`]: the legacy virtio-pci I/O BAR register
+//!   layout, used on x86_64.
+
+#![allow(dead_code)]
+
+#[cfg(feature = "axstd")]
+pub mod blk;
+#[cfg(feature = "axstd")]
+pub mod console;
+pub mod mmio;
+#[cfg(feature = "axstd")]
+pub mod net;
+#[cfg(feature = "axstd")]
+pub mod pci;
+pub mod queue;
+
+use crate::gspace::GuestSpace;
+use queue::Virtqueue;
+
+/// Virtio device ID of a network card.
+pub const VIRTIO_ID_NET: u32 = 1;
+/// Virtio device ID of a block device.
+pub const VIRTIO_ID_BLOCK: u32 = 2;
+/// Virtio device ID of a console.
+pub const VIRTIO_ID_CONSOLE: u32 = 3;
+
+/// Device status: the driver is done with feature negotiation.
+pub const STATUS_FEATURES_OK: u32 = 8;
+
+/// Feature: the device complies with virtio 1.0 or later.
+pub const VIRTIO_F_VERSION_1: u64 = 1 << 32;
+
+/// A virtio device model, independent of the transport.
+pub trait VirtioDevice {
+    /// Virtio device ID.
+    fn device_id(&self) -> u32;
+    /// Feature bits offered to the driver.
+    fn features(&self) -> u64;
+    /// Number of virtqueues.
+    fn num_queues(&self) -> usize;
+    /// Reads `width` bytes at `offset` of the device configuration space.
+    fn read_config(&self, offset: usize, width: usize) -> u64;
+    /// Writes `width` bytes at `offset` of the device configuration space.
+    fn write_config(&mut self, _offset: usize, _width: usize, _value: u64) {}
+    /// Informs the device of the features the driver accepted, including
+    /// transport features such as [`VIRTIO_F_VERSION_1`].
+    fn ack_features(&mut self, _features: u64) {}
+    /// Processes the buffers made available on queue `index`.
+    ///
+    /// Returns `true` if buffers were added to the used ring; the transport
+    /// then interrupts the driver unless it suppressed interrupts.
+    fn notify(&mut self, space: &mut GuestSpace, index: usize, queue: &mut Virtqueue) -> bool;
+    /// Delivers events that do not originate from the driver, such as
+    /// received input, to the queues. Called before every guest entry.
+    ///
+    /// Returns `true` if buffers were added to a used ring.
+    fn poll(&mut self, _space: &mut GuestSpace, _queues: &mut [Virtqueue]) -> bool {
+        false
+    }
+    /// Returns the device to its initial state after a transport reset.
+    fn reset(&mut self) {}
+}
+
+/// Reads `width` bytes at `offset` of a little-endian configuration
+/// structure; bytes past its end read as zero.
+pub fn read_config_bytes(config: &[u8], offset: usize, width: usize) -> u64 {
+    let mut value = 0u64;
+    for i in (0..width.min(8)).rev() {
+        value = value << 8 | config.get(offset + i).copied().unwrap_or(0) as u64;
+    }
+    value
+}
@@ -0,0 +1,224 @@
+//! virtio-net device with a hypervisor-internal backend.
+//!
+//! Frames are exchanged through a [`NetBackend`]. The only backend is
+//! [`Loopback`]: a virtual cable between two VMs (VM `2k` and VM `2k + 1`),
+//! enough for ARP, ping and TCP between the pair with static addresses.
+//! Bridging to the host's axnet stack would need a user-mode NAT, as axnet
+//! offers sockets rather than raw Ethernet frames, and is not provided.
+
+use alloc::collections::{BTreeMap, VecDeque};
+use alloc::vec;
+use alloc::vec::Vec;
+
+use axstd::sync::Mutex;
+
+use super::queue::Virtqueue;
+use super::{VIRTIO_F_VERSION_1, VIRTIO_ID_NET, VirtioDevice, read_config_bytes};
+use crate::gspace::GuestSpace;
+
+/// Feature: the device has a MAC address in its configuration space.
+const VIRTIO_NET_F_MAC: u64 = 1 << 5;
+/// Feature: the configuration space has a link status field.
+const VIRTIO_NET_F_STATUS: u64 = 1 << 16;
+
+/// Link status: up.
+const VIRTIO_NET_S_LINK_UP: u16 = 1;
+
+/// Index of receiveq1.
+const RX_QUEUE: usize = 0;
+/// Index of transmitq1.
+const TX_QUEUE: usize = 1;
+
+/// Length of `struct virtio_net_hdr` without `num_buffers` (legacy
+/// interface without mergeable receive buffers).
+const NET_HDR_LEN_LEGACY: usize = 10;
+/// Length of `struct virtio_net_hdr` with `num_buffers` (virtio 1.0).
+const NET_HDR_LEN: usize = 12;
+
+/// Largest Ethernet frame accepted from the guest (without FCS).
+const MAX_FRAME_LEN: usize = 1514;
+
+/// A source and sink of Ethernet frames for one virtio-net device.
+pub trait NetBackend: Send {
+    /// Sends one frame out of the device.
+    fn send(&mut self, frame: &[u8]);
+    /// Takes the next received frame, if any.
+    fn recv(&mut self) -> Option<Vec<u8>>;
+}
+
+/// Frames in flight to each VM on a loopback cable, by VM id.
+static LOOPBACK_INBOX: Mutex<BTreeMap<usize, VecDeque<Vec<u8>>>> = Mutex::new(BTreeMap::new());
+
+/// Frames queued for a VM that does not receive them; further frames are
+/// dropped, like a switch with a full port buffer.
+const LOOPBACK_QUEUE_LEN: usize = 256;
+
+/// One end of a virtual cable between VM `2k` and VM `2k + 1`.
+pub struct Loopback {
+    vm_id: usize,
+}
+
+impl Loopback {
+    /// Plugs VM `vm_id` into its cable. Frames sent to a previous instance
+    /// of the VM (before a reboot) are discarded.
+    pub fn new(vm_id: usize) -> Self {
+        LOOPBACK_INBOX.lock().insert(vm_id, VecDeque::new());
+        Self { vm_id }
+    }
+
+    fn peer(&self) -> usize {
+        self.vm_id ^ 1
+    }
+}
+
+impl NetBackend for Loopback {
+    fn send(&mut self, frame: &[u8]) {
+        let mut inbox = LOOPBACK_INBOX.lock();
+        // Without a peer, the cable is not connected.
+        if let Some(queue) = inbox.get_mut(&self.peer())
+            && queue.len() < LOOPBACK_QUEUE_LEN
+        {
+            queue.push_back(frame.to_vec());
+        }
+    }
+
+    fn recv(&mut self) -> Option<Vec<u8>> {
+        LOOPBACK_INBOX.lock().get_mut(&self.vm_id)?.pop_front()
+    }
+}
+
+impl Drop for Loopback {
+    fn drop(&mut self) {
+        LOOPBACK_INBOX.lock().remove(&self.vm_id);
+    }
+}
+
+/// A network device with one receive and one transmit queue.
+pub struct VirtioNet<B: NetBackend> {
+    backend: B,
+    mac: [u8; 6],
+    /// Header length for the negotiated features.
+    hdr_len: usize,
+    /// A received frame waiting for a receive buffer.
+    pending: Option<Vec<u8>>,
+}
+
+impl<B: NetBackend> VirtioNet<B> {
+    /// Creates a device with MAC address `mac` on `backend`.
+    pub fn new(backend: B, mac: [u8; 6]) -> Self {
+        Self {
+            backend,
+            mac,
+            hdr_len: NET_HDR_LEN_LEGACY,
+            pending: None,
+        }
+    }
+
+    /// Returns the MAC address.
+    pub fn mac(&self) -> [u8; 6] {
+        self.mac
+    }
+
+    /// Copies received frames into the receive buffers, one frame per
+    /// buffer, each after a zeroed header. Returns `true` if any buffer was
+    /// used.
+    fn receive(&mut self, space: &mut GuestSpace, queue: &mut Virtqueue) -> bool {
+        let mut used = false;
+        while let Some(frame) = self.pending.take().or_else(|| self.backend.recv()) {
+            let Ok(Some(chain)) = queue.pop(space) else {
+                // No buffer yet: keep the frame for the next notification.
+                self.pending = Some(frame);
+                break;
+            };
+            let mut packet = vec![0u8; self.hdr_len];
+            if self.hdr_len == NET_HDR_LEN {
+                // num_buffers: the frame is in one buffer.
+                packet[10] = 1;
+            }
+            packet.extend_from_slice(&frame);
+            // Frames larger than the buffer are truncated by write_all.
+            let written = chain.write_all(space, &packet).unwrap_or(0);
+            if queue.push_used(space, chain.head, written as u32).is_err() {
+                break;
+            }
+            used = true;
+        }
+        used
+    }
+
+    /// Sends the frames on the transmit queue to the backend.
+    fn transmit(&mut self, space: &mut GuestSpace, queue: &mut Virtqueue) -> bool {
+        let mut used = false;
+        while let Ok(Some(chain)) = queue.pop(space) {
+            if let Ok(packet) = chain.read_all(space, self.hdr_len + MAX_FRAME_LEN)
+                && packet.len() > self.hdr_len
+            {
+                // Checksum and segmentation offloads are not offered, so the
+                // header carries nothing to act on.
+                self.backend.send(&packet[self.hdr_len..]);
+            }
+            if queue.push_used(space, chain.head, 0).is_err() {
+                break;
+            }
+            used = true;
+        }
+        used
+    }
+}
+
+impl<B: NetBackend> VirtioDevice for VirtioNet<B> {
+    fn device_id(&self) -> u32 {
+        VIRTIO_ID_NET
+    }
+
+    fn features(&self) -> u64 {
+        VIRTIO_NET_F_MAC | VIRTIO_NET_F_STATUS
+    }
+
+    fn ack_features(&mut self, features: u64) {
+        self.hdr_len = if features & VIRTIO_F_VERSION_1 != 0 {
+            NET_HDR_LEN
+        } else {
+            NET_HDR_LEN_LEGACY
+        };
+    }
+
+    fn num_queues(&self) -> usize {
+        2
+    }
+
+    fn read_config(&self, offset: usize, width: usize) -> u64 {
+        // struct virtio_net_config: mac[6], status.
+        let mut config = [0u8; 8];
+        config[..6].copy_from_slice(&self.mac);
+        config[6..].copy_from_slice(&VIRTIO_NET_S_LINK_UP.to_le_bytes());
+        read_config_bytes(&config, offset, width)
+    }
+
+    fn write_config(&mut self, offset: usize, width: usize, value: u64) {
+        // Legacy drivers may set the MAC address through the config space.
+        for i in 0..width {
+            if let Some(byte) = self.mac.get_mut(offset + i) {
+                *byte = (value >> (8 * i)) as u8;
+            }
+        }
+    }
+
+    fn notify(&mut self, space: &mut GuestSpace, index: usize, queue: &mut Virtqueue) -> bool {
+        match index {
+            // New receive buffers: deliver frames that were waiting for them.
+            RX_QUEUE => self.receive(space, queue),
+            TX_QUEUE => self.transmit(space, queue),
+            _ => false,
+        }
+    }
+
+    fn poll(&mut self, space: &mut GuestSpace, queues: &mut [Virtqueue]) -> bool {
+        self.receive(space, &mut queues[RX_QUEUE])
+    }
+
+    fn reset(&mut self) {
+        self.hdr_len = NET_HDR_LEN_LEGACY;
+        self.pending = None;
+    }
+}
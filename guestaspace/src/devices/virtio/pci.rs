@@ -0,0 +1,212 @@
+//! Legacy virtio-pci transport: the I/O BAR register block.
+//!
+//! The registers live in the x86 I/O port space and are reached through port
+//! I/O intercepts, which need no instruction decoding. The device sits behind
+//! the virtual host bridge ([`crate::devices::pci`]), which gives it its
+//! configuration space, with the transitional virtio-pci IDs
+//! ([`VirtioPciLegacy::pci_ids`]), and forwards the accesses to its I/O BAR.
+
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+
+use super::queue::{RING_FEATURES, VIRTIO_F_EVENT_IDX, Virtqueue};
+use super::{VIRTIO_ID_BLOCK, VIRTIO_ID_CONSOLE, VIRTIO_ID_NET, VirtioDevice};
+use crate::devices::mmio::MmioDevice;
+use crate::devices::pci::PciIds;
+use crate::gspace::GuestSpace;
+
+/// Size of the I/O BAR: common registers plus up to 0x2C bytes of device
+/// configuration.
+pub const VIRTIO_PCI_IO_SIZE: usize = 0x40;
+
+/// PCI vendor ID of virtio devices.
+const VIRTIO_PCI_VENDOR: u16 = 0x1AF4;
+/// Transitional PCI device IDs are this plus the virtio device ID minus 1.
+const VIRTIO_PCI_DEVICE_BASE: u16 = 0x1000;
+
+/// Queue size; fixed by the device in the legacy interface.
+const QUEUE_SIZE: u16 = 256;
+/// Legacy queues are laid out with this alignment, in pages of 4K.
+const QUEUE_ALIGN: usize = 4096;
+/// ISR status: used buffer notification.
+const ISR_QUEUE: u8 = 1;
+
+const REG_HOST_FEATURES: usize = 0x00;
+const REG_GUEST_FEATURES: usize = 0x04;
+const REG_QUEUE_PFN: usize = 0x08;
+const REG_QUEUE_NUM: usize = 0x0C;
+const REG_QUEUE_SEL: usize = 0x0E;
+const REG_QUEUE_NOTIFY: usize = 0x10;
+const REG_STATUS: usize = 0x12;
+const REG_ISR: usize = 0x13;
+const REG_CONFIG: usize = 0x14;
+
+/// A virtio device behind a legacy virtio-pci I/O BAR.
+pub struct VirtioPciLegacy {
+    dev: Box<dyn VirtioDevice>,
+    queues: Vec<Virtqueue>,
+    queue_sel: u16,
+    guest_features: u32,
+    status: u8,
+    isr: u8,
+}
+
+impl VirtioPciLegacy {
+    /// Creates the transport of `dev`, to be plugged into the host bridge.
+    pub fn new(dev: Box<dyn VirtioDevice>) -> Self {
+        let queues = (0..dev.num_queues())
+            .map(|_| Virtqueue::new(QUEUE_SIZE))
+            .collect();
+        Self {
+            dev,
+            queues,
+            queue_sel: 0,
+            guest_features: 0,
+            status: 0,
+            isr: 0,
+        }
+    }
+
+    /// Returns the PCI identity of the device: the transitional device ID
+    /// that legacy drivers bind to, the virtio device ID as subsystem and
+    /// the class of the device kind.
+    pub fn pci_ids(&self) -> PciIds {
+        let id = self.dev.device_id();
+        let class = match id {
+            VIRTIO_ID_NET => 0x02_0000,
+            VIRTIO_ID_BLOCK => 0x01_0000,
+            VIRTIO_ID_CONSOLE => 0x07_8000,
+            _ => 0xFF_0000,
+        };
+        PciIds {
+            vendor: VIRTIO_PCI_VENDOR,
+            device: VIRTIO_PCI_DEVICE_BASE + id as u16 - 1,
+            subsystem_vendor: VIRTIO_PCI_VENDOR,
+            subsystem: id as u16,
+            class,
+            revision: 0,
+        }
+    }
+
+    fn features(&self) -> u64 {
+        self.dev.features() | RING_FEATURES
+    }
+
+    fn reset(&mut self) {
+        for queue in &mut self.queues {
+            *queue = Virtqueue::new(QUEUE_SIZE);
+        }
+        self.dev.reset();
+        self.queue_sel = 0;
+        self.guest_features = 0;
+        self.status = 0;
+        self.isr = 0;
+    }
+
+    /// Sets up the selected queue from its page frame number: descriptor
+    /// table, available ring and (page aligned) used ring are contiguous.
+    fn set_queue_pfn(&mut self, pfn: u32) {
+        let Some(queue) = self.queues.get_mut(self.queue_sel as usize) else {
+            return;
+        };
+        *queue = Virtqueue::new(QUEUE_SIZE);
+        if pfn == 0 {
+            return;
+        }
+        let size = QUEUE_SIZE as usize;
+        queue.desc = pfn as usize * QUEUE_ALIGN;
+        queue.avail = queue.desc + 16 * size;
+        queue.used = (queue.avail + 6 + 2 * size).next_multiple_of(QUEUE_ALIGN);
+        queue.event_idx = self.guest_features as u64 & VIRTIO_F_EVENT_IDX != 0;
+        queue.ready = true;
+    }
+}
+
+impl MmioDevice for VirtioPciLegacy {
+    /// The host bridge passes accesses on as offsets into the BAR.
+    fn base(&self) -> usize {
+        0
+    }
+
+    fn size(&self) -> usize {
+        VIRTIO_PCI_IO_SIZE
+    }
+
+    fn read(&mut self, _space: &mut GuestSpace, offset: usize, width: usize) -> u64 {
+        if offset >= REG_CONFIG {
+            return self.dev.read_config(offset - REG_CONFIG, width);
+        }
+        match offset {
+            // Only the low 32 feature bits exist; VERSION_1 is not offered.
+            REG_HOST_FEATURES => self.features() as u32 as u64,
+            REG_GUEST_FEATURES => self.guest_features as u64,
+            REG_QUEUE_PFN => self
+                .queues
+                .get(self.queue_sel as usize)
+                .map_or(0, |q| (q.desc / QUEUE_ALIGN) as u64),
+            REG_QUEUE_NUM => {
+                if (self.queue_sel as usize) < self.queues.len() {
+                    QUEUE_SIZE as u64
+                } else {
+                    0
+                }
+            }
+            REG_QUEUE_SEL => self.queue_sel as u64,
+            REG_STATUS => self.status as u64,
+            REG_ISR => {
+                // Reading the ISR acknowledges the interrupt.
+                let isr = self.isr;
+                self.isr = 0;
+                isr as u64
+            }
+            _ => 0,
+        }
+    }
+
+    fn write(&mut self, space: &mut GuestSpace, offset: usize, width: usize, value: u64) {
+        if offset >= REG_CONFIG {
+            self.dev.write_config(offset - REG_CONFIG, width, value);
+            return;
+        }
+        match offset {
+            REG_GUEST_FEATURES => {
+                self.guest_features = value as u32 & self.features() as u32;
+                self.dev.ack_features(self.guest_features as u64);
+            }
+            REG_QUEUE_PFN => self.set_queue_pfn(value as u32),
+            REG_QUEUE_SEL => self.queue_sel = value as u16,
+            REG_QUEUE_NOTIFY => {
+                let index = value as usize;
+                if let Some(queue) = self.queues.get_mut(index)
+                    && self.dev.notify(space, index, queue)
+                    && queue.needs_interrupt(space)
+                {
+                    self.isr |= ISR_QUEUE;
+                }
+            }
+            REG_STATUS => {
+                if value as u8 == 0 {
+                    self.reset();
+                } else {
+                    self.status = value as u8;
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn poll(&mut self, space: &mut GuestSpace) {
+        if self.dev.poll(space, &mut self.queues)
+            && self
+                .queues
+                .iter_mut()
+                .fold(false, |irq, q| q.needs_interrupt(space) | irq)
+        {
+            self.isr |= ISR_QUEUE;
+        }
+    }
+
+    fn irq_pending(&self) -> bool {
+        self.isr != 0
+    }
+}
@@ -0,0 +1,445 @@
+//! Split virtqueues in guest memory.
+//!
+//! The rings and buffers are reached through [`GuestMemory`], which
+//! translates guest physical addresses through the stage-2 page table and
+//! handles accesses crossing page boundaries, so device models never touch
+//! host addresses directly.
+//!
+//! Supported: descriptor chains, indirect descriptor tables
+//! ([`VIRTIO_F_INDIRECT_DESC`]) and interrupt suppression, either with the
+//! `NO_INTERRUPT` flag or with `used_event` ([`VIRTIO_F_EVENT_IDX`]).
+
+use alloc::vec::Vec;
+use core::sync::atomic::{Ordering, fence};
+
+use axerrno::{AxError, AxResult};
+
+use crate::gmem::GuestMemory;
+use crate::gspace::GuestSpace;
+use crate::migrate::{MigrationError, StateReader, StateWriter};
+
+/// Feature: the driver may use indirect descriptor tables.
+pub const VIRTIO_F_INDIRECT_DESC: u64 = 1 << 28;
+/// Feature: `used_event` and `avail_event` replace the ring flags.
+pub const VIRTIO_F_EVENT_IDX: u64 = 1 << 29;
+
+/// Ring features the transports offer on behalf of every device.
+pub const RING_FEATURES: u64 = VIRTIO_F_INDIRECT_DESC | VIRTIO_F_EVENT_IDX;
+
+/// Descriptor continues via the `next` field.
+const VIRTQ_DESC_F_NEXT: u16 = 1;
+/// Buffer is device write-only.
+const VIRTQ_DESC_F_WRITE: u16 = 2;
+/// Buffer contains a table of descriptors.
+const VIRTQ_DESC_F_INDIRECT: u16 = 4;
+
+/// Available ring flag: the driver does not want used buffer interrupts.
+const VIRTQ_AVAIL_F_NO_INTERRUPT: u16 = 1;
+
+/// Size of a descriptor in the descriptor table.
+const DESC_SIZE: usize = 16;
+
+/// One descriptor of a chain.
+#[derive(Clone, Copy, Debug)]
+pub struct Desc {
+    /// Guest physical address of the buffer.
+    pub addr: usize,
+    /// Buffer length in bytes.
+    pub len: usize,
+    /// Device write-only (`true`) or device read-only buffer.
+    pub write: bool,
+}
+
+/// A descriptor chain taken from the available ring.
+#[derive(Debug)]
+pub struct DescChain {
+    /// Index of the head descriptor, returned in the used ring.
+    pub head: u16,
+    /// The descriptors in chain order, indirect tables flattened.
+    pub descs: Vec<Desc>,
+}
+
+impl DescChain {
+    /// Returns the device-readable part of the chain as one buffer, or
+    /// `InvalidData` if it is longer than `max_len` bytes. The lengths come
+    /// from the guest, so they are checked before anything is allocated.
+    pub fn read_all(&self, space: &GuestSpace, max_len: usize) -> AxResult<Vec<u8>> {
+        let len = self
+            .descs
+            .iter()
+            .filter(|d| !d.write)
+            .try_fold(0usize, |len, d| len.checked_add(d.len))
+            .filter(|&len| len <= max_len)
+            .ok_or(AxError::InvalidData)?;
+        let mut data = Vec::with_capacity(len);
+        for desc in self.descs.iter().filter(|d| !d.write) {
+            let start = data.len();
+            data.resize(start + desc.len, 0);
+            space.copy_from_guest(desc.addr, &mut data[start..])?;
+        }
+        Ok(data)
+    }
+
+    /// Returns the total size of the device-writable buffers.
+    pub fn writable_len(&self) -> usize {
+        self.descs.iter().filter(|d| d.write).map(|d| d.len).sum()
+    }
+
+    /// Copies `data` into the device-writable buffers in order and returns
+    /// the number of bytes written.
+    pub fn write_all(&self, space: &mut GuestSpace, data: &[u8]) -> AxResult<usize> {
+        let mut done = 0;
+        for desc in self.descs.iter().filter(|d| d.write) {
+            if done == data.len() {
+                break;
+            }
+            let len = desc.len.min(data.len() - done);
+            space.copy_to_guest(desc.addr, &data[done..done + len])?;
+            done += len;
+        }
+        Ok(done)
+    }
+}
+
+/// A split virtqueue in guest memory, as configured by the driver.
+#[derive(Clone, Debug, Default)]
+pub struct Virtqueue {
+    /// Number of descriptors (a power of two).
+    pub size: u16,
+    /// The driver enabled the queue.
+    pub ready: bool,
+    /// Guest physical address of the descriptor table.
+    pub desc: usize,
+    /// Guest physical address of the available (driver) ring.
+    pub avail: usize,
+    /// Guest physical address of the used (device) ring.
+    pub used: usize,
+    /// [`VIRTIO_F_EVENT_IDX`] was negotiated.
+    pub event_idx: bool,
+    /// Next available ring entry to consume.
+    last_avail: u16,
+    /// Next used ring entry to fill.
+    used_idx: u16,
+    /// `used_idx` when the driver was last considered for an interrupt.
+    signalled_used: u16,
+}
+
+impl Virtqueue {
+    /// Creates a disabled queue of `size` descriptors.
+    pub fn new(size: u16) -> Self {
+        Self {
+            size,
+            ..Default::default()
+        }
+    }
+
+    /// Appends the queue's configuration and ring positions to `out`, for a
+    /// migration.
+    pub fn save_state(&self, out: &mut StateWriter) {
+        out.varint(self.size.into());
+        out.byte(self.ready as u8 | (self.event_idx as u8) << 1);
+        for addr in [self.desc, self.avail, self.used] {
+            out.varint(addr as u64);
+        }
+        for index in [self.last_avail, self.used_idx, self.signalled_used] {
+            out.varint(index.into());
+        }
+    }
+
+    /// Reads a queue saved by [`save_state`](Self::save_state).
+    pub fn restore_state(state: &mut StateReader) -> Result<Self, MigrationError> {
+        let size: u16 = state.varint_into()?;
+        if !size.is_power_of_two() {
+            return Err(MigrationError::Corrupt);
+        }
+        let flags = state.byte()?;
+        Ok(Self {
+            size,
+            ready: flags & 1 != 0,
+            event_idx: flags & 2 != 0,
+            desc: state.varint_into()?,
+            avail: state.varint_into()?,
+            used: state.varint_into()?,
+            last_avail: state.varint_into()?,
+            used_idx: state.varint_into()?,
+            signalled_used: state.varint_into()?,
+        })
+    }
+
+    /// Takes the next available descriptor chain, if any.
+    pub fn pop(&mut self, space: &mut GuestSpace) -> AxResult<Option<DescChain>> {
+        if !self.ready || self.size == 0 {
+            return Ok(None);
+        }
+        let avail_idx = space.read_obj::<u16>(self.avail + 2)?;
+        if avail_idx == self.last_avail {
+            return Ok(None);
+        }
+        // Read the ring entry only after seeing the index.
+        fence(Ordering::Acquire);
+        let slot = (self.last_avail % self.size) as usize;
+        let head = space.read_obj::<u16>(self.avail + 4 + 2 * slot)?;
+        self.last_avail = self.last_avail.wrapping_add(1);
+        if self.event_idx {
+            // avail_event: notify us as soon as anything new is available.
+            let avail_event = self.used + 4 + 8 * self.size as usize;
+            space.write_obj(avail_event, &self.last_avail)?;
+        }
+
+        let mut descs = Vec::new();
+        self.walk(space, self.desc, self.size, head, true, &mut descs)?;
+        Ok(Some(DescChain { head, descs }))
+    }
+
+    /// Returns the chain starting at `head` to the driver, with `len` bytes
+    /// written into its buffers.
+    pub fn push_used(&mut self, space: &mut GuestSpace, head: u16, len: u32) -> AxResult {
+        let slot = (self.used_idx % self.size) as usize;
+        // struct virtq_used_elem { le32 id; le32 len; }
+        space.write_obj(self.used + 4 + 8 * slot, &[head as u32, len])?;
+        self.used_idx = self.used_idx.wrapping_add(1);
+        // The element must be visible before the index that publishes it.
+        fence(Ordering::Release);
+        space.write_obj(self.used + 2, &self.used_idx)
+    }
+
+    /// Checks whether the driver wants an interrupt for the buffers used
+    /// since the last call.
+    pub fn needs_interrupt(&mut self, space: &GuestSpace) -> bool {
+        let old = self.signalled_used;
+        let new = self.used_idx;
+        if old == new || !self.ready {
+            return false;
+        }
+        self.signalled_used = new;
+        // The driver's suppression state must be read after the used index
+        // was published.
+        fence(Ordering::SeqCst);
+        if self.event_idx {
+            let used_event = self.avail + 4 + 2 * self.size as usize;
+            match space.read_obj::<u16>(used_event) {
+                // Interrupt if used_event lies in [old, new).
+                Ok(event) => new.wrapping_sub(event).wrapping_sub(1) < new.wrapping_sub(old),
+                Err(_) => true,
+            }
+        } else {
+            space
+                .read_obj::<u16>(self.avail)
+                .map_or(true, |flags| flags & VIRTQ_AVAIL_F_NO_INTERRUPT == 0)
+        }
+    }
+
+    /// Appends the chain starting at `index` of the descriptor table at
+    /// `table` (`len` entries) to `descs`. Indirect tables are followed only
+    /// from the main table and may not nest.
+    fn walk(
+        &self,
+        space: &GuestSpace,
+        table: usize,
+        len: u16,
+        mut index: u16,
+        allow_indirect: bool,
+        descs: &mut Vec<Desc>,
+    ) -> AxResult {
+        let mut seen = 0;
+        loop {
+            if index >= len || seen >= len {
+                // Out-of-range index or a loop in the chain.
+                return Err(AxError::InvalidData);
+            }
+            seen += 1;
+            let raw: [u8; DESC_SIZE] = space.read_obj(table + DESC_SIZE * index as usize)?;
+            let addr = u64::from_le_bytes(raw[0..8].try_into().unwrap()) as usize;
+            let size = u32::from_le_bytes(raw[8..12].try_into().unwrap()) as usize;
+            let flags = u16::from_le_bytes([raw[12], raw[13]]);
+            if flags & VIRTQ_DESC_F_INDIRECT != 0 {
+                let entries = size / DESC_SIZE;
+                if !allow_indirect
+                    || flags & VIRTQ_DESC_F_NEXT != 0
+                    || entries == 0
+                    || entries > u16::MAX as usize
+                {
+                    return Err(AxError::InvalidData);
+                }
+                return self.walk(space, addr, entries as u16, 0, false, descs);
+            }
+            descs.push(Desc {
+                addr,
+                len: size,
+                write: flags & VIRTQ_DESC_F_WRITE != 0,
+            });
+            if flags & VIRTQ_DESC_F_NEXT == 0 {
+                return Ok(());
+            }
+            index = u16::from_le_bytes([raw[14], raw[15]]);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use memory_addr::VirtAddr;
+
+    const SIZE: u16 = 8;
+    const DESC: usize = 0x0;
+    const AVAIL: usize = 0x100;
+    const USED: usize = 0x200;
+    const INDIRECT: usize = 0x400;
+
+    /// Guest RAM with an enabled queue of [`SIZE`] descriptors, filled in
+    /// the way a driver does.
+    fn setup(event_idx: bool) -> (GuestSpace, Virtqueue) {
+        let space = GuestSpace::new(VirtAddr::from(0), 0x4000).unwrap();
+        let queue = Virtqueue {
+            ready: true,
+            desc: DESC,
+            avail: AVAIL,
+            used: USED,
+            event_idx,
+            ..Virtqueue::new(SIZE)
+        };
+        (space, queue)
+    }
+
+    fn set_desc(space: &mut GuestSpace, table: usize, index: u16, desc: (usize, u32, u16, u16)) {
+        let (addr, len, flags, next) = desc;
+        let mut raw = [0u8; DESC_SIZE];
+        raw[0..8].copy_from_slice(&(addr as u64).to_le_bytes());
+        raw[8..12].copy_from_slice(&len.to_le_bytes());
+        raw[12..14].copy_from_slice(&flags.to_le_bytes());
+        raw[14..16].copy_from_slice(&next.to_le_bytes());
+        space
+            .write_obj(table + DESC_SIZE * index as usize, &raw)
+            .unwrap();
+    }
+
+    /// Makes the chains starting at `heads` available.
+    fn make_available(space: &mut GuestSpace, heads: &[u16]) {
+        let mut idx = space.read_obj::<u16>(AVAIL + 2).unwrap();
+        for &head in heads {
+            space
+                .write_obj(AVAIL + 4 + 2 * (idx % SIZE) as usize, &head)
+                .unwrap();
+            idx = idx.wrapping_add(1);
+        }
+        space.write_obj(AVAIL + 2, &idx).unwrap();
+    }
+
+    #[test]
+    fn pops_chains_in_order() {
+        let (mut space, mut queue) = setup(false);
+        set_desc(&mut space, DESC, 0, (0x1000, 4, VIRTQ_DESC_F_NEXT, 3));
+        set_desc(&mut space, DESC, 3, (0x2000, 16, VIRTQ_DESC_F_WRITE, 0));
+        set_desc(&mut space, DESC, 5, (0x3000, 2, 0, 0));
+        space.write_obj(0x1000, b"ping").unwrap();
+        assert!(queue.pop(&mut space).unwrap().is_none());
+
+        make_available(&mut space, &[0, 5]);
+        let chain = queue.pop(&mut space).unwrap().unwrap();
+        assert_eq!(chain.head, 0);
+        assert_eq!(chain.descs.len(), 2);
+        assert_eq!(chain.read_all(&space, 4).unwrap(), b"ping");
+        assert_eq!(chain.writable_len(), 16);
+        assert_eq!(chain.write_all(&mut space, b"pong").unwrap(), 4);
+        assert_eq!(space.read_obj::<[u8; 4]>(0x2000).unwrap(), *b"pong");
+
+        assert_eq!(queue.pop(&mut space).unwrap().unwrap().head, 5);
+        assert!(queue.pop(&mut space).unwrap().is_none());
+    }
+
+    #[test]
+    fn follows_indirect_tables() {
+        let (mut space, mut queue) = setup(false);
+        set_desc(
+            &mut space,
+            DESC,
+            1,
+            (INDIRECT, 2 * DESC_SIZE as u32, VIRTQ_DESC_F_INDIRECT, 0),
+        );
+        set_desc(&mut space, INDIRECT, 0, (0x1000, 8, VIRTQ_DESC_F_NEXT, 1));
+        set_desc(&mut space, INDIRECT, 1, (0x2000, 8, VIRTQ_DESC_F_WRITE, 0));
+        make_available(&mut space, &[1]);
+        let chain = queue.pop(&mut space).unwrap().unwrap();
+        assert_eq!(chain.head, 1);
+        let descs: Vec<_> = chain.descs.iter().map(|d| (d.addr, d.write)).collect();
+        assert_eq!(descs, [(0x1000, false), (0x2000, true)]);
+    }
+
+    #[test]
+    fn rejects_malformed_chains() {
+        let (mut space, mut queue) = setup(false);
+        // A loop.
+        set_desc(&mut space, DESC, 0, (0x1000, 4, VIRTQ_DESC_F_NEXT, 1));
+        set_desc(&mut space, DESC, 1, (0x1000, 4, VIRTQ_DESC_F_NEXT, 0));
+        // An index past the table.
+        set_desc(&mut space, DESC, 2, (0x1000, 4, VIRTQ_DESC_F_NEXT, SIZE));
+        // A nested indirect table.
+        set_desc(
+            &mut space,
+            DESC,
+            3,
+            (INDIRECT, DESC_SIZE as u32, VIRTQ_DESC_F_INDIRECT, 0),
+        );
+        set_desc(
+            &mut space,
+            INDIRECT,
+            0,
+            (INDIRECT, DESC_SIZE as u32, VIRTQ_DESC_F_INDIRECT, 0),
+        );
+        make_available(&mut space, &[0, 2, 3]);
+        for _ in 0..3 {
+            assert_eq!(queue.pop(&mut space).unwrap_err(), AxError::InvalidData);
+        }
+    }
+
+    #[test]
+    fn bounds_readable_length() {
+        let (mut space, mut queue) = setup(false);
+        set_desc(&mut space, DESC, 0, (0x1000, 4, VIRTQ_DESC_F_NEXT, 1));
+        set_desc(&mut space, DESC, 1, (0x2000, u32::MAX, 0, 0));
+        make_available(&mut space, &[0]);
+        let chain = queue.pop(&mut space).unwrap().unwrap();
+        assert_eq!(
+            chain.read_all(&space, 0x1000).unwrap_err(),
+            AxError::InvalidData
+        );
+        assert_eq!(chain.read_all(&space, 3).unwrap_err(), AxError::InvalidData);
+    }
+
+    #[test]
+    fn publishes_used_buffers() {
+        let (mut space, mut queue) = setup(false);
+        queue.push_used(&mut space, 4, 32).unwrap();
+        queue.push_used(&mut space, 6, 0).unwrap();
+        assert_eq!(space.read_obj::<u16>(USED + 2).unwrap(), 2);
+        assert_eq!(space.read_obj::<[u32; 2]>(USED + 4).unwrap(), [4, 32]);
+        assert_eq!(space.read_obj::<[u32; 2]>(USED + 12).unwrap(), [6, 0]);
+
+        assert!(queue.needs_interrupt(&space));
+        // Nothing new used since.
+        assert!(!queue.needs_interrupt(&space));
+        space.write_obj(AVAIL, &VIRTQ_AVAIL_F_NO_INTERRUPT).unwrap();
+        queue.push_used(&mut space, 5, 0).unwrap();
+        assert!(!queue.needs_interrupt(&space));
+    }
+
+    #[test]
+    fn event_index_suppresses_interrupts() {
+        let (mut space, mut queue) = setup(true);
+        set_desc(&mut space, DESC, 0, (0x1000, 4, 0, 0));
+        make_available(&mut space, &[0]);
+        queue.pop(&mut space).unwrap().unwrap();
+        // avail_event tells the driver the next index to notify for.
+        let avail_event = USED + 4 + 8 * SIZE as usize;
+        assert_eq!(space.read_obj::<u16>(avail_event).unwrap(), 1);
+
+        // The driver wants an interrupt once used entry 1 is filled.
+        let used_event = AVAIL + 4 + 2 * SIZE as usize;
+        space.write_obj(used_event, &1u16).unwrap();
+        queue.push_used(&mut space, 0, 0).unwrap();
+        assert!(!queue.needs_interrupt(&space));
+        queue.push_used(&mut space, 0, 0).unwrap();
+        assert!(queue.needs_interrupt(&space));
+    }
+}
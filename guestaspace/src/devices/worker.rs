@@ -0,0 +1,154 @@
+//! Device back-ends running on their own tasks.
+//!
+//! The slow part of a device model, such as the file I/O of virtio-blk or
+//! waiting for host console input, runs on a dedicated worker task instead
+//! of in the VM exit path. The device model hands a [`Backend`] to
+//! [`Worker::spawn`], then [submits](Worker::submit) requests from its exit
+//! handlers and collects the [completions](Worker::completions) when the
+//! transport polls it before the next guest entry. A slow disk read thus
+//! no longer stalls the vCPU, and the devices of a VM make progress
+//! concurrently, each on its own task.
+//!
+//! Every worker runs a small event loop: it serves queued requests in
+//! order, polls the back-end for events of its own
+//! ([`Backend::poll`], at [`Backend::POLL_INTERVAL`]) and kicks the VM's
+//! [`IdleQueue`] whenever it queued a completion, so an idle guest is woken
+//! up at once. Guest memory stays with the VM task: requests and
+//! completions carry copies of the data. The worker is stopped and joined
+//! when the [`Worker`] is dropped, after finishing the requests already
+//! submitted.
+
+#![allow(dead_code)]
+
+use alloc::collections::VecDeque;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, Ordering};
+use core::time::Duration;
+
+use axstd::sync::Mutex;
+use axstd::thread::JoinHandle;
+use axtask::WaitQueue;
+
+use crate::idle::IdleQueue;
+
+/// Most completions queued before a worker stops polling its back-end for
+/// events of its own.
+pub const MAX_QUEUED_COMPLETIONS: usize = 64;
+
+/// The part of a device model that runs on a worker task.
+pub trait Backend: Send + 'static {
+    /// Work handed over by the device model.
+    type Request: Send + 'static;
+    /// The result of a request or an event of the back-end.
+    type Completion: Send + 'static;
+
+    /// How often [`Backend::poll`] is called; `None` if the back-end only
+    /// serves requests.
+    const POLL_INTERVAL: Option<Duration> = None;
+
+    /// Serves one request.
+    fn handle(&mut self, req: Self::Request) -> Self::Completion;
+
+    /// Returns an event that no request caused, such as host input.
+    fn poll(&mut self) -> Option<Self::Completion> {
+        None
+    }
+}
+
+/// State shared by a worker task and its device model.
+struct Channel<B: Backend> {
+    requests: Mutex<VecDeque<B::Request>>,
+    completions: Mutex<VecDeque<B::Completion>>,
+    /// The worker task waits here for requests.
+    wq: WaitQueue,
+    stop: AtomicBool,
+    /// Kicked when a completion is queued.
+    idle: Arc<IdleQueue>,
+}
+
+/// A worker task serving one device back-end.
+pub struct Worker<B: Backend> {
+    chan: Arc<Channel<B>>,
+    task: Option<JoinHandle<()>>,
+}
+
+impl<B: Backend> Worker<B> {
+    /// Starts a task named `name` serving `backend`; completions kick
+    /// `idle`.
+    pub fn spawn(name: String, backend: B, idle: Arc<IdleQueue>) -> Self {
+        let chan = Arc::new(Channel {
+            requests: Mutex::new(VecDeque::new()),
+            completions: Mutex::new(VecDeque::new()),
+            wq: WaitQueue::new(),
+            stop: AtomicBool::new(false),
+            idle,
+        });
+        let task = {
+            let chan = chan.clone();
+            axstd::thread::Builder::new()
+                .name(name)
+                .spawn(move || event_loop(backend, &chan))
+                .expect("spawn device worker")
+        };
+        Self {
+            chan,
+            task: Some(task),
+        }
+    }
+
+    /// Queues `req` for the worker.
+    pub fn submit(&self, req: B::Request) {
+        self.chan.requests.lock().push_back(req);
+        self.chan.wq.notify_one(false);
+    }
+
+    /// Takes the completions queued so far, oldest first.
+    pub fn completions(&self) -> Vec<B::Completion> {
+        self.chan.completions.lock().drain(..).collect()
+    }
+}
+
+impl<B: Backend> Drop for Worker<B> {
+    fn drop(&mut self) {
+        self.chan.stop.store(true, Ordering::Release);
+        self.chan.wq.notify_all(false);
+        if let Some(task) = self.task.take() {
+            let _ = task.join();
+        }
+    }
+}
+
+fn event_loop<B: Backend>(mut backend: B, chan: &Channel<B>) {
+    loop {
+        let mut queued = false;
+        while let Some(req) = chan.requests.lock().pop_front() {
+            let completion = backend.handle(req);
+            chan.completions.lock().push_back(completion);
+            queued = true;
+        }
+        if B::POLL_INTERVAL.is_some() {
+            while chan.completions.lock().len() < MAX_QUEUED_COMPLETIONS {
+                let Some(event) = backend.poll() else {
+                    break;
+                };
+                chan.completions.lock().push_back(event);
+                queued = true;
+            }
+        }
+        if queued {
+            chan.idle.kick();
+        }
+        if chan.stop.load(Ordering::Acquire) && chan.requests.lock().is_empty() {
+            break;
+        }
+        let ready = || chan.stop.load(Ordering::Acquire) || !chan.requests.lock().is_empty();
+        match B::POLL_INTERVAL {
+            Some(interval) => {
+                chan.wq.wait_timeout_until(interval, ready);
+            }
+            None => chan.wq.wait_until(ready),
+        }
+    }
+}
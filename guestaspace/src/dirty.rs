@@ -0,0 +1,205 @@
+//! Write-protection based dirty page tracking for guest RAM.
+//!
+//! Enabling the log clears the WRITE permission on every second-stage (or
+//! NPT / guest TTBR0) mapping in the tracked region. The first guest write to
+//! such a page traps into the hypervisor as a permission fault, the page's
+//! GPA is recorded in a bitmap and WRITE is restored so the guest can resume.
+//!
+//! Huge mappings are tracked at their own granularity: a write to a 2M page
+//! marks all of its 4K pages dirty.
+//!
+//! The caller is responsible for invalidating the guest TLB whenever a call
+//! write-protects pages again.
+
+#![allow(dead_code)]
+
+use alloc::vec;
+use alloc::vec::Vec;
+
+use axerrno::{AxError, AxResult};
+use axhal::paging::MappingFlags;
+use memory_addr::PAGE_SIZE_4K;
+
+use crate::gspace::GuestSpace;
+
+/// Dirty page bitmap for one contiguous region of guest physical memory.
+pub struct DirtyLog {
+    /// First guest physical address covered by the log (4K aligned).
+    base: usize,
+    /// Number of 4K pages covered by the log.
+    pages: usize,
+    /// Mapping flags of the region when writes are allowed.
+    flags: MappingFlags,
+    /// One bit per page, set when the page has been written.
+    bitmap: Vec<u64>,
+    /// Whether write protection is currently armed.
+    enabled: bool,
+}
+
+impl DirtyLog {
+    /// Creates a (disabled) dirty log for `[base, base + size)`.
+    ///
+    /// `flags` are the normal mapping flags of the region and must include
+    /// `WRITE`; they are restored on every recorded page.
+    pub fn new(base: usize, size: usize, flags: MappingFlags) -> AxResult<Self> {
+        if !base.is_multiple_of(PAGE_SIZE_4K) || !size.is_multiple_of(PAGE_SIZE_4K) {
+            return Err(AxError::InvalidInput);
+        }
+        if !flags.contains(MappingFlags::WRITE) {
+            return Err(AxError::InvalidInput);
+        }
+        let pages = size / PAGE_SIZE_4K;
+        Ok(Self {
+            base,
+            pages,
+            flags,
+            bitmap: vec![0; pages.div_ceil(64)],
+            enabled: false,
+        })
+    }
+
+    /// Returns the tracked guest physical range as `(base, size)`.
+    pub fn range(&self) -> (usize, usize) {
+        (self.base, self.pages * PAGE_SIZE_4K)
+    }
+
+    /// Returns whether write protection is currently armed.
+    pub fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+
+    /// Returns whether `gpa` falls inside the tracked region.
+    pub fn contains(&self, gpa: usize) -> bool {
+        gpa >= self.base && gpa - self.base < self.pages * PAGE_SIZE_4K
+    }
+
+    /// Starts logging: clears the bitmap and write-protects the whole region.
+    ///
+    /// The guest TLB must be flushed before the guest runs again.
+    pub fn enable(&mut self, aspace: &mut GuestSpace) -> AxResult {
+        self.bitmap.fill(0);
+        aspace
+            .protect(
+                self.base.into(),
+                self.pages * PAGE_SIZE_4K,
+                self.flags - MappingFlags::WRITE,
+            )
+            .map_err(|_| AxError::BadState)?;
+        self.enabled = true;
+        Ok(())
+    }
+
+    /// Stops logging and restores write access to the whole region.
+    ///
+    /// The bitmap is kept so that it can still be fetched afterwards.
+    pub fn disable(&mut self, aspace: &mut GuestSpace) -> AxResult {
+        aspace
+            .protect(self.base.into(), self.pages * PAGE_SIZE_4K, self.flags)
+            .map_err(|_| AxError::BadState)?;
+        self.enabled = false;
+        Ok(())
+    }
+
+    /// Handles a guest write fault at `gpa`.
+    ///
+    /// Returns `true` if the fault was caused by dirty logging, in which case
+    /// the page has been recorded and made writable again. Returns `false` if
+    /// the fault must be handled elsewhere.
+    pub fn handle_write_fault(&mut self, aspace: &mut GuestSpace, gpa: usize) -> bool {
+        if !self.enabled || !self.contains(gpa) {
+            return false;
+        }
+        // Only a present, write-protected page can be a logging fault.
+        let page_size = match aspace.query(gpa.into()) {
+            Ok((_, flags, size)) if !flags.contains(MappingFlags::WRITE) => size as usize,
+            _ => return false,
+        };
+        let page = gpa & !(page_size - 1);
+        if aspace.protect(page.into(), page_size, self.flags).is_err() {
+            return false;
+        }
+        // Read-only RAM (the code of a packaged image) stays so: the write
+        // is the guest's fault.
+        if !aspace
+            .query(gpa.into())
+            .is_ok_and(|(_, flags, _)| flags.contains(MappingFlags::WRITE))
+        {
+            return false;
+        }
+        for offset in (0..page_size).step_by(PAGE_SIZE_4K) {
+            if self.contains(page + offset) {
+                self.set_dirty(page + offset);
+            }
+        }
+        true
+    }
+
+    /// Records a write to `gpa` that was handled elsewhere (e.g. by breaking
+    /// copy-on-write sharing), if it falls inside the tracked region.
+    pub fn record_write(&mut self, gpa: usize) {
+        if self.enabled && self.contains(gpa) {
+            self.set_dirty(gpa & !(PAGE_SIZE_4K - 1));
+        }
+    }
+
+    /// Returns whether the page containing `gpa` has been written.
+    pub fn is_dirty(&self, gpa: usize) -> bool {
+        if !self.contains(gpa) {
+            return false;
+        }
+        let idx = (gpa - self.base) / PAGE_SIZE_4K;
+        self.bitmap[idx / 64] & (1 << (idx % 64)) != 0
+    }
+
+    /// Returns the number of pages written since the last fetch.
+    pub fn dirty_count(&self) -> usize {
+        self.bitmap.iter().map(|w| w.count_ones() as usize).sum()
+    }
+
+    /// Returns the raw bitmap; bit `n` corresponds to page `base + n * 4K`.
+    pub fn bitmap(&self) -> &[u64] {
+        &self.bitmap
+    }
+
+    /// Returns the GPAs of all dirty pages, clears the bitmap and
+    /// write-protects the returned pages again.
+    ///
+    /// The guest TLB must be flushed before the guest runs again if the
+    /// returned list is not empty.
+    pub fn clear_and_fetch(&mut self, aspace: &mut GuestSpace) -> AxResult<Vec<usize>> {
+        let mut dirty = Vec::with_capacity(self.dirty_count());
+        for (word_idx, word) in self.bitmap.iter_mut().enumerate() {
+            let mut bits = core::mem::take(word);
+            while bits != 0 {
+                let bit = bits.trailing_zeros() as usize;
+                bits &= bits - 1;
+                dirty.push(self.base + (word_idx * 64 + bit) * PAGE_SIZE_4K);
+            }
+        }
+        if self.enabled {
+            let ro_flags = self.flags - MappingFlags::WRITE;
+            // Dirty pages of one huge mapping are re-protected together.
+            let mut protected_end = 0;
+            for &gpa in &dirty {
+                if gpa < protected_end {
+                    continue;
+                }
+                let page_size = match aspace.query(gpa.into()) {
+                    Ok((_, _, size)) => size as usize,
+                    Err(_) => PAGE_SIZE_4K,
+                };
+                let page = gpa & !(page_size - 1);
+                aspace
+                    .protect(page.into(), page_size, ro_flags)
+                    .map_err(|_| AxError::BadState)?;
+                protected_end = page + page_size;
+            }
+        }
+        Ok(dirty)
+    }
+
+    fn set_dirty(&mut self, page: usize) {
+        let idx = (page - self.base) / PAGE_SIZE_4K;
+        self.bitmap[idx / 64] |= 1 << (idx % 64);
+    }
+}
@@ -0,0 +1,213 @@
+//! Table-driven dispatch of VM exits.
+//!
+//! A run loop sorts every exit of its vCPU into an [`ExitClass`] and hands
+//! it to the handler registered for that class in its [`ExitDispatcher`].
+//! The handlers are registered when the VM is built. They work on the vCPU
+//! state `S` of the architecture, which holds the guest registers and
+//! whatever else the loop owns (devices, the address space, the console),
+//! and each decides whether the guest is re-entered or the VM ends, and
+//! how. A class without a handler goes to the dispatcher's fallback, which
+//! reports the exit as unhandled.
+//!
+//! The core loop thus only enters the guest, classifies and traces the
+//! exit and dispatches it: a new exit type is a new handler, and a handler
+//! can run on a state built without a guest.
+
+#![allow(dead_code)]
+
+use core::ops::ControlFlow;
+
+/// What an exit is about, as far as dispatching goes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ExitClass {
+    /// A host interrupt ended the run.
+    Interrupt,
+    /// An SBI call: ECALL from VS-mode (riscv64).
+    Sbi,
+    /// A hypercall: SVC (aarch64) or VMMCALL (x86_64).
+    Hypercall,
+    /// A stage-2 fault on RAM or passthrough memory, or a guest page fault
+    /// under shadow paging (x86_64).
+    Npf,
+    /// A stage-2 fault on an emulated device.
+    Mmio,
+    /// Port I/O (x86_64).
+    Pio,
+    /// WFI or HLT.
+    Halt,
+    /// An instruction the hypervisor emulates: riscv64 virtual instructions,
+    /// x86_64 RDTSC/RDTSCP and the CR3, CR4 and INVLPG intercepts of shadow
+    /// paging.
+    Insn,
+    /// An FP/SIMD access with the guest's FP registers not loaded; on
+    /// riscv64, any illegal instruction.
+    Fp,
+    /// A debug exception: breakpoint, watchpoint or single step.
+    Debug,
+    /// The guest reset itself (x86_64 triple fault).
+    Reset,
+    /// Anything else.
+    Other,
+}
+
+impl ExitClass {
+    /// Number of classes.
+    pub const COUNT: usize = 12;
+
+    /// All classes, in the order of their numbers in exit traces.
+    pub const ALL: [ExitClass; Self::COUNT] = [
+        Self::Interrupt,
+        Self::Sbi,
+        Self::Hypercall,
+        Self::Npf,
+        Self::Mmio,
+        Self::Pio,
+        Self::Halt,
+        Self::Insn,
+        Self::Fp,
+        Self::Debug,
+        Self::Reset,
+        Self::Other,
+    ];
+
+    /// Returns the name of the class in exit traces.
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Interrupt => "irq",
+            Self::Sbi => "sbi",
+            Self::Hypercall => "hypercall",
+            Self::Npf => "npf",
+            Self::Mmio => "mmio",
+            Self::Pio => "pio",
+            Self::Halt => "halt",
+            Self::Insn => "insn",
+            Self::Fp => "fp",
+            Self::Debug => "debug",
+            Self::Reset => "reset",
+            Self::Other => "other",
+        }
+    }
+
+    fn index(self) -> usize {
+        self as usize
+    }
+}
+
+/// Handles one exit of the vCPU `S`: `Continue` re-enters the guest,
+/// `Break` ends the run loop with the value.
+pub type ExitHandler<S, B> = fn(&mut S) -> ControlFlow<B>;
+
+/// The exit handlers of a vCPU, by class.
+pub struct ExitDispatcher<S, B> {
+    handlers: [Option<ExitHandler<S, B>>; ExitClass::COUNT],
+    fallback: ExitHandler<S, B>,
+}
+
+impl<S, B> ExitDispatcher<S, B> {
+    /// Creates a dispatcher that sends every exit to `fallback`.
+    pub fn new(fallback: ExitHandler<S, B>) -> Self {
+        Self {
+            handlers: [None; ExitClass::COUNT],
+            fallback,
+        }
+    }
+
+    /// Registers `handler` for the exits of `class`.
+    ///
+    /// # Panics
+    ///
+    /// If `class` already has a handler.
+    pub fn register(&mut self, class: ExitClass, handler: ExitHandler<S, B>) -> &mut Self {
+        let slot = &mut self.handlers[class.index()];
+        assert!(slot.is_none(), "{class:?} exits already have a handler");
+        *slot = Some(handler);
+        self
+    }
+
+    /// Checks whether `class` has a handler of its own.
+    pub fn handles(&self, class: ExitClass) -> bool {
+        self.handlers[class.index()].is_some()
+    }
+
+    /// Runs the handler of `class`, or the fallback, on `state`.
+    pub fn dispatch(&self, class: ExitClass, state: &mut S) -> ControlFlow<B> {
+        let handler = self.handlers[class.index()].unwrap_or(self.fallback);
+        handler(state)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A vCPU state without a guest: the exits its handlers saw.
+    #[derive(Default)]
+    struct State {
+        seen: &'static str,
+    }
+
+    fn on_mmio(state: &mut State) -> ControlFlow<u32> {
+        state.seen = "mmio";
+        ControlFlow::Continue(())
+    }
+
+    fn on_halt(state: &mut State) -> ControlFlow<u32> {
+        state.seen = "halt";
+        ControlFlow::Break(0)
+    }
+
+    fn unhandled(state: &mut State) -> ControlFlow<u32> {
+        state.seen = "fallback";
+        ControlFlow::Break(1)
+    }
+
+    #[test]
+    fn classes_are_numbered_in_order() {
+        for (i, class) in ExitClass::ALL.into_iter().enumerate() {
+            assert_eq!(class.index(), i);
+        }
+    }
+
+    #[test]
+    fn dispatches_by_class() {
+        let mut dispatcher = ExitDispatcher::new(unhandled);
+        dispatcher
+            .register(ExitClass::Mmio, on_mmio)
+            .register(ExitClass::Halt, on_halt);
+        let mut state = State::default();
+
+        assert_eq!(
+            dispatcher.dispatch(ExitClass::Mmio, &mut state),
+            ControlFlow::Continue(())
+        );
+        assert_eq!(state.seen, "mmio");
+        assert_eq!(
+            dispatcher.dispatch(ExitClass::Halt, &mut state),
+            ControlFlow::Break(0)
+        );
+        assert_eq!(state.seen, "halt");
+        assert!(dispatcher.handles(ExitClass::Halt));
+        assert!(!dispatcher.handles(ExitClass::Pio));
+    }
+
+    #[test]
+    fn unregistered_classes_go_to_the_fallback() {
+        let dispatcher = ExitDispatcher::new(unhandled);
+        let mut state = State::default();
+        for class in ExitClass::ALL {
+            assert_eq!(
+                dispatcher.dispatch(class, &mut state),
+                ControlFlow::Break(1)
+            );
+        }
+        assert_eq!(state.seen, "fallback");
+    }
+
+    #[test]
+    #[should_panic(expected = "Mmio exits already have a handler")]
+    fn one_handler_per_class() {
+        ExitDispatcher::new(unhandled)
+            .register(ExitClass::Mmio, on_mmio)
+            .register(ExitClass::Mmio, on_halt);
+    }
+}
@@ -0,0 +1,143 @@
+//! Crash report of a VM ended by an exit the hypervisor does not handle.
+//!
+//! Before a run loop gives up with [`VmError::UnhandledExit`], it prints a
+//! report on the VM's console: the guest's general purpose registers, the
+//! trap and system registers of the architecture, the bytes of the guest
+//! instruction at the PC, and the stage-2 walks of the PC and of the fault
+//! address.
+//!
+//! The stage-2 printers ([`print_walk`], [`print_stage2`]) are also usable
+//! on their own when diagnosing guest faults.
+//!
+//! [`VmError::UnhandledExit`]: crate::error::VmError::UnhandledExit
+
+#![allow(dead_code)]
+
+use alloc::string::String;
+use core::fmt::Write;
+
+use memory_addr::{PAGE_SIZE_4K, VirtAddr};
+
+use crate::gmem::GuestMemory;
+use crate::gspace::{GuestSpace, Stage2Entry, Stage2Walk};
+
+/// Number of instruction bytes shown at the guest PC.
+const CODE_BYTES: usize = 16;
+
+/// Registers shown per line.
+const REGS_PER_LINE: usize = 4;
+
+/// The state of a vCPU at an unhandled exit.
+pub struct CrashDump<'a> {
+    /// General purpose registers, by name.
+    pub gprs: &'a [(&'static str, u64)],
+    /// Trap and system registers, by name.
+    pub sysregs: &'a [(&'static str, u64)],
+    /// Guest physical address of the PC, if the guest's own translation of
+    /// it is known.
+    pub pc_gpa: Option<usize>,
+    /// Guest physical address the exit reported, if any.
+    pub fault_gpa: Option<usize>,
+}
+
+impl CrashDump<'_> {
+    /// Prints the report on the console of VM `vm`.
+    pub fn print(&self, vm: usize, space: &GuestSpace) {
+        vm_println!(vm, "---- crash dump ----");
+        print_regs(vm, self.gprs);
+        print_regs(vm, self.sysregs);
+        match self.pc_gpa {
+            Some(gpa) => {
+                // Up to the end of the page: the next one may be unmapped.
+                let len = CODE_BYTES.min(PAGE_SIZE_4K - gpa % PAGE_SIZE_4K);
+                let mut code = [0u8; CODE_BYTES];
+                match space.copy_from_guest(gpa, &mut code[..len]) {
+                    Ok(()) => vm_println!(vm, "code at {:#x}: {:02x?}", gpa, &code[..len]),
+                    Err(e) => vm_println!(vm, "code at {:#x}: unreadable ({:?})", gpa, e),
+                }
+                print_translation(vm, "pc", space, gpa);
+            }
+            None => vm_println!(vm, "code: the guest PC has no known guest physical address"),
+        }
+        if let Some(gpa) = self.fault_gpa {
+            print_translation(vm, "fault", space, gpa);
+        }
+        vm_println!(vm, "--------------------");
+    }
+}
+
+fn print_regs(vm: usize, regs: &[(&'static str, u64)]) {
+    for line in regs.chunks(REGS_PER_LINE) {
+        let mut text = String::new();
+        for (name, value) in line {
+            let _ = write!(text, "{:>9}={:#018x}", name, value);
+        }
+        vm_println!(vm, "{}", text);
+    }
+}
+
+/// Prints the stage-2 walk of `gpa`, one line per level.
+fn print_translation(vm: usize, what: &str, space: &GuestSpace, gpa: usize) {
+    let walk = space.walk(VirtAddr::from(gpa));
+    match walk.hpa() {
+        Some(hpa) => vm_println!(vm, "{} gpa {:#x} -> hpa {:#x}", what, gpa, hpa),
+        None => vm_println!(vm, "{} gpa {:#x}: not mapped in stage 2", what, gpa),
+    }
+    print_walk(vm, &walk);
+}
+
+/// Prints the entries of a stage-2 walk, root first.
+pub fn print_walk(vm: usize, walk: &Stage2Walk) {
+    for entry in &walk.entries {
+        print_entry(vm, entry);
+    }
+    if let Some((level, index)) = walk.missing {
+        vm_println!(vm, "  L{}[{:3}] not present", level, index);
+    }
+}
+
+/// Prints the present stage-2 entries that cover part of `[start, start +
+/// size)`, indented by level.
+pub fn print_stage2(vm: usize, space: &GuestSpace, start: usize, size: usize) {
+    let end = start.saturating_add(size);
+    vm_println!(
+        vm,
+        "stage-2 table at {:#x}, gpa [{:#x}, {:#x}):",
+        space.page_table_root(),
+        start,
+        end
+    );
+    space.for_each_entry(|entry| {
+        if entry.gpa < end && entry.gpa.saturating_add(entry.span) > start {
+            print_entry(vm, entry);
+        }
+    });
+}
+
+fn print_entry(vm: usize, entry: &Stage2Entry) {
+    let indent = "  ".repeat(entry.level + 1);
+    match entry.page_size {
+        Some(size) => vm_println!(
+            vm,
+            "{}L{}[{:3}] gpa {:#x} -> {:#x} {:?} page, {:?} (pte {:#x})",
+            indent,
+            entry.level,
+            entry.index,
+            entry.gpa,
+            entry.paddr,
+            size,
+            entry.flags,
+            entry.bits
+        ),
+        None => vm_println!(
+            vm,
+            "{}L{}[{:3}] gpa {:#x} -> table {:#x} (pte {:#x})",
+            indent,
+            entry.level,
+            entry.index,
+            entry.gpa,
+            entry.paddr,
+            entry.bits
+        ),
+    }
+}
@@ -0,0 +1,146 @@
+//! Errors that terminate a single VM.
+//!
+//! Building a VM and running it return a [`VmError`] instead of panicking
+//! when the configuration cannot be honoured or the guest does something
+//! the hypervisor cannot handle. The VM concerned is torn down and the
+//! error is reported on its console; the hypervisor and the other VMs keep
+//! running.
+
+#![allow(dead_code)]
+
+use alloc::format;
+use alloc::string::String;
+use core::fmt;
+
+use crate::permfault::PermissionFault;
+
+/// Why a VM was terminated.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum VmError {
+    /// Building the VM failed at `step`.
+    Setup { step: &'static str, reason: String },
+    /// The memory map region `region` at `[start, end)` overlaps region
+    /// `other`.
+    RegionOverlap {
+        region: &'static str,
+        start: usize,
+        end: usize,
+        other: &'static str,
+    },
+    /// The memory map region `region` at `[start, end)` belongs in guest
+    /// RAM but is not inside it.
+    RegionOutsideRam {
+        region: &'static str,
+        start: usize,
+        end: usize,
+    },
+    /// The guest touched more RAM than its memory cap; `used` bytes were
+    /// backed.
+    MemoryLimit { used: usize },
+    /// A guest access to `gpa` that no RAM or device backs and that cannot
+    /// be passed through.
+    UnmappableFault { gpa: usize, pc: usize },
+    /// A guest access that the permissions of its memory deny, on a guest
+    /// that cannot take the exception for it (aarch64).
+    PermissionFault(PermissionFault),
+    /// An access to the emulated device at `addr` that cannot be emulated
+    /// (undecodable instruction, unsupported width or command).
+    UnsupportedAccess { addr: usize, pc: usize },
+    /// The stage-2 fault at `gpa` came back `count` times among the recent
+    /// fixed-up faults of a vCPU: its fix-up does not stick.
+    RepeatedFault { gpa: usize, pc: usize, count: usize },
+    /// The instruction at `pc` that caused the exit cannot be read.
+    InsnFetch { pc: usize },
+    /// The guest switched at `pc` to a paging mode that shadow paging does
+    /// not support.
+    UnsupportedPaging { pc: usize },
+    /// The guest halted with interrupts disabled, which it can never leave.
+    HaltedForever { pc: usize },
+    /// The guest showed no sign of life for `secs` seconds; it was at `pc`.
+    WatchdogExpired { secs: u64, pc: usize },
+    /// An exit the hypervisor does not handle: `code` is the riscv64
+    /// `scause`, the aarch64 exception class or the x86_64 exit code, `info`
+    /// the trap details (`stval`/`htval`, ESR/FAR, EXITINFO1/EXITINFO2).
+    UnhandledExit {
+        code: usize,
+        pc: usize,
+        info: [usize; 2],
+    },
+}
+
+impl VmError {
+    /// Returns a mapper of the error of setup step `step` to a
+    /// [`VmError::Setup`], for use with `map_err`.
+    pub fn setup<E: fmt::Debug>(step: &'static str) -> impl FnOnce(E) -> Self {
+        move |e| Self::Setup {
+            step,
+            reason: format!("{:?}", e),
+        }
+    }
+}
+
+impl fmt::Display for VmError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Setup { step, reason } => write!(f, "cannot {}: {}", step, reason),
+            Self::RegionOverlap {
+                region,
+                start,
+                end,
+                other,
+            } => write!(
+                f,
+                "memory map: {} [{:#x}, {:#x}) overlaps {}",
+                region, start, end, other
+            ),
+            Self::RegionOutsideRam { region, start, end } => write!(
+                f,
+                "memory map: {} [{:#x}, {:#x}) is not inside guest RAM",
+                region, start, end
+            ),
+            Self::MemoryLimit { used } => {
+                write!(f, "memory limit reached ({} KiB used)", used / 1024)
+            }
+            Self::UnmappableFault { gpa, pc } => {
+                write!(f, "unmappable guest access at {:#x}, pc={:#x}", gpa, pc)
+            }
+            Self::PermissionFault(fault) => write!(f, "guest {}", fault),
+            Self::UnsupportedAccess { addr, pc } => {
+                write!(f, "unsupported device access at {:#x}, pc={:#x}", addr, pc)
+            }
+            Self::RepeatedFault { gpa, pc, count } => write!(
+                f,
+                "guest access at {:#x} faulted {} times in the last {} stage-2 faults, pc={:#x}",
+                gpa,
+                count,
+                crate::refault::HISTORY,
+                pc
+            ),
+            Self::InsnFetch { pc } => {
+                write!(f, "cannot fetch the trapping instruction at pc={:#x}", pc)
+            }
+            Self::UnsupportedPaging { pc } => {
+                write!(
+                    f,
+                    "paging mode not supported by shadow paging at pc={:#x}",
+                    pc
+                )
+            }
+            Self::HaltedForever { pc } => {
+                write!(f, "halted with interrupts disabled at pc={:#x}", pc)
+            }
+            Self::WatchdogExpired { secs, pc } => {
+                write!(
+                    f,
+                    "watchdog expired: no sign of life for {} s, pc={:#x}",
+                    secs, pc
+                )
+            }
+            Self::UnhandledExit { code, pc, info } => write!(
+                f,
+                "unhandled exit {:#x} at pc={:#x} ({:#x}, {:#x})",
+                code, pc, info[0], info[1]
+            ),
+        }
+    }
+}
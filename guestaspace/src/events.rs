@@ -0,0 +1,131 @@
+//! Interrupts and exceptions pending for a vCPU.
+//!
+//! Device models and exit handlers raise events on a vCPU's
+//! [`PendingEvents`] without knowing how the architecture delivers them; the
+//! run loop lands them in the guest right before the next entry:
+//!
+//! - riscv64: interrupt lines are the `hvip` bits (VS software 2, VS timer
+//!   6, VS external 10); exceptions are taken through the VS-level trap
+//!   CSRs (`VmCpuRegisters::land_events`);
+//! - x86_64: the highest pending line becomes the VMCB virtual interrupt
+//!   (the line number is the vector, 32-63); exceptions go to `EVENTINJ`
+//!   (`Vmcb::land_events`). Once the guest enabled its local APIC, device
+//!   lines go through the APIC instead, which offers its own vector;
+//! - aarch64: the guest runs at EL0 and takes no interrupts or exceptions
+//!   from the hypervisor (there is no vGIC and no `HCR_EL2.VI` at EL1): a
+//!   pending line (GIC INTIDs: virtual timer 27, devices 32) ends a WFI
+//!   idle, and the guest polls the device or timer.
+//!
+//! Interrupt lines are level-triggered: they stay pending until lowered. A
+//! line raised with [`PendingEvents::pulse_irq`] is edge-triggered instead:
+//! it is pending until the guest takes the interrupt. Exceptions are delivered once, in the order they were pushed, one per
+//! entry.
+
+#![allow(dead_code)]
+
+use alloc::collections::VecDeque;
+
+/// An exception waiting for delivery.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PendingException {
+    /// Exception number: `scause` code on riscv64, vector on x86_64.
+    pub vector: u32,
+    /// `stval` on riscv64, error code on x86_64 (if the vector has one).
+    pub error: Option<u64>,
+}
+
+/// The events pending for one vCPU.
+#[derive(Default)]
+pub struct PendingEvents {
+    /// Bit `n` set: interrupt line `n` is asserted.
+    irqs: u64,
+    /// The asserted lines that are edge-triggered.
+    edges: u64,
+    exceptions: VecDeque<PendingException>,
+}
+
+impl PendingEvents {
+    /// Creates an empty set of events.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Asserts (`level` set) or deasserts interrupt line `irq` (0-63).
+    pub fn set_irq(&mut self, irq: u32, level: bool) {
+        self.edges &= !(1 << irq);
+        if level {
+            self.irqs |= 1 << irq;
+        } else {
+            self.irqs &= !(1 << irq);
+        }
+    }
+
+    /// Asserts interrupt line `irq`.
+    pub fn raise_irq(&mut self, irq: u32) {
+        self.set_irq(irq, true);
+    }
+
+    /// Deasserts interrupt line `irq`.
+    pub fn lower_irq(&mut self, irq: u32) {
+        self.set_irq(irq, false);
+    }
+
+    /// Raises edge-triggered interrupt line `irq`: it stays pending until
+    /// the guest takes it ([`PendingEvents::ack_irq`]).
+    pub fn pulse_irq(&mut self, irq: u32) {
+        self.irqs |= 1 << irq;
+        self.edges |= 1 << irq;
+    }
+
+    /// Tells that the guest took interrupt `irq`: an edge-triggered line
+    /// drops, a level-triggered one stays asserted.
+    pub fn ack_irq(&mut self, irq: u32) {
+        if self.edges & (1 << irq) != 0 {
+            self.irqs &= !(1 << irq);
+            self.edges &= !(1 << irq);
+        }
+    }
+
+    /// Checks whether interrupt line `irq` is asserted.
+    pub fn irq_pending(&self, irq: u32) -> bool {
+        self.irqs & (1 << irq) != 0
+    }
+
+    /// Returns the asserted interrupt lines as a bit mask.
+    pub fn irqs(&self) -> u64 {
+        self.irqs
+    }
+
+    /// Returns the highest asserted interrupt line.
+    pub fn highest_irq(&self) -> Option<u32> {
+        (self.irqs != 0).then(|| 63 - self.irqs.leading_zeros())
+    }
+
+    /// Queues exception `vector` with the given error code or trap value.
+    pub fn push_exception(&mut self, vector: u32, error: Option<u64>) {
+        self.exceptions
+            .push_back(PendingException { vector, error });
+    }
+
+    /// Returns the next exception to deliver, leaving it queued.
+    pub fn next_exception(&self) -> Option<PendingException> {
+        self.exceptions.front().copied()
+    }
+
+    /// Takes the next exception to deliver.
+    pub fn take_exception(&mut self) -> Option<PendingException> {
+        self.exceptions.pop_front()
+    }
+
+    /// Checks whether anything is pending.
+    pub fn is_empty(&self) -> bool {
+        self.irqs == 0 && self.exceptions.is_empty()
+    }
+
+    /// Drops all pending events (vCPU reset).
+    pub fn clear(&mut self) {
+        self.irqs = 0;
+        self.edges = 0;
+        self.exceptions.clear();
+    }
+}
@@ -0,0 +1,81 @@
+//! Exit codes of guests and the exit status of the hypervisor.
+//!
+//! Guests power off with an exit code (0: success):
+//!
+//! - riscv64: the reason of an SBI SRST shutdown, `0xF000_0000 + code` (no
+//!   reason is 0, a system failure 1);
+//! - aarch64: `x0` of the exit SVC (`x8` = 2); PSCI `SYSTEM_OFF` is 0;
+//! - x86_64: `RAX` = `code << 8 | 2` for VMMCALL; PSCI `SYSTEM_OFF` is 0.
+//!
+//! The hypervisor's exit status is the first nonzero code of its VMs, in VM
+//! id order, where a VM terminated by a [`VmError`](crate::error::VmError)
+//! counts as 1. With the `qemu-exit` feature, a nonzero status ends QEMU
+//! with a failing exit status, so that automated runs detect guest
+//! failures:
+//!
+//! - riscv64: the `sifive_test` device of the virt machine (FAIL, the
+//!   status is the code);
+//! - aarch64: semihosting `SYS_EXIT` (QEMU `-semihosting`; the status is
+//!   the code);
+//! - x86_64: `isa-debug-exit` at port 0xF4 (QEMU `-device
+//!   isa-debug-exit,iobase=0xf4,iosize=0x04`; the status is
+//!   `code << 1 | 1`).
+//!
+//! A zero status powers the machine off as before.
+
+/// Exit code of a VM terminated by a [`VmError`](crate::error::VmError).
+pub const VM_ERROR_EXIT_CODE: u32 = 1;
+
+/// Physical address of the riscv64 virt machine's `sifive_test` device.
+#[cfg(all(feature = "qemu-exit", target_arch = "riscv64"))]
+const SIFIVE_TEST_BASE: usize = 0x10_0000;
+
+/// I/O port of QEMU's `isa-debug-exit` device.
+#[cfg(all(feature = "qemu-exit", target_arch = "x86_64"))]
+const ISA_DEBUG_EXIT_PORT: u16 = 0xF4;
+
+/// Reports the exit status of the hypervisor once all VMs are done and,
+/// with the `qemu-exit` feature, ends QEMU if it is nonzero. Returns if the
+/// status is zero (or cannot be passed on), for the usual power-off.
+pub fn report(status: u32) {
+    if status == 0 {
+        return;
+    }
+    ax_println!("Hypervisor exit status: {}", status);
+    #[cfg(feature = "qemu-exit")]
+    fail_qemu(status);
+}
+
+#[cfg(all(feature = "qemu-exit", target_arch = "riscv64"))]
+fn fail_qemu(status: u32) {
+    const FINISHER_FAIL: u32 = 0x3333;
+    let reg = axhal::mem::phys_to_virt(SIFIVE_TEST_BASE.into()).as_mut_ptr() as *mut u32;
+    unsafe { reg.write_volatile(FINISHER_FAIL | (status & 0xFFFF) << 16) };
+}
+
+#[cfg(all(feature = "qemu-exit", target_arch = "aarch64"))]
+fn fail_qemu(status: u32) {
+    const SYS_EXIT: u64 = 0x18;
+    const ADP_STOPPED_APPLICATION_EXIT: u64 = 0x2_0026;
+    let block = [ADP_STOPPED_APPLICATION_EXIT, status as u64];
+    unsafe {
+        core::arch::asm!(
+            "hlt #0xf000",
+            inout("x0") SYS_EXIT => _,
+            in("x1") block.as_ptr(),
+            options(nostack),
+        );
+    }
+}
+
+#[cfg(all(feature = "qemu-exit", target_arch = "x86_64"))]
+fn fail_qemu(status: u32) {
+    unsafe {
+        core::arch::asm!(
+            "out dx, eax",
+            in("dx") ISA_DEBUG_EXIT_PORT,
+            in("eax") status,
+            options(nomem, nostack),
+        );
+    }
+}
@@ -0,0 +1,709 @@
+//! Recording of guest exits to a trace file, and their replay.
+//!
+//! With `record=PATH` in a VM's `vms.conf` line, the run loop writes every
+//! exit of the VM to a compact binary trace at `PATH` on the FAT disk
+//! ([`ExitRecorder`]). An [`ExitEntry`] holds the exit's class and
+//! architectural reason, the PC, key registers ([`TraceArch::key_regs`])
+//! and pending events before and after the exit handler ran, and the
+//! device accesses the handler emulated with the values read or written.
+//!
+//! Everything but the recorder uses only `core` and `alloc`, so a host
+//! build reads the traces (`cargo xtask exits` prints them) and replays
+//! them: [`replay`] loads the state before each exit into a
+//! [`ReplayTarget`], runs an exit handler on it and reports where the
+//! state the handler leaves differs from the recorded one. The target
+//! answers device reads with the recorded values, so handlers run without
+//! devices or a guest.
+//!
+//! # Format
+//!
+//! Integers are LEB128 varints unless they are bytes. The header is the
+//! magic `GXIT`, the format version (byte), the architecture (byte), the VM
+//! id and the names of the exit classes (count byte, then each name's
+//! length and UTF-8 bytes), so a trace reads without the hypervisor that
+//! wrote it. Each exit follows as:
+//!
+//! - class (byte), vCPU, nanoseconds since the previous exit, reason and
+//!   the two info words;
+//! - the state before the handler: PC, key registers, asserted interrupt
+//!   lines and the next exception (byte: 0 none, 1 without, 2 with error
+//!   code; then vector and error code);
+//! - the state after it: the PC as a zigzag delta to the PC before, a mask
+//!   of the key registers that changed and their values, interrupt lines
+//!   and next exception;
+//! - the device accesses: their count, then for each a byte (bit 7 set for
+//!   a write, the width in bytes below), the address and the value.
+
+#![allow(dead_code)]
+
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt;
+
+/// First bytes of a trace.
+pub const TRACE_MAGIC: [u8; 4] = *b"GXIT";
+
+/// Version of the trace format.
+pub const TRACE_VERSION: u8 = 1;
+
+/// Most key registers of an architecture.
+pub const MAX_KEY_REGS: usize = 9;
+
+/// Architecture of the VM a trace was recorded on.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TraceArch {
+    Riscv64 = 1,
+    Aarch64 = 2,
+    X86_64 = 3,
+}
+
+impl TraceArch {
+    /// Returns the architecture's name.
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Riscv64 => "riscv64",
+            Self::Aarch64 => "aarch64",
+            Self::X86_64 => "x86_64",
+        }
+    }
+
+    /// Returns the names of the registers recorded around every exit: the
+    /// argument and return registers of hypercalls and port I/O.
+    pub fn key_regs(self) -> &'static [&'static str] {
+        match self {
+            Self::Riscv64 => &["a0", "a1", "a2", "a3", "a4", "a5", "a6", "a7"],
+            Self::Aarch64 => &["x0", "x1", "x2", "x3", "x4", "x5", "x6", "x7", "x8"],
+            Self::X86_64 => &["rax", "rbx", "rcx", "rdx", "rsi", "rdi", "r8", "r9"],
+        }
+    }
+
+    /// Returns the architecture with the code `code`.
+    pub(crate) fn from_code(code: u8) -> Option<Self> {
+        match code {
+            1 => Some(Self::Riscv64),
+            2 => Some(Self::Aarch64),
+            3 => Some(Self::X86_64),
+            _ => None,
+        }
+    }
+}
+
+/// What a trace was recorded on.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TraceHeader {
+    pub arch: TraceArch,
+    /// Id of the VM.
+    pub vm: u32,
+    /// Names of the exit classes, by [`ExitEntry::class`].
+    pub classes: Vec<String>,
+}
+
+/// vCPU state recorded around an exit.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ExitSnapshot {
+    /// Guest PC.
+    pub pc: u64,
+    /// The key registers of the architecture, zero past them.
+    pub regs: [u64; MAX_KEY_REGS],
+    /// Bit `n` set: interrupt line `n` is asserted.
+    pub irqs: u64,
+    /// The next exception to deliver: vector and error code.
+    pub exception: Option<(u32, Option<u64>)>,
+}
+
+/// A device access emulated by an exit handler.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct IoValue {
+    /// Guest physical address or port.
+    pub addr: u64,
+    /// Access size in bytes.
+    pub width: u8,
+    /// Store (`true`) or load.
+    pub is_write: bool,
+    /// The value stored, or read from the device.
+    pub value: u64,
+}
+
+/// One recorded exit.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ExitEntry {
+    /// Exit class, an index into [`TraceHeader::classes`].
+    pub class: u8,
+    /// The vCPU that exited.
+    pub cpu: u32,
+    /// Host monotonic time of the exit, in nanoseconds.
+    pub time_ns: u64,
+    /// Architectural exit reason: `scause`, exception class or exit code.
+    pub code: u64,
+    /// Exit details (`stval`/`htval`, ESR/FAR, EXITINFO1/EXITINFO2).
+    pub info: [u64; 2],
+    /// State when the exit handler started.
+    pub before: ExitSnapshot,
+    /// State the exit handler left.
+    pub after: ExitSnapshot,
+    /// Device accesses of the exit, in order.
+    pub io: Vec<IoValue>,
+}
+
+/// Why a trace cannot be read.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TraceError {
+    /// The data does not start with [`TRACE_MAGIC`].
+    BadMagic,
+    /// The trace has a format version this build does not read.
+    Version(u8),
+    /// The trace was recorded on an unknown architecture.
+    Arch(u8),
+    /// The data ends in the middle of an entry.
+    Truncated,
+    /// A value is out of range.
+    Corrupt,
+}
+
+impl fmt::Display for TraceError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::BadMagic => write!(f, "not an exit trace"),
+            Self::Version(version) => write!(f, "unsupported trace version {version}"),
+            Self::Arch(arch) => write!(f, "unknown architecture {arch}"),
+            Self::Truncated => write!(f, "trace truncated"),
+            Self::Corrupt => write!(f, "trace corrupt"),
+        }
+    }
+}
+
+/// Encodes a trace.
+pub struct TraceWriter {
+    regs: usize,
+    last_time: u64,
+}
+
+impl TraceWriter {
+    /// Appends the header to `out`; entries follow with
+    /// [`TraceWriter::write`].
+    pub fn new(header: &TraceHeader, out: &mut Vec<u8>) -> Self {
+        out.extend_from_slice(&TRACE_MAGIC);
+        out.push(TRACE_VERSION);
+        out.push(header.arch as u8);
+        put_varint(out, header.vm.into());
+        out.push(header.classes.len() as u8);
+        for name in &header.classes {
+            put_varint(out, name.len() as u64);
+            out.extend_from_slice(name.as_bytes());
+        }
+        Self {
+            regs: header.arch.key_regs().len(),
+            last_time: 0,
+        }
+    }
+
+    /// Appends `entry` to `out`.
+    pub fn write(&mut self, entry: &ExitEntry, out: &mut Vec<u8>) {
+        out.push(entry.class);
+        put_varint(out, entry.cpu.into());
+        put_varint(out, entry.time_ns.saturating_sub(self.last_time));
+        self.last_time = entry.time_ns;
+        put_varint(out, entry.code);
+        put_varint(out, entry.info[0]);
+        put_varint(out, entry.info[1]);
+
+        let (before, after) = (&entry.before, &entry.after);
+        put_varint(out, before.pc);
+        for &reg in &before.regs[..self.regs] {
+            put_varint(out, reg);
+        }
+        put_events(out, before);
+        put_varint(out, zigzag(after.pc.wrapping_sub(before.pc) as i64));
+        let changed = (0..self.regs)
+            .filter(|&i| after.regs[i] != before.regs[i])
+            .fold(0, |mask, i| mask | 1 << i);
+        put_varint(out, changed);
+        for i in (0..self.regs).filter(|i| changed & 1 << i != 0) {
+            put_varint(out, after.regs[i]);
+        }
+        put_events(out, after);
+
+        put_varint(out, entry.io.len() as u64);
+        for io in &entry.io {
+            out.push(io.width | if io.is_write { 0x80 } else { 0 });
+            put_varint(out, io.addr);
+            put_varint(out, io.value);
+        }
+    }
+}
+
+/// Decodes a trace: the header, then the entries as an iterator, which
+/// ends at the first error.
+pub struct TraceReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+    header: TraceHeader,
+    regs: usize,
+    last_time: u64,
+}
+
+impl<'a> TraceReader<'a> {
+    /// Reads the header of the trace in `data`.
+    pub fn new(data: &'a [u8]) -> Result<Self, TraceError> {
+        if !data.starts_with(&TRACE_MAGIC) {
+            return Err(TraceError::BadMagic);
+        }
+        let mut reader = Self {
+            data,
+            pos: TRACE_MAGIC.len(),
+            header: TraceHeader {
+                arch: TraceArch::Riscv64,
+                vm: 0,
+                classes: Vec::new(),
+            },
+            regs: 0,
+            last_time: 0,
+        };
+        let version = reader.byte()?;
+        if version != TRACE_VERSION {
+            return Err(TraceError::Version(version));
+        }
+        let arch = reader.byte()?;
+        reader.header.arch = TraceArch::from_code(arch).ok_or(TraceError::Arch(arch))?;
+        reader.header.vm = reader
+            .varint()?
+            .try_into()
+            .map_err(|_| TraceError::Corrupt)?;
+        for _ in 0..reader.byte()? {
+            let len = reader.varint()? as usize;
+            let name = reader.bytes(len)?;
+            let name = core::str::from_utf8(name).map_err(|_| TraceError::Corrupt)?;
+            reader.header.classes.push(name.into());
+        }
+        reader.regs = reader.header.arch.key_regs().len();
+        Ok(reader)
+    }
+
+    /// Returns the header of the trace.
+    pub fn header(&self) -> &TraceHeader {
+        &self.header
+    }
+
+    /// Returns the name of the class of `entry`.
+    pub fn class_name(&self, entry: &ExitEntry) -> &str {
+        self.header
+            .classes
+            .get(entry.class as usize)
+            .map_or("?", String::as_str)
+    }
+
+    fn entry(&mut self) -> Result<ExitEntry, TraceError> {
+        let mut entry = ExitEntry {
+            class: self.byte()?,
+            cpu: self.varint()?.try_into().map_err(|_| TraceError::Corrupt)?,
+            ..ExitEntry::default()
+        };
+        self.last_time = self.last_time.wrapping_add(self.varint()?);
+        entry.time_ns = self.last_time;
+        entry.code = self.varint()?;
+        entry.info = [self.varint()?, self.varint()?];
+
+        let before = &mut entry.before;
+        before.pc = self.varint()?;
+        for i in 0..self.regs {
+            before.regs[i] = self.varint()?;
+        }
+        self.events(before)?;
+        let mut after = *before;
+        after.pc = before.pc.wrapping_add(unzigzag(self.varint()?) as u64);
+        let changed = self.varint()?;
+        for i in (0..self.regs).filter(|i| changed & 1 << i != 0) {
+            after.regs[i] = self.varint()?;
+        }
+        self.events(&mut after)?;
+        entry.after = after;
+
+        for _ in 0..self.varint()? {
+            let flags = self.byte()?;
+            entry.io.push(IoValue {
+                width: flags & 0x7F,
+                is_write: flags & 0x80 != 0,
+                addr: self.varint()?,
+                value: self.varint()?,
+            });
+        }
+        Ok(entry)
+    }
+
+    fn events(&mut self, snapshot: &mut ExitSnapshot) -> Result<(), TraceError> {
+        snapshot.irqs = self.varint()?;
+        snapshot.exception = match self.byte()? {
+            0 => None,
+            1 => Some((self.vector()?, None)),
+            2 => Some((self.vector()?, Some(self.varint()?))),
+            _ => return Err(TraceError::Corrupt),
+        };
+        Ok(())
+    }
+
+    fn vector(&mut self) -> Result<u32, TraceError> {
+        self.varint()?.try_into().map_err(|_| TraceError::Corrupt)
+    }
+
+    fn byte(&mut self) -> Result<u8, TraceError> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn bytes(&mut self, len: usize) -> Result<&'a [u8], TraceError> {
+        let bytes = self
+            .data
+            .get(self.pos..self.pos.saturating_add(len))
+            .ok_or(TraceError::Truncated)?;
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    fn varint(&mut self) -> Result<u64, TraceError> {
+        let mut value = 0u64;
+        for shift in (0..64).step_by(7) {
+            let byte = self.byte()?;
+            value |= u64::from(byte & 0x7F) << shift;
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+        Err(TraceError::Corrupt)
+    }
+}
+
+impl Iterator for TraceReader<'_> {
+    type Item = Result<ExitEntry, TraceError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.pos >= self.data.len() {
+            return None;
+        }
+        let entry = self.entry();
+        if entry.is_err() {
+            self.pos = self.data.len();
+        }
+        Some(entry)
+    }
+}
+
+fn put_varint(out: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        out.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
+
+fn put_events(out: &mut Vec<u8>, snapshot: &ExitSnapshot) {
+    put_varint(out, snapshot.irqs);
+    match snapshot.exception {
+        None => out.push(0),
+        Some((vector, None)) => {
+            out.push(1);
+            put_varint(out, vector.into());
+        }
+        Some((vector, Some(error))) => {
+            out.push(2);
+            put_varint(out, vector.into());
+            put_varint(out, error);
+        }
+    }
+}
+
+fn zigzag(value: i64) -> u64 {
+    ((value << 1) ^ (value >> 63)) as u64
+}
+
+fn unzigzag(value: u64) -> i64 {
+    (value >> 1) as i64 ^ -((value & 1) as i64)
+}
+
+/// A vCPU state that exit handlers run on during a replay.
+pub trait ReplayTarget {
+    /// Puts the vCPU in the state of `entry` before its exit, with the
+    /// device reads of the entry ready to be answered.
+    fn load(&mut self, entry: &ExitEntry);
+    /// Returns the state the exit handler left.
+    fn snapshot(&self) -> ExitSnapshot;
+}
+
+/// An exit after which the replayed state differs from the recorded one.
+#[derive(Clone, Debug)]
+pub struct Divergence {
+    /// Position of the exit in the trace.
+    pub index: usize,
+    /// The exit as recorded.
+    pub entry: ExitEntry,
+    /// The state the replayed handler left.
+    pub replayed: ExitSnapshot,
+}
+
+/// The result of a replay.
+#[derive(Clone, Debug, Default)]
+pub struct ReplayReport {
+    /// Exits replayed.
+    pub exits: usize,
+    /// The exits whose replay did not end in the recorded state.
+    pub divergences: Vec<Divergence>,
+}
+
+/// Replays the exits of `trace` on `target`: `handle` runs the exit
+/// handler of each entry's class once the entry is loaded.
+pub fn replay<T: ReplayTarget>(
+    trace: &[u8],
+    target: &mut T,
+    mut handle: impl FnMut(&mut T, &ExitEntry),
+) -> Result<ReplayReport, TraceError> {
+    let mut report = ReplayReport::default();
+    for (index, entry) in TraceReader::new(trace)?.enumerate() {
+        let entry = entry?;
+        target.load(&entry);
+        handle(target, &entry);
+        let replayed = target.snapshot();
+        if replayed != entry.after {
+            report.divergences.push(Divergence {
+                index,
+                entry,
+                replayed,
+            });
+        }
+        report.exits += 1;
+    }
+    Ok(report)
+}
+
+#[cfg(feature = "axstd")]
+pub use recorder::ExitRecorder;
+
+#[cfg(feature = "axstd")]
+mod recorder {
+    use alloc::string::{String, ToString};
+    use alloc::vec::Vec;
+
+    use axerrno::{AxError, AxResult};
+    use axstd::fs::File;
+    use axstd::io::Write;
+
+    use super::{ExitEntry, TraceArch, TraceHeader, TraceWriter};
+
+    /// Bytes of encoded exits collected before they are written out.
+    const FLUSH_LEN: usize = 64 * 1024;
+
+    /// Writes the exits of one VM to its trace file. A failed write stops
+    /// the recording, not the VM.
+    pub struct ExitRecorder {
+        vm: usize,
+        path: String,
+        file: Option<File>,
+        writer: TraceWriter,
+        buf: Vec<u8>,
+        exits: u64,
+    }
+
+    impl ExitRecorder {
+        /// Creates the trace at `path` for VM `vm`, whose exit classes are
+        /// named `classes`.
+        pub fn create(vm: usize, path: &str, arch: TraceArch, classes: &[&str]) -> AxResult<Self> {
+            let file = File::create(path).map_err(|_| AxError::NotFound)?;
+            let header = TraceHeader {
+                arch,
+                vm: vm as u32,
+                classes: classes.iter().map(|name| name.to_string()).collect(),
+            };
+            let mut buf = Vec::with_capacity(FLUSH_LEN);
+            let writer = TraceWriter::new(&header, &mut buf);
+            Ok(Self {
+                vm,
+                path: path.to_string(),
+                file: Some(file),
+                writer,
+                buf,
+                exits: 0,
+            })
+        }
+
+        /// Records `entry`, stamped with the current time.
+        pub fn record(&mut self, mut entry: ExitEntry) {
+            if self.file.is_none() {
+                return;
+            }
+            entry.time_ns = axhal::time::monotonic_time_nanos();
+            self.writer.write(&entry, &mut self.buf);
+            self.exits += 1;
+            if self.buf.len() >= FLUSH_LEN {
+                self.flush();
+            }
+        }
+
+        fn flush(&mut self) {
+            let Some(file) = &mut self.file else {
+                return;
+            };
+            if file
+                .write_all(&self.buf)
+                .and_then(|_| file.flush())
+                .is_err()
+            {
+                vm_println!(
+                    self.vm,
+                    "Exit trace: writing {} failed, recording stopped",
+                    self.path
+                );
+                self.file = None;
+            }
+            self.buf.clear();
+        }
+    }
+
+    impl Drop for ExitRecorder {
+        fn drop(&mut self) {
+            self.flush();
+            if self.file.is_some() {
+                vm_println!(
+                    self.vm,
+                    "Exit trace: {} exits recorded in {}",
+                    self.exits,
+                    self.path
+                );
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::vec;
+
+    use super::*;
+
+    fn header() -> TraceHeader {
+        TraceHeader {
+            arch: TraceArch::X86_64,
+            vm: 3,
+            classes: vec!["irq".into(), "pio".into()],
+        }
+    }
+
+    fn entries() -> Vec<ExitEntry> {
+        let before = ExitSnapshot {
+            pc: 0xFFFF_FFFF_8100_0000,
+            regs: [1, 2, 3, 4, 5, 6, 7, 8, 0],
+            irqs: 0,
+            exception: None,
+        };
+        let pio = ExitEntry {
+            class: 1,
+            cpu: 0,
+            time_ns: 1_000,
+            code: 0x7B,
+            info: [0x3F8 << 16, 0],
+            before,
+            after: ExitSnapshot {
+                pc: before.pc + 1,
+                regs: [0x41, 2, 3, 4, 5, 6, 7, 8, 0],
+                irqs: 1 << 4,
+                exception: Some((14, Some(2))),
+            },
+            io: vec![IoValue {
+                addr: 0x3F8,
+                width: 1,
+                is_write: false,
+                value: 0x41,
+            }],
+        };
+        // The PC may go backwards; x86_64 has 8 key registers.
+        let irq = ExitEntry {
+            class: 0,
+            cpu: 1,
+            time_ns: 1_900,
+            code: 0x60,
+            before: ExitSnapshot {
+                pc: 0x1000,
+                ..before
+            },
+            after: ExitSnapshot {
+                pc: 0x1000,
+                exception: Some((32, None)),
+                ..before
+            },
+            ..ExitEntry::default()
+        };
+        vec![pio, irq]
+    }
+
+    fn encode(entries: &[ExitEntry]) -> Vec<u8> {
+        let mut out = Vec::new();
+        let mut writer = TraceWriter::new(&header(), &mut out);
+        for entry in entries {
+            writer.write(entry, &mut out);
+        }
+        out
+    }
+
+    #[test]
+    fn round_trip() {
+        let entries = entries();
+        let trace = encode(&entries);
+        let reader = TraceReader::new(&trace).unwrap();
+        assert_eq!(*reader.header(), header());
+        assert_eq!(reader.class_name(&entries[0]), "pio");
+        let decoded: Vec<_> = reader.map(Result::unwrap).collect();
+        assert_eq!(decoded, entries);
+    }
+
+    #[test]
+    fn bad_traces() {
+        assert_eq!(TraceReader::new(b"ELF").err(), Some(TraceError::BadMagic));
+        let mut trace = encode(&[]);
+        trace[TRACE_MAGIC.len()] = TRACE_VERSION + 1;
+        assert_eq!(
+            TraceReader::new(&trace).err(),
+            Some(TraceError::Version(TRACE_VERSION + 1))
+        );
+        trace[TRACE_MAGIC.len()] = TRACE_VERSION;
+        trace[TRACE_MAGIC.len() + 1] = 9;
+        assert_eq!(TraceReader::new(&trace).err(), Some(TraceError::Arch(9)));
+
+        let trace = encode(&entries());
+        let cut = &trace[..trace.len() - 1];
+        let mut reader = TraceReader::new(cut).unwrap();
+        assert!(reader.next().unwrap().is_ok());
+        assert_eq!(reader.next(), Some(Err(TraceError::Truncated)));
+        assert_eq!(reader.next(), None);
+    }
+
+    /// Replays exits on a vCPU that only tracks its registers.
+    struct Regs(ExitSnapshot);
+
+    impl ReplayTarget for Regs {
+        fn load(&mut self, entry: &ExitEntry) {
+            self.0 = entry.before;
+        }
+        fn snapshot(&self) -> ExitSnapshot {
+            self.0
+        }
+    }
+
+    #[test]
+    fn replay_reports_divergences() {
+        let entries = entries();
+        let trace = encode(&entries);
+        let mut target = Regs(ExitSnapshot::default());
+        // A handler that emulates port reads, but never raises interrupts
+        // or exceptions.
+        let report = replay(&trace, &mut target, |regs, entry| {
+            if let Some(io) = entry.io.first() {
+                regs.0.regs[0] = io.value;
+                regs.0.pc += 1;
+            }
+        })
+        .unwrap();
+        assert_eq!(report.exits, 2);
+        let diverged: Vec<_> = report.divergences.iter().map(|d| d.index).collect();
+        assert_eq!(diverged, [0, 1]);
+        assert_eq!(report.divergences[0].replayed.regs[0], 0x41);
+
+        let report = replay(&trace, &mut target, |regs, entry| regs.0 = entry.after).unwrap();
+        assert!(report.divergences.is_empty());
+    }
+}
@@ -0,0 +1,604 @@
+//! Flattened device tree (FDT) generation for guests.
+//!
+//! [`FdtBuilder`] writes a device tree blob (version 17) node by node.
+//! [`guest_fdt`] describes a guest on riscv64 and aarch64: its RAM, its
+//! CPU, the interrupt controller, UART and timer of the QEMU virt machine
+//! at their usual addresses, the virtio-mmio devices and the PL031 RTC of
+//! the VM and the location of its initrd and command line in `/chosen`. The blob is
+//! placed at [`boot::fdt_gpa`] and its address is passed in `a1` (riscv64)
+//! or `x0` (aarch64).
+//!
+//! [`boot::fdt_gpa`]: crate::boot::fdt_gpa
+
+#![allow(dead_code)]
+
+use alloc::string::String;
+use alloc::vec::Vec;
+
+const FDT_MAGIC: u32 = 0xD00D_FEED;
+const FDT_VERSION: u32 = 17;
+const FDT_LAST_COMP_VERSION: u32 = 16;
+const FDT_HEADER_LEN: usize = 40;
+
+const FDT_BEGIN_NODE: u32 = 1;
+const FDT_END_NODE: u32 = 2;
+const FDT_PROP: u32 = 3;
+const FDT_END: u32 = 9;
+
+/// Writes a device tree blob.
+#[derive(Default)]
+pub struct FdtBuilder {
+    structure: Vec<u8>,
+    strings: Vec<u8>,
+    depth: usize,
+}
+
+impl FdtBuilder {
+    /// Creates an empty tree; the first node must be the root (`""`).
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Opens the node `name` (`node-name@unit-address`).
+    pub fn begin_node(&mut self, name: &str) {
+        self.push_u32(FDT_BEGIN_NODE);
+        self.structure.extend_from_slice(name.as_bytes());
+        self.structure.push(0);
+        self.align();
+        self.depth += 1;
+    }
+
+    /// Closes the innermost open node.
+    pub fn end_node(&mut self) {
+        assert!(self.depth > 0, "unbalanced FDT nodes");
+        self.push_u32(FDT_END_NODE);
+        self.depth -= 1;
+    }
+
+    /// Adds a property with a raw value.
+    pub fn prop(&mut self, name: &str, value: &[u8]) {
+        let nameoff = self.string_offset(name);
+        self.push_u32(FDT_PROP);
+        self.push_u32(value.len() as u32);
+        self.push_u32(nameoff);
+        self.structure.extend_from_slice(value);
+        self.align();
+    }
+
+    /// Adds a property without value (a flag such as `ranges`).
+    pub fn prop_empty(&mut self, name: &str) {
+        self.prop(name, &[]);
+    }
+
+    /// Adds a property holding one cell.
+    pub fn prop_u32(&mut self, name: &str, value: u32) {
+        self.prop_cells(name, &[value]);
+    }
+
+    /// Adds a property holding a list of cells.
+    pub fn prop_cells(&mut self, name: &str, cells: &[u32]) {
+        let value: Vec<u8> = cells.iter().flat_map(|c| c.to_be_bytes()).collect();
+        self.prop(name, &value);
+    }
+
+    /// Adds a property holding 64-bit values as pairs of cells, as `reg`
+    /// needs with `#address-cells = #size-cells = 2`.
+    pub fn prop_u64s(&mut self, name: &str, values: &[u64]) {
+        let value: Vec<u8> = values.iter().flat_map(|v| v.to_be_bytes()).collect();
+        self.prop(name, &value);
+    }
+
+    /// Adds a string property.
+    pub fn prop_str(&mut self, name: &str, value: &str) {
+        self.prop_strs(name, &[value]);
+    }
+
+    /// Adds a string list property.
+    pub fn prop_strs(&mut self, name: &str, values: &[&str]) {
+        let mut value = Vec::new();
+        for s in values {
+            value.extend_from_slice(s.as_bytes());
+            value.push(0);
+        }
+        self.prop(name, &value);
+    }
+
+    /// Returns the blob; all nodes must be closed.
+    pub fn finish(mut self) -> Vec<u8> {
+        assert!(self.depth == 0, "unbalanced FDT nodes");
+        self.push_u32(FDT_END);
+
+        // Header, empty memory reservation map, structure, strings.
+        let off_mem_rsvmap = FDT_HEADER_LEN;
+        let off_dt_struct = off_mem_rsvmap + 16;
+        let off_dt_strings = off_dt_struct + self.structure.len();
+        let totalsize = off_dt_strings + self.strings.len();
+        let header = [
+            FDT_MAGIC,
+            totalsize as u32,
+            off_dt_struct as u32,
+            off_dt_strings as u32,
+            off_mem_rsvmap as u32,
+            FDT_VERSION,
+            FDT_LAST_COMP_VERSION,
+            0, // boot_cpuid_phys
+            self.strings.len() as u32,
+            self.structure.len() as u32,
+        ];
+        let mut blob = Vec::with_capacity(totalsize);
+        blob.extend(header.iter().flat_map(|v| v.to_be_bytes()));
+        blob.extend_from_slice(&[0u8; 16]);
+        blob.extend_from_slice(&self.structure);
+        blob.extend_from_slice(&self.strings);
+        blob
+    }
+
+    fn push_u32(&mut self, value: u32) {
+        self.structure.extend_from_slice(&value.to_be_bytes());
+    }
+
+    fn align(&mut self) {
+        let len = self.structure.len().next_multiple_of(4);
+        self.structure.resize(len, 0);
+    }
+
+    /// Returns the offset of `name` in the strings block, adding it if new.
+    fn string_offset(&mut self, name: &str) -> u32 {
+        let mut offset = 0;
+        for s in self.strings.split(|&b| b == 0) {
+            if s == name.as_bytes() && offset < self.strings.len() {
+                return offset as u32;
+            }
+            offset += s.len() + 1;
+        }
+        let offset = self.strings.len();
+        self.strings.extend_from_slice(name.as_bytes());
+        self.strings.push(0);
+        offset as u32
+    }
+}
+
+/// What the device tree of a guest describes.
+pub struct GuestLayout {
+    /// Guest physical start of RAM.
+    pub ram_start: usize,
+    /// Size of guest RAM in bytes.
+    pub ram_size: usize,
+    /// Number of CPUs (riscv64; aarch64 guests have one).
+    pub num_cpus: usize,
+    /// virtio-mmio devices: base address, window size and interrupt line.
+    pub virtio_mmio: Vec<(usize, usize, u32)>,
+    /// Base address and window size of the emulated PL031 RTC.
+    pub rtc: Option<(usize, usize)>,
+    /// The guest has an IMSIC and an APLIC instead of the PLIC (riscv64).
+    pub aia: bool,
+    /// ISA string of the guest's harts (riscv64).
+    pub isa: String,
+    /// Initial ramdisk `[start, end)` in guest memory.
+    pub initrd: Option<(usize, usize)>,
+    /// Kernel command line (`/chosen/bootargs`).
+    pub bootargs: Option<String>,
+}
+
+/// Phandle of the interrupt controller node.
+const PHANDLE_INTC: u32 = 1;
+/// Phandle of the interrupt controller of CPU 0 (riscv64); CPU `i` has
+/// `PHANDLE_CPU_INTC + i`.
+const PHANDLE_CPU_INTC: u32 = 2;
+/// Phandle of the UART and RTC clock (aarch64); riscv64 numbers its RTC
+/// clock after the CPU interrupt controllers.
+const PHANDLE_APB_CLK: u32 = 2;
+
+/// PLIC of the QEMU virt machine (riscv64), passed through to the guest.
+#[cfg(target_arch = "riscv64")]
+pub const PLIC_BASE: u64 = 0x0c00_0000;
+#[cfg(target_arch = "riscv64")]
+pub const PLIC_SIZE: u64 = 0x60_0000;
+/// NS16550 UART of the QEMU virt machine (riscv64), passed through to the
+/// guest. Its registers take 0x100 bytes of the page.
+#[cfg(target_arch = "riscv64")]
+pub const UART_BASE: u64 = 0x1000_0000;
+#[cfg(target_arch = "riscv64")]
+pub const UART_SIZE: u64 = 0x1000;
+/// IMSIC interrupt file of the guest's hart 0 and its APLIC, at their
+/// QEMU virt (`aia=aplic-imsic`) addresses, in place of the PLIC.
+#[cfg(target_arch = "riscv64")]
+pub const IMSIC_BASE: u64 = 0x2800_0000;
+#[cfg(target_arch = "riscv64")]
+pub const IMSIC_SIZE: u64 = 0x1000;
+#[cfg(target_arch = "riscv64")]
+pub const APLIC_BASE: u64 = 0x0d00_0000;
+/// Interrupt identities of the guest's interrupt file.
+#[cfg(target_arch = "riscv64")]
+pub const IMSIC_NUM_IDS: u32 = 63;
+
+/// Builds the device tree of a riscv64 guest.
+#[cfg(target_arch = "riscv64")]
+pub fn guest_fdt(layout: &GuestLayout) -> Vec<u8> {
+    use alloc::format;
+
+    // QEMU virt: NS16550 UART at interrupt 10, 10 MHz timebase.
+    const UART_IRQ: u32 = 10;
+    const TIMEBASE_FREQ: u32 = 10_000_000;
+    /// Supervisor external interrupt, as seen by the guest.
+    const IRQ_S_EXT: u32 = 9;
+    /// APLIC interrupt specifier flags.
+    const IRQ_TYPE_LEVEL_HIGH: u32 = 4;
+    // After the CPU interrupt controllers.
+    let apb_clk = PHANDLE_CPU_INTC + layout.num_cpus.max(1) as u32;
+    let phandle_imsic = apb_clk + 1;
+
+    let mut fdt = FdtBuilder::new();
+    fdt.begin_node("");
+    fdt.prop_u32("#address-cells", 2);
+    fdt.prop_u32("#size-cells", 2);
+    fdt.prop_str("compatible", "riscv-virtio");
+    fdt.prop_str("model", "arceos-guest,riscv-virtio");
+
+    chosen_node(&mut fdt, layout, &format!("/soc/serial@{:x}", UART_BASE));
+
+    fdt.begin_node("cpus");
+    fdt.prop_u32("#address-cells", 1);
+    fdt.prop_u32("#size-cells", 0);
+    fdt.prop_u32("timebase-frequency", TIMEBASE_FREQ);
+    for hart in 0..layout.num_cpus.max(1) as u32 {
+        fdt.begin_node(&format!("cpu@{}", hart));
+        fdt.prop_str("device_type", "cpu");
+        fdt.prop_u32("reg", hart);
+        fdt.prop_str("status", "okay");
+        fdt.prop_str("compatible", "riscv");
+        fdt.prop_str("riscv,isa", &layout.isa);
+        fdt.prop_str("mmu-type", "riscv,sv39");
+        fdt.begin_node("interrupt-controller");
+        fdt.prop_u32("#interrupt-cells", 1);
+        fdt.prop_empty("interrupt-controller");
+        fdt.prop_str("compatible", "riscv,cpu-intc");
+        fdt.prop_u32("phandle", PHANDLE_CPU_INTC + hart);
+        fdt.end_node();
+        fdt.end_node();
+    }
+    fdt.end_node();
+
+    memory_node(&mut fdt, layout);
+
+    fdt.begin_node("soc");
+    fdt.prop_u32("#address-cells", 2);
+    fdt.prop_u32("#size-cells", 2);
+    fdt.prop_str("compatible", "simple-bus");
+    fdt.prop_empty("ranges");
+
+    if layout.aia {
+        // The interrupt file of hart 0, which has the only one, and the
+        // APLIC that turns device interrupts into its MSIs.
+        fdt.begin_node(&format!("imsics@{:x}", IMSIC_BASE));
+        fdt.prop_str("compatible", "riscv,imsics");
+        fdt.prop_u64s("reg", &[IMSIC_BASE, IMSIC_SIZE]);
+        fdt.prop_u32("#interrupt-cells", 0);
+        fdt.prop_empty("interrupt-controller");
+        fdt.prop_u32("#msi-cells", 0);
+        fdt.prop_empty("msi-controller");
+        fdt.prop_u32("riscv,num-ids", IMSIC_NUM_IDS);
+        fdt.prop_cells("interrupts-extended", &[PHANDLE_CPU_INTC, IRQ_S_EXT]);
+        fdt.prop_u32("phandle", phandle_imsic);
+        fdt.end_node();
+
+        let aplic_size = crate::devices::aplic::APLIC_SIZE as u64;
+        fdt.begin_node(&format!("aplic@{:x}", APLIC_BASE));
+        fdt.prop_str("compatible", "riscv,aplic");
+        fdt.prop_u64s("reg", &[APLIC_BASE, aplic_size]);
+        fdt.prop_u32("#interrupt-cells", 2);
+        fdt.prop_empty("interrupt-controller");
+        fdt.prop_u32("msi-parent", phandle_imsic);
+        fdt.prop_u32(
+            "riscv,num-sources",
+            crate::devices::aplic::NUM_SOURCES as u32,
+        );
+        fdt.prop_u32("phandle", PHANDLE_INTC);
+        fdt.end_node();
+    } else {
+        fdt.begin_node(&format!("plic@{:x}", PLIC_BASE));
+        fdt.prop_strs("compatible", &["sifive,plic-1.0.0", "riscv,plic0"]);
+        fdt.prop_u64s("reg", &[PLIC_BASE, PLIC_SIZE]);
+        fdt.prop_u32("#interrupt-cells", 1);
+        fdt.prop_u32("#address-cells", 0);
+        fdt.prop_empty("interrupt-controller");
+        fdt.prop_u32("riscv,ndev", 0x5f);
+        // Device interrupts are delivered to hart 0 only.
+        fdt.prop_cells("interrupts-extended", &[PHANDLE_CPU_INTC, IRQ_S_EXT]);
+        fdt.prop_u32("phandle", PHANDLE_INTC);
+        fdt.end_node();
+    }
+
+    fdt.begin_node(&format!("serial@{:x}", UART_BASE));
+    fdt.prop_str("compatible", "ns16550a");
+    fdt.prop_u64s("reg", &[UART_BASE, 0x100]);
+    fdt.prop_u32("clock-frequency", 0x38_4000);
+    // The passed-through UART's line goes to the host's interrupt
+    // controller: with the AIA, the guest polls it.
+    if !layout.aia {
+        fdt.prop_u32("interrupt-parent", PHANDLE_INTC);
+        fdt.prop_u32("interrupts", UART_IRQ);
+    }
+    fdt.end_node();
+
+    for &(base, size, irq) in &layout.virtio_mmio {
+        fdt.begin_node(&format!("virtio_mmio@{:x}", base));
+        fdt.prop_str("compatible", "virtio,mmio");
+        fdt.prop_u64s("reg", &[base as u64, size as u64]);
+        fdt.prop_u32("interrupt-parent", PHANDLE_INTC);
+        if layout.aia {
+            fdt.prop_cells("interrupts", &[irq, IRQ_TYPE_LEVEL_HIGH]);
+        } else {
+            fdt.prop_u32("interrupts", irq);
+        }
+        fdt.end_node();
+    }
+
+    if let Some((base, size)) = layout.rtc {
+        // The PL031 is a primecell: the AMBA bus wants its APB clock.
+        fdt.begin_node("apb-pclk");
+        fdt.prop_str("compatible", "fixed-clock");
+        fdt.prop_u32("#clock-cells", 0);
+        fdt.prop_u32("clock-frequency", 24_000_000);
+        fdt.prop_u32("phandle", apb_clk);
+        fdt.end_node();
+        rtc_node(&mut fdt, base, size, apb_clk);
+    }
+    fdt.end_node();
+
+    fdt.end_node();
+    fdt.finish()
+}
+
+/// Builds the device tree of an aarch64 guest.
+#[cfg(target_arch = "aarch64")]
+pub fn guest_fdt(layout: &GuestLayout) -> Vec<u8> {
+    use alloc::format;
+
+    // QEMU virt: GICv2 distributor and CPU interface, PL011 UART (SPI 1).
+    const GICD_BASE: u64 = 0x0800_0000;
+    const GICC_BASE: u64 = 0x0801_0000;
+    const UART_BASE: u64 = 0x0900_0000;
+    const UART_SPI: u32 = 1;
+    const GIC_SPI: u32 = 0;
+    const GIC_PPI: u32 = 1;
+    const IRQ_TYPE_LEVEL_HIGH: u32 = 4;
+    const IRQ_TYPE_EDGE_RISING: u32 = 1;
+    /// PPI flags: level-low, delivered to CPU 0.
+    const PPI_CPU0_LEVEL_LOW: u32 = 0x104;
+
+    let mut fdt = FdtBuilder::new();
+    fdt.begin_node("");
+    fdt.prop_u32("#address-cells", 2);
+    fdt.prop_u32("#size-cells", 2);
+    fdt.prop_str("compatible", "linux,dummy-virt");
+    fdt.prop_str("model", "arceos-guest,dummy-virt");
+    fdt.prop_u32("interrupt-parent", PHANDLE_INTC);
+
+    chosen_node(&mut fdt, layout, &format!("/pl011@{:x}", UART_BASE));
+
+    fdt.begin_node("cpus");
+    fdt.prop_u32("#address-cells", 1);
+    fdt.prop_u32("#size-cells", 0);
+    fdt.begin_node("cpu@0");
+    fdt.prop_str("device_type", "cpu");
+    fdt.prop_str("compatible", "arm,armv8");
+    fdt.prop_u32("reg", 0);
+    fdt.end_node();
+    fdt.end_node();
+
+    memory_node(&mut fdt, layout);
+
+    fdt.begin_node("timer");
+    fdt.prop_str("compatible", "arm,armv8-timer");
+    // Secure, non-secure, virtual and hypervisor physical timer PPIs.
+    let mut cells = Vec::new();
+    for ppi in [13, 14, 11, 10] {
+        cells.extend([GIC_PPI, ppi, PPI_CPU0_LEVEL_LOW]);
+    }
+    fdt.prop_cells("interrupts", &cells);
+    fdt.prop_empty("always-on");
+    fdt.end_node();
+
+    fdt.begin_node(&format!("intc@{:x}", GICD_BASE));
+    fdt.prop_str("compatible", "arm,cortex-a15-gic");
+    fdt.prop_u64s("reg", &[GICD_BASE, 0x1_0000, GICC_BASE, 0x1_0000]);
+    fdt.prop_u32("#interrupt-cells", 3);
+    fdt.prop_empty("interrupt-controller");
+    fdt.prop_u32("phandle", PHANDLE_INTC);
+    fdt.end_node();
+
+    fdt.begin_node("apb-pclk");
+    fdt.prop_str("compatible", "fixed-clock");
+    fdt.prop_u32("#clock-cells", 0);
+    fdt.prop_u32("clock-frequency", 24_000_000);
+    fdt.prop_str("clock-output-names", "clk24mhz");
+    fdt.prop_u32("phandle", PHANDLE_APB_CLK);
+    fdt.end_node();
+
+    fdt.begin_node(&format!("pl011@{:x}", UART_BASE));
+    fdt.prop_strs("compatible", &["arm,pl011", "arm,primecell"]);
+    fdt.prop_u64s("reg", &[UART_BASE, 0x1000]);
+    fdt.prop_cells("interrupts", &[GIC_SPI, UART_SPI, IRQ_TYPE_LEVEL_HIGH]);
+    fdt.prop_cells("clocks", &[PHANDLE_APB_CLK, PHANDLE_APB_CLK]);
+    fdt.prop_strs("clock-names", &["uartclk", "apb_pclk"]);
+    fdt.end_node();
+
+    for &(base, size, spi) in &layout.virtio_mmio {
+        fdt.begin_node(&format!("virtio_mmio@{:x}", base));
+        fdt.prop_str("compatible", "virtio,mmio");
+        fdt.prop_u64s("reg", &[base as u64, size as u64]);
+        fdt.prop_cells("interrupts", &[GIC_SPI, spi, IRQ_TYPE_EDGE_RISING]);
+        fdt.prop_empty("dma-coherent");
+        fdt.end_node();
+    }
+
+    // No interrupt: an EL0 guest has no interrupt controller.
+    if let Some((base, size)) = layout.rtc {
+        rtc_node(&mut fdt, base, size, PHANDLE_APB_CLK);
+    }
+
+    fdt.end_node();
+    fdt.finish()
+}
+
+fn rtc_node(fdt: &mut FdtBuilder, base: usize, size: usize, apb_clk: u32) {
+    fdt.begin_node(&alloc::format!("pl031@{:x}", base));
+    fdt.prop_strs("compatible", &["arm,pl031", "arm,primecell"]);
+    fdt.prop_u64s("reg", &[base as u64, size as u64]);
+    fdt.prop_u32("clocks", apb_clk);
+    fdt.prop_str("clock-names", "apb_pclk");
+    fdt.end_node();
+}
+
+fn chosen_node(fdt: &mut FdtBuilder, layout: &GuestLayout, stdout_path: &str) {
+    fdt.begin_node("chosen");
+    fdt.prop_str("stdout-path", stdout_path);
+    if let Some(bootargs) = &layout.bootargs {
+        fdt.prop_str("bootargs", bootargs);
+    }
+    if let Some((start, end)) = layout.initrd {
+        fdt.prop_u64s("linux,initrd-start", &[start as u64]);
+        fdt.prop_u64s("linux,initrd-end", &[end as u64]);
+    }
+    fdt.end_node();
+}
+
+fn memory_node(fdt: &mut FdtBuilder, layout: &GuestLayout) {
+    fdt.begin_node(&alloc::format!("memory@{:x}", layout.ram_start));
+    fdt.prop_str("device_type", "memory");
+    fdt.prop_u64s("reg", &[layout.ram_start as u64, layout.ram_size as u64]);
+    fdt.end_node();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn be32(blob: &[u8], offset: usize) -> u32 {
+        u32::from_be_bytes(blob[offset..offset + 4].try_into().unwrap())
+    }
+
+    /// Returns the structure block of `blob` as 32-bit words.
+    fn structure(blob: &[u8]) -> Vec<u32> {
+        let (start, len) = (be32(blob, 8) as usize, be32(blob, 36) as usize);
+        (start..start + len)
+            .step_by(4)
+            .map(|i| be32(blob, i))
+            .collect()
+    }
+
+    fn strings(blob: &[u8]) -> &[u8] {
+        let (start, len) = (be32(blob, 12) as usize, be32(blob, 32) as usize);
+        &blob[start..start + len]
+    }
+
+    #[test]
+    fn empty_tree() {
+        let mut fdt = FdtBuilder::new();
+        fdt.begin_node("");
+        fdt.end_node();
+        let blob = fdt.finish();
+        assert_eq!(be32(&blob, 0), FDT_MAGIC);
+        assert_eq!(be32(&blob, 4) as usize, blob.len());
+        assert_eq!(be32(&blob, 16) as usize, FDT_HEADER_LEN);
+        assert_eq!(be32(&blob, 20), FDT_VERSION);
+        assert_eq!(be32(&blob, 24), FDT_LAST_COMP_VERSION);
+        // The memory reservation map only has its terminating entry.
+        assert!(
+            blob[FDT_HEADER_LEN..FDT_HEADER_LEN + 16]
+                .iter()
+                .all(|&b| b == 0)
+        );
+        assert_eq!(structure(&blob), [FDT_BEGIN_NODE, 0, FDT_END_NODE, FDT_END]);
+        assert!(strings(&blob).is_empty());
+    }
+
+    #[test]
+    fn properties() {
+        let mut fdt = FdtBuilder::new();
+        fdt.begin_node("");
+        fdt.prop_u32("#address-cells", 2);
+        fdt.begin_node("memory@80000000");
+        fdt.prop_str("device_type", "memory");
+        fdt.prop_u64s("reg", &[0x8000_0000, 0x800_0000]);
+        fdt.prop_empty("dma-coherent");
+        fdt.end_node();
+        fdt.end_node();
+        let blob = fdt.finish();
+
+        let words = structure(&blob);
+        // "memory@80000000\0" takes four words.
+        let name: Vec<u8> = words[7..11].iter().flat_map(|w| w.to_be_bytes()).collect();
+        assert_eq!(&name, b"memory@80000000\0");
+        assert_eq!(&words[..6], [FDT_BEGIN_NODE, 0, FDT_PROP, 4, 0, 2]);
+        // device_type = "memory\0", padded to two words.
+        assert_eq!(&words[11..14], [FDT_PROP, 7, 15]);
+        assert_eq!(&words[16..19], [FDT_PROP, 16, 27]);
+        assert_eq!(&words[19..23], [0, 0x8000_0000, 0, 0x800_0000]);
+        assert_eq!(
+            &words[23..],
+            [FDT_PROP, 0, 31, FDT_END_NODE, FDT_END_NODE, FDT_END]
+        );
+        assert_eq!(
+            strings(&blob),
+            b"#address-cells\0device_type\0reg\0dma-coherent\0"
+        );
+    }
+
+    #[test]
+    fn property_names_are_shared() {
+        let mut fdt = FdtBuilder::new();
+        fdt.begin_node("");
+        for node in ["a", "b"] {
+            fdt.begin_node(node);
+            fdt.prop_strs("compatible", &["x,y", "z"]);
+            fdt.end_node();
+        }
+        fdt.end_node();
+        assert_eq!(strings(&fdt.finish()), b"compatible\0");
+    }
+
+    #[test]
+    fn chosen_and_memory_nodes() {
+        let layout = GuestLayout {
+            ram_start: 0x8000_0000,
+            ram_size: 0x100_0000,
+            num_cpus: 1,
+            virtio_mmio: Vec::new(),
+            rtc: None,
+            aia: false,
+            isa: String::new(),
+            initrd: Some((0x8100_0000, 0x8120_0000)),
+            bootargs: Some("console=ttyS0".into()),
+        };
+        let mut fdt = FdtBuilder::new();
+        fdt.begin_node("");
+        chosen_node(&mut fdt, &layout, "/uart");
+        memory_node(&mut fdt, &layout);
+        fdt.end_node();
+        let blob = fdt.finish();
+        assert_eq!(
+            strings(&blob),
+            b"stdout-path\0bootargs\0linux,initrd-start\0linux,initrd-end\0device_type\0reg\0"
+        );
+        let words = structure(&blob);
+        // The initrd bounds are single 64-bit values.
+        let start = words.windows(5).position(|w| w[..3] == [FDT_PROP, 8, 21]);
+        assert_eq!(&words[start.unwrap() + 3..][..2], [0, 0x8100_0000]);
+    }
+
+    #[test]
+    #[should_panic(expected = "unbalanced FDT nodes")]
+    fn unclosed_node() {
+        let mut fdt = FdtBuilder::new();
+        fdt.begin_node("");
+        fdt.finish();
+    }
+
+    #[test]
+    #[should_panic(expected = "unbalanced FDT nodes")]
+    fn extra_end_node() {
+        FdtBuilder::new().end_node();
+    }
+}
@@ -0,0 +1,90 @@
+//! Typed access to guest physical memory.
+//!
+//! [`GuestMemory`] adds object and slice helpers on top of
+//! [`GuestSpace::read`] / [`GuestSpace::write`], which translate guest
+//! physical addresses through the stage-2 page table and split accesses at
+//! page boundaries. Device models and hypercall handlers of all three
+//! architectures use it instead of translating addresses themselves.
+//!
+//! Errors: [`AxError::InvalidInput`] if the range leaves the guest address
+//! space, [`AxError::BadAddress`] if part of it is not mapped.
+//!
+//! [`AxError::InvalidInput`]: axerrno::AxError::InvalidInput
+//! [`AxError::BadAddress`]: axerrno::AxError::BadAddress
+
+#![allow(dead_code)]
+
+use alloc::vec;
+use alloc::vec::Vec;
+use core::mem::{MaybeUninit, size_of};
+
+use axerrno::AxResult;
+use memory_addr::VirtAddr;
+
+use crate::gspace::GuestSpace;
+
+/// Plain data that can be copied from and to guest memory.
+///
+/// # Safety
+///
+/// Every bit pattern of `size_of::<Self>()` bytes must be a valid value, and
+/// the type must have no padding bytes.
+pub unsafe trait GuestObj: Copy {}
+
+macro_rules! impl_guest_obj {
+    ($($t:ty),*) => {
+        $(unsafe impl GuestObj for $t {})*
+    };
+}
+
+impl_guest_obj!(u8, u16, u32, u64, usize, i8, i16, i32, i64, isize);
+
+unsafe impl<T: GuestObj, const N: usize> GuestObj for [T; N] {}
+
+/// Reads and writes guest physical memory. Objects are copied in host byte
+/// order, which is little-endian like the guests on all three architectures.
+pub trait GuestMemory {
+    /// Copies guest memory at `gpa` into `buf`.
+    fn copy_from_guest(&self, gpa: usize, buf: &mut [u8]) -> AxResult;
+
+    /// Copies `data` to guest memory at `gpa`.
+    fn copy_to_guest(&mut self, gpa: usize, data: &[u8]) -> AxResult;
+
+    /// Reads `len` bytes at `gpa`.
+    fn read_slice(&self, gpa: usize, len: usize) -> AxResult<Vec<u8>> {
+        let mut buf = vec![0u8; len];
+        self.copy_from_guest(gpa, &mut buf)?;
+        Ok(buf)
+    }
+
+    /// Reads an object at `gpa`; it need not be aligned.
+    fn read_obj<T: GuestObj>(&self, gpa: usize) -> AxResult<T> {
+        let mut obj = MaybeUninit::<T>::uninit();
+        // SAFETY: `T: GuestObj` is valid for any bytes, which are all
+        // initialized by the copy before `assume_init`.
+        unsafe {
+            let bytes = core::slice::from_raw_parts_mut(obj.as_mut_ptr().cast(), size_of::<T>());
+            self.copy_from_guest(gpa, bytes)?;
+            Ok(obj.assume_init())
+        }
+    }
+
+    /// Writes `obj` at `gpa`; it need not be aligned.
+    fn write_obj<T: GuestObj>(&mut self, gpa: usize, obj: &T) -> AxResult {
+        // SAFETY: `T: GuestObj` has no padding, so all its bytes are
+        // initialized.
+        let bytes =
+            unsafe { core::slice::from_raw_parts((obj as *const T).cast(), size_of::<T>()) };
+        self.copy_to_guest(gpa, bytes)
+    }
+}
+
+impl GuestMemory for GuestSpace {
+    fn copy_from_guest(&self, gpa: usize, buf: &mut [u8]) -> AxResult {
+        self.read(VirtAddr::from(gpa), buf)
+    }
+
+    fn copy_to_guest(&mut self, gpa: usize, data: &[u8]) -> AxResult {
+        self.write(VirtAddr::from(gpa), data)
+    }
+}
@@ -0,0 +1,1300 @@
+//! Guest physical address space with huge page backing.
+//!
+//! Guest RAM is backed with the largest page size (1G, 2M or 4K) that the
+//! guest physical range, the remaining region size and the host frame
+//! allocator allow, which cuts the number of stage-2 faults and TLB entries
+//! for guests with tens of MB of RAM.
+//!
+//! This replaces `axmm::AddrSpace`, which cannot be extended to do so: both
+//! of its backends pass `allow_huge = false` to the page table, its
+//! allocating backend takes one 4K frame at a time, populated or at the
+//! fault, and its `Backend` is a closed enum that another mapping kind
+//! cannot be added to. Owning the frames here is also what the
+//! copy-on-write, memory limit and balloon support below build on.
+//!
+//! Guest images can be mapped copy-on-write from [`SharedPages`], so that
+//! VMs booting the same image share its frames until they write to them.
+//!
+//! The frames an address space owns are counted against an optional memory
+//! limit ([`GuestSpace::set_mem_limit`]): backing a page or breaking sharing
+//! beyond it fails like running out of host memory. A guest can give frames
+//! back ([`GuestSpace::discard`]) through the balloon hypercall. Ranges the
+//! guest shares with the hypervisor are pinned ([`GuestSpace::pin`]): backed,
+//! private and kept out of reach of the balloon.
+//!
+//! A hardened address space ([`GuestSpace::set_hardened`]) is W^X: only
+//! still shared copy-on-write pages (the guest image) and RAM mapped
+//! without WRITE (the code of a packaged image) are executable; writable
+//! RAM, passthrough ranges and image pages the guest wrote to are not.
+//!
+//! The permissions RAM is mapped with are its upper bound:
+//! [`GuestSpace::protect`] never makes read-only RAM writable, and
+//! [`GuestSpace::denied_access`] tells a guest access they deny from a
+//! fault the hypervisor fixes up.
+//!
+//! [`GuestSpace::harvest_accessed`] counts and clears the accessed bits of
+//! the stage-2 leaves mapping RAM, for working-set estimation; where the
+//! hardware does not set them again, the guest's next access to the page
+//! faults and [`GuestSpace::handle_access_fault`] does.
+//!
+//! The 4K frames that back faulting pages come from a per-space
+//! [`PagePool`] that zeroes them ahead of time.
+//!
+//! For debugging, [`GuestSpace::walk`] translates a guest physical address
+//! by reading the stage-2 tables level by level, and
+//! [`GuestSpace::for_each_entry`] visits every present entry.
+//!
+//! The API mirrors the subset of `AddrSpace` the hypervisor uses, with guest
+//! physical addresses passed as [`VirtAddr`] (the input address of the
+//! stage-2 / NPT / guest TTBR0 table).
+
+#![allow(dead_code)]
+
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use axalloc::{UsageKind, global_allocator};
+use axerrno::{AxError, AxResult};
+use axhal::mem::{phys_to_virt, virt_to_phys};
+use axhal::paging::{MappingFlags, PageSize, PageTable, PagingError};
+use memory_addr::{MemoryAddr, PAGE_SIZE_4K, PhysAddr, VirtAddr};
+use page_table_multiarch::{GenericPTE, PageTable64, PagingHandler, PagingMetaData};
+
+use crate::hugeblock;
+use crate::permfault::{Access, Perms};
+use crate::pool::{PagePool, PoolStats};
+
+/// Page sizes tried for guest RAM, largest first.
+const PAGE_SIZES: [PageSize; 3] = [PageSize::Size1G, PageSize::Size2M, PageSize::Size4K];
+/// The huge ones of [`PAGE_SIZES`].
+const HUGE_SIZES: [PageSize; 2] = [PageSize::Size1G, PageSize::Size2M];
+
+/// Accessed bit of a stage-2 leaf entry: riscv64 A, aarch64 AF, x86_64 NPT
+/// A. riscv64 and aarch64 leaves are created with it set.
+#[cfg(target_arch = "riscv64")]
+const PTE_ACCESSED: usize = 1 << 6;
+#[cfg(target_arch = "aarch64")]
+const PTE_ACCESSED: usize = 1 << 10;
+#[cfg(not(any(target_arch = "riscv64", target_arch = "aarch64")))]
+const PTE_ACCESSED: usize = 1 << 5;
+
+/// One host frame (of any page size) backing guest RAM.
+struct Frame {
+    gpa: usize,
+    paddr: PhysAddr,
+    size: PageSize,
+}
+
+/// Read-only 4K frames holding data shared by several guest address spaces.
+///
+/// The frames are freed when the last mapping (and handle) goes away.
+pub struct SharedPages {
+    frames: Vec<PhysAddr>,
+    len: usize,
+}
+
+impl SharedPages {
+    /// Copies `data` into freshly allocated frames; the tail of the last
+    /// frame is zeroed.
+    pub fn new(data: &[u8]) -> AxResult<Self> {
+        let mut pages = Self {
+            frames: Vec::with_capacity(data.len().div_ceil(PAGE_SIZE_4K)),
+            len: data.len(),
+        };
+        for chunk in data.chunks(PAGE_SIZE_4K) {
+            let paddr = alloc_frame(PageSize::Size4K).ok_or(AxError::NoMemory)?;
+            pages.frames.push(paddr);
+            let dst = phys_to_virt(paddr).as_mut_ptr();
+            unsafe {
+                core::ptr::copy_nonoverlapping(chunk.as_ptr(), dst, chunk.len());
+            }
+            sync_icache(paddr);
+        }
+        Ok(pages)
+    }
+
+    /// Returns the length of the shared data in bytes.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Returns the mapped size: the data length rounded up to whole pages.
+    pub fn size(&self) -> usize {
+        self.frames.len() * PAGE_SIZE_4K
+    }
+
+    /// Copies the data at `offset` into `buf`; bytes past the end read as
+    /// zero.
+    pub fn read(&self, offset: usize, buf: &mut [u8]) {
+        let mut done = 0;
+        while done < buf.len() {
+            let pos = offset + done;
+            let chunk = (buf.len() - done).min(PAGE_SIZE_4K - pos % PAGE_SIZE_4K);
+            let dst = &mut buf[done..done + chunk];
+            match self.frames.get(pos / PAGE_SIZE_4K) {
+                // The tail of the last frame is zeroed by `new`.
+                Some(&paddr) => unsafe {
+                    let src = phys_to_virt(paddr).as_ptr().add(pos % PAGE_SIZE_4K);
+                    core::ptr::copy_nonoverlapping(src, dst.as_mut_ptr(), chunk);
+                },
+                None => dst.fill(0),
+            }
+            done += chunk;
+        }
+    }
+}
+
+impl Drop for SharedPages {
+    fn drop(&mut self) {
+        for &paddr in &self.frames {
+            dealloc_frame(paddr, PageSize::Size4K);
+        }
+    }
+}
+
+enum Backing {
+    /// Fixed host physical range (device passthrough).
+    Linear,
+    /// Frames from the global allocator; `populate == false` regions are
+    /// backed on first access.
+    Alloc { populate: bool, frames: Vec<Frame> },
+    /// Shared read-only frames; a page is copied into `private` on its first
+    /// write.
+    Cow {
+        shared: Arc<SharedPages>,
+        private: BTreeMap<usize, PhysAddr>,
+    },
+}
+
+struct Region {
+    size: usize,
+    flags: MappingFlags,
+    backing: Backing,
+}
+
+/// A guest physical address space: its stage-2 page table and the memory
+/// regions mapped into it. All owned frames are freed on drop.
+pub struct GuestSpace {
+    base: usize,
+    size: usize,
+    pt: PageTable,
+    /// Regions keyed by their first guest physical address.
+    regions: BTreeMap<usize, Region>,
+    /// Bytes of RAM frames owned (shared copy-on-write frames excluded).
+    used: usize,
+    /// Most bytes of RAM frames that may be owned.
+    limit: usize,
+    /// An allocation failed because of `limit`.
+    limit_hit: bool,
+    /// Writable mappings are never executable.
+    hardened: bool,
+    /// 4K frames for faults.
+    pool: PagePool,
+    /// 4K pages backed together by a fault that gets no huge page.
+    fault_around: usize,
+    /// Pinned ranges, start to size.
+    pinned: BTreeMap<usize, usize>,
+}
+
+impl GuestSpace {
+    /// Creates an empty guest address space covering `[base, base + size)`.
+    pub fn new(base: VirtAddr, size: usize) -> AxResult<Self> {
+        Ok(Self {
+            base: base.as_usize(),
+            size,
+            pt: PageTable::try_new().map_err(|_| AxError::NoMemory)?,
+            regions: BTreeMap::new(),
+            used: 0,
+            limit: usize::MAX,
+            limit_hit: false,
+            hardened: false,
+            pool: PagePool::new(),
+            fault_around: 1,
+            pinned: BTreeMap::new(),
+        })
+    }
+
+    /// Makes the mappings created from now on W^X: writable RAM and
+    /// passthrough regions lose EXECUTE, and copy-on-write pages lose it
+    /// once they become private. Call before mapping anything.
+    pub fn set_hardened(&mut self, hardened: bool) {
+        self.hardened = hardened;
+    }
+
+    /// Checks whether the address space is W^X.
+    pub fn is_hardened(&self) -> bool {
+        self.hardened
+    }
+
+    /// Returns `flags` for guest-writable memory: without EXECUTE in a
+    /// hardened address space.
+    fn data_flags(&self, flags: MappingFlags) -> MappingFlags {
+        if self.hardened {
+            flags - MappingFlags::EXECUTE
+        } else {
+            flags
+        }
+    }
+
+    /// Returns `flags` for RAM: [`Self::data_flags`] if writable; read-only
+    /// RAM keeps EXECUTE, which W^X allows.
+    fn ram_flags(&self, flags: MappingFlags) -> MappingFlags {
+        if flags.contains(MappingFlags::WRITE) {
+            self.data_flags(flags)
+        } else {
+            flags
+        }
+    }
+
+    /// Makes a fault in lazily backed RAM that cannot take a huge page back
+    /// the not yet backed pages of its aligned window of `pages` 4K pages,
+    /// so a guest touching memory in sequence takes one fault per window.
+    pub fn set_fault_around(&mut self, pages: usize) {
+        self.fault_around = pages.max(1);
+    }
+
+    /// Limits the RAM frames this address space may own to `bytes`. Frames
+    /// owned already are kept, even beyond the limit.
+    pub fn set_mem_limit(&mut self, bytes: usize) {
+        self.limit = bytes;
+    }
+
+    /// Returns the memory limit, if one is set.
+    pub fn mem_limit(&self) -> Option<usize> {
+        (self.limit != usize::MAX).then_some(self.limit)
+    }
+
+    /// Returns the bytes of RAM frames owned by this address space (shared
+    /// copy-on-write frames excluded).
+    pub fn mem_used(&self) -> usize {
+        self.used
+    }
+
+    /// Checks whether backing a page or breaking copy-on-write sharing has
+    /// failed because of the memory limit.
+    pub fn mem_limit_reached(&self) -> bool {
+        self.limit_hit
+    }
+
+    /// Accounts `bytes` of new frames if the limit allows them.
+    fn charge(&mut self, bytes: usize) -> bool {
+        if self.used.saturating_add(bytes) > self.limit {
+            return false;
+        }
+        self.used += bytes;
+        true
+    }
+
+    /// Zeroes frames for future faults; call while the guest idles.
+    pub fn prepare_pool(&mut self) {
+        self.pool.prepare();
+    }
+
+    /// Returns the counters of the fault frame pool.
+    pub fn pool_stats(&self) -> PoolStats {
+        self.pool.stats()
+    }
+
+    /// Returns the stage-2 page table.
+    pub fn page_table(&self) -> &PageTable {
+        &self.pt
+    }
+
+    /// Returns the physical address of the root page table.
+    pub fn page_table_root(&self) -> PhysAddr {
+        self.pt.root_paddr()
+    }
+
+    /// Checks whether `[start, start + size)` lies inside the address space.
+    pub fn contains_range(&self, start: VirtAddr, size: usize) -> bool {
+        let start = start.as_usize();
+        start >= self.base
+            && start
+                .checked_add(size)
+                .is_some_and(|end| end <= self.base + self.size)
+    }
+
+    /// Maps `[start, start + size)` to the host physical range at `paddr`,
+    /// using huge pages where both sides are suitably aligned.
+    pub fn map_linear(
+        &mut self,
+        start: VirtAddr,
+        paddr: PhysAddr,
+        size: usize,
+        flags: MappingFlags,
+    ) -> AxResult {
+        self.check_new_region(start, size)?;
+        if !paddr.is_aligned_4k() {
+            return Err(AxError::InvalidInput);
+        }
+        let flags = self.data_flags(flags);
+        let offset = start.as_usize().wrapping_sub(paddr.as_usize());
+        self.pt
+            .cursor()
+            .map_region(
+                start,
+                |va| PhysAddr::from(va.as_usize().wrapping_sub(offset)),
+                size,
+                flags,
+                true,
+            )
+            .map_err(paging_err)?;
+        self.regions.insert(
+            start.as_usize(),
+            Region {
+                size,
+                flags,
+                backing: Backing::Linear,
+            },
+        );
+        Ok(())
+    }
+
+    /// Maps `[start, start + size)` to freshly allocated, zeroed guest RAM.
+    ///
+    /// With `populate` the whole range is backed immediately, otherwise each
+    /// block is backed by [`Self::handle_page_fault`] on first access. Either
+    /// way, aligned blocks get 1G or 2M frames when the allocator has them.
+    pub fn map_alloc(
+        &mut self,
+        start: VirtAddr,
+        size: usize,
+        flags: MappingFlags,
+        populate: bool,
+    ) -> AxResult {
+        self.check_new_region(start, size)?;
+        let flags = self.ram_flags(flags);
+        let mut frames = Vec::new();
+        if populate {
+            let end = start.as_usize() + size;
+            let mut gpa = start.as_usize();
+            while gpa < end {
+                match self.back_block(gpa, end - gpa, flags) {
+                    Ok(frame) => {
+                        gpa += frame.size as usize;
+                        frames.push(frame);
+                    }
+                    Err(e) => {
+                        self.release_frames(&frames);
+                        return Err(e);
+                    }
+                }
+            }
+        }
+        self.regions.insert(
+            start.as_usize(),
+            Region {
+                size,
+                flags,
+                backing: Backing::Alloc { populate, frames },
+            },
+        );
+        Ok(())
+    }
+
+    /// Maps `shared` at `start` copy-on-write: every page stays a read-only
+    /// mapping of the shared frame until the guest (or [`Self::write`])
+    /// writes to it, see [`Self::handle_cow_fault`].
+    pub fn map_cow(
+        &mut self,
+        start: VirtAddr,
+        shared: Arc<SharedPages>,
+        flags: MappingFlags,
+    ) -> AxResult {
+        self.check_new_region(start, shared.size())?;
+        let mut cursor = self.pt.cursor();
+        for (i, &paddr) in shared.frames.iter().enumerate() {
+            let gpa = start + i * PAGE_SIZE_4K;
+            if let Err(e) = cursor.map(gpa, paddr, PageSize::Size4K, flags - MappingFlags::WRITE) {
+                for j in 0..i {
+                    let _ = cursor.unmap(start + j * PAGE_SIZE_4K);
+                }
+                return Err(paging_err(e));
+            }
+        }
+        drop(cursor);
+        self.regions.insert(
+            start.as_usize(),
+            Region {
+                size: shared.size(),
+                flags,
+                backing: Backing::Cow {
+                    shared,
+                    private: BTreeMap::new(),
+                },
+            },
+        );
+        Ok(())
+    }
+
+    /// Breaks copy-on-write sharing of the page containing `gpa`: copies the
+    /// shared frame into a private one and maps it with the region's flags
+    /// (without EXECUTE if the address space is hardened).
+    ///
+    /// Returns `true` if a copy was made, `false` if `gpa` is not a still
+    /// shared copy-on-write page. The guest TLB entry for the page must be
+    /// flushed afterwards.
+    pub fn handle_cow_fault(&mut self, gpa: VirtAddr) -> bool {
+        let gpa = gpa.as_usize();
+        let Some((&start, region)) = self.regions.range_mut(..=gpa).next_back() else {
+            return false;
+        };
+        if gpa >= start + region.size {
+            return false;
+        }
+        let flags = if self.hardened {
+            region.flags - MappingFlags::EXECUTE
+        } else {
+            region.flags
+        };
+        let Backing::Cow { shared, private } = &mut region.backing else {
+            return false;
+        };
+        let idx = (gpa - start) / PAGE_SIZE_4K;
+        if private.contains_key(&idx) {
+            return false;
+        }
+        if self.used.saturating_add(PAGE_SIZE_4K) > self.limit {
+            self.limit_hit = true;
+            return false;
+        }
+        let Some(paddr) = self.pool.take_any() else {
+            return false;
+        };
+        unsafe {
+            core::ptr::copy_nonoverlapping(
+                phys_to_virt(shared.frames[idx]).as_ptr(),
+                phys_to_virt(paddr).as_mut_ptr(),
+                PAGE_SIZE_4K,
+            );
+        }
+        sync_icache(paddr);
+        let page = start + idx * PAGE_SIZE_4K;
+        if self.pt.cursor().remap(page.into(), paddr, flags).is_err() {
+            dealloc_frame(paddr, PageSize::Size4K);
+            return false;
+        }
+        private.insert(idx, paddr);
+        self.used += PAGE_SIZE_4K;
+        true
+    }
+
+    /// Backs the page containing `gpa` if it lies in a not yet populated
+    /// allocation region, using the largest aligned block that fits in the
+    /// region. A single 4K page comes with the unbacked pages of its
+    /// fault-around window ([`Self::set_fault_around`]) as far as the region
+    /// and the memory limit allow.
+    ///
+    /// Returns `true` if a mapping was created.
+    pub fn handle_page_fault(&mut self, gpa: VirtAddr) -> bool {
+        let gpa = gpa.as_usize();
+        let Some((&start, region)) = self.regions.range(..=gpa).next_back() else {
+            return false;
+        };
+        if gpa >= start + region.size || self.pt.query(gpa.into()).is_ok() {
+            return false;
+        }
+        let (size, flags) = (region.size, region.flags);
+        if !matches!(
+            region.backing,
+            Backing::Alloc {
+                populate: false,
+                ..
+            }
+        ) {
+            return false;
+        }
+        // Largest huge block around `gpa` that stays inside the region and
+        // can be allocated. Part of one may already be backed by 4K pages
+        // after an earlier huge allocation failed; fall back to a single
+        // page then.
+        let huge = hugeblock::back_around(gpa, start, start + size, &HUGE_SIZES, |block, ps| {
+            self.back_frame(block, ps, flags).ok()
+        });
+        let page = gpa & !(PAGE_SIZE_4K - 1);
+        let mut new_frames = Vec::new();
+        match huge {
+            Some(frame) => new_frames.push(frame),
+            None => {
+                match self.back_block(page, PAGE_SIZE_4K, flags) {
+                    Ok(frame) => new_frames.push(frame),
+                    Err(_) => return false,
+                }
+                self.fault_around(page, start, size, flags, &mut new_frames);
+            }
+        }
+        if let Some(Region {
+            backing: Backing::Alloc { frames, .. },
+            ..
+        }) = self.regions.get_mut(&start)
+        {
+            frames.extend(new_frames);
+        }
+        true
+    }
+
+    /// Backs the unbacked 4K pages of the fault-around window of `page` in
+    /// the region `[start, start + size)`. Stops quietly at the memory limit
+    /// or when the host runs out of memory: the faulting page is backed.
+    fn fault_around(
+        &mut self,
+        page: usize,
+        start: usize,
+        size: usize,
+        flags: MappingFlags,
+        frames: &mut Vec<Frame>,
+    ) {
+        let window = self.fault_around * PAGE_SIZE_4K;
+        let first = (page - page % window).max(start);
+        let end = (page - page % window + window).min(start + size);
+        for gpa in (first..end).step_by(PAGE_SIZE_4K) {
+            if self.pt.query(gpa.into()).is_ok() {
+                continue;
+            }
+            if self.used.saturating_add(PAGE_SIZE_4K) > self.limit {
+                break;
+            }
+            match self.back_block(gpa, PAGE_SIZE_4K, flags) {
+                Ok(frame) => frames.push(frame),
+                Err(_) => break,
+            }
+        }
+    }
+
+    /// Removes the regions starting exactly at `start` and covering exactly
+    /// `size` bytes, freeing their frames.
+    pub fn unmap(&mut self, start: VirtAddr, size: usize) -> AxResult {
+        let mut gpa = start.as_usize();
+        let end = gpa + size;
+        // Only whole regions can be removed.
+        while gpa < end {
+            match self.regions.get(&gpa) {
+                Some(region) if gpa + region.size <= end => gpa += region.size,
+                _ => return Err(AxError::InvalidInput),
+            }
+        }
+        gpa = start.as_usize();
+        while gpa < end {
+            let region = self.regions.remove(&gpa).unwrap();
+            self.unmap_region(gpa, &region);
+            gpa += region.size;
+        }
+        Ok(())
+    }
+
+    /// Gives the RAM frames backing `[start, start + size)` back to the
+    /// host. Allocation frames lying wholly inside the range are unmapped
+    /// and freed, to be backed again (zeroed) on the next access; private
+    /// copies of copy-on-write pages are freed and the shared page is mapped
+    /// again. Huge frames reaching outside the range are kept.
+    ///
+    /// Returns the bytes freed, or [`AxError::ResourceBusy`] if the range
+    /// overlaps a pinned one. The guest TLB entries of the range must be
+    /// flushed afterwards.
+    pub fn discard(&mut self, start: VirtAddr, size: usize) -> AxResult<usize> {
+        if !self.contains_range(start, size)
+            || !start.is_aligned_4k()
+            || !size.is_multiple_of(PAGE_SIZE_4K)
+        {
+            return Err(AxError::InvalidInput);
+        }
+        let (start, end) = (start.as_usize(), start.as_usize() + size);
+        if self.overlaps_pinned(start, end) {
+            return Err(AxError::ResourceBusy);
+        }
+        let mut freed = 0;
+        let first = self
+            .regions
+            .range(..=start)
+            .next_back()
+            .map_or(start, |(&s, _)| s);
+        for (&base, region) in self.regions.range_mut(first..end) {
+            if base + region.size <= start {
+                continue;
+            }
+            match &mut region.backing {
+                Backing::Alloc { populate, frames } => {
+                    let mut cursor = self.pt.cursor();
+                    frames.retain(|frame| {
+                        if frame.gpa < start || frame.gpa + frame.size as usize > end {
+                            return true;
+                        }
+                        let _ = cursor.unmap(frame.gpa.into());
+                        dealloc_frame(frame.paddr, frame.size);
+                        freed += frame.size as usize;
+                        false
+                    });
+                    // The range is backed on fault from now on.
+                    *populate = false;
+                }
+                Backing::Cow { shared, private } => {
+                    let mut cursor = self.pt.cursor();
+                    let flags = region.flags - MappingFlags::WRITE;
+                    private.retain(|&idx, &mut paddr| {
+                        let gpa = base + idx * PAGE_SIZE_4K;
+                        if gpa < start || gpa >= end {
+                            return true;
+                        }
+                        if cursor.remap(gpa.into(), shared.frames[idx], flags).is_err() {
+                            return true;
+                        }
+                        dealloc_frame(paddr, PageSize::Size4K);
+                        freed += PAGE_SIZE_4K;
+                        false
+                    });
+                }
+                Backing::Linear => {}
+            }
+        }
+        self.used -= freed;
+        Ok(freed)
+    }
+
+    /// Pins `[start, start + size)`, page aligned guest RAM, for the
+    /// hypervisor to access while the guest runs: every page is backed and
+    /// made private, and [`Self::discard`] refuses the range until it is
+    /// unpinned. Pinned ranges must not overlap.
+    ///
+    /// Fails with [`AxError::ResourceBusy`] if the range overlaps a pinned
+    /// one, [`AxError::BadAddress`] if part of it is not RAM and
+    /// [`AxError::NoMemory`] if it cannot be backed; pages backed before the
+    /// failure stay backed. The guest TLB entries of the range must be
+    /// flushed afterwards.
+    pub fn pin(&mut self, start: VirtAddr, size: usize) -> AxResult {
+        if size == 0
+            || !self.contains_range(start, size)
+            || !start.is_aligned_4k()
+            || !size.is_multiple_of(PAGE_SIZE_4K)
+        {
+            return Err(AxError::InvalidInput);
+        }
+        let (start, end) = (start.as_usize(), start.as_usize() + size);
+        if self.overlaps_pinned(start, end) {
+            return Err(AxError::ResourceBusy);
+        }
+        for gpa in (start..end).step_by(PAGE_SIZE_4K) {
+            self.handle_cow_fault(gpa.into());
+            if self.pt.query(gpa.into()).is_err() {
+                self.handle_page_fault(gpa.into());
+            }
+            match self.is_private_ram(gpa) {
+                Some(true) => {}
+                Some(false) => return Err(AxError::NoMemory),
+                None => return Err(AxError::BadAddress),
+            }
+        }
+        self.pinned.insert(start, size);
+        Ok(())
+    }
+
+    /// Unpins the range pinned at `start`; [`AxError::NotFound`] if there
+    /// is none.
+    pub fn unpin(&mut self, start: VirtAddr) -> AxResult {
+        self.pinned
+            .remove(&start.as_usize())
+            .map(|_| ())
+            .ok_or(AxError::NotFound)
+    }
+
+    fn overlaps_pinned(&self, start: usize, end: usize) -> bool {
+        self.pinned
+            .range(..end)
+            .next_back()
+            .is_some_and(|(&s, &size)| s + size > start)
+    }
+
+    /// Checks whether the page at `gpa` is RAM backed by a frame of this
+    /// address space; `None` if it is not RAM.
+    fn is_private_ram(&self, gpa: usize) -> Option<bool> {
+        let (&start, region) = self.regions.range(..=gpa).next_back()?;
+        if gpa >= start + region.size {
+            return None;
+        }
+        match &region.backing {
+            Backing::Alloc { .. } => Some(self.pt.query(gpa.into()).is_ok()),
+            Backing::Cow { private, .. } => {
+                Some(private.contains_key(&((gpa - start) / PAGE_SIZE_4K)))
+            }
+            Backing::Linear => None,
+        }
+    }
+
+    /// Returns the permissions of the page at `gpa` if they deny `access`
+    /// for good: a write to read-only RAM, or a fetch from RAM or a
+    /// passthrough range that is not executable. `None` if `gpa` is outside
+    /// every region or the region allows the access (a fault there is one
+    /// the hypervisor fixes: copy-on-write, lazy backing, dirty logging).
+    pub fn denied_access(&self, gpa: VirtAddr, access: Access) -> Option<Perms> {
+        let gpa = gpa.as_usize();
+        let (&start, region) = self.regions.range(..=gpa).next_back()?;
+        if gpa >= start + region.size {
+            return None;
+        }
+        let flags = match &region.backing {
+            Backing::Alloc { .. } => self.ram_flags(region.flags),
+            Backing::Linear => self.data_flags(region.flags),
+            Backing::Cow { private, .. }
+                if private.contains_key(&((gpa - start) / PAGE_SIZE_4K)) =>
+            {
+                self.data_flags(region.flags)
+            }
+            Backing::Cow { .. } => region.flags,
+        };
+        let perms = Perms {
+            read: flags.contains(MappingFlags::READ),
+            write: flags.contains(MappingFlags::WRITE),
+            execute: flags.contains(MappingFlags::EXECUTE),
+        };
+        (!perms.allows(access)).then_some(perms)
+    }
+
+    /// Clears the accessed bits of the stage-2 leaves mapping RAM and
+    /// returns the bytes those that were set map. Passthrough ranges are
+    /// skipped.
+    ///
+    /// The guest TLB must be flushed afterwards so that the next access to
+    /// a page sets its bit again.
+    pub fn harvest_accessed(&mut self) -> usize {
+        let mut accessed = 0;
+        for (&start, region) in &self.regions {
+            if matches!(region.backing, Backing::Linear) {
+                continue;
+            }
+            let end = start + region.size;
+            let mut gpa = start;
+            while gpa < end {
+                let span = match leaf_entry(&self.pt, gpa) {
+                    Ok((entry, span)) => {
+                        let entry = unsafe { AtomicUsize::from_ptr(entry) };
+                        if entry.fetch_and(!PTE_ACCESSED, Ordering::Relaxed) & PTE_ACCESSED != 0 {
+                            accessed += span;
+                        }
+                        span
+                    }
+                    Err(span) => span,
+                };
+                gpa = (gpa & !(span - 1)) + span;
+            }
+        }
+        accessed
+    }
+
+    /// Sets the accessed bit of the stage-2 leaf mapping `gpa`, for the
+    /// fault the guest takes on a page [`Self::harvest_accessed`] cleared
+    /// it on where the hardware does not set it (riscv64 without Svadu,
+    /// aarch64 access flag faults).
+    ///
+    /// Returns `true` if the bit was clear; the guest TLB entry for the
+    /// page must be flushed then.
+    pub fn handle_access_fault(&mut self, gpa: VirtAddr) -> bool {
+        let Ok((entry, _)) = leaf_entry(&self.pt, gpa.as_usize()) else {
+            return false;
+        };
+        let entry = unsafe { AtomicUsize::from_ptr(entry) };
+        entry.fetch_or(PTE_ACCESSED, Ordering::Relaxed) & PTE_ACCESSED == 0
+    }
+
+    /// Removes all regions, freeing their frames. The page table keeps only
+    /// empty intermediate tables, freed with the address space.
+    pub fn clear(&mut self) {
+        let regions = core::mem::take(&mut self.regions);
+        for (&start, region) in &regions {
+            self.unmap_region(start, region);
+        }
+        self.limit_hit = false;
+        self.pinned.clear();
+    }
+
+    /// Queries the mapping of `gpa`: host physical address (of `gpa` itself,
+    /// not of the page), flags and page size.
+    pub fn query(&self, gpa: VirtAddr) -> AxResult<(PhysAddr, MappingFlags, PageSize)> {
+        self.pt.query(gpa).map_err(paging_err)
+    }
+
+    /// Translates `gpa` by reading the stage-2 tables from the root down,
+    /// recording every entry on the way.
+    pub fn walk(&self, gpa: VirtAddr) -> Stage2Walk {
+        walk_table(&self.pt, gpa.as_usize())
+    }
+
+    /// Calls `f` with every present stage-2 entry, a table entry before the
+    /// entries of the table it points to.
+    pub fn for_each_entry(&self, f: impl Fn(&Stage2Entry)) {
+        for_each_table_entry(&self.pt, f)
+    }
+
+    /// Changes the flags of all present mappings in `[start, start + size)`.
+    /// Still shared copy-on-write pages never become writable, RAM mapped
+    /// without WRITE keeps at most its flags, and in a hardened address
+    /// space no other page becomes executable.
+    ///
+    /// The range must not split a huge mapping.
+    pub fn protect(&mut self, start: VirtAddr, size: usize, flags: MappingFlags) -> AxResult {
+        if !self.contains_range(start, size)
+            || !start.is_aligned_4k()
+            || !size.is_multiple_of(PAGE_SIZE_4K)
+        {
+            return Err(AxError::InvalidInput);
+        }
+        let end = start.as_usize() + size;
+        let mut gpa = start.as_usize();
+        while gpa < end {
+            let step = match self.pt.query(gpa.into()) {
+                Ok((_, _, ps)) => ps as usize,
+                Err(_) => PAGE_SIZE_4K,
+            };
+            if !gpa.is_multiple_of(step) || gpa + step > end {
+                return Err(AxError::InvalidInput);
+            }
+            gpa += step;
+        }
+        let mut cursor = self.pt.cursor();
+        cursor
+            .protect_region(start, size, flags)
+            .map_err(paging_err)?;
+        let start = start.as_usize();
+        let data_flags = if self.hardened {
+            flags - MappingFlags::EXECUTE
+        } else {
+            flags
+        };
+        if flags.contains(MappingFlags::WRITE) || data_flags != flags {
+            for (&s, region) in self.regions.range(..end) {
+                match &region.backing {
+                    Backing::Alloc { .. } if !region.flags.contains(MappingFlags::WRITE) => {
+                        let (lo, hi) = (s.max(start), (s + region.size).min(end));
+                        let ro_flags = flags & region.flags;
+                        if lo < hi && ro_flags != flags {
+                            let _ = cursor.protect_region(lo.into(), hi - lo, ro_flags);
+                        }
+                    }
+                    Backing::Cow { private, .. } => {
+                        for idx in 0..region.size / PAGE_SIZE_4K {
+                            let page = s + idx * PAGE_SIZE_4K;
+                            if page < start || page >= end {
+                                continue;
+                            }
+                            let page_flags = if private.contains_key(&idx) {
+                                data_flags
+                            } else {
+                                flags - MappingFlags::WRITE
+                            };
+                            if page_flags != flags {
+                                let _ = cursor.protect(page.into(), page_flags);
+                            }
+                        }
+                    }
+                    _ if data_flags != flags => {
+                        let (lo, hi) = (s.max(start), (s + region.size).min(end));
+                        if lo < hi {
+                            let _ = cursor.protect_region(lo.into(), hi - lo, data_flags);
+                        }
+                    }
+                    _ => {}
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Reads guest memory; not yet backed allocation pages read as zeros.
+    pub fn read(&self, start: VirtAddr, buf: &mut [u8]) -> AxResult {
+        if !self.contains_range(start, buf.len()) {
+            return Err(AxError::InvalidInput);
+        }
+        let mut done = 0;
+        while done < buf.len() {
+            let gpa = start.as_usize() + done;
+            let chunk = match self.pt.query(gpa.into()) {
+                Ok((paddr, _, ps)) => {
+                    let chunk = (buf.len() - done).min(ps as usize - gpa % ps as usize);
+                    let src = phys_to_virt(paddr).as_ptr();
+                    unsafe {
+                        core::ptr::copy_nonoverlapping(src, buf[done..].as_mut_ptr(), chunk);
+                    }
+                    chunk
+                }
+                Err(_) if self.is_lazy_alloc(gpa) => {
+                    let chunk = (buf.len() - done).min(PAGE_SIZE_4K - gpa % PAGE_SIZE_4K);
+                    buf[done..done + chunk].fill(0);
+                    chunk
+                }
+                Err(_) => return Err(AxError::BadAddress),
+            };
+            done += chunk;
+        }
+        Ok(())
+    }
+
+    /// Writes guest memory, backing not yet populated allocation pages and
+    /// breaking copy-on-write sharing.
+    pub fn write(&mut self, start: VirtAddr, buf: &[u8]) -> AxResult {
+        if !self.contains_range(start, buf.len()) {
+            return Err(AxError::InvalidInput);
+        }
+        let mut done = 0;
+        while done < buf.len() {
+            let gpa = start.as_usize() + done;
+            self.handle_cow_fault(gpa.into());
+            let (paddr, _, ps) = match self.pt.query(gpa.into()) {
+                Ok(mapping) => mapping,
+                Err(_) if self.handle_page_fault(gpa.into()) => {
+                    self.pt.query(gpa.into()).map_err(paging_err)?
+                }
+                Err(_) => return Err(AxError::BadAddress),
+            };
+            let chunk = (buf.len() - done).min(ps as usize - gpa % ps as usize);
+            let dst = phys_to_virt(paddr).as_mut_ptr();
+            unsafe {
+                core::ptr::copy_nonoverlapping(buf[done..].as_ptr(), dst, chunk);
+            }
+            done += chunk;
+        }
+        Ok(())
+    }
+
+    /// Makes instructions copied to `[start, start + size)` with
+    /// [`Self::write`] visible to instruction fetch.
+    pub fn sync_icache_range(&self, start: VirtAddr, size: usize) {
+        let end = start.as_usize() + size;
+        for gpa in (start.align_down_4k().as_usize()..end).step_by(PAGE_SIZE_4K) {
+            if let Ok((paddr, _, _)) = self.pt.query(gpa.into()) {
+                sync_icache(paddr.align_down_4k());
+            }
+        }
+    }
+
+    /// Returns the number of guest RAM frames owned by this address space
+    /// (shared copy-on-write frames excluded) per page size as
+    /// `(4K, 2M, 1G)`.
+    pub fn frame_counts(&self) -> (usize, usize, usize) {
+        let mut counts = (0, 0, 0);
+        for region in self.regions.values() {
+            match &region.backing {
+                Backing::Alloc { frames, .. } => {
+                    for frame in frames {
+                        match frame.size {
+                            PageSize::Size4K => counts.0 += 1,
+                            PageSize::Size2M => counts.1 += 1,
+                            PageSize::Size1G => counts.2 += 1,
+                            _ => {}
+                        }
+                    }
+                }
+                Backing::Cow { private, .. } => counts.0 += private.len(),
+                Backing::Linear => {}
+            }
+        }
+        counts
+    }
+
+    fn check_new_region(&self, start: VirtAddr, size: usize) -> AxResult {
+        if size == 0 || !start.is_aligned_4k() || !size.is_multiple_of(PAGE_SIZE_4K) {
+            return Err(AxError::InvalidInput);
+        }
+        if !self.contains_range(start, size) {
+            return Err(AxError::InvalidInput);
+        }
+        let (start, end) = (start.as_usize(), start.as_usize() + size);
+        let overlaps = self
+            .regions
+            .range(..end)
+            .next_back()
+            .is_some_and(|(&s, r)| s + r.size > start);
+        if overlaps {
+            return Err(AxError::AlreadyExists);
+        }
+        Ok(())
+    }
+
+    fn is_lazy_alloc(&self, gpa: usize) -> bool {
+        self.regions
+            .range(..=gpa)
+            .next_back()
+            .is_some_and(|(&s, r)| {
+                gpa < s + r.size
+                    && matches!(
+                        r.backing,
+                        Backing::Alloc {
+                            populate: false,
+                            ..
+                        }
+                    )
+            })
+    }
+
+    /// Allocates and maps one frame at `gpa`, as large as the alignment of
+    /// `gpa`, `max_size`, the memory limit and the allocator permit.
+    fn back_block(&mut self, gpa: usize, max_size: usize, flags: MappingFlags) -> AxResult<Frame> {
+        for size in PAGE_SIZES {
+            let bytes = size as usize;
+            if !gpa.is_multiple_of(bytes) || bytes > max_size {
+                continue;
+            }
+            match self.back_frame(gpa, size, flags) {
+                Err(AxError::NoMemory) => continue,
+                result => return result,
+            }
+        }
+        Err(AxError::NoMemory)
+    }
+
+    /// Allocates and maps one frame of `size` at `gpa`, which must be
+    /// aligned to it. Fails with `NoMemory` if the memory limit or the
+    /// allocator does not permit it.
+    fn back_frame(&mut self, gpa: usize, size: PageSize, flags: MappingFlags) -> AxResult<Frame> {
+        let bytes = size as usize;
+        if !self.charge(bytes) {
+            if bytes == PAGE_SIZE_4K {
+                self.limit_hit = true;
+            }
+            return Err(AxError::NoMemory);
+        }
+        let paddr = if size == PageSize::Size4K {
+            self.pool.take_zeroed()
+        } else {
+            alloc_frame(size)
+        };
+        let Some(paddr) = paddr else {
+            self.used -= bytes;
+            return Err(AxError::NoMemory);
+        };
+        if let Err(e) = self.pt.cursor().map(gpa.into(), paddr, size, flags) {
+            dealloc_frame(paddr, size);
+            self.used -= bytes;
+            return Err(paging_err(e));
+        }
+        Ok(Frame { gpa, paddr, size })
+    }
+
+    fn release_frames(&mut self, frames: &[Frame]) {
+        let mut cursor = self.pt.cursor();
+        for frame in frames {
+            let _ = cursor.unmap(frame.gpa.into());
+            dealloc_frame(frame.paddr, frame.size);
+            self.used -= frame.size as usize;
+        }
+    }
+
+    fn unmap_region(&mut self, start: usize, region: &Region) {
+        match &region.backing {
+            Backing::Linear => {
+                let _ = self.pt.cursor().unmap_region(start.into(), region.size);
+            }
+            Backing::Alloc { frames, .. } => self.release_frames(frames),
+            Backing::Cow { private, .. } => {
+                let _ = self.pt.cursor().unmap_region(start.into(), region.size);
+                for &paddr in private.values() {
+                    dealloc_frame(paddr, PageSize::Size4K);
+                }
+                self.used -= private.len() * PAGE_SIZE_4K;
+            }
+        }
+    }
+}
+
+impl Drop for GuestSpace {
+    fn drop(&mut self) {
+        // The page table frees its own tables and shared frames are freed
+        // with their last `Arc`; only private RAM frames are ours.
+        for region in self.regions.values() {
+            match &region.backing {
+                Backing::Alloc { frames, .. } => {
+                    for frame in frames {
+                        dealloc_frame(frame.paddr, frame.size);
+                    }
+                }
+                Backing::Cow { private, .. } => {
+                    for &paddr in private.values() {
+                        dealloc_frame(paddr, PageSize::Size4K);
+                    }
+                }
+                Backing::Linear => {}
+            }
+        }
+    }
+}
+
+/// A present stage-2 page table entry.
+#[derive(Clone, Copy, Debug)]
+pub struct Stage2Entry {
+    /// Level of the table holding the entry, 0 being the root.
+    pub level: usize,
+    /// Index of the entry in its table.
+    pub index: usize,
+    /// First guest physical address the entry covers.
+    pub gpa: usize,
+    /// Bytes of guest physical address space the entry covers.
+    pub span: usize,
+    /// The raw entry.
+    pub bits: usize,
+    /// Output address: the next table, or the frame of a leaf.
+    pub paddr: PhysAddr,
+    /// Flags of a leaf (meaningless for a table entry).
+    pub flags: MappingFlags,
+    /// Size of the page a leaf maps, `None` for a table entry.
+    pub page_size: Option<PageSize>,
+}
+
+/// The stage-2 walk of one guest physical address.
+#[derive(Clone, Debug)]
+pub struct Stage2Walk {
+    /// The translated guest physical address.
+    pub gpa: usize,
+    /// The present entries read, root first; the last one is the leaf if
+    /// the address is mapped.
+    pub entries: Vec<Stage2Entry>,
+    /// `(level, index)` of the entry found not present, which ended the
+    /// walk.
+    pub missing: Option<(usize, usize)>,
+}
+
+impl Stage2Walk {
+    /// Returns the host physical address `gpa` maps to, if it is mapped.
+    pub fn hpa(&self) -> Option<PhysAddr> {
+        let leaf = self.entries.last()?;
+        let size = leaf.page_size? as usize;
+        Some(leaf.paddr + (self.gpa & (size - 1)))
+    }
+}
+
+/// Shift of the guest physical address bits that index a table at `level`.
+fn level_shift<M: PagingMetaData>(level: usize) -> usize {
+    12 + 9 * (M::LEVELS - 1 - level)
+}
+
+/// Size of the page a leaf at `level` maps.
+fn level_page_size<M: PagingMetaData>(level: usize) -> PageSize {
+    match M::LEVELS - 1 - level {
+        0 => PageSize::Size4K,
+        1 => PageSize::Size2M,
+        _ => PageSize::Size1G,
+    }
+}
+
+fn table_entry<M: PagingMetaData, PTE: GenericPTE>(
+    level: usize,
+    index: usize,
+    gpa: usize,
+    pte: &PTE,
+) -> Stage2Entry {
+    let leaf = level == M::LEVELS - 1 || pte.is_huge();
+    Stage2Entry {
+        level,
+        index,
+        gpa,
+        span: 1 << level_shift::<M>(level),
+        bits: pte.bits(),
+        paddr: pte.paddr(),
+        flags: pte.flags(),
+        page_size: leaf.then(|| level_page_size::<M>(level)),
+    }
+}
+
+fn walk_table<M: PagingMetaData, PTE: GenericPTE, H: PagingHandler>(
+    pt: &PageTable64<M, PTE, H>,
+    gpa: usize,
+) -> Stage2Walk {
+    let mut walk = Stage2Walk {
+        gpa,
+        entries: Vec::new(),
+        missing: None,
+    };
+    let mut table = pt.root_paddr();
+    for level in 0..M::LEVELS {
+        let shift = level_shift::<M>(level);
+        let index = (gpa >> shift) & 0x1FF;
+        let pte = unsafe { *(H::phys_to_virt(table).as_ptr() as *const PTE).add(index) };
+        if !pte.is_present() {
+            walk.missing = Some((level, index));
+            break;
+        }
+        let entry = table_entry::<M, PTE>(level, index, gpa & !((1 << shift) - 1), &pte);
+        walk.entries.push(entry);
+        if entry.page_size.is_some() {
+            break;
+        }
+        table = entry.paddr;
+    }
+    walk
+}
+
+/// Finds the leaf entry mapping `gpa`: a pointer to the raw entry and the
+/// bytes it maps, or `Err` with the bytes the entry found not present
+/// covers.
+fn leaf_entry<M: PagingMetaData, PTE: GenericPTE, H: PagingHandler>(
+    pt: &PageTable64<M, PTE, H>,
+    gpa: usize,
+) -> Result<(*mut usize, usize), usize> {
+    let mut table = pt.root_paddr();
+    for level in 0..M::LEVELS {
+        let shift = level_shift::<M>(level);
+        let index = (gpa >> shift) & 0x1FF;
+        let entry = unsafe { (H::phys_to_virt(table).as_mut_ptr() as *mut PTE).add(index) };
+        let pte = unsafe { *entry };
+        if !pte.is_present() {
+            return Err(1 << shift);
+        }
+        if level == M::LEVELS - 1 || pte.is_huge() {
+            return Ok((entry as *mut usize, 1 << shift));
+        }
+        table = pte.paddr();
+    }
+    unreachable!()
+}
+
+fn for_each_table_entry<M: PagingMetaData, PTE: GenericPTE, H: PagingHandler>(
+    pt: &PageTable64<M, PTE, H>,
+    f: impl Fn(&Stage2Entry),
+) {
+    let visit = |level: usize, index: usize, gpa: M::VirtAddr, pte: &PTE| {
+        f(&table_entry::<M, PTE>(level, index, gpa.into(), pte));
+    };
+    pt.walk(usize::MAX, Some(&visit), None);
+}
+
+/// Makes instructions written to the frame at `paddr` visible to
+/// instruction fetch (AArch64 caches are not coherent for that).
+fn sync_icache(paddr: PhysAddr) {
+    #[cfg(target_arch = "aarch64")]
+    unsafe {
+        let va = phys_to_virt(paddr).as_usize();
+        for off in (0..PAGE_SIZE_4K).step_by(64) {
+            core::arch::asm!("dc cvau, {}", in(reg) (va + off));
+        }
+        core::arch::asm!("dsb ish", "ic iallu", "dsb ish", "isb");
+    }
+    #[cfg(not(target_arch = "aarch64"))]
+    let _ = paddr;
+}
+
+/// Allocates a zeroed host frame of the given size.
+pub fn alloc_frame(size: PageSize) -> Option<PhysAddr> {
+    let bytes = size as usize;
+    let vaddr = global_allocator()
+        .alloc_pages(bytes / PAGE_SIZE_4K, bytes, UsageKind::VirtMem)
+        .ok()?;
+    unsafe { core::ptr::write_bytes(vaddr as *mut u8, 0, bytes) };
+    Some(virt_to_phys(vaddr.into()))
+}
+
+/// Frees a frame returned by [`alloc_frame`].
+pub fn dealloc_frame(paddr: PhysAddr, size: PageSize) {
+    let vaddr = phys_to_virt(paddr);
+    global_allocator().dealloc_pages(
+        vaddr.as_usize(),
+        size as usize / PAGE_SIZE_4K,
+        UsageKind::VirtMem,
+    );
+}
+
+fn paging_err(e: PagingError) -> AxError {
+    match e {
+        PagingError::NoMemory => AxError::NoMemory,
+        PagingError::NotMapped => AxError::BadAddress,
+        PagingError::AlreadyMapped => AxError::AlreadyExists,
+        PagingError::NotAligned | PagingError::MappedToHugePage => AxError::InvalidInput,
+    }
+}
@@ -0,0 +1,60 @@
+//! Flat guest RAM for host unit tests.
+//!
+//! Stands in for the stage-2 [`GuestSpace`] of `gspace.rs` when the crate is
+//! built for the host without axstd: one contiguous buffer at a guest
+//! physical base, with the same [`GuestSpace::read`] / [`GuestSpace::write`]
+//! contract, so the device models and virtqueues under test reach guest
+//! memory through [`crate::gmem::GuestMemory`] as they do on a target.
+
+#![allow(dead_code)]
+
+use alloc::vec;
+use alloc::vec::Vec;
+
+use axerrno::{AxError, AxResult};
+use memory_addr::VirtAddr;
+
+/// Guest RAM of `ram.len()` bytes at guest physical address `base`.
+pub struct GuestSpace {
+    base: usize,
+    ram: Vec<u8>,
+}
+
+impl GuestSpace {
+    /// Creates `size` bytes of zeroed guest RAM at `base`.
+    pub fn new(base: VirtAddr, size: usize) -> AxResult<Self> {
+        base.as_usize()
+            .checked_add(size)
+            .ok_or(AxError::InvalidInput)?;
+        Ok(Self {
+            base: base.as_usize(),
+            ram: vec![0; size],
+        })
+    }
+
+    /// Returns the offset in `ram` of `size` bytes at `start`.
+    fn offset(&self, start: VirtAddr, size: usize) -> AxResult<usize> {
+        let offset = start
+            .as_usize()
+            .checked_sub(self.base)
+            .ok_or(AxError::InvalidInput)?;
+        match offset.checked_add(size) {
+            Some(end) if end <= self.ram.len() => Ok(offset),
+            _ => Err(AxError::InvalidInput),
+        }
+    }
+
+    /// Reads guest memory.
+    pub fn read(&self, start: VirtAddr, buf: &mut [u8]) -> AxResult {
+        let offset = self.offset(start, buf.len())?;
+        buf.copy_from_slice(&self.ram[offset..offset + buf.len()]);
+        Ok(())
+    }
+
+    /// Writes guest memory.
+    pub fn write(&mut self, start: VirtAddr, buf: &[u8]) -> AxResult {
+        let offset = self.offset(start, buf.len())?;
+        self.ram[offset..offset + buf.len()].copy_from_slice(buf);
+        Ok(())
+    }
+}
@@ -0,0 +1,217 @@
+//! Translation of guest virtual addresses, for device models and debugging.
+//!
+//! [`gva_to_gpa`] resolves a guest virtual address to the guest physical
+//! address the guest's own translation gives it, checked for an access of
+//! the given kind, and [`gva_to_hpa`] follows it through the VM's stage-2
+//! table to the host frame. The guest's translation is resolved with the
+//! help of the hardware:
+//!
+//! - riscv64: an HLV (HLVX for an execute access) probe at the address
+//!   ([`crate::hlv`]) lets the hart check the guest's permissions with its
+//!   own rules (U, SUM, MXR). A G-stage fault of the probe carries the
+//!   guest physical address in `htval`; after a successful one the guest's
+//!   page table at `vsatp` is walked for it. Writes are checked against the
+//!   leaf's W bit, without writing. The vCPU's CSRs and the VM's `hgatp`
+//!   must be loaded on the hart, as right after an exit.
+//! - aarch64: the guest runs at EL0 on the VM's table, so its virtual
+//!   addresses are its physical addresses, and the VM's table is the
+//!   second stage: `AT S1E0R`/`AT S1E0W` walks it and checks the access at
+//!   EL0 (execute permission is not checked). The VM's table must be
+//!   installed in `TTBR0_EL1`.
+//! - x86_64: the guest's 4-level page table is walked in software
+//!   ([`crate::x86_64_svm::insn::guest_translate`]), which only checks that
+//!   the address is present.
+
+#![allow(dead_code)]
+
+use axhal::paging::MappingFlags;
+use memory_addr::{PhysAddr, VirtAddr};
+
+use crate::gspace::GuestSpace;
+
+/// The kind of access a translation is checked for.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Access {
+    Read,
+    Write,
+    Execute,
+}
+
+impl Access {
+    fn flag(self) -> MappingFlags {
+        match self {
+            Self::Read => MappingFlags::READ,
+            Self::Write => MappingFlags::WRITE,
+            Self::Execute => MappingFlags::EXECUTE,
+        }
+    }
+}
+
+/// The guest's translation state, from the vCPU as of its last exit.
+#[cfg(target_arch = "riscv64")]
+#[derive(Clone, Copy, Debug)]
+pub struct GuestPaging {
+    pub vsatp: usize,
+    /// `sstatus` of the exit; `SPP` is the privilege the guest trapped from.
+    pub sstatus: usize,
+}
+
+/// The guest's translation state: none, the guest has no translation of
+/// its own.
+#[cfg(target_arch = "aarch64")]
+#[derive(Clone, Copy, Debug)]
+pub struct GuestPaging;
+
+/// The guest's translation state, from the vCPU as of its last exit.
+#[cfg(target_arch = "x86_64")]
+#[derive(Clone, Copy, Debug)]
+pub struct GuestPaging {
+    pub cr0: u64,
+    /// The guest's own CR3, not a shadow root.
+    pub cr3: u64,
+    pub cr4: u64,
+}
+
+/// Returns the guest physical address `gva` translates to for `access`, or
+/// `None` if the guest's translation does not allow it.
+pub fn gva_to_gpa(
+    space: &GuestSpace,
+    paging: &GuestPaging,
+    gva: usize,
+    access: Access,
+) -> Option<usize> {
+    translate(space, paging, gva, access)
+}
+
+/// Returns the host physical address `gva` translates to for `access`
+/// through both stages, or `None` if either does not allow it. A page not
+/// faulted in yet, a device page or a copy-on-write page for a write gives
+/// `None` although the guest's access would succeed.
+pub fn gva_to_hpa(
+    space: &GuestSpace,
+    paging: &GuestPaging,
+    gva: usize,
+    access: Access,
+) -> Option<PhysAddr> {
+    let gpa = translate(space, paging, gva, access)?;
+    stage2(space, gpa, access)
+}
+
+/// Translates `gpa` through the VM's stage-2 table for `access`.
+#[cfg(any(target_arch = "riscv64", target_arch = "x86_64"))]
+fn stage2(space: &GuestSpace, gpa: usize, access: Access) -> Option<PhysAddr> {
+    let (hpa, flags, _) = space.query(VirtAddr::from(gpa)).ok()?;
+    flags.contains(access.flag()).then_some(hpa)
+}
+
+/// Translates `gpa` through the VM's table with `AT`, as an EL0 access.
+#[cfg(target_arch = "aarch64")]
+fn stage2(_space: &GuestSpace, gpa: usize, access: Access) -> Option<PhysAddr> {
+    /// PAR_EL1.F: the translation aborted.
+    const PAR_F: u64 = 1 << 0;
+    /// PAR_EL1.PA, bits 47:12.
+    const PAR_PA: u64 = ((1 << 48) - 1) & !0xFFF;
+
+    let par: u64;
+    unsafe {
+        // AT has no execute check: an instruction fetch is checked as a read.
+        match access {
+            Access::Write => core::arch::asm!("at s1e0w, {}", in(reg) gpa),
+            Access::Read | Access::Execute => core::arch::asm!("at s1e0r, {}", in(reg) gpa),
+        }
+        core::arch::asm!("isb", "mrs {}, par_el1", out(reg) par);
+    }
+    if par & PAR_F != 0 {
+        return None;
+    }
+    Some(PhysAddr::from((par & PAR_PA) as usize | (gpa & 0xFFF)))
+}
+
+#[cfg(target_arch = "riscv64")]
+fn translate(
+    space: &GuestSpace,
+    paging: &GuestPaging,
+    gva: usize,
+    access: Access,
+) -> Option<usize> {
+    use crate::hlv::{self, GuestPriv};
+
+    /// `scause` of a load guest-page fault (G-stage).
+    const LOAD_GUEST_PAGE_FAULT: usize = 21;
+
+    if paging.vsatp >> 60 == 0 {
+        return Some(gva);
+    }
+    let mode = GuestPriv::from_sstatus(paging.sstatus);
+    let probe = match access {
+        Access::Execute => hlv::fetch_u16(gva & !1, mode).map(drop),
+        Access::Read | Access::Write => hlv::read_u8(gva, mode).map(drop),
+    };
+    match probe {
+        Err(fault) if fault.scause != LOAD_GUEST_PAGE_FAULT => return None,
+        // The VS-stage translated the address and only the G-stage failed;
+        // a write still needs the leaf's W bit from the walk.
+        Err(fault) if access != Access::Write => return Some((fault.htval << 2) | (gva & 3)),
+        _ => {}
+    }
+    walk_vs_stage(space, paging.vsatp, gva, access == Access::Write)
+}
+
+/// Walks the guest's Sv39/Sv48/Sv57 table at `vsatp` for `gva`, requiring
+/// a writable leaf if `write` is set.
+#[cfg(target_arch = "riscv64")]
+fn walk_vs_stage(space: &GuestSpace, vsatp: usize, gva: usize, write: bool) -> Option<usize> {
+    use crate::gmem::GuestMemory;
+
+    const PTE_V: u64 = 1 << 0;
+    const PTE_R: u64 = 1 << 1;
+    const PTE_W: u64 = 1 << 2;
+    const PTE_X: u64 = 1 << 3;
+    const PTE_PPN_MASK: u64 = ((1 << 44) - 1) << 10;
+
+    let levels = match vsatp >> 60 {
+        8 => 3,
+        9 => 4,
+        10 => 5,
+        _ => return None,
+    };
+    let mut table = (vsatp & ((1 << 44) - 1)) << 12;
+    for level in (0..levels).rev() {
+        let shift = 12 + 9 * level;
+        let index = (gva >> shift) & 0x1FF;
+        let pte: u64 = space.read_obj(table + index * 8).ok()?;
+        if pte & PTE_V == 0 {
+            return None;
+        }
+        let base = ((pte & PTE_PPN_MASK) << 2) as usize;
+        if pte & (PTE_R | PTE_X) != 0 {
+            if write && pte & PTE_W == 0 {
+                return None;
+            }
+            // A superpage maps the low bits of the address unchanged.
+            return Some((base & !((1 << shift) - 1)) | (gva & ((1 << shift) - 1)));
+        }
+        table = base;
+    }
+    None
+}
+
+#[cfg(target_arch = "aarch64")]
+fn translate(
+    space: &GuestSpace,
+    _paging: &GuestPaging,
+    gva: usize,
+    _access: Access,
+) -> Option<usize> {
+    space.contains_range(VirtAddr::from(gva), 1).then_some(gva)
+}
+
+#[cfg(target_arch = "x86_64")]
+fn translate(
+    space: &GuestSpace,
+    paging: &GuestPaging,
+    gva: usize,
+    _access: Access,
+) -> Option<usize> {
+    crate::x86_64_svm::insn::guest_translate(space, paging.cr0, paging.cr4, paging.cr3, gva as u64)
+}
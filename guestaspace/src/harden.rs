@@ -0,0 +1,99 @@
+//! Hardened VMs (`harden=on` in `vms.conf`).
+//!
+//! The guest address space of a hardened VM is W^X (see
+//! [`GuestSpace::set_hardened`]): only its image, while still shared
+//! copy-on-write, and the read-only code segments of a packaged image are
+//! executable, and every page the guest can write is not. Such a VM can
+//! only run code from its image, which suits the payloads but not kernels
+//! that load or decompress themselves.
+//!
+//! The hypervisor never reaches guest memory through the guest's own
+//! mappings: device models, hypercalls and the loaders go through
+//! [`GuestSpace::read`] / [`GuestSpace::write`] and the host linear map,
+//! which maps RAM non-executable. The guest tables hold guest mappings
+//! only (the host kernel lives in the host's own tables, TTBR1 on
+//! aarch64), and the aarch64 guest pages, mapped for EL0, are PXN. So the
+//! host can forbid itself any access to user mappings, which
+//! [`protect_host`] does once a hardened VM is configured: SMEP and SMAP on
+//! x86_64, PAN on aarch64 (ARMv8.1), `sstatus.SUM` cleared on riscv64.
+//!
+//! [`GuestSpace::set_hardened`]: crate::gspace::GuestSpace::set_hardened
+//! [`GuestSpace::read`]: crate::gspace::GuestSpace::read
+//! [`GuestSpace::write`]: crate::gspace::GuestSpace::write
+
+#![allow(dead_code)]
+
+/// Stops the host from executing and accessing user (guest) mappings where
+/// the CPU supports it, and reports what was enabled.
+pub fn protect_host() {
+    let enabled = arch_protect_host();
+    ax_println!("Host protections for hardened VMs: {}", enabled);
+}
+
+#[cfg(target_arch = "x86_64")]
+fn arch_protect_host() -> &'static str {
+    const CPUID_EXT_FEATURES: u32 = 7;
+    const SMEP: u32 = 1 << 7;
+    const SMAP: u32 = 1 << 20;
+    const CR4_SMEP: u64 = 1 << 20;
+    const CR4_SMAP: u64 = 1 << 21;
+
+    let ebx = core::arch::x86_64::__cpuid_count(CPUID_EXT_FEATURES, 0).ebx;
+    let mut bits = 0;
+    if ebx & SMEP != 0 {
+        bits |= CR4_SMEP;
+    }
+    if ebx & SMAP != 0 {
+        bits |= CR4_SMAP;
+    }
+    unsafe {
+        core::arch::asm!(
+            "mov {tmp}, cr4",
+            "or {tmp}, {bits}",
+            "mov cr4, {tmp}",
+            tmp = out(reg) _,
+            bits = in(reg) bits,
+        );
+    }
+    match (bits & CR4_SMEP != 0, bits & CR4_SMAP != 0) {
+        (true, true) => "SMEP, SMAP",
+        (true, false) => "SMEP",
+        (false, true) => "SMAP",
+        (false, false) => "none (no SMEP/SMAP)",
+    }
+}
+
+#[cfg(target_arch = "aarch64")]
+fn arch_protect_host() -> &'static str {
+    const SCTLR_SPAN: u64 = 1 << 23;
+    let mmfr1: u64;
+    unsafe {
+        core::arch::asm!("mrs {}, id_aa64mmfr1_el1", out(reg) mmfr1);
+    }
+    if (mmfr1 >> 20) & 0xF == 0 {
+        return "none (no PAN)";
+    }
+    unsafe {
+        // SPAN clear: PAN is set again on every exception to EL1.
+        // `msr pan, #1` is encoded by hand for ARMv8.0 assemblers.
+        core::arch::asm!(
+            "mrs {tmp}, sctlr_el1",
+            "bic {tmp}, {tmp}, {span}",
+            "msr sctlr_el1, {tmp}",
+            ".inst 0xd500419f",
+            "isb",
+            tmp = out(reg) _,
+            span = in(reg) SCTLR_SPAN,
+        );
+    }
+    "PAN"
+}
+
+#[cfg(target_arch = "riscv64")]
+fn arch_protect_host() -> &'static str {
+    const SSTATUS_SUM: usize = 1 << 18;
+    unsafe {
+        core::arch::asm!("csrc sstatus, {}", in(reg) SSTATUS_SUM);
+    }
+    "sstatus.SUM clear"
+}
@@ -0,0 +1,226 @@
+//! Guest memory accesses through the guest's own translation (riscv64).
+//!
+//! The hypervisor-extension loads and stores HLV, HLVX and HSV access memory
+//! as the guest would, at the privilege in `hstatus.SPVP`: through the
+//! VS-stage translation of `vsatp` and the G-stage one of `hgatp`. With
+//! them the hypervisor reads an instruction at a guest PC, an SBI string
+//! argument or any other guest virtual address while the guest runs with
+//! paging. HLVX reads with execute permission, as an instruction fetch
+//! would, so it also reads execute-only pages.
+//!
+//! A failing access (a page fault of either stage or an access fault) does
+//! not trap into the host: while the instruction runs, interrupts are off
+//! and `stvec` points to a handler that skips it and records the trap,
+//! which the accessor returns as a [`GuestFault`].
+//!
+//! The VM's `hgatp` and the vCPU's VS-level CSRs must be loaded on the hart,
+//! as they are right after the vCPU's exit.
+
+#![allow(dead_code)]
+
+use core::arch::{asm, global_asm};
+
+/// The trap a guest memory access raised.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct GuestFault {
+    /// `scause`: 13/15 load/store page fault (VS-stage), 21/23 load/store
+    /// guest page fault (G-stage), 5/7 access fault, 4/6 misaligned.
+    pub scause: usize,
+    /// `stval`: the faulting guest virtual address.
+    pub stval: usize,
+    /// `htval`: the faulting guest physical address shifted right by 2,
+    /// for a guest page fault.
+    pub htval: usize,
+}
+
+/// The privilege the guest accesses memory with.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum GuestPriv {
+    /// VU-mode: only user pages.
+    User,
+    /// VS-mode: supervisor pages, and user pages if `vsstatus.SUM` is set.
+    Supervisor,
+}
+
+impl GuestPriv {
+    /// Returns the privilege the guest had when it trapped, from the
+    /// `sstatus.SPP` it exited with.
+    pub fn from_sstatus(sstatus: usize) -> Self {
+        const SSTATUS_SPP: usize = 1 << 8;
+        if sstatus & SSTATUS_SPP != 0 {
+            Self::Supervisor
+        } else {
+            Self::User
+        }
+    }
+
+    fn spvp(self) -> usize {
+        const HSTATUS_SPVP: usize = 1 << 8;
+        match self {
+            Self::User => 0,
+            Self::Supervisor => HSTATUS_SPVP,
+        }
+    }
+}
+
+// Entered instead of the host trap handler when a guest access faults: skips
+// the 4-byte instruction and returns the cause in t6 (a fault never has
+// cause 0, an instruction address misalignment).
+global_asm!(
+    ".section .text",
+    ".balign 4",
+    ".global __hlv_trap",
+    "__hlv_trap:",
+    "csrr t5, sepc",
+    "addi t5, t5, 4",
+    "csrw sepc, t5",
+    "csrr t6, scause",
+    "sret",
+);
+
+/// Runs the guest access `$insn` at `{gva}` with `hstatus.SPVP` set for
+/// `$priv`; `$value` is the `{value}` operand, an input for a store and an
+/// output for a load.
+macro_rules! guest_access {
+    ($insn:expr, $priv:expr, $gva:expr, $($value:tt)+) => {{
+        let (cause, stval, htval): (usize, usize, usize);
+        unsafe {
+            asm!(
+                "csrrci {sstatus}, sstatus, 2",
+                "csrrw {stvec}, stvec, {handler}",
+                "csrr {hstatus}, hstatus",
+                "csrc hstatus, {spvp_mask}",
+                "csrs hstatus, {spvp}",
+                $insn,
+                "csrw hstatus, {hstatus}",
+                "csrw stvec, {stvec}",
+                "csrr {stval}, stval",
+                "csrr {htval}, htval",
+                "csrw sstatus, {sstatus}",
+                sstatus = out(reg) _,
+                stvec = out(reg) _,
+                hstatus = out(reg) _,
+                handler = in(reg) hlv_trap_addr(),
+                spvp_mask = in(reg) GuestPriv::Supervisor.spvp(),
+                spvp = in(reg) $priv.spvp(),
+                gva = in(reg) $gva,
+                $($value)+,
+                stval = out(reg) stval,
+                htval = out(reg) htval,
+                inout("t6") 0usize => cause,
+                out("t5") _,
+                options(nostack),
+            );
+        }
+        if cause != 0 {
+            return Err(GuestFault {
+                scause: cause,
+                stval,
+                htval,
+            });
+        }
+    }};
+}
+
+fn hlv_trap_addr() -> usize {
+    unsafe extern "C" {
+        fn __hlv_trap();
+    }
+    __hlv_trap as *const () as usize
+}
+
+/// Defines a load accessor: `$imm` selects HLV.B/BU/H/HU/W/WU/D or
+/// HLVX.HU/WU in the immediate field of the SYSTEM opcode.
+macro_rules! guest_load {
+    ($(#[$doc:meta])* $name:ident, $ty:ty, $imm:literal) => {
+        $(#[$doc])*
+        pub fn $name(gva: usize, mode: GuestPriv) -> Result<$ty, GuestFault> {
+            let value: usize;
+            guest_access!(
+                concat!(".insn i 0x73, 0x4, {value}, {gva}, ", $imm),
+                mode,
+                gva,
+                value = out(reg) value
+            );
+            Ok(value as $ty)
+        }
+    };
+}
+
+/// Defines a store accessor: `$funct7` selects HSV.B/H/W/D.
+macro_rules! guest_store {
+    ($(#[$doc:meta])* $name:ident, $ty:ty, $funct7:literal) => {
+        $(#[$doc])*
+        pub fn $name(gva: usize, value: $ty, mode: GuestPriv) -> Result<(), GuestFault> {
+            guest_access!(
+                concat!(".insn r 0x73, 0x4, ", $funct7, ", x0, {gva}, {value}"),
+                mode,
+                gva,
+                value = in(reg) value as usize
+            );
+            Ok(())
+        }
+    };
+}
+
+guest_load!(
+    /// Reads a byte (HLV.BU).
+    read_u8, u8, "0x601"
+);
+guest_load!(
+    /// Reads a halfword (HLV.HU).
+    read_u16, u16, "0x641"
+);
+guest_load!(
+    /// Reads a word (HLV.WU).
+    read_u32, u32, "0x681"
+);
+guest_load!(
+    /// Reads a doubleword (HLV.D).
+    read_u64, u64, "0x6C0"
+);
+guest_load!(
+    /// Reads a halfword with execute permission (HLVX.HU).
+    fetch_u16, u16, "0x643"
+);
+guest_load!(
+    /// Reads a word with execute permission (HLVX.WU).
+    fetch_u32, u32, "0x683"
+);
+guest_store!(
+    /// Writes a byte (HSV.B).
+    write_u8, u8, "0x31"
+);
+guest_store!(
+    /// Writes a halfword (HSV.H).
+    write_u16, u16, "0x33"
+);
+guest_store!(
+    /// Writes a word (HSV.W).
+    write_u32, u32, "0x35"
+);
+guest_store!(
+    /// Writes a doubleword (HSV.D).
+    write_u64, u64, "0x37"
+);
+
+/// Fetches the instruction at the guest virtual address `pc`: 16 bits for
+/// a compressed one, halfword by halfword, as an instruction may cross a
+/// page boundary.
+pub fn fetch_insn(pc: usize, mode: GuestPriv) -> Result<u32, GuestFault> {
+    let low = fetch_u16(pc, mode)? as u32;
+    if low & 3 != 3 {
+        return Ok(low);
+    }
+    let high = fetch_u16(pc + 2, mode)? as u32;
+    Ok((high << 16) | low)
+}
+
+/// Reads `buf.len()` bytes from the guest virtual address `gva`, a byte at
+/// a time.
+pub fn read_bytes(gva: usize, buf: &mut [u8], mode: GuestPriv) -> Result<(), GuestFault> {
+    for (i, byte) in buf.iter_mut().enumerate() {
+        *byte = read_u8(gva + i, mode)?;
+    }
+    Ok(())
+}
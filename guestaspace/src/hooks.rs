@@ -0,0 +1,194 @@
+//! Observers of the lifecycle of VMs.
+//!
+//! Code built on the hypervisor extends it without touching the run loops:
+//! it implements [`VmObserver`] and [`register`]s it before the VMs start.
+//! Every [`Vm`](crate::vm::Vm) created afterwards takes the registered
+//! observers into its [`VmHooks`], and its run loop calls them when the VM
+//! boots, at every exit (before the exit is handled), for the nested page
+//! faults and hypercalls among those exits, for every working set sample
+//! (see the `wss` module), and when the guest resets.
+//!
+//! Observers only watch: they get the VM id and what happened, not the
+//! vCPU, and cannot change how an exit is handled.
+
+#![allow(dead_code)]
+
+use alloc::vec::Vec;
+
+use crate::dispatch::ExitClass;
+use crate::wss::WssSample;
+
+/// Callbacks on the events of a VM, all doing nothing by default. A VM
+/// runs in its own task, so the callbacks of different VMs may run
+/// concurrently.
+pub trait VmObserver: Sync {
+    /// VM `vm` is built and about to enter the guest for the first time.
+    fn on_boot(&self, vm: usize) {
+        let _ = vm;
+    }
+
+    /// VM `vm` exited for a reason of `class`.
+    fn on_exit(&self, vm: usize, class: ExitClass) {
+        let _ = (vm, class);
+    }
+
+    /// The exit of VM `vm` was a stage-2 (nested) page fault on RAM or
+    /// passthrough memory at guest physical address `gpa`.
+    fn on_npf(&self, vm: usize, gpa: usize) {
+        let _ = (vm, gpa);
+    }
+
+    /// The exit of VM `vm` was a hypercall with function number `func`: the
+    /// SBI extension ID (`a7`) on riscv64, `x8` of the SVC on aarch64,
+    /// `RAX` of the VMMCALL on x86_64.
+    fn on_hypercall(&self, vm: usize, func: usize) {
+        let _ = (vm, func);
+    }
+
+    /// The working set of VM `vm` was sampled.
+    fn on_working_set(&self, vm: usize, sample: WssSample) {
+        let _ = (vm, sample);
+    }
+
+    /// The guest of VM `vm` reset itself (or its watchdog reset it); the
+    /// VM is torn down and booted again.
+    fn on_reset(&self, vm: usize) {
+        let _ = vm;
+    }
+}
+
+/// The registered observers.
+#[cfg(feature = "axstd")]
+static OBSERVERS: axstd::sync::Mutex<Vec<&'static dyn VmObserver>> =
+    axstd::sync::Mutex::new(Vec::new());
+
+/// Registers `observer` for the VMs created from now on.
+#[cfg(feature = "axstd")]
+pub fn register(observer: &'static dyn VmObserver) {
+    OBSERVERS.lock().push(observer);
+}
+
+/// The observers of one VM, fixed when the VM is created.
+#[derive(Clone, Default)]
+pub struct VmHooks {
+    vm: usize,
+    observers: Vec<&'static dyn VmObserver>,
+}
+
+impl VmHooks {
+    /// Returns the hooks of VM `vm`: the observers registered so far.
+    #[cfg(feature = "axstd")]
+    pub fn new(vm: usize) -> Self {
+        Self::with_observers(vm, OBSERVERS.lock().clone())
+    }
+
+    /// Returns the hooks of VM `vm` calling `observers`.
+    pub fn with_observers(vm: usize, observers: Vec<&'static dyn VmObserver>) -> Self {
+        Self { vm, observers }
+    }
+
+    /// Tells the observers that the VM boots.
+    pub fn boot(&self) {
+        self.observers.iter().for_each(|o| o.on_boot(self.vm));
+    }
+
+    /// Tells the observers about an exit of `class`.
+    pub fn exit(&self, class: ExitClass) {
+        self.observers
+            .iter()
+            .for_each(|o| o.on_exit(self.vm, class));
+    }
+
+    /// Tells the observers about a nested page fault at `gpa`.
+    pub fn npf(&self, gpa: usize) {
+        self.observers.iter().for_each(|o| o.on_npf(self.vm, gpa));
+    }
+
+    /// Tells the observers about a hypercall of function `func`.
+    pub fn hypercall(&self, func: usize) {
+        self.observers
+            .iter()
+            .for_each(|o| o.on_hypercall(self.vm, func));
+    }
+
+    /// Tells the observers about a working set sample.
+    pub fn working_set(&self, sample: WssSample) {
+        self.observers
+            .iter()
+            .for_each(|o| o.on_working_set(self.vm, sample));
+    }
+
+    /// Tells the observers that the guest reset.
+    pub fn reset(&self) {
+        self.observers.iter().for_each(|o| o.on_reset(self.vm));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::sync::atomic::{AtomicUsize, Ordering};
+
+    /// Counts the events it sees.
+    struct Counter {
+        boots: AtomicUsize,
+        npfs: AtomicUsize,
+        last_gpa: AtomicUsize,
+        working_set: AtomicUsize,
+    }
+
+    impl VmObserver for Counter {
+        fn on_boot(&self, _vm: usize) {
+            self.boots.fetch_add(1, Ordering::Relaxed);
+        }
+
+        fn on_npf(&self, vm: usize, gpa: usize) {
+            assert_eq!(vm, 3);
+            self.npfs.fetch_add(1, Ordering::Relaxed);
+            self.last_gpa.store(gpa, Ordering::Relaxed);
+        }
+
+        fn on_working_set(&self, _vm: usize, sample: WssSample) {
+            self.working_set.store(sample.estimate, Ordering::Relaxed);
+        }
+    }
+
+    /// Implements no callback at all.
+    struct Silent;
+
+    impl VmObserver for Silent {}
+
+    #[test]
+    fn hooks_reach_every_observer() {
+        static COUNTER: Counter = Counter {
+            boots: AtomicUsize::new(0),
+            npfs: AtomicUsize::new(0),
+            last_gpa: AtomicUsize::new(0),
+            working_set: AtomicUsize::new(0),
+        };
+        static SILENT: Silent = Silent;
+        let hooks = VmHooks::with_observers(3, alloc::vec![&COUNTER, &SILENT, &COUNTER]);
+
+        hooks.boot();
+        hooks.exit(ExitClass::Npf);
+        hooks.npf(0x8000_1000);
+        hooks.hypercall(1);
+        hooks.working_set(WssSample {
+            accessed: 0x10_0000,
+            estimate: 0x20_0000,
+            backed: 0x40_0000,
+        });
+        hooks.reset();
+        assert_eq!(COUNTER.boots.load(Ordering::Relaxed), 2);
+        assert_eq!(COUNTER.npfs.load(Ordering::Relaxed), 2);
+        assert_eq!(COUNTER.last_gpa.load(Ordering::Relaxed), 0x8000_1000);
+        assert_eq!(COUNTER.working_set.load(Ordering::Relaxed), 0x20_0000);
+    }
+
+    #[test]
+    fn no_observers_is_fine() {
+        let hooks = VmHooks::default();
+        hooks.boot();
+        hooks.exit(ExitClass::Other);
+    }
+}
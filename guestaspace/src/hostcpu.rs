@@ -0,0 +1,39 @@
+//! Host CPUs of the VM tasks.
+//!
+//! A VM runs in one task, whose vCPUs take turns in it, and the task stays
+//! on one host CPU for its whole life. The hart-local state a VM sets up is
+//! then the state of the CPU it runs on: trap delegation and the loaded
+//! vCPU on riscv64, the EL1 trap controls on aarch64. `pin=N` in a VM's
+//! `vms.conf` line picks the CPU; otherwise the task is pinned where the
+//! scheduler spawned it. On x86_64, SVM is enabled on each CPU a VM runs on
+//! (`x86_64_svm::svmcpu`).
+
+/// Most host CPUs a VM can be pinned to.
+pub const MAX_HOST_CPUS: usize = usize::BITS as usize;
+
+/// Pins the calling task of VM `vm` to host CPU `pin`, or to the CPU it
+/// runs on without one, and returns the CPU. A CPU that does not exist is
+/// reported and replaced by the current one.
+pub fn pin_vm_task(vm: usize, pin: Option<usize>) -> usize {
+    let num_cpus = axhal::cpu_num().min(MAX_HOST_CPUS);
+    let current = axhal::percpu::this_cpu_id();
+    let cpu = match pin {
+        Some(cpu) if cpu < num_cpus => cpu,
+        Some(cpu) => {
+            vm_println!(
+                vm,
+                "Cannot pin to host CPU {} ({} CPUs), using CPU {}",
+                cpu,
+                num_cpus,
+                current
+            );
+            current
+        }
+        None => current,
+    };
+    // Moves the task if it runs elsewhere.
+    if !axtask::set_current_affinity(axtask::AxCpuMask::one_shot(cpu)) {
+        vm_println!(vm, "Cannot pin to host CPU {}", cpu);
+    }
+    cpu
+}
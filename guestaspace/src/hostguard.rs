@@ -0,0 +1,124 @@
+//! Putting the host's virtualization state back when the hypervisor stops.
+//!
+//! While a VM runs, this CPU holds guest configuration: the VM's G-stage
+//! table in `hgatp` (riscv64), its table in `TTBR0_EL1` (aarch64), SVM
+//! enabled in `EFER` (x86_64), and guest translations in the TLB. A VM puts
+//! the host's values back when it is torn down ([`crate::vm`]), but not
+//! when the hypervisor stops in the middle of a run.
+//!
+//! [`restore_host`] forces the host state back from any point: it installs
+//! the host's translation roots, disables SVM and flushes the TLB. The
+//! [`HostGuard`] of the hypervisor's main function calls it when dropped,
+//! and [`host_panic!`] calls it before panicking, so the hypervisor's own
+//! panics, including the one that powers riscv64 and x86_64 off at the end,
+//! print on a CPU in host configuration.
+//!
+//! The panic handler belongs to the ArceOS runtime, which has no hook, and
+//! panics abort without unwinding: a panic raised elsewhere (an `expect`, a
+//! failed assertion in a dependency) prints its message and powers off
+//! before anything of the hypervisor can run, and no `Drop` runs after it.
+
+#![allow(dead_code)]
+
+#[cfg(target_arch = "aarch64")]
+use core::sync::atomic::{AtomicU64, Ordering};
+
+/// Restores the host's virtualization state, then panics with the message.
+// Unused on aarch64, which powers off without a panic.
+#[allow(unused_macros)]
+macro_rules! host_panic {
+    ($($arg:tt)*) => {{
+        $crate::hostguard::restore_host();
+        panic!($($arg)*)
+    }};
+}
+
+/// The host's `TTBR0_EL1`, saved by [`HostGuard::new`].
+#[cfg(target_arch = "aarch64")]
+static HOST_TTBR0: AtomicU64 = AtomicU64::new(0);
+
+/// Restores the host's virtualization state when dropped.
+pub struct HostGuard(());
+
+impl HostGuard {
+    /// Saves the host state that [`restore_host`] puts back. Created before
+    /// the first VM is built.
+    pub fn new() -> Self {
+        #[cfg(target_arch = "aarch64")]
+        {
+            let ttbr0: u64;
+            unsafe {
+                core::arch::asm!("mrs {}, ttbr0_el1", out(reg) ttbr0);
+            }
+            HOST_TTBR0.store(ttbr0, Ordering::Relaxed);
+        }
+        Self(())
+    }
+}
+
+impl Drop for HostGuard {
+    fn drop(&mut self) {
+        restore_host();
+    }
+}
+
+/// Takes the CPU out of guest configuration: no VM's table stays installed,
+/// SVM is off and no guest translation is cached. Idempotent, and safe at
+/// any point after [`HostGuard::new`]; no VM can run afterwards.
+pub fn restore_host() {
+    let irqs_were_enabled = axhal::asm::irqs_enabled();
+    axhal::asm::disable_irqs();
+    arch_restore_host();
+    if irqs_were_enabled {
+        axhal::asm::enable_irqs();
+    }
+}
+
+/// `hgatp` Bare, then every G-stage and VS-stage translation flushed.
+#[cfg(target_arch = "riscv64")]
+fn arch_restore_host() {
+    unsafe {
+        core::arch::asm!("csrw hgatp, zero");
+        core::arch::riscv64::hfence_gvma_all();
+        core::arch::riscv64::hfence_vvma_all();
+    }
+}
+
+/// The host's `TTBR0_EL1`, then the EL1&0 TLB flushed.
+#[cfg(target_arch = "aarch64")]
+fn arch_restore_host() {
+    let ttbr0 = HOST_TTBR0.load(Ordering::Relaxed);
+    unsafe {
+        core::arch::asm!(
+            "msr ttbr0_el1, {}",
+            "isb",
+            "tlbi vmalle1is",
+            "dsb ish",
+            "isb",
+            in(reg) ttbr0,
+        );
+    }
+}
+
+/// GIF set and `EFER.SVME` cleared, then the non-global TLB entries
+/// flushed. The nested tables are only referenced by VMCBs, which no VMRUN
+/// loads any more.
+#[cfg(target_arch = "x86_64")]
+fn arch_restore_host() {
+    use crate::x86_64_svm::svm::{EFER_SVME, MSR_EFER, rdmsr, wrmsr};
+
+    unsafe {
+        let efer = rdmsr(MSR_EFER);
+        if efer & EFER_SVME != 0 {
+            // A stop between CLGI and STGI leaves interrupts held; STGI
+            // needs SVM enabled.
+            core::arch::asm!("stgi");
+            wrmsr(MSR_EFER, efer & !EFER_SVME);
+        }
+        core::arch::asm!(
+            "mov {tmp}, cr3",
+            "mov cr3, {tmp}",
+            tmp = out(reg) _,
+        );
+    }
+}
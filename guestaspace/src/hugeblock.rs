@@ -0,0 +1,74 @@
+//! Huge stage-2 blocks for lazily backed guest RAM.
+//!
+//! A fault in lazily backed RAM is backed by the largest aligned block
+//! around the faulting address that stays inside its region and that the
+//! host can allocate and charge ([`crate::gspace::GuestSpace`]). Every size
+//! is tried at its own aligned block: when no 1G frame is left, the 2M block
+//! containing the fault is backed, not the first 2M of the 1G window, so the
+//! faulting page is always covered and the rest of the window can still take
+//! 2M blocks.
+
+#![allow(dead_code)]
+
+/// Tries the blocks of `sizes` (largest first, powers of two) around `gpa`
+/// that lie in `[start, end)` with `back(block, size)`, and returns what
+/// the first one that succeeds returned.
+pub fn back_around<S: Copy + Into<usize>, T>(
+    gpa: usize,
+    start: usize,
+    end: usize,
+    sizes: &[S],
+    mut back: impl FnMut(usize, S) -> Option<T>,
+) -> Option<T> {
+    sizes.iter().find_map(|&size| {
+        let bytes = size.into();
+        let block = gpa & !(bytes - 1);
+        if block >= start && block.checked_add(bytes)? <= end {
+            back(block, size)
+        } else {
+            None
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec::Vec;
+
+    const SIZE_1G: usize = 1 << 30;
+    const SIZE_2M: usize = 2 << 20;
+    const SIZES: [usize; 2] = [SIZE_1G, SIZE_2M];
+
+    #[test]
+    fn largest_block_in_region() {
+        let gpa = 0x4012_3456;
+        let blocks = back_around(gpa, 0, 4 * SIZE_1G, &SIZES, |block, size| {
+            Some((block, size))
+        });
+        assert_eq!(blocks, Some((0x4000_0000, SIZE_1G)));
+        // The 1G block would leave the region.
+        let blocks = back_around(gpa, 0x4000_0000, 0x5000_0000, &SIZES, |block, size| {
+            Some((block, size))
+        });
+        assert_eq!(blocks, Some((0x4000_0000, SIZE_2M)));
+    }
+
+    #[test]
+    fn failed_1g_block_falls_back_to_the_2m_block_of_the_fault() {
+        let gpa = 0x4345_6789;
+        let mut tried = Vec::new();
+        let block = back_around(gpa, 0, 4 * SIZE_1G, &SIZES, |block, size| {
+            tried.push((block, size));
+            (size != SIZE_1G).then_some(block)
+        });
+        assert_eq!(tried, [(0x4000_0000, SIZE_1G), (0x4340_0000, SIZE_2M)]);
+        assert!((0x4340_0000..0x4340_0000 + SIZE_2M).contains(&gpa));
+        assert_eq!(block, Some(0x4340_0000));
+        // No block can be allocated.
+        assert_eq!(
+            back_around(gpa, 0, 4 * SIZE_1G, &SIZES, |_, _| None::<()>),
+            None
+        );
+    }
+}
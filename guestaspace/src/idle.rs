@@ -0,0 +1,64 @@
+//! Idling of guests that wait for an interrupt.
+//!
+//! A guest with nothing to do executes WFI (riscv64, aarch64) or HLT
+//! (x86_64). The hypervisor traps the instruction and, instead of entering
+//! the guest again right away, blocks the VM task on the VM's [`IdleQueue`]
+//! so that the host CPU goes to other VMs or idles itself:
+//!
+//! - until the guest's next timer deadline;
+//! - until another task [kicks](IdleQueue::kick) the queue because it made
+//!   an interrupt pending for the guest, such as a device worker that
+//!   completed a request or read console input
+//!   ([`crate::devices::worker`]);
+//! - at most for [`IDLE_POLL_INTERVAL`], as the run loop still polls
+//!   devices without a worker.
+//!
+//! The run loop then checks the guest's interrupt sources and either enters
+//! the guest or waits again.
+
+#![allow(dead_code)]
+
+use core::sync::atomic::{AtomicBool, Ordering};
+use core::time::Duration;
+
+use axtask::WaitQueue;
+
+/// Longest time an idle VM task sleeps before polling its devices again.
+pub const IDLE_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// The queue a VM task blocks on while its guest idles.
+pub struct IdleQueue {
+    wq: WaitQueue,
+    /// A kick that the VM task has not seen yet.
+    kicked: AtomicBool,
+}
+
+impl IdleQueue {
+    /// Creates a queue without pending kicks.
+    pub const fn new() -> Self {
+        Self {
+            wq: WaitQueue::new(),
+            kicked: AtomicBool::new(false),
+        }
+    }
+
+    /// Blocks the calling VM task until the queue is kicked or `timeout`
+    /// (`None`: no deadline) has elapsed, and at most for
+    /// [`IDLE_POLL_INTERVAL`]. Returns at once if a kick arrived since the
+    /// last wait.
+    pub fn wait(&self, timeout: Option<Duration>) {
+        let timeout = timeout.map_or(IDLE_POLL_INTERVAL, |t| t.min(IDLE_POLL_INTERVAL));
+        if timeout.is_zero() {
+            return;
+        }
+        self.wq
+            .wait_timeout_until(timeout, || self.kicked.swap(false, Ordering::AcqRel));
+    }
+
+    /// Wakes the VM task if it waits on the queue; otherwise its next
+    /// [`wait`](Self::wait) returns at once.
+    pub fn kick(&self) {
+        self.kicked.store(true, Ordering::Release);
+        self.wq.notify_all(false);
+    }
+}
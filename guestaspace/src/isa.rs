@@ -0,0 +1,76 @@
+//! RISC-V ISA extensions of the host, as its device tree describes them.
+//!
+//! Extensions whose CSRs trap as illegal instructions when absent (such as
+//! `hstateen0` of Smstateen) cannot be probed by accessing them; the
+//! hypervisor checks the device tree the firmware passed to the host
+//! instead. It reads the first CPU node, either its `riscv,isa-extensions`
+//! list or its `riscv,isa` string (`rv64imafdch_zicbom_smstateen`).
+
+#![allow(dead_code)]
+
+/// Checks whether the ISA string `isa` names extension `ext` (lower case):
+/// a single-letter extension in its base, or a multi-letter one after it.
+pub fn isa_string_has(isa: &str, ext: &str) -> bool {
+    let mut parts = isa.split('_').map(str::trim);
+    let Some(base) = parts.next() else {
+        return false;
+    };
+    let letters = base
+        .get(..4)
+        .filter(|xlen| xlen.eq_ignore_ascii_case("rv64") || xlen.eq_ignore_ascii_case("rv32"))
+        .map_or("", |_| &base[4..]);
+    match ext.as_bytes() {
+        [letter] => letters.bytes().any(|c| c.eq_ignore_ascii_case(letter)),
+        _ => parts.any(|part| part.eq_ignore_ascii_case(ext)),
+    }
+}
+
+/// Checks whether the host's harts have extension `ext` (lower case). The
+/// first CPU node of the device tree stands for all of them.
+#[cfg(feature = "axstd")]
+pub fn host_has(ext: &str) -> bool {
+    let Some(fdt) = axhal::dtb::get_fdt() else {
+        return false;
+    };
+    let Some(cpu) = fdt.all_nodes().find(|node| {
+        node.find_property("device_type")
+            .is_some_and(|p| p.str() == "cpu")
+    }) else {
+        return false;
+    };
+    if let Some(list) = cpu.find_property("riscv,isa-extensions") {
+        return list.str_list().any(|e| e.eq_ignore_ascii_case(ext));
+    }
+    cpu.find_property("riscv,isa")
+        .is_some_and(|isa| isa_string_has(isa.str(), ext))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn single_letter_extensions() {
+        let isa = "rv64imafdch_zicbom_zicboz_smstateen_sstc";
+        assert!(isa_string_has(isa, "h"));
+        assert!(isa_string_has(isa, "c"));
+        assert!(!isa_string_has(isa, "v"));
+        // "s" only starts multi-letter names here.
+        assert!(!isa_string_has(isa, "s"));
+        assert!(isa_string_has("RV64GC", "c"));
+        assert!(!isa_string_has("imafdc", "c"));
+    }
+
+    #[test]
+    fn multi_letter_extensions() {
+        let isa = "rv64imafdch_zicbom_zicboz_smstateen_sstc";
+        assert!(isa_string_has(isa, "smstateen"));
+        assert!(isa_string_has(isa, "sstc"));
+        assert!(isa_string_has(isa, "zicboz"));
+        assert!(!isa_string_has(isa, "svpbmt"));
+        // A multi-letter name is a whole component, not a prefix.
+        assert!(!isa_string_has(isa, "zicb"));
+        assert!(!isa_string_has("rv64imafdc", "sstc"));
+        assert!(!isa_string_has("", "h"));
+    }
+}
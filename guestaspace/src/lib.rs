@@ -0,0 +1,4725 @@
+//! The hypervisor of arceos-guestaspace, for ArceOS apps to embed.
+//!
+//! With the `axstd` feature, on riscv64 (H extension), aarch64 (guests at
+//! EL0 of an EL1 hypervisor) and x86_64 (AMD SVM), [`run`] runs every VM
+//! configured in `/etc/vms.conf` ([`config`]) in its own task until all of
+//! them shut down, then powers the machine off. An app calls it from its
+//! `main`, after registering the [`hooks::VmObserver`]s that extend the
+//! hypervisor's behavior.
+//!
+//! The building blocks are public as well:
+//!
+//! - [`vcpu`], [`regs`] and [`csrs`]: riscv64 vCPU registers, the VM entry
+//!   and exit path and the hypervisor CSRs;
+//! - [`sbi`]: decoding of the SBI calls of riscv64 guests;
+//! - [`aarch64`]: aarch64 vCPU registers, hypercall decoding, the guest's
+//!   virtual timer, FP/SIMD and ID registers;
+//! - [`x86_64_svm`]: the SVM helpers, the VMCB and the x86_64 device and
+//!   instruction emulation;
+//! - [`loader`]: loading guest images into a guest address space;
+//! - [`hooks`], [`dispatch`], [`config`] and [`error`]: lifecycle
+//!   observers, exit classes, VM configuration and the errors that end a
+//!   VM.
+//!
+//! Without `axstd`, only the architecture-independent modules build, for
+//! the host unit tests.
+
+#![cfg_attr(feature = "axstd", no_std)]
+#![cfg_attr(
+    all(feature = "axstd", target_arch = "riscv64"),
+    feature(riscv_ext_intrinsics)
+)]
+
+#[cfg(feature = "axstd")]
+extern crate axstd as std;
+
+#[cfg(any(feature = "axstd", test))]
+extern crate alloc;
+
+#[cfg(feature = "axstd")]
+#[macro_use]
+extern crate axlog;
+
+#[cfg(feature = "axstd")]
+extern crate axio;
+
+// ────────────────── Console (macros used by the modules below) ──────────────────
+#[cfg(feature = "axstd")]
+#[macro_use]
+mod console;
+
+// ────────────────── RISC-V 64 specific modules ──────────────────
+#[cfg(all(feature = "axstd", target_arch = "riscv64"))]
+mod aia;
+#[cfg(all(feature = "axstd", target_arch = "riscv64"))]
+pub mod csrs;
+#[cfg(all(feature = "axstd", target_arch = "riscv64"))]
+mod hlv;
+#[cfg(any(all(feature = "axstd", target_arch = "riscv64"), test))]
+mod isa;
+#[cfg(all(feature = "axstd", target_arch = "riscv64"))]
+pub mod regs;
+#[cfg(any(all(feature = "axstd", target_arch = "riscv64"), test))]
+pub mod sbi;
+#[cfg(all(feature = "axstd", target_arch = "riscv64"))]
+mod vclock;
+#[cfg(all(feature = "axstd", target_arch = "riscv64"))]
+pub mod vcpu;
+#[cfg(all(feature = "axstd", target_arch = "riscv64"))]
+mod vinsn;
+#[cfg(any(all(feature = "axstd", target_arch = "riscv64"), test))]
+mod vpmu;
+
+// ────────────────── AArch64 specific modules ──────────────────
+#[cfg(all(feature = "axstd", target_arch = "aarch64"))]
+#[path = "aarch64/mod.rs"]
+pub mod aarch64;
+
+// ────────────────── x86_64 (AMD SVM) specific modules ──────────────────
+#[cfg(all(feature = "axstd", target_arch = "x86_64"))]
+#[path = "x86_64/mod.rs"]
+pub mod x86_64_svm;
+
+// ────────────────── Common modules ──────────────────
+// Those built with `any(feature = "axstd", test)` are arch-independent and
+// compile for the host too, where `cargo test` runs their unit tests.
+#[cfg(feature = "axstd")]
+mod balloon;
+#[cfg(feature = "axstd")]
+mod boot;
+#[cfg(feature = "axstd")]
+pub mod config;
+#[cfg(any(feature = "axstd", test))]
+mod cpumodel;
+#[cfg(any(feature = "axstd", test))]
+mod devices;
+#[cfg(feature = "axstd")]
+mod dirty;
+#[cfg(any(feature = "axstd", test))]
+pub mod dispatch;
+#[cfg(feature = "axstd")]
+mod dump;
+#[cfg(feature = "axstd")]
+pub mod error;
+#[cfg(feature = "axstd")]
+mod events;
+#[cfg(feature = "axstd")]
+mod exit;
+#[cfg(any(feature = "axstd", test))]
+mod exitlog;
+#[cfg(any(
+    all(
+        feature = "axstd",
+        any(target_arch = "riscv64", target_arch = "aarch64")
+    ),
+    test
+))]
+mod fdt;
+#[cfg(any(feature = "axstd", test))]
+mod gmem;
+#[cfg(feature = "axstd")]
+mod gspace;
+// Host unit tests run device models on flat guest RAM instead.
+#[cfg(all(test, not(feature = "axstd")))]
+#[path = "gspace_host.rs"]
+mod gspace;
+#[cfg(feature = "axstd")]
+mod gva;
+#[cfg(feature = "axstd")]
+mod harden;
+#[cfg(any(feature = "axstd", test))]
+pub mod hooks;
+#[cfg(feature = "axstd")]
+mod hostcpu;
+#[cfg(feature = "axstd")]
+#[macro_use]
+mod hostguard;
+#[cfg(any(feature = "axstd", test))]
+mod hugeblock;
+#[cfg(feature = "axstd")]
+mod idle;
+#[cfg(feature = "axstd")]
+pub mod loader;
+#[cfg(any(feature = "axstd", test))]
+mod measure;
+#[cfg(feature = "axstd")]
+mod memmap;
+#[cfg(any(feature = "axstd", test))]
+mod migrate;
+#[cfg(any(feature = "axstd", test))]
+mod package;
+#[cfg(feature = "axstd")]
+mod pause;
+#[cfg(any(feature = "axstd", test))]
+mod permfault;
+#[cfg(feature = "axstd")]
+mod pool;
+#[cfg(feature = "axstd")]
+mod refault;
+#[cfg(feature = "axstd")]
+mod serial;
+#[cfg(feature = "axstd")]
+mod shmem;
+#[cfg(all(
+    feature = "axstd",
+    any(target_arch = "riscv64", target_arch = "aarch64")
+))]
+mod tlb;
+#[cfg(feature = "axstd")]
+mod trace;
+#[cfg(feature = "axstd")]
+mod vm;
+#[cfg(feature = "axstd")]
+mod vmid;
+#[cfg(feature = "axstd")]
+mod wallclock;
+#[cfg(feature = "axstd")]
+mod watchdog;
+#[cfg(any(feature = "axstd", test))]
+mod wss;
+
+#[cfg(feature = "axstd")]
+use core::ops::ControlFlow;
+#[cfg(feature = "axstd")]
+use dispatch::{ExitClass, ExitDispatcher};
+#[cfg(feature = "axstd")]
+use error::VmError;
+
+// VM entry point (guest physical / intermediate-physical address)
+#[cfg(all(feature = "axstd", target_arch = "riscv64"))]
+const VM_ENTRY: usize = 0x8020_0000;
+
+#[cfg(all(feature = "axstd", target_arch = "aarch64"))]
+const VM_ENTRY: usize = 0x4020_0000;
+
+#[cfg(all(feature = "axstd", target_arch = "x86_64"))]
+const VM_ENTRY: usize = 0x10000;
+
+#[cfg(all(
+    feature = "axstd",
+    not(any(
+        target_arch = "riscv64",
+        target_arch = "aarch64",
+        target_arch = "x86_64"
+    ))
+))]
+const VM_ENTRY: usize = 0x8020_0000;
+
+// Guest pflash window; the QEMU machines have their pflash1 at the same
+// (host) physical address.
+// RISC-V 64 virt: pflash0 @ 0x20000000 (32MB), pflash1 @ 0x22000000 (32MB).
+#[cfg(all(feature = "axstd", target_arch = "riscv64"))]
+const PFLASH_START: usize = 0x2200_0000;
+#[cfg(all(feature = "axstd", target_arch = "riscv64"))]
+const PFLASH_SIZE: usize = 0x200_0000;
+
+// AArch64 virt: pflash0 @ 0x00000000 (64MB), pflash1 @ 0x04000000 (64MB).
+#[cfg(all(feature = "axstd", target_arch = "aarch64"))]
+const PFLASH_START: usize = 0x0400_0000;
+#[cfg(all(feature = "axstd", target_arch = "aarch64"))]
+const PFLASH_SIZE: usize = 0x400_0000;
+
+// x86_64: no flash on the host, the guest's 4MB flash ends at 4GB.
+#[cfg(all(feature = "axstd", target_arch = "x86_64"))]
+const PFLASH_START: usize = 0xFFC0_0000;
+#[cfg(all(feature = "axstd", target_arch = "x86_64"))]
+const PFLASH_SIZE: usize = 0x40_0000;
+
+// Guest virtio devices: the virtio-mmio slots of the QEMU virt machines on
+// riscv64/aarch64 (virtio-blk, virtio-console and virtio-net in that order),
+// legacy virtio-pci devices behind the virtual host bridge on x86_64 sharing
+// INTx line 11, delivered as vector 0x2B (PIC remapped to 0x20).
+#[cfg(all(feature = "axstd", target_arch = "riscv64"))]
+const VIRTIO_MMIO_BASE: usize = 0x1000_1000;
+#[cfg(all(feature = "axstd", target_arch = "riscv64"))]
+const VIRTIO_MMIO_STRIDE: usize = 0x1000;
+#[cfg(all(feature = "axstd", target_arch = "aarch64"))]
+const VIRTIO_MMIO_BASE: usize = 0x0a00_0000;
+#[cfg(all(feature = "axstd", target_arch = "aarch64"))]
+const VIRTIO_MMIO_STRIDE: usize = 0x200;
+#[cfg(all(feature = "axstd", target_arch = "x86_64"))]
+const VIRTIO_PCI_VECTOR: u32 = 0x2B;
+/// Vector of the PIT interrupt (IRQ0).
+#[cfg(all(feature = "axstd", target_arch = "x86_64"))]
+const PIT_VECTOR: u32 = 0x20;
+
+// Emulated PL031 RTC: where QEMU virt has its Goldfish RTC (riscv64) or its
+// PL031 (aarch64). x86_64 guests get the CMOS RTC at its usual ports.
+#[cfg(all(feature = "axstd", target_arch = "riscv64"))]
+const RTC_BASE: usize = 0x0010_1000;
+#[cfg(all(feature = "axstd", target_arch = "aarch64"))]
+const RTC_BASE: usize = 0x0901_0000;
+
+/// Most harts a riscv64 guest can have.
+#[cfg(all(feature = "axstd", target_arch = "riscv64"))]
+const MAX_GUEST_HARTS: usize = 8;
+/// Longest time a riscv64 guest runs before the host timer ends its run,
+/// so that the other tasks get their turn. On aarch64 and x86_64 the
+/// host's own timer tick ends it.
+#[cfg(all(feature = "axstd", target_arch = "riscv64"))]
+const TIME_SLICE: core::time::Duration = core::time::Duration::from_millis(10);
+
+/// Why a guest run loop ended, unless it ended with a [`VmError`].
+#[cfg(feature = "axstd")]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum GuestExit {
+    /// The guest powered off with an exit code (0: success, see [`exit`]).
+    Shutdown(u32),
+    /// The guest asked for a system reset: rebuild the VM and run it again.
+    Reboot,
+    /// The guest was migrated out (see [`migrate`]) and runs elsewhere.
+    #[cfg(target_arch = "riscv64")]
+    Migrated,
+}
+
+/// What an exit handler decides: re-enter the guest, or end the run loop.
+#[cfg(feature = "axstd")]
+type ExitFlow = ControlFlow<Result<GuestExit, VmError>>;
+
+/// Runs every configured VM in its own host task and waits for all of them.
+///
+/// The tasks are scheduled by the CFS scheduler of axtask, weighted by each
+/// VM's CPU share, and every VM entry is a scheduling point. A VM that
+/// requests a reboot is rebuilt from scratch by calling `run_vm` again. A VM
+/// that fails with a [`VmError`] is terminated with a diagnostic; the other
+/// VMs keep running.
+///
+/// Returns the exit status of the hypervisor: the first nonzero exit code
+/// of the VMs.
+#[cfg(feature = "axstd")]
+fn run_vms<F>(run_vm: F) -> u32
+where
+    F: Fn(&config::VmConfig) -> Result<GuestExit, VmError> + Copy + Send + 'static,
+{
+    use alloc::format;
+    use alloc::vec::Vec;
+
+    let configs = config::load_vm_configs();
+    if configs.iter().any(|cfg| cfg.harden) {
+        harden::protect_host();
+    }
+    let tasks: Vec<_> = configs
+        .into_iter()
+        .map(|mut cfg| {
+            std::thread::Builder::new()
+                .name(format!("vm{}", cfg.id))
+                .spawn(move || {
+                    axtask::set_priority(cfg.nice());
+                    let cpu = hostcpu::pin_vm_task(cfg.id, cfg.pin);
+                    if axhal::cpu_num() > 1 {
+                        vm_println!(cfg.id, "Running on host CPU {}", cpu);
+                    }
+                    loop {
+                        match run_vm(&cfg) {
+                            Ok(GuestExit::Reboot) => {
+                                vm_println!(cfg.id, "Guest requested reboot, restarting VM...");
+                                // A guest that was migrated in boots its image.
+                                cfg.incoming = None;
+                            }
+                            #[cfg(target_arch = "riscv64")]
+                            Ok(GuestExit::Migrated) => {
+                                vm_println!(cfg.id, "VM migrated out");
+                                break 0;
+                            }
+                            Ok(GuestExit::Shutdown(0)) => {
+                                vm_println!(cfg.id, "Shutdown vm normally!");
+                                break 0;
+                            }
+                            Ok(GuestExit::Shutdown(code)) => {
+                                vm_println!(cfg.id, "Guest exited with code {}", code);
+                                break code;
+                            }
+                            Err(e) => {
+                                vm_println!(cfg.id, "VM terminated: {}", e);
+                                break exit::VM_ERROR_EXIT_CODE;
+                            }
+                        }
+                    }
+                })
+                .expect("spawn VM task")
+        })
+        .collect();
+    tasks
+        .into_iter()
+        .map(|task| task.join().expect("join VM task"))
+        .fold(0, |status, code| if status != 0 { status } else { code })
+}
+
+/// Ends the run of a VM whose watchdog expired with the guest at `pc`, once
+/// the caller printed its crash dump: the VM is rebooted or terminated, as
+/// configured.
+#[cfg(feature = "axstd")]
+fn watchdog_exit(
+    vm: usize,
+    expired: watchdog::WatchdogConfig,
+    pc: usize,
+) -> Result<GuestExit, VmError> {
+    match expired.action {
+        watchdog::WatchdogAction::Reset => {
+            vm_println!(
+                vm,
+                "Guest watchdog expired after {} s, rebooting",
+                expired.secs
+            );
+            Ok(GuestExit::Reboot)
+        }
+        watchdog::WatchdogAction::Terminate => Err(VmError::WatchdogExpired {
+            secs: expired.secs,
+            pc,
+        }),
+    }
+}
+
+/// Refuses `migrate=` and `incoming=` on a VM that cannot be migrated: see
+/// the `migrate` module.
+#[cfg(feature = "axstd")]
+fn check_migratable(cfg: &config::VmConfig) -> Result<(), VmError> {
+    if cfg.migrate.is_none() && cfg.incoming.is_none() {
+        return Ok(());
+    }
+    let reason = if !cfg!(target_arch = "riscv64") {
+        "only riscv64 guests migrate"
+    } else if cfg.cpus > 1 {
+        "a VM with more than one vCPU does not migrate"
+    } else if cfg.disk.is_some() {
+        "a VM with a virtio-blk disk does not migrate"
+    } else {
+        return Ok(());
+    };
+    Err(VmError::Setup {
+        step: "set up migration",
+        reason: reason.into(),
+    })
+}
+
+/// Takes a sample of the VM's working set if one is due: counts and clears
+/// the accessed bits of `space`, flushes the guest TLB with `flush_tlb` so
+/// that the next accesses set them again, and tells the observers.
+#[cfg(feature = "axstd")]
+fn sample_working_set(
+    wss: &mut wss::WorkingSet,
+    space: &mut gspace::GuestSpace,
+    hooks: &hooks::VmHooks,
+    flush_tlb: impl FnOnce(),
+) {
+    let now = axhal::time::monotonic_time_nanos();
+    if !wss.due(now) {
+        return;
+    }
+    let accessed = space.harvest_accessed();
+    flush_tlb();
+    hooks.working_set(wss.record(now, accessed, space.mem_used()));
+}
+
+/// Measures the guest image (with the `measure` feature) and checks it
+/// against the digest the VM is configured with (`sha256=`); returns the
+/// measurement. A VM whose image does not match, or that asks for a check
+/// the hypervisor cannot make, is not started.
+#[cfg(feature = "axstd")]
+fn measure_image(
+    cfg: &config::VmConfig,
+    image: &gspace::SharedPages,
+) -> Result<Option<measure::Digest>, VmError> {
+    const STEP: &str = "verify guest image";
+    let expected = match cfg.sha256.as_deref() {
+        Some(hex) => Some(measure::Digest::parse(hex).ok_or_else(|| VmError::Setup {
+            step: STEP,
+            reason: alloc::format!("sha256={} is not 64 hex digits", hex),
+        })?),
+        None => None,
+    };
+    #[cfg(feature = "measure")]
+    let measurement = Some(loader::measure(image));
+    #[cfg(not(feature = "measure"))]
+    let measurement = {
+        let _ = image;
+        None
+    };
+    match (expected, measurement) {
+        (Some(_), None) => Err(VmError::Setup {
+            step: STEP,
+            reason: "built without the measure feature".into(),
+        }),
+        (Some(expected), Some(digest)) if digest != expected => Err(VmError::Setup {
+            step: STEP,
+            reason: alloc::format!("SHA-256 {} instead of {}", digest, expected),
+        }),
+        (expected, measurement) => {
+            if let Some(digest) = measurement {
+                let checked = if expected.is_some() {
+                    " (as expected)"
+                } else {
+                    ""
+                };
+                vm_println!(cfg.id, "Image SHA-256: {}{}", digest, checked);
+            }
+            Ok(measurement)
+        }
+    }
+}
+
+/// Creates the exit trace of the VM, if it records one. A trace that cannot
+/// be created is reported and the VM runs without it.
+#[cfg(feature = "axstd")]
+fn exit_recorder(
+    cfg: &config::VmConfig,
+    arch: exitlog::TraceArch,
+) -> Option<exitlog::ExitRecorder> {
+    let path = cfg.record.as_deref()?;
+    let classes = ExitClass::ALL.map(ExitClass::name);
+    match exitlog::ExitRecorder::create(cfg.id, path, arch, &classes) {
+        Ok(recorder) => Some(recorder),
+        Err(e) => {
+            vm_println!(cfg.id, "Exit trace: cannot create {}: {:?}", path, e);
+            None
+        }
+    }
+}
+
+/// Opens the disk image of the VM, if it has one, as a virtio-blk device.
+#[cfg(feature = "axstd")]
+fn open_vm_disk(
+    cfg: &config::VmConfig,
+    idle: &alloc::sync::Arc<idle::IdleQueue>,
+) -> Option<devices::virtio::blk::VirtioBlk> {
+    let path = cfg.disk.as_deref()?;
+    match devices::virtio::blk::VirtioBlk::open(path, cfg.id, idle.clone()) {
+        Ok(blk) => {
+            vm_println!(
+                cfg.id,
+                "virtio-blk: {} ({} KB{})",
+                path,
+                blk.size() / 1024,
+                if blk.is_read_only() {
+                    ", read-only"
+                } else {
+                    ""
+                }
+            );
+            Some(blk)
+        }
+        Err(e) => {
+            vm_println!(cfg.id, "Cannot open disk image {}: {:?}", path, e);
+            None
+        }
+    }
+}
+
+/// Loads the initrd of the VM, if it has one, into guest RAM `[floor,
+/// limit)` and returns the range it occupies.
+#[cfg(feature = "axstd")]
+fn load_vm_initrd(
+    cfg: &config::VmConfig,
+    uspace: &mut gspace::GuestSpace,
+    floor: usize,
+    limit: usize,
+) -> Option<(usize, usize)> {
+    let path = cfg.initrd.as_deref()?;
+    match loader::load_initrd(uspace, path, floor, limit) {
+        Ok((start, end)) => {
+            vm_println!(
+                cfg.id,
+                "initrd: {} ({} KB at {:#x})",
+                path,
+                (end - start) / 1024,
+                start
+            );
+            Some((start, end))
+        }
+        Err(e) => {
+            vm_println!(cfg.id, "Cannot load initrd {}: {:?}", path, e);
+            None
+        }
+    }
+}
+
+/// Creates the virtio-console of the VM. Host console input goes to the
+/// first VM only.
+#[cfg(feature = "axstd")]
+fn vm_virtio_console(
+    cfg: &config::VmConfig,
+    idle: &alloc::sync::Arc<idle::IdleQueue>,
+) -> devices::virtio::console::VirtioConsole {
+    devices::virtio::console::VirtioConsole::new(cfg.id, cfg.id == 0, idle.clone())
+}
+
+/// Gives the VM the secondary host serial port if it asks for it
+/// (`serial=on`) and the port is free; otherwise the VM keeps the shared
+/// console.
+#[cfg(feature = "axstd")]
+fn vm_claim_serial(cfg: &config::VmConfig) -> Option<serial::HostSerial> {
+    if !cfg.serial {
+        return None;
+    }
+    let port = serial::claim(cfg.id);
+    if port.is_none() {
+        vm_println!(
+            cfg.id,
+            "serial=on: no secondary serial port, or another VM owns it; using the shared console"
+        );
+    }
+    port
+}
+
+/// Creates the virtio-net of the VM, cabled to its neighbour VM (0 with 1,
+/// 2 with 3, ...). The MAC address is 52:54:00:12:34:xx, xx = 0x56 + VM id.
+#[cfg(feature = "axstd")]
+fn vm_virtio_net(
+    cfg: &config::VmConfig,
+) -> devices::virtio::net::VirtioNet<devices::virtio::net::Loopback> {
+    use devices::virtio::net::{Loopback, VirtioNet};
+
+    let mac = [
+        0x52,
+        0x54,
+        0x00,
+        0x12,
+        0x34,
+        0x56u8.wrapping_add(cfg.id as u8),
+    ];
+    vm_println!(
+        cfg.id,
+        "virtio-net: {:02x?}, linked to vm{}",
+        mac,
+        cfg.id ^ 1
+    );
+    VirtioNet::new(Loopback::new(cfg.id), mac)
+}
+
+/// Declares the virtio-mmio devices in `slots` (0 = blk, 1 = console,
+/// 2 = net) in the memory map.
+#[cfg(all(
+    feature = "axstd",
+    any(target_arch = "riscv64", target_arch = "aarch64")
+))]
+fn declare_virtio_mmio(map: &mut memmap::MemoryMap, slots: &[usize]) -> Result<(), VmError> {
+    const NAMES: [&str; 3] = ["virtio-blk", "virtio-console", "virtio-net"];
+    for &slot in slots {
+        map.add(
+            memmap::RegionKind::Mmio,
+            NAMES[slot],
+            VIRTIO_MMIO_BASE + slot * VIRTIO_MMIO_STRIDE,
+            devices::virtio::mmio::VIRTIO_MMIO_SIZE,
+        )?;
+    }
+    Ok(())
+}
+
+/// The boot information entries of the virtio-mmio devices in `slots`.
+#[cfg(all(
+    feature = "axstd",
+    any(target_arch = "riscv64", target_arch = "aarch64")
+))]
+fn virtio_mmio_boot_info(slots: &[usize]) -> impl Iterator<Item = (u32, usize, usize)> + '_ {
+    slots.iter().map(|&slot| {
+        (
+            hvcall_abi::bootinfo::device::VIRTIO_MMIO,
+            VIRTIO_MMIO_BASE + slot * VIRTIO_MMIO_STRIDE,
+            devices::virtio::mmio::VIRTIO_MMIO_SIZE,
+        )
+    })
+}
+
+/// Adds the boot information page (see `devices::bootinfo`) of a VM with
+/// `cpus` vCPUs, RAM at `[ram_start, ram_start + ram_size)` and `devices`
+/// (kind, base, size) to its MMIO bus and memory map.
+#[cfg(feature = "axstd")]
+fn add_boot_info(
+    cfg: &config::VmConfig,
+    map: &mut memmap::MemoryMap,
+    mmio: &mut devices::mmio::MmioBus,
+    cpus: usize,
+    (ram_start, ram_size): (usize, usize),
+    devices: impl IntoIterator<Item = (u32, usize, usize)>,
+) -> Result<(), VmError> {
+    use devices::bootinfo::{BOOT_INFO_GPA, BootInfoPage};
+    use hvcall_abi::bootinfo::{BootInfo, SIZE};
+
+    map.add(memmap::RegionKind::Mmio, "boot info", BOOT_INFO_GPA, SIZE)?;
+    let mut info = BootInfo::new(
+        cfg.id as u32,
+        cpus as u32,
+        ram_start as u64,
+        ram_size as u64,
+    );
+    for (kind, base, size) in devices {
+        info.add_device(kind, base as u64, size as u64);
+    }
+    let page = BootInfoPage::new(&info).map_err(VmError::setup("create boot info page"))?;
+    mmio.add(alloc::boxed::Box::new(page))
+        .map_err(VmError::setup("add boot info page"))
+}
+
+// ════════════════════════════════════════════════════════════════
+//  Entry point
+// ════════════════════════════════════════════════════════════════
+
+/// Runs every configured VM until all of them have ended, reports the
+/// hypervisor's exit status and powers the machine off.
+#[cfg(feature = "axstd")]
+pub fn run() {
+    #[cfg(target_arch = "riscv64")]
+    riscv64_main();
+
+    #[cfg(target_arch = "aarch64")]
+    aarch64_main();
+
+    #[cfg(target_arch = "x86_64")]
+    x86_64_main();
+}
+
+// ════════════════════════════════════════════════════════════════
+//  RISC-V 64  (H-extension hypervisor — h_2_0 style)
+//  Full OS guest support: SBI forwarding, on-demand NPF mapping
+// ════════════════════════════════════════════════════════════════
+
+#[cfg(all(feature = "axstd", target_arch = "riscv64"))]
+fn riscv64_main() {
+    ax_println!("Hypervisor ...");
+    // Put back by the final panic, or by whatever stops the hypervisor
+    // early.
+    let _host = hostguard::HostGuard::new();
+
+    // Check pflash
+    ax_println!("Reading PFlash at physical address {:#X}...", PFLASH_START);
+    let va = axhal::mem::phys_to_virt(PFLASH_START.into()).as_usize();
+    let ptr = va as *const u32;
+    unsafe {
+        ax_println!(
+            "Try to access pflash dev region [{:#X}], got {:#X}",
+            va,
+            *ptr
+        );
+        let magic = (*ptr).to_ne_bytes();
+        ax_println!(
+            "Got pflash magic: {}",
+            core::str::from_utf8(&magic).unwrap()
+        );
+    }
+
+    let status = run_vms(riscv64_run_vm);
+    exit::report(status);
+
+    host_panic!("Hypervisor ok!");
+}
+
+/// Prints the crash report of a riscv64 guest at an unhandled exit.
+#[cfg(all(feature = "axstd", target_arch = "riscv64"))]
+fn riscv64_crash_dump(vm: usize, ctx: &vcpu::VmCpuRegisters, space: &gspace::GuestSpace) {
+    use alloc::vec::Vec;
+    use regs::GprIndex;
+
+    const ABI_NAMES: [&str; 32] = [
+        "zero", "ra", "sp", "gp", "tp", "t0", "t1", "t2", "s0", "s1", "a0", "a1", "a2", "a3", "a4",
+        "a5", "a6", "a7", "s2", "s3", "s4", "s5", "s6", "s7", "s8", "s9", "s10", "s11", "t3", "t4",
+        "t5", "t6",
+    ];
+    let gprs: [(&str, u64); 32] = core::array::from_fn(|i| {
+        let reg = GprIndex::from_raw(i as u32).unwrap();
+        (ABI_NAMES[i], ctx.guest_regs.gprs.reg(reg) as u64)
+    });
+    let (guest, trap) = (&ctx.guest_regs, &ctx.trap_csrs);
+    let sysregs: Vec<(&str, u64)> = [
+        ("sepc", guest.sepc),
+        ("sstatus", guest.sstatus),
+        ("hstatus", guest.hstatus),
+        ("scause", trap.scause),
+        ("stval", trap.stval),
+        ("htval", trap.htval),
+        ("htinst", trap.htinst),
+    ]
+    .iter()
+    .chain(ctx.vs_csrs().iter())
+    .map(|&(name, value)| (name, value as u64))
+    .collect();
+    dump::CrashDump {
+        gprs: &gprs,
+        sysregs: &sysregs,
+        pc_gpa: gva::gva_to_gpa(
+            space,
+            &gva::GuestPaging {
+                vsatp: ctx.vsatp(),
+                sstatus: guest.sstatus,
+            },
+            guest.sepc,
+            gva::Access::Execute,
+        ),
+        // htval holds the guest physical address shifted right by 2.
+        fault_gpa: (trap.htval != 0).then_some(trap.htval << 2 | trap.stval & 3),
+    }
+    .print(vm, space);
+}
+
+/// Builds the guest VM from scratch and runs it until it shuts down or
+/// requests a reboot. The address space (and with it the G-stage page table
+/// and all guest RAM) is freed on return.
+#[cfg(all(feature = "axstd", target_arch = "riscv64"))]
+fn riscv64_run_vm(cfg: &config::VmConfig) -> Result<GuestExit, VmError> {
+    use alloc::boxed::Box;
+    use alloc::sync::Arc;
+    use alloc::vec::Vec;
+    use axerrno::AxError;
+    use axhal::mem::PhysAddr;
+    use axhal::paging::MappingFlags;
+    use csrs::defs::hcounteren;
+    use csrs::traps;
+    use csrs::{CSR, RiscvCsrTrait};
+    use devices::virtio::mmio::{VIRTIO_MMIO_SIZE, VirtioMmio};
+    use gmem::GuestMemory;
+    use memory_addr::va;
+    use riscv::register::scause;
+    use vcpu::_run_guest;
+    use vcpu::VmCpuRegisters;
+
+    // ════════════════════════════════════════════════════
+    //  Step 0: Setup H-extension CSRs  (matches riscv_vcpu::setup_csrs)
+    // ════════════════════════════════════════════════════
+    unsafe {
+        // Delegate the guest's own exceptions (page faults, system calls,
+        // breakpoints, ...) and the VS-level interrupts to it, so they do not
+        // exit to the hypervisor. Illegal instructions exit: the guest's
+        // first FP instruction after an entry loads its FP registers.
+        csrs::TrapDelegation::guest_default()
+            .observe_exception(traps::exception::ILLEGAL_INST)
+            .apply();
+
+        // Clear all pending virtual interrupts.
+        CSR.hvip.read_and_clear_bits(
+            traps::interrupt::VIRTUAL_SUPERVISOR_TIMER
+                | traps::interrupt::VIRTUAL_SUPERVISOR_EXTERNAL
+                | traps::interrupt::VIRTUAL_SUPERVISOR_SOFT,
+        );
+
+        // The guest reads cycle, time and instret directly; reads of the
+        // hardware performance counters trap as virtual instructions and
+        // are emulated.
+        CSR.hcounteren.write_value(
+            (hcounteren::cycle::SET + hcounteren::time::SET + hcounteren::instret::SET).value,
+        );
+    }
+    // Optional ISA state is granted explicitly or traps. With Sstc, the
+    // guest's timer is `vstimecmp`: the guest programs it directly and the
+    // hardware raises its timer interrupt.
+    check_migratable(cfg)?;
+    let env = csrs::GuestEnv::probe(isa::host_has("smstateen"));
+    let sstc = env.sstc();
+    if sstc {
+        vm_println!(cfg.id, "Sstc: guest timer in vstimecmp");
+    }
+
+    // ════════════════════════════════════════════════════
+    //  Step 1: Create guest address space
+    // ════════════════════════════════════════════════════
+    // G-stage TLB entries of this VM are tagged with its own VMID. The
+    // address space is torn down (and hgatp cleared) when the VM is
+    // destroyed after the run loop.
+    let mut vm =
+        vm::Vm::new(cfg, va!(0x0), 0x7fff_ffff_f000, 0).map_err(VmError::setup("create VM"))?;
+    // The machine has a single UART: this only reports that it stays shared.
+    vm_claim_serial(cfg);
+    let (uspace, map) = (&mut vm.space, &mut vm.map);
+
+    let flags =
+        MappingFlags::READ | MappingFlags::WRITE | MappingFlags::EXECUTE | MappingFlags::USER;
+
+    // The G-stage table only ever maps the regions declared here and in
+    // the steps below: guest RAM, the pflash, and the host devices the
+    // device tree hands to the guest. Any other guest physical address
+    // faults for good, so no host memory is reachable by accident.
+    let device_flags =
+        MappingFlags::READ | MappingFlags::WRITE | MappingFlags::DEVICE | MappingFlags::USER;
+    let num_harts = cfg.cpus.min(MAX_GUEST_HARTS);
+    // A single-hart VM on a host with AIA guest interrupt files gets one,
+    // at its IMSIC address, and an emulated APLIC; otherwise the host's
+    // PLIC is passed through. So is it for a VM that migrates: the state
+    // of an interrupt file stays with the host.
+    let migrates = cfg.migrate.is_some() || cfg.incoming.is_some();
+    let aia = (num_harts == 1 && !migrates)
+        .then(aia::GuestFile::alloc)
+        .flatten()
+        .map(|file| aia::GuestAia::new(fdt::APLIC_BASE as usize, file));
+    let mut passthrough = alloc::vec![("UART", fdt::UART_BASE, fdt::UART_SIZE, None)];
+    match &aia {
+        Some(aia) => {
+            vm_println!(
+                cfg.id,
+                "AIA: guest interrupt file {} of hart {}",
+                aia.file.vgein() >> 12,
+                axhal::percpu::this_cpu_id()
+            );
+            passthrough.push((
+                "IMSIC",
+                fdt::IMSIC_BASE,
+                fdt::IMSIC_SIZE,
+                Some(aia.file.page()),
+            ));
+            map.add(
+                memmap::RegionKind::Mmio,
+                "APLIC",
+                fdt::APLIC_BASE as usize,
+                devices::aplic::APLIC_SIZE,
+            )?;
+        }
+        None => passthrough.push(("PLIC", fdt::PLIC_BASE, fdt::PLIC_SIZE, None)),
+    }
+    let env = if aia.is_some() { env.with_aia() } else { env };
+    // The guest's harts have what the hypervisor switches or enabled.
+    let cpu_model = cpumodel::GuestCpuModel::baseline()
+        .with(cpumodel::Feature::Atomics)
+        .with(cpumodel::Feature::Fp)
+        .with_if(cpumodel::Feature::Sstc, sstc)
+        .with_if(cpumodel::Feature::Svpbmt, env.svpbmt())
+        .with_if(cpumodel::Feature::Ssaia, aia.is_some());
+    for (name, base, size, host) in passthrough {
+        let (base, size) = (base as usize, size as usize);
+        map.add(memmap::RegionKind::Mmio, name, base, size)?;
+        uspace
+            .map_linear(base.into(), host.unwrap_or(base.into()), size, device_flags)
+            .map_err(VmError::setup("map passthrough devices"))?;
+    }
+
+    // The guest's pflash1: the image from the disk if there is one,
+    // otherwise QEMU's flash mapped read-only at the same address.
+    map.add(
+        memmap::RegionKind::Pflash,
+        "pflash",
+        PFLASH_START,
+        PFLASH_SIZE,
+    )?;
+    let mut pflash =
+        devices::pflash::PFlash::open(PFLASH_START, PFLASH_SIZE, Some(PFLASH_START.into()), true)
+            .map_err(VmError::setup("create pflash"))?;
+    pflash
+        .attach(uspace)
+        .map_err(VmError::setup("map pflash"))?;
+    vm_println!(
+        cfg.id,
+        "PFlash at {:#x} ({} MB, {})",
+        pflash.base(),
+        pflash.size() / (1024 * 1024),
+        pflash.kind()
+    );
+
+    // Woken by the device workers when they complete requests, while the
+    // guest idles.
+    let idle = Arc::new(idle::IdleQueue::new());
+    // Emulated MMIO devices: accesses to them trap and are decoded from htinst.
+    let mut mmio = devices::mmio::MmioBus::new();
+    mmio.add(Box::new(pflash)).expect("add pflash");
+    // virtio-mmio slots in use, described in the device tree.
+    let mut virtio_slots = Vec::new();
+    if let Some(blk) = open_vm_disk(cfg, &idle) {
+        mmio.add(Box::new(VirtioMmio::new(VIRTIO_MMIO_BASE, Box::new(blk))))
+            .expect("add virtio-blk");
+        virtio_slots.push(0);
+    }
+    mmio.add(Box::new(VirtioMmio::new(
+        VIRTIO_MMIO_BASE + VIRTIO_MMIO_STRIDE,
+        Box::new(vm_virtio_console(cfg, &idle)),
+    )))
+    .expect("add virtio-console");
+    mmio.add(Box::new(VirtioMmio::new(
+        VIRTIO_MMIO_BASE + 2 * VIRTIO_MMIO_STRIDE,
+        Box::new(vm_virtio_net(cfg)),
+    )))
+    .expect("add virtio-net");
+    virtio_slots.extend([1, 2]);
+    declare_virtio_mmio(map, &virtio_slots)?;
+    mmio.add(Box::new(devices::pl031::Pl031::new(RTC_BASE)))
+        .expect("add RTC");
+    map.add(
+        memmap::RegionKind::Mmio,
+        "RTC",
+        RTC_BASE,
+        devices::pl031::PL031_SIZE,
+    )?;
+
+    // ════════════════════════════════════════════════════
+    //  Step 2: Load guest binary
+    //
+    //  The image comes from the shared image cache: VMs running the same
+    //  binary map the same read-only frames and copy a page only when the
+    //  guest writes to it.
+    // ════════════════════════════════════════════════════
+    const PHY_MEM_START: usize = 0x8000_0000;
+    const PHY_MEM_SIZE: usize = 0x100_0000; // 16 MB
+    const LINUX_MEM_SIZE: usize = 0x400_0000; // 64 MB
+
+    vm_println!(cfg.id, "app: {}", cfg.image);
+    let image = loader::shared_image(&cfg.image).map_err(VmError::setup("open guest image"))?;
+    vm_println!(
+        cfg.id,
+        "Loaded {} bytes from {} (shared by {} VMs)",
+        image.len(),
+        cfg.image,
+        Arc::strong_count(&image)
+    );
+    vm.measurement = measure_image(cfg, &image)?;
+
+    // A package, or a flat binary with a load descriptor, brings its own
+    // layout and permissions (see `package`); a Linux `Image` is placed at
+    // its text offset from the start of RAM and gets more memory; anything
+    // else is a flat binary at VM_ENTRY.
+    let package = loader::package(&cfg.image, &image, VM_ENTRY)
+        .transpose()
+        .map_err(VmError::setup("parse guest package"))?;
+    let linux = package
+        .is_none()
+        .then(|| boot::riscv_image_header(&image))
+        .flatten();
+    let (entry, ram_size) = match (&package, linux) {
+        (Some(pkg), _) => {
+            let ram_size = PHY_MEM_SIZE.max(pkg.ram_size as usize);
+            let fdt = boot::fdt_gpa(PHY_MEM_START, ram_size);
+            if !pkg.fits(PHY_MEM_START as u64, fdt as u64) {
+                return Err(VmError::Setup {
+                    step: "place package in guest RAM",
+                    reason: "segments outside guest RAM".into(),
+                });
+            }
+            vm_println!(
+                cfg.id,
+                "Package: {} segments at {:#x}, entry {:#x}, {} KB RAM",
+                pkg.segments.len(),
+                pkg.span().0,
+                pkg.entry,
+                ram_size / 1024
+            );
+            (pkg.entry as usize, ram_size)
+        }
+        (None, Some(hdr)) => {
+            let ram_size = LINUX_MEM_SIZE;
+            let fdt = boot::fdt_gpa(PHY_MEM_START, ram_size);
+            let entry = hdr
+                .load_address(PHY_MEM_START, fdt)
+                .ok_or(AxError::NoMemory)
+                .map_err(VmError::setup("place Linux image in guest RAM"))?;
+            vm_println!(
+                cfg.id,
+                "Linux riscv64 Image: text_offset {:#x}, size {} KB, loaded at {:#x}",
+                hdr.text_offset,
+                hdr.image_size / 1024,
+                entry
+            );
+            (entry, ram_size)
+        }
+        (None, None) => (VM_ENTRY, PHY_MEM_SIZE),
+    };
+    let (image_start, image_size) = loader::image_span(&image, package.as_ref(), entry);
+
+    // ════════════════════════════════════════════════════
+    //  Step 3: Pre-allocate guest physical RAM around the image
+    //          (like h_2_0 map_alloc)
+    //
+    //  h_2_0 allocates 16MB at 0x8000_0000 up front.
+    //  This eliminates thousands of NPF VM-exits during guest boot.
+    // ════════════════════════════════════════════════════
+    vm_println!(
+        cfg.id,
+        "Pre-allocating {} MB guest RAM at {:#x}...",
+        ram_size / (1024 * 1024),
+        PHY_MEM_START
+    );
+    map.add(
+        memmap::RegionKind::Ram,
+        "guest RAM",
+        PHY_MEM_START,
+        ram_size,
+    )?;
+    map.add(
+        memmap::RegionKind::Image,
+        if package.is_some() {
+            "package"
+        } else if linux.is_some() {
+            "Linux Image"
+        } else {
+            "image"
+        },
+        image_start,
+        image_size,
+    )?;
+    match &package {
+        Some(pkg) => {
+            loader::map_ram_with_package(uspace, PHY_MEM_START, ram_size, &image, pkg, flags)
+        }
+        None => loader::map_ram_with_image(uspace, PHY_MEM_START, ram_size, entry, image, flags),
+    }
+    .map_err(VmError::setup("map guest RAM"))?;
+    let (pages_4k, pages_2m, pages_1g) = uspace.frame_counts();
+    vm_println!(
+        cfg.id,
+        "Guest RAM backed by {} 1G + {} 2M + {} 4K pages",
+        pages_1g,
+        pages_2m,
+        pages_4k
+    );
+
+    // The device tree goes to the end of RAM with the initrd just below it
+    // (before dirty logging starts, as the hypervisor's own writes are not
+    // logged).
+    let fdt_gpa = boot::fdt_gpa(PHY_MEM_START, ram_size);
+    map.add(
+        memmap::RegionKind::Dtb,
+        "device tree",
+        fdt_gpa,
+        boot::FDT_MAX_SIZE,
+    )?;
+    let initrd = load_vm_initrd(cfg, uspace, image_start + image_size, fdt_gpa);
+    if let Some((start, end)) = initrd {
+        map.add(memmap::RegionKind::Initrd, "initrd", start, end - start)?;
+    }
+    let fdt = fdt::guest_fdt(&fdt::GuestLayout {
+        ram_start: PHY_MEM_START,
+        ram_size,
+        num_cpus: num_harts,
+        initrd,
+        bootargs: cfg.cmdline.clone(),
+        // QEMU virt numbering: virtio-mmio slot i raises PLIC interrupt i + 1.
+        virtio_mmio: virtio_slots
+            .iter()
+            .map(|&i| {
+                (
+                    VIRTIO_MMIO_BASE + i * VIRTIO_MMIO_STRIDE,
+                    VIRTIO_MMIO_SIZE,
+                    i as u32 + 1,
+                )
+            })
+            .collect(),
+        rtc: Some((RTC_BASE, devices::pl031::PL031_SIZE)),
+        aia: aia.is_some(),
+        isa: cpu_model.riscv_isa(),
+    });
+    assert!(fdt.len() <= boot::FDT_MAX_SIZE, "device tree too large");
+    uspace
+        .copy_to_guest(fdt_gpa, &fdt)
+        .map_err(VmError::setup("write device tree"))?;
+    vm_println!(cfg.id, "Device tree: {} bytes at {:#x}", fdt.len(), fdt_gpa);
+    // The same for guests without a device tree parser.
+    {
+        use hvcall_abi::bootinfo::device;
+        let irqchip = match &aia {
+            Some(_) => [
+                (
+                    device::APLIC,
+                    fdt::APLIC_BASE,
+                    devices::aplic::APLIC_SIZE as u64,
+                ),
+                (device::IMSIC, fdt::IMSIC_BASE, fdt::IMSIC_SIZE),
+            ]
+            .to_vec(),
+            None => [(device::PLIC, fdt::PLIC_BASE, fdt::PLIC_SIZE)].to_vec(),
+        };
+        let devices = [
+            (
+                device::NS16550,
+                fdt::UART_BASE as usize,
+                fdt::UART_SIZE as usize,
+            ),
+            (device::PFLASH, PFLASH_START, PFLASH_SIZE),
+            (device::PL031, RTC_BASE, devices::pl031::PL031_SIZE),
+        ]
+        .into_iter()
+        .chain(
+            irqchip
+                .into_iter()
+                .map(|(kind, base, size)| (kind, base as usize, size as usize)),
+        )
+        .chain(virtio_mmio_boot_info(&virtio_slots));
+        add_boot_info(
+            cfg,
+            map,
+            &mut mmio,
+            num_harts,
+            (PHY_MEM_START, ram_size),
+            devices,
+        )?;
+    }
+    vm.print_memory_map();
+    let (uspace, vmid, hooks) = (&mut vm.space, &vm.vmid, &vm.hooks);
+    // Zeroed frames for the faults of the guest's boot.
+    uspace.prepare_pool();
+
+    // Track guest RAM writes. The VMID was flushed on allocation and the
+    // guest has not run yet, so no stale writable entries exist.
+    let mut dirty_log = dirty::DirtyLog::new(PHY_MEM_START, ram_size, flags)
+        .map_err(VmError::setup("create dirty log"))?;
+    dirty_log
+        .enable(uspace)
+        .map_err(VmError::setup("enable dirty log"))?;
+    let mut tracer = trace::ExitTracer::new(cfg.id, cfg.trace);
+    let mut recorder = exit_recorder(cfg, exitlog::TraceArch::Riscv64);
+    let balloon = balloon::Balloon::new(cfg.id);
+    let shmem = shmem::SharedMem::new(cfg.id);
+    let mut outgoing = cfg
+        .migrate
+        .as_ref()
+        .map(|migrate| {
+            migrate::Outgoing::create(
+                cfg.id,
+                migrate,
+                exitlog::TraceArch::Riscv64,
+                (PHY_MEM_START, ram_size),
+                axhal::time::monotonic_time_nanos(),
+            )
+        })
+        .transpose()
+        .map_err(VmError::setup("create migration stream"))?;
+
+    // ════════════════════════════════════════════════════
+    //  Step 4: Prepare guest context & G-stage page table
+    // ════════════════════════════════════════════════════
+    let mut ctx = VmCpuRegisters::default();
+    prepare_guest_context(&mut ctx, entry);
+    // Boot protocol of Linux (and of OpenSBI payloads in general):
+    // a0 = hart id, a1 = device tree address.
+    ctx.guest_regs.gprs.set_reg(regs::GprIndex::A0, 0);
+    ctx.guest_regs.gprs.set_reg(regs::GprIndex::A1, fdt_gpa);
+    // The guest's IMSIC accesses reach its interrupt file.
+    if let Some(aia) = &aia {
+        ctx.guest_regs.hstatus |= aia.file.vgein();
+    }
+
+    // Hart 0 boots; the other harts stay stopped until the guest starts
+    // them through SBI HSM. All harts share this task: the registers of the
+    // running one live in `ctx`, those of the others are parked in `harts`,
+    // and the started harts that are not waiting for an interrupt take turns
+    // at every VM exit.
+    let mut harts: Vec<GuestHart> = (0..num_harts).map(|_| GuestHart::default()).collect();
+    harts[0].state = sbi::HartState::Started;
+    let hart = 0;
+    // VS-level CSRs are tracked per vCPU, across all VMs.
+    let vcpu_id = |hart: usize| cfg.id * MAX_GUEST_HARTS + hart;
+
+    // hgatp is installed whenever this VM is (re)activated on the hart.
+    let hgatp = vm_hgatp(uspace.page_table_root(), vmid.get());
+    let shared_vmid = vmid.is_shared();
+    let console = console::VmConsole::new(cfg.id);
+    // Guest time starts at zero here and is paused while the VM is stopped.
+    let clock = vclock::GuestClock::new();
+    let pause = pause::VmPause::new(cfg.id);
+
+    // ════════════════════════════════════════════════════
+    //  Step 5: Run guest in loop  (h_2_0 style)
+    //
+    //  Exits go to the handlers of `riscv64_exit_dispatcher`:
+    //    - VirtualSupervisorEnvCall (scause 10): SBI calls
+    //    - Guest page faults (scause 20/21/23): emulated MMIO, lazily
+    //      backed RAM, copy-on-write and dirty logging
+    //    - Virtual instructions (scause 22): WFI idles the hart
+    //    - Illegal instructions (scause 2): lazy FP switch, else to the guest
+    //    - Host interrupts: passed to the host's handler, then the guest is
+    //      re-entered. The supervisor timer marks the end of the time slice
+    //      or, without Sstc, the guest's timer deadline (injected via hvip)
+    // ════════════════════════════════════════════════════
+    let mut vcpu = Riscv64Vcpu {
+        cfg,
+        space: uspace,
+        hooks,
+        vmid: vmid.get(),
+        ctx,
+        harts,
+        hart,
+        mmio,
+        console,
+        clock,
+        balloon,
+        shmem,
+        dirty_log,
+        watchdog: watchdog::Watchdog::new(cfg.watchdog),
+        measurement: vm.measurement,
+        wss: wss::WorkingSet::new(
+            cfg.id,
+            cfg.wss_period_ms,
+            axhal::time::monotonic_time_nanos(),
+        ),
+        aia,
+    };
+    if recorder.is_some() {
+        vcpu.mmio.log_io();
+    }
+    // A VM migrated in resumes where its source stopped.
+    if let Some(path) = &cfg.incoming {
+        riscv64_receive(&mut vcpu, path, (PHY_MEM_START, ram_size))?;
+    }
+    let dispatcher = riscv64_exit_dispatcher();
+    vm_println!(cfg.id, "Entering VM run loop...");
+    vcpu.hooks.boot();
+
+    let exit = loop {
+        // Give the other VM tasks a turn before every guest entry.
+        std::thread::yield_now();
+        // Let devices pick up host-side events (console input).
+        vcpu.mmio.poll(vcpu.space);
+        // With the AIA, device interrupts become MSIs to the interrupt file.
+        // QEMU virt numbering: virtio-mmio slot i raises interrupt i + 1.
+        if let Some(aia) = &mut vcpu.aia {
+            aia.update(vcpu.mmio.irq_lines().filter_map(|(base, level)| {
+                let slot = base.checked_sub(VIRTIO_MMIO_BASE)? / VIRTIO_MMIO_STRIDE;
+                virtio_slots.contains(&slot).then_some((slot + 1, level))
+            }));
+        }
+
+        // A paused VM stays parked here, its guest clock stopped.
+        if pause.is_requested() {
+            vcpu.clock.pause();
+            pause.park();
+            vcpu.watchdog.pet();
+        }
+        // A paused guest clock runs again once the guest does.
+        vcpu.clock.resume();
+        // A guest without a sign of life for too long is hung.
+        if let Some(expired) = vcpu.watchdog.expired() {
+            riscv64_crash_dump(cfg.id, &vcpu.ctx, vcpu.space);
+            break watchdog_exit(cfg.id, expired, vcpu.ctx.guest_regs.sepc);
+        }
+        let vmid = vcpu.vmid;
+        sample_working_set(&mut vcpu.wss, vcpu.space, vcpu.hooks, || {
+            tlb::flush_guest_all(vmid)
+        });
+        if let Some(out) = &mut outgoing
+            && out.due(axhal::time::monotonic_time_nanos())
+        {
+            match riscv64_migrate(&mut vcpu, out) {
+                Ok(false) => {}
+                Ok(true) => break Ok(GuestExit::Migrated),
+                Err(e) => {
+                    vm_println!(
+                        cfg.id,
+                        "Migration to {} failed ({:?}), the guest keeps running",
+                        out.path(),
+                        e
+                    );
+                    outgoing = None;
+                }
+            }
+        }
+
+        // Switch to the next started hart that can run: harts idling in WFI
+        // wait for an interrupt.
+        let harts = &mut vcpu.harts;
+        if harts.iter().all(|h| h.state == sbi::HartState::Stopped) {
+            vm_println!(cfg.id, "Guest: all harts stopped");
+            break Ok(GuestExit::Shutdown(0));
+        }
+        let now = vcpu.clock.now();
+        let device_irq = match &vcpu.aia {
+            Some(aia) => aia.file.pending(),
+            None => vcpu.mmio.irq_pending(),
+        };
+        for (i, h) in harts.iter_mut().enumerate() {
+            if h.events.irq_pending(vcpu::IRQ_VS_SOFT)
+                || now >= h.timer_deadline
+                || (i == 0 && device_irq)
+            {
+                h.waiting = false;
+            }
+        }
+        let mut switched = false;
+        let next = (1..=harts.len())
+            .map(|i| (vcpu.hart + i) % harts.len())
+            .find(|&h| harts[h].state != sbi::HartState::Stopped && !harts[h].waiting);
+        match next {
+            None => {
+                // Every started hart waits for an interrupt: sleep until the
+                // first timer deadline, a kick or the next device poll.
+                let timeout = harts
+                    .iter()
+                    .filter(|h| h.state != sbi::HartState::Stopped && h.timer_deadline != u64::MAX)
+                    .map(|h| vcpu.clock.time_until(h.timer_deadline))
+                    .min();
+                // Get frames ready for the guest's next faults meanwhile,
+                // and print what it left in its console ring.
+                vcpu.space.prepare_pool();
+                let _ = vcpu.shmem.drain_console_ring(vcpu.space, &mut vcpu.console);
+                // Idling is waiting for the hypervisor, not being hung.
+                vcpu.watchdog.pet();
+                idle.wait(timeout);
+                continue;
+            }
+            Some(next) if next != vcpu.hart => {
+                core::mem::swap(&mut vcpu.ctx, &mut harts[vcpu.hart].ctx);
+                core::mem::swap(&mut vcpu.ctx, &mut harts[next].ctx);
+                harts[next].state = sbi::HartState::Started;
+                vcpu.hart = next;
+                switched = true;
+            }
+            Some(_) => {}
+        }
+
+        // Disable host interrupts while guest is running (like h_2_0 vcpu_run)
+        let (ctx, hart) = (&mut vcpu.ctx, vcpu.hart);
+        let saved_sstatus: usize;
+        unsafe {
+            core::arch::asm!("csrrci {}, sstatus, 0x2", out(reg) saved_sstatus);
+
+            if ctx.activate(vcpu_id(hart)) {
+                // Another vCPU ran on this hart since this one's last exit.
+                // TLB entries of other VMs carry a different VMID, so no
+                // flush is needed unless the VMID is the shared one. Its VM
+                // may have had other ISA state.
+                core::arch::asm!("csrw hgatp, {}", in(reg) hgatp);
+                if shared_vmid {
+                    tlb::flush_guest_all(vcpu.vmid);
+                }
+                env.apply();
+            }
+            if switched {
+                // The harts of a VM share its VMID: drop the VS-stage
+                // translations of the previous hart, which may come from other
+                // page tables under the same ASID.
+                core::arch::riscv64::hfence_vvma_all();
+            }
+            // The virtual timer is pending iff this VM's deadline has passed.
+            // Device interrupts are level-triggered on the external line of
+            // hart 0, unless its interrupt file raises it. IPIs pend the
+            // software line of their target hart.
+            // With Sstc, the hardware compares the deadline in vstimecmp
+            // instead.
+            let timer_due = !sstc && vcpu.clock.now() >= harts[hart].timer_deadline;
+            let events = &mut harts[hart].events;
+            events.set_irq(vcpu::IRQ_VS_TIMER, timer_due);
+            events.set_irq(
+                vcpu::IRQ_VS_EXTERNAL,
+                hart == 0 && vcpu.aia.is_none() && vcpu.mmio.irq_pending(),
+            );
+            ctx.land_events(events);
+
+            // The guest reads `time` relative to its own clock.
+            CSR.htimedelta.write_value(vcpu.clock.htimedelta());
+            // The host timer ends the run at the guest's timer deadline
+            // (unless the guest's vstimecmp holds it) or, at the latest, when
+            // its time slice is used up. It is the host's scheduler tick as
+            // well: the host handles it once its interrupts are enabled again
+            // after the exit.
+            let slice_end = axhal::time::current_ticks()
+                + axhal::time::nanos_to_ticks(TIME_SLICE.as_nanos() as u64);
+            if sstc {
+                CSR.vstimecmp
+                    .write_value(harts[hart].timer_deadline as usize);
+                sbi_rt::set_timer(slice_end);
+            } else {
+                sbi_rt::set_timer(
+                    vcpu.clock
+                        .to_host(harts[hart].timer_deadline)
+                        .min(slice_end),
+                );
+            }
+            CSR.sie
+                .read_and_set_bits(traps::interrupt::SUPERVISOR_TIMER);
+
+            _run_guest(ctx);
+
+            // Capture the trap state before a host interrupt can clobber it.
+            ctx.trap_csrs.scause = scause::read().bits();
+            core::arch::asm!("csrr {}, stval", out(reg) ctx.trap_csrs.stval);
+            core::arch::asm!("csrr {}, htval", out(reg) ctx.trap_csrs.htval);
+            core::arch::asm!("csrr {}, htinst", out(reg) ctx.trap_csrs.htinst);
+            ctx.save_vs_csrs();
+            ctx.put_guest_fp();
+            // The guest may have moved its deadline without an exit.
+            if sstc {
+                harts[hart].timer_deadline = CSR.vstimecmp.get_value() as u64;
+            }
+            // The guest acknowledges IPIs by clearing sip.SSIP, an alias of
+            // hvip.VSSIP.
+            harts[hart].events.set_irq(
+                vcpu::IRQ_VS_SOFT,
+                CSR.hvip.get_value() & traps::interrupt::VIRTUAL_SUPERVISOR_SOFT != 0,
+            );
+
+            core::arch::asm!("csrs sstatus, {}", in(reg) saved_sstatus & 0x2);
+        }
+
+        let class = riscv64_exit_class(&vcpu);
+        riscv64_exit_hooks(&vcpu, class);
+        let trap = &vcpu.ctx.trap_csrs;
+        tracer.record(
+            vcpu.hart,
+            class.trace_kind(),
+            trap.scause,
+            vcpu.ctx.guest_regs.sepc,
+            [trap.stval, trap.htval],
+        );
+        if class != ExitClass::Interrupt {
+            // Anything but a host interrupt is forward progress.
+            vcpu.watchdog.pet();
+        }
+        let entry = recorder.is_some().then(|| riscv64_exit_entry(&vcpu, class));
+        let flow = dispatcher.dispatch(class, &mut vcpu);
+        if let (Some(recorder), Some(mut entry)) = (&mut recorder, entry) {
+            entry.after = riscv64_exit_snapshot(&vcpu);
+            entry.io = vcpu.mmio.take_io();
+            recorder.record(entry);
+        }
+        if let ControlFlow::Break(exit) = flow {
+            break exit;
+        }
+    };
+    if matches!(exit, Ok(GuestExit::Reboot)) {
+        vcpu.hooks.reset();
+    }
+
+    // Force the next VM entry on this hart to reload hgatp and flush.
+    for hart in 0..vcpu.harts.len() {
+        VmCpuRegisters::deactivate(vcpu_id(hart));
+    }
+    vm_println!(
+        cfg.id,
+        "Guest dirtied {} pages of RAM",
+        vcpu.dirty_log.dirty_count()
+    );
+    let _ = vcpu.shmem.drain_console_ring(vcpu.space, &mut vcpu.console);
+    vcpu.balloon.report();
+    vcpu.wss.report();
+    vcpu.shmem.report();
+    drop(vcpu);
+    vm.print_pool_stats();
+    vm.destroy();
+    return exit;
+
+    /// Sv39x4 hgatp value for the G-stage table at `ept_root`, tagged with `vmid`.
+    fn vm_hgatp(ept_root: PhysAddr, vmid: usize) -> usize {
+        8usize << 60 | vmid << 44 | usize::from(ept_root) >> 12
+    }
+}
+
+/// A guest hart.
+#[cfg(all(feature = "axstd", target_arch = "riscv64"))]
+struct GuestHart {
+    state: sbi::HartState,
+    /// The registers, while another hart runs.
+    ctx: vcpu::VmCpuRegisters,
+    /// Guest `time` value of the hart's next timer event (SBI SetTimer, or
+    /// the guest's `vstimecmp` with Sstc).
+    timer_deadline: u64,
+    /// Interrupts and exceptions to land at the hart's next entry.
+    events: events::PendingEvents,
+    /// The hart executed WFI and waits for an interrupt.
+    waiting: bool,
+    /// Recent fixed-up G-stage faults.
+    faults: refault::FaultHistory,
+    /// The hart's SBI PMU counters.
+    pmu: vpmu::Vpmu,
+}
+
+#[cfg(all(feature = "axstd", target_arch = "riscv64"))]
+impl Default for GuestHart {
+    fn default() -> Self {
+        Self {
+            state: sbi::HartState::Stopped,
+            ctx: vcpu::VmCpuRegisters::default(),
+            timer_deadline: u64::MAX,
+            events: events::PendingEvents::new(),
+            waiting: false,
+            faults: refault::FaultHistory::new(),
+            pmu: vpmu::Vpmu::new(),
+        }
+    }
+}
+
+#[cfg(all(feature = "axstd", target_arch = "riscv64"))]
+fn prepare_guest_context(ctx: &mut vcpu::VmCpuRegisters, entry: usize) {
+    use csrs::defs::hstatus;
+    use csrs::{CSR, RiscvCsrTrait};
+    use tock_registers::LocalRegisterCopy;
+
+    let hstatus_val: usize;
+    unsafe {
+        core::arch::asm!("csrr {}, hstatus", out(reg) hstatus_val);
+    }
+    let mut hstatus_reg = LocalRegisterCopy::<usize, hstatus::Register>::new(hstatus_val);
+    hstatus_reg.modify(hstatus::spv::Guest);
+    hstatus_reg.modify(hstatus::spvp::Supervisor);
+    // WFI in the guest traps (as a virtual instruction) so that an idle
+    // hart does not hold the host hart.
+    hstatus_reg.modify(hstatus::vtw::SET);
+    CSR.hstatus.write_value(hstatus_reg.get());
+    ctx.guest_regs.hstatus = hstatus_reg.get();
+
+    unsafe {
+        riscv::register::sstatus::set_spp(riscv::register::sstatus::SPP::Supervisor);
+    }
+    let sstatus_val: usize;
+    unsafe {
+        core::arch::asm!("csrr {}, sstatus", out(reg) sstatus_val);
+    }
+    // The guest starts with FP disabled (sstatus.FS Off): its FP
+    // registers are loaded on its first FP instruction.
+    ctx.guest_regs.sstatus = sstatus_val & !(3 << 13);
+    ctx.guest_regs.sepc = entry;
+}
+
+/// Runs the next step of the migration of a riscv64 VM out: a pre-copy
+/// round or, once they are over and the hart has no exception to take, the
+/// stop-and-copy. Returns `true` once the guest was migrated, `false` while
+/// it keeps running here. A guest sharing buffers with the hypervisor
+/// fails it with [`AxError::ResourceBusy`].
+///
+/// [`AxError::ResourceBusy`]: axerrno::AxError::ResourceBusy
+#[cfg(all(feature = "axstd", target_arch = "riscv64"))]
+fn riscv64_migrate(vcpu: &mut Riscv64Vcpu, out: &mut migrate::Outgoing) -> axerrno::AxResult<bool> {
+    use axerrno::AxError;
+    use migrate::SendPhase;
+
+    let hart = &vcpu.harts[vcpu.hart];
+    let stop = out.phase() == SendPhase::StopAndCopy;
+    if stop && hart.events.next_exception().is_some() {
+        // Stop once the guest has taken it.
+        return Ok(false);
+    }
+    if vcpu.shmem.shared() > 0 {
+        return Err(AxError::ResourceBusy);
+    }
+    // The pages dirtied since the last step are write-protected again.
+    let dirty = vcpu.dirty_log.clear_and_fetch(vcpu.space)?;
+    if !dirty.is_empty() {
+        tlb::flush_guest_all(vcpu.vmid);
+    }
+    if !stop {
+        let now = axhal::time::monotonic_time_nanos();
+        out.precopy(now, vcpu.space, &dirty)?;
+        return Ok(false);
+    }
+
+    let mut cpu = vcpu.ctx.save_state();
+    cpu.set(vcpu::STATE_VSTIMECMP, hart.timer_deadline);
+    let state = migrate::VmState {
+        cpus: alloc::vec![cpu],
+        devices: vcpu.mmio.save_states(),
+        guest_time: vcpu.clock.now(),
+    };
+    let stats = out.finish(vcpu.space, &dirty, &state)?;
+    vm_println!(
+        vcpu.cfg.id,
+        "Migrated to {}: {} pre-copy rounds, {} pages ({} KB) sent, {} with the guest stopped",
+        out.path(),
+        stats.rounds,
+        stats.pages,
+        stats.bytes / 1024,
+        stats.final_pages
+    );
+    Ok(true)
+}
+
+/// Resumes a riscv64 VM, built as usual, from the migration stream at
+/// `path` instead of booting it: its RAM of `ram.1` bytes at `ram.0`, its
+/// hart's registers and timer, its devices and its guest time.
+#[cfg(all(feature = "axstd", target_arch = "riscv64"))]
+fn riscv64_receive(
+    vcpu: &mut Riscv64Vcpu,
+    path: &str,
+    (ram_base, ram_size): (usize, usize),
+) -> Result<(), VmError> {
+    use alloc::string::ToString;
+    use migrate::MigrationError;
+
+    let fail = |e: MigrationError| VmError::Setup {
+        step: "receive migration",
+        reason: e.to_string(),
+    };
+    vm_println!(vcpu.cfg.id, "Waiting for the migration stream {}...", path);
+    let mut receiver =
+        migrate::MigrationReceiver::new(exitlog::TraceArch::Riscv64, ram_base, ram_size);
+    let state = migrate::receive(path, &mut receiver, vcpu.space).map_err(fail)?;
+    let [cpu] = state.cpus.as_slice() else {
+        return Err(fail(MigrationError::Corrupt));
+    };
+    vcpu.ctx.restore_state(cpu);
+    if let Some(deadline) = cpu.get(vcpu::STATE_VSTIMECMP) {
+        vcpu.harts[vcpu.hart].timer_deadline = deadline;
+    }
+    for (base, data) in &state.devices {
+        vcpu.mmio.restore_state(*base, data).map_err(fail)?;
+    }
+    // The guest time goes on from where it stopped, once the host time has
+    // caught up with it on a host that booted later.
+    let lag = vclock::GuestClock::host_lag(state.guest_time);
+    if !lag.is_zero() {
+        vm_println!(
+            vcpu.cfg.id,
+            "Waiting {} ms for the host time to reach the guest time",
+            lag.as_millis()
+        );
+        std::thread::sleep(lag);
+    }
+    vcpu.clock.set(state.guest_time);
+    vm_println!(
+        vcpu.cfg.id,
+        "Resumed from {}: {} pages received",
+        path,
+        receiver.pages()
+    );
+    Ok(())
+}
+
+/// The state of the harts of a riscv64 VM that their exit handlers work on.
+#[cfg(all(feature = "axstd", target_arch = "riscv64"))]
+struct Riscv64Vcpu<'a> {
+    cfg: &'a config::VmConfig,
+    space: &'a mut gspace::GuestSpace,
+    /// The observers of the VM's lifecycle.
+    hooks: &'a hooks::VmHooks,
+    /// VMID of the VM's G-stage TLB entries.
+    vmid: usize,
+    /// The registers of the running hart.
+    ctx: vcpu::VmCpuRegisters,
+    /// All harts share this task: the registers of the running one live in
+    /// `ctx`, those of the others are parked here.
+    harts: alloc::vec::Vec<GuestHart>,
+    /// The running hart.
+    hart: usize,
+    /// Emulated MMIO devices: accesses to them trap and are decoded from
+    /// htinst.
+    mmio: devices::mmio::MmioBus,
+    console: console::VmConsole,
+    clock: vclock::GuestClock,
+    balloon: balloon::Balloon,
+    shmem: shmem::SharedMem,
+    dirty_log: dirty::DirtyLog,
+    /// Stops the VM when the guest shows no sign of life.
+    watchdog: watchdog::Watchdog,
+    /// SHA-256 digest of the guest image, for GET_MEASUREMENT.
+    measurement: Option<measure::Digest>,
+    /// The working set estimate, for BALLOON_TARGET.
+    wss: wss::WorkingSet,
+    /// The guest interrupt file and APLIC of a VM using the AIA.
+    aia: Option<aia::GuestAia>,
+}
+
+/// Sorts the last exit of a riscv64 hart by its `scause`.
+#[cfg(all(feature = "axstd", target_arch = "riscv64"))]
+fn riscv64_exit_class(vcpu: &Riscv64Vcpu) -> ExitClass {
+    let trap = &vcpu.ctx.trap_csrs;
+    // The interrupt bit is the top bit of scause.
+    if (trap.scause as isize) < 0 {
+        return ExitClass::Interrupt;
+    }
+    let fault_gpa = (trap.htval << 2) | (trap.stval & 0x3);
+    match trap.scause {
+        10 => ExitClass::Sbi,
+        20 | 21 | 23
+            if vcpu.mmio.contains(fault_gpa)
+                || vcpu
+                    .aia
+                    .as_ref()
+                    .is_some_and(|a| a.aplic.contains(fault_gpa)) =>
+        {
+            ExitClass::Mmio
+        }
+        20 | 21 | 23 => ExitClass::Npf,
+        // stval holds the trapping instruction.
+        22 if trap.stval == vinsn::INSN_WFI as usize => ExitClass::Halt,
+        22 => ExitClass::Insn,
+        2 => ExitClass::Fp,
+        _ => ExitClass::Other,
+    }
+}
+
+/// Returns the state of the running hart that exit traces record.
+#[cfg(all(feature = "axstd", target_arch = "riscv64"))]
+fn riscv64_exit_snapshot(vcpu: &Riscv64Vcpu) -> exitlog::ExitSnapshot {
+    let events = &vcpu.harts[vcpu.hart].events;
+    let mut regs = [0; exitlog::MAX_KEY_REGS];
+    for (reg, &value) in regs.iter_mut().zip(vcpu.ctx.guest_regs.gprs.a_regs()) {
+        *reg = value as u64;
+    }
+    exitlog::ExitSnapshot {
+        pc: vcpu.ctx.guest_regs.sepc as u64,
+        regs,
+        irqs: events.irqs(),
+        exception: events.next_exception().map(|e| (e.vector, e.error)),
+    }
+}
+
+/// Starts the exit trace entry of the last exit of a riscv64 hart.
+#[cfg(all(feature = "axstd", target_arch = "riscv64"))]
+fn riscv64_exit_entry(vcpu: &Riscv64Vcpu, class: ExitClass) -> exitlog::ExitEntry {
+    let trap = &vcpu.ctx.trap_csrs;
+    exitlog::ExitEntry {
+        class: class as u8,
+        cpu: vcpu.hart as u32,
+        code: trap.scause as u64,
+        info: [trap.stval as u64, trap.htval as u64],
+        before: riscv64_exit_snapshot(vcpu),
+        ..Default::default()
+    }
+}
+
+/// Tells the VM's observers about the last exit of a riscv64 hart.
+#[cfg(all(feature = "axstd", target_arch = "riscv64"))]
+fn riscv64_exit_hooks(vcpu: &Riscv64Vcpu, class: ExitClass) {
+    let trap = &vcpu.ctx.trap_csrs;
+    vcpu.hooks.exit(class);
+    match class {
+        ExitClass::Npf => vcpu.hooks.npf((trap.htval << 2) | (trap.stval & 0x3)),
+        ExitClass::Sbi => vcpu.hooks.hypercall(vcpu.ctx.guest_regs.gprs.a_regs()[7]),
+        _ => {}
+    }
+}
+
+/// Builds the exit handler table of riscv64 harts.
+#[cfg(all(feature = "axstd", target_arch = "riscv64"))]
+fn riscv64_exit_dispatcher<'a>() -> ExitDispatcher<Riscv64Vcpu<'a>, Result<GuestExit, VmError>> {
+    let mut dispatcher = ExitDispatcher::new(riscv64_exit_unhandled);
+    dispatcher
+        .register(ExitClass::Interrupt, riscv64_exit_interrupt)
+        .register(ExitClass::Sbi, riscv64_exit_sbi)
+        .register(ExitClass::Fp, riscv64_exit_illegal_insn)
+        .register(ExitClass::Halt, riscv64_exit_virtual_insn)
+        .register(ExitClass::Insn, riscv64_exit_virtual_insn)
+        .register(ExitClass::Mmio, riscv64_exit_mmio)
+        .register(ExitClass::Npf, riscv64_exit_npf);
+    dispatcher
+}
+
+/// Host interrupt: it (timer, software or external) stayed pending while
+/// the trap state was read and was taken by the host's handler when
+/// sstatus.SIE was set again: re-enter the guest untouched. A timer
+/// interrupt marks the guest's deadline or the end of its time slice; the
+/// virtual timer interrupt is pended before the next entry if the deadline
+/// has passed, and the next iteration gives the other tasks their turn.
+#[cfg(all(feature = "axstd", target_arch = "riscv64"))]
+fn riscv64_exit_interrupt(_vcpu: &mut Riscv64Vcpu) -> ExitFlow {
+    ControlFlow::Continue(())
+}
+
+/// ECALL from VS-mode: an SBI call, with the extension ID in a7 and the
+/// function ID in a6.
+#[cfg(all(feature = "axstd", target_arch = "riscv64"))]
+fn riscv64_exit_sbi(vcpu: &mut Riscv64Vcpu) -> ExitFlow {
+    use axerrno::AxError;
+    use axhal::mem::PAGE_SIZE_4K;
+    use gmem::GuestMemory;
+    use vcpu::VmCpuRegisters;
+
+    let Riscv64Vcpu {
+        cfg,
+        space,
+        vmid,
+        ctx,
+        harts,
+        hart,
+        console,
+        balloon,
+        shmem,
+        dirty_log,
+        watchdog,
+        measurement,
+        wss,
+        ..
+    } = vcpu;
+    let (uspace, hart, vmid) = (&mut **space, *hart, *vmid);
+    let a7 = ctx.guest_regs.gprs.a_regs()[7]; // extension ID
+    let a6 = ctx.guest_regs.gprs.a_regs()[6]; // function ID
+
+    // ── Hypervisor GET_CMDLINE: a0 = buffer GPA, a1 = its size ──
+    if a7 == boot::SBI_EXT_HYPERVISOR && a6 == boot::SBI_FID_GET_CMDLINE {
+        let (buf, len) = (
+            ctx.guest_regs.gprs.a_regs()[0],
+            ctx.guest_regs.gprs.a_regs()[1],
+        );
+        let cmdline = cfg.cmdline.as_deref().unwrap_or_default();
+        let (error, len) = match boot::copy_cmdline(uspace, cmdline, buf, len) {
+            Ok(len) => (sbi::SBI_SUCCESS, len),
+            Err(_) => (sbi::SBI_ERR_INVALID_ADDRESS as usize, 0),
+        };
+        ctx.guest_regs.gprs.set_reg(regs::GprIndex::A0, error);
+        ctx.guest_regs.gprs.set_reg(regs::GprIndex::A1, len);
+        ctx.guest_regs.sepc += 4;
+        return ControlFlow::Continue(());
+    }
+
+    // ── Hypervisor BALLOON_RELEASE: a0 = start GPA, a1 = size ──
+    if a7 == boot::SBI_EXT_HYPERVISOR && a6 == balloon::SBI_FID_BALLOON_RELEASE {
+        let (start, size) = (
+            ctx.guest_regs.gprs.a_regs()[0],
+            ctx.guest_regs.gprs.a_regs()[1],
+        );
+        let (error, freed) = match balloon.release(uspace, dirty_log, start, size) {
+            Ok(freed) => (sbi::SBI_SUCCESS, freed),
+            Err(_) => (sbi::SBI_ERR_INVALID_ADDRESS as usize, 0),
+        };
+        tlb::flush_guest_range(vmid, start, size);
+        ctx.guest_regs.gprs.set_reg(regs::GprIndex::A0, error);
+        ctx.guest_regs.gprs.set_reg(regs::GprIndex::A1, freed);
+        ctx.guest_regs.sepc += 4;
+        return ControlFlow::Continue(());
+    }
+
+    // ── Hypervisor BALLOON_TARGET: the bytes the guest should release ──
+    if a7 == boot::SBI_EXT_HYPERVISOR && a6 == balloon::SBI_FID_BALLOON_TARGET {
+        let target = wss.balloon_target(uspace.mem_used());
+        ctx.guest_regs
+            .gprs
+            .set_reg(regs::GprIndex::A0, sbi::SBI_SUCCESS);
+        ctx.guest_regs.gprs.set_reg(regs::GprIndex::A1, target);
+        ctx.guest_regs.sepc += 4;
+        return ControlFlow::Continue(());
+    }
+
+    // ── Hypervisor WATCHDOG_PET: a heartbeat of the guest ──
+    if a7 == boot::SBI_EXT_HYPERVISOR && a6 == watchdog::SBI_FID_WATCHDOG_PET {
+        watchdog.pet();
+        ctx.guest_regs
+            .gprs
+            .set_reg(regs::GprIndex::A0, sbi::SBI_SUCCESS);
+        ctx.guest_regs.gprs.set_reg(regs::GprIndex::A1, 0);
+        ctx.guest_regs.sepc += 4;
+        return ControlFlow::Continue(());
+    }
+
+    // ── Hypervisor GET_BOOT_INFO: the address of the boot information page ──
+    if a7 == boot::SBI_EXT_HYPERVISOR && a6 == hvcall_abi::sbi::FID_GET_BOOT_INFO {
+        ctx.guest_regs
+            .gprs
+            .set_reg(regs::GprIndex::A0, sbi::SBI_SUCCESS);
+        ctx.guest_regs
+            .gprs
+            .set_reg(regs::GprIndex::A1, devices::bootinfo::BOOT_INFO_GPA);
+        ctx.guest_regs.sepc += 4;
+        return ControlFlow::Continue(());
+    }
+
+    // ── Hypervisor GET_MEASUREMENT: a0 = buffer GPA, a1 = its size ──
+    if a7 == boot::SBI_EXT_HYPERVISOR && a6 == hvcall_abi::sbi::FID_GET_MEASUREMENT {
+        let (buf, len) = (
+            ctx.guest_regs.gprs.a_regs()[0],
+            ctx.guest_regs.gprs.a_regs()[1],
+        );
+        let (error, len) = match measurement.map(|m| m.copy_to_guest(uspace, buf, len)) {
+            Some(Ok(len)) => (sbi::SBI_SUCCESS, len),
+            Some(Err(_)) => (sbi::SBI_ERR_INVALID_ADDRESS as usize, 0),
+            None => (sbi::SBI_ERR_NOT_SUPPORTED as usize, 0),
+        };
+        ctx.guest_regs.gprs.set_reg(regs::GprIndex::A0, error);
+        ctx.guest_regs.gprs.set_reg(regs::GprIndex::A1, len);
+        ctx.guest_regs.sepc += 4;
+        return ControlFlow::Continue(());
+    }
+
+    // ── Hypervisor shared memory and console ring: a0, a1 ──
+    if let Some(call) = shmem::ShmemCall::from_sbi(a6).filter(|_| a7 == boot::SBI_EXT_HYPERVISOR) {
+        let (arg0, arg1) = (
+            ctx.guest_regs.gprs.a_regs()[0],
+            ctx.guest_regs.gprs.a_regs()[1],
+        );
+        let (error, value) = match shmem.hypercall(call, uspace, console, arg0, arg1) {
+            Ok(value) => (sbi::SBI_SUCCESS as isize, value),
+            Err(AxError::BadAddress) => (sbi::SBI_ERR_INVALID_ADDRESS, 0),
+            Err(AxError::InvalidInput | AxError::NotFound) => (sbi::SBI_ERR_INAVLID_PARAM, 0),
+            Err(_) => (sbi::SBI_ERR_FAILUER, 0),
+        };
+        if call.remaps() {
+            tlb::flush_guest_range(vmid, arg0, arg1);
+        }
+        ctx.guest_regs
+            .gprs
+            .set_reg(regs::GprIndex::A0, error as usize);
+        ctx.guest_regs.gprs.set_reg(regs::GprIndex::A1, value);
+        ctx.guest_regs.sepc += 4;
+        return ControlFlow::Continue(());
+    }
+
+    // ── Standard extensions ──
+    let (error, value) = match sbi::SbiMessage::from_regs(ctx.guest_regs.gprs.a_regs()) {
+        Ok(sbi::SbiMessage::Reset(sbi::ResetFunction::Reset { reset_type, reason })) => {
+            if a7 == sbi_spec::legacy::LEGACY_SHUTDOWN {
+                vm_println!(cfg.id, "Guest: SBI legacy shutdown");
+                return ControlFlow::Break(Ok(GuestExit::Shutdown(0)));
+            }
+            // Cold/warm reset reboots the VM; anything else powers it
+            // off, with the exit code given by the reason.
+            if matches!(
+                reset_type,
+                sbi::ResetType::ColdReset | sbi::ResetType::WarmReset
+            ) {
+                vm_println!(cfg.id, "Guest: SBI SRST reboot");
+                return ControlFlow::Break(Ok(GuestExit::Reboot));
+            }
+            vm_println!(cfg.id, "Guest: SBI SRST shutdown");
+            return ControlFlow::Break(Ok(GuestExit::Shutdown(reason.exit_code())));
+        }
+
+        // ── Legacy SBI PutChar (line-buffered, tagged with the VM id) ──
+        Ok(sbi::SbiMessage::PutChar(ch)) => {
+            console.putchar(ch as u8);
+            ctx.guest_regs.sepc += 4;
+            return ControlFlow::Continue(());
+        }
+
+        // ── SBI SetTimer (TIME extension or legacy) ──
+        Ok(sbi::SbiMessage::SetTimer(deadline)) => {
+            // The host timer, or vstimecmp, is programmed at the next entry.
+            harts[hart].timer_deadline = deadline as u64;
+            harts[hart].pmu.count(vpmu::FwEvent::SetTimer);
+            // Clear guest timer pending
+            harts[hart].events.lower_irq(vcpu::IRQ_VS_TIMER);
+            ctx.guest_regs.gprs.set_reg(regs::GprIndex::A0, 0);
+            ctx.guest_regs.sepc += 4;
+            return ControlFlow::Continue(());
+        }
+
+        // ── Legacy SBI GetChar ──
+        Ok(sbi::SbiMessage::GetChar) => {
+            // -1 without input.
+            let mut ch = [0u8];
+            let c = match console::read_host_input(&mut ch) {
+                0 => usize::MAX,
+                _ => ch[0] as usize,
+            };
+            ctx.guest_regs.gprs.set_reg(regs::GprIndex::A0, c);
+            ctx.guest_regs.sepc += 4;
+            return ControlFlow::Continue(());
+        }
+
+        // ── SBI HSM: start, stop and query the guest's harts ──
+        Ok(sbi::SbiMessage::Hsm(function)) => match function {
+            sbi::HsmFunction::Start {
+                hartid,
+                start_addr,
+                opaque,
+            } => match harts.get_mut(hartid) {
+                None => (sbi::SBI_ERR_INAVLID_PARAM, 0),
+                Some(target) if target.state != sbi::HartState::Stopped => {
+                    (sbi::SBI_ERR_ALREADY_AVAILABLE, 0)
+                }
+                Some(target) => {
+                    // The hart starts in S-mode with the MMU
+                    // off, a0 = hart id and a1 = opaque.
+                    target.ctx = VmCpuRegisters::default();
+                    prepare_guest_context(&mut target.ctx, start_addr);
+                    let gprs = &mut target.ctx.guest_regs.gprs;
+                    gprs.set_reg(regs::GprIndex::A0, hartid);
+                    gprs.set_reg(regs::GprIndex::A1, opaque);
+                    target.timer_deadline = u64::MAX;
+                    target.events.clear();
+                    target.waiting = false;
+                    target.state = sbi::HartState::StartPending;
+                    vm_println!(
+                        cfg.id,
+                        "Guest: hart {} started at {:#x}",
+                        hartid,
+                        start_addr
+                    );
+                    (sbi::SBI_SUCCESS as isize, 0)
+                }
+            },
+            sbi::HsmFunction::Stop => {
+                // Does not return: the hart is parked until
+                // started again.
+                harts[hart].state = sbi::HartState::Stopped;
+                harts[hart].timer_deadline = u64::MAX;
+                return ControlFlow::Continue(());
+            }
+            sbi::HsmFunction::GetStatus { hartid } => match harts.get(hartid) {
+                Some(target) => (sbi::SBI_SUCCESS as isize, target.state as usize),
+                None => (sbi::SBI_ERR_INAVLID_PARAM, 0),
+            },
+            sbi::HsmFunction::Suspend { .. } => (sbi::SBI_ERR_NOT_SUPPORTED, 0),
+        },
+
+        // ── SBI IPI: pend the software interrupt of the target harts ──
+        Ok(sbi::SbiMessage::Ipi(sbi::IpiFunction::SendIpi { hart_mask })) => {
+            if hart_mask.is_within(harts.len()) {
+                for (hartid, target) in harts.iter_mut().enumerate() {
+                    if hart_mask.contains(hartid) {
+                        target.events.raise_irq(vcpu::IRQ_VS_SOFT);
+                        target.pmu.count(vpmu::FwEvent::IpiReceived);
+                    }
+                }
+                harts[hart].pmu.count(vpmu::FwEvent::IpiSent);
+                (sbi::SBI_SUCCESS as isize, 0)
+            } else {
+                (sbi::SBI_ERR_INAVLID_PARAM, 0)
+            }
+        }
+
+        // ── SBI RFENCE: fence the target harts ──
+        //
+        // All harts of the VM run on the host hart of its task, so
+        // fencing this hart covers every target.
+        Ok(sbi::SbiMessage::RemoteFence(fence)) => match fence {
+            _ if !fence.hart_mask().is_within(harts.len()) => (sbi::SBI_ERR_INAVLID_PARAM, 0),
+            _ => {
+                let (sent, received) = match fence {
+                    sbi::RemoteFenceFunction::FenceI { .. } => {
+                        unsafe { core::arch::asm!("fence.i") };
+                        (vpmu::FwEvent::FenceISent, vpmu::FwEvent::FenceIReceived)
+                    }
+                    sbi::RemoteFenceFunction::RemoteSFenceVMA {
+                        start_addr, size, ..
+                    } => {
+                        tlb::flush_guest_vs_range(None, start_addr as usize, size as usize);
+                        (
+                            vpmu::FwEvent::SfenceVmaSent,
+                            vpmu::FwEvent::SfenceVmaReceived,
+                        )
+                    }
+                    sbi::RemoteFenceFunction::RemoteSFenceVMAAsid {
+                        start_addr,
+                        size,
+                        asid,
+                        ..
+                    } => {
+                        let asid = Some(asid as usize);
+                        tlb::flush_guest_vs_range(asid, start_addr as usize, size as usize);
+                        (
+                            vpmu::FwEvent::SfenceVmaAsidSent,
+                            vpmu::FwEvent::SfenceVmaAsidReceived,
+                        )
+                    }
+                };
+                let targets = fence.hart_mask();
+                for (hartid, target) in harts.iter_mut().enumerate() {
+                    if targets.contains(hartid) {
+                        target.pmu.count(received);
+                    }
+                }
+                harts[hart].pmu.count(sent);
+                (sbi::SBI_SUCCESS as isize, 0)
+            }
+        },
+
+        // ── SBI DBCN: the debug console, through the VM's console ──
+        //
+        // Writes and reads are capped at a page per call; the guest
+        // retries with the rest.
+        Ok(sbi::SbiMessage::DebugConsole(function)) => match function {
+            sbi::DebugConsoleFunction::Write { len, addr } => {
+                match uspace.read_slice(addr, len.min(PAGE_SIZE_4K)) {
+                    Ok(bytes) => {
+                        bytes.iter().for_each(|&ch| console.putchar(ch));
+                        (sbi::SBI_SUCCESS as isize, bytes.len())
+                    }
+                    Err(_) => (sbi::SBI_ERR_INAVLID_PARAM, 0),
+                }
+            }
+            sbi::DebugConsoleFunction::Read { len, addr } => {
+                // Check the buffer before taking input the guest
+                // could not receive.
+                let mut buf = alloc::vec![0u8; len.min(PAGE_SIZE_4K)];
+                match uspace.copy_from_guest(addr, &mut buf) {
+                    Ok(()) => {
+                        let n = console::read_host_input(&mut buf);
+                        match uspace.copy_to_guest(addr, &buf[..n]) {
+                            Ok(()) => (sbi::SBI_SUCCESS as isize, n),
+                            Err(_) => (sbi::SBI_ERR_INAVLID_PARAM, 0),
+                        }
+                    }
+                    Err(_) => (sbi::SBI_ERR_INAVLID_PARAM, 0),
+                }
+            }
+            sbi::DebugConsoleFunction::WriteByte(ch) => {
+                console.putchar(ch);
+                (sbi::SBI_SUCCESS as isize, 0)
+            }
+        },
+
+        // ── SBI PMU: the hart's virtual counters ──
+        Ok(sbi::SbiMessage::Pmu(function)) => match harts[hart].pmu.call(function) {
+            Ok(value) => (sbi::SBI_SUCCESS as isize, value),
+            Err(error) => (error, 0),
+        },
+
+        // ── Forward Base and unknown extensions to the real SBI (OpenSBI) ──
+        Ok(sbi::SbiMessage::Base(_)) | Err(sbi::SbiError::UnknownExtension(_)) => {
+            let a = ctx.guest_regs.gprs.a_regs();
+            let (a0, a1, a2, a3, a4, a5) = (a[0], a[1], a[2], a[3], a[4], a[5]);
+            let ret_error: usize;
+            let ret_value: usize;
+            unsafe {
+                core::arch::asm!(
+                    "ecall",
+                    inout("a0") a0 => ret_error,
+                    inout("a1") a1 => ret_value,
+                    in("a2") a2,
+                    in("a3") a3,
+                    in("a4") a4,
+                    in("a5") a5,
+                    in("a6") a6,
+                    in("a7") a7,
+                );
+            }
+            (ret_error as isize, ret_value)
+        }
+
+        // ── Functions and arguments the decoder rejects fail the call ──
+        Err(e) => (e.code(), 0),
+    };
+    ctx.guest_regs
+        .gprs
+        .set_reg(regs::GprIndex::A0, error as usize);
+    ctx.guest_regs.gprs.set_reg(regs::GprIndex::A1, value);
+    ctx.guest_regs.sepc += 4;
+    ControlFlow::Continue(())
+}
+
+/// Illegal instruction: the guest's first FP instruction since the entry,
+/// retried once its FP registers are loaded, or a genuinely illegal one,
+/// which goes to the guest.
+#[cfg(all(feature = "axstd", target_arch = "riscv64"))]
+fn riscv64_exit_illegal_insn(vcpu: &mut Riscv64Vcpu) -> ExitFlow {
+    if !vcpu.ctx.load_guest_fp() {
+        let tval = vcpu.ctx.trap_csrs.stval as u64;
+        vcpu.harts[vcpu.hart].events.push_exception(2, Some(tval));
+    }
+    ControlFlow::Continue(())
+}
+
+/// Virtual instruction: WFI, a counter not enabled in hcounteren, or a
+/// hypervisor-extension instruction.
+#[cfg(all(feature = "axstd", target_arch = "riscv64"))]
+fn riscv64_exit_virtual_insn(vcpu: &mut Riscv64Vcpu) -> ExitFlow {
+    let ctx = &mut vcpu.ctx;
+    let Some(insn) = vinsn::fetch(
+        ctx.trap_csrs.stval,
+        ctx.guest_regs.sepc,
+        ctx.vsatp(),
+        ctx.guest_regs.sstatus,
+        vcpu.space,
+    ) else {
+        return ControlFlow::Break(Err(VmError::InsnFetch {
+            pc: ctx.guest_regs.sepc,
+        }));
+    };
+    let hart = &mut vcpu.harts[vcpu.hart];
+    match vinsn::decode(insn) {
+        Some(vinsn::VirtualInsn::Wfi) => {
+            // Idle until an interrupt is pending for this hart.
+            hart.waiting = true;
+            ctx.guest_regs.sepc += 4;
+        }
+        Some(vinsn::VirtualInsn::Csr(access))
+            if !access.writes()
+                && let Some(value) = vinsn::counter_value(access.csr, vcpu.clock.now()) =>
+        {
+            if let Some(rd) = regs::GprIndex::from_raw(access.rd)
+                && rd != regs::GprIndex::Zero
+            {
+                ctx.guest_regs.gprs.set_reg(rd, value as usize);
+            }
+            ctx.guest_regs.sepc += 4;
+        }
+        _ => {
+            // The guest has no hypervisor extension: raise an illegal
+            // instruction exception (cause 2) in it.
+            hart.events.push_exception(2, Some(insn as u64));
+        }
+    }
+    ControlFlow::Continue(())
+}
+
+/// Guest page fault (G-stage) on an emulated device: a page the device
+/// maps on fault, or a register access, emulated from htinst or, if the
+/// hart left it zero, the instruction.
+#[cfg(all(feature = "axstd", target_arch = "riscv64"))]
+fn riscv64_exit_mmio(vcpu: &mut Riscv64Vcpu) -> ExitFlow {
+    let ctx = &mut vcpu.ctx;
+    let fault_addr = (ctx.trap_csrs.htval << 2) | (ctx.trap_csrs.stval & 0x3);
+    if vcpu
+        .mmio
+        .map_on_fault(vcpu.space, fault_addr, ctx.trap_csrs.scause == 23)
+    {
+        return riscv64_fault_fixed(vcpu, fault_addr);
+    }
+    let access = devices::mmio::decode_htinst(ctx.trap_csrs.htinst, fault_addr).or_else(|| {
+        vinsn::fetch(
+            0,
+            ctx.guest_regs.sepc,
+            ctx.vsatp(),
+            ctx.guest_regs.sstatus,
+            vcpu.space,
+        )
+        .and_then(|insn| devices::mmio::decode_riscv_insn(insn, fault_addr))
+    });
+    let Some(access) = access else {
+        return ControlFlow::Break(Err(VmError::UnsupportedAccess {
+            addr: fault_addr,
+            pc: ctx.guest_regs.sepc,
+        }));
+    };
+    let reg = regs::GprIndex::from_raw(access.reg as u32).unwrap();
+    let value = ctx.guest_regs.gprs.reg(reg) as u64;
+    if let Some(aplic) = vcpu
+        .aia
+        .as_mut()
+        .map(|a| &mut a.aplic)
+        .filter(|a| a.contains(fault_addr))
+    {
+        let offset = access.addr - aplic.base();
+        if access.is_write {
+            aplic.write(offset, access.width, access.store_value(value));
+        } else {
+            let value = access.load_value(aplic.read(offset, access.width));
+            ctx.guest_regs.gprs.set_reg(reg, value as usize);
+        }
+        ctx.guest_regs.sepc += access.insn_len;
+        return ControlFlow::Continue(());
+    }
+    if let Ok(Some(value)) = vcpu.mmio.emulate(vcpu.space, &access, value) {
+        ctx.guest_regs.gprs.set_reg(reg, value as usize);
+    }
+    ctx.guest_regs.sepc += access.insn_len;
+    if access.is_write {
+        // A device may have unmapped pages it mapped on fault.
+        tlb::flush_guest_all(vcpu.vmid);
+    }
+    ControlFlow::Continue(())
+}
+
+/// Guest page fault (G-stage) outside the emulated devices: lazily backed
+/// RAM, copy-on-write and dirty logging. An access the memory's permissions
+/// deny becomes an access fault of the guest.
+#[cfg(all(feature = "axstd", target_arch = "riscv64"))]
+fn riscv64_exit_npf(vcpu: &mut Riscv64Vcpu) -> ExitFlow {
+    let trap = &vcpu.ctx.trap_csrs;
+    let fault_addr = (trap.htval << 2) | (trap.stval & 0x3);
+    let access =
+        permfault::Access::from_riscv64_cause(trap.scause).unwrap_or(permfault::Access::Read);
+    let is_store = access == permfault::Access::Write;
+    let space = &mut *vcpu.space;
+    if space.handle_access_fault(fault_addr.into()) {
+        // A page whose accessed bit the working set sampling cleared: the
+        // fault is not one a fix-up failed to fix.
+        tlb::flush_guest_page(vcpu.vmid, fault_addr & !0xFFF);
+        return ControlFlow::Continue(());
+    }
+    if is_store && space.handle_cow_fault(fault_addr.into()) {
+        // First write to a shared image page: now a private copy.
+        vcpu.dirty_log.record_write(fault_addr);
+    } else if space.handle_page_fault(fault_addr.into()) {
+        // Lazily backed RAM: a whole huge block or fault-around window is
+        // now mapped.
+    } else if let Some(perms) = space.denied_access(fault_addr.into(), access) {
+        let fault = permfault::PermissionFault {
+            gpa: fault_addr,
+            pc: vcpu.ctx.guest_regs.sepc,
+            access,
+            perms,
+        };
+        let cause = access.riscv64_access_fault();
+        vm_println!(
+            vcpu.cfg.id,
+            "Guest permission fault: {}; injecting exception {}",
+            fault,
+            cause
+        );
+        let tval = vcpu.ctx.trap_csrs.stval as u64;
+        vcpu.harts[vcpu.hart]
+            .events
+            .push_exception(cause, Some(tval));
+        return ControlFlow::Continue(());
+    } else if space.mem_limit_reached() {
+        return ControlFlow::Break(Err(VmError::MemoryLimit {
+            used: space.mem_used(),
+        }));
+    } else if is_store && vcpu.dirty_log.handle_write_fault(space, fault_addr) {
+        // First write to a write-protected RAM page: now logged.
+    } else {
+        // Outside every declared region (or a permission the region does
+        // not grant): host memory stays out of reach.
+        return ControlFlow::Break(Err(VmError::UnmappableFault {
+            gpa: fault_addr,
+            pc: vcpu.ctx.guest_regs.sepc,
+        }));
+    }
+    riscv64_fault_fixed(vcpu, fault_addr)
+}
+
+/// Ends a guest page fault at `fault_addr` whose fix-up mapped memory: ends
+/// the VM if the hart keeps faulting there, otherwise drops the stale TLB
+/// entry.
+#[cfg(all(feature = "axstd", target_arch = "riscv64"))]
+fn riscv64_fault_fixed(vcpu: &mut Riscv64Vcpu, fault_addr: usize) -> ExitFlow {
+    if let Some(count) = vcpu.harts[vcpu.hart].faults.record(fault_addr) {
+        riscv64_crash_dump(vcpu.cfg.id, &vcpu.ctx, vcpu.space);
+        return ControlFlow::Break(Err(VmError::RepeatedFault {
+            gpa: fault_addr,
+            pc: vcpu.ctx.guest_regs.sepc,
+            count,
+        }));
+    }
+    tlb::flush_guest_page(vcpu.vmid, fault_addr & !0xFFF);
+    ControlFlow::Continue(())
+}
+
+/// Any other exception ends the VM with a crash report.
+#[cfg(all(feature = "axstd", target_arch = "riscv64"))]
+fn riscv64_exit_unhandled(vcpu: &mut Riscv64Vcpu) -> ExitFlow {
+    let ctx = &vcpu.ctx;
+    riscv64_crash_dump(vcpu.cfg.id, ctx, vcpu.space);
+    ControlFlow::Break(Err(VmError::UnhandledExit {
+        code: ctx.trap_csrs.scause,
+        pc: ctx.guest_regs.sepc,
+        info: [ctx.trap_csrs.stval, ctx.trap_csrs.htval],
+    }))
+}
+
+// ════════════════════════════════════════════════════════════════
+//  AArch64  (EL1 hypervisor — bare-metal guest at EL0)
+//
+//  Since the ArceOS platform crate drops from EL2 to EL1 during
+//  boot, the hypervisor runs at EL1 and the guest at EL0.
+//  The guest uses SVC hypercalls for console I/O, its command line and
+//  shutdown.
+//  Data aborts from EL0 (page faults) are used to demonstrate
+//  on-demand page mapping (analogous to stage-2 page faults).
+// ════════════════════════════════════════════════════════════════
+
+/// The CPU of aarch64 guests: FP/SIMD is switched lazily (`aarch64::fpu`),
+/// and LSE atomics and the crypto instructions bring no state of their own.
+#[cfg(all(feature = "axstd", target_arch = "aarch64"))]
+const AARCH64_CPU_MODEL: cpumodel::GuestCpuModel = cpumodel::GuestCpuModel::baseline()
+    .with(cpumodel::Feature::Fp)
+    .with(cpumodel::Feature::Simd)
+    .with(cpumodel::Feature::Atomics)
+    .with(cpumodel::Feature::Crypto);
+
+/// Sets up the EL1 trap controls of the host CPU the calling VM task is
+/// pinned to.
+#[cfg(all(feature = "axstd", target_arch = "aarch64"))]
+fn aarch64_prepare_cpu() {
+    // Guest WFI at EL0 traps to the hypervisor (EC 0x01) instead of
+    // stopping the host CPU: clear SCTLR_EL1.nTWI. The hypervisor itself
+    // runs at EL1, which the bit does not affect.
+    unsafe {
+        core::arch::asm!(
+            "mrs {tmp}, sctlr_el1",
+            "bic {tmp}, {tmp}, #(1 << 16)",
+            "msr sctlr_el1, {tmp}",
+            "isb",
+            tmp = out(reg) _,
+        );
+    }
+    // Guest counter and timer accesses trap and are emulated per VM.
+    aarch64::vtimer::trap_guest_access();
+    // Guest FP/SIMD accesses trap, so the registers are switched lazily.
+    aarch64::fpu::trap_guest_access();
+}
+
+#[cfg(all(feature = "axstd", target_arch = "aarch64"))]
+fn aarch64_main() {
+    ax_println!("Hypervisor ...");
+    // Puts the host's TTBR0_EL1 back before the power-off.
+    let host = hostguard::HostGuard::new();
+
+    // The VMs take turns on TTBR0_EL1; put the host's value back at the end.
+    let host_ttbr0: u64;
+    unsafe {
+        core::arch::asm!("mrs {}, ttbr0_el1", out(reg) host_ttbr0);
+    }
+    let status = run_vms(move |cfg| aarch64_run_vm(cfg, host_ttbr0));
+
+    ax_println!("Hypervisor ok!");
+    exit::report(status);
+    drop(host);
+    // Shutdown QEMU via PSCI SYSTEM_OFF (SMC at EL3)
+    unsafe {
+        core::arch::asm!(
+            "movz x0, #0x0008",
+            "movk x0, #0x8400, lsl #16",
+            "smc  #0",
+            options(noreturn)
+        );
+    }
+}
+
+/// Prints the crash report of an aarch64 guest at an unhandled exit. The
+/// guest runs at EL0 on the hypervisor's stage-1 tables, so its virtual
+/// addresses are intermediate physical addresses.
+#[cfg(all(feature = "axstd", target_arch = "aarch64"))]
+fn aarch64_crash_dump(vm: usize, ctx: &aarch64::vcpu::VmCpuRegisters, space: &gspace::GuestSpace) {
+    const NAMES: [&str; 31] = [
+        "x0", "x1", "x2", "x3", "x4", "x5", "x6", "x7", "x8", "x9", "x10", "x11", "x12", "x13",
+        "x14", "x15", "x16", "x17", "x18", "x19", "x20", "x21", "x22", "x23", "x24", "x25", "x26",
+        "x27", "x28", "x29", "x30",
+    ];
+    let mut gprs = [("sp", ctx.guest.sp); 32];
+    for (i, slot) in gprs[..31].iter_mut().enumerate() {
+        *slot = (NAMES[i], ctx.guest.gprs.0[i]);
+    }
+    let sysregs = [
+        ("elr", ctx.guest.elr),
+        ("spsr", ctx.guest.spsr),
+        ("esr", ctx.trap.esr),
+        ("far", ctx.trap.far),
+    ];
+    dump::CrashDump {
+        gprs: &gprs,
+        sysregs: &sysregs,
+        pc_gpa: Some(ctx.guest.elr as usize),
+        fault_gpa: Some(ctx.trap.far as usize),
+    }
+    .print(vm, space);
+}
+
+/// Builds the guest VM from scratch and runs it until it shuts down or
+/// requests a reboot. TTBR0_EL1 is reset to `host_ttbr0` if it still points
+/// at this VM, and the guest address space is freed on return.
+#[cfg(all(feature = "axstd", target_arch = "aarch64"))]
+fn aarch64_run_vm(cfg: &config::VmConfig, host_ttbr0: u64) -> Result<GuestExit, VmError> {
+    use aarch64::vcpu::VmCpuRegisters;
+    use alloc::boxed::Box;
+    use alloc::sync::Arc;
+    use alloc::vec::Vec;
+    use axhal::paging::MappingFlags;
+    use devices::virtio::mmio::{VIRTIO_MMIO_SIZE, VirtioMmio};
+    use gmem::GuestMemory;
+    use memory_addr::va;
+
+    aarch64_prepare_cpu();
+    check_migratable(cfg)?;
+
+    // ── 1. Create guest address space ──
+    // Must cover pflash (0x04000000) and guest RAM (0x40000000, up to 64 MB)
+    let mut vm = vm::Vm::new(cfg, va!(0x0), 0x4400_0000, host_ttbr0 as usize)
+        .map_err(VmError::setup("create VM"))?;
+    // The machine has a single UART: this only reports that it stays shared.
+    vm_claim_serial(cfg);
+    let (uspace, map) = (&mut vm.space, &mut vm.map);
+
+    let flags =
+        MappingFlags::READ | MappingFlags::WRITE | MappingFlags::EXECUTE | MappingFlags::USER;
+
+    // pflash1: the image from the disk, or QEMU's flash mapped read-only
+    map.add(
+        memmap::RegionKind::Pflash,
+        "pflash",
+        PFLASH_START,
+        PFLASH_SIZE,
+    )?;
+    let mut pflash =
+        devices::pflash::PFlash::open(PFLASH_START, PFLASH_SIZE, Some(PFLASH_START.into()), true)
+            .map_err(VmError::setup("create pflash"))?;
+    pflash
+        .attach(uspace)
+        .map_err(VmError::setup("map pflash"))?;
+    vm_println!(
+        cfg.id,
+        "PFlash at {:#x} ({} MB, {})",
+        pflash.base(),
+        pflash.size() / (1024 * 1024),
+        pflash.kind()
+    );
+
+    // Woken by the device workers when they complete requests, while the
+    // guest idles.
+    let idle = Arc::new(idle::IdleQueue::new());
+    // Emulated MMIO devices, decoded from the data abort syndrome. An EL0
+    // guest has no interrupt controller, so drivers poll their used rings.
+    let mut mmio = devices::mmio::MmioBus::new();
+    mmio.add(Box::new(pflash)).expect("add pflash");
+    // virtio-mmio slots in use, described in the device tree.
+    let mut virtio_slots = Vec::new();
+    if let Some(blk) = open_vm_disk(cfg, &idle) {
+        mmio.add(Box::new(VirtioMmio::new(VIRTIO_MMIO_BASE, Box::new(blk))))
+            .expect("add virtio-blk");
+        virtio_slots.push(0);
+    }
+    mmio.add(Box::new(VirtioMmio::new(
+        VIRTIO_MMIO_BASE + VIRTIO_MMIO_STRIDE,
+        Box::new(vm_virtio_console(cfg, &idle)),
+    )))
+    .expect("add virtio-console");
+    mmio.add(Box::new(VirtioMmio::new(
+        VIRTIO_MMIO_BASE + 2 * VIRTIO_MMIO_STRIDE,
+        Box::new(vm_virtio_net(cfg)),
+    )))
+    .expect("add virtio-net");
+    virtio_slots.extend([1, 2]);
+    declare_virtio_mmio(map, &virtio_slots)?;
+    mmio.add(Box::new(devices::pl031::Pl031::new(RTC_BASE)))
+        .expect("add RTC");
+    map.add(
+        memmap::RegionKind::Mmio,
+        "RTC",
+        RTC_BASE,
+        devices::pl031::PL031_SIZE,
+    )?;
+
+    // ── 2. Load guest binary into guest RAM ──
+    // The image is shared copy-on-write with other VMs running it; the RAM
+    // around it (including the stack) is freshly allocated.
+    const RAM_START: usize = 0x4000_0000;
+    const RAM_SIZE: usize = 0x200_0000; // 32 MB
+    vm_println!(cfg.id, "app: {}", cfg.image);
+    let image = loader::shared_image(&cfg.image).map_err(VmError::setup("open guest image"))?;
+    vm_println!(
+        cfg.id,
+        "Loaded {} bytes from {} (shared by {} VMs)",
+        image.len(),
+        cfg.image,
+        alloc::sync::Arc::strong_count(&image)
+    );
+    vm.measurement = measure_image(cfg, &image)?;
+    let package = loader::package(&cfg.image, &image, VM_ENTRY)
+        .transpose()
+        .map_err(VmError::setup("parse guest package"))?;
+    let (entry, ram_size) = match &package {
+        Some(pkg) => {
+            let ram_size = RAM_SIZE.max(pkg.ram_size as usize);
+            let fdt = boot::fdt_gpa(RAM_START, ram_size);
+            if !pkg.fits(RAM_START as u64, fdt as u64) {
+                return Err(VmError::Setup {
+                    step: "place package in guest RAM",
+                    reason: "segments outside guest RAM".into(),
+                });
+            }
+            vm_println!(
+                cfg.id,
+                "Package: {} segments at {:#x}, entry {:#x}, {} KB RAM",
+                pkg.segments.len(),
+                pkg.span().0,
+                pkg.entry,
+                ram_size / 1024
+            );
+            (pkg.entry as usize, ram_size)
+        }
+        None => (VM_ENTRY, RAM_SIZE),
+    };
+    let (image_start, image_size) = loader::image_span(&image, package.as_ref(), entry);
+    map.add(memmap::RegionKind::Ram, "guest RAM", RAM_START, ram_size)?;
+    map.add(
+        memmap::RegionKind::Image,
+        if package.is_some() {
+            "package"
+        } else {
+            "image"
+        },
+        image_start,
+        image_size,
+    )?;
+    match &package {
+        Some(pkg) => loader::map_ram_with_package(uspace, RAM_START, ram_size, &image, pkg, flags),
+        None => loader::map_ram_with_image(uspace, RAM_START, ram_size, entry, image, flags),
+    }
+    .map_err(VmError::setup("map guest RAM"))?;
+
+    // ── 3. Guest stack, device tree and initrd, all in guest RAM ──
+    const STACK_SIZE: usize = 0x8000; // 32KB
+    const STACK_BASE: usize = 0x4100_0000;
+    const STACK_TOP: usize = STACK_BASE + STACK_SIZE;
+    vm_println!(cfg.id, "Guest stack: {:#x} - {:#x}", STACK_BASE, STACK_TOP);
+    map.add(memmap::RegionKind::Stack, "stack", STACK_BASE, STACK_SIZE)?;
+
+    let fdt_gpa = boot::fdt_gpa(RAM_START, ram_size);
+    map.add(
+        memmap::RegionKind::Dtb,
+        "device tree",
+        fdt_gpa,
+        boot::FDT_MAX_SIZE,
+    )?;
+    let initrd = load_vm_initrd(
+        cfg,
+        uspace,
+        (image_start + image_size).max(STACK_TOP),
+        fdt_gpa,
+    );
+    if let Some((start, end)) = initrd {
+        map.add(memmap::RegionKind::Initrd, "initrd", start, end - start)?;
+    }
+    let fdt = fdt::guest_fdt(&fdt::GuestLayout {
+        ram_start: RAM_START,
+        ram_size,
+        num_cpus: 1,
+        initrd,
+        bootargs: cfg.cmdline.clone(),
+        // QEMU virt numbering: virtio-mmio slot i raises SPI 16 + i.
+        virtio_mmio: virtio_slots
+            .iter()
+            .map(|&i| {
+                (
+                    VIRTIO_MMIO_BASE + i * VIRTIO_MMIO_STRIDE,
+                    VIRTIO_MMIO_SIZE,
+                    16 + i as u32,
+                )
+            })
+            .collect(),
+        rtc: Some((RTC_BASE, devices::pl031::PL031_SIZE)),
+        aia: false,
+        isa: alloc::string::String::new(),
+    });
+    assert!(fdt.len() <= boot::FDT_MAX_SIZE, "device tree too large");
+    uspace
+        .copy_to_guest(fdt_gpa, &fdt)
+        .map_err(VmError::setup("write device tree"))?;
+    vm_println!(cfg.id, "Device tree: {} bytes at {:#x}", fdt.len(), fdt_gpa);
+    // The same for guests without a device tree parser.
+    {
+        use hvcall_abi::bootinfo::device;
+        let devices = [
+            (device::PFLASH, PFLASH_START, PFLASH_SIZE),
+            (device::PL031, RTC_BASE, devices::pl031::PL031_SIZE),
+        ]
+        .into_iter()
+        .chain(virtio_mmio_boot_info(&virtio_slots));
+        add_boot_info(cfg, map, &mut mmio, 1, (RAM_START, ram_size), devices)?;
+    }
+    vm.print_memory_map();
+    let (uspace, asid, hooks) = (&mut vm.space, &vm.vmid, &vm.hooks);
+    // Zeroed frames for the faults of the guest's boot.
+    uspace.prepare_pool();
+
+    // Track guest RAM writes; the TLB flush on the first TTBR0 switch to
+    // this VM covers the write-protection done here.
+    let mut dirty_log = dirty::DirtyLog::new(RAM_START, ram_size, flags)
+        .map_err(VmError::setup("create dirty log"))?;
+    dirty_log
+        .enable(uspace)
+        .map_err(VmError::setup("enable dirty log"))?;
+    // Stage-2 faults whose fix-up does not stick end the VM.
+    let faults = refault::FaultHistory::new();
+    let mut tracer = trace::ExitTracer::new(cfg.id, cfg.trace);
+    let mut recorder = exit_recorder(cfg, exitlog::TraceArch::Aarch64);
+    let balloon = balloon::Balloon::new(cfg.id);
+    let shmem = shmem::SharedMem::new(cfg.id);
+
+    // ── 4. Guest page table root and (shared) ASID, installed in TTBR0_EL1 on every entry ──
+    let guest_ttbr0: u64 = usize::from(uspace.page_table_root()) as u64 | (asid.get() as u64) << 48;
+
+    // ── 5. Prepare guest context ──
+    let mut ctx = VmCpuRegisters::default();
+    ctx.guest.elr = entry as u64;
+    // EL0t with IRQ/FIQ unmasked: host interrupts (the scheduler tick) target
+    // EL1 and end the guest's run. The guest cannot mask them, as EL0 has no
+    // access to DAIF (SCTLR_EL1.UMA is clear).
+    ctx.guest.spsr = 0x300; // EL0t, D and A masked
+    ctx.guest.sp = STACK_TOP as u64;
+    // x0 = device tree address, as the arm64 Linux boot protocol passes it.
+    ctx.guest.gprs.set_x(0, fdt_gpa as u64);
+
+    // ── 6. Run guest in loop ──
+    let pause = pause::VmPause::new(cfg.id);
+    // Pending lines only end WFI idling: the guest at EL0 takes no
+    // interrupts.
+    let mut events = events::PendingEvents::new();
+    let mut vcpu = Aarch64Vcpu {
+        cfg,
+        space: uspace,
+        hooks,
+        asid: asid.get(),
+        flags,
+        ctx,
+        mmio,
+        console: console::VmConsole::new(cfg.id),
+        balloon,
+        shmem,
+        dirty_log,
+        faults,
+        fp: aarch64::fpu::GuestFp::new(),
+        vtimer: aarch64::vtimer::GuestTimer::new(),
+        halted: false,
+        watchdog: watchdog::Watchdog::new(cfg.watchdog),
+        measurement: vm.measurement,
+        wss: wss::WorkingSet::new(
+            cfg.id,
+            cfg.wss_period_ms,
+            axhal::time::monotonic_time_nanos(),
+        ),
+    };
+    if recorder.is_some() {
+        vcpu.mmio.log_io();
+    }
+    let dispatcher = aarch64_exit_dispatcher();
+    vm_println!(cfg.id, "Entering VM run loop...");
+    vcpu.hooks.boot();
+    let exit = loop {
+        // Give the other VM tasks a turn before every guest entry.
+        std::thread::yield_now();
+        // A paused VM stays parked here.
+        if pause.is_requested() {
+            pause.park();
+            vcpu.watchdog.pet();
+        }
+        // A guest without a sign of life for too long is hung.
+        if let Some(expired) = vcpu.watchdog.expired() {
+            aarch64_crash_dump(cfg.id, &vcpu.ctx, vcpu.space);
+            break watchdog_exit(cfg.id, expired, vcpu.ctx.guest.elr as usize);
+        }
+        let asid = vcpu.asid;
+        sample_working_set(&mut vcpu.wss, vcpu.space, vcpu.hooks, || {
+            tlb::flush_guest_all(asid)
+        });
+        // Let devices pick up host-side events (console input).
+        vcpu.mmio.poll(vcpu.space);
+        events.set_irq(aarch64::vcpu::IRQ_DEVICES, vcpu.mmio.irq_pending());
+        events.set_irq(aarch64::vcpu::IRQ_VTIMER, vcpu.vtimer.pending());
+        if vcpu.halted {
+            if events.irqs() == 0 {
+                // Get frames ready for the guest's next faults meanwhile.
+                vcpu.space.prepare_pool();
+                let _ = vcpu.shmem.drain_console_ring(vcpu.space, &mut vcpu.console);
+                // Idling is waiting for the hypervisor, not being hung.
+                vcpu.watchdog.pet();
+                idle.wait(vcpu.vtimer.time_until());
+                continue;
+            }
+            vcpu.halted = false;
+        }
+
+        // No task switch may happen between installing our TTBR0 and entering
+        // the guest, or another VM could run on our page table.
+        let irqs_were_enabled = axhal::asm::irqs_enabled();
+        axhal::asm::disable_irqs();
+        unsafe {
+            switch_ttbr0(guest_ttbr0);
+            aarch64::vcpu::_run_guest(&mut vcpu.ctx);
+        }
+        vcpu.fp.put_guest();
+        if irqs_were_enabled {
+            axhal::asm::enable_irqs();
+        }
+
+        let class = aarch64_exit_class(&vcpu);
+        aarch64_exit_hooks(&vcpu, class);
+        let trap = &vcpu.ctx.trap;
+        tracer.record(
+            0,
+            class.trace_kind(),
+            ((trap.esr >> 26) & 0x3F) as usize,
+            vcpu.ctx.guest.elr as usize,
+            [trap.esr as usize, trap.far as usize],
+        );
+        if class != ExitClass::Interrupt {
+            // Anything but a host interrupt is forward progress.
+            vcpu.watchdog.pet();
+        }
+        let entry = recorder.is_some().then(|| aarch64_exit_entry(&vcpu, class));
+        let flow = dispatcher.dispatch(class, &mut vcpu);
+        if let (Some(recorder), Some(mut entry)) = (&mut recorder, entry) {
+            entry.after = aarch64_exit_snapshot(&vcpu);
+            entry.io = vcpu.mmio.take_io();
+            recorder.record(entry);
+        }
+        if let ControlFlow::Break(exit) = flow {
+            break exit;
+        }
+    };
+    if matches!(exit, Ok(GuestExit::Reboot)) {
+        vcpu.hooks.reset();
+    }
+    vm_println!(
+        cfg.id,
+        "Guest dirtied {} pages of RAM",
+        vcpu.dirty_log.dirty_count()
+    );
+    let _ = vcpu.shmem.drain_console_ring(vcpu.space, &mut vcpu.console);
+    vcpu.balloon.report();
+    vcpu.wss.report();
+    vcpu.shmem.report();
+    drop(vcpu);
+    vm.print_pool_stats();
+
+    // ── 7. Detach TTBR0_EL1 from the page table and free the guest's memory ──
+    vm.destroy();
+
+    return exit;
+
+    /// Installs `ttbr0` and flushes the EL1&0 TLB if it is not already loaded.
+    ///
+    /// The flush is global because `page_table_multiarch` creates global (nG
+    /// clear) guest mappings, which match any ASID; for the same reason all
+    /// VMs share ASID 0 ([`crate::vmid`]).
+    unsafe fn switch_ttbr0(ttbr0: u64) {
+        let cur: u64;
+        unsafe {
+            core::arch::asm!("mrs {}, ttbr0_el1", out(reg) cur);
+            if cur != ttbr0 {
+                core::arch::asm!(
+                    "msr ttbr0_el1, {val}",
+                    "isb",
+                    "tlbi vmalle1is",
+                    "dsb ish",
+                    "isb",
+                    val = in(reg) ttbr0,
+                );
+            }
+        }
+    }
+}
+
+/// The state of an aarch64 vCPU that its exit handlers work on.
+#[cfg(all(feature = "axstd", target_arch = "aarch64"))]
+struct Aarch64Vcpu<'a> {
+    cfg: &'a config::VmConfig,
+    space: &'a mut gspace::GuestSpace,
+    /// The observers of the VM's lifecycle.
+    hooks: &'a hooks::VmHooks,
+    /// ASID of the VM's TLB entries.
+    asid: usize,
+    /// Flags of guest RAM and passthrough mappings.
+    flags: axhal::paging::MappingFlags,
+    ctx: aarch64::vcpu::VmCpuRegisters,
+    /// Emulated MMIO devices, decoded from the data abort syndrome. An EL0
+    /// guest has no interrupt controller, so drivers poll their used rings.
+    mmio: devices::mmio::MmioBus,
+    console: console::VmConsole,
+    balloon: balloon::Balloon,
+    shmem: shmem::SharedMem,
+    dirty_log: dirty::DirtyLog,
+    /// Stage-2 faults whose fix-up does not stick end the VM.
+    faults: refault::FaultHistory,
+    fp: aarch64::fpu::GuestFp,
+    /// The guest's counter and virtual timer.
+    vtimer: aarch64::vtimer::GuestTimer,
+    /// The guest executed WFI and waits for an interrupt.
+    halted: bool,
+    /// Stops the VM when the guest shows no sign of life.
+    watchdog: watchdog::Watchdog,
+    /// SHA-256 digest of the guest image, for GET_MEASUREMENT.
+    measurement: Option<measure::Digest>,
+    /// The working set estimate, for BALLOON_TARGET.
+    wss: wss::WorkingSet,
+}
+
+/// Sorts the last exit of an aarch64 vCPU by its exception class.
+#[cfg(all(feature = "axstd", target_arch = "aarch64"))]
+fn aarch64_exit_class(vcpu: &Aarch64Vcpu) -> ExitClass {
+    // ESR_EL1 is NOT updated for asynchronous exceptions (IRQ/FIQ/SError),
+    // so they are told apart by the vector entry.
+    if vcpu.ctx.trap.is_irq != 0 {
+        return ExitClass::Interrupt;
+    }
+    match (vcpu.ctx.trap.esr >> 26) & 0x3F {
+        0x01 => ExitClass::Halt,
+        0x07 => ExitClass::Fp,
+        0x15 => ExitClass::Hypercall,
+        aarch64::idregs::ESR_EC_SYSREG => ExitClass::Insn,
+        0x24 if vcpu.mmio.contains(vcpu.ctx.trap.far as usize) => ExitClass::Mmio,
+        // Instruction and data aborts from EL0.
+        0x20 | 0x24 => ExitClass::Npf,
+        // Breakpoint, software step and watchpoint from EL0, and BRK.
+        0x30 | 0x32 | 0x34 | 0x3C => ExitClass::Debug,
+        _ => ExitClass::Other,
+    }
+}
+
+/// Returns the state of an aarch64 vCPU that exit traces record. The
+/// guest at EL0 takes no injected events.
+#[cfg(all(feature = "axstd", target_arch = "aarch64"))]
+fn aarch64_exit_snapshot(vcpu: &Aarch64Vcpu) -> exitlog::ExitSnapshot {
+    let mut regs = [0; exitlog::MAX_KEY_REGS];
+    for (n, reg) in regs.iter_mut().enumerate() {
+        *reg = vcpu.ctx.guest.gprs.x(n);
+    }
+    exitlog::ExitSnapshot {
+        pc: vcpu.ctx.guest.elr,
+        regs,
+        ..Default::default()
+    }
+}
+
+/// Starts the exit trace entry of the last exit of an aarch64 vCPU.
+#[cfg(all(feature = "axstd", target_arch = "aarch64"))]
+fn aarch64_exit_entry(vcpu: &Aarch64Vcpu, class: ExitClass) -> exitlog::ExitEntry {
+    let trap = &vcpu.ctx.trap;
+    exitlog::ExitEntry {
+        class: class as u8,
+        code: (trap.esr >> 26) & 0x3F,
+        info: [trap.esr, trap.far],
+        before: aarch64_exit_snapshot(vcpu),
+        ..Default::default()
+    }
+}
+
+/// Tells the VM's observers about the last exit of an aarch64 vCPU. The
+/// guest runs on the hypervisor's stage-1 tables, so the fault address is
+/// the guest physical address.
+#[cfg(all(feature = "axstd", target_arch = "aarch64"))]
+fn aarch64_exit_hooks(vcpu: &Aarch64Vcpu, class: ExitClass) {
+    vcpu.hooks.exit(class);
+    match class {
+        ExitClass::Npf => vcpu.hooks.npf(vcpu.ctx.trap.far as usize),
+        ExitClass::Hypercall => vcpu.hooks.hypercall(vcpu.ctx.guest.gprs.0[8] as usize),
+        _ => {}
+    }
+}
+
+/// Builds the exit handler table of aarch64 vCPUs.
+#[cfg(all(feature = "axstd", target_arch = "aarch64"))]
+fn aarch64_exit_dispatcher<'a>() -> ExitDispatcher<Aarch64Vcpu<'a>, Result<GuestExit, VmError>> {
+    let mut dispatcher = ExitDispatcher::new(aarch64_exit_unhandled);
+    dispatcher
+        .register(ExitClass::Interrupt, aarch64_exit_interrupt)
+        .register(ExitClass::Fp, aarch64_exit_fp)
+        .register(ExitClass::Halt, aarch64_exit_halt)
+        .register(ExitClass::Hypercall, aarch64_exit_hypercall)
+        .register(ExitClass::Insn, aarch64_exit_insn)
+        .register(ExitClass::Mmio, aarch64_exit_mmio)
+        .register(ExitClass::Npf, aarch64_exit_npf);
+    dispatcher
+}
+
+/// Asynchronous exit (IRQ/FIQ/SError): the interrupt stayed pending and
+/// was handled by the host once its interrupts were enabled again. ESR is
+/// not interpreted and ELR not advanced; the next iteration gives the
+/// other tasks their turn.
+#[cfg(all(feature = "axstd", target_arch = "aarch64"))]
+fn aarch64_exit_interrupt(_vcpu: &mut Aarch64Vcpu) -> ExitFlow {
+    ControlFlow::Continue(())
+}
+
+/// First FP/SIMD access since the entry: load the guest's registers and
+/// retry the instruction (ELR points at it).
+#[cfg(all(feature = "axstd", target_arch = "aarch64"))]
+fn aarch64_exit_fp(vcpu: &mut Aarch64Vcpu) -> ExitFlow {
+    vcpu.fp.load_guest();
+    ControlFlow::Continue(())
+}
+
+/// Trapped WFI: ELR points at the instruction. Idle until a device raises
+/// an interrupt or the guest's timer fires.
+#[cfg(all(feature = "axstd", target_arch = "aarch64"))]
+fn aarch64_exit_halt(vcpu: &mut Aarch64Vcpu) -> ExitFlow {
+    vcpu.ctx.guest.elr += 4;
+    vcpu.halted = true;
+    ControlFlow::Continue(())
+}
+
+/// MRS of an ID register: the guest reads what its CPU model reports. MRS
+/// and MSR of a counter or virtual timer register: emulated on the guest's
+/// timer. Other system register accesses end the VM.
+#[cfg(all(feature = "axstd", target_arch = "aarch64"))]
+fn aarch64_exit_insn(vcpu: &mut Aarch64Vcpu) -> ExitFlow {
+    use aarch64::idregs::{SysregAccess, host_value};
+
+    let access = SysregAccess::decode(vcpu.ctx.trap.esr);
+    let gprs = &mut vcpu.ctx.guest.gprs;
+    if access.read {
+        let value = match vcpu.vtimer.read(access.reg) {
+            Some(value) => value,
+            None if cpumodel::aarch64::is_id_reg(access.reg) => {
+                AARCH64_CPU_MODEL.aarch64_id_reg(access.reg, host_value(access.reg))
+            }
+            None => return aarch64_exit_unhandled(vcpu),
+        };
+        if access.rt != 31 {
+            gprs.set_x(access.rt, value);
+        }
+    } else {
+        // Rt 31 is XZR.
+        let value = if access.rt == 31 {
+            0
+        } else {
+            gprs.x(access.rt)
+        };
+        if !vcpu.vtimer.write(access.reg, value) {
+            return aarch64_exit_unhandled(vcpu);
+        }
+    }
+    vcpu.ctx.guest.elr += 4;
+    ControlFlow::Continue(())
+}
+
+/// SVC from EL0: hypercall with the function ID in x8 and arguments in x0
+/// and x1.
+///
+/// On AArch64, ELR_EL1 for SVC already points to the instruction AFTER the
+/// SVC (the "preferred return address"). This differs from RISC-V where
+/// sepc points to the ecall itself. Therefore ELR is NOT advanced here.
+#[cfg(all(feature = "axstd", target_arch = "aarch64"))]
+fn aarch64_exit_hypercall(vcpu: &mut Aarch64Vcpu) -> ExitFlow {
+    use aarch64::hvc::GuestMessage;
+
+    let ctx = &mut vcpu.ctx;
+    let func = ctx.guest.gprs.0[8]; // x8
+    match func {
+        hvcall_abi::PUTCHAR => {
+            // putchar: x0 = character
+            vcpu.console.putchar(ctx.guest.gprs.0[0] as u8);
+        }
+        hvcall_abi::EXIT => {
+            // exit: x0 = exit code
+            return ControlFlow::Break(Ok(GuestExit::Shutdown(ctx.guest.gprs.x(0) as u32)));
+        }
+        boot::HYPERCALL_GET_CMDLINE => {
+            // x0 = buffer GPA, x1 = its size; returns the length in x0, or
+            // -1 if the buffer is not guest memory.
+            let cmdline = vcpu.cfg.cmdline.as_deref().unwrap_or_default();
+            let ret = boot::copy_cmdline(
+                vcpu.space,
+                cmdline,
+                ctx.guest.gprs.x(0) as usize,
+                ctx.guest.gprs.x(1) as usize,
+            );
+            ctx.guest
+                .gprs
+                .set_x(0, ret.map_or(u64::MAX, |len| len as u64));
+        }
+        balloon::HYPERCALL_BALLOON_RELEASE => {
+            // x0 = start GPA, x1 = size; returns the bytes freed in x0, or
+            // -1 if the range is not page aligned guest memory.
+            let (start, size) = (ctx.guest.gprs.x(0) as usize, ctx.guest.gprs.x(1) as usize);
+            let ret = vcpu
+                .balloon
+                .release(vcpu.space, &mut vcpu.dirty_log, start, size);
+            tlb::flush_guest_range(vcpu.asid, start, size);
+            ctx.guest
+                .gprs
+                .set_x(0, ret.map_or(u64::MAX, |freed| freed as u64));
+        }
+        balloon::HYPERCALL_BALLOON_TARGET => {
+            // Returns the bytes the guest should release in x0.
+            let target = vcpu.wss.balloon_target(vcpu.space.mem_used());
+            ctx.guest.gprs.set_x(0, target as u64);
+        }
+        watchdog::HYPERCALL_WATCHDOG_PET => {
+            // Heartbeat of the guest; returns 0 in x0.
+            vcpu.watchdog.pet();
+            ctx.guest.gprs.set_x(0, 0);
+        }
+        hvcall_abi::GET_BOOT_INFO => {
+            ctx.guest
+                .gprs
+                .set_x(0, devices::bootinfo::BOOT_INFO_GPA as u64);
+        }
+        hvcall_abi::GET_MEASUREMENT => {
+            // x0 = buffer GPA, x1 = its size; returns the digest length in
+            // x0, or -1.
+            let (buf, len) = (ctx.guest.gprs.x(0) as usize, ctx.guest.gprs.x(1) as usize);
+            let ret = vcpu
+                .measurement
+                .ok_or(axerrno::AxError::Unsupported)
+                .and_then(|m| m.copy_to_guest(vcpu.space, buf, len));
+            ctx.guest
+                .gprs
+                .set_x(0, ret.map_or(u64::MAX, |len| len as u64));
+        }
+        _ => {
+            if let Some(call) = shmem::ShmemCall::from_hypercall(func) {
+                // Shared memory: x0, x1 = arguments; returns the value in
+                // x0, or -1 on failure.
+                let (arg0, arg1) = (ctx.guest.gprs.x(0) as usize, ctx.guest.gprs.x(1) as usize);
+                let ret = vcpu
+                    .shmem
+                    .hypercall(call, vcpu.space, &mut vcpu.console, arg0, arg1);
+                if call.remaps() {
+                    tlb::flush_guest_range(vcpu.asid, arg0, arg1);
+                }
+                ctx.guest
+                    .gprs
+                    .set_x(0, ret.map_or(u64::MAX, |value| value as u64));
+            } else {
+                // Otherwise accept PSCI power requests (function ID in x0).
+                match GuestMessage::from_esr_and_regs(ctx.trap.esr, &ctx.guest.gprs.0) {
+                    Ok(GuestMessage::PsciSystemOff) => {
+                        return ControlFlow::Break(Ok(GuestExit::Shutdown(0)));
+                    }
+                    Ok(GuestMessage::PsciSystemReset) => {
+                        return ControlFlow::Break(Ok(GuestExit::Reboot));
+                    }
+                    _ => {}
+                }
+            }
+        }
+    }
+    ControlFlow::Continue(())
+}
+
+/// Data abort on an emulated device: a page the device maps on fault, or a
+/// register access, emulated from the syndrome or, if it has none, the
+/// instruction.
+#[cfg(all(feature = "axstd", target_arch = "aarch64"))]
+fn aarch64_exit_mmio(vcpu: &mut Aarch64Vcpu) -> ExitFlow {
+    use gmem::GuestMemory;
+
+    let (esr, far) = (vcpu.ctx.trap.esr, vcpu.ctx.trap.far as usize);
+    // ISS.WnR (bit 6) = write access
+    if vcpu.mmio.map_on_fault(vcpu.space, far, esr & (1 << 6) != 0) {
+        return aarch64_fault_fixed(vcpu, far);
+    }
+    let ctx = &mut vcpu.ctx;
+    let decoded = match devices::mmio::decode_esr(esr, far) {
+        Some(access) => Some(devices::mmio::A64LoadStore {
+            access,
+            reg2: None,
+            writeback: None,
+        }),
+        None => vcpu
+            .space
+            .read_obj::<u32>(ctx.guest.elr as usize)
+            .ok()
+            .and_then(|insn| devices::mmio::decode_a64(insn, far)),
+    };
+    let Some(decoded) = decoded else {
+        return ControlFlow::Break(Err(VmError::UnsupportedAccess {
+            addr: far,
+            pc: ctx.guest.elr as usize,
+        }));
+    };
+    for access in decoded.accesses() {
+        // Register 31 is XZR for loads and stores.
+        let value = if access.reg < 31 {
+            ctx.guest.gprs.x(access.reg)
+        } else {
+            0
+        };
+        if let Ok(Some(value)) = vcpu.mmio.emulate(vcpu.space, &access, value)
+            && access.reg < 31
+        {
+            ctx.guest.gprs.set_x(access.reg, value);
+        }
+    }
+    // Base register 31 is SP.
+    match decoded.writeback {
+        Some((31, offset)) => ctx.guest.sp = ctx.guest.sp.wrapping_add_signed(offset),
+        Some((rn, offset)) => ctx
+            .guest
+            .gprs
+            .set_x(rn, ctx.guest.gprs.x(rn).wrapping_add_signed(offset)),
+        None => {}
+    }
+    let access = decoded.access;
+    ctx.guest.elr += access.insn_len as u64;
+    if access.is_write {
+        // A device may have unmapped pages it mapped on fault.
+        tlb::flush_guest_all(vcpu.asid);
+    }
+    ControlFlow::Continue(())
+}
+
+/// Instruction or data abort from EL0 outside the emulated devices:
+/// on-demand page mapping, analogous to nested page fault handling in true
+/// hypervisors. An access the memory's permissions deny ends the VM: the
+/// guest at EL0 cannot take the abort.
+#[cfg(all(feature = "axstd", target_arch = "aarch64"))]
+fn aarch64_exit_npf(vcpu: &mut Aarch64Vcpu) -> ExitFlow {
+    use axhal::mem::PhysAddr;
+
+    let (esr, far) = (vcpu.ctx.trap.esr, vcpu.ctx.trap.far as usize);
+    let page_addr = far & !0xFFF;
+    let access = permfault::Access::from_aarch64_esr(esr);
+    let space = &mut *vcpu.space;
+    // ISS.WnR (bit 6) = write access, ISS.DFSC 0b0011xx = permission fault
+    let is_write_perm_fault = access == permfault::Access::Write && esr & 0x3C == 0x0C;
+    // ISS.DFSC 0b0010xx = access flag fault
+    if esr & 0x3C == 0x08 && space.handle_access_fault(far.into()) {
+        // A page whose access flag the working set sampling cleared: the
+        // fault is not one a fix-up failed to fix.
+        tlb::flush_guest_page(vcpu.asid, page_addr);
+        return ControlFlow::Continue(());
+    }
+    if is_write_perm_fault && space.handle_cow_fault(far.into()) {
+        // First write to a shared image page: now a private copy.
+    } else if space.handle_page_fault(far.into()) {
+        // Lazily backed RAM: a whole huge block or fault-around window is
+        // now mapped.
+    } else if let Some(perms) = space.denied_access(far.into(), access) {
+        let fault = permfault::PermissionFault {
+            gpa: far,
+            pc: vcpu.ctx.guest.elr as usize,
+            access,
+            perms,
+        };
+        vm_println!(vcpu.cfg.id, "Guest permission fault: {}", fault);
+        aarch64_crash_dump(vcpu.cfg.id, &vcpu.ctx, vcpu.space);
+        return ControlFlow::Break(Err(VmError::PermissionFault(fault)));
+    } else if space.mem_limit_reached() {
+        return ControlFlow::Break(Err(VmError::MemoryLimit {
+            used: space.mem_used(),
+        }));
+    } else if is_write_perm_fault && vcpu.dirty_log.handle_write_fault(space, far) {
+        // First write to a write-protected RAM page: now logged.
+    } else if access == permfault::Access::Execute {
+        // Device memory is never executable.
+        return ControlFlow::Break(Err(VmError::UnmappableFault {
+            gpa: far,
+            pc: vcpu.ctx.guest.elr as usize,
+        }));
+    } else {
+        // Passthrough map: VA -> PA (same address) for other MMIO
+        let mapped = space.map_linear(
+            page_addr.into(),
+            PhysAddr::from(page_addr),
+            axhal::mem::PAGE_SIZE_4K,
+            vcpu.flags,
+        );
+        if mapped.is_err() {
+            return ControlFlow::Break(Err(VmError::UnmappableFault {
+                gpa: far,
+                pc: vcpu.ctx.guest.elr as usize,
+            }));
+        }
+    }
+    aarch64_fault_fixed(vcpu, far)
+}
+
+/// Ends a data abort at `far` whose fix-up mapped memory: ends the VM if
+/// the guest keeps faulting there, otherwise drops the stale TLB entry.
+#[cfg(all(feature = "axstd", target_arch = "aarch64"))]
+fn aarch64_fault_fixed(vcpu: &mut Aarch64Vcpu, far: usize) -> ExitFlow {
+    if let Some(count) = vcpu.faults.record(far) {
+        aarch64_crash_dump(vcpu.cfg.id, &vcpu.ctx, vcpu.space);
+        return ControlFlow::Break(Err(VmError::RepeatedFault {
+            gpa: far,
+            pc: vcpu.ctx.guest.elr as usize,
+            count,
+        }));
+    }
+    tlb::flush_guest_page(vcpu.asid, far & !0xFFF);
+    ControlFlow::Continue(())
+}
+
+/// Any other exit ends the VM with a crash report.
+#[cfg(all(feature = "axstd", target_arch = "aarch64"))]
+fn aarch64_exit_unhandled(vcpu: &mut Aarch64Vcpu) -> ExitFlow {
+    aarch64_crash_dump(vcpu.cfg.id, &vcpu.ctx, vcpu.space);
+    ControlFlow::Break(Err(VmError::UnhandledExit {
+        code: ((vcpu.ctx.trap.esr >> 26) & 0x3F) as usize,
+        pc: vcpu.ctx.guest.elr as usize,
+        info: [vcpu.ctx.trap.esr as usize, vcpu.ctx.trap.far as usize],
+    }))
+}
+
+// ════════════════════════════════════════════════════════════════
+//  x86_64  (AMD SVM hypervisor — long-mode guest with NPT)
+//
+//  The guest runs in 64-bit long mode inside an SVM container.
+//  The hypervisor creates initial page tables, GDT, and VMCB for
+//  the guest, then uses VMRUN to execute it.
+//
+//  Nested Page Tables (NPT) provide GPA→HPA translation.
+//  Guest page tables provide GVA→GPA translation.
+//  Two-stage translation: GVA→GPA→HPA.
+//
+//  VMMCALL hypercalls are used for console I/O, the command line and
+//  shutdown.
+//  NPF (Nested Page Fault) is used for pflash emulation.
+// ════════════════════════════════════════════════════════════════
+
+/// The CPU of x86_64 guests: FXSAVE switches the x87 and SSE state
+/// (`x86_64_svm::fpu`), but not the XSAVE state of AVX and beyond.
+#[cfg(all(feature = "axstd", target_arch = "x86_64"))]
+const X86_CPU_MODEL: cpumodel::GuestCpuModel = cpumodel::GuestCpuModel::baseline()
+    .with(cpumodel::Feature::Fp)
+    .with(cpumodel::Feature::Simd)
+    .with(cpumodel::Feature::Crypto);
+
+#[cfg(all(feature = "axstd", target_arch = "x86_64"))]
+fn x86_64_main() {
+    use x86_64_svm::svm::*;
+
+    ax_println!("Hypervisor ...");
+    // SVM is disabled again by the final panic, or by whatever stops the
+    // hypervisor early.
+    let _host = hostguard::HostGuard::new();
+
+    // ── 1. Check AMD SVM support ──
+    let (_, _, ecx, _) = unsafe { cpuid(0x8000_0001) };
+    if ecx & (1 << 2) == 0 {
+        host_panic!("CPU does not support AMD SVM!");
+    }
+    let features = SvmFeatures::detect();
+    ax_println!(
+        "SVM features: nested paging {}, NRIP save {}, decode assists {}, AVIC {}",
+        features.nested_paging,
+        features.nrip_save,
+        features.decode_assists,
+        features.avic
+    );
+
+    // Guests in TSC offset mode see the host TSC frequency.
+    let tsc_khz = x86_64_svm::tsc::calibrate(10);
+    ax_println!("Host TSC: {} kHz", tsc_khz);
+
+    // Every VM (and every reboot or triple fault) gets a fresh NPT, guest
+    // RAM, image and VMCB. SVM is enabled on each host CPU a VM runs on,
+    // whose host-save area and host VMCB its VMs share
+    // (`x86_64_svm::svmcpu`).
+    let status = run_vms(move |cfg| x86_64_run_vm(cfg, &features));
+
+    ax_println!("Hypervisor ok!");
+    exit::report(status);
+
+    // Shutdown QEMU via ACPI
+    unsafe {
+        core::arch::asm!("mov dx, 0x604", "mov ax, 0x2000", "out dx, ax",);
+    }
+    host_panic!("Hypervisor ok!");
+}
+
+/// Prints the crash report of an x86_64 guest at an unhandled exit.
+#[cfg(all(feature = "axstd", target_arch = "x86_64"))]
+fn x86_64_crash_dump(
+    vm: usize,
+    vmcb: &x86_64_svm::vmcb::Vmcb,
+    gprs: &x86_64_svm::svm::SvmGuestGprs,
+    space: &gspace::GuestSpace,
+    shadow: Option<&x86_64_svm::shadow::ShadowPaging>,
+) {
+    use x86_64_svm::vmcb::*;
+
+    let regs = [
+        ("rax", vmcb.guest_rax()),
+        ("rbx", gprs.rbx),
+        ("rcx", gprs.rcx),
+        ("rdx", gprs.rdx),
+        ("rsi", gprs.rsi),
+        ("rdi", gprs.rdi),
+        ("rbp", gprs.rbp),
+        ("rsp", vmcb.read_u64(SAVE_RSP)),
+        ("r8", gprs.r8),
+        ("r9", gprs.r9),
+        ("r10", gprs.r10),
+        ("r11", gprs.r11),
+        ("r12", gprs.r12),
+        ("r13", gprs.r13),
+        ("r14", gprs.r14),
+        ("r15", gprs.r15),
+    ];
+    // Under shadow paging, the VMCB CR3 is the shadow root.
+    let (cr0, cr3, cr4) = (
+        vmcb.read_u64(SAVE_CR0),
+        shadow.map_or(vmcb.read_u64(SAVE_CR3), |shadow| shadow.guest_cr3()),
+        vmcb.read_u64(SAVE_CR4),
+    );
+    let sysregs = [
+        ("rip", vmcb.guest_rip()),
+        ("rflags", vmcb.read_u64(SAVE_RFLAGS)),
+        ("cr0", cr0),
+        ("cr3", cr3),
+        ("cr4", cr4),
+        ("efer", vmcb.read_u64(SAVE_EFER)),
+        ("exitcode", vmcb.exit_code()),
+        ("exitinfo1", vmcb.exit_info1()),
+        ("exitinfo2", vmcb.exit_info2()),
+    ];
+    dump::CrashDump {
+        gprs: &regs,
+        sysregs: &sysregs,
+        pc_gpa: gva::gva_to_gpa(
+            space,
+            &gva::GuestPaging { cr0, cr3, cr4 },
+            vmcb.guest_rip() as usize,
+            gva::Access::Execute,
+        ),
+        // EXITINFO2 is the faulting guest physical address of nested page
+        // faults only.
+        fault_gpa: (vmcb.exit_code() == VMEXIT_NPF).then_some(vmcb.exit_info2() as usize),
+    }
+    .print(vm, space);
+}
+
+/// Builds the guest VM from scratch and runs it until it shuts down or
+/// requests a reboot. The NPT and all guest RAM are freed on return.
+#[cfg(all(feature = "axstd", target_arch = "x86_64"))]
+fn x86_64_run_vm(
+    cfg: &config::VmConfig,
+    features: &x86_64_svm::svm::SvmFeatures,
+) -> Result<GuestExit, VmError> {
+    use alloc::boxed::Box;
+    use alloc::sync::Arc;
+    use alloc::vec::Vec;
+    use axhal::paging::MappingFlags;
+    use devices::pci::{PCI_CONFIG_PORT, PCI_CONFIG_PORTS, PCI_IO_WINDOW, PciHost};
+    use devices::virtio::VirtioDevice;
+    use devices::virtio::pci::{VIRTIO_PCI_IO_SIZE, VirtioPciLegacy};
+    use gmem::GuestMemory;
+    use memory_addr::va;
+    use x86_64_svm::svm::*;
+    use x86_64_svm::vmcb::*;
+
+    check_migratable(cfg)?;
+
+    // ── 2-3. Enable SVM on the VM's host CPU, with its host-save area ──
+    let svm_cpu = x86_64_svm::svmcpu::SvmCpu::enter();
+
+    // ── 4. Allocate IOPM and MSRPM ──
+    #[repr(C, align(4096))]
+    struct Iopm([u8; 12288]);
+    #[repr(C, align(4096))]
+    struct Msrpm([u8; 8192]);
+    // all zeros = allow all I/O; emulated device ports are intercepted below
+    let mut iopm = Box::new(Iopm([0u8; 12288]));
+    let msrpm = Box::new(Msrpm([0u8; 8192])); // all zeros = allow all MSRs
+    let iopm_pa = virt_to_phys_ptr(&iopm.0[0]);
+    let msrpm_pa = virt_to_phys_ptr(&msrpm.0[0]);
+
+    // Woken by the device workers when they complete requests, while the
+    // guest idles.
+    let idle = Arc::new(idle::IdleQueue::new());
+    // Emulated devices in the I/O port space.
+    let mut pio = devices::mmio::MmioBus::new();
+    // The virtio devices, on the PCI host bridge: their I/O BARs are
+    // allocated from the start of the I/O window (0xC000).
+    let mut virtio: Vec<Box<dyn VirtioDevice>> = Vec::new();
+    if let Some(blk) = open_vm_disk(cfg, &idle) {
+        virtio.push(Box::new(blk));
+    }
+    virtio.push(Box::new(vm_virtio_console(cfg, &idle)));
+    virtio.push(Box::new(vm_virtio_net(cfg)));
+    let mut pci = PciHost::new();
+    for dev in virtio {
+        let dev = VirtioPciLegacy::new(dev);
+        pci.add(dev.pci_ids(), VIRTIO_PCI_IO_SIZE, Box::new(dev))
+            .expect("add virtio device");
+    }
+    pio.add(Box::new(pci)).expect("add PCI host bridge");
+    for port in (PCI_CONFIG_PORT..PCI_CONFIG_PORT + PCI_CONFIG_PORTS).chain(PCI_IO_WINDOW) {
+        iopm.0[port / 8] |= 1 << (port % 8);
+    }
+    // The CMOS RTC, showing the host's wall-clock time.
+    {
+        use devices::mc146818::{CMOS_PORT, CMOS_PORTS, Mc146818};
+        pio.add(Box::new(Mc146818::new())).expect("add CMOS RTC");
+        for port in CMOS_PORT..CMOS_PORT + CMOS_PORTS {
+            iopm.0[port / 8] |= 1 << (port % 8);
+        }
+    }
+    // The PIT, outside the bus: it raises its own vector.
+    let pit = x86_64_svm::pit::VPit::new();
+    for port in x86_64_svm::pit::VPit::ports() {
+        iopm.0[port / 8] |= 1 << (port % 8);
+    }
+
+    // ── 5. Create NPT, load guest binary and pre-allocate guest RAM ──
+    // Range covers both low memory (code, page tables, stack) and pflash
+    let mut vm =
+        vm::Vm::new(cfg, va!(0x0), 0x1_0000_0000, 0).map_err(VmError::setup("create VM"))?;
+    // The owner of the secondary serial port finds it at COM1, which the
+    // other guests reach directly.
+    if let Some(port) = vm_claim_serial(cfg) {
+        use devices::uart16550::{COM1_BASE, UART_SIZE, Uart16550};
+        pio.add(Box::new(Uart16550::new(COM1_BASE, port)))
+            .expect("add 16550");
+        for port in COM1_BASE..COM1_BASE + UART_SIZE {
+            iopm.0[port / 8] |= 1 << (port % 8);
+        }
+    }
+    let (npt, map) = (&mut vm.space, &mut vm.map);
+
+    let flags =
+        MappingFlags::READ | MappingFlags::WRITE | MappingFlags::EXECUTE | MappingFlags::USER;
+
+    // Emulated flash below 4GB, read by the guest through its page tables.
+    map.add(
+        memmap::RegionKind::Pflash,
+        "pflash",
+        PFLASH_START,
+        PFLASH_SIZE,
+    )?;
+    map.add(
+        memmap::RegionKind::Mmio,
+        "local APIC",
+        x86_64_svm::lapic::LAPIC_BASE,
+        x86_64_svm::lapic::LAPIC_SIZE,
+    )?;
+    let pflash = devices::pflash::PFlash::open(PFLASH_START, PFLASH_SIZE, None, true)
+        .map_err(VmError::setup("create pflash"))?;
+    vm_println!(
+        cfg.id,
+        "PFlash at {:#x} ({} MB, {})",
+        pflash.base(),
+        pflash.size() / (1024 * 1024),
+        pflash.kind()
+    );
+    // Emulated MMIO devices: the faulting instruction is fetched and
+    // decoded.
+    let mut mmio = devices::mmio::MmioBus::new();
+    mmio.add(Box::new(pflash)).expect("add pflash");
+
+    // The binary at GPA VM_ENTRY (0x10000) is shared copy-on-write with
+    // other VMs running the same image.
+    vm_println!(cfg.id, "app: {}", cfg.image);
+    let image = loader::shared_image(&cfg.image).map_err(VmError::setup("open guest image"))?;
+    vm_println!(
+        cfg.id,
+        "Loaded {} bytes from {} (shared by {} VMs)",
+        image.len(),
+        cfg.image,
+        Arc::strong_count(&image)
+    );
+    vm.measurement = measure_image(cfg, &image)?;
+
+    // A Linux bzImage gets its own memory layout and the 32-bit boot
+    // protocol, a boot sector starts in real mode on the emulated BIOS; a
+    // package or a flat binary with a load descriptor (see `package`) and
+    // anything else, a flat 64-bit binary at VM_ENTRY, start in long mode.
+    const LINUX_RAM_SIZE: usize = 0x400_0000; // 64 MB
+    const BOOT_PARAMS_GPA: usize = 0x7000;
+    const CMDLINE_GPA: usize = 0x2_0000;
+    const BIOS_RAM_SIZE: usize = 0x100_0000; // 16 MB
+    let package = loader::package(&cfg.image, &image, VM_ENTRY)
+        .transpose()
+        .map_err(VmError::setup("parse guest package"))?;
+    let bzimage = package
+        .is_none()
+        .then(|| boot::bzimage_header(&image))
+        .flatten();
+    let bios = (package.is_none() && bzimage.is_none() && x86_64_svm::bios::is_boot_sector(&image))
+        .then(|| x86_64_svm::bios::Bios::new(cfg.id, BIOS_RAM_SIZE));
+    if (bzimage.is_some() || bios.is_some()) && !features.nested_paging {
+        // The 32-bit boot protocol and real mode start with paging off.
+        return Err(VmError::Setup {
+            step: if bios.is_some() {
+                "boot a real-mode boot sector"
+            } else {
+                "boot a Linux bzImage"
+            },
+            reason: "shadow paging needs a guest that starts in long mode".into(),
+        });
+    }
+    let ram_size = if let Some(bz) = &bzimage {
+        vm_println!(
+            cfg.id,
+            "Linux bzImage: boot protocol {}.{:02}, init size {} KB",
+            bz.version >> 8,
+            bz.version & 0xFF,
+            bz.init_size / 1024
+        );
+        map.add(memmap::RegionKind::Ram, "guest RAM", 0, LINUX_RAM_SIZE)?;
+        npt.map_alloc(0.into(), LINUX_RAM_SIZE, flags, true)
+            .map_err(VmError::setup("map guest RAM"))?;
+
+        // Only the protected-mode kernel is needed: it is copied to 1 MB
+        // (the offset in the file is not page aligned, so it cannot be
+        // shared copy-on-write). The real-mode setup code is skipped.
+        let kernel_len = image.len().saturating_sub(bz.kernel_offset);
+        let load = boot::BzImage::LOAD_ADDRESS;
+        if kernel_len.max(bz.init_size) > LINUX_RAM_SIZE - load {
+            return Err(VmError::Setup {
+                step: "load Linux kernel",
+                reason: alloc::format!(
+                    "{} KB kernel does not fit in {} MB of guest RAM",
+                    kernel_len.max(bz.init_size) / 1024,
+                    LINUX_RAM_SIZE >> 20
+                ),
+            });
+        }
+        let mut kernel = alloc::vec![0u8; kernel_len];
+        image.read(bz.kernel_offset, &mut kernel);
+        map.add(
+            memmap::RegionKind::Image,
+            "Linux kernel",
+            load,
+            kernel.len().max(bz.init_size),
+        )?;
+        npt.copy_to_guest(load, &kernel)
+            .map_err(VmError::setup("copy kernel"))?;
+
+        let mut cmdline = cfg
+            .cmdline
+            .as_deref()
+            .unwrap_or(boot::DEFAULT_CMDLINE)
+            .as_bytes()
+            .to_vec();
+        cmdline.truncate(bz.cmdline_size);
+        cmdline.push(0);
+        map.add(
+            memmap::RegionKind::BootData,
+            "command line",
+            CMDLINE_GPA,
+            cmdline.len(),
+        )?;
+        npt.copy_to_guest(CMDLINE_GPA, &cmdline)
+            .map_err(VmError::setup("write command line"))?;
+
+        // RAM below the legacy video/BIOS hole, the hole itself, RAM above
+        // 1 MB, and the flash below 4 GB.
+        let e820 = [
+            boot::E820Entry {
+                addr: 0,
+                size: 0x9_FC00,
+                kind: boot::E820_RAM,
+            },
+            boot::E820Entry {
+                addr: 0x9_FC00,
+                size: 0x10_0000 - 0x9_FC00,
+                kind: boot::E820_RESERVED,
+            },
+            boot::E820Entry {
+                addr: 0x10_0000,
+                size: (LINUX_RAM_SIZE - 0x10_0000) as u64,
+                kind: boot::E820_RAM,
+            },
+            boot::E820Entry {
+                addr: PFLASH_START as u64,
+                size: PFLASH_SIZE as u64,
+                kind: boot::E820_RESERVED,
+            },
+        ];
+        // The initrd goes to the top of RAM, within the kernel's limit.
+        let initrd = load_vm_initrd(
+            cfg,
+            npt,
+            load + kernel.len().max(bz.init_size),
+            LINUX_RAM_SIZE.min(bz.initrd_addr_max + 1),
+        );
+        if let Some((start, end)) = initrd {
+            map.add(memmap::RegionKind::Initrd, "initrd", start, end - start)?;
+        }
+        let boot_params = bz.boot_params(CMDLINE_GPA, initrd, &e820);
+        map.add(
+            memmap::RegionKind::BootData,
+            "boot_params",
+            BOOT_PARAMS_GPA,
+            boot_params.len(),
+        )?;
+        npt.copy_to_guest(BOOT_PARAMS_GPA, &boot_params)
+            .map_err(VmError::setup("write boot_params"))?;
+
+        // Flat 32-bit segments at the selectors the boot protocol names:
+        // __BOOT_CS = 0x10, __BOOT_DS = 0x18.
+        let gdt: [u64; 4] = [0, 0, 0x00CF_9B00_0000_FFFF, 0x00CF_9300_0000_FFFF];
+        map.add(
+            memmap::RegionKind::BootData,
+            "GDT",
+            0x5000,
+            size_of_val(&gdt),
+        )?;
+        npt.write_obj(0x5000, &gdt)
+            .map_err(VmError::setup("write GDT"))?;
+        LINUX_RAM_SIZE
+    } else if let Some(bios) = &bios {
+        if cfg.initrd.is_some() {
+            vm_println!(cfg.id, "initrd ignored: the guest is not a Linux bzImage");
+        }
+        vm_println!(
+            cfg.id,
+            "Boot sector: real mode at {:#x}, {} MB RAM",
+            x86_64_svm::bios::BOOT_SECTOR_GPA,
+            BIOS_RAM_SIZE / (1024 * 1024)
+        );
+        map.add(memmap::RegionKind::Ram, "guest RAM", 0, BIOS_RAM_SIZE)?;
+        for (name, start, size) in x86_64_svm::bios::Bios::regions() {
+            map.add(memmap::RegionKind::BootData, name, start, size)?;
+        }
+        map.add(
+            memmap::RegionKind::Image,
+            "boot sector",
+            x86_64_svm::bios::BOOT_SECTOR_GPA,
+            x86_64_svm::bios::BOOT_SECTOR_SIZE,
+        )?;
+        npt.map_alloc(0.into(), BIOS_RAM_SIZE, flags, true)
+            .map_err(VmError::setup("map guest RAM"))?;
+        let mut sector = [0u8; x86_64_svm::bios::BOOT_SECTOR_SIZE];
+        image.read(0, &mut sector);
+        npt.copy_to_guest(x86_64_svm::bios::BOOT_SECTOR_GPA, &sector)
+            .map_err(VmError::setup("copy boot sector"))?;
+        bios.install(npt).map_err(VmError::setup("install BIOS"))?;
+        for port in x86_64_svm::bios::Bios::ports() {
+            iopm.0[port / 8] |= 1 << (port % 8);
+        }
+        BIOS_RAM_SIZE
+    } else {
+        // Pre-allocate 2MB of guest RAM at GPA 0x0 around the image
+        // This covers: page tables (0x1000-0x5000), GDT (0x5000),
+        //              guest code (0x10000), and stack (up to 0x80000)
+        const GUEST_RAM_SIZE: usize = 0x20_0000; // 2MB
+        // The guest page tables map only these 2MB.
+        if let Some(pkg) = &package
+            && (pkg.ram_size as usize > GUEST_RAM_SIZE || !pkg.fits(0x6000, GUEST_RAM_SIZE as u64))
+        {
+            return Err(VmError::Setup {
+                step: "place package in guest RAM",
+                reason: "a long-mode guest gets 2 MB of RAM above 0x6000".into(),
+            });
+        }
+        if cfg.initrd.is_some() {
+            vm_println!(cfg.id, "initrd ignored: the guest is not a Linux bzImage");
+        }
+        vm_println!(
+            cfg.id,
+            "Pre-allocating {} KB guest RAM at GPA 0x0...",
+            GUEST_RAM_SIZE / 1024
+        );
+        map.add(memmap::RegionKind::Ram, "guest RAM", 0, GUEST_RAM_SIZE)?;
+        map.add(memmap::RegionKind::BootData, "page tables", 0x1000, 0x4000)?;
+        map.add(memmap::RegionKind::BootData, "GDT", 0x5000, 4 * 8)?;
+        match &package {
+            Some(pkg) => {
+                let (start, size) = loader::image_span(&image, Some(pkg), VM_ENTRY);
+                vm_println!(
+                    cfg.id,
+                    "Package: {} segments at {:#x}, entry {:#x}",
+                    pkg.segments.len(),
+                    start,
+                    pkg.entry
+                );
+                map.add(memmap::RegionKind::Image, "package", start, size)?;
+                loader::map_ram_with_package(npt, 0, GUEST_RAM_SIZE, &image, pkg, flags)
+            }
+            None => {
+                map.add(memmap::RegionKind::Image, "image", VM_ENTRY, image.size())?;
+                loader::map_ram_with_image(npt, 0, GUEST_RAM_SIZE, VM_ENTRY, image, flags)
+            }
+        }
+        .map_err(VmError::setup("map guest RAM"))?;
+        let (pages_4k, pages_2m, pages_1g) = npt.frame_counts();
+        vm_println!(
+            cfg.id,
+            "Guest RAM backed by {} 1G + {} 2M + {} 4K pages",
+            pages_1g,
+            pages_2m,
+            pages_4k
+        );
+
+        // ── 6. Write guest page tables into NPT-mapped memory ──
+        // Guest paging: GVA → GPA (identity mapping for first 2MB + pflash)
+        //
+        // PML4 at GPA 0x1000:
+        //   [0] → PDPT at GPA 0x2000
+        //
+        // PDPT at GPA 0x2000:
+        //   [0] → PD0 at GPA 0x3000  (first 1GB)
+        //   [3] → PD3 at GPA 0x4000  (3–4GB range, for pflash at 0xFFC00000)
+        //
+        // PD0 at GPA 0x3000:
+        //   [0] = 2MB identity page: GVA 0x0–0x200000 → GPA 0x0–0x200000
+        //
+        // PD3 at GPA 0x4000:
+        //   [510] = 2MB page: GVA 0xFFC00000 → GPA 0xFFC00000  (pflash)
+
+        const PTE_PRESENT: u64 = 1;
+        const PTE_RW: u64 = 1 << 1;
+        const PTE_USER: u64 = 1 << 2;
+        const PTE_PS: u64 = 1 << 7; // Page Size (2MB huge page)
+        const PT_FLAGS: u64 = PTE_PRESENT | PTE_RW | PTE_USER;
+
+        // PML4[0] → PDPT
+        npt.write_obj(0x1000, &(0x2000u64 | PT_FLAGS))
+            .map_err(VmError::setup("write PML4"))?;
+
+        // PDPT[0] → PD0, PDPT[3] → PD3
+        npt.write_obj(0x2000, &(0x3000u64 | PT_FLAGS))
+            .map_err(VmError::setup("write PDPT[0]"))?;
+        npt.write_obj(0x2000 + 3 * 8, &(0x4000u64 | PT_FLAGS))
+            .map_err(VmError::setup("write PDPT[3]"))?;
+
+        // PD0[0] = 2MB identity page at GPA 0x0
+        npt.write_obj(0x3000, &(0x0u64 | PT_FLAGS | PTE_PS))
+            .map_err(VmError::setup("write PD0[0]"))?;
+
+        // PD3[510] = 2MB page at GPA 0xFFC00000 (pflash)
+        npt.write_obj(0x4000 + 510 * 8, &(0xFFC0_0000u64 | PT_FLAGS | PTE_PS))
+            .map_err(VmError::setup("write PD3[510]"))?;
+
+        // ── 7. Write GDT into guest memory (GPA 0x5000) ──
+        // [0] Null, [1] 32-bit code, [2] 64-bit code (L=1), [3] Data
+        let gdt: [u64; 4] = [
+            0x0000_0000_0000_0000, // 0x00: null
+            0x00CF_9B00_0000_FFFF, // 0x08: 32-bit code (not used, placeholder)
+            0x00AF_9B00_0000_FFFF, // 0x10: 64-bit code (L=1, D=0, G=1)
+            0x00CF_9300_0000_FFFF, // 0x18: data (R/W, G=1)
+        ];
+        npt.write_obj(0x5000, &gdt)
+            .map_err(VmError::setup("write GDT"))?;
+        GUEST_RAM_SIZE
+    };
+    {
+        use devices::mc146818::{CMOS_PORT, CMOS_PORTS};
+        use devices::uart16550::{COM1_BASE, UART_SIZE};
+        use hvcall_abi::bootinfo::device;
+        let devices = [
+            (device::PFLASH, PFLASH_START, PFLASH_SIZE),
+            (
+                device::LAPIC,
+                x86_64_svm::lapic::LAPIC_BASE,
+                x86_64_svm::lapic::LAPIC_SIZE,
+            ),
+            (device::PCI_CONFIG_IO, PCI_CONFIG_PORT, PCI_CONFIG_PORTS),
+            (device::MC146818_IO, CMOS_PORT, CMOS_PORTS),
+            (device::NS16550_IO, COM1_BASE, UART_SIZE),
+        ];
+        add_boot_info(cfg, map, &mut mmio, 1, (0, ram_size), devices)?;
+    }
+
+    // Track guest RAM writes; the VMCB requests a guest TLB flush below.
+    let mut dirty_log =
+        dirty::DirtyLog::new(0, ram_size, flags).map_err(VmError::setup("create dirty log"))?;
+    dirty_log
+        .enable(npt)
+        .map_err(VmError::setup("enable dirty log"))?;
+    // Stage-2 faults whose fix-up does not stick end the VM.
+    let faults = refault::FaultHistory::new();
+    let mut tracer = trace::ExitTracer::new(cfg.id, cfg.trace);
+    let mut recorder = exit_recorder(cfg, exitlog::TraceArch::X86_64);
+    let balloon = balloon::Balloon::new(cfg.id);
+    let shmem = shmem::SharedMem::new(cfg.id);
+
+    let npt_root_pa: u64 = usize::from(npt.page_table_root()) as u64;
+    vm.print_memory_map();
+    let (npt, asid, hooks) = (&mut vm.space, &vm.vmid, &vm.hooks);
+    // Zeroed frames for the faults of the guest's boot.
+    npt.prepare_pool();
+
+    // ── 8. Build VMCB for 64-bit long mode ──
+    let mut vmcb = Box::new(Vmcb::new());
+
+    // Control area — intercept VMRUN, VMMCALL, HLT, CPUID, shutdown, IOPM
+    // ports and host interrupts, NMIs and SMIs (they belong to the host; the host
+    // timer tick ends the guest's time slice); enable NPT
+    vmcb.write_u32(
+        CTRL_INTERCEPT_MISC1,
+        INTERCEPT_INTR
+            | INTERCEPT_NMI
+            | INTERCEPT_SMI
+            | INTERCEPT_SHUTDOWN
+            | INTERCEPT_IOIO
+            | INTERCEPT_HLT
+            | INTERCEPT_CPUID,
+    );
+    vmcb.write_u32(CTRL_INTERCEPT_MISC2, INTERCEPT_VMRUN | INTERCEPT_VMMCALL);
+    vmcb.write_u64(CTRL_IOPM_BASE, iopm_pa);
+    vmcb.write_u64(CTRL_MSRPM_BASE, msrpm_pa);
+    // Each VM has its own ASID, so switching between VMs needs no TLB flush;
+    // only the entries left by the ASID's previous owner are dropped. A VM
+    // on the shared ASID flushes it at every VMRUN instead.
+    vmcb.write_u32(CTRL_GUEST_ASID, asid.get() as u32);
+    vmcb.write_u32(CTRL_TLB_CONTROL, TLB_CONTROL_FLUSH_ASID);
+    if features.nested_paging {
+        vmcb.write_u64(CTRL_NP_ENABLE, 1);
+        vmcb.write_u64(CTRL_NCR3, npt_root_pa);
+    }
+
+    if bzimage.is_some() {
+        // 32-bit boot protocol: flat protected mode without paging.
+        // CS attrib: P=1 DPL=0 S=1 Type=0xB | D=1 G=1 = 0x0C9B
+        vmcb.set_segment(SAVE_CS, 0x10, 0x0C9B, 0xFFFF_FFFF, 0);
+        vmcb.set_segment(SAVE_DS, 0x18, 0x0C93, 0xFFFF_FFFF, 0);
+        vmcb.set_segment(SAVE_ES, 0x18, 0x0C93, 0xFFFF_FFFF, 0);
+        vmcb.set_segment(SAVE_SS, 0x18, 0x0C93, 0xFFFF_FFFF, 0);
+        vmcb.set_segment(SAVE_FS, 0x18, 0x0C93, 0xFFFF_FFFF, 0);
+        vmcb.set_segment(SAVE_GS, 0x18, 0x0C93, 0xFFFF_FFFF, 0);
+        vmcb.set_segment(SAVE_GDTR, 0, 0, 31, 0x5000);
+        vmcb.set_segment(SAVE_IDTR, 0, 0, 0, 0);
+        vmcb.set_segment(SAVE_TR, 0, 0x008B, 0x67, 0);
+        vmcb.set_segment(SAVE_LDTR, 0, 0x0082, 0, 0);
+        // CR0: PE | ET
+        vmcb.write_u64(SAVE_CR0, 0x11);
+        vmcb.write_u64(SAVE_CR3, 0);
+        vmcb.write_u64(SAVE_CR4, 0);
+        vmcb.write_u64(SAVE_EFER, EFER_SVME);
+    } else if bios.is_some() {
+        // Real mode at reset values, with the IVT at 0.
+        // Attrib: P=1 S=1 Type=0xB (code) / 0x3 (data)
+        vmcb.set_segment(SAVE_CS, 0, 0x009B, 0xFFFF, 0);
+        vmcb.set_segment(SAVE_DS, 0, 0x0093, 0xFFFF, 0);
+        vmcb.set_segment(SAVE_ES, 0, 0x0093, 0xFFFF, 0);
+        vmcb.set_segment(SAVE_SS, 0, 0x0093, 0xFFFF, 0);
+        vmcb.set_segment(SAVE_FS, 0, 0x0093, 0xFFFF, 0);
+        vmcb.set_segment(SAVE_GS, 0, 0x0093, 0xFFFF, 0);
+        vmcb.set_segment(SAVE_GDTR, 0, 0, 0xFFFF, 0);
+        vmcb.set_segment(SAVE_IDTR, 0, 0, 0x3FF, 0);
+        vmcb.set_segment(SAVE_TR, 0, 0x008B, 0xFFFF, 0);
+        vmcb.set_segment(SAVE_LDTR, 0, 0x0082, 0xFFFF, 0);
+        // CR0: ET
+        vmcb.write_u64(SAVE_CR0, 0x10);
+        vmcb.write_u64(SAVE_CR3, 0);
+        vmcb.write_u64(SAVE_CR4, 0);
+        vmcb.write_u64(SAVE_EFER, EFER_SVME);
+    } else {
+        // Save area — 64-bit long-mode guest
+        // CS: 64-bit code segment (GDT offset 0x10)
+        // Attrib: P=1 DPL=0 S=1 Type=0xB | L=1 D=0 G=1 = 0x0A9B
+        vmcb.set_segment(SAVE_CS, 0x10, 0x0A9B, 0xFFFF_FFFF, 0);
+        // DS/ES/SS: data segment (GDT offset 0x18)
+        vmcb.set_segment(SAVE_DS, 0x18, 0x0C93, 0xFFFF_FFFF, 0);
+        vmcb.set_segment(SAVE_ES, 0x18, 0x0C93, 0xFFFF_FFFF, 0);
+        vmcb.set_segment(SAVE_SS, 0x18, 0x0C93, 0xFFFF_FFFF, 0);
+        vmcb.set_segment(SAVE_FS, 0, 0, 0, 0);
+        vmcb.set_segment(SAVE_GS, 0, 0, 0, 0);
+        // GDTR: GDT at GPA 0x5000, 4 entries (32 bytes), limit = 31
+        vmcb.set_segment(SAVE_GDTR, 0, 0, 31, 0x5000);
+        // IDTR: no IDT needed for simple payload
+        vmcb.set_segment(SAVE_IDTR, 0, 0, 0xFFF, 0);
+        // TR: required but minimal
+        vmcb.set_segment(SAVE_TR, 0, 0x008B, 0x67, 0);
+        vmcb.set_segment(SAVE_LDTR, 0, 0x0082, 0, 0);
+
+        // CR0: PE | ET | WP | PG (protected mode + paging)
+        vmcb.write_u64(SAVE_CR0, 0x8001_0011);
+        // CR3: PML4 at GPA 0x1000
+        vmcb.write_u64(SAVE_CR3, 0x1000);
+        // CR4: PAE | PGE
+        vmcb.write_u64(SAVE_CR4, 0x00A0);
+        // EFER: SVME | LME | LMA | NXE
+        vmcb.write_u64(SAVE_EFER, EFER_SVME | (1 << 8) | (1 << 10) | (1 << 11));
+    }
+
+    vmcb.write_u64(SAVE_DR6, 0xFFFF_0FF0);
+    vmcb.write_u64(SAVE_DR7, 0x0400);
+    vmcb.write_u64(SAVE_RFLAGS, 0x2);
+    if bzimage.is_some() {
+        // Entry at the start of the protected-mode kernel; ESI points to
+        // boot_params and EBP, EDI, EBX are zero.
+        vmcb.write_u64(SAVE_RIP, boot::BzImage::LOAD_ADDRESS as u64);
+        vmcb.write_u64(SAVE_RSP, BOOT_PARAMS_GPA as u64);
+    } else if bios.is_some() {
+        // The boot sector at 0000:7C00, its stack below it.
+        vmcb.write_u64(SAVE_RIP, x86_64_svm::bios::BOOT_SECTOR_GPA as u64);
+        vmcb.write_u64(SAVE_RSP, x86_64_svm::bios::BOOT_SECTOR_GPA as u64);
+    } else {
+        // RIP: guest entry point
+        let entry = package.as_ref().map_or(VM_ENTRY as u64, |pkg| pkg.entry);
+        vmcb.write_u64(SAVE_RIP, entry);
+        // RSP: stack at 0x80000 (grows down, within the pre-allocated 2MB)
+        vmcb.write_u64(SAVE_RSP, 0x80000);
+    }
+
+    // Without nested paging, the guest runs on shadow tables: the VMCB CR3
+    // is their root, and the guest's own CR3, its TLB flushes and its page
+    // faults are intercepted to keep them in step with its page tables.
+    let shadow = if features.nested_paging {
+        vm_println!(cfg.id, "Paging: nested");
+        None
+    } else {
+        vm_println!(cfg.id, "Paging: shadow (no nested paging)");
+        let shadow = x86_64_svm::shadow::ShadowPaging::new(vmcb.read_u64(SAVE_CR3))
+            .map_err(VmError::setup("create shadow page tables"))?;
+        vmcb.write_u64(SAVE_CR3, shadow.root_pa());
+        vmcb.write_u16(CTRL_INTERCEPT_CR_READS, intercept_cr(3));
+        vmcb.write_u16(CTRL_INTERCEPT_CR_WRITES, intercept_cr(3) | intercept_cr(4));
+        vmcb.write_u32(CTRL_INTERCEPT_EXCEPTIONS, INTERCEPT_EXCP_PF);
+        let misc1 = vmcb.read_u32(CTRL_INTERCEPT_MISC1);
+        vmcb.write_u32(CTRL_INTERCEPT_MISC1, misc1 | INTERCEPT_INVLPG);
+        Some(shadow)
+    };
+
+    // The guest TSC reads zero now and stops while the VM is paused.
+    let tsc = x86_64_svm::tsc::GuestTsc::new(cfg.tsc);
+    tsc.init(&mut vmcb);
+    vm_println!(cfg.id, "TSC: {:?}, {} kHz", cfg.tsc, tsc.khz());
+
+    let vmcb_pa = virt_to_phys_ptr(&vmcb.data[0]);
+
+    // ── 9. Create guest GPR save area ──
+    let mut gprs = SvmGuestGprs::new();
+    if bzimage.is_some() {
+        gprs.rsi = BOOT_PARAMS_GPA as u64;
+    } else if bios.is_some() {
+        gprs.rdx = x86_64_svm::bios::BOOT_DRIVE;
+    }
+
+    // ── 10. Run guest in loop ──
+    let pause = pause::VmPause::new(cfg.id);
+    let mut fpu = Box::new(x86_64_svm::fpu::GuestFpu::new());
+    // Without nested paging, the hardware sets the accessed bits of the
+    // shadow tables, not those of the guest space: no sampling.
+    let wss_period_ms = cfg.wss_period_ms.filter(|_| shadow.is_none());
+    let mut vcpu = X86Vcpu {
+        cfg,
+        features,
+        space: npt,
+        hooks,
+        flags,
+        vmcb,
+        gprs,
+        mmio,
+        pio,
+        pit,
+        bios,
+        lapic: x86_64_svm::lapic::VLapic::new(0),
+        shadow,
+        tsc,
+        events: events::PendingEvents::new(),
+        console: console::VmConsole::new(cfg.id),
+        balloon,
+        shmem,
+        dirty_log,
+        faults,
+        halted: false,
+        watchdog: watchdog::Watchdog::new(cfg.watchdog),
+        measurement: vm.measurement,
+        wss: wss::WorkingSet::new(cfg.id, wss_period_ms, axhal::time::monotonic_time_nanos()),
+    };
+    if recorder.is_some() {
+        vcpu.mmio.log_io();
+        vcpu.pio.log_io();
+    }
+    let dispatcher = x86_64_exit_dispatcher();
+    vm_println!(cfg.id, "Entering VM run loop...");
+    vcpu.hooks.boot();
+    let exit = loop {
+        // Give the other VM tasks a turn before every guest entry.
+        std::thread::yield_now();
+        // A paused VM stays parked here, with its TSC stopped.
+        if pause.is_requested() {
+            vcpu.tsc.pause();
+            pause.park();
+            vcpu.watchdog.pet();
+        }
+        vcpu.tsc.resume();
+        // A guest without a sign of life for too long is hung.
+        if let Some(expired) = vcpu.watchdog.expired() {
+            x86_64_crash_dump(
+                cfg.id,
+                &vcpu.vmcb,
+                &vcpu.gprs,
+                vcpu.space,
+                vcpu.shadow.as_ref(),
+            );
+            break watchdog_exit(cfg.id, expired, vcpu.vmcb.guest_rip() as usize);
+        }
+        let vmcb = &mut vcpu.vmcb;
+        sample_working_set(&mut vcpu.wss, vcpu.space, vcpu.hooks, || {
+            vmcb.write_u32(CTRL_TLB_CONTROL, TLB_CONTROL_FLUSH_ASID)
+        });
+
+        // Let devices pick up host-side events (console input).
+        vcpu.pio.poll(vcpu.space);
+        let now = axhal::time::monotonic_time_nanos();
+        if let Some(bios) = &mut vcpu.bios {
+            bios.poll(vcpu.space, now);
+        }
+        let (lapic, events) = (&mut vcpu.lapic, &mut vcpu.events);
+        lapic.poll(now);
+        if vcpu.pit.poll(now) {
+            // IRQ0 is an edge, through the local APIC once the guest
+            // enabled it.
+            if lapic.is_enabled() {
+                lapic.raise(PIT_VECTOR as u8);
+            } else {
+                events.pulse_irq(PIT_VECTOR);
+            }
+        }
+        // Device interrupts are level-triggered: keep a virtual interrupt
+        // pending for as long as a device asserts its line, through the
+        // local APIC once the guest enabled it.
+        // The guest's IF never masks host interrupts.
+        if lapic.is_enabled() {
+            lapic.set_level(VIRTIO_PCI_VECTOR as u8, vcpu.pio.irq_pending());
+            events.lower_irq(VIRTIO_PCI_VECTOR);
+        } else {
+            events.set_irq(VIRTIO_PCI_VECTOR, vcpu.pio.irq_pending());
+        }
+        if vcpu.halted {
+            if events.irqs() == 0 && lapic.deliverable().is_none() {
+                // Sleep until the next timer expiry at the latest.
+                let deadline = match (lapic.next_deadline(), vcpu.pit.next_deadline()) {
+                    (Some(a), Some(b)) => Some(a.min(b)),
+                    (a, b) => a.or(b),
+                };
+                let timeout = deadline
+                    .map(|deadline| core::time::Duration::from_nanos(deadline.saturating_sub(now)));
+                // Get frames ready for the guest's next faults meanwhile.
+                vcpu.space.prepare_pool();
+                let _ = vcpu.shmem.drain_console_ring(vcpu.space, &mut vcpu.console);
+                // Idling is waiting for the hypervisor, not being hung.
+                vcpu.watchdog.pet();
+                idle.wait(timeout);
+                continue;
+            }
+            vcpu.halted = false;
+        }
+        let vmcb = &mut vcpu.vmcb;
+        if asid.is_shared() {
+            // Other VMs may have run on this CPU with the same ASID.
+            vmcb.write_u32(CTRL_TLB_CONTROL, TLB_CONTROL_FLUSH_ASID);
+        }
+        let mut offered = vmcb.land_events(events);
+        if lapic.land(vmcb) {
+            offered = None;
+        }
+        if let Some(shadow) = &mut vcpu.shadow {
+            // A guest TLB flush drops the shadow tables too.
+            shadow.sync_tlb(vmcb);
+        }
+        vcpu.tsc.land(vmcb);
+
+        // No other VM may take the FPU between loading our state and
+        // VMRUN; _run_guest enables interrupts again after the exit.
+        axhal::asm::disable_irqs();
+        fpu.load();
+        unsafe {
+            _run_guest(vmcb_pa, svm_cpu.host_vmcb_pa(), &mut vcpu.gprs);
+        }
+        // A TLB flush request only applies to the VMRUN that consumed it.
+        vmcb.write_u32(CTRL_TLB_CONTROL, 0);
+        lapic.sync(vmcb);
+        if let Some(irq) = offered
+            && vmcb.virq_taken()
+        {
+            events.ack_irq(irq);
+        }
+
+        let class = x86_64_exit_class(&vcpu);
+        x86_64_exit_hooks(&vcpu, class);
+        let vmcb = &vcpu.vmcb;
+        tracer.record(
+            0,
+            class.trace_kind(),
+            vmcb.exit_code() as usize,
+            vmcb.guest_rip() as usize,
+            [vmcb.exit_info1() as usize, vmcb.exit_info2() as usize],
+        );
+        if class != ExitClass::Interrupt {
+            // Anything but a host interrupt is forward progress.
+            vcpu.watchdog.pet();
+        }
+        let entry = recorder.is_some().then(|| x86_64_exit_entry(&vcpu, class));
+        let flow = dispatcher.dispatch(class, &mut vcpu);
+        if let (Some(recorder), Some(mut entry)) = (&mut recorder, entry) {
+            entry.after = x86_64_exit_snapshot(&vcpu);
+            entry.io = [vcpu.mmio.take_io(), vcpu.pio.take_io()].concat();
+            recorder.record(entry);
+        }
+        if let ControlFlow::Break(exit) = flow {
+            break exit;
+        }
+    };
+    if matches!(exit, Ok(GuestExit::Reboot)) {
+        vcpu.hooks.reset();
+    }
+
+    vm_println!(
+        cfg.id,
+        "Guest dirtied {} pages of RAM",
+        vcpu.dirty_log.dirty_count()
+    );
+    let _ = vcpu.shmem.drain_console_ring(vcpu.space, &mut vcpu.console);
+    vcpu.balloon.report();
+    vcpu.wss.report();
+    vcpu.shmem.report();
+    // The VMCB goes first: it is the only reference to the NPT.
+    drop(vcpu);
+    vm.print_pool_stats();
+    vm.destroy();
+    exit
+}
+
+/// The state of an x86_64 vCPU that its exit handlers work on.
+#[cfg(all(feature = "axstd", target_arch = "x86_64"))]
+struct X86Vcpu<'a> {
+    cfg: &'a config::VmConfig,
+    features: &'a x86_64_svm::svm::SvmFeatures,
+    /// The nested page table, or the guest memory behind the shadow tables.
+    space: &'a mut gspace::GuestSpace,
+    /// The observers of the VM's lifecycle.
+    hooks: &'a hooks::VmHooks,
+    /// Flags of guest RAM mapped on fault.
+    flags: axhal::paging::MappingFlags,
+    vmcb: alloc::boxed::Box<x86_64_svm::vmcb::Vmcb>,
+    gprs: x86_64_svm::svm::SvmGuestGprs,
+    /// Emulated MMIO devices: the faulting instruction is fetched and
+    /// decoded.
+    mmio: devices::mmio::MmioBus,
+    /// Emulated devices in the I/O port space.
+    pio: devices::mmio::MmioBus,
+    /// The PIT, outside the bus: it raises its own vector.
+    pit: x86_64_svm::pit::VPit,
+    /// The BIOS services of a boot sector guest.
+    bios: Option<x86_64_svm::bios::Bios>,
+    lapic: x86_64_svm::lapic::VLapic,
+    /// The shadow page tables, without nested paging.
+    shadow: Option<x86_64_svm::shadow::ShadowPaging>,
+    tsc: x86_64_svm::tsc::GuestTsc,
+    events: events::PendingEvents,
+    console: console::VmConsole,
+    balloon: balloon::Balloon,
+    shmem: shmem::SharedMem,
+    dirty_log: dirty::DirtyLog,
+    /// Stage-2 faults whose fix-up does not stick end the VM.
+    faults: refault::FaultHistory,
+    /// The guest executed HLT and waits for an interrupt.
+    halted: bool,
+    /// Stops the VM when the guest shows no sign of life.
+    watchdog: watchdog::Watchdog,
+    /// SHA-256 digest of the guest image, for GET_MEASUREMENT.
+    measurement: Option<measure::Digest>,
+    /// The working set estimate, for BALLOON_TARGET.
+    wss: wss::WorkingSet,
+}
+
+/// Sorts the last exit of an x86_64 vCPU by its exit code.
+#[cfg(all(feature = "axstd", target_arch = "x86_64"))]
+fn x86_64_exit_class(vcpu: &X86Vcpu) -> ExitClass {
+    use x86_64_svm::vmcb::*;
+
+    let vmcb = &vcpu.vmcb;
+    match vmcb.exit_code() {
+        VMEXIT_INTR | VMEXIT_NMI | VMEXIT_SMI => ExitClass::Interrupt,
+        VMEXIT_VMMCALL => ExitClass::Hypercall,
+        VMEXIT_NPF
+            if vcpu.mmio.contains(vmcb.exit_info2() as usize)
+                || vcpu.lapic.contains(vmcb.exit_info2() as usize) =>
+        {
+            ExitClass::Mmio
+        }
+        VMEXIT_NPF | VMEXIT_EXCP_PF => ExitClass::Npf,
+        VMEXIT_IOIO => ExitClass::Pio,
+        VMEXIT_HLT => ExitClass::Halt,
+        VMEXIT_RDTSC | VMEXIT_RDTSCP | VMEXIT_CPUID | VMEXIT_CR3_READ | VMEXIT_CR3_WRITE
+        | VMEXIT_CR4_WRITE | VMEXIT_INVLPG => ExitClass::Insn,
+        VMEXIT_SHUTDOWN => ExitClass::Reset,
+        _ => ExitClass::Other,
+    }
+}
+
+/// Returns the state of an x86_64 vCPU that exit traces record.
+#[cfg(all(feature = "axstd", target_arch = "x86_64"))]
+fn x86_64_exit_snapshot(vcpu: &X86Vcpu) -> exitlog::ExitSnapshot {
+    let gprs = &vcpu.gprs;
+    let mut regs = [0; exitlog::MAX_KEY_REGS];
+    regs[..8].copy_from_slice(&[
+        vcpu.vmcb.guest_rax(),
+        gprs.rbx,
+        gprs.rcx,
+        gprs.rdx,
+        gprs.rsi,
+        gprs.rdi,
+        gprs.r8,
+        gprs.r9,
+    ]);
+    exitlog::ExitSnapshot {
+        pc: vcpu.vmcb.guest_rip(),
+        regs,
+        irqs: vcpu.events.irqs(),
+        exception: vcpu.events.next_exception().map(|e| (e.vector, e.error)),
+    }
+}
+
+/// Starts the exit trace entry of the last exit of an x86_64 vCPU.
+#[cfg(all(feature = "axstd", target_arch = "x86_64"))]
+fn x86_64_exit_entry(vcpu: &X86Vcpu, class: ExitClass) -> exitlog::ExitEntry {
+    let vmcb = &vcpu.vmcb;
+    exitlog::ExitEntry {
+        class: class as u8,
+        code: vmcb.exit_code(),
+        info: [vmcb.exit_info1(), vmcb.exit_info2()],
+        before: x86_64_exit_snapshot(vcpu),
+        ..Default::default()
+    }
+}
+
+/// Tells the VM's observers about the last exit of an x86_64 vCPU. Guest
+/// page faults under shadow paging are not nested page faults.
+#[cfg(all(feature = "axstd", target_arch = "x86_64"))]
+fn x86_64_exit_hooks(vcpu: &X86Vcpu, class: ExitClass) {
+    use x86_64_svm::vmcb::*;
+
+    let vmcb = &vcpu.vmcb;
+    vcpu.hooks.exit(class);
+    match class {
+        ExitClass::Npf if vmcb.exit_code() == VMEXIT_NPF => {
+            vcpu.hooks.npf(vmcb.exit_info2() as usize)
+        }
+        ExitClass::Hypercall => vcpu.hooks.hypercall(vmcb.guest_rax() as usize),
+        _ => {}
+    }
+}
+
+/// Builds the exit handler table of x86_64 vCPUs.
+#[cfg(all(feature = "axstd", target_arch = "x86_64"))]
+fn x86_64_exit_dispatcher<'a>() -> ExitDispatcher<X86Vcpu<'a>, Result<GuestExit, VmError>> {
+    let mut dispatcher = ExitDispatcher::new(x86_64_exit_unhandled);
+    dispatcher
+        .register(ExitClass::Interrupt, x86_64_exit_interrupt)
+        .register(ExitClass::Hypercall, x86_64_exit_hypercall)
+        .register(ExitClass::Mmio, x86_64_exit_mmio)
+        .register(ExitClass::Npf, x86_64_exit_npf)
+        .register(ExitClass::Pio, x86_64_exit_pio)
+        .register(ExitClass::Halt, x86_64_exit_halt)
+        .register(ExitClass::Insn, x86_64_exit_insn)
+        .register(ExitClass::Reset, x86_64_exit_shutdown);
+    dispatcher
+}
+
+/// A host interrupt (e.g. the scheduler tick), NMI or SMI ended the run. It
+/// stayed pending across the #VMEXIT and the host took it at STGI in
+/// _run_guest: re-enter the guest untouched. The next iteration gives the
+/// other tasks their turn.
+#[cfg(all(feature = "axstd", target_arch = "x86_64"))]
+fn x86_64_exit_interrupt(_vcpu: &mut X86Vcpu) -> ExitFlow {
+    ControlFlow::Continue(())
+}
+
+/// VMMCALL: hypercall with the function ID in RAX[7:0].
+#[cfg(all(feature = "axstd", target_arch = "x86_64"))]
+fn x86_64_exit_hypercall(vcpu: &mut X86Vcpu) -> ExitFlow {
+    use x86_64_svm::insn::VMMCALL_LEN;
+    use x86_64_svm::vmcb::*;
+
+    let (vmcb, gprs, features) = (&mut vcpu.vmcb, &vcpu.gprs, vcpu.features);
+    let guest_rax = vmcb.guest_rax();
+    let func = hvcall_abi::x86::func(guest_rax);
+
+    if guest_rax == hvcall_abi::psci::SYSTEM_OFF {
+        // Exit (PSCI SYSTEM_OFF convention)
+        return ControlFlow::Break(Ok(GuestExit::Shutdown(0)));
+    } else if func == hvcall_abi::EXIT {
+        // Exit with the code in bits [39:8] of RAX
+        return ControlFlow::Break(Ok(GuestExit::Shutdown(hvcall_abi::x86::exit_code(
+            guest_rax,
+        ))));
+    } else if guest_rax == hvcall_abi::psci::SYSTEM_RESET {
+        // Reboot (PSCI SYSTEM_RESET convention)
+        return ControlFlow::Break(Ok(GuestExit::Reboot));
+    } else if func == hvcall_abi::PUTCHAR {
+        // Putchar: character in bits [15:8] of RAX
+        vcpu.console
+            .putchar(hvcall_abi::x86::putchar_char(guest_rax));
+    } else if func == boot::HYPERCALL_GET_CMDLINE {
+        // RBX = buffer GPA, RCX = its size; returns the length in RAX, or
+        // -1 if the buffer is not guest memory.
+        let cmdline = vcpu.cfg.cmdline.as_deref().unwrap_or_default();
+        let ret = boot::copy_cmdline(vcpu.space, cmdline, gprs.rbx as usize, gprs.rcx as usize);
+        vmcb.write_u64(SAVE_RAX, ret.map_or(u64::MAX, |len| len as u64));
+    } else if func == x86_64_svm::tsc::HYPERCALL_GET_TSC_KHZ {
+        vmcb.write_u64(SAVE_RAX, vcpu.tsc.khz());
+    } else if func == balloon::HYPERCALL_BALLOON_RELEASE {
+        // RBX = start GPA, RCX = size; returns the bytes freed in RAX, or
+        // -1 if the range is not page aligned guest memory.
+        let ret = vcpu.balloon.release(
+            vcpu.space,
+            &mut vcpu.dirty_log,
+            gprs.rbx as usize,
+            gprs.rcx as usize,
+        );
+        vmcb.write_u64(SAVE_RAX, ret.map_or(u64::MAX, |freed| freed as u64));
+        vmcb.write_u32(CTRL_TLB_CONTROL, TLB_CONTROL_FLUSH_ASID);
+    } else if func == balloon::HYPERCALL_BALLOON_TARGET {
+        // Returns the bytes the guest should release in RAX.
+        let target = vcpu.wss.balloon_target(vcpu.space.mem_used());
+        vmcb.write_u64(SAVE_RAX, target as u64);
+    } else if func == watchdog::HYPERCALL_WATCHDOG_PET {
+        // Heartbeat of the guest; returns 0 in RAX.
+        vcpu.watchdog.pet();
+        vmcb.write_u64(SAVE_RAX, 0);
+    } else if func == hvcall_abi::GET_BOOT_INFO {
+        vmcb.write_u64(SAVE_RAX, devices::bootinfo::BOOT_INFO_GPA as u64);
+    } else if func == hvcall_abi::GET_MEASUREMENT {
+        // RBX = buffer GPA, RCX = its size; returns the digest length in
+        // RAX, or -1.
+        let ret = vcpu
+            .measurement
+            .ok_or(axerrno::AxError::Unsupported)
+            .and_then(|m| m.copy_to_guest(vcpu.space, gprs.rbx as usize, gprs.rcx as usize));
+        vmcb.write_u64(SAVE_RAX, ret.map_or(u64::MAX, |len| len as u64));
+    } else if let Some(call) = shmem::ShmemCall::from_hypercall(func) {
+        // RBX, RCX = arguments; returns the value in RAX, or -1 on failure.
+        let ret = vcpu.shmem.hypercall(
+            call,
+            vcpu.space,
+            &mut vcpu.console,
+            gprs.rbx as usize,
+            gprs.rcx as usize,
+        );
+        vmcb.write_u64(SAVE_RAX, ret.map_or(u64::MAX, |value| value as u64));
+        if call.remaps() {
+            vmcb.write_u32(CTRL_TLB_CONTROL, TLB_CONTROL_FLUSH_ASID);
+        }
+    }
+    vmcb.skip_insn(features, VMMCALL_LEN);
+    ControlFlow::Continue(())
+}
+
+/// Returns the guest's own CR3: the VMCB's, or the one the shadow tables
+/// stand in for.
+#[cfg(all(feature = "axstd", target_arch = "x86_64"))]
+fn x86_64_guest_cr3(vcpu: &X86Vcpu) -> u64 {
+    vcpu.shadow
+        .as_ref()
+        .map_or(vcpu.vmcb.read_u64(x86_64_svm::vmcb::SAVE_CR3), |shadow| {
+            shadow.guest_cr3()
+        })
+}
+
+/// Nested page fault on the local APIC or an emulated device: a page the
+/// device maps on fault, or a register access, decoded from the
+/// instruction and emulated.
+#[cfg(all(feature = "axstd", target_arch = "x86_64"))]
+fn x86_64_exit_mmio(vcpu: &mut X86Vcpu) -> ExitFlow {
+    use x86_64_svm::vmcb::*;
+
+    let cr3 = x86_64_guest_cr3(vcpu);
+    let fault_addr = vcpu.vmcb.exit_info2() as usize;
+    let is_write = vcpu.vmcb.exit_info1() & NPF_INFO_WRITE != 0;
+    let done = if vcpu.lapic.contains(fault_addr) {
+        vcpu.lapic.emulate(
+            fault_addr,
+            &mut vcpu.vmcb,
+            &mut vcpu.gprs,
+            vcpu.features,
+            vcpu.space,
+            cr3,
+        )
+    } else if vcpu.mmio.map_on_fault(vcpu.space, fault_addr, is_write) {
+        Ok(())
+    } else {
+        // Register access: decode and emulate the instruction.
+        x86_64_svm::mmio::emulate(
+            &mut vcpu.mmio,
+            vcpu.space,
+            fault_addr,
+            &mut vcpu.vmcb,
+            &mut vcpu.gprs,
+            vcpu.features,
+            cr3,
+        )
+        .inspect(|_| {
+            if is_write {
+                // A device may have unmapped pages it mapped on fault.
+                vcpu.vmcb
+                    .write_u32(CTRL_TLB_CONTROL, TLB_CONTROL_FLUSH_ASID);
+            }
+        })
+    };
+    match done {
+        Ok(()) => ControlFlow::Continue(()),
+        Err(e) => ControlFlow::Break(Err(e)),
+    }
+}
+
+/// Nested page fault on RAM, or a guest #PF under shadow paging. A #PF
+/// that the guest's page tables allow is a stage-2 fault on the guest
+/// physical address behind it, which may be a device. An access the
+/// memory's permissions deny becomes a #PF of the guest under shadow
+/// paging, which knows its linear address, and ends the VM otherwise.
+#[cfg(all(feature = "axstd", target_arch = "x86_64"))]
+fn x86_64_exit_npf(vcpu: &mut X86Vcpu) -> ExitFlow {
+    use x86_64_svm::shadow::ShadowFault;
+    use x86_64_svm::vmcb::*;
+
+    // The linear address of a guest #PF under shadow paging.
+    let mut linear = None;
+    if let Some(shadow) = &mut vcpu.shadow
+        && vcpu.vmcb.exit_code() == VMEXIT_EXCP_PF
+    {
+        let gva = vcpu.vmcb.exit_info2();
+        match shadow.handle_fault(vcpu.space, &mut vcpu.vmcb) {
+            Ok(ShadowFault::Filled) => return ControlFlow::Continue(()),
+            Ok(ShadowFault::Reflect(error)) => {
+                vcpu.events.push_exception(14, Some(error));
+                return ControlFlow::Continue(());
+            }
+            Ok(ShadowFault::Stage2) => linear = Some(gva),
+            Err(_) => {
+                return ControlFlow::Break(Err(VmError::MemoryLimit {
+                    used: vcpu.space.mem_used(),
+                }));
+            }
+        }
+        let fault_addr = vcpu.vmcb.exit_info2() as usize;
+        if vcpu.lapic.contains(fault_addr) || vcpu.mmio.contains(fault_addr) {
+            return x86_64_exit_mmio(vcpu);
+        }
+    }
+
+    let vmcb = &mut vcpu.vmcb;
+    let space = &mut *vcpu.space;
+    let fault_addr = vmcb.exit_info2() as usize;
+    let page_addr = fault_addr & !0xFFF;
+    let info1 = vmcb.exit_info1();
+    let is_write_perm_fault = info1 & NPF_INFO_PRESENT != 0 && info1 & NPF_INFO_WRITE != 0;
+    if let Some(count) = vcpu.faults.record(fault_addr) {
+        x86_64_crash_dump(vcpu.cfg.id, vmcb, &vcpu.gprs, space, vcpu.shadow.as_ref());
+        return ControlFlow::Break(Err(VmError::RepeatedFault {
+            gpa: fault_addr,
+            pc: vmcb.guest_rip() as usize,
+            count,
+        }));
+    }
+    if is_write_perm_fault && space.handle_cow_fault(fault_addr.into()) {
+        // First write to a shared image page: now a private copy.
+        vcpu.dirty_log.record_write(fault_addr);
+        vmcb.write_u32(CTRL_TLB_CONTROL, TLB_CONTROL_FLUSH_ASID);
+        return ControlFlow::Continue(());
+    }
+    if space.handle_page_fault(fault_addr.into()) {
+        // Lazily backed RAM: a whole huge block or fault-around window is
+        // now mapped.
+        return ControlFlow::Continue(());
+    }
+    let access = permfault::Access::from_npf_info(info1);
+    if vmcb.exit_code() == VMEXIT_NPF
+        && let Some(perms) = space.denied_access(fault_addr.into(), access)
+    {
+        let fault = permfault::PermissionFault {
+            gpa: fault_addr,
+            pc: vmcb.guest_rip() as usize,
+            access,
+            perms,
+        };
+        // A nested page fault does not report the linear address the
+        // guest's #PF handler would find in CR2.
+        let Some(linear) = linear else {
+            vm_println!(vcpu.cfg.id, "Guest permission fault: {}", fault);
+            x86_64_crash_dump(vcpu.cfg.id, vmcb, &vcpu.gprs, space, vcpu.shadow.as_ref());
+            return ControlFlow::Break(Err(VmError::PermissionFault(fault)));
+        };
+        vm_println!(
+            vcpu.cfg.id,
+            "Guest permission fault: {}; injecting #PF",
+            fault
+        );
+        vmcb.write_u64(SAVE_CR2, linear);
+        let error = access.x86_64_pf_error(vmcb.guest_cpl() == 3);
+        vcpu.events.push_exception(14, Some(error));
+        return ControlFlow::Continue(());
+    }
+    if space.mem_limit_reached() {
+        return ControlFlow::Break(Err(VmError::MemoryLimit {
+            used: space.mem_used(),
+        }));
+    }
+    if is_write_perm_fault && vcpu.dirty_log.handle_write_fault(space, fault_addr) {
+        // First write to a write-protected RAM page: now logged.
+        return ControlFlow::Continue(());
+    }
+
+    if space
+        .map_alloc(
+            page_addr.into(),
+            memory_addr::PAGE_SIZE_4K,
+            vcpu.flags,
+            true,
+        )
+        .is_err()
+    {
+        return ControlFlow::Break(Err(if space.mem_limit_reached() {
+            VmError::MemoryLimit {
+                used: space.mem_used(),
+            }
+        } else {
+            VmError::UnmappableFault {
+                gpa: fault_addr,
+                pc: vmcb.guest_rip() as usize,
+            }
+        }));
+    }
+    ControlFlow::Continue(())
+}
+
+/// IN or OUT on an intercepted port: the BIOS services, the PIT or a
+/// device on the port bus. String I/O is not emulated.
+#[cfg(all(feature = "axstd", target_arch = "x86_64"))]
+fn x86_64_exit_pio(vcpu: &mut X86Vcpu) -> ExitFlow {
+    use x86_64_svm::vmcb::*;
+
+    let vmcb = &mut vcpu.vmcb;
+    let info1 = vmcb.exit_info1();
+    let access = devices::mmio::MmioAccess {
+        addr: (info1 >> IOIO_PORT_SHIFT) as u16 as usize,
+        width: ((info1 >> IOIO_SIZE_SHIFT) & 7) as usize,
+        is_write: info1 & IOIO_TYPE_IN == 0,
+        reg: 0, // RAX
+        sign_extend: false,
+        reg_32bit: false,
+        insn_len: 0,
+    };
+    if let Some(bios) = &mut vcpu.bios
+        && bios.contains(access.addr)
+        && info1 & IOIO_STR == 0
+    {
+        if access.addr == x86_64_svm::bios::BIOS_PORT && access.is_write {
+            // OUTs from anywhere but the interrupt stubs do nothing.
+            bios.call(vmcb, &mut vcpu.gprs, vcpu.space, &mut vcpu.console);
+        } else if let Some(value) = bios.emulate_port(&access, vmcb.guest_rax()) {
+            let rax = vmcb.guest_rax();
+            vmcb.write_u64(SAVE_RAX, rax & !0xFF | value);
+        }
+        vmcb.write_u64(SAVE_RIP, vmcb.exit_info2());
+        return ControlFlow::Continue(());
+    }
+    let pit = &mut vcpu.pit;
+    if info1 & IOIO_STR != 0 || !(vcpu.pio.contains(access.addr) || pit.contains(access.addr)) {
+        return ControlFlow::Break(Err(VmError::UnsupportedAccess {
+            addr: access.addr,
+            pc: vmcb.guest_rip() as usize,
+        }));
+    }
+    let rax = vmcb.guest_rax();
+    let value = if pit.contains(access.addr) {
+        pit.emulate(&access, rax, axhal::time::monotonic_time_nanos())
+    } else {
+        vcpu.pio.emulate(vcpu.space, &access, rax).ok().flatten()
+    };
+    if let Some(value) = value {
+        // IN to EAX zero-extends into RAX, AL/AX keep the rest.
+        let rax = match access.width {
+            4 => value,
+            w => rax & !((1 << (w * 8)) - 1) | value,
+        };
+        vmcb.write_u64(SAVE_RAX, rax);
+    }
+    // EXITINFO2 holds the RIP of the next instruction.
+    vmcb.write_u64(SAVE_RIP, vmcb.exit_info2());
+    ControlFlow::Continue(())
+}
+
+/// HLT: the guest resumes after it once an interrupt is pending.
+#[cfg(all(feature = "axstd", target_arch = "x86_64"))]
+fn x86_64_exit_halt(vcpu: &mut X86Vcpu) -> ExitFlow {
+    use x86_64_svm::insn::HLT_LEN;
+    use x86_64_svm::vmcb::*;
+
+    // Without an interrupt that can wake the guest, HLT would stop it for
+    // good (NMIs are not emulated).
+    if vcpu.vmcb.read_u64(SAVE_RFLAGS) & RFLAGS_IF == 0 {
+        return ControlFlow::Break(Err(VmError::HaltedForever {
+            pc: vcpu.vmcb.guest_rip() as usize,
+        }));
+    }
+    vcpu.vmcb.skip_insn(vcpu.features, HLT_LEN);
+    vcpu.halted = true;
+    ControlFlow::Continue(())
+}
+
+/// RDTSC and RDTSCP, CPUID, and the CR3, CR4 and INVLPG intercepts of
+/// shadow paging.
+#[cfg(all(feature = "axstd", target_arch = "x86_64"))]
+fn x86_64_exit_insn(vcpu: &mut X86Vcpu) -> ExitFlow {
+    use x86_64_svm::vmcb::*;
+    const CPUID_LEN: u64 = 2;
+
+    let (vmcb, gprs, features) = (&mut vcpu.vmcb, &mut vcpu.gprs, vcpu.features);
+    let exit_code = vmcb.exit_code();
+    if exit_code == VMEXIT_RDTSC || exit_code == VMEXIT_RDTSCP {
+        vcpu.tsc.emulate(vmcb, gprs, features);
+        return ControlFlow::Continue(());
+    }
+    if exit_code == VMEXIT_CPUID {
+        // The guest sees its CPU model, not the host.
+        let (leaf, subleaf) = (vmcb.read_u64(SAVE_RAX) as u32, gprs.rcx as u32);
+        let host = core::arch::x86_64::__cpuid_count(leaf, subleaf);
+        let [eax, ebx, ecx, edx] =
+            X86_CPU_MODEL.x86_cpuid(leaf, subleaf, [host.eax, host.ebx, host.ecx, host.edx]);
+        vmcb.write_u64(SAVE_RAX, eax.into());
+        (gprs.rbx, gprs.rcx, gprs.rdx) = (ebx.into(), ecx.into(), edx.into());
+        vmcb.skip_insn(features, CPUID_LEN);
+        return ControlFlow::Continue(());
+    }
+    // Intercepted under shadow paging only.
+    let Some(shadow) = &mut vcpu.shadow else {
+        return x86_64_exit_unhandled(vcpu);
+    };
+    let done = if exit_code == VMEXIT_INVLPG {
+        shadow.emulate_invlpg(vmcb, features, vcpu.space)
+    } else {
+        shadow.emulate_mov_cr(vmcb, gprs, features, vcpu.space)
+    };
+    match done {
+        Ok(()) => ControlFlow::Continue(()),
+        Err(e) => ControlFlow::Break(Err(e)),
+    }
+}
+
+/// Triple fault: a real machine resets, so reboot the VM.
+#[cfg(all(feature = "axstd", target_arch = "x86_64"))]
+fn x86_64_exit_shutdown(vcpu: &mut X86Vcpu) -> ExitFlow {
+    vm_println!(
+        vcpu.cfg.id,
+        "Guest triple fault at RIP={:#x}",
+        vcpu.vmcb.guest_rip()
+    );
+    ControlFlow::Break(Ok(GuestExit::Reboot))
+}
+
+/// Any other exit ends the VM with a crash report.
+#[cfg(all(feature = "axstd", target_arch = "x86_64"))]
+fn x86_64_exit_unhandled(vcpu: &mut X86Vcpu) -> ExitFlow {
+    let vmcb = &vcpu.vmcb;
+    x86_64_crash_dump(
+        vcpu.cfg.id,
+        vmcb,
+        &vcpu.gprs,
+        vcpu.space,
+        vcpu.shadow.as_ref(),
+    );
+    ControlFlow::Break(Err(VmError::UnhandledExit {
+        code: vmcb.exit_code() as usize,
+        pc: vmcb.guest_rip() as usize,
+        info: [vmcb.exit_info1() as usize, vmcb.exit_info2() as usize],
+    }))
+}
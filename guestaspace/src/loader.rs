@@ -0,0 +1,235 @@
+use crate::gmem::GuestMemory;
+use crate::gspace::{GuestSpace, SharedPages};
+use crate::package::{
+    LoadDescriptor, MAX_TABLE_SIZE, Package, PackageError, SEGMENT_EXEC, SEGMENT_READ,
+    SEGMENT_WRITE, is_package,
+};
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::sync::{Arc, Weak};
+use alloc::vec::Vec;
+use axhal::paging::MappingFlags;
+use axstd::fs::File;
+use axstd::io::Read;
+use axstd::sync::Mutex;
+use memory_addr::PAGE_SIZE_4K;
+
+/// Guest images currently mapped by at least one VM, keyed by path.
+static IMAGE_CACHE: Mutex<BTreeMap<String, Weak<SharedPages>>> = Mutex::new(BTreeMap::new());
+
+/// Returns the contents of the guest image `fname` as shared read-only pages.
+///
+/// VMs booting the same image while another VM still maps it get the same
+/// frames; the file is only read again once no VM uses it any more.
+pub fn shared_image(fname: &str) -> axio::Result<Arc<SharedPages>> {
+    // Holding the lock while reading makes concurrent loaders share one copy.
+    let mut cache = IMAGE_CACHE.lock();
+    if let Some(image) = cache.get(fname).and_then(Weak::upgrade) {
+        return Ok(image);
+    }
+
+    let data = read_file(fname)?;
+    let image = Arc::new(SharedPages::new(&data).map_err(|_| axio::Error::NoMemory)?);
+
+    cache.retain(|_, image| image.strong_count() > 0);
+    cache.insert(fname.into(), Arc::downgrade(&image));
+    Ok(image)
+}
+
+/// Returns the SHA-256 digest of `image`, hashed page by page from its
+/// frames: the bytes the guest boots from.
+#[cfg(feature = "measure")]
+pub fn measure(image: &SharedPages) -> crate::measure::Digest {
+    let mut hasher = crate::measure::Sha256::new();
+    let mut page = [0u8; PAGE_SIZE_4K];
+    let mut offset = 0;
+    while offset < image.len() {
+        let chunk = &mut page[..(image.len() - offset).min(PAGE_SIZE_4K)];
+        image.read(offset, chunk);
+        hasher.update(chunk);
+        offset += chunk.len();
+    }
+    hasher.finish()
+}
+
+/// Reads the whole file `fname`.
+pub fn read_file(fname: &str) -> axio::Result<Vec<u8>> {
+    let mut file = File::open(fname).map_err(|_| axio::Error::NotFound)?;
+    let mut data = Vec::new();
+    file.read_to_end(&mut data).map_err(|_| axio::Error::Io)?;
+    Ok(data)
+}
+
+/// Loads the initrd `fname` into guest RAM `[floor, limit)`, as high as
+/// possible, and returns the range it occupies.
+pub fn load_initrd(
+    uspace: &mut GuestSpace,
+    fname: &str,
+    floor: usize,
+    limit: usize,
+) -> axio::Result<(usize, usize)> {
+    let data = read_file(fname)?;
+    let start = crate::boot::initrd_gpa(floor, limit, data.len()).ok_or(axio::Error::NoMemory)?;
+    uspace
+        .copy_to_guest(start, &data)
+        .map_err(|_| axio::Error::BadAddress)?;
+    Ok((start, start + data.len()))
+}
+
+/// Maps guest RAM `[ram_start, ram_start + ram_size)` with `image` mapped
+/// copy-on-write at `entry` and freshly allocated memory around it.
+///
+/// The allocated memory is backed up front, unless `uspace` has a memory
+/// limit: it is then backed on first access, so that only the pages the
+/// guest touches count against the limit.
+pub fn map_ram_with_image(
+    uspace: &mut GuestSpace,
+    ram_start: usize,
+    ram_size: usize,
+    entry: usize,
+    image: Arc<SharedPages>,
+    flags: MappingFlags,
+) -> axio::Result<()> {
+    let populate = uspace.mem_limit().is_none();
+    let image_end = entry + image.size();
+    let ram_end = ram_start + ram_size;
+    if entry < ram_start || image_end > ram_end {
+        return Err(axio::Error::InvalidInput);
+    }
+    if entry > ram_start {
+        uspace
+            .map_alloc(ram_start.into(), entry - ram_start, flags, populate)
+            .map_err(|_| axio::Error::NoMemory)?;
+    }
+    uspace
+        .map_cow(entry.into(), image, flags)
+        .map_err(|_| axio::Error::NoMemory)?;
+    if image_end < ram_end {
+        uspace
+            .map_alloc(image_end.into(), ram_end - image_end, flags, populate)
+            .map_err(|_| axio::Error::NoMemory)?;
+    }
+    Ok(())
+}
+
+/// Returns the package of the guest image `fname` (see [`crate::package`]):
+/// the header and segment table of `image` if it is a package, or the
+/// segments of the flat binary loaded at `load_addr` that its load
+/// descriptor `<fname>.load` describes. `None` for other images.
+pub fn package(
+    fname: &str,
+    image: &SharedPages,
+    load_addr: usize,
+) -> Option<Result<Package, PackageError>> {
+    let mut table = [0u8; MAX_TABLE_SIZE];
+    let len = table.len().min(image.len());
+    image.read(0, &mut table[..len]);
+    if is_package(&table) {
+        return Some(Package::parse(&table[..len], image.len()));
+    }
+    let descriptor = read_file(&alloc::format!("{fname}.load")).ok()?;
+    Some(
+        LoadDescriptor::parse(&descriptor)
+            .and_then(|desc| desc.package(load_addr as u64, image.len())),
+    )
+}
+
+/// Returns the guest physical start and size of `image`: the span of the
+/// segments of `package`, or the whole image at `entry`.
+pub fn image_span(image: &SharedPages, package: Option<&Package>, entry: usize) -> (usize, usize) {
+    match package {
+        Some(pkg) => {
+            let (start, end) = pkg.span();
+            (start as usize, (end - start) as usize)
+        }
+        None => (entry, image.size()),
+    }
+}
+
+/// Returns the flags of the pages of `package` in RAM mapped with `flags`,
+/// as runs of pages `(start, end, flags)` by address: the permissions of
+/// its segments, and of all segments sharing a page for such pages.
+fn segment_pages(package: &Package, flags: MappingFlags) -> Vec<(usize, usize, MappingFlags)> {
+    let perms = MappingFlags::READ | MappingFlags::WRITE | MappingFlags::EXECUTE;
+    let mut pages = BTreeMap::new();
+    for segment in &package.segments {
+        let mut segment_flags = flags - perms;
+        for (bit, flag) in [
+            (SEGMENT_READ, MappingFlags::READ),
+            (SEGMENT_WRITE, MappingFlags::WRITE),
+            (SEGMENT_EXEC, MappingFlags::EXECUTE),
+        ] {
+            if segment.flags & bit != 0 {
+                segment_flags |= flag & flags;
+            }
+        }
+        let start = segment.gpa as usize & !(PAGE_SIZE_4K - 1);
+        let end = (segment.end() as usize).next_multiple_of(PAGE_SIZE_4K);
+        for page in (start..end).step_by(PAGE_SIZE_4K) {
+            *pages.entry(page).or_insert(flags - perms) |= segment_flags;
+        }
+    }
+    let mut runs: Vec<(usize, usize, MappingFlags)> = Vec::new();
+    for (page, page_flags) in pages {
+        match runs.last_mut() {
+            Some(run) if run.1 == page && run.2 == page_flags => run.1 += PAGE_SIZE_4K,
+            _ => runs.push((page, page + PAGE_SIZE_4K, page_flags)),
+        }
+    }
+    runs
+}
+
+/// Maps guest RAM `[ram_start, ram_start + ram_size)` and copies the
+/// segments of `package` from `image` into it.
+///
+/// The pages of the segments get their permissions, so code is not
+/// writable and data not executable; the rest of RAM gets `flags`. Unlike
+/// a flat binary, a package is not shared between VMs: its segments need
+/// not be page aligned, and their zeroed tails (bss) are part of the RAM.
+pub fn map_ram_with_package(
+    uspace: &mut GuestSpace,
+    ram_start: usize,
+    ram_size: usize,
+    image: &SharedPages,
+    package: &Package,
+    flags: MappingFlags,
+) -> axio::Result<()> {
+    let ram_end = ram_start + ram_size;
+    if !package.fits(ram_start as u64, ram_end as u64) {
+        return Err(axio::Error::InvalidInput);
+    }
+    let populate = uspace.mem_limit().is_none();
+    let mut next = ram_start;
+    for (start, end, page_flags) in segment_pages(package, flags)
+        .into_iter()
+        .chain([(ram_end, ram_end, flags)])
+    {
+        let (start, end) = (start.max(ram_start), end.min(ram_end));
+        // The RAM before the run, then the run.
+        for (gpa, size, map_flags) in [
+            (next, start - next, flags),
+            (start, end - start, page_flags),
+        ] {
+            if size > 0 {
+                uspace
+                    .map_alloc(gpa.into(), size, map_flags, populate)
+                    .map_err(|_| axio::Error::NoMemory)?;
+            }
+        }
+        next = end;
+    }
+    let mut buf = [0u8; 4096];
+    for segment in &package.segments {
+        let mut done = 0;
+        while done < segment.file_size as usize {
+            let chunk = (segment.file_size as usize - done).min(buf.len());
+            image.read(segment.offset as usize + done, &mut buf[..chunk]);
+            uspace
+                .copy_to_guest(segment.gpa as usize + done, &buf[..chunk])
+                .map_err(|_| axio::Error::BadAddress)?;
+            done += chunk;
+        }
+        uspace.sync_icache_range((segment.gpa as usize).into(), segment.file_size as usize);
+    }
+    Ok(())
+}
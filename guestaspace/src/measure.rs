@@ -0,0 +1,253 @@
+//! Measurement of guest images.
+//!
+//! With the `measure` feature, the hypervisor hashes every guest image with
+//! SHA-256 (a software implementation, [`Sha256`]) right after reading it,
+//! before the guest runs: the digest is printed, kept with the VM and
+//! handed to the guest by the GET_MEASUREMENT hypercall, so a guest, or
+//! whoever reads the console, can check that the expected payload booted.
+//! A VM configured with `sha256=<hex>` only boots if its image has that
+//! digest.
+//!
+//! Without the feature, images are not measured: GET_MEASUREMENT fails,
+//! and a VM with an expected digest is not started.
+
+#![allow(dead_code)]
+
+use axerrno::AxResult;
+use core::fmt;
+
+use crate::gmem::GuestMemory;
+
+/// A SHA-256 digest.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Digest(pub [u8; 32]);
+
+impl Digest {
+    /// Length of a digest in bytes.
+    pub const LEN: usize = 32;
+
+    /// Parses 64 hex digits.
+    pub fn parse(hex: &str) -> Option<Self> {
+        if hex.len() != 2 * Self::LEN || !hex.bytes().all(|b| b.is_ascii_hexdigit()) {
+            return None;
+        }
+        let mut digest = [0; Self::LEN];
+        for (byte, pair) in digest.iter_mut().zip(hex.as_bytes().chunks(2)) {
+            let pair = core::str::from_utf8(pair).ok()?;
+            *byte = u8::from_str_radix(pair, 16).ok()?;
+        }
+        Some(Self(digest))
+    }
+
+    /// Copies the digest, or its first `len` bytes, to guest memory at
+    /// `buf`, for the GET_MEASUREMENT hypercall; returns [`Self::LEN`].
+    pub fn copy_to_guest(
+        &self,
+        mem: &mut impl GuestMemory,
+        buf: usize,
+        len: usize,
+    ) -> AxResult<usize> {
+        mem.copy_to_guest(buf, &self.0[..len.min(Self::LEN)])?;
+        Ok(Self::LEN)
+    }
+
+    /// Returns the digest of `data`.
+    #[cfg(any(feature = "measure", test))]
+    pub fn of(data: &[u8]) -> Self {
+        let mut hasher = Sha256::new();
+        hasher.update(data);
+        hasher.finish()
+    }
+}
+
+impl fmt::Display for Digest {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for byte in self.0 {
+            write!(f, "{:02x}", byte)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(any(feature = "measure", test))]
+const K: [u32; 64] = [
+    0x428a2f98, 0x71374491, 0xb5c0fbcf, 0xe9b5dba5, 0x3956c25b, 0x59f111f1, 0x923f82a4, 0xab1c5ed5,
+    0xd807aa98, 0x12835b01, 0x243185be, 0x550c7dc3, 0x72be5d74, 0x80deb1fe, 0x9bdc06a7, 0xc19bf174,
+    0xe49b69c1, 0xefbe4786, 0x0fc19dc6, 0x240ca1cc, 0x2de92c6f, 0x4a7484aa, 0x5cb0a9dc, 0x76f988da,
+    0x983e5152, 0xa831c66d, 0xb00327c8, 0xbf597fc7, 0xc6e00bf3, 0xd5a79147, 0x06ca6351, 0x14292967,
+    0x27b70a85, 0x2e1b2138, 0x4d2c6dfc, 0x53380d13, 0x650a7354, 0x766a0abb, 0x81c2c92e, 0x92722c85,
+    0xa2bfe8a1, 0xa81a664b, 0xc24b8b70, 0xc76c51a3, 0xd192e819, 0xd6990624, 0xf40e3585, 0x106aa070,
+    0x19a4c116, 0x1e376c08, 0x2748774c, 0x34b0bcb5, 0x391c0cb3, 0x4ed8aa4a, 0x5b9cca4f, 0x682e6ff3,
+    0x748f82ee, 0x78a5636f, 0x84c87814, 0x8cc70208, 0x90befffa, 0xa4506ceb, 0xbef9a3f7, 0xc67178f2,
+];
+
+/// An incremental SHA-256 (FIPS 180-4) hash.
+#[cfg(any(feature = "measure", test))]
+pub struct Sha256 {
+    state: [u32; 8],
+    block: [u8; 64],
+    /// Bytes in `block`.
+    filled: usize,
+    /// Bytes hashed so far.
+    len: u64,
+}
+
+#[cfg(any(feature = "measure", test))]
+impl Sha256 {
+    /// Starts a hash.
+    pub fn new() -> Self {
+        Self {
+            state: [
+                0x6a09e667, 0xbb67ae85, 0x3c6ef372, 0xa54ff53a, 0x510e527f, 0x9b05688c, 0x1f83d9ab,
+                0x5be0cd19,
+            ],
+            block: [0; 64],
+            filled: 0,
+            len: 0,
+        }
+    }
+
+    /// Hashes `data` after what was hashed so far.
+    pub fn update(&mut self, mut data: &[u8]) {
+        self.len += data.len() as u64;
+        while !data.is_empty() {
+            let n = data.len().min(64 - self.filled);
+            self.block[self.filled..self.filled + n].copy_from_slice(&data[..n]);
+            self.filled += n;
+            data = &data[n..];
+            if self.filled == 64 {
+                self.compress();
+                self.filled = 0;
+            }
+        }
+    }
+
+    /// Pads the message and returns its digest.
+    pub fn finish(mut self) -> Digest {
+        let bits = self.len.wrapping_mul(8);
+        self.block[self.filled] = 0x80;
+        self.block[self.filled + 1..].fill(0);
+        if self.filled >= 56 {
+            self.compress();
+            self.block.fill(0);
+        }
+        self.block[56..].copy_from_slice(&bits.to_be_bytes());
+        self.compress();
+        let mut digest = [0; Digest::LEN];
+        for (out, word) in digest.chunks_mut(4).zip(self.state) {
+            out.copy_from_slice(&word.to_be_bytes());
+        }
+        Digest(digest)
+    }
+
+    fn compress(&mut self) {
+        let mut w = [0u32; 64];
+        for (word, bytes) in w.iter_mut().zip(self.block.chunks(4)) {
+            *word = u32::from_be_bytes(bytes.try_into().unwrap());
+        }
+        for i in 16..64 {
+            let s0 = w[i - 15].rotate_right(7) ^ w[i - 15].rotate_right(18) ^ (w[i - 15] >> 3);
+            let s1 = w[i - 2].rotate_right(17) ^ w[i - 2].rotate_right(19) ^ (w[i - 2] >> 10);
+            w[i] = w[i - 16]
+                .wrapping_add(s0)
+                .wrapping_add(w[i - 7])
+                .wrapping_add(s1);
+        }
+        let [mut a, mut b, mut c, mut d, mut e, mut f, mut g, mut h] = self.state;
+        for i in 0..64 {
+            let s1 = e.rotate_right(6) ^ e.rotate_right(11) ^ e.rotate_right(25);
+            let ch = (e & f) ^ (!e & g);
+            let t1 = h
+                .wrapping_add(s1)
+                .wrapping_add(ch)
+                .wrapping_add(K[i])
+                .wrapping_add(w[i]);
+            let s0 = a.rotate_right(2) ^ a.rotate_right(13) ^ a.rotate_right(22);
+            let maj = (a & b) ^ (a & c) ^ (b & c);
+            let t2 = s0.wrapping_add(maj);
+            h = g;
+            g = f;
+            f = e;
+            e = d.wrapping_add(t1);
+            d = c;
+            c = b;
+            b = a;
+            a = t1.wrapping_add(t2);
+        }
+        for (state, v) in self.state.iter_mut().zip([a, b, c, d, e, f, g, h]) {
+            *state = state.wrapping_add(v);
+        }
+    }
+}
+
+#[cfg(any(feature = "measure", test))]
+impl Default for Sha256 {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::format;
+    use alloc::vec;
+    use memory_addr::VirtAddr;
+
+    use super::*;
+    use crate::gspace::GuestSpace;
+
+    #[test]
+    fn sha256_vectors() {
+        assert_eq!(
+            format!("{}", Digest::of(b"")),
+            "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
+        );
+        assert_eq!(
+            format!("{}", Digest::of(b"abc")),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+        assert_eq!(
+            format!(
+                "{}",
+                Digest::of(b"abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq")
+            ),
+            "248d6a61d20638b8e5c026930c3e6039a33ce45964ff2167f6ecedd419db06c1"
+        );
+        // Fed in uneven pieces across block boundaries.
+        let data = vec![b'a'; 1_000_000];
+        let mut hasher = Sha256::new();
+        for chunk in data.chunks(4093) {
+            hasher.update(chunk);
+        }
+        assert_eq!(
+            format!("{}", hasher.finish()),
+            "cdc76e5c9914fb9281a1c7e284d73e67f1809a48a497200e046d39ccc7112cd0"
+        );
+    }
+
+    #[test]
+    fn parse_digest() {
+        let hex = "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad";
+        assert_eq!(Digest::parse(hex), Some(Digest::of(b"abc")));
+        assert_eq!(Digest::parse(&hex.to_uppercase()), Some(Digest::of(b"abc")));
+        assert_eq!(Digest::parse(&hex[1..]), None);
+        assert_eq!(Digest::parse(&hex.replace('b', "g")), None);
+        assert_eq!(Digest::parse(&hex.replace("ba", "+a")), None);
+    }
+
+    #[test]
+    fn copy_to_guest() {
+        let mut space = GuestSpace::new(VirtAddr::from(0x8000_0000), 0x1000).unwrap();
+        let digest = Digest::of(b"abc");
+        assert_eq!(digest.copy_to_guest(&mut space, 0x8000_0100, 64), Ok(32));
+        let mut buf = [0; 33];
+        space.copy_from_guest(0x8000_0100, &mut buf).unwrap();
+        assert_eq!(buf[..32], digest.0);
+        assert_eq!(buf[32], 0);
+        // A short buffer gets the start of the digest.
+        assert_eq!(digest.copy_to_guest(&mut space, 0x8000_0200, 4), Ok(32));
+        space.copy_from_guest(0x8000_0200, &mut buf[..8]).unwrap();
+        assert_eq!(buf[..8], [0xba, 0x78, 0x16, 0xbf, 0, 0, 0, 0]);
+        assert!(digest.copy_to_guest(&mut space, 0x8000_0FF0, 32).is_err());
+    }
+}
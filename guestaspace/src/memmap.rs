@@ -0,0 +1,165 @@
+//! The guest physical memory map of a VM.
+//!
+//! Every VM declares what it puts where while it is set up: its RAM and
+//! device windows ([`RegionKind::is_window`]) and the contents placed in
+//! RAM (image, device tree, initrd, boot data, stack). [`MemoryMap::add`]
+//! rejects a window that overlaps another window, and contents that leave
+//! RAM or overlap other contents, so a layout mistake (an image loaded on
+//! top of the device tree, a device inside RAM) ends the VM at setup with
+//! [`VmError::RegionOverlap`] or [`VmError::RegionOutsideRam`] instead of
+//! corrupting the guest later. [`MemoryMap::print`] lists the map.
+//!
+//! The map is bookkeeping only; the stage-2 mappings themselves are made
+//! through [`GuestSpace`](crate::gspace::GuestSpace).
+
+#![allow(dead_code)]
+
+use alloc::vec::Vec;
+
+use crate::error::VmError;
+
+/// What a region of the memory map holds.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RegionKind {
+    /// Guest RAM.
+    Ram,
+    /// An emulated or passed-through device.
+    Mmio,
+    /// The emulated flash.
+    Pflash,
+    /// The guest image, in RAM.
+    Image,
+    /// The device tree, in RAM.
+    Dtb,
+    /// The initrd, in RAM.
+    Initrd,
+    /// Other data the hypervisor writes into RAM for the guest (boot
+    /// parameters, command line, page tables, BIOS tables).
+    BootData,
+    /// The initial stack, in RAM.
+    Stack,
+}
+
+impl RegionKind {
+    /// Checks whether the region is a window of the address space (RAM or
+    /// a device) rather than contents placed in RAM.
+    pub fn is_window(self) -> bool {
+        matches!(self, Self::Ram | Self::Mmio | Self::Pflash)
+    }
+
+    fn as_str(self) -> &'static str {
+        match self {
+            Self::Ram => "RAM",
+            Self::Mmio => "MMIO",
+            Self::Pflash => "pflash",
+            Self::Image => "image",
+            Self::Dtb => "DTB",
+            Self::Initrd => "initrd",
+            Self::BootData => "boot data",
+            Self::Stack => "stack",
+        }
+    }
+}
+
+/// One named region of the memory map.
+#[derive(Clone, Copy, Debug)]
+pub struct MemRegion {
+    pub kind: RegionKind,
+    pub name: &'static str,
+    pub start: usize,
+    pub size: usize,
+}
+
+impl MemRegion {
+    /// Returns the end of the region (exclusive).
+    pub fn end(&self) -> usize {
+        self.start + self.size
+    }
+
+    fn overlaps(&self, other: &MemRegion) -> bool {
+        self.start < other.end() && other.start < self.end()
+    }
+}
+
+/// The declared regions of one VM, sorted by start address.
+pub struct MemoryMap {
+    vm_id: usize,
+    regions: Vec<MemRegion>,
+}
+
+impl MemoryMap {
+    /// Creates an empty map for VM `vm_id`.
+    pub fn new(vm_id: usize) -> Self {
+        Self {
+            vm_id,
+            regions: Vec::new(),
+        }
+    }
+
+    /// Declares `[start, start + size)` as `name`. Windows must not overlap
+    /// other windows; contents must lie in one RAM window and must not
+    /// overlap other contents.
+    pub fn add(
+        &mut self,
+        kind: RegionKind,
+        name: &'static str,
+        start: usize,
+        size: usize,
+    ) -> Result<(), VmError> {
+        let region = MemRegion {
+            kind,
+            name,
+            start,
+            size,
+        };
+        if !kind.is_window()
+            && !self
+                .regions
+                .iter()
+                .any(|r| r.kind == RegionKind::Ram && r.start <= start && region.end() <= r.end())
+        {
+            return Err(VmError::RegionOutsideRam {
+                region: name,
+                start,
+                end: region.end(),
+            });
+        }
+        if let Some(other) = self
+            .regions
+            .iter()
+            .find(|r| r.kind.is_window() == kind.is_window() && r.overlaps(&region))
+        {
+            return Err(VmError::RegionOverlap {
+                region: name,
+                start,
+                end: region.end(),
+                other: other.name,
+            });
+        }
+        let pos = self.regions.partition_point(|r| r.start <= start);
+        self.regions.insert(pos, region);
+        Ok(())
+    }
+
+    /// Returns the regions, sorted by start address.
+    pub fn regions(&self) -> &[MemRegion] {
+        &self.regions
+    }
+
+    /// Prints the map, contents indented under their RAM window.
+    pub fn print(&self) {
+        vm_println!(self.vm_id, "Memory map:");
+        for r in &self.regions {
+            vm_println!(
+                self.vm_id,
+                "  {}{:#011x}-{:#011x} {:>7} KB  {:<9} {}",
+                if r.kind.is_window() { "" } else { "  " },
+                r.start,
+                r.end(),
+                r.size.div_ceil(1024),
+                r.kind.as_str(),
+                r.name
+            );
+        }
+    }
+}
@@ -0,0 +1,1026 @@
+//! Live migration of a guest between two hypervisor instances.
+//!
+//! A VM with `migrate=PATH[@SECS]` in `vms.conf` ([`MigrateConfig::parse`])
+//! is migrated out `SECS` seconds after it boots. Its RAM, vCPU and device
+//! state go to a migration stream at `PATH` on the FAT disk, written as it
+//! is produced ([`Outgoing`]). A VM with `incoming=PATH` and otherwise the
+//! same `vms.conf` line is built as usual, then reads the stream instead of
+//! booting ([`receive`]) and resumes where the source stopped. The two VMs
+//! may run in one hypervisor, which streams the guest from one to the
+//! other while it runs, or in two instances sharing the disk image, the
+//! second one started once the first has written the stream. virtio-net is
+//! no transport: its only backend is the loopback between the VMs of one
+//! hypervisor, without a host network.
+//!
+//! The sender ([`MigrationSender`]) copies RAM while the guest runs:
+//!
+//! - the first round sends all of it, zero pages as a single byte;
+//! - every later pre-copy round sends the pages the dirty log caught since
+//!   the previous one;
+//! - once a round finds at most [`MigrationLimits::converge_pages`] dirty
+//!   pages, or after [`MigrationLimits::max_rounds`], the guest stops for
+//!   good: the stop-and-copy sends the last dirty pages, then every page
+//!   whose contents changed behind the dirty log (the hypervisor and its
+//!   devices write guest memory without write faults), found by comparing
+//!   each page in full with the contents it was last sent with. The vCPU
+//!   registers, the state of the emulated devices and the guest time
+//!   follow. A source VM that migrated ends; a failed migration leaves it
+//!   running.
+//!
+//! The sender keeps a copy of every nonzero page it sent for that
+//! comparison, so a migrating VM takes up to the size of its RAM again in
+//! host memory.
+//!
+//! The receiver ([`MigrationReceiver`]) is fed the stream in pieces of any
+//! size and writes the pages to guest memory as they arrive.
+//!
+//! Only riscv64 VMs with one hart and no virtio-blk disk migrate: the
+//! requests of a disk in flight on its worker are not migrated, and
+//! neither are an aarch64 guest's timer, which compares against the host
+//! counter, or the VMCB, local APIC and PIT state of an x86_64 guest. A
+//! migrating VM keeps the passed-through PLIC instead of an AIA interrupt
+//! file, whose state stays with the host, as does the PLIC's own. A guest
+//! sharing buffers with the hypervisor does not migrate, and the input
+//! devices hold on the host side (console input, a received packet not yet
+//! delivered) is lost.
+//!
+//! # Format
+//!
+//! Integers are LEB128 varints unless they are bytes. The header is the
+//! magic `GMIG`, the format version (byte), the architecture (byte, as in
+//! exit traces) and the base and size of guest RAM. Records follow, each
+//! starting with its type (byte):
+//!
+//! - pages (1): their count, then for each its index in RAM and a byte: 0
+//!   for a zero page, 1 followed by its 4096 bytes;
+//! - vCPU (2): its index and the count of its registers, then each
+//!   register's id and value;
+//! - device (3): its base address, then the length and bytes of its state;
+//! - time (4): the guest time;
+//! - end (0xFF).
+
+#![allow(dead_code)]
+
+use alloc::boxed::Box;
+use alloc::string::{String, ToString};
+use alloc::vec;
+use alloc::vec::Vec;
+use core::fmt;
+
+use crate::exitlog::TraceArch;
+use crate::gmem::GuestMemory;
+
+/// First bytes of a migration stream.
+pub const MIGRATION_MAGIC: [u8; 4] = *b"GMIG";
+
+/// Version of the stream format.
+pub const MIGRATION_VERSION: u8 = 1;
+
+/// Longest delay of `migrate=`, in seconds.
+pub const MAX_START_SECS: u64 = 3600;
+
+/// Most vCPUs a stream may describe.
+pub const MAX_CPUS: usize = 64;
+
+const PAGE_SIZE: usize = 0x1000;
+/// Most pages in one pages record.
+const PAGES_PER_RECORD: usize = 256;
+
+const RECORD_PAGES: u8 = 1;
+const RECORD_CPU: u8 = 2;
+const RECORD_DEVICE: u8 = 3;
+const RECORD_TIME: u8 = 4;
+const RECORD_END: u8 = 0xFF;
+
+const PAGE_ZERO: u8 = 0;
+const PAGE_DATA: u8 = 1;
+
+/// Where and when a VM is migrated out, as configured.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MigrateConfig {
+    /// Path of the migration stream on the FAT disk.
+    pub path: String,
+    /// Seconds the guest runs before the migration starts.
+    pub after_secs: u64,
+}
+
+impl MigrateConfig {
+    /// Parses a `migrate=` value: the path of the stream, optionally
+    /// followed by `@SECS`, the seconds (up to [`MAX_START_SECS`]) the guest
+    /// runs before the migration starts; it starts at once without.
+    pub fn parse(spec: &str) -> Option<Self> {
+        let (path, after_secs) = match spec.rsplit_once('@') {
+            Some((path, secs)) => (path, secs.parse().ok().filter(|s| *s <= MAX_START_SECS)?),
+            None => (spec, 0),
+        };
+        (!path.is_empty()).then(|| Self {
+            path: path.to_string(),
+            after_secs,
+        })
+    }
+}
+
+/// Why a migration failed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MigrationError {
+    /// The data does not start with [`MIGRATION_MAGIC`].
+    BadMagic,
+    /// The stream has a format version this build does not read.
+    Version(u8),
+    /// The stream is of another architecture than the receiving VM.
+    Arch(u8),
+    /// The stream ended before its end record.
+    Truncated,
+    /// A value is out of range.
+    Corrupt,
+    /// The guest RAM of the stream is not the receiving VM's.
+    Layout,
+    /// The guest memory at this address cannot be written.
+    Memory(usize),
+    /// No device is at this base address, or it rejected its state.
+    Device(u64),
+    /// The stream cannot be read or written.
+    Io,
+    /// The stream did not grow for too long.
+    Stalled,
+}
+
+impl fmt::Display for MigrationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::BadMagic => write!(f, "not a migration stream"),
+            Self::Version(version) => write!(f, "unsupported stream version {version}"),
+            Self::Arch(arch) => write!(f, "stream of architecture {arch}"),
+            Self::Truncated => write!(f, "stream truncated"),
+            Self::Corrupt => write!(f, "stream corrupt"),
+            Self::Layout => write!(f, "guest RAM differs"),
+            Self::Memory(gpa) => write!(f, "guest memory at {gpa:#x} not writable"),
+            Self::Device(base) => write!(f, "device state at {base:#x} rejected"),
+            Self::Io => write!(f, "stream I/O failed"),
+            Self::Stalled => write!(f, "stream stalled"),
+        }
+    }
+}
+
+/// Encodes the state of a vCPU or device, and the migration stream.
+#[derive(Default)]
+pub struct StateWriter {
+    buf: Vec<u8>,
+}
+
+impl StateWriter {
+    /// Creates an empty writer.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Appends `value` as a varint.
+    pub fn varint(&mut self, mut value: u64) {
+        while value >= 0x80 {
+            self.buf.push(value as u8 | 0x80);
+            value >>= 7;
+        }
+        self.buf.push(value as u8);
+    }
+
+    /// Appends a byte.
+    pub fn byte(&mut self, value: u8) {
+        self.buf.push(value);
+    }
+
+    /// Appends `data` as it is.
+    pub fn bytes(&mut self, data: &[u8]) {
+        self.buf.extend_from_slice(data);
+    }
+
+    /// Returns the number of bytes written.
+    pub fn len(&self) -> usize {
+        self.buf.len()
+    }
+
+    /// Checks whether nothing was written.
+    pub fn is_empty(&self) -> bool {
+        self.buf.is_empty()
+    }
+
+    /// Takes the bytes written so far.
+    pub fn take(&mut self) -> Vec<u8> {
+        core::mem::take(&mut self.buf)
+    }
+
+    /// Returns the bytes written.
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.buf
+    }
+}
+
+/// Decodes what a [`StateWriter`] wrote.
+pub struct StateReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> StateReader<'a> {
+    /// Reads `data` from its start.
+    pub fn new(data: &'a [u8]) -> Self {
+        Self { data, pos: 0 }
+    }
+
+    /// Reads a varint.
+    pub fn varint(&mut self) -> Result<u64, MigrationError> {
+        let mut value = 0u64;
+        for shift in (0..64).step_by(7) {
+            let byte = self.byte()?;
+            value |= u64::from(byte & 0x7F) << shift;
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+        Err(MigrationError::Corrupt)
+    }
+
+    /// Reads a varint into a narrower integer.
+    pub fn varint_into<T: TryFrom<u64>>(&mut self) -> Result<T, MigrationError> {
+        self.varint()?
+            .try_into()
+            .map_err(|_| MigrationError::Corrupt)
+    }
+
+    /// Reads a byte.
+    pub fn byte(&mut self) -> Result<u8, MigrationError> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    /// Reads `len` bytes.
+    pub fn bytes(&mut self, len: usize) -> Result<&'a [u8], MigrationError> {
+        let bytes = self
+            .data
+            .get(self.pos..self.pos.saturating_add(len))
+            .ok_or(MigrationError::Truncated)?;
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    /// Checks whether everything was read.
+    pub fn is_empty(&self) -> bool {
+        self.pos == self.data.len()
+    }
+}
+
+/// The registers of a vCPU, by architecture-defined ids.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct CpuState {
+    regs: Vec<(u32, u64)>,
+}
+
+impl CpuState {
+    /// Sets register `id` to `value`.
+    pub fn set(&mut self, id: u32, value: u64) {
+        match self.regs.iter_mut().find(|(reg, _)| *reg == id) {
+            Some((_, old)) => *old = value,
+            None => self.regs.push((id, value)),
+        }
+    }
+
+    /// Returns the value of register `id`, if the state has it.
+    pub fn get(&self, id: u32) -> Option<u64> {
+        self.regs
+            .iter()
+            .find_map(|&(reg, value)| (reg == id).then_some(value))
+    }
+
+    /// Returns every register with its id.
+    pub fn regs(&self) -> &[(u32, u64)] {
+        &self.regs
+    }
+}
+
+/// What a migration stream carries besides RAM.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct VmState {
+    /// The vCPUs, by index.
+    pub cpus: Vec<CpuState>,
+    /// The state of the emulated devices, by base address.
+    pub devices: Vec<(u64, Vec<u8>)>,
+    /// Guest time at the stop-and-copy, in the architecture's unit.
+    pub guest_time: u64,
+}
+
+/// When the pre-copy ends.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct MigrationLimits {
+    /// Most pre-copy rounds, the first one included.
+    pub max_rounds: usize,
+    /// Dirty pages of a round small enough to stop the guest for.
+    pub converge_pages: usize,
+}
+
+impl Default for MigrationLimits {
+    fn default() -> Self {
+        Self {
+            max_rounds: 8,
+            converge_pages: 64,
+        }
+    }
+}
+
+/// Where the sender is.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SendPhase {
+    /// Nothing was sent.
+    Start,
+    /// Pre-copy round `round` (from 1) was sent.
+    PreCopy { round: usize },
+    /// The pre-copy is over: stop the guest and copy the rest.
+    StopAndCopy,
+    /// The stream is complete.
+    Done,
+}
+
+/// What a migration sent.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct MigrationStats {
+    /// Pre-copy rounds.
+    pub rounds: usize,
+    /// Pages sent, in all rounds.
+    pub pages: usize,
+    /// Pages sent while the guest was stopped.
+    pub final_pages: usize,
+    /// Bytes of the stream.
+    pub bytes: usize,
+}
+
+/// Encodes the migration stream of a VM.
+pub struct MigrationSender {
+    ram_base: usize,
+    limits: MigrationLimits,
+    phase: SendPhase,
+    /// The contents each page of RAM was last sent with, `None` for a zero
+    /// page.
+    sent: Vec<Option<Box<[u8]>>>,
+    out: StateWriter,
+    /// Bytes taken from `out` so far.
+    taken: usize,
+    stats: MigrationStats,
+}
+
+impl MigrationSender {
+    /// Starts the stream of a VM of architecture `arch` whose RAM is
+    /// `ram_size` bytes at `ram_base`.
+    pub fn new(arch: TraceArch, ram_base: usize, ram_size: usize, limits: MigrationLimits) -> Self {
+        let mut out = StateWriter::new();
+        out.bytes(&MIGRATION_MAGIC);
+        out.byte(MIGRATION_VERSION);
+        out.byte(arch as u8);
+        out.varint(ram_base as u64);
+        out.varint(ram_size as u64);
+        Self {
+            ram_base,
+            limits,
+            phase: SendPhase::Start,
+            sent: (0..ram_size / PAGE_SIZE).map(|_| None).collect(),
+            out,
+            taken: 0,
+            stats: MigrationStats::default(),
+        }
+    }
+
+    /// Returns where the sender is.
+    pub fn phase(&self) -> SendPhase {
+        self.phase
+    }
+
+    /// Returns what was sent so far.
+    pub fn stats(&self) -> MigrationStats {
+        MigrationStats {
+            bytes: self.taken + self.out.len(),
+            ..self.stats
+        }
+    }
+
+    /// Takes the stream encoded since the last call.
+    pub fn take_output(&mut self) -> Vec<u8> {
+        self.taken += self.out.len();
+        self.out.take()
+    }
+
+    /// Sends all of RAM, the first pre-copy round. The dirty log must have
+    /// been cleared right before: later rounds send what it catches.
+    pub fn first_round(&mut self, mem: &impl GuestMemory) -> SendPhase {
+        self.send_pages(mem, 0..self.sent.len(), false);
+        self.end_round(usize::MAX)
+    }
+
+    /// Sends the pages at the guest physical addresses in `dirty`, a
+    /// pre-copy round after the first, and decides whether the guest is
+    /// stopped next.
+    pub fn precopy_round(&mut self, mem: &impl GuestMemory, dirty: &[usize]) -> SendPhase {
+        let pages: Vec<_> = dirty.iter().filter_map(|&gpa| self.page(gpa)).collect();
+        self.send_pages(mem, pages, false);
+        self.end_round(dirty.len())
+    }
+
+    /// Sends the last dirty pages and what changed behind the dirty log
+    /// while the guest is stopped, then `state`, and ends the stream.
+    pub fn stop_and_copy(
+        &mut self,
+        mem: &impl GuestMemory,
+        dirty: &[usize],
+        state: &VmState,
+    ) -> MigrationStats {
+        let pages: Vec<_> = dirty.iter().filter_map(|&gpa| self.page(gpa)).collect();
+        let logged = self.send_pages(mem, pages, false);
+        let unlogged = self.send_pages(mem, 0..self.sent.len(), true);
+        self.stats.final_pages = logged + unlogged;
+
+        for (index, cpu) in state.cpus.iter().enumerate() {
+            self.out.byte(RECORD_CPU);
+            self.out.varint(index as u64);
+            self.out.varint(cpu.regs.len() as u64);
+            for &(id, value) in &cpu.regs {
+                self.out.varint(id.into());
+                self.out.varint(value);
+            }
+        }
+        for (base, data) in &state.devices {
+            self.out.byte(RECORD_DEVICE);
+            self.out.varint(*base);
+            self.out.varint(data.len() as u64);
+            self.out.bytes(data);
+        }
+        self.out.byte(RECORD_TIME);
+        self.out.varint(state.guest_time);
+        self.out.byte(RECORD_END);
+        self.phase = SendPhase::Done;
+        self.stats()
+    }
+
+    /// Returns the index in RAM of the page at `gpa`.
+    fn page(&self, gpa: usize) -> Option<usize> {
+        let page = gpa.checked_sub(self.ram_base)? / PAGE_SIZE;
+        (page < self.sent.len()).then_some(page)
+    }
+
+    fn end_round(&mut self, dirty: usize) -> SendPhase {
+        let round = self.stats.rounds + 1;
+        self.stats.rounds = round;
+        self.phase = if dirty <= self.limits.converge_pages || round >= self.limits.max_rounds {
+            SendPhase::StopAndCopy
+        } else {
+            SendPhase::PreCopy { round }
+        };
+        self.phase
+    }
+
+    /// Sends `pages` (indexes in RAM), or with `changed_only` those whose
+    /// contents differ from what was last sent. A page that cannot be read
+    /// is sent as a zero page. Returns the number of pages sent.
+    fn send_pages(
+        &mut self,
+        mem: &impl GuestMemory,
+        pages: impl IntoIterator<Item = usize>,
+        changed_only: bool,
+    ) -> usize {
+        let mut data = vec![0u8; PAGE_SIZE];
+        let mut record = StateWriter::new();
+        let (mut batch, mut sent) = (0, 0);
+        for page in pages {
+            if mem
+                .copy_from_guest(self.ram_base + page * PAGE_SIZE, &mut data)
+                .is_err()
+            {
+                data.fill(0);
+            }
+            let zero = data.iter().all(|&b| b == 0);
+            let slot = &mut self.sent[page];
+            if changed_only {
+                let unchanged = match slot {
+                    Some(sent) => **sent == *data,
+                    None => zero,
+                };
+                if unchanged {
+                    continue;
+                }
+            }
+            record.varint(page as u64);
+            if zero {
+                record.byte(PAGE_ZERO);
+                *slot = None;
+            } else {
+                record.byte(PAGE_DATA);
+                record.bytes(&data);
+                match slot {
+                    Some(sent) => sent.copy_from_slice(&data),
+                    None => *slot = Some(data.clone().into_boxed_slice()),
+                }
+            }
+            batch += 1;
+            sent += 1;
+            if batch == PAGES_PER_RECORD {
+                self.put_pages(&mut record, batch);
+                batch = 0;
+            }
+        }
+        if batch > 0 {
+            self.put_pages(&mut record, batch);
+        }
+        self.stats.pages += sent;
+        sent
+    }
+
+    fn put_pages(&mut self, record: &mut StateWriter, count: usize) {
+        self.out.byte(RECORD_PAGES);
+        self.out.varint(count as u64);
+        self.out.bytes(&record.take());
+    }
+}
+
+/// Decodes a migration stream into a VM.
+pub struct MigrationReceiver {
+    arch: TraceArch,
+    ram_base: usize,
+    ram_size: usize,
+    /// The part of the stream fed that does not complete a record yet.
+    pending: Vec<u8>,
+    header: bool,
+    done: bool,
+    state: VmState,
+    pages: usize,
+}
+
+impl MigrationReceiver {
+    /// Creates the receiver of a VM of architecture `arch` whose RAM is
+    /// `ram_size` bytes at `ram_base`.
+    pub fn new(arch: TraceArch, ram_base: usize, ram_size: usize) -> Self {
+        Self {
+            arch,
+            ram_base,
+            ram_size,
+            pending: Vec::new(),
+            header: false,
+            done: false,
+            state: VmState::default(),
+            pages: 0,
+        }
+    }
+
+    /// Decodes the next `data` of the stream, writing the pages it
+    /// completes to `mem`. Returns `true` once the end record was read;
+    /// anything after it is ignored.
+    pub fn feed(
+        &mut self,
+        data: &[u8],
+        mem: &mut impl GuestMemory,
+    ) -> Result<bool, MigrationError> {
+        if self.done {
+            return Ok(true);
+        }
+        let mut pending = core::mem::take(&mut self.pending);
+        pending.extend_from_slice(data);
+        let mut reader = StateReader::new(&pending);
+        let mut consumed = 0;
+        while !self.done && !reader.is_empty() {
+            let result = if self.header {
+                self.record(&mut reader, mem)
+            } else {
+                self.read_header(&mut reader)
+            };
+            match result {
+                Ok(()) => consumed = reader.pos,
+                // Wait for the rest of the record.
+                Err(MigrationError::Truncated) => break,
+                Err(e) => return Err(e),
+            }
+        }
+        pending.drain(..consumed);
+        self.pending = pending;
+        Ok(self.done)
+    }
+
+    /// Returns the state the stream ended with.
+    pub fn finish(&mut self) -> Result<VmState, MigrationError> {
+        if !self.done {
+            return Err(MigrationError::Truncated);
+        }
+        Ok(core::mem::take(&mut self.state))
+    }
+
+    /// Returns the number of pages received.
+    pub fn pages(&self) -> usize {
+        self.pages
+    }
+
+    fn read_header(&mut self, reader: &mut StateReader) -> Result<(), MigrationError> {
+        let magic = reader.bytes(MIGRATION_MAGIC.len())?;
+        if magic != MIGRATION_MAGIC {
+            return Err(MigrationError::BadMagic);
+        }
+        let version = reader.byte()?;
+        if version != MIGRATION_VERSION {
+            return Err(MigrationError::Version(version));
+        }
+        let arch = reader.byte()?;
+        if TraceArch::from_code(arch) != Some(self.arch) {
+            return Err(MigrationError::Arch(arch));
+        }
+        let ram_base = reader.varint_into::<usize>()?;
+        let ram_size = reader.varint_into::<usize>()?;
+        if (ram_base, ram_size) != (self.ram_base, self.ram_size) {
+            return Err(MigrationError::Layout);
+        }
+        self.header = true;
+        Ok(())
+    }
+
+    /// Reads one record and applies it. Nothing is applied if the record is
+    /// incomplete.
+    fn record(
+        &mut self,
+        reader: &mut StateReader,
+        mem: &mut impl GuestMemory,
+    ) -> Result<(), MigrationError> {
+        match reader.byte()? {
+            RECORD_PAGES => {
+                let count = reader.varint_into::<usize>()?;
+                if count > PAGES_PER_RECORD {
+                    return Err(MigrationError::Corrupt);
+                }
+                let mut pages = Vec::with_capacity(count);
+                for _ in 0..count {
+                    let page = reader.varint_into::<usize>()?;
+                    if page >= self.ram_size / PAGE_SIZE {
+                        return Err(MigrationError::Corrupt);
+                    }
+                    let data = match reader.byte()? {
+                        PAGE_ZERO => None,
+                        PAGE_DATA => Some(reader.bytes(PAGE_SIZE)?),
+                        _ => return Err(MigrationError::Corrupt),
+                    };
+                    pages.push((self.ram_base + page * PAGE_SIZE, data));
+                }
+                for (gpa, data) in pages {
+                    write_page(mem, gpa, data).map_err(|_| MigrationError::Memory(gpa))?;
+                }
+                self.pages += count;
+            }
+            RECORD_CPU => {
+                let index = reader.varint_into::<usize>()?;
+                let count = reader.varint_into::<usize>()?;
+                if index >= MAX_CPUS {
+                    return Err(MigrationError::Corrupt);
+                }
+                let mut cpu = CpuState::default();
+                for _ in 0..count {
+                    let id = reader.varint_into()?;
+                    cpu.set(id, reader.varint()?);
+                }
+                if self.state.cpus.len() <= index {
+                    self.state.cpus.resize(index + 1, CpuState::default());
+                }
+                self.state.cpus[index] = cpu;
+            }
+            RECORD_DEVICE => {
+                let base = reader.varint()?;
+                let len = reader.varint_into::<usize>()?;
+                let data = reader.bytes(len)?.to_vec();
+                self.state.devices.push((base, data));
+            }
+            RECORD_TIME => self.state.guest_time = reader.varint()?,
+            RECORD_END => self.done = true,
+            _ => return Err(MigrationError::Corrupt),
+        }
+        Ok(())
+    }
+}
+
+/// Writes a received page to guest memory. A zero page is only written if
+/// the page is not zero already, which leaves lazily backed RAM unbacked.
+fn write_page(mem: &mut impl GuestMemory, gpa: usize, data: Option<&[u8]>) -> axerrno::AxResult {
+    match data {
+        Some(data) => mem.copy_to_guest(gpa, data),
+        None => {
+            let mut page = vec![0u8; PAGE_SIZE];
+            if mem.copy_from_guest(gpa, &mut page).is_ok() && page.iter().all(|&b| b == 0) {
+                return Ok(());
+            }
+            page.fill(0);
+            mem.copy_to_guest(gpa, &page)
+        }
+    }
+}
+
+#[cfg(all(feature = "axstd", target_arch = "riscv64"))]
+pub use transport::{Outgoing, receive};
+
+#[cfg(all(feature = "axstd", target_arch = "riscv64"))]
+mod transport {
+    use alloc::string::{String, ToString};
+    use alloc::vec;
+    use core::time::Duration;
+
+    use axerrno::{AxError, AxResult};
+    use axstd::fs::File;
+    use axstd::io::{Read, Seek, SeekFrom, Write};
+
+    use super::{
+        GuestMemory, MigrateConfig, MigrationError, MigrationLimits, MigrationReceiver,
+        MigrationSender, MigrationStats, SendPhase, TraceArch, VmState,
+    };
+
+    /// Time between two pre-copy rounds, in nanoseconds: the guest runs in
+    /// between and dirties the pages of the next round.
+    const ROUND_INTERVAL_NS: u64 = 100_000_000;
+    /// How long the receiver waits for the stream to grow.
+    const STALL_TIMEOUT: Duration = Duration::from_secs(30);
+    const POLL_INTERVAL: Duration = Duration::from_millis(10);
+    const READ_CHUNK: usize = 64 * 1024;
+
+    /// The migration of a VM out to its stream file.
+    pub struct Outgoing {
+        vm: usize,
+        path: String,
+        file: File,
+        sender: MigrationSender,
+        /// Host time of the next step, in nanoseconds.
+        next_ns: u64,
+    }
+
+    impl Outgoing {
+        /// Creates the stream of VM `vm`, whose RAM is `ram_size` bytes at
+        /// `ram_base`, as configured by `config`; the migration starts
+        /// `config.after_secs` after `now_ns`.
+        pub fn create(
+            vm: usize,
+            config: &MigrateConfig,
+            arch: TraceArch,
+            (ram_base, ram_size): (usize, usize),
+            now_ns: u64,
+        ) -> AxResult<Self> {
+            let file = File::create(&config.path).map_err(|_| AxError::NotFound)?;
+            Ok(Self {
+                vm,
+                path: config.path.to_string(),
+                file,
+                sender: MigrationSender::new(arch, ram_base, ram_size, MigrationLimits::default()),
+                next_ns: now_ns.saturating_add(config.after_secs * 1_000_000_000),
+            })
+        }
+
+        /// Returns the path of the stream.
+        pub fn path(&self) -> &str {
+            &self.path
+        }
+
+        /// Returns where the sender is.
+        pub fn phase(&self) -> SendPhase {
+            self.sender.phase()
+        }
+
+        /// Checks whether the next step is due at `now_ns`.
+        pub fn due(&self, now_ns: u64) -> bool {
+            self.phase() != SendPhase::Done && now_ns >= self.next_ns
+        }
+
+        /// Sends the next pre-copy round at `now_ns`: all of RAM the first
+        /// time, then the pages in `dirty`, the guest physical addresses
+        /// dirtied since the previous round.
+        pub fn precopy(
+            &mut self,
+            now_ns: u64,
+            mem: &impl GuestMemory,
+            dirty: &[usize],
+        ) -> AxResult<SendPhase> {
+            let phase = match self.sender.phase() {
+                SendPhase::Start => {
+                    vm_println!(self.vm, "Migrating to {}...", self.path);
+                    self.sender.first_round(mem)
+                }
+                _ => self.sender.precopy_round(mem, dirty),
+            };
+            self.flush()?;
+            self.next_ns = now_ns.saturating_add(ROUND_INTERVAL_NS);
+            Ok(phase)
+        }
+
+        /// Ends the stream with the stop-and-copy of the stopped guest:
+        /// the pages in `dirty`, those changed behind the dirty log and
+        /// `state`.
+        pub fn finish(
+            &mut self,
+            mem: &impl GuestMemory,
+            dirty: &[usize],
+            state: &VmState,
+        ) -> AxResult<MigrationStats> {
+            let stats = self.sender.stop_and_copy(mem, dirty, state);
+            self.flush()?;
+            Ok(stats)
+        }
+
+        fn flush(&mut self) -> AxResult {
+            let data = self.sender.take_output();
+            self.file
+                .write_all(&data)
+                .and_then(|_| self.file.flush())
+                .map_err(|_| AxError::Io)
+        }
+    }
+
+    /// Reads the stream at `path` into `receiver` and `mem` as it is
+    /// written, until its end record, and returns the state it ends with.
+    /// Waits for the file to appear and to grow, for at most
+    /// [`STALL_TIMEOUT`] at a time.
+    pub fn receive(
+        path: &str,
+        receiver: &mut MigrationReceiver,
+        mem: &mut impl GuestMemory,
+    ) -> Result<VmState, MigrationError> {
+        let mut buf = vec![0u8; READ_CHUNK];
+        let (mut pos, mut waited) = (0u64, Duration::ZERO);
+        loop {
+            if let Ok(mut file) = File::open(path) {
+                file.seek(SeekFrom::Start(pos))
+                    .map_err(|_| MigrationError::Io)?;
+                loop {
+                    let len = file.read(&mut buf).map_err(|_| MigrationError::Io)?;
+                    if len == 0 {
+                        break;
+                    }
+                    pos += len as u64;
+                    waited = Duration::ZERO;
+                    if receiver.feed(&buf[..len], mem)? {
+                        return receiver.finish();
+                    }
+                }
+            }
+            if waited >= STALL_TIMEOUT {
+                return Err(MigrationError::Stalled);
+            }
+            std::thread::sleep(POLL_INTERVAL);
+            waited += POLL_INTERVAL;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use memory_addr::VirtAddr;
+
+    use super::*;
+    use crate::gspace::GuestSpace;
+
+    const BASE: usize = 0x8000_0000;
+    const SIZE: usize = 64 * PAGE_SIZE;
+
+    fn space() -> GuestSpace {
+        GuestSpace::new(VirtAddr::from(BASE), SIZE).unwrap()
+    }
+
+    fn state() -> VmState {
+        let mut cpu = CpuState::default();
+        cpu.set(1, 0x8020_0000);
+        cpu.set(0x1141, u64::MAX);
+        VmState {
+            cpus: vec![cpu],
+            devices: vec![(0x1000_1000, vec![1, 2, 3]), (0x10_1000, vec![])],
+            guest_time: 123_456_789,
+        }
+    }
+
+    /// Feeds `stream` to a receiver in pieces of `chunk` bytes.
+    fn receive(
+        stream: &[u8],
+        chunk: usize,
+        mem: &mut GuestSpace,
+    ) -> Result<VmState, MigrationError> {
+        let mut receiver = MigrationReceiver::new(TraceArch::Riscv64, BASE, SIZE);
+        for piece in stream.chunks(chunk) {
+            if receiver.feed(piece, mem)? {
+                break;
+            }
+        }
+        receiver.finish()
+    }
+
+    #[test]
+    fn parse_config() {
+        let config = MigrateConfig::parse("/mig/vm0@5").unwrap();
+        assert_eq!(config.path, "/mig/vm0");
+        assert_eq!(config.after_secs, 5);
+        assert_eq!(MigrateConfig::parse("/mig/vm0").unwrap().after_secs, 0);
+        assert_eq!(MigrateConfig::parse("/mig@3601"), None);
+        assert_eq!(MigrateConfig::parse("/mig@soon"), None);
+        assert_eq!(MigrateConfig::parse("@5"), None);
+    }
+
+    #[test]
+    fn migrates_ram_and_state() {
+        let mut src = space();
+        src.write_obj(BASE, &0x1122_3344u32).unwrap();
+        src.write_obj(BASE + 5 * PAGE_SIZE + 8, &7u64).unwrap();
+        let mut sender =
+            MigrationSender::new(TraceArch::Riscv64, BASE, SIZE, MigrationLimits::default());
+        let mut stream = Vec::new();
+        assert_eq!(sender.first_round(&src), SendPhase::PreCopy { round: 1 });
+        stream.extend(sender.take_output());
+
+        // The guest writes a page, the dirty log catches it; the hypervisor
+        // writes another one and clears a third behind the log.
+        src.write_obj(BASE + 9 * PAGE_SIZE, &9u8).unwrap();
+        sender.precopy_round(&src, &[BASE + 9 * PAGE_SIZE]);
+        src.write_obj(BASE + 63 * PAGE_SIZE + 0xFF8, &63u64)
+            .unwrap();
+        src.write_obj(BASE + 5 * PAGE_SIZE + 8, &0u64).unwrap();
+        src.write_obj(BASE + 10 * PAGE_SIZE, &10u8).unwrap();
+        let stats = sender.stop_and_copy(&src, &[BASE + 10 * PAGE_SIZE], &state());
+        assert_eq!(sender.phase(), SendPhase::Done);
+        assert_eq!(stats.rounds, 2);
+        assert_eq!(stats.final_pages, 3);
+        assert_eq!(stats.pages, 64 + 1 + 3);
+        stream.extend(sender.take_output());
+        assert_eq!(stats.bytes, stream.len());
+
+        // Zero pages are not sent in full.
+        assert!(stream.len() < 8 * PAGE_SIZE);
+
+        // A page the destination has dirty is cleared.
+        let mut dst = space();
+        dst.write_obj(BASE + 20 * PAGE_SIZE, &0xFFu8).unwrap();
+        assert_eq!(receive(&stream, 1000, &mut dst), Ok(state()));
+        let mut a = vec![0u8; SIZE];
+        let mut b = vec![0u8; SIZE];
+        src.copy_from_guest(BASE, &mut a).unwrap();
+        dst.copy_from_guest(BASE, &mut b).unwrap();
+        assert!(a == b);
+    }
+
+    #[test]
+    fn precopy_converges() {
+        let src = space();
+        let limits = MigrationLimits {
+            max_rounds: 3,
+            converge_pages: 2,
+        };
+        let mut sender = MigrationSender::new(TraceArch::Riscv64, BASE, SIZE, limits);
+        assert_eq!(sender.first_round(&src), SendPhase::PreCopy { round: 1 });
+        let dirty: Vec<_> = (0..4).map(|i| BASE + i * PAGE_SIZE).collect();
+        assert_eq!(
+            sender.precopy_round(&src, &dirty),
+            SendPhase::PreCopy { round: 2 }
+        );
+        // Few dirty pages: stop now.
+        assert_eq!(
+            sender.precopy_round(&src, &dirty[..2]),
+            SendPhase::StopAndCopy
+        );
+
+        // The guest never settles: stop after the last round.
+        let mut sender = MigrationSender::new(TraceArch::Riscv64, BASE, SIZE, limits);
+        sender.first_round(&src);
+        sender.precopy_round(&src, &dirty);
+        assert_eq!(sender.precopy_round(&src, &dirty), SendPhase::StopAndCopy);
+        assert_eq!(sender.stats().rounds, 3);
+    }
+
+    #[test]
+    fn bad_streams() {
+        let mut mem = space();
+        let mut sender =
+            MigrationSender::new(TraceArch::Riscv64, BASE, SIZE, MigrationLimits::default());
+        sender.first_round(&mem);
+        sender.stop_and_copy(&mem, &[], &state());
+        let stream = sender.take_output();
+
+        assert_eq!(
+            receive(b"GXIT\x01", 5, &mut mem),
+            Err(MigrationError::BadMagic)
+        );
+        let mut bad = stream.clone();
+        bad[4] = MIGRATION_VERSION + 1;
+        assert_eq!(
+            receive(&bad, 64, &mut mem),
+            Err(MigrationError::Version(MIGRATION_VERSION + 1))
+        );
+        bad = stream.clone();
+        bad[5] = TraceArch::Aarch64 as u8;
+        assert_eq!(receive(&bad, 64, &mut mem), Err(MigrationError::Arch(2)));
+        let mut other = MigrationReceiver::new(TraceArch::Riscv64, BASE, 2 * SIZE);
+        assert_eq!(other.feed(&stream, &mut mem), Err(MigrationError::Layout));
+
+        // Cut anywhere, the stream is incomplete.
+        assert_eq!(
+            receive(&stream[..stream.len() - 1], 64, &mut mem),
+            Err(MigrationError::Truncated)
+        );
+        // A page outside RAM, an unknown record.
+        let header_len =
+            MigrationSender::new(TraceArch::Riscv64, BASE, SIZE, MigrationLimits::default())
+                .stats()
+                .bytes;
+        bad = stream[..header_len].to_vec();
+        bad.extend([RECORD_PAGES, 1, 64, PAGE_ZERO]);
+        assert_eq!(receive(&bad, 64, &mut mem), Err(MigrationError::Corrupt));
+        bad.truncate(header_len);
+        bad.push(5);
+        assert_eq!(receive(&bad, 64, &mut mem), Err(MigrationError::Corrupt));
+    }
+}
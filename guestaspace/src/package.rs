@@ -0,0 +1,720 @@
+//! Packaged guest images.
+//!
+//! A package is a guest image with the metadata a flat binary lacks: the
+//! entry point, the guest RAM it needs and the address, memory size and
+//! permissions of each of its segments, so zero-initialized data beyond
+//! the file contents (`.bss`) and gaps between segments survive packaging
+//! and code and data get the stage-2 permissions they need instead of
+//! one RWX blob. `cargo xtask package` builds one from the guest's ELF
+//! ([`Package::from_elf`]); the hypervisor recognizes it by its magic and
+//! copies the segments into guest RAM
+//! ([`crate::loader::map_ram_with_package`]).
+//!
+//! A flat binary gets the same treatment with a [`LoadDescriptor`] next to
+//! it, a file generated at build time with the sizes of its text, data and
+//! bss; it turns the binary into a package ([`LoadDescriptor::package`]).
+//!
+//! Everything here uses only `core` and `alloc`, so xtask includes this
+//! file to write packages.
+//!
+//! # Format
+//!
+//! All integers are little-endian. The 32-byte header is the magic `GAPK`,
+//! the format version (u32), the entry point (u64), the guest RAM needed
+//! (u64, 0 for the hypervisor's default), the number of segments (u32) and
+//! a reserved u32. A 40-byte entry per segment follows: its guest physical
+//! address, the file offset and size of its contents and its size in
+//! memory (u64 each), then its permissions (u32, the ELF `p_flags` bits)
+//! and a reserved u32. The contents of the segments come last. Version 1
+//! entries are 32 bytes, without permissions; their segments are RWX.
+//!
+//! A load descriptor is the magic `GALD`, the format version (u32), the
+//! entry point and the sizes of text, data and bss (u64 each).
+
+#![allow(dead_code)]
+
+use alloc::vec::Vec;
+use core::fmt;
+
+/// Magic at the start of a package.
+pub const PACKAGE_MAGIC: [u8; 4] = *b"GAPK";
+/// Version of the format written by this build; it reads version 1 too.
+pub const PACKAGE_VERSION: u32 = 2;
+/// Size of the header.
+pub const HEADER_SIZE: usize = 32;
+/// Size of a segment table entry.
+pub const SEGMENT_SIZE: usize = 40;
+/// Size of a segment table entry of version 1.
+const SEGMENT_SIZE_V1: usize = 32;
+/// Most segments in a package.
+pub const MAX_SEGMENTS: usize = 16;
+/// Bytes that hold the header and the largest segment table.
+pub const MAX_TABLE_SIZE: usize = HEADER_SIZE + MAX_SEGMENTS * SEGMENT_SIZE;
+
+/// Segment permission: executable.
+pub const SEGMENT_EXEC: u32 = 1;
+/// Segment permission: writable.
+pub const SEGMENT_WRITE: u32 = 2;
+/// Segment permission: readable.
+pub const SEGMENT_READ: u32 = 4;
+/// Permissions of segments without any (version 1).
+pub const SEGMENT_RWX: u32 = SEGMENT_READ | SEGMENT_WRITE | SEGMENT_EXEC;
+
+/// Magic at the start of a load descriptor.
+pub const DESCRIPTOR_MAGIC: [u8; 4] = *b"GALD";
+/// Version of the load descriptor format.
+pub const DESCRIPTOR_VERSION: u32 = 1;
+/// Size of a load descriptor.
+pub const DESCRIPTOR_SIZE: usize = 40;
+
+/// One segment of a package.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Segment {
+    /// Guest physical address of the segment.
+    pub gpa: u64,
+    /// Offset of its contents in the package.
+    pub offset: u64,
+    /// Size of its contents; the rest of the segment is zeroed.
+    pub file_size: u64,
+    /// Size of the segment in guest memory.
+    pub mem_size: u64,
+    /// Permissions, a combination of `SEGMENT_READ`, `SEGMENT_WRITE` and
+    /// `SEGMENT_EXEC`.
+    pub flags: u32,
+}
+
+impl Segment {
+    /// Guest physical address just past the segment.
+    pub fn end(&self) -> u64 {
+        self.gpa + self.mem_size
+    }
+}
+
+/// The header and segment table of a package.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Package {
+    /// Guest physical address the guest starts at.
+    pub entry: u64,
+    /// Guest RAM the image needs in bytes, or 0 for the default.
+    pub ram_size: u64,
+    /// The segments, in table order.
+    pub segments: Vec<Segment>,
+}
+
+/// Why a package cannot be read or built.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PackageError {
+    /// The data does not start with [`PACKAGE_MAGIC`].
+    BadMagic,
+    /// The package has a format version this build does not read.
+    Version(u32),
+    /// The package has no segments or more than [`MAX_SEGMENTS`].
+    Segments(u32),
+    /// A segment's contents lie outside the package or exceed its memory
+    /// size, or the segment wraps around the address space.
+    BadSegment(usize),
+    /// The entry point lies outside every segment.
+    Entry(u64),
+    /// The data ends in the middle of the header or segment table.
+    Truncated,
+    /// The input of [`Package::from_elf`] is not a usable ELF64 file.
+    Elf(&'static str),
+}
+
+impl fmt::Display for PackageError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::BadMagic => write!(f, "not a guest package"),
+            Self::Version(version) => write!(f, "unsupported package version {version}"),
+            Self::Segments(count) => write!(f, "bad segment count {count}"),
+            Self::BadSegment(index) => write!(f, "segment {index} invalid"),
+            Self::Entry(entry) => write!(f, "entry point {entry:#x} outside the segments"),
+            Self::Truncated => write!(f, "package truncated"),
+            Self::Elf(reason) => write!(f, "bad ELF file: {reason}"),
+        }
+    }
+}
+
+fn u32_at(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap())
+}
+
+fn u64_at(data: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(data[offset..offset + 8].try_into().unwrap())
+}
+
+/// Returns whether `data` starts with the package magic.
+pub fn is_package(data: &[u8]) -> bool {
+    data.starts_with(&PACKAGE_MAGIC)
+}
+
+impl Package {
+    /// Parses the header and segment table at the start of a package of
+    /// `len` bytes; `data` needs to hold no more than [`MAX_TABLE_SIZE`]
+    /// bytes.
+    pub fn parse(data: &[u8], len: usize) -> Result<Self, PackageError> {
+        if !is_package(data) {
+            return Err(PackageError::BadMagic);
+        }
+        if data.len() < HEADER_SIZE {
+            return Err(PackageError::Truncated);
+        }
+        let version = u32_at(data, 4);
+        let entry_size = match version {
+            1 => SEGMENT_SIZE_V1,
+            PACKAGE_VERSION => SEGMENT_SIZE,
+            _ => return Err(PackageError::Version(version)),
+        };
+        let count = u32_at(data, 24);
+        if count == 0 || count as usize > MAX_SEGMENTS {
+            return Err(PackageError::Segments(count));
+        }
+        let table_end = HEADER_SIZE + count as usize * entry_size;
+        if data.len() < table_end || len < table_end {
+            return Err(PackageError::Truncated);
+        }
+        let segments = (0..count as usize)
+            .map(|index| {
+                let entry = &data[HEADER_SIZE + index * entry_size..];
+                let segment = Segment {
+                    gpa: u64_at(entry, 0),
+                    offset: u64_at(entry, 8),
+                    file_size: u64_at(entry, 16),
+                    mem_size: u64_at(entry, 24),
+                    flags: match version {
+                        1 => SEGMENT_RWX,
+                        _ => u32_at(entry, 32) & SEGMENT_RWX,
+                    },
+                };
+                let contents_end = segment.offset.checked_add(segment.file_size);
+                let valid = contents_end.is_some_and(|end| end <= len as u64)
+                    && segment.file_size <= segment.mem_size
+                    && segment.gpa.checked_add(segment.mem_size).is_some();
+                valid
+                    .then_some(segment)
+                    .ok_or(PackageError::BadSegment(index))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        let package = Self {
+            entry: u64_at(data, 8),
+            ram_size: u64_at(data, 16),
+            segments,
+        };
+        package.check_entry()
+    }
+
+    /// Returns the package if its entry point lies in one of its segments.
+    fn check_entry(self) -> Result<Self, PackageError> {
+        match self
+            .segments
+            .iter()
+            .any(|s| s.gpa <= self.entry && self.entry < s.end())
+        {
+            true => Ok(self),
+            false => Err(PackageError::Entry(self.entry)),
+        }
+    }
+
+    /// Returns the guest physical range `[start, end)` the segments cover.
+    pub fn span(&self) -> (u64, u64) {
+        let start = self.segments.iter().map(|s| s.gpa).min().unwrap_or(0);
+        let end = self.segments.iter().map(Segment::end).max().unwrap_or(0);
+        (start, end)
+    }
+
+    /// Returns whether all segments lie in `[start, limit)`.
+    pub fn fits(&self, start: u64, limit: u64) -> bool {
+        let (lo, hi) = self.span();
+        start <= lo && hi <= limit
+    }
+
+    /// Builds a package of `segments`, each given as its guest physical
+    /// address, memory size, permissions and contents; the segment table of
+    /// `self` is replaced.
+    pub fn write(&mut self, segments: &[(u64, u64, u32, &[u8])]) -> Vec<u8> {
+        let mut offset = (HEADER_SIZE + segments.len() * SEGMENT_SIZE) as u64;
+        self.segments = segments
+            .iter()
+            .map(|&(gpa, mem_size, flags, contents)| {
+                let segment = Segment {
+                    gpa,
+                    offset,
+                    file_size: contents.len() as u64,
+                    mem_size: mem_size.max(contents.len() as u64),
+                    flags,
+                };
+                offset = (offset + segment.file_size).next_multiple_of(16);
+                segment
+            })
+            .collect();
+
+        let mut out = Vec::with_capacity(offset as usize);
+        out.extend_from_slice(&PACKAGE_MAGIC);
+        out.extend_from_slice(&PACKAGE_VERSION.to_le_bytes());
+        out.extend_from_slice(&self.entry.to_le_bytes());
+        out.extend_from_slice(&self.ram_size.to_le_bytes());
+        out.extend_from_slice(&(segments.len() as u32).to_le_bytes());
+        out.extend_from_slice(&0u32.to_le_bytes());
+        for segment in &self.segments {
+            for field in [
+                segment.gpa,
+                segment.offset,
+                segment.file_size,
+                segment.mem_size,
+            ] {
+                out.extend_from_slice(&field.to_le_bytes());
+            }
+            out.extend_from_slice(&segment.flags.to_le_bytes());
+            out.extend_from_slice(&0u32.to_le_bytes());
+        }
+        for (segment, (_, _, _, contents)) in self.segments.iter().zip(segments) {
+            out.resize(segment.offset as usize, 0);
+            out.extend_from_slice(contents);
+        }
+        out
+    }
+
+    /// Builds a package from the loadable segments of the ELF64
+    /// little-endian file `elf`, placed so that the lowest one starts at
+    /// `load_addr` and the others keep their distance from it, as in a
+    /// flat binary made by `objcopy -O binary`.
+    pub fn from_elf(elf: &[u8], load_addr: u64, ram_size: u64) -> Result<Vec<u8>, PackageError> {
+        let (entry, segments) = elf_segments(elf, load_addr)?;
+        if segments.len() > MAX_SEGMENTS {
+            return Err(PackageError::Segments(segments.len() as u32));
+        }
+        let mut package = Self {
+            entry,
+            ram_size,
+            segments: Vec::new(),
+        };
+        Ok(package.write(&segments))
+    }
+}
+
+/// A loadable segment of an ELF file as (guest physical address, memory
+/// size, permissions, contents).
+type ElfSegment<'a> = (u64, u64, u32, &'a [u8]);
+
+/// Returns the entry point and the non-empty loadable segments of the
+/// ELF64 little-endian file `elf`, by address, placed so that the lowest
+/// one starts at `load_addr`.
+fn elf_segments(elf: &[u8], load_addr: u64) -> Result<(u64, Vec<ElfSegment<'_>>), PackageError> {
+    const PT_LOAD: u32 = 1;
+    if elf.len() < 64 || !elf.starts_with(b"\x7fELF") {
+        return Err(PackageError::Elf("no ELF header"));
+    }
+    if elf[4] != 2 || elf[5] != 1 {
+        return Err(PackageError::Elf("not 64-bit little-endian"));
+    }
+    let e_entry = u64_at(elf, 24);
+    let phoff = u64_at(elf, 32) as usize;
+    let phentsize = u16::from_le_bytes([elf[54], elf[55]]) as usize;
+    let phnum = u16::from_le_bytes([elf[56], elf[57]]) as usize;
+    if phentsize < 56 || phoff.saturating_add(phnum * phentsize) > elf.len() {
+        return Err(PackageError::Elf("program headers out of range"));
+    }
+
+    // (paddr, vaddr, memsz, flags, contents) of each non-empty PT_LOAD.
+    let mut loads = Vec::new();
+    for index in 0..phnum {
+        let ph = &elf[phoff + index * phentsize..];
+        let (offset, filesz, memsz) = (u64_at(ph, 8), u64_at(ph, 32), u64_at(ph, 40));
+        if u32_at(ph, 0) != PT_LOAD || memsz == 0 {
+            continue;
+        }
+        let contents = offset
+            .checked_add(filesz)
+            .filter(|&end| end <= elf.len() as u64)
+            .map(|end| &elf[offset as usize..end as usize])
+            .ok_or(PackageError::Elf("segment out of range"))?;
+        let flags = u32_at(ph, 4) & SEGMENT_RWX;
+        loads.push((u64_at(ph, 24), u64_at(ph, 16), memsz, flags, contents));
+    }
+    loads.sort_by_key(|load| load.0);
+    let base = loads
+        .first()
+        .map(|load| load.0)
+        .ok_or(PackageError::Elf("no loadable segments"))?;
+
+    let entry = loads
+        .iter()
+        .find(|&&(_, vaddr, memsz, _, _)| vaddr <= e_entry && e_entry - vaddr < memsz)
+        .map(|&(paddr, vaddr, ..)| load_addr + (paddr - base) + (e_entry - vaddr))
+        .ok_or(PackageError::Entry(e_entry))?;
+    let segments = loads
+        .iter()
+        .map(|&(paddr, _, memsz, flags, contents)| {
+            (load_addr + (paddr - base), memsz, flags, contents)
+        })
+        .collect();
+    Ok((entry, segments))
+}
+
+/// The layout of a flat binary: text (read-only and executable), then data
+/// (writable) and zeroed bss (writable), each following the previous one.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct LoadDescriptor {
+    /// Guest physical address the guest starts at.
+    pub entry: u64,
+    /// Size of the text at the start of the binary.
+    pub text_size: u64,
+    /// Size of the data after the text, to the end of the binary.
+    pub data_size: u64,
+    /// Size of the bss after the data.
+    pub bss_size: u64,
+}
+
+impl LoadDescriptor {
+    /// Parses a load descriptor.
+    pub fn parse(data: &[u8]) -> Result<Self, PackageError> {
+        if !data.starts_with(&DESCRIPTOR_MAGIC) {
+            return Err(PackageError::BadMagic);
+        }
+        if data.len() < DESCRIPTOR_SIZE {
+            return Err(PackageError::Truncated);
+        }
+        let version = u32_at(data, 4);
+        if version != DESCRIPTOR_VERSION {
+            return Err(PackageError::Version(version));
+        }
+        Ok(Self {
+            entry: u64_at(data, 8),
+            text_size: u64_at(data, 16),
+            data_size: u64_at(data, 24),
+            bss_size: u64_at(data, 32),
+        })
+    }
+
+    /// Encodes the descriptor.
+    pub fn to_bytes(self) -> [u8; DESCRIPTOR_SIZE] {
+        let mut out = [0u8; DESCRIPTOR_SIZE];
+        out[..4].copy_from_slice(&DESCRIPTOR_MAGIC);
+        out[4..8].copy_from_slice(&DESCRIPTOR_VERSION.to_le_bytes());
+        for (index, field) in [self.entry, self.text_size, self.data_size, self.bss_size]
+            .into_iter()
+            .enumerate()
+        {
+            out[8 + index * 8..16 + index * 8].copy_from_slice(&field.to_le_bytes());
+        }
+        out
+    }
+
+    /// Returns the flat binary `objcopy -O binary` makes of the ELF64
+    /// file `elf` and its descriptor when loaded at `load_addr`: the leading
+    /// segments without write permission are text, everything from the
+    /// first writable one up to the last file contents is data and the rest
+    /// is bss.
+    pub fn flat_binary(elf: &[u8], load_addr: u64) -> Result<(Vec<u8>, Self), PackageError> {
+        let (entry, segments) = elf_segments(elf, load_addr)?;
+        let mut binary = Vec::new();
+        for &(gpa, _, _, contents) in segments.iter().filter(|s| !s.3.is_empty()) {
+            let offset = (gpa - load_addr) as usize;
+            if binary.len() < offset + contents.len() {
+                binary.resize(offset + contents.len(), 0);
+            }
+            binary[offset..offset + contents.len()].copy_from_slice(contents);
+        }
+        let file_end = load_addr + binary.len() as u64;
+        let mem_end = segments
+            .iter()
+            .map(|segment| segment.0 + segment.1)
+            .max()
+            .unwrap_or(load_addr);
+        let data_start = segments
+            .iter()
+            .find(|segment| segment.2 & SEGMENT_WRITE != 0)
+            .map_or(file_end, |segment| segment.0)
+            .min(file_end);
+        let desc = Self {
+            entry,
+            text_size: data_start - load_addr,
+            data_size: file_end - data_start,
+            bss_size: mem_end.max(file_end) - file_end,
+        };
+        Ok((binary, desc))
+    }
+
+    /// Returns the package of the flat binary of `len` bytes it describes,
+    /// loaded at `load_addr`: text, data and bss as read-only executable,
+    /// writable and zeroed writable segments, at offsets into the binary.
+    pub fn package(&self, load_addr: u64, len: usize) -> Result<Package, PackageError> {
+        let text_end = self.text_size;
+        let data_end = text_end
+            .checked_add(self.data_size)
+            .filter(|&end| end <= len as u64)
+            .ok_or(PackageError::BadSegment(1))?;
+        let segments = [
+            (0, text_end, SEGMENT_READ | SEGMENT_EXEC),
+            (text_end, self.data_size, SEGMENT_READ | SEGMENT_WRITE),
+        ]
+        .into_iter()
+        .map(|(offset, size, flags)| Segment {
+            gpa: load_addr + offset,
+            offset,
+            file_size: size,
+            mem_size: size,
+            flags,
+        })
+        .chain([Segment {
+            gpa: load_addr + data_end,
+            offset: data_end,
+            file_size: 0,
+            mem_size: self.bss_size,
+            flags: SEGMENT_READ | SEGMENT_WRITE,
+        }])
+        .filter(|segment| segment.mem_size != 0)
+        .collect::<Vec<_>>();
+        let package = Package {
+            entry: self.entry,
+            ram_size: 0,
+            segments,
+        };
+        package.check_entry()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::vec;
+
+    use super::*;
+
+    #[test]
+    fn write_and_parse() {
+        let mut package = Package {
+            entry: 0x8020_0040,
+            ram_size: 0x200_0000,
+            segments: Vec::new(),
+        };
+        let data = package.write(&[
+            (
+                0x8020_0000,
+                0x1000,
+                SEGMENT_READ | SEGMENT_EXEC,
+                &[0xAA; 0x123],
+            ),
+            (
+                0x8030_0000,
+                0x8000,
+                SEGMENT_READ | SEGMENT_WRITE,
+                &[0x55; 0x20],
+            ),
+        ]);
+        assert_eq!(package.segments[1].offset % 16, 0);
+        assert_eq!(package.segments[1].mem_size, 0x8000);
+        assert_eq!(package.segments[1].flags, SEGMENT_READ | SEGMENT_WRITE);
+        let parsed = Package::parse(&data[..MAX_TABLE_SIZE.min(data.len())], data.len()).unwrap();
+        assert_eq!(parsed, package);
+        assert_eq!(parsed.span(), (0x8020_0000, 0x8030_8000));
+        assert!(parsed.fits(0x8000_0000, 0x8100_0000));
+        assert!(!parsed.fits(0x8000_0000, 0x8030_0000));
+        let s = parsed.segments[0];
+        assert_eq!(
+            &data[s.offset as usize..][..s.file_size as usize],
+            &[0xAA; 0x123]
+        );
+    }
+
+    #[test]
+    fn parse_rejects_bad_packages() {
+        let mut package = Package {
+            entry: 0x1000,
+            ..Package::default()
+        };
+        let data = package.write(&[(0x1000, 0x1000, SEGMENT_RWX, &[1, 2, 3])]);
+        assert_eq!(Package::parse(b"\x7fELF", 4), Err(PackageError::BadMagic));
+        assert_eq!(
+            Package::parse(&data[..40], data.len()),
+            Err(PackageError::Truncated)
+        );
+
+        let mut bad = data.clone();
+        bad[4] = 9;
+        assert_eq!(
+            Package::parse(&bad, bad.len()),
+            Err(PackageError::Version(9))
+        );
+        // Contents past the end of the file.
+        assert_eq!(
+            Package::parse(&data, data.len() - 1),
+            Err(PackageError::BadSegment(0))
+        );
+        let mut bad = data.clone();
+        bad[8] = 0;
+        bad[9] = 0x30;
+        assert_eq!(
+            Package::parse(&bad, bad.len()),
+            Err(PackageError::Entry(0x3000))
+        );
+        let mut bad = data;
+        bad[24] = 0;
+        assert_eq!(
+            Package::parse(&bad, bad.len()),
+            Err(PackageError::Segments(0))
+        );
+    }
+
+    #[test]
+    fn parse_version_1() {
+        // One segment without permissions, its contents right after it.
+        let mut data = Vec::new();
+        data.extend_from_slice(b"GAPK");
+        data.extend_from_slice(&1u32.to_le_bytes());
+        data.extend_from_slice(&0x1000u64.to_le_bytes());
+        data.extend_from_slice(&0u64.to_le_bytes());
+        data.extend_from_slice(&1u32.to_le_bytes());
+        data.extend_from_slice(&0u32.to_le_bytes());
+        for field in [0x1000u64, 64, 4, 0x1000] {
+            data.extend_from_slice(&field.to_le_bytes());
+        }
+        data.extend_from_slice(&[1, 2, 3, 4]);
+        let package = Package::parse(&data, data.len()).unwrap();
+        assert_eq!(
+            package.segments,
+            [Segment {
+                gpa: 0x1000,
+                offset: 64,
+                file_size: 4,
+                mem_size: 0x1000,
+                flags: SEGMENT_RWX,
+            }]
+        );
+    }
+
+    /// A minimal ELF64 file with a text segment at `0xffff_ffc0_8020_0000`
+    /// (physical `0x8020_0000`) and a data segment 0x3000 above it whose
+    /// memory size exceeds its contents.
+    fn elf() -> Vec<u8> {
+        let mut elf = vec![0u8; 0x200];
+        elf[..6].copy_from_slice(b"\x7fELF\x02\x01");
+        elf[24..32].copy_from_slice(&0xffff_ffc0_8020_0010u64.to_le_bytes());
+        elf[32..40].copy_from_slice(&64u64.to_le_bytes());
+        elf[54..56].copy_from_slice(&56u16.to_le_bytes());
+        elf[56..58].copy_from_slice(&3u16.to_le_bytes());
+        let phdrs = [
+            // (type, flags, offset, vaddr, paddr, filesz, memsz)
+            (
+                1u32,
+                5u32,
+                0x100u64,
+                0xffff_ffc0_8020_0000u64,
+                0x8020_0000u64,
+                0x40u64,
+                0x40u64,
+            ),
+            // PT_NOTE: skipped.
+            (4, 4, 0x140, 0, 0, 0x10, 0x10),
+            (
+                1,
+                6,
+                0x180,
+                0xffff_ffc0_8020_3000,
+                0x8020_3000,
+                0x20,
+                0x1000,
+            ),
+        ];
+        for (index, (ty, flags, offset, vaddr, paddr, filesz, memsz)) in
+            phdrs.into_iter().enumerate()
+        {
+            let ph = &mut elf[64 + index * 56..];
+            ph[..4].copy_from_slice(&ty.to_le_bytes());
+            ph[4..8].copy_from_slice(&flags.to_le_bytes());
+            for (at, value) in [
+                (8, offset),
+                (16, vaddr),
+                (24, paddr),
+                (32, filesz),
+                (40, memsz),
+            ] {
+                ph[at..at + 8].copy_from_slice(&value.to_le_bytes());
+            }
+        }
+        elf[0x100..0x140].fill(0x11);
+        elf[0x180..0x1A0].fill(0x22);
+        elf
+    }
+
+    #[test]
+    fn from_elf() {
+        let data = Package::from_elf(&elf(), 0x4020_0000, 0x400_0000).unwrap();
+        let package = Package::parse(&data, data.len()).unwrap();
+        assert_eq!(package.entry, 0x4020_0010);
+        assert_eq!(package.ram_size, 0x400_0000);
+        let gpas: Vec<_> = package
+            .segments
+            .iter()
+            .map(|s| (s.gpa, s.file_size, s.mem_size, s.flags))
+            .collect();
+        assert_eq!(
+            gpas,
+            [
+                (0x4020_0000, 0x40, 0x40, SEGMENT_READ | SEGMENT_EXEC),
+                (0x4020_3000, 0x20, 0x1000, SEGMENT_READ | SEGMENT_WRITE)
+            ]
+        );
+        let data_segment = package.segments[1];
+        assert_eq!(data[data_segment.offset as usize], 0x22);
+
+        let mut elf = elf();
+        elf[24..32].copy_from_slice(&0x1234u64.to_le_bytes());
+        assert_eq!(
+            Package::from_elf(&elf, 0x4020_0000, 0),
+            Err(PackageError::Entry(0x1234))
+        );
+        assert_eq!(
+            Package::from_elf(b"GAPK", 0, 0),
+            Err(PackageError::Elf("no ELF header"))
+        );
+    }
+
+    #[test]
+    fn load_descriptor() {
+        let (binary, desc) = LoadDescriptor::flat_binary(&elf(), 0x4020_0000).unwrap();
+        assert_eq!(binary.len(), 0x3020);
+        assert_eq!(
+            (binary[0x3F], binary[0x40], binary[0x3000]),
+            (0x11, 0, 0x22)
+        );
+        assert_eq!(
+            desc,
+            LoadDescriptor {
+                entry: 0x4020_0010,
+                text_size: 0x3000,
+                data_size: 0x20,
+                bss_size: 0xFE0,
+            }
+        );
+        assert_eq!(LoadDescriptor::parse(&desc.to_bytes()), Ok(desc));
+        assert_eq!(LoadDescriptor::parse(b"GAPK"), Err(PackageError::BadMagic));
+
+        // The flat binary: the text, the gap up to the data, the data.
+        let package = desc.package(0x4020_0000, 0x3020).unwrap();
+        let layout: Vec<_> = package
+            .segments
+            .iter()
+            .map(|s| (s.gpa, s.offset, s.file_size, s.mem_size, s.flags))
+            .collect();
+        assert_eq!(
+            layout,
+            [
+                (0x4020_0000, 0, 0x3000, 0x3000, SEGMENT_READ | SEGMENT_EXEC),
+                (
+                    0x4020_3000,
+                    0x3000,
+                    0x20,
+                    0x20,
+                    SEGMENT_READ | SEGMENT_WRITE
+                ),
+                (0x4020_3020, 0x3020, 0, 0xFE0, SEGMENT_READ | SEGMENT_WRITE),
+            ]
+        );
+        assert_eq!(package.entry, 0x4020_0010);
+        // A binary shorter than text and data.
+        assert_eq!(
+            desc.package(0x4020_0000, 0x3000),
+            Err(PackageError::BadSegment(1))
+        );
+    }
+}
@@ -0,0 +1,124 @@
+//! Pausing and resuming the vCPUs of a VM.
+//!
+//! All vCPUs of a VM are driven by its VM task, so they are paused
+//! together. [`VmPause::pause`] only sets a request; the run loop checks it
+//! before every guest entry and parks the task until [`VmPause::resume`].
+//! A running guest reaches the check at its next exit, which the host timer
+//! tick forces within one tick; a guest idling in WFI/HLT reaches it when
+//! its idle wait ends, after at most [`IDLE_POLL_INTERVAL`].
+//!
+//! Every running VM is listed here, so that [`pause_all`] and
+//! [`resume_all`] (console commands `Ctrl-A p` and `Ctrl-A r`) reach them
+//! all. Console commands keep working while all VMs are parked: the parked
+//! tasks poll the console.
+//!
+//! [`IDLE_POLL_INTERVAL`]: crate::idle::IDLE_POLL_INTERVAL
+
+#![allow(dead_code)]
+
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use axstd::sync::Mutex;
+use axtask::WaitQueue;
+
+use crate::idle::IDLE_POLL_INTERVAL;
+
+/// The pause state of all running VMs, by VM id.
+static VMS: Mutex<Vec<(usize, Arc<PauseState>)>> = Mutex::new(Vec::new());
+
+struct PauseState {
+    /// A pause was requested and not resumed since.
+    requested: AtomicBool,
+    /// The VM task is parked.
+    parked: AtomicBool,
+    wq: WaitQueue,
+}
+
+/// The pause control of one VM, listed for [`pause_all`] and
+/// [`resume_all`] until it is dropped.
+pub struct VmPause {
+    vm: usize,
+    state: Arc<PauseState>,
+}
+
+impl VmPause {
+    /// Creates the pause control of VM `vm`, not paused.
+    pub fn new(vm: usize) -> Self {
+        let state = Arc::new(PauseState {
+            requested: AtomicBool::new(false),
+            parked: AtomicBool::new(false),
+            wq: WaitQueue::new(),
+        });
+        VMS.lock().push((vm, state.clone()));
+        Self { vm, state }
+    }
+
+    /// Requests the VM to stop at its next exit.
+    pub fn pause(&self) {
+        self.state.pause();
+    }
+
+    /// Lets a paused VM run again.
+    pub fn resume(&self) {
+        self.state.resume();
+    }
+
+    /// Checks whether a pause was requested and not resumed since.
+    pub fn is_requested(&self) -> bool {
+        self.state.requested.load(Ordering::Acquire)
+    }
+
+    /// Checks whether the VM task is parked: the guest does not run until
+    /// it is resumed.
+    pub fn is_parked(&self) -> bool {
+        self.state.parked.load(Ordering::Acquire)
+    }
+
+    /// Parks the calling VM task while a pause is requested. Called by the
+    /// run loop, with no guest state loaded, before entering the guest.
+    pub fn park(&self) {
+        if !self.is_requested() {
+            return;
+        }
+        self.state.parked.store(true, Ordering::Release);
+        vm_println!(self.vm, "VM paused");
+        while self.is_requested() {
+            self.state
+                .wq
+                .wait_timeout_until(IDLE_POLL_INTERVAL, || !self.is_requested());
+            crate::console::poll_commands();
+        }
+        self.state.parked.store(false, Ordering::Release);
+        vm_println!(self.vm, "VM resumed");
+    }
+}
+
+impl Drop for VmPause {
+    fn drop(&mut self) {
+        VMS.lock()
+            .retain(|(_, state)| !Arc::ptr_eq(state, &self.state));
+    }
+}
+
+impl PauseState {
+    fn pause(&self) {
+        self.requested.store(true, Ordering::Release);
+    }
+
+    fn resume(&self) {
+        self.requested.store(false, Ordering::Release);
+        self.wq.notify_all(false);
+    }
+}
+
+/// Requests all running VMs to pause.
+pub fn pause_all() {
+    VMS.lock().iter().for_each(|(_, state)| state.pause());
+}
+
+/// Resumes all paused VMs.
+pub fn resume_all() {
+    VMS.lock().iter().for_each(|(_, state)| state.resume());
+}
@@ -0,0 +1,211 @@
+//! Guest accesses that the permissions of guest memory deny.
+//!
+//! Guest RAM is not all RWX: the code of a packaged image is mapped
+//! read-only, its data without EXECUTE, and a hardened VM's writable RAM
+//! is never executable. A stage-2 fault that no fix-up applies to
+//! (copy-on-write, lazy backing, dirty logging) and that the region's
+//! permissions deny is a guest bug, not a reason to end the VM with an
+//! unmappable access: the exit handlers print one line naming the access,
+//! the address and the permissions. Where the hypervisor knows what the
+//! guest would need to handle it, the guest takes the exception its own
+//! page tables would have raised; elsewhere the VM ends with
+//! [`VmError::PermissionFault`], so the exception is only injected on part
+//! of the targets:
+//!
+//! - riscv64: an instruction, load or store/AMO access fault (1, 5, 7) with
+//!   `stval` = the faulting guest virtual address;
+//! - x86_64 under shadow paging: a #PF whose error code has P, W, U (guest
+//!   CPL 3) and I/D as for the access, with CR2 = the linear address of the
+//!   intercepted #PF. A nested page fault does not report the linear
+//!   address, so with nested paging the VM ends;
+//! - aarch64: the guest runs at EL0 and takes no exceptions from the
+//!   hypervisor, so the VM ends.
+//!
+//! [`VmError::PermissionFault`]: crate::error::VmError::PermissionFault
+
+#![allow(dead_code)]
+
+use core::fmt;
+
+/// The kind of guest access a stage-2 fault was taken for.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Access {
+    Read,
+    Write,
+    Execute,
+}
+
+impl Access {
+    /// Returns the access of a riscv64 guest-page fault: instruction (20),
+    /// load (21) or store/AMO (23).
+    pub fn from_riscv64_cause(scause: usize) -> Option<Self> {
+        match scause {
+            20 => Some(Self::Execute),
+            21 => Some(Self::Read),
+            23 => Some(Self::Write),
+            _ => None,
+        }
+    }
+
+    /// Returns the access of an aarch64 abort from EL0 by its ESR: an
+    /// instruction abort (EC 0x20), or a data abort (EC 0x24) writing if
+    /// ISS.WnR is set.
+    pub fn from_aarch64_esr(esr: u64) -> Self {
+        if (esr >> 26) & 0x3F == 0x20 {
+            Self::Execute
+        } else if esr & (1 << 6) != 0 {
+            Self::Write
+        } else {
+            Self::Read
+        }
+    }
+
+    /// Returns the access of an x86_64 nested page fault by its EXITINFO1:
+    /// I/D (bit 4) for a fetch, W (bit 1) for a write.
+    pub fn from_npf_info(info1: u64) -> Self {
+        if info1 & (1 << 4) != 0 {
+            Self::Execute
+        } else if info1 & (1 << 1) != 0 {
+            Self::Write
+        } else {
+            Self::Read
+        }
+    }
+
+    /// Returns the riscv64 access fault `scause` the guest takes instead of
+    /// the guest-page fault.
+    pub fn riscv64_access_fault(self) -> u32 {
+        match self {
+            Self::Execute => 1,
+            Self::Read => 5,
+            Self::Write => 7,
+        }
+    }
+
+    /// Returns the error code of the x86_64 #PF the guest takes for a
+    /// protection violation (P set) at CPL 3 if `user`.
+    pub fn x86_64_pf_error(self, user: bool) -> u64 {
+        let mut error = 1;
+        match self {
+            Self::Write => error |= 1 << 1,
+            Self::Execute => error |= 1 << 4,
+            Self::Read => {}
+        }
+        if user {
+            error |= 1 << 2;
+        }
+        error
+    }
+
+    /// Names the access for reports.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Read => "read",
+            Self::Write => "write",
+            Self::Execute => "execute",
+        }
+    }
+}
+
+/// The permissions of a guest memory page, printed as `rwx` with `-` for
+/// the ones missing.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Perms {
+    pub read: bool,
+    pub write: bool,
+    pub execute: bool,
+}
+
+impl Perms {
+    /// Checks whether the permissions allow `access`.
+    pub fn allows(self, access: Access) -> bool {
+        match access {
+            Access::Read => self.read,
+            Access::Write => self.write,
+            Access::Execute => self.execute,
+        }
+    }
+}
+
+impl fmt::Display for Perms {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (set, c) in [(self.read, "r"), (self.write, "w"), (self.execute, "x")] {
+            f.write_str(if set { c } else { "-" })?;
+        }
+        Ok(())
+    }
+}
+
+/// A guest access to `gpa` at `pc` that the page's permissions deny.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PermissionFault {
+    pub gpa: usize,
+    pub pc: usize,
+    pub access: Access,
+    pub perms: Perms,
+}
+
+impl fmt::Display for PermissionFault {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} at {:#x} denied by its {} mapping, pc={:#x}",
+            self.access.as_str(),
+            self.gpa,
+            self.perms,
+            self.pc
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::format;
+
+    use super::*;
+
+    #[test]
+    fn decode_accesses() {
+        assert_eq!(Access::from_riscv64_cause(20), Some(Access::Execute));
+        assert_eq!(Access::from_riscv64_cause(21), Some(Access::Read));
+        assert_eq!(Access::from_riscv64_cause(23), Some(Access::Write));
+        assert_eq!(Access::from_riscv64_cause(13), None);
+        assert_eq!(Access::from_aarch64_esr(0x8200_000F), Access::Execute);
+        assert_eq!(Access::from_aarch64_esr(0x9200_004F), Access::Write);
+        assert_eq!(Access::from_aarch64_esr(0x9200_000F), Access::Read);
+        assert_eq!(Access::from_npf_info(0x1_0000_0015), Access::Execute);
+        assert_eq!(Access::from_npf_info(0x1_0000_0007), Access::Write);
+        assert_eq!(Access::from_npf_info(0x1_0000_0005), Access::Read);
+    }
+
+    #[test]
+    fn injected_exceptions() {
+        assert_eq!(Access::Execute.riscv64_access_fault(), 1);
+        assert_eq!(Access::Read.riscv64_access_fault(), 5);
+        assert_eq!(Access::Write.riscv64_access_fault(), 7);
+        assert_eq!(Access::Read.x86_64_pf_error(false), 0x1);
+        assert_eq!(Access::Write.x86_64_pf_error(false), 0x3);
+        assert_eq!(Access::Write.x86_64_pf_error(true), 0x7);
+        assert_eq!(Access::Execute.x86_64_pf_error(false), 0x11);
+    }
+
+    #[test]
+    fn report() {
+        let fault = PermissionFault {
+            gpa: 0x8020_1000,
+            pc: 0x8020_0010,
+            access: Access::Write,
+            perms: Perms {
+                read: true,
+                write: false,
+                execute: true,
+            },
+        };
+        assert!(fault.perms.allows(Access::Execute));
+        assert!(!fault.perms.allows(Access::Write));
+        assert_eq!(
+            format!("{}", fault),
+            "write at 0x80201000 denied by its r-x mapping, pc=0x80200010"
+        );
+    }
+}
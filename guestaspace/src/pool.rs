@@ -0,0 +1,150 @@
+//! Per-VM pool of 4K frames for stage-2 faults.
+//!
+//! Backing a lazily allocated guest page or breaking copy-on-write sharing
+//! on a fault needs one 4K frame, and going to the host allocator and
+//! zeroing the frame on every fault slows down boot storms, where a guest
+//! touches thousands of new pages in a row. The [`PagePool`] of a
+//! [`GuestSpace`](crate::gspace::GuestSpace) takes frames from the host
+//! allocator a batch at a time and zeroes them ahead of the faults, while
+//! the guest idles ([`PagePool::prepare`]); a fault then only pops a frame.
+//! A fault that finds no zeroed frame (a miss) zeroes one itself.
+//!
+//! Frames in the pool are not counted against the VM's memory limit until
+//! they are handed out, and freed guest frames go straight back to the host
+//! allocator, not to the pool.
+
+#![allow(dead_code)]
+
+use alloc::vec::Vec;
+
+use axalloc::{UsageKind, global_allocator};
+use axhal::mem::{phys_to_virt, virt_to_phys};
+use axhal::paging::PageSize;
+use memory_addr::{PAGE_SIZE_4K, PhysAddr};
+
+use crate::gspace::dealloc_frame;
+
+/// Frames taken from the host allocator at a time.
+pub const POOL_BATCH: usize = 64;
+/// Zeroed frames [`PagePool::prepare`] keeps ready.
+pub const POOL_TARGET: usize = 128;
+
+/// Pool counters.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct PoolStats {
+    /// Zeroed frames handed out ready.
+    pub hits: usize,
+    /// Zeroed frames that had to be zeroed (or allocated) at the fault.
+    pub misses: usize,
+    /// Frames handed out for copies, which need no zeroing.
+    pub copies: usize,
+    /// Batches taken from the host allocator.
+    pub batches: usize,
+}
+
+impl PoolStats {
+    /// Returns the percentage of zeroed frames handed out ready.
+    pub fn hit_rate(&self) -> usize {
+        (self.hits * 100)
+            .checked_div(self.hits + self.misses)
+            .unwrap_or(0)
+    }
+}
+
+/// 4K frames held for one guest address space.
+pub struct PagePool {
+    /// Frames ready to back a guest page.
+    zeroed: Vec<PhysAddr>,
+    /// Frames with stale contents.
+    raw: Vec<PhysAddr>,
+    stats: PoolStats,
+}
+
+impl PagePool {
+    /// Creates an empty pool.
+    pub fn new() -> Self {
+        Self {
+            zeroed: Vec::new(),
+            raw: Vec::new(),
+            stats: PoolStats::default(),
+        }
+    }
+
+    /// Takes a zeroed frame, or `None` if the host is out of memory.
+    pub fn take_zeroed(&mut self) -> Option<PhysAddr> {
+        if let Some(paddr) = self.zeroed.pop() {
+            self.stats.hits += 1;
+            return Some(paddr);
+        }
+        self.stats.misses += 1;
+        if self.raw.is_empty() {
+            self.refill();
+        }
+        let paddr = self.raw.pop()?;
+        zero_frame(paddr);
+        Some(paddr)
+    }
+
+    /// Takes a frame whose contents the caller overwrites, or `None` if the
+    /// host is out of memory.
+    pub fn take_any(&mut self) -> Option<PhysAddr> {
+        if self.raw.is_empty() && self.zeroed.is_empty() {
+            self.refill();
+        }
+        let paddr = self.raw.pop().or_else(|| self.zeroed.pop())?;
+        self.stats.copies += 1;
+        Some(paddr)
+    }
+
+    /// Zeroes frames until [`POOL_TARGET`] are ready, taking batches from
+    /// the host allocator as needed. Called while the guest idles.
+    pub fn prepare(&mut self) {
+        while self.zeroed.len() < POOL_TARGET {
+            if self.raw.is_empty() && !self.refill() {
+                break;
+            }
+            let paddr = self.raw.pop().unwrap();
+            zero_frame(paddr);
+            self.zeroed.push(paddr);
+        }
+    }
+
+    /// Returns the counters.
+    pub fn stats(&self) -> PoolStats {
+        self.stats
+    }
+
+    /// Returns the number of frames held.
+    pub fn len(&self) -> usize {
+        self.zeroed.len() + self.raw.len()
+    }
+
+    /// Takes up to [`POOL_BATCH`] frames from the host allocator. Returns
+    /// `false` if it had none left.
+    fn refill(&mut self) -> bool {
+        let before = self.raw.len();
+        for _ in 0..POOL_BATCH {
+            match global_allocator().alloc_pages(1, PAGE_SIZE_4K, UsageKind::VirtMem) {
+                Ok(vaddr) => self.raw.push(virt_to_phys(vaddr.into())),
+                Err(_) => break,
+            }
+        }
+        if self.raw.len() == before {
+            return false;
+        }
+        self.stats.batches += 1;
+        true
+    }
+}
+
+impl Drop for PagePool {
+    fn drop(&mut self) {
+        for &paddr in self.zeroed.iter().chain(&self.raw) {
+            dealloc_frame(paddr, PageSize::Size4K);
+        }
+    }
+}
+
+fn zero_frame(paddr: PhysAddr) {
+    unsafe { core::ptr::write_bytes(phys_to_virt(paddr).as_mut_ptr(), 0, PAGE_SIZE_4K) };
+}
@@ -0,0 +1,58 @@
+//! Detection of stage-2 faults that keep coming back.
+//!
+//! After the hypervisor fixes up a stage-2 fault (backs RAM, breaks
+//! copy-on-write sharing, maps a passthrough page...), the guest retries the
+//! access and does not fault on that address again. If it does, the fix-up
+//! did not stick (a missing TLB flush, wrong flags) and the guest would
+//! fault forever. Each vCPU keeps the pages of its last [`HISTORY`] fixed-up
+//! faults in a [`FaultHistory`]; once one page makes up [`THRESHOLD`] of
+//! them, the run loop prints a crash dump and ends the VM with
+//! [`VmError::RepeatedFault`].
+//!
+//! Register accesses to emulated devices are not recorded: a guest polling
+//! a device register faults on it every time.
+//!
+//! [`VmError::RepeatedFault`]: crate::error::VmError::RepeatedFault
+
+#![allow(dead_code)]
+
+use memory_addr::PAGE_SIZE_4K;
+
+/// Number of recent fixed-up faults remembered per vCPU.
+pub const HISTORY: usize = 32;
+
+/// Faults on one page among the last [`HISTORY`] that end the VM.
+pub const THRESHOLD: usize = 16;
+
+/// The pages of the last fixed-up stage-2 faults of a vCPU.
+pub struct FaultHistory {
+    /// Ring of page numbers, `usize::MAX` for free slots.
+    pages: [usize; HISTORY],
+    /// Slot of the next fault.
+    next: usize,
+}
+
+impl FaultHistory {
+    /// Creates an empty history.
+    pub const fn new() -> Self {
+        Self {
+            pages: [usize::MAX; HISTORY],
+            next: 0,
+        }
+    }
+
+    /// Records a fixed-up fault at `gpa`. Returns how many of the recent
+    /// faults hit its page if that reaches [`THRESHOLD`].
+    pub fn record(&mut self, gpa: usize) -> Option<usize> {
+        let page = gpa / PAGE_SIZE_4K;
+        self.pages[self.next] = page;
+        self.next = (self.next + 1) % HISTORY;
+        let count = self.pages.iter().filter(|&&p| p == page).count();
+        (count >= THRESHOLD).then_some(count)
+    }
+
+    /// Forgets all recorded faults.
+    pub fn clear(&mut self) {
+        *self = Self::new();
+    }
+}
@@ -1,4 +1,4 @@
-use axerrno::{AxError, AxResult};
+use super::SbiError;
 
 /// Functions defined for the Base extension
 #[derive(Clone, Copy, Debug)]
@@ -20,7 +20,7 @@ pub enum BaseFunction {
 }
 
 impl BaseFunction {
-    pub(crate) fn from_regs(args: &[usize]) -> AxResult<Self> {
+    pub(crate) fn from_regs(args: &[usize]) -> Result<Self, SbiError> {
         match args[6] {
             0 => Ok(BaseFunction::GetSepcificationVersion),
             1 => Ok(BaseFunction::GetImplementationID),
@@ -29,7 +29,7 @@ impl BaseFunction {
             4 => Ok(BaseFunction::GetMachineVendorID),
             5 => Ok(BaseFunction::GetMachineArchitectureID),
             6 => Ok(BaseFunction::GetMachineImplementationID),
-            _ => Err(AxError::NotFound),
+            _ => Err(SbiError::unsupported(args)),
         }
     }
 }
@@ -0,0 +1,41 @@
+use sbi_spec::dbcn::{CONSOLE_READ, CONSOLE_WRITE, CONSOLE_WRITE_BYTE};
+
+use super::SbiError;
+
+/// Functions for the Debug Console extension
+#[derive(Copy, Clone, Debug)]
+pub enum DebugConsoleFunction {
+    /// Writes `len` bytes at guest physical address `addr` to the console.
+    Write {
+        /// The number of bytes to write.
+        len: usize,
+        /// The guest physical address of the bytes.
+        addr: usize,
+    },
+    /// Reads up to `len` bytes from the console into guest physical address
+    /// `addr`.
+    Read {
+        /// The size of the buffer.
+        len: usize,
+        /// The guest physical address of the buffer.
+        addr: usize,
+    },
+    /// Writes a single byte to the console.
+    WriteByte(u8),
+}
+
+impl DebugConsoleFunction {
+    /// Attempts to parse `Self` from the passed in `a0-a7`. The address is
+    /// split across `a1` (low) and `a2` (high); only RV64 is supported, so the
+    /// high half must be zero.
+    pub(crate) fn from_regs(args: &[usize]) -> Result<Self, SbiError> {
+        let (len, addr) = (args[0], args[1]);
+        match args[6] {
+            CONSOLE_WRITE | CONSOLE_READ if args[2] != 0 => Err(SbiError::InvalidParam),
+            CONSOLE_WRITE => Ok(Self::Write { len, addr }),
+            CONSOLE_READ => Ok(Self::Read { len, addr }),
+            CONSOLE_WRITE_BYTE => Ok(Self::WriteByte(args[0] as u8)),
+            _ => Err(SbiError::unsupported(args)),
+        }
+    }
+}
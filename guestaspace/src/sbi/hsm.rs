@@ -0,0 +1,58 @@
+use sbi_spec::hsm::{HART_GET_STATUS, HART_START, HART_STOP, HART_SUSPEND};
+
+use super::SbiError;
+
+/// Functions for the Hart State Management extension.
+#[derive(Copy, Clone, Debug)]
+pub enum HsmFunction {
+    /// Starts a stopped hart in supervisor mode at `start_addr`, with the hart
+    /// id in `a0` and `opaque` in `a1`.
+    Start {
+        hartid: usize,
+        start_addr: usize,
+        opaque: usize,
+    },
+    /// Stops the calling hart.
+    Stop,
+    /// Returns the state of the given hart.
+    GetStatus { hartid: usize },
+    /// Puts the calling hart into a low power state.
+    Suspend {
+        suspend_type: u32,
+        resume_addr: usize,
+        opaque: usize,
+    },
+}
+
+impl HsmFunction {
+    /// Attempts to parse `Self` from the passed in `a0-a7`.
+    pub(crate) fn from_regs(args: &[usize]) -> Result<Self, SbiError> {
+        Ok(match args[6] {
+            HART_START => HsmFunction::Start {
+                hartid: args[0],
+                start_addr: args[1],
+                opaque: args[2],
+            },
+            HART_STOP => HsmFunction::Stop,
+            HART_GET_STATUS => HsmFunction::GetStatus { hartid: args[0] },
+            HART_SUSPEND => HsmFunction::Suspend {
+                suspend_type: args[0] as u32,
+                resume_addr: args[1],
+                opaque: args[2],
+            },
+            _ => return Err(SbiError::unsupported(args)),
+        })
+    }
+}
+
+/// The states of a hart reported by `GetStatus`.
+#[repr(usize)]
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum HartState {
+    /// The hart is running.
+    Started = sbi_spec::hsm::HART_STATE_STARTED,
+    /// The hart is not running.
+    Stopped = sbi_spec::hsm::HART_STATE_STOPPED,
+    /// The hart was started but has not run yet.
+    StartPending = sbi_spec::hsm::HART_STATE_START_PENDING,
+}
@@ -0,0 +1,22 @@
+use sbi_spec::spi::SEND_IPI;
+
+use super::{HartMask, SbiError};
+
+/// Functions for the IPI extension.
+#[derive(Copy, Clone, Debug)]
+pub enum IpiFunction {
+    /// Sends a supervisor software interrupt to the given harts.
+    SendIpi { hart_mask: HartMask },
+}
+
+impl IpiFunction {
+    /// Attempts to parse `Self` from the passed in `a0-a7`.
+    pub(crate) fn from_regs(args: &[usize]) -> Result<Self, SbiError> {
+        match args[6] {
+            SEND_IPI => Ok(Self::SendIpi {
+                hart_mask: HartMask::new(args[0], args[1]),
+            }),
+            _ => Err(SbiError::unsupported(args)),
+        }
+    }
+}
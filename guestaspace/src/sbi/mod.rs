@@ -0,0 +1,361 @@
+#![allow(dead_code)]
+
+mod base;
+mod dbcn;
+mod hsm;
+mod ipi;
+mod pmu;
+mod rfnc;
+mod srst;
+
+pub use base::BaseFunction;
+pub use dbcn::DebugConsoleFunction;
+pub use hsm::{HartState, HsmFunction};
+pub use ipi::IpiFunction;
+pub use pmu::PmuFunction;
+pub use rfnc::RemoteFenceFunction;
+pub use srst::{ResetFunction, ResetType};
+
+pub const SBI_SUCCESS: usize = 0;
+pub const SBI_ERR_FAILUER: isize = -1;
+pub const SBI_ERR_NOT_SUPPORTED: isize = -2;
+pub const SBI_ERR_INAVLID_PARAM: isize = -3;
+pub const SBI_ERR_DENIED: isize = -4;
+pub const SBI_ERR_INVALID_ADDRESS: isize = -5;
+pub const SBI_ERR_ALREADY_AVAILABLE: isize = -6;
+pub const SBI_ERR_ALREADY_STARTED: isize = -7;
+pub const SBI_ERR_ALREADY_STOPPED: isize = -8;
+
+/// Why an ECALL could not be decoded into an [`SbiMessage`]. The handler
+/// forwards [`SbiError::code`] to the guest in `a0`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SbiError {
+    /// No extension with this ID (`a7`) is known.
+    UnknownExtension(usize),
+    /// The extension is known but has no function `fid` (`a6`).
+    UnsupportedFunction { eid: usize, fid: usize },
+    /// An argument is out of range for the function.
+    InvalidParam,
+}
+
+impl SbiError {
+    /// The error for an unknown function of the extension called with `args`.
+    pub(crate) fn unsupported(args: &[usize]) -> Self {
+        Self::UnsupportedFunction {
+            eid: args[7],
+            fid: args[6],
+        }
+    }
+
+    /// The SBI error code returned to the guest.
+    pub fn code(self) -> isize {
+        match self {
+            Self::UnknownExtension(_) | Self::UnsupportedFunction { .. } => SBI_ERR_NOT_SUPPORTED,
+            Self::InvalidParam => SBI_ERR_INAVLID_PARAM,
+        }
+    }
+}
+
+/// A set of harts, as the IPI and RFENCE extensions pass it: bit `i` of
+/// `mask` selects hart `base + i`, and a `base` of `usize::MAX` selects all
+/// harts.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct HartMask {
+    mask: usize,
+    base: usize,
+}
+
+impl HartMask {
+    /// Creates the set from the `hart_mask` and `hart_mask_base` arguments.
+    pub fn new(mask: usize, base: usize) -> Self {
+        Self { mask, base }
+    }
+
+    /// Checks whether hart `hartid` is in the set.
+    pub fn contains(&self, hartid: usize) -> bool {
+        if self.base == usize::MAX {
+            return true;
+        }
+        hartid
+            .checked_sub(self.base)
+            .is_some_and(|bit| bit < usize::BITS as usize && self.mask >> bit & 1 != 0)
+    }
+
+    /// Checks that the set only names harts below `num_harts`.
+    pub fn is_within(&self, num_harts: usize) -> bool {
+        self.base == usize::MAX
+            || (0..usize::BITS as usize)
+                .filter(|bit| self.mask >> bit & 1 != 0)
+                .all(|bit| self.base.checked_add(bit).is_some_and(|h| h < num_harts))
+    }
+}
+
+/// The values returned from an SBI function call.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SbiReturn {
+    /// The error code(0 for success)
+    pub error_code: i64,
+    /// The return value if the operation is successful
+    pub return_value: i64,
+}
+
+/// SBI return value conventions
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SbiReturnTyoe {
+    /// Legacy(v0.1) extensions return a single value in A0, usually with the convention that 0
+    /// is success and < 0 is an implementation defined error code.
+    Legacy(u64),
+    /// Modern extensions use the standard error code values enumerated above.
+    Standard(SbiReturn),
+}
+
+/// SBI Message used to invoke the specfified SBI extension in the firmware.
+#[derive(Clone, Copy, Debug)]
+pub enum SbiMessage {
+    /// The base SBI extension functions.
+    Base(BaseFunction),
+    /// The legacy GetChar extension.
+    GetChar,
+    /// The legacy PutChar extension.
+    PutChar(usize),
+    /// The SetTimer Extension
+    SetTimer(usize),
+    /// Handles output to the console for debug
+    DebugConsole(DebugConsoleFunction),
+    /// Handles system reset
+    Reset(ResetFunction),
+    /// The Hart State Management extension.
+    Hsm(HsmFunction),
+    /// The IPI extension.
+    Ipi(IpiFunction),
+    /// The RemoteFence Extension.
+    RemoteFence(RemoteFenceFunction),
+    /// The PMU Extension
+    Pmu(PmuFunction),
+}
+
+impl SbiMessage {
+    /// Creates an SbiMessage struct from the given GPRs. Intended for use from the ECALL handler
+    /// and passed the saved register state from the calling OS. A7 must contain a valid SBI
+    /// extension and the other A* registers will be interpreted based on the extension A7 selects.
+    /// Extensions and functions that are not known decode to an [`SbiError`] rather than a
+    /// message, so the handler can fail the call without stopping the guest.
+    pub fn from_regs(args: &[usize]) -> Result<Self, SbiError> {
+        match args[7] {
+            sbi_spec::base::EID_BASE => BaseFunction::from_regs(args).map(SbiMessage::Base),
+            sbi_spec::legacy::LEGACY_CONSOLE_PUTCHAR => Ok(SbiMessage::PutChar(args[0])),
+            sbi_spec::legacy::LEGACY_CONSOLE_GETCHAR => Ok(SbiMessage::GetChar),
+            sbi_spec::legacy::LEGACY_SET_TIMER => Ok(SbiMessage::SetTimer(args[0])),
+            sbi_spec::legacy::LEGACY_SHUTDOWN => Ok(SbiMessage::Reset(ResetFunction::shutdown())),
+            sbi_spec::time::EID_TIME => match args[6] {
+                sbi_spec::time::SET_TIMER => Ok(SbiMessage::SetTimer(args[0])),
+                _ => Err(SbiError::unsupported(args)),
+            },
+            sbi_spec::spi::EID_SPI => IpiFunction::from_regs(args).map(SbiMessage::Ipi),
+            sbi_spec::srst::EID_SRST => ResetFunction::from_regs(args).map(SbiMessage::Reset),
+            sbi_spec::hsm::EID_HSM => HsmFunction::from_regs(args).map(SbiMessage::Hsm),
+            sbi_spec::rfnc::EID_RFNC => {
+                RemoteFenceFunction::from_args(args).map(SbiMessage::RemoteFence)
+            }
+            sbi_spec::pmu::EID_PMU => PmuFunction::from_regs(args).map(SbiMessage::Pmu),
+            sbi_spec::dbcn::EID_DBCN => {
+                DebugConsoleFunction::from_regs(args).map(SbiMessage::DebugConsole)
+            }
+            eid => Err(SbiError::UnknownExtension(eid)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The `a0`-`a7` of an ECALL to function `fid` of extension `eid`.
+    fn regs(eid: usize, fid: usize, a: &[usize]) -> [usize; 8] {
+        let mut regs = [0; 8];
+        regs[..a.len()].copy_from_slice(a);
+        regs[6] = fid;
+        regs[7] = eid;
+        regs
+    }
+
+    #[test]
+    fn base_functions() {
+        let msg = SbiMessage::from_regs(&regs(sbi_spec::base::EID_BASE, 3, &[0x48534D])).unwrap();
+        assert!(matches!(
+            msg,
+            SbiMessage::Base(BaseFunction::ProbeSbiExtension(0x48534D))
+        ));
+        assert_eq!(
+            SbiMessage::from_regs(&regs(sbi_spec::base::EID_BASE, 7, &[])).unwrap_err(),
+            SbiError::UnsupportedFunction {
+                eid: sbi_spec::base::EID_BASE,
+                fid: 7
+            }
+        );
+    }
+
+    #[test]
+    fn legacy_extensions() {
+        let putchar = regs(
+            sbi_spec::legacy::LEGACY_CONSOLE_PUTCHAR,
+            0,
+            &[b'x' as usize],
+        );
+        assert!(matches!(
+            SbiMessage::from_regs(&putchar).unwrap(),
+            SbiMessage::PutChar(0x78)
+        ));
+        let timer = regs(sbi_spec::legacy::LEGACY_SET_TIMER, 0, &[1234]);
+        assert!(matches!(
+            SbiMessage::from_regs(&timer).unwrap(),
+            SbiMessage::SetTimer(1234)
+        ));
+        let shutdown = regs(sbi_spec::legacy::LEGACY_SHUTDOWN, 0, &[]);
+        assert!(matches!(
+            SbiMessage::from_regs(&shutdown).unwrap(),
+            SbiMessage::Reset(ResetFunction::Reset {
+                reset_type: ResetType::Shutdown,
+                reason: srst::ResetReason::NoReason,
+            })
+        ));
+    }
+
+    #[test]
+    fn system_reset() {
+        let reboot = regs(sbi_spec::srst::EID_SRST, 0, &[1, 0xF000_0007]);
+        let SbiMessage::Reset(ResetFunction::Reset { reset_type, reason }) =
+            SbiMessage::from_regs(&reboot).unwrap()
+        else {
+            panic!("not a reset");
+        };
+        assert_eq!(reset_type, ResetType::ColdReset);
+        assert_eq!(reason.exit_code(), 7);
+
+        let bad_type = regs(sbi_spec::srst::EID_SRST, 0, &[3, 0]);
+        assert_eq!(
+            SbiMessage::from_regs(&bad_type).unwrap_err(),
+            SbiError::InvalidParam
+        );
+        let bad_reason = regs(sbi_spec::srst::EID_SRST, 0, &[0, 2]);
+        assert_eq!(
+            SbiMessage::from_regs(&bad_reason).unwrap_err(),
+            SbiError::InvalidParam
+        );
+    }
+
+    #[test]
+    fn hart_state_management() {
+        let start = regs(
+            sbi_spec::hsm::EID_HSM,
+            sbi_spec::hsm::HART_START,
+            &[1, 0x8020_0000, 42],
+        );
+        assert!(matches!(
+            SbiMessage::from_regs(&start).unwrap(),
+            SbiMessage::Hsm(HsmFunction::Start {
+                hartid: 1,
+                start_addr: 0x8020_0000,
+                opaque: 42,
+            })
+        ));
+        assert_eq!(HartState::StartPending as usize, 2);
+    }
+
+    #[test]
+    fn remote_fence() {
+        let fence = regs(
+            sbi_spec::rfnc::EID_RFNC,
+            sbi_spec::rfnc::REMOTE_SFENCE_VMA,
+            &[0b101, 2, 0x1000, 0x2000],
+        );
+        let SbiMessage::RemoteFence(function) = SbiMessage::from_regs(&fence).unwrap() else {
+            panic!("not a remote fence");
+        };
+        let harts = function.hart_mask();
+        assert!(!harts.contains(1));
+        assert!(harts.contains(2));
+        assert!(!harts.contains(3));
+        assert!(harts.contains(4));
+
+        let hfence = regs(
+            sbi_spec::rfnc::EID_RFNC,
+            sbi_spec::rfnc::REMOTE_HFENCE_GVMA,
+            &[],
+        );
+        let err = SbiMessage::from_regs(&hfence).unwrap_err();
+        assert!(matches!(err, SbiError::UnsupportedFunction { .. }));
+        assert_eq!(err.code(), SBI_ERR_NOT_SUPPORTED);
+    }
+
+    #[test]
+    fn unknown_extension() {
+        let err = SbiMessage::from_regs(&regs(0x1234_5678, 0, &[])).unwrap_err();
+        assert_eq!(err, SbiError::UnknownExtension(0x1234_5678));
+        assert_eq!(err.code(), SBI_ERR_NOT_SUPPORTED);
+
+        let timer = regs(sbi_spec::time::EID_TIME, 1, &[]);
+        assert!(matches!(
+            SbiMessage::from_regs(&timer).unwrap_err(),
+            SbiError::UnsupportedFunction { fid: 1, .. }
+        ));
+    }
+
+    #[test]
+    fn ipi_and_debug_console() {
+        let ipi = regs(sbi_spec::spi::EID_SPI, sbi_spec::spi::SEND_IPI, &[0b10, 0]);
+        let SbiMessage::Ipi(IpiFunction::SendIpi { hart_mask }) =
+            SbiMessage::from_regs(&ipi).unwrap()
+        else {
+            panic!("not an IPI");
+        };
+        assert!(hart_mask.contains(1) && !hart_mask.contains(0));
+
+        let write = regs(
+            sbi_spec::dbcn::EID_DBCN,
+            sbi_spec::dbcn::CONSOLE_WRITE,
+            &[5, 0x8020_0000, 0],
+        );
+        assert!(matches!(
+            SbiMessage::from_regs(&write).unwrap(),
+            SbiMessage::DebugConsole(DebugConsoleFunction::Write {
+                len: 5,
+                addr: 0x8020_0000
+            })
+        ));
+        let high = regs(
+            sbi_spec::dbcn::EID_DBCN,
+            sbi_spec::dbcn::CONSOLE_READ,
+            &[5, 0, 1],
+        );
+        let err = SbiMessage::from_regs(&high).unwrap_err();
+        assert_eq!(err, SbiError::InvalidParam);
+        assert_eq!(err.code(), SBI_ERR_INAVLID_PARAM);
+        let byte = regs(
+            sbi_spec::dbcn::EID_DBCN,
+            sbi_spec::dbcn::CONSOLE_WRITE_BYTE,
+            &[0x141],
+        );
+        assert!(matches!(
+            SbiMessage::from_regs(&byte).unwrap(),
+            SbiMessage::DebugConsole(DebugConsoleFunction::WriteByte(0x41))
+        ));
+    }
+
+    #[test]
+    fn hart_mask() {
+        let all = HartMask::new(0, usize::MAX);
+        assert!(all.contains(63));
+        assert!(all.is_within(1));
+
+        let mask = HartMask::new(0b11, 1);
+        assert!(!mask.contains(0));
+        assert!(mask.contains(1) && mask.contains(2));
+        assert!(!mask.contains(1 + usize::BITS as usize));
+        assert!(mask.is_within(3));
+        assert!(!mask.is_within(2));
+
+        // Bits past the end of the hart numbers.
+        assert!(!HartMask::new(1 << 63, usize::MAX - 10).is_within(usize::MAX));
+    }
+}
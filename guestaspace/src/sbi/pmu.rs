@@ -0,0 +1,102 @@
+use sbi_spec::pmu::{
+    PMU_COUNTER_CONFIG_MATCHING, PMU_COUNTER_FW_READ, PMU_COUNTER_FW_READ_HI, PMU_COUNTER_GET_INFO,
+    PMU_COUNTER_START, PMU_COUNTER_STOP, PMU_NUM_COUNTERS,
+};
+
+use super::SbiError;
+
+/// `PMU_SNAPSHOT_SET_SHMEM`, added in SBI 2.0 after the `sbi_spec` release
+/// in use.
+const PMU_SNAPSHOT_SET_SHMEM: usize = 7;
+
+#[derive(Clone, Copy, Debug)]
+pub enum PmuFunction {
+    /// Returns the total of performance counters (hardware and fireware).
+    GetNumCounters,
+    /// Returns information about hardware counter specified by the inner value.
+    GetCounterInfo(u64),
+    /// Finds and configures a counter from the set selected by
+    /// counter_index and counter_mask that can monitor the given event.
+    ConfigMatching {
+        /// Counter index base.
+        counter_index: u64,
+        /// Counter index mask.
+        counter_mask: u64,
+        /// Counter config flags.
+        config_flags: u64,
+        /// The event to monitor.
+        event_index: u64,
+        /// Event-specific data.
+        event_data: u64,
+    },
+    /// Starts the counters selected by counter_index and counter_mask.
+    StartCounter {
+        /// Counter index base.
+        counter_index: u64,
+        /// Counter index mask.
+        counter_mask: u64,
+        /// Counter start flags.
+        start_flags: u64,
+        /// The initial value of the counters.
+        initial_value: u64,
+    },
+    /// Stops the couters selected by counter_index and counter_mask.
+    /// See the sbi_pmu_counter_stop documentation for details.
+    StopCounter {
+        /// Countert index base.
+        counter_index: u64,
+        /// Counter index mask.
+        counter_mask: u64,
+        /// Counter stop flags.
+        stop_flags: u64,
+    },
+    /// Reads the firmware counter given by the inner value.
+    FwRead(u64),
+    /// Reads the upper 32 bits of the firmware counter given by the inner
+    /// value.
+    FwReadHi(u64),
+    /// Sets the shared memory for counter snapshots.
+    SnapshotSetShmem {
+        /// Low bits of the shared memory address.
+        addr_lo: u64,
+        /// High bits of the shared memory address.
+        addr_hi: u64,
+        /// Reserved flags.
+        flags: u64,
+    },
+}
+
+impl PmuFunction {
+    pub(crate) fn from_regs(args: &[usize]) -> Result<Self, SbiError> {
+        match args[6] {
+            PMU_NUM_COUNTERS => Ok(Self::GetNumCounters),
+            PMU_COUNTER_GET_INFO => Ok(Self::GetCounterInfo(args[0] as u64)),
+            PMU_COUNTER_CONFIG_MATCHING => Ok(Self::ConfigMatching {
+                counter_index: args[0] as u64,
+                counter_mask: args[1] as u64,
+                config_flags: args[2] as u64,
+                event_index: args[3] as u64,
+                event_data: args[4] as u64,
+            }),
+            PMU_COUNTER_START => Ok(Self::StartCounter {
+                counter_index: args[0] as u64,
+                counter_mask: args[1] as u64,
+                start_flags: args[2] as u64,
+                initial_value: args[3] as u64,
+            }),
+            PMU_COUNTER_STOP => Ok(Self::StopCounter {
+                counter_index: args[0] as u64,
+                counter_mask: args[1] as u64,
+                stop_flags: args[2] as u64,
+            }),
+            PMU_COUNTER_FW_READ => Ok(Self::FwRead(args[0] as u64)),
+            PMU_COUNTER_FW_READ_HI => Ok(Self::FwReadHi(args[0] as u64)),
+            PMU_SNAPSHOT_SET_SHMEM => Ok(Self::SnapshotSetShmem {
+                addr_lo: args[0] as u64,
+                addr_hi: args[1] as u64,
+                flags: args[2] as u64,
+            }),
+            _ => Err(SbiError::unsupported(args)),
+        }
+    }
+}
@@ -0,0 +1,70 @@
+use sbi_spec::rfnc::{REMOTE_FENCE_I, REMOTE_SFENCE_VMA, REMOTE_SFENCE_VMA_ASID};
+
+use super::{HartMask, SbiError};
+
+#[derive(Clone, Copy, Debug)]
+pub enum RemoteFenceFunction {
+    FenceI {
+        hart_mask: u64,
+        hart_mask_base: u64,
+    },
+    RemoteSFenceVMA {
+        hart_mask: u64,
+        hart_mask_base: u64,
+        start_addr: u64,
+        size: u64,
+    },
+    RemoteSFenceVMAAsid {
+        hart_mask: u64,
+        hart_mask_base: u64,
+        start_addr: u64,
+        size: u64,
+        asid: u64,
+    },
+}
+
+impl RemoteFenceFunction {
+    /// Attempts to parse `Self` from the passed in `a0-a7`. The hypervisor
+    /// fences (`REMOTE_HFENCE_*`) are not supported for guests.
+    pub fn from_args(args: &[usize]) -> Result<Self, SbiError> {
+        match args[6] {
+            REMOTE_FENCE_I => Ok(Self::FenceI {
+                hart_mask: args[0] as u64,
+                hart_mask_base: args[1] as u64,
+            }),
+            REMOTE_SFENCE_VMA => Ok(Self::RemoteSFenceVMA {
+                hart_mask: args[0] as u64,
+                hart_mask_base: args[1] as u64,
+                start_addr: args[2] as u64,
+                size: args[3] as u64,
+            }),
+            REMOTE_SFENCE_VMA_ASID => Ok(Self::RemoteSFenceVMAAsid {
+                hart_mask: args[0] as u64,
+                hart_mask_base: args[1] as u64,
+                start_addr: args[2] as u64,
+                size: args[3] as u64,
+                asid: args[4] as u64,
+            }),
+            _ => Err(SbiError::unsupported(args)),
+        }
+    }
+
+    /// Returns the harts the fence applies to.
+    pub fn hart_mask(&self) -> HartMask {
+        let (Self::FenceI {
+            hart_mask,
+            hart_mask_base,
+        }
+        | Self::RemoteSFenceVMA {
+            hart_mask,
+            hart_mask_base,
+            ..
+        }
+        | Self::RemoteSFenceVMAAsid {
+            hart_mask,
+            hart_mask_base,
+            ..
+        }) = *self;
+        HartMask::new(hart_mask as usize, hart_mask_base as usize)
+    }
+}
@@ -1,4 +1,4 @@
-use axerrno::{AxError, AxResult};
+use super::SbiError;
 
 /// Functions for the Reset extension
 #[derive(Copy, Clone, Debug)]
@@ -27,42 +27,58 @@ pub enum ResetType {
 impl ResetType {
     // Creates a reset type from the a0 register value or returns an error if no mapping is
     // known for the given value.
-    fn from_reg(a0: usize) -> AxResult<Self> {
+    fn from_reg(a0: usize) -> Result<Self, SbiError> {
         use ResetType::*;
         Ok(match a0 {
             0 => Shutdown,
             1 => ColdReset,
             2 => WarmReset,
-            _ => return Err(AxError::InvalidInput),
+            _ => return Err(SbiError::InvalidParam),
         })
     }
 }
 
 /// Reasons why a supervisor requests a reset.
-#[repr(u64)]
 #[derive(Copy, Clone, Debug, Eq, PartialEq)]
 pub enum ResetReason {
     /// Used for normal resets.
-    NoReason = 0,
+    NoReason,
     /// Used when the system has failed.
-    SystemFailure = 1,
+    SystemFailure,
+    /// A guest exit code, passed in the platform-specific reasons
+    /// `0xF000_0000 + code` (code below `0x1000_0000`).
+    ExitCode(u32),
 }
 
+/// First platform-specific reset reason, carrying exit code 0.
+const RESET_REASON_EXIT_CODE: usize = hvcall_abi::sbi::RESET_REASON_EXIT_CODE as usize;
+
 impl ResetReason {
     // Creates a reset reason from the a1 register value or returns an error if no mapping is
     // known for the given value.
-    fn from_reg(a1: usize) -> AxResult<Self> {
+    fn from_reg(a1: usize) -> Result<Self, SbiError> {
         use ResetReason::*;
         Ok(match a1 {
             0 => NoReason,
             1 => SystemFailure,
-            _ => return Err(AxError::InvalidInput),
+            RESET_REASON_EXIT_CODE..=0xFFFF_FFFF => ExitCode((a1 - RESET_REASON_EXIT_CODE) as u32),
+            _ => return Err(SbiError::InvalidParam),
         })
     }
+
+    /// The exit code of a guest that powers off for this reason: 0 for no
+    /// reason, 1 for a system failure.
+    pub fn exit_code(self) -> u32 {
+        match self {
+            Self::NoReason => 0,
+            Self::SystemFailure => 1,
+            Self::ExitCode(code) => code,
+        }
+    }
 }
 impl ResetFunction {
     /// Attempts to parse `Self` from the passed in `a0-a7`.
-    pub(crate) fn from_regs(args: &[usize]) -> AxResult<Self> {
+    pub(crate) fn from_regs(args: &[usize]) -> Result<Self, SbiError> {
         use ResetFunction::*;
 
         Ok(match args[6] {
@@ -70,7 +86,7 @@ impl ResetFunction {
                 reset_type: ResetType::from_reg(args[0])?,
                 reason: ResetReason::from_reg(args[1])?,
             },
-            _ => return Err(AxError::InvalidInput),
+            _ => return Err(SbiError::unsupported(args)),
         })
     }
 
@@ -0,0 +1,129 @@
+//! The secondary host serial port, owned by one guest.
+//!
+//! The first UART carries the hypervisor log and the tagged console of all
+//! VMs. A VM configured with `serial=on` takes the second one (QEMU's second
+//! `-serial`) for itself: its emulated 16550 (see
+//! [`Uart16550`](crate::devices::uart16550::Uart16550)) and its putchar
+//! output go there raw, untagged and without interleaving with hypervisor
+//! messages, and the guest reads its input from there.
+//!
+//! Only x86_64 has a second UART on the QEMU machine, COM2 at I/O port
+//! [`COM2_BASE`]. The `virt` machines of riscv64 and aarch64 have a single
+//! UART, so there [`claim`] fails and the VM keeps the shared console.
+
+#![allow(dead_code)]
+
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+/// I/O port of the host COM2.
+pub const COM2_BASE: u16 = 0x2F8;
+
+/// No VM owns the port.
+const NO_OWNER: usize = usize::MAX;
+
+/// VM owning the secondary port.
+static OWNER: AtomicUsize = AtomicUsize::new(NO_OWNER);
+
+// 16550 registers and bits used by the host driver.
+const REG_DATA: u16 = 0;
+const REG_IER: u16 = 1;
+const REG_FCR: u16 = 2;
+const REG_LCR: u16 = 3;
+const REG_MCR: u16 = 4;
+const REG_LSR: u16 = 5;
+const REG_SCR: u16 = 7;
+const LCR_DLAB: u8 = 0x80;
+const LCR_8N1: u8 = 0x03;
+const LSR_DATA_READY: u8 = 0x01;
+const LSR_THR_EMPTY: u8 = 0x20;
+
+/// A handle on the secondary host serial port, given to the VM owning it.
+#[derive(Clone, Copy)]
+pub struct HostSerial {
+    base: u16,
+}
+
+/// Gives the secondary serial port to VM `vm_id`. Fails if the machine has
+/// none or another VM owns it.
+pub fn claim(vm_id: usize) -> Option<HostSerial> {
+    let serial = probe()?;
+    OWNER
+        .compare_exchange(NO_OWNER, vm_id, Ordering::AcqRel, Ordering::Acquire)
+        .ok()?;
+    serial.init();
+    Some(serial)
+}
+
+/// Returns the port if VM `vm_id` owns it.
+pub fn owned_by(vm_id: usize) -> Option<HostSerial> {
+    (OWNER.load(Ordering::Acquire) == vm_id).then_some(HostSerial { base: COM2_BASE })
+}
+
+/// Gives the port back if VM `vm_id` owns it.
+pub fn release(vm_id: usize) {
+    let _ = OWNER.compare_exchange(vm_id, NO_OWNER, Ordering::AcqRel, Ordering::Acquire);
+}
+
+/// Returns COM2 if its scratch register holds a written value.
+#[cfg(target_arch = "x86_64")]
+fn probe() -> Option<HostSerial> {
+    let serial = HostSerial { base: COM2_BASE };
+    serial.out(REG_SCR, 0x5A);
+    (serial.inp(REG_SCR) == 0x5A).then_some(serial)
+}
+
+#[cfg(not(target_arch = "x86_64"))]
+fn probe() -> Option<HostSerial> {
+    None
+}
+
+impl HostSerial {
+    /// Sets 115200 baud, 8N1, FIFOs on and interrupts off.
+    fn init(&self) {
+        self.out(REG_IER, 0);
+        self.out(REG_LCR, LCR_DLAB);
+        self.out(REG_DATA, 1);
+        self.out(REG_IER, 0);
+        self.out(REG_LCR, LCR_8N1);
+        self.out(REG_FCR, 0x07);
+        // DTR and RTS.
+        self.out(REG_MCR, 0x03);
+    }
+
+    /// Sends one byte.
+    pub fn write(&self, byte: u8) {
+        while self.inp(REG_LSR) & LSR_THR_EMPTY == 0 {
+            core::hint::spin_loop();
+        }
+        self.out(REG_DATA, byte);
+    }
+
+    /// Receives one byte, if one has arrived.
+    pub fn read(&self) -> Option<u8> {
+        (self.inp(REG_LSR) & LSR_DATA_READY != 0).then(|| self.inp(REG_DATA))
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    fn out(&self, reg: u16, value: u8) {
+        unsafe {
+            core::arch::asm!("out dx, al", in("dx") self.base + reg, in("al") value);
+        }
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    fn inp(&self, reg: u16) -> u8 {
+        let value: u8;
+        unsafe {
+            core::arch::asm!("in al, dx", in("dx") self.base + reg, out("al") value);
+        }
+        value
+    }
+
+    #[cfg(not(target_arch = "x86_64"))]
+    fn out(&self, _reg: u16, _value: u8) {}
+
+    #[cfg(not(target_arch = "x86_64"))]
+    fn inp(&self, _reg: u16) -> u8 {
+        0
+    }
+}
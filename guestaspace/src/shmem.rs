@@ -0,0 +1,302 @@
+//! Guest buffers shared with the hypervisor.
+//!
+//! A paravirt guest hands the hypervisor a page-aligned range of its RAM
+//! with the SHARE_MEM hypercall (`start`, `size`) and gets back a token
+//! naming it; UNSHARE_MEM (`token`) takes the buffer back. While shared,
+//! the range is pinned ([`GuestSpace::pin`]): backed, private, and refused
+//! to the balloon, so the hypervisor reaches it through a [`SharedView`]
+//! (the [`GuestMemory`] API with offsets into the buffer) without faults.
+//! Tokens are never reused within a VM, so a stale token fails instead of
+//! naming another buffer.
+//!
+//! The first user is the console ring: the guest writes console output
+//! into a shared buffer and notifies the hypervisor once per batch
+//! (CONSOLE_KICK) instead of exiting once per character, as the putchar
+//! hypercall does; what it leaves there is also printed whenever it idles
+//! and when the VM ends. Paravirt device queues that need less than virtio
+//! can be built the same way.
+//!
+//! The calls are SBI extension [`SBI_EXT_HYPERVISOR`] functions 2 to 5 with
+//! arguments in `a0`/`a1` on riscv64, and functions 6 to 9 (`x8` with
+//! `x0`/`x1` on aarch64, `RAX[7:0]` with `RBX`/`RCX` on x86_64) elsewhere;
+//! see [`ShmemCall`].
+//!
+//! [`GuestSpace::pin`]: crate::gspace::GuestSpace::pin
+//! [`SBI_EXT_HYPERVISOR`]: crate::boot::SBI_EXT_HYPERVISOR
+
+#![allow(dead_code)]
+
+use alloc::collections::BTreeMap;
+
+use axerrno::{AxError, AxResult};
+
+use crate::console::VmConsole;
+use crate::gmem::GuestMemory;
+use crate::gspace::GuestSpace;
+
+/// Most buffers a VM may share at a time.
+pub const MAX_SHARED_BUFFERS: usize = 16;
+
+/// Bytes at the start of a console ring buffer before its data: the
+/// guest's write index (`u32` at 0) and the hypervisor's read index (`u32`
+/// at 4), padded to a cache line.
+pub const CONSOLE_RING_HEADER: usize = 64;
+
+/// A shared memory hypercall.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ShmemCall {
+    /// Shares `[arg0, arg0 + arg1)`; returns a token.
+    Share,
+    /// Takes back the buffer of token `arg0`.
+    Unshare,
+    /// Makes the buffer of token `arg0` the console ring, or detaches the
+    /// ring if `arg0` is 0.
+    ConsoleRing,
+    /// Prints what the guest has written to the console ring; returns the
+    /// bytes printed.
+    ConsoleKick,
+}
+
+impl ShmemCall {
+    /// Decodes an aarch64/x86_64 hypercall function ID.
+    pub fn from_hypercall(func: u64) -> Option<Self> {
+        match func {
+            hvcall_abi::SHARE_MEM => Some(Self::Share),
+            hvcall_abi::UNSHARE_MEM => Some(Self::Unshare),
+            hvcall_abi::CONSOLE_RING => Some(Self::ConsoleRing),
+            hvcall_abi::CONSOLE_KICK => Some(Self::ConsoleKick),
+            _ => None,
+        }
+    }
+
+    /// Decodes a function ID of the riscv64 hypervisor SBI extension.
+    pub fn from_sbi(fid: usize) -> Option<Self> {
+        match fid {
+            hvcall_abi::sbi::FID_SHARE_MEM => Some(Self::Share),
+            hvcall_abi::sbi::FID_UNSHARE_MEM => Some(Self::Unshare),
+            hvcall_abi::sbi::FID_CONSOLE_RING => Some(Self::ConsoleRing),
+            hvcall_abi::sbi::FID_CONSOLE_KICK => Some(Self::ConsoleKick),
+            _ => None,
+        }
+    }
+
+    /// Checks whether the call may change stage-2 mappings, so the guest
+    /// TLB entries of `[arg0, arg0 + arg1)` must be flushed after it.
+    pub fn remaps(self) -> bool {
+        self == Self::Share
+    }
+}
+
+/// A shared guest buffer.
+#[derive(Clone, Copy, Debug)]
+pub struct SharedBuffer {
+    pub gpa: usize,
+    pub size: usize,
+}
+
+/// The buffers a VM shares with the hypervisor, and its console ring.
+pub struct SharedMem {
+    vm_id: usize,
+    buffers: BTreeMap<u64, SharedBuffer>,
+    next_token: u64,
+    ring: ConsoleRing,
+    /// Buffers shared so far.
+    shares: usize,
+}
+
+impl SharedMem {
+    /// Creates an empty table for VM `vm_id`.
+    pub fn new(vm_id: usize) -> Self {
+        Self {
+            vm_id,
+            buffers: BTreeMap::new(),
+            next_token: 1,
+            ring: ConsoleRing::default(),
+            shares: 0,
+        }
+    }
+
+    /// Serves `call` with arguments `arg0`/`arg1`; returns the value for
+    /// the guest.
+    pub fn hypercall(
+        &mut self,
+        call: ShmemCall,
+        space: &mut GuestSpace,
+        console: &mut VmConsole,
+        arg0: usize,
+        arg1: usize,
+    ) -> AxResult<usize> {
+        match call {
+            ShmemCall::Share => self.share(space, arg0, arg1).map(|token| token as usize),
+            ShmemCall::Unshare => self.unshare(space, console, arg0 as u64).map(|_| 0),
+            ShmemCall::ConsoleRing => self.attach_console_ring(space, console, arg0 as u64),
+            ShmemCall::ConsoleKick => self.drain_console_ring(space, console),
+        }
+    }
+
+    /// Pins and shares `[gpa, gpa + size)`; returns its token.
+    pub fn share(&mut self, space: &mut GuestSpace, gpa: usize, size: usize) -> AxResult<u64> {
+        if self.buffers.len() >= MAX_SHARED_BUFFERS {
+            return Err(AxError::NoMemory);
+        }
+        space.pin(gpa.into(), size)?;
+        let token = self.next_token;
+        self.next_token += 1;
+        self.buffers.insert(token, SharedBuffer { gpa, size });
+        self.shares += 1;
+        Ok(token)
+    }
+
+    /// Takes back the buffer of `token`, draining and detaching the console
+    /// ring first if it lives there.
+    pub fn unshare(
+        &mut self,
+        space: &mut GuestSpace,
+        console: &mut VmConsole,
+        token: u64,
+    ) -> AxResult {
+        let buffer = self.get(token)?;
+        if self.ring.token == Some(token) {
+            let _ = self.drain_console_ring(space, console);
+            self.ring.token = None;
+        }
+        space.unpin(buffer.gpa.into())?;
+        self.buffers.remove(&token);
+        Ok(())
+    }
+
+    /// Returns the number of buffers shared now.
+    pub fn shared(&self) -> usize {
+        self.buffers.len()
+    }
+
+    /// Returns the buffer of `token`.
+    pub fn get(&self, token: u64) -> AxResult<SharedBuffer> {
+        self.buffers.get(&token).copied().ok_or(AxError::NotFound)
+    }
+
+    /// Returns a view of the buffer of `token` in `space`.
+    pub fn view<'a>(&self, space: &'a mut GuestSpace, token: u64) -> AxResult<SharedView<'a>> {
+        Ok(SharedView {
+            space,
+            buffer: self.get(token)?,
+        })
+    }
+
+    /// Makes the buffer of `token` the console ring (emptied), or detaches
+    /// the ring if `token` is 0. Returns the ring's capacity in bytes.
+    fn attach_console_ring(
+        &mut self,
+        space: &mut GuestSpace,
+        console: &mut VmConsole,
+        token: u64,
+    ) -> AxResult<usize> {
+        if self.ring.token.is_some() {
+            let _ = self.drain_console_ring(space, console);
+        }
+        if token == 0 {
+            self.ring.token = None;
+            return Ok(0);
+        }
+        let buffer = self.get(token)?;
+        if buffer.size <= CONSOLE_RING_HEADER {
+            return Err(AxError::InvalidInput);
+        }
+        let mut view = self.view(space, token)?;
+        view.write_obj(0, &[0u32; 2])?;
+        self.ring.token = Some(token);
+        Ok(buffer.size - CONSOLE_RING_HEADER)
+    }
+
+    /// Prints the bytes between the ring's read and write indexes and moves
+    /// the read index up; returns how many. A write index outside the ring
+    /// detaches it.
+    pub fn drain_console_ring(
+        &mut self,
+        space: &mut GuestSpace,
+        console: &mut VmConsole,
+    ) -> AxResult<usize> {
+        let Some(token) = self.ring.token else {
+            return Ok(0);
+        };
+        let mut view = self.view(space, token)?;
+        let cap = view.buffer.size - CONSOLE_RING_HEADER;
+        let [head, tail] = view.read_obj::<[u32; 2]>(0)?.map(|i| i as usize);
+        if head >= cap || tail >= cap {
+            self.ring.token = None;
+            vm_println!(self.vm_id, "Console ring: bad index, ring detached");
+            return Err(AxError::InvalidData);
+        }
+        let len = (head + cap - tail) % cap;
+        // At most two pieces: up to the end of the ring, then from its start.
+        let first = len.min(cap - tail);
+        let data = view.read_slice(CONSOLE_RING_HEADER + tail, first)?;
+        let rest = view.read_slice(CONSOLE_RING_HEADER, len - first)?;
+        for &ch in data.iter().chain(&rest) {
+            console.putchar(ch);
+        }
+        view.write_obj(4, &(head as u32))?;
+        self.ring.bytes += len;
+        Ok(len)
+    }
+
+    /// Prints what the guest shared and sent through the console ring, if
+    /// it shared anything.
+    pub fn report(&self) {
+        if self.shares > 0 {
+            vm_println!(
+                self.vm_id,
+                "Shared memory: {} buffers shared, {} still shared, {} bytes through the console ring",
+                self.shares,
+                self.buffers.len(),
+                self.ring.bytes
+            );
+        }
+    }
+}
+
+/// The console ring: data bytes follow [`CONSOLE_RING_HEADER`]; the guest
+/// writes at its index and moves it up, the hypervisor reads from its own.
+/// Both wrap at the capacity; the ring is empty when they are equal, so it
+/// holds one byte less than its capacity.
+#[derive(Default)]
+struct ConsoleRing {
+    /// Token of the ring's buffer, if the guest has set one up.
+    token: Option<u64>,
+    /// Bytes printed from the ring.
+    bytes: usize,
+}
+
+/// A shared buffer seen through [`GuestMemory`], with addresses as offsets
+/// into the buffer. Accesses reaching past its end fail with
+/// [`AxError::InvalidInput`].
+pub struct SharedView<'a> {
+    space: &'a mut GuestSpace,
+    buffer: SharedBuffer,
+}
+
+impl SharedView<'_> {
+    /// Returns the size of the buffer.
+    pub fn size(&self) -> usize {
+        self.buffer.size
+    }
+
+    fn gpa(&self, offset: usize, len: usize) -> AxResult<usize> {
+        match offset.checked_add(len) {
+            Some(end) if end <= self.buffer.size => Ok(self.buffer.gpa + offset),
+            _ => Err(AxError::InvalidInput),
+        }
+    }
+}
+
+impl GuestMemory for SharedView<'_> {
+    fn copy_from_guest(&self, offset: usize, buf: &mut [u8]) -> AxResult {
+        let gpa = self.gpa(offset, buf.len())?;
+        self.space.copy_from_guest(gpa, buf)
+    }
+
+    fn copy_to_guest(&mut self, offset: usize, data: &[u8]) -> AxResult {
+        let gpa = self.gpa(offset, data.len())?;
+        self.space.copy_to_guest(gpa, data)
+    }
+}
@@ -0,0 +1,105 @@
+//! Targeted guest TLB invalidation after second-stage mapping changes.
+//!
+//! Fixing up a single guest page fault only changes one mapping, so only
+//! that page is invalidated instead of the whole TLB:
+//!
+//! - riscv64: `hfence.gvma` with the GPA and the VM's VMID;
+//! - aarch64: `tlbi vaae1is` for the guest address. `tlbi ipas2e1is` needs
+//!   EL2, while this hypervisor runs at EL1 with the guest at EL0 and its
+//!   "guest physical" addresses are TTBR0_EL1 virtual addresses.
+//!
+//! On riscv64, remote `sfence.vma` requests of a guest's harts are served
+//! with `hfence.vvma` on the guest's own (VS-stage) translations.
+//!
+//! x86_64 has no by-GPA invalidation for nested page tables; NPT changes are
+//! flushed through the VMCB TLB control field instead.
+
+#![allow(dead_code)]
+
+use memory_addr::PAGE_SIZE_4K;
+
+/// Above this many pages a whole-VM flush is cheaper than per-page fences.
+const MAX_PAGE_FLUSHES: usize = 64;
+
+/// Invalidates the cached translations of the guest page containing `gpa`.
+pub fn flush_guest_page(vmid: usize, gpa: usize) {
+    let page = gpa & !(PAGE_SIZE_4K - 1);
+    #[cfg(target_arch = "riscv64")]
+    unsafe {
+        core::arch::riscv64::hfence_gvma(page >> 2, vmid);
+    }
+    #[cfg(target_arch = "aarch64")]
+    {
+        // Guest mappings are global, so the ASID is not part of the match.
+        let _ = vmid;
+        unsafe {
+            core::arch::asm!(
+                "dsb ishst",
+                "tlbi vaae1is, {arg}",
+                "dsb ish",
+                "isb",
+                arg = in(reg) (page >> 12) as u64,
+            );
+        }
+    }
+}
+
+/// Invalidates the cached translations of `[gpa, gpa + size)` in the given
+/// address space, falling back to a whole-VM flush for large ranges.
+pub fn flush_guest_range(vmid: usize, gpa: usize, size: usize) {
+    let start = gpa & !(PAGE_SIZE_4K - 1);
+    let pages = (gpa + size - start).div_ceil(PAGE_SIZE_4K);
+    if pages > MAX_PAGE_FLUSHES {
+        flush_guest_all(vmid);
+        return;
+    }
+    for i in 0..pages {
+        flush_guest_page(vmid, start + i * PAGE_SIZE_4K);
+    }
+}
+
+/// Invalidates the guest's own (VS-stage) translations of `[va, va + size)`
+/// tagged with `asid`, or with any ASID if `asid` is `None`, for the VMID in
+/// the current `hgatp`. A zero `start` and `size`, or a `size` of
+/// `usize::MAX`, stands for the whole address space as in SBI RFENCE.
+#[cfg(target_arch = "riscv64")]
+pub fn flush_guest_vs_range(asid: Option<usize>, va: usize, size: usize) {
+    use core::arch::riscv64::{hfence_vvma, hfence_vvma_all, hfence_vvma_asid, hfence_vvma_vaddr};
+
+    let start = va & !(PAGE_SIZE_4K - 1);
+    let pages = va
+        .saturating_add(size)
+        .saturating_sub(start)
+        .div_ceil(PAGE_SIZE_4K);
+    let whole = (va == 0 && size == 0) || size == usize::MAX || pages > MAX_PAGE_FLUSHES;
+    unsafe {
+        if whole {
+            match asid {
+                Some(asid) => hfence_vvma_asid(asid),
+                None => hfence_vvma_all(),
+            }
+            return;
+        }
+        for i in 0..pages {
+            let page = start + i * PAGE_SIZE_4K;
+            match asid {
+                Some(asid) => hfence_vvma(page, asid),
+                None => hfence_vvma_vaddr(page),
+            }
+        }
+    }
+}
+
+/// Invalidates all cached translations of the VM.
+pub fn flush_guest_all(vmid: usize) {
+    #[cfg(target_arch = "riscv64")]
+    crate::vmid::flush_vmid(vmid);
+    #[cfg(target_arch = "aarch64")]
+    {
+        // Global guest mappings are not removed by a per-ASID flush.
+        let _ = vmid;
+        unsafe {
+            core::arch::asm!("dsb ishst", "tlbi vmalle1is", "dsb ish", "isb");
+        }
+    }
+}
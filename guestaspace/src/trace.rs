@@ -0,0 +1,300 @@
+//! Tracing of guest exits.
+//!
+//! Every exit of a vCPU is classified into an [`ExitKind`], and each kind
+//! has a [`TraceLevel`], set per VM with `trace=` in `vms.conf` (see
+//! [`TraceConfig::parse`]):
+//!
+//! - `off`: the exit is not traced;
+//! - `ring`: the exit is recorded in the VM's in-memory ring of the last
+//!   [`RING_LEN`] exits, which [`dump_rings`] prints on demand (console
+//!   command `Ctrl-A t`, see [`crate::console::read_host_input`]);
+//! - `print`: the exit is recorded and also printed, at most
+//!   [`PRINT_BURST`] lines per kind and second; the number of lines left
+//!   out is printed when the next second starts.
+//!
+//! Without `trace=`, all exits are recorded in the ring and none printed.
+
+#![allow(dead_code)]
+
+use alloc::collections::VecDeque;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use axstd::sync::Mutex;
+
+use crate::dispatch::ExitClass;
+
+/// Exits kept in the ring of a VM.
+pub const RING_LEN: usize = 256;
+
+/// Most exits of one kind printed per second and VM.
+pub const PRINT_BURST: usize = 20;
+
+/// Nanoseconds per rate limiting window.
+const WINDOW_NS: u64 = 1_000_000_000;
+
+/// The last exits of one VM, oldest first.
+type ExitRing = Arc<Mutex<VecDeque<ExitRecord>>>;
+
+/// The rings of all running VMs, by VM id.
+static RINGS: Mutex<Vec<(usize, ExitRing)>> = Mutex::new(Vec::new());
+
+/// Class of a guest exit.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ExitKind {
+    /// Hypercall: SBI call, SVC or VMMCALL.
+    Hypercall,
+    /// Stage-2 fault on RAM or passthrough memory.
+    Fault,
+    /// Access to an emulated MMIO device.
+    Mmio,
+    /// Port I/O (x86_64).
+    Pio,
+    /// Host interrupt.
+    Interrupt,
+    /// WFI or HLT.
+    Halt,
+    /// Any other exit.
+    Other,
+}
+
+impl ExitKind {
+    /// All kinds, in [`ExitKind::index`] order.
+    pub const ALL: [ExitKind; 7] = [
+        Self::Hypercall,
+        Self::Fault,
+        Self::Mmio,
+        Self::Pio,
+        Self::Interrupt,
+        Self::Halt,
+        Self::Other,
+    ];
+
+    /// Returns the name used by `trace=` and in trace lines.
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Hypercall => "hypercall",
+            Self::Fault => "fault",
+            Self::Mmio => "mmio",
+            Self::Pio => "pio",
+            Self::Interrupt => "irq",
+            Self::Halt => "halt",
+            Self::Other => "other",
+        }
+    }
+
+    fn index(self) -> usize {
+        self as usize
+    }
+}
+
+impl ExitClass {
+    /// Returns the kind the exit is traced as.
+    pub fn trace_kind(self) -> ExitKind {
+        match self {
+            Self::Interrupt => ExitKind::Interrupt,
+            Self::Sbi | Self::Hypercall => ExitKind::Hypercall,
+            Self::Npf => ExitKind::Fault,
+            Self::Mmio => ExitKind::Mmio,
+            Self::Pio => ExitKind::Pio,
+            Self::Halt => ExitKind::Halt,
+            Self::Insn | Self::Fp | Self::Debug | Self::Reset | Self::Other => ExitKind::Other,
+        }
+    }
+}
+
+/// How much of the exits of one kind is traced.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum TraceLevel {
+    /// Not traced.
+    Off,
+    /// Recorded in the ring.
+    Ring,
+    /// Recorded in the ring and printed, rate limited.
+    Print,
+}
+
+impl TraceLevel {
+    fn parse(text: &str) -> Option<Self> {
+        match text {
+            "off" => Some(Self::Off),
+            "ring" => Some(Self::Ring),
+            "print" => Some(Self::Print),
+            _ => None,
+        }
+    }
+}
+
+/// Trace level of each exit kind of a VM.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TraceConfig {
+    levels: [TraceLevel; ExitKind::ALL.len()],
+}
+
+impl Default for TraceConfig {
+    fn default() -> Self {
+        Self {
+            levels: [TraceLevel::Ring; ExitKind::ALL.len()],
+        }
+    }
+}
+
+impl TraceConfig {
+    /// Parses a `trace=` value: comma-separated `KIND[:LEVEL]` items, where
+    /// `KIND` is an [`ExitKind::name`] or `all` and `LEVEL` is `off`, `ring`
+    /// or `print` (the default). Later items override earlier ones, on top
+    /// of the default of `all:ring`.
+    pub fn parse(spec: &str) -> Option<Self> {
+        let mut config = Self::default();
+        for item in spec.split(',').filter(|item| !item.is_empty()) {
+            let (kind, level) = match item.split_once(':') {
+                Some((kind, level)) => (kind, TraceLevel::parse(level)?),
+                None => (item, TraceLevel::Print),
+            };
+            if kind == "all" {
+                config.levels = [level; ExitKind::ALL.len()];
+            } else {
+                let kind = ExitKind::ALL.into_iter().find(|k| k.name() == kind)?;
+                config.levels[kind.index()] = level;
+            }
+        }
+        Some(config)
+    }
+
+    /// Returns the level of exits of `kind`.
+    pub fn level(&self, kind: ExitKind) -> TraceLevel {
+        self.levels[kind.index()]
+    }
+}
+
+/// One traced exit.
+#[derive(Clone, Copy, Debug)]
+pub struct ExitRecord {
+    /// Host monotonic time of the exit, in nanoseconds.
+    pub time_ns: u64,
+    /// The vCPU that exited.
+    pub cpu: usize,
+    /// Class of the exit.
+    pub kind: ExitKind,
+    /// Architectural exit reason: `scause`, exception class or exit code.
+    pub code: usize,
+    /// Guest PC at the exit.
+    pub pc: usize,
+    /// Exit details (`stval`/`htval`, ESR/FAR, EXITINFO1/EXITINFO2).
+    pub info: [usize; 2],
+}
+
+/// Rate limiting of the printed exits of one kind.
+#[derive(Clone, Copy, Default)]
+struct RateLimit {
+    window_start: u64,
+    printed: usize,
+    suppressed: usize,
+}
+
+/// The exit tracer of one VM. Its ring is listed for [`dump_rings`] until it
+/// is dropped.
+pub struct ExitTracer {
+    vm: usize,
+    config: TraceConfig,
+    ring: ExitRing,
+    limits: [RateLimit; ExitKind::ALL.len()],
+}
+
+impl ExitTracer {
+    /// Creates the tracer of VM `vm`.
+    pub fn new(vm: usize, config: TraceConfig) -> Self {
+        let ring = Arc::new(Mutex::new(VecDeque::with_capacity(RING_LEN)));
+        RINGS.lock().push((vm, ring.clone()));
+        Self {
+            vm,
+            config,
+            ring,
+            limits: [RateLimit::default(); ExitKind::ALL.len()],
+        }
+    }
+
+    /// Traces an exit of vCPU `cpu`.
+    pub fn record(&mut self, cpu: usize, kind: ExitKind, code: usize, pc: usize, info: [usize; 2]) {
+        let level = self.config.level(kind);
+        if level == TraceLevel::Off {
+            return;
+        }
+        let record = ExitRecord {
+            time_ns: axhal::time::monotonic_time_nanos(),
+            cpu,
+            kind,
+            code,
+            pc,
+            info,
+        };
+        {
+            let mut ring = self.ring.lock();
+            if ring.len() == RING_LEN {
+                ring.pop_front();
+            }
+            ring.push_back(record);
+        }
+        if level == TraceLevel::Print {
+            self.print_limited(&record);
+        }
+    }
+
+    fn print_limited(&mut self, record: &ExitRecord) {
+        let limit = &mut self.limits[record.kind.index()];
+        if record.time_ns.saturating_sub(limit.window_start) >= WINDOW_NS {
+            if limit.suppressed != 0 {
+                vm_println!(
+                    self.vm,
+                    "exit trace: {} {} exits not printed (rate limit)",
+                    limit.suppressed,
+                    record.kind.name()
+                );
+            }
+            *limit = RateLimit {
+                window_start: record.time_ns,
+                ..RateLimit::default()
+            };
+        }
+        if limit.printed < PRINT_BURST {
+            limit.printed += 1;
+            print_record(self.vm, record);
+        } else {
+            limit.suppressed += 1;
+        }
+    }
+}
+
+impl Drop for ExitTracer {
+    fn drop(&mut self) {
+        RINGS
+            .lock()
+            .retain(|(_, ring)| !Arc::ptr_eq(ring, &self.ring));
+    }
+}
+
+fn print_record(vm: usize, record: &ExitRecord) {
+    vm_println!(
+        vm,
+        "exit {:>6}.{:06} cpu{} {:<9} code={:#x} pc={:#x} info=({:#x}, {:#x})",
+        record.time_ns / 1_000_000_000,
+        record.time_ns % 1_000_000_000 / 1000,
+        record.cpu,
+        record.kind.name(),
+        record.code,
+        record.pc,
+        record.info[0],
+        record.info[1]
+    );
+}
+
+/// Prints the rings of all running VMs, oldest exit first.
+pub fn dump_rings() {
+    let rings: Vec<_> = RINGS.lock().clone();
+    for (vm, ring) in rings {
+        let records: Vec<ExitRecord> = ring.lock().iter().copied().collect();
+        vm_println!(vm, "---- last {} exits ----", records.len());
+        for record in &records {
+            print_record(vm, record);
+        }
+    }
+}
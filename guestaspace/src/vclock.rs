@@ -0,0 +1,99 @@
+//! Virtual `time` of riscv64 guests.
+//!
+//! A guest reads `time` directly, and the hardware adds `htimedelta` to the
+//! host's value. [`GuestClock`] keeps that offset so that guest time starts
+//! at zero when the VM boots, and can be stopped while the guest does not
+//! run (a snapshot being taken or restored, a long hypervisor stall) so the
+//! guest does not see the gap. Guest time never runs ahead of host time,
+//! which keeps the offset a plain subtraction.
+//!
+//! Timer deadlines the guest programs through SBI are guest times;
+//! [`GuestClock::to_host`] converts them for the host timer. On a host with
+//! the Sstc extension (see `csrs::GuestEnv`), the guest programs its
+//! deadline in `vstimecmp` itself and the hardware raises its timer
+//! interrupt, without SBI calls or exits.
+
+#![allow(dead_code)]
+
+use core::time::Duration;
+
+use axhal::time::ticks_to_nanos;
+use riscv::register::time;
+
+/// The `time` seen by one guest.
+pub struct GuestClock {
+    /// Host time at guest time zero.
+    offset: u64,
+    /// Guest time at which the clock was paused.
+    paused_at: Option<u64>,
+}
+
+impl GuestClock {
+    /// Creates a running clock that reads zero now.
+    pub fn new() -> Self {
+        Self {
+            offset: time::read64(),
+            paused_at: None,
+        }
+    }
+
+    /// Returns the current guest time.
+    pub fn now(&self) -> u64 {
+        self.paused_at
+            .unwrap_or_else(|| time::read64().saturating_sub(self.offset))
+    }
+
+    /// Returns the `htimedelta` value that makes the guest's `time` reads
+    /// return [`now`](Self::now) (while running).
+    pub fn htimedelta(&self) -> usize {
+        self.offset.wrapping_neg() as usize
+    }
+
+    /// Converts the guest time `t` to host time. `u64::MAX` (no deadline)
+    /// stays `u64::MAX`.
+    pub fn to_host(&self, t: u64) -> u64 {
+        t.saturating_add(self.offset)
+    }
+
+    /// Returns how long it takes until the guest time reaches `t`.
+    pub fn time_until(&self, t: u64) -> Duration {
+        Duration::from_nanos(ticks_to_nanos(t.saturating_sub(self.now())))
+    }
+
+    /// Stops the guest's time at its current value.
+    pub fn pause(&mut self) {
+        if self.paused_at.is_none() {
+            self.paused_at = Some(self.now());
+        }
+    }
+
+    /// Lets the guest's time run again from where it was paused.
+    pub fn resume(&mut self) {
+        if let Some(t) = self.paused_at.take() {
+            self.set(t);
+        }
+    }
+
+    /// Checks whether the clock is paused.
+    pub fn is_paused(&self) -> bool {
+        self.paused_at.is_some()
+    }
+
+    /// Returns how long the host time takes to reach the guest time `t`,
+    /// which [`set`](Self::set) would clamp otherwise: the time of a guest
+    /// migrated in from a host that has been up for longer.
+    pub fn host_lag(t: u64) -> Duration {
+        Duration::from_nanos(ticks_to_nanos(t.saturating_sub(time::read64())))
+    }
+
+    /// Sets the guest time to `t`, e.g. the time saved in a snapshot. It is
+    /// clamped to the host time.
+    pub fn set(&mut self, t: u64) {
+        let host = time::read64();
+        if self.paused_at.is_some() {
+            self.paused_at = Some(t.min(host));
+        } else {
+            self.offset = host - t.min(host);
+        }
+    }
+}
@@ -0,0 +1,615 @@
+use core::arch::global_asm;
+use core::mem::size_of;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use super::regs::{GeneralPurposeRegisters, GprIndex};
+use crate::events::PendingEvents;
+use crate::hostcpu::MAX_HOST_CPUS;
+use crate::migrate::CpuState;
+use memoffset::offset_of;
+
+/// VS-level software interrupt line (`hvip.VSSIP`).
+pub const IRQ_VS_SOFT: u32 = 2;
+/// VS-level timer interrupt line (`hvip.VSTIP`).
+pub const IRQ_VS_TIMER: u32 = 6;
+/// VS-level external interrupt line (`hvip.VSEIP`).
+pub const IRQ_VS_EXTERNAL: u32 = 10;
+/// The `hvip` bits driven from [`PendingEvents`].
+const HVIP_VS_IRQS: usize = 1 << IRQ_VS_SOFT | 1 << IRQ_VS_TIMER | 1 << IRQ_VS_EXTERNAL;
+
+/// Register ids of a migrated [`CpuState`]: `x1`-`x31` are 1 to 31, `f0`-`f31`
+/// start at [`STATE_FPR`] and CSRs at [`STATE_CSR`] plus their CSR number.
+pub const STATE_FPR: u32 = 0x20;
+pub const STATE_CSR: u32 = 0x1000;
+/// The guest's timer deadline, kept by the run loop.
+pub const STATE_VSTIMECMP: u32 = STATE_CSR + 0x24D;
+
+/// Hypervisor GPR and CSR state which must be saved/restored when entering/exiting virtualization.
+#[derive(Default)]
+#[repr(C)]
+struct HypervisorCpuState {
+    gprs: GeneralPurposeRegisters,
+    sstatus: usize,
+    scounteren: usize,
+    stvec: usize,
+    sscratch: usize,
+}
+
+/// Guest GPR and CSR state which must be saved/restored when exiting/entering virtualization.
+#[derive(Default)]
+#[repr(C)]
+pub struct GuestCpuState {
+    pub gprs: GeneralPurposeRegisters,
+    pub sstatus: usize,
+    pub hstatus: usize,
+    pub scounteren: usize,
+    pub sepc: usize,
+}
+
+/// The CSRs that are only in effect when virtualization is enabled (V=1) and must be saved and
+/// restored whenever we switch between VMs.
+#[derive(Default)]
+#[repr(C)]
+pub struct GuestVsCsrs {
+    htimedelta: usize,
+    vsstatus: usize,
+    vsie: usize,
+    vstvec: usize,
+    vsscratch: usize,
+    vsepc: usize,
+    vscause: usize,
+    vstval: usize,
+    vsatp: usize,
+    vstimecmp: usize,
+}
+
+/// The floating-point registers of the guest or of the hypervisor, switched lazily: a guest enters
+/// with `sstatus.FS` Off, and its first FP instruction after an entry exits as an illegal
+/// instruction so the hypervisor can load them.
+#[derive(Default)]
+#[repr(C)]
+struct FpRegs {
+    f: [u64; 32],
+    fcsr: usize,
+}
+
+/// The FP registers of a vCPU and of the hypervisor while the guest's are loaded.
+#[derive(Default)]
+#[repr(C)]
+struct GuestFpState {
+    guest: FpRegs,
+    host: FpRegs,
+    /// The guest's registers are in the FP register file.
+    loaded: bool,
+}
+
+/// `sstatus.FS` and two of its values.
+const SSTATUS_FS: usize = 3 << 13;
+const SSTATUS_FS_CLEAN: usize = 2 << 13;
+const SSTATUS_FS_DIRTY: usize = 3 << 13;
+
+impl FpRegs {
+    /// Copies the FP register file here. `sstatus.FS` must not be Off.
+    fn save(&mut self) {
+        unsafe {
+            core::arch::asm!(
+                "fsd f0, 0({0})",
+                "fsd f1, 8({0})",
+                "fsd f2, 16({0})",
+                "fsd f3, 24({0})",
+                "fsd f4, 32({0})",
+                "fsd f5, 40({0})",
+                "fsd f6, 48({0})",
+                "fsd f7, 56({0})",
+                "fsd f8, 64({0})",
+                "fsd f9, 72({0})",
+                "fsd f10, 80({0})",
+                "fsd f11, 88({0})",
+                "fsd f12, 96({0})",
+                "fsd f13, 104({0})",
+                "fsd f14, 112({0})",
+                "fsd f15, 120({0})",
+                "fsd f16, 128({0})",
+                "fsd f17, 136({0})",
+                "fsd f18, 144({0})",
+                "fsd f19, 152({0})",
+                "fsd f20, 160({0})",
+                "fsd f21, 168({0})",
+                "fsd f22, 176({0})",
+                "fsd f23, 184({0})",
+                "fsd f24, 192({0})",
+                "fsd f25, 200({0})",
+                "fsd f26, 208({0})",
+                "fsd f27, 216({0})",
+                "fsd f28, 224({0})",
+                "fsd f29, 232({0})",
+                "fsd f30, 240({0})",
+                "fsd f31, 248({0})",
+                "frcsr {1}",
+                in(reg) self.f.as_mut_ptr(),
+                out(reg) self.fcsr,
+            );
+        }
+    }
+
+    /// Loads these values into the FP register file. `sstatus.FS` must not be Off.
+    fn restore(&self) {
+        unsafe {
+            core::arch::asm!(
+                "fld f0, 0({0})",
+                "fld f1, 8({0})",
+                "fld f2, 16({0})",
+                "fld f3, 24({0})",
+                "fld f4, 32({0})",
+                "fld f5, 40({0})",
+                "fld f6, 48({0})",
+                "fld f7, 56({0})",
+                "fld f8, 64({0})",
+                "fld f9, 72({0})",
+                "fld f10, 80({0})",
+                "fld f11, 88({0})",
+                "fld f12, 96({0})",
+                "fld f13, 104({0})",
+                "fld f14, 112({0})",
+                "fld f15, 120({0})",
+                "fld f16, 128({0})",
+                "fld f17, 136({0})",
+                "fld f18, 144({0})",
+                "fld f19, 152({0})",
+                "fld f20, 160({0})",
+                "fld f21, 168({0})",
+                "fld f22, 176({0})",
+                "fld f23, 184({0})",
+                "fld f24, 192({0})",
+                "fld f25, 200({0})",
+                "fld f26, 208({0})",
+                "fld f27, 216({0})",
+                "fld f28, 224({0})",
+                "fld f29, 232({0})",
+                "fld f30, 240({0})",
+                "fld f31, 248({0})",
+                "fscsr {1}",
+                in(reg) self.f.as_ptr(),
+                in(reg) self.fcsr,
+            );
+        }
+    }
+}
+
+/// Runs `f` with the hypervisor's own `sstatus.FS` set, so that it may access the FP registers.
+fn with_fp_enabled(f: impl FnOnce()) {
+    let saved: usize;
+    unsafe {
+        core::arch::asm!("csrrs {}, sstatus, {}", out(reg) saved, in(reg) SSTATUS_FS_CLEAN);
+    }
+    f();
+    unsafe {
+        core::arch::asm!("csrc sstatus, {}", in(reg) !saved & SSTATUS_FS);
+    }
+}
+
+/// Virtualized HS-level CSRs that are used to emulate (part of) the hypervisor extension for the
+/// guest.
+#[derive(Default)]
+#[repr(C)]
+pub struct GuestVirtualHsCsrs {
+    hie: usize,
+    hgeie: usize,
+    hgatp: usize,
+}
+
+/// CSRs written on an exit from virtualization that are used by the hypervisor to determine the cause
+/// of the trap.
+#[derive(Default, Clone)]
+#[repr(C)]
+pub struct VmCpuTrapState {
+    pub scause: usize,
+    pub stval: usize,
+    pub htval: usize,
+    pub htinst: usize,
+}
+
+/// (v)CPU register state that must be saved or restored when entering/exiting a VM or switching
+/// between VMs.
+#[derive(Default)]
+#[repr(C)]
+pub struct VmCpuRegisters {
+    // CPU state that's shared between our's and the guest's execution environment. Saved/restored
+    // when entering/exiting a VM.
+    hyp_regs: HypervisorCpuState,
+    pub guest_regs: GuestCpuState,
+
+    // CPU state that only applies when V=1, e.g. the VS-level CSRs. Saved/restored on activation of
+    // the vCPU.
+    vs_csrs: GuestVsCsrs,
+
+    // Virtualized HS-level CPU state.
+    virtual_hs_csrs: GuestVirtualHsCsrs,
+
+    // Read on VM exit.
+    pub trap_csrs: VmCpuTrapState,
+
+    // Loaded on the guest's first FP instruction after an entry, put back at the next exit.
+    fp: GuestFpState,
+}
+
+/// ID of the vCPU whose VS-level CSRs are currently loaded, per hart.
+static LOADED_VCPU: [AtomicUsize; MAX_HOST_CPUS] =
+    [const { AtomicUsize::new(usize::MAX) }; MAX_HOST_CPUS];
+
+impl GuestVsCsrs {
+    /// Reads the VS-level CSRs of the guest that just exited.
+    fn save(&mut self) {
+        unsafe {
+            core::arch::asm!(
+                "csrr {0}, htimedelta",
+                "csrr {1}, vsstatus",
+                "csrr {2}, vsie",
+                "csrr {3}, vstvec",
+                "csrr {4}, vsscratch",
+                out(reg) self.htimedelta,
+                out(reg) self.vsstatus,
+                out(reg) self.vsie,
+                out(reg) self.vstvec,
+                out(reg) self.vsscratch,
+            );
+            core::arch::asm!(
+                "csrr {0}, vsepc",
+                "csrr {1}, vscause",
+                "csrr {2}, vstval",
+                "csrr {3}, vsatp",
+                out(reg) self.vsepc,
+                out(reg) self.vscause,
+                out(reg) self.vstval,
+                out(reg) self.vsatp,
+            );
+        }
+    }
+
+    /// Writes these VS-level CSRs back to the hart.
+    fn restore(&self) {
+        unsafe {
+            core::arch::asm!(
+                "csrw htimedelta, {0}",
+                "csrw vsstatus, {1}",
+                "csrw vsie, {2}",
+                "csrw vstvec, {3}",
+                "csrw vsscratch, {4}",
+                in(reg) self.htimedelta,
+                in(reg) self.vsstatus,
+                in(reg) self.vsie,
+                in(reg) self.vstvec,
+                in(reg) self.vsscratch,
+            );
+            core::arch::asm!(
+                "csrw vsepc, {0}",
+                "csrw vscause, {1}",
+                "csrw vstval, {2}",
+                "csrw vsatp, {3}",
+                in(reg) self.vsepc,
+                in(reg) self.vscause,
+                in(reg) self.vstval,
+                in(reg) self.vsatp,
+            );
+        }
+    }
+}
+
+impl VmCpuRegisters {
+    /// Makes the VS-level CSRs of vCPU `vcpu_id` (unique among all VMs)
+    /// current on this hart.
+    ///
+    /// Returns `true` if another vCPU's state had to be replaced, in which
+    /// case the caller must also switch the G-stage translation. Must be
+    /// called with interrupts disabled, right before entering the guest.
+    pub fn activate(&self, vcpu_id: usize) -> bool {
+        let loaded = &LOADED_VCPU[axhal::percpu::this_cpu_id()];
+        if loaded.swap(vcpu_id, Ordering::Relaxed) == vcpu_id {
+            return false;
+        }
+        self.vs_csrs.restore();
+        true
+    }
+
+    /// Saves the VS-level CSRs after a VM exit, so that another VM may run
+    /// on this hart before this one is resumed.
+    pub fn save_vs_csrs(&mut self) {
+        self.vs_csrs.save();
+    }
+
+    /// Returns the guest's `vsatp` as of its last exit.
+    pub fn vsatp(&self) -> usize {
+        self.vs_csrs.vsatp
+    }
+
+    /// Returns the guest's VS-level CSRs as of its last exit, by name.
+    pub fn vs_csrs(&self) -> [(&'static str, usize); 10] {
+        let c = &self.vs_csrs;
+        [
+            ("vsstatus", c.vsstatus),
+            ("vsie", c.vsie),
+            ("vstvec", c.vstvec),
+            ("vsscratch", c.vsscratch),
+            ("vsepc", c.vsepc),
+            ("vscause", c.vscause),
+            ("vstval", c.vstval),
+            ("vsatp", c.vsatp),
+            ("vstimecmp", c.vstimecmp),
+            ("htimedelta", c.htimedelta),
+        ]
+    }
+
+    /// Makes the guest take the exception `cause` with `stval` = `tval` at
+    /// the instruction that exited, as if the hardware had raised it in the
+    /// guest: the trap is recorded in the VS-level CSRs and the guest resumes
+    /// in VS-mode at its `vstvec`.
+    ///
+    /// Must be called while this vCPU's VS-level CSRs are loaded on the
+    /// hart: right after its exit, or after its activation.
+    pub fn inject_exception(&mut self, cause: usize, tval: usize) {
+        const SSTATUS_SIE: usize = 1 << 1;
+        const SSTATUS_SPIE: usize = 1 << 5;
+        const SSTATUS_SPP: usize = 1 << 8;
+        const HSTATUS_SPVP: usize = 1 << 8;
+
+        // sstatus.SPP tells whether the guest was in VS- or VU-mode.
+        let vs = &mut self.vs_csrs;
+        let mut vsstatus = vs.vsstatus & !(SSTATUS_SPP | SSTATUS_SPIE | SSTATUS_SIE);
+        vsstatus |= self.guest_regs.sstatus & SSTATUS_SPP;
+        if vs.vsstatus & SSTATUS_SIE != 0 {
+            vsstatus |= SSTATUS_SPIE;
+        }
+        vs.vsstatus = vsstatus;
+        vs.vsepc = self.guest_regs.sepc;
+        vs.vscause = cause;
+        vs.vstval = tval;
+        unsafe {
+            core::arch::asm!(
+                "csrw vsstatus, {0}",
+                "csrw vsepc, {1}",
+                "csrw vscause, {2}",
+                "csrw vstval, {3}",
+                in(reg) vs.vsstatus,
+                in(reg) vs.vsepc,
+                in(reg) vs.vscause,
+                in(reg) vs.vstval,
+            );
+        }
+
+        // Exceptions always enter at the vstvec base, in VS-mode.
+        self.guest_regs.sepc = vs.vstvec & !3;
+        self.guest_regs.sstatus |= SSTATUS_SPP;
+        self.guest_regs.hstatus |= HSTATUS_SPVP;
+    }
+
+    /// Lands the events pending for this vCPU right before its entry: the
+    /// asserted VS interrupt lines become the `hvip` bits, and the next
+    /// exception is injected. Must be called after [`activate`].
+    ///
+    /// [`activate`]: Self::activate
+    pub fn land_events(&mut self, events: &mut PendingEvents) {
+        let hvip = events.irqs() as usize & HVIP_VS_IRQS;
+        unsafe {
+            core::arch::asm!(
+                "csrc hvip, {0}",
+                "csrs hvip, {1}",
+                in(reg) HVIP_VS_IRQS & !hvip,
+                in(reg) hvip,
+            );
+        }
+        if let Some(e) = events.take_exception() {
+            self.inject_exception(e.vector as usize, e.error.unwrap_or(0) as usize);
+        }
+    }
+
+    /// Loads the guest's FP registers after an illegal instruction exit, if it was the guest's first
+    /// FP instruction since the entry: `sstatus.FS` was Off. The guest then retries the instruction.
+    /// Returns `false` if the instruction is illegal for another reason.
+    pub fn load_guest_fp(&mut self) -> bool {
+        if self.fp.loaded || self.guest_regs.sstatus & SSTATUS_FS != 0 {
+            return false;
+        }
+        let fp = &mut self.fp;
+        with_fp_enabled(|| {
+            fp.host.save();
+            fp.guest.restore();
+        });
+        fp.loaded = true;
+        self.guest_regs.sstatus |= SSTATUS_FS_CLEAN;
+        true
+    }
+
+    /// Saves the guest's FP registers, if it changed them, and gives the hypervisor back its own
+    /// after an exit. The guest runs with FP disabled again until its next FP instruction.
+    pub fn put_guest_fp(&mut self) {
+        if !self.fp.loaded {
+            return;
+        }
+        let dirty = self.guest_regs.sstatus & SSTATUS_FS == SSTATUS_FS_DIRTY;
+        let fp = &mut self.fp;
+        with_fp_enabled(|| {
+            if dirty {
+                fp.guest.save();
+            }
+            fp.host.restore();
+        });
+        fp.loaded = false;
+        self.guest_regs.sstatus &= !SSTATUS_FS;
+    }
+
+    /// Returns the guest's registers for a migration: the general purpose
+    /// and FP registers, and the CSRs as of its last exit. Its FP registers
+    /// must have been put back.
+    pub fn save_state(&self) -> CpuState {
+        let (g, vs) = (&self.guest_regs, &self.vs_csrs);
+        let mut state = CpuState::default();
+        for i in 1..32 {
+            let reg = GprIndex::from_raw(i).unwrap();
+            state.set(i, g.gprs.reg(reg) as u64);
+        }
+        for (i, &f) in self.fp.guest.f.iter().enumerate() {
+            state.set(STATE_FPR + i as u32, f);
+        }
+        for (csr, value) in [
+            (0x003, self.fp.guest.fcsr),
+            (0x100, g.sstatus),
+            (0x106, g.scounteren),
+            (0x141, g.sepc),
+            (0x600, g.hstatus),
+            (0x200, vs.vsstatus),
+            (0x204, vs.vsie),
+            (0x205, vs.vstvec),
+            (0x240, vs.vsscratch),
+            (0x241, vs.vsepc),
+            (0x242, vs.vscause),
+            (0x243, vs.vstval),
+            (0x280, vs.vsatp),
+        ] {
+            state.set(STATE_CSR + csr, value as u64);
+        }
+        state
+    }
+
+    /// Loads the registers a migration brought; those `state` lacks keep
+    /// their values. The VS-level CSRs are loaded at the next activation.
+    pub fn restore_state(&mut self, state: &CpuState) {
+        for i in 1..32 {
+            if let Some(value) = state.get(i) {
+                let reg = GprIndex::from_raw(i).unwrap();
+                self.guest_regs.gprs.set_reg(reg, value as usize);
+            }
+        }
+        for (i, f) in self.fp.guest.f.iter_mut().enumerate() {
+            *f = state.get(STATE_FPR + i as u32).unwrap_or(*f);
+        }
+        let (g, vs) = (&mut self.guest_regs, &mut self.vs_csrs);
+        for (csr, value) in [
+            (0x003, &mut self.fp.guest.fcsr),
+            (0x100, &mut g.sstatus),
+            (0x106, &mut g.scounteren),
+            (0x141, &mut g.sepc),
+            (0x600, &mut g.hstatus),
+            (0x200, &mut vs.vsstatus),
+            (0x204, &mut vs.vsie),
+            (0x205, &mut vs.vstvec),
+            (0x240, &mut vs.vsscratch),
+            (0x241, &mut vs.vsepc),
+            (0x242, &mut vs.vscause),
+            (0x243, &mut vs.vstval),
+            (0x280, &mut vs.vsatp),
+        ] {
+            if let Some(v) = state.get(STATE_CSR + csr) {
+                *value = v as usize;
+            }
+        }
+    }
+
+    /// Forgets that vCPU `vcpu_id` is loaded on any hart, so that a rebuilt
+    /// vCPU with the same id starts from its own (fresh) VS-level CSRs.
+    pub fn deactivate(vcpu_id: usize) {
+        for loaded in &LOADED_VCPU {
+            let _ =
+                loaded.compare_exchange(vcpu_id, usize::MAX, Ordering::Relaxed, Ordering::Relaxed);
+        }
+    }
+}
+
+#[allow(dead_code)]
+const fn hyp_gpr_offset(index: GprIndex) -> usize {
+    offset_of!(VmCpuRegisters, hyp_regs)
+        + offset_of!(HypervisorCpuState, gprs)
+        + (index as usize) * size_of::<u64>()
+}
+
+#[allow(dead_code)]
+const fn guest_gpr_offset(index: GprIndex) -> usize {
+    offset_of!(VmCpuRegisters, guest_regs)
+        + offset_of!(GuestCpuState, gprs)
+        + (index as usize) * size_of::<u64>()
+}
+
+#[allow(unused_macros)]
+macro_rules! hyp_csr_offset {
+    ($reg:tt) => {
+        offset_of!(VmCpuRegisters, hyp_regs) + offset_of!(HypervisorCpuState, $reg)
+    };
+}
+
+#[allow(unused_macros)]
+macro_rules! guest_csr_offset {
+    ($reg:tt) => {
+        offset_of!(VmCpuRegisters, guest_regs) + offset_of!(GuestCpuState, $reg)
+    };
+}
+
+global_asm!(
+    include_str!("guest.S"),
+    hyp_ra = const hyp_gpr_offset(GprIndex::RA),
+    hyp_gp = const hyp_gpr_offset(GprIndex::GP),
+    hyp_tp = const hyp_gpr_offset(GprIndex::TP),
+    hyp_s0 = const hyp_gpr_offset(GprIndex::S0),
+    hyp_s1 = const hyp_gpr_offset(GprIndex::S1),
+    hyp_a1 = const hyp_gpr_offset(GprIndex::A1),
+    hyp_a2 = const hyp_gpr_offset(GprIndex::A2),
+    hyp_a3 = const hyp_gpr_offset(GprIndex::A3),
+    hyp_a4 = const hyp_gpr_offset(GprIndex::A4),
+    hyp_a5 = const hyp_gpr_offset(GprIndex::A5),
+    hyp_a6 = const hyp_gpr_offset(GprIndex::A6),
+    hyp_a7 = const hyp_gpr_offset(GprIndex::A7),
+    hyp_s2 = const hyp_gpr_offset(GprIndex::S2),
+    hyp_s3 = const hyp_gpr_offset(GprIndex::S3),
+    hyp_s4 = const hyp_gpr_offset(GprIndex::S4),
+    hyp_s5 = const hyp_gpr_offset(GprIndex::S5),
+    hyp_s6 = const hyp_gpr_offset(GprIndex::S6),
+    hyp_s7 = const hyp_gpr_offset(GprIndex::S7),
+    hyp_s8 = const hyp_gpr_offset(GprIndex::S8),
+    hyp_s9 = const hyp_gpr_offset(GprIndex::S9),
+    hyp_s10 = const hyp_gpr_offset(GprIndex::S10),
+    hyp_s11 = const hyp_gpr_offset(GprIndex::S11),
+    hyp_sp = const hyp_gpr_offset(GprIndex::SP),
+    hyp_sstatus = const hyp_csr_offset!(sstatus),
+    hyp_scounteren = const hyp_csr_offset!(scounteren),
+    hyp_stvec = const hyp_csr_offset!(stvec),
+    hyp_sscratch = const hyp_csr_offset!(sscratch),
+    guest_ra = const guest_gpr_offset(GprIndex::RA),
+    guest_gp = const guest_gpr_offset(GprIndex::GP),
+    guest_tp = const guest_gpr_offset(GprIndex::TP),
+    guest_s0 = const guest_gpr_offset(GprIndex::S0),
+    guest_s1 = const guest_gpr_offset(GprIndex::S1),
+    guest_a0 = const guest_gpr_offset(GprIndex::A0),
+    guest_a1 = const guest_gpr_offset(GprIndex::A1),
+    guest_a2 = const guest_gpr_offset(GprIndex::A2),
+    guest_a3 = const guest_gpr_offset(GprIndex::A3),
+    guest_a4 = const guest_gpr_offset(GprIndex::A4),
+    guest_a5 = const guest_gpr_offset(GprIndex::A5),
+    guest_a6 = const guest_gpr_offset(GprIndex::A6),
+    guest_a7 = const guest_gpr_offset(GprIndex::A7),
+    guest_s2 = const guest_gpr_offset(GprIndex::S2),
+    guest_s3 = const guest_gpr_offset(GprIndex::S3),
+    guest_s4 = const guest_gpr_offset(GprIndex::S4),
+    guest_s5 = const guest_gpr_offset(GprIndex::S5),
+    guest_s6 = const guest_gpr_offset(GprIndex::S6),
+    guest_s7 = const guest_gpr_offset(GprIndex::S7),
+    guest_s8 = const guest_gpr_offset(GprIndex::S8),
+    guest_s9 = const guest_gpr_offset(GprIndex::S9),
+    guest_s10 = const guest_gpr_offset(GprIndex::S10),
+    guest_s11 = const guest_gpr_offset(GprIndex::S11),
+    guest_t0 = const guest_gpr_offset(GprIndex::T0),
+    guest_t1 = const guest_gpr_offset(GprIndex::T1),
+    guest_t2 = const guest_gpr_offset(GprIndex::T2),
+    guest_t3 = const guest_gpr_offset(GprIndex::T3),
+    guest_t4 = const guest_gpr_offset(GprIndex::T4),
+    guest_t5 = const guest_gpr_offset(GprIndex::T5),
+    guest_t6 = const guest_gpr_offset(GprIndex::T6),
+    guest_sp = const guest_gpr_offset(GprIndex::SP),
+
+    guest_sstatus = const guest_csr_offset!(sstatus),
+    guest_hstatus = const guest_csr_offset!(hstatus),
+    guest_scounteren = const guest_csr_offset!(scounteren),
+    guest_sepc = const guest_csr_offset!(sepc),
+
+);
+
+unsafe extern "C" {
+    pub fn _run_guest(state: *mut VmCpuRegisters);
+}
@@ -0,0 +1,139 @@
+//! Emulation of guest instructions that raise virtual instruction exceptions
+//! (`scause` 22) on riscv64.
+//!
+//! The hardware reports the trapping instruction in `stval`, or the
+//! hypervisor reads it from guest memory ([`fetch`]). Instructions trap
+//! this way when:
+//!
+//! - they read a counter not enabled in `hcounteren`: only `cycle`, `time`
+//!   and `instret` are, so reads of the hardware performance counters are
+//!   emulated and return zero;
+//! - the guest executes WFI with `hstatus.VTW` set: the hart idles until
+//!   an interrupt is pending for it;
+//! - they belong to the hypervisor extension (hypervisor CSRs, `hfence`,
+//!   `hlv`/`hsv`), which is not offered to guests: the guest gets the
+//!   illegal instruction exception it would get without the extension.
+
+#![allow(dead_code)]
+
+use crate::gmem::GuestMemory;
+use crate::hlv;
+
+const OPCODE_SYSTEM: u32 = 0x73;
+pub const INSN_WFI: u32 = 0x1050_0073;
+
+/// `cycle`, the first counter CSR.
+pub const CSR_CYCLE: u16 = 0xC00;
+/// `time`.
+pub const CSR_TIME: u16 = 0xC01;
+/// `instret`.
+pub const CSR_INSTRET: u16 = 0xC02;
+/// `hpmcounter31`, the last counter CSR.
+pub const CSR_HPMCOUNTER31: u16 = 0xC1F;
+
+/// How a CSR instruction updates the CSR.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CsrOp {
+    /// CSRRW/CSRRWI: replace the value.
+    Write,
+    /// CSRRS/CSRRSI: set the bits of the source.
+    Set,
+    /// CSRRC/CSRRCI: clear the bits of the source.
+    Clear,
+}
+
+/// A decoded CSR instruction.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CsrAccess {
+    /// CSR number.
+    pub csr: u16,
+    pub op: CsrOp,
+    /// Destination register index (0 discards the old value).
+    pub rd: u32,
+    /// Source register index, or the 5-bit immediate if `imm` is set.
+    pub src: u32,
+    pub imm: bool,
+}
+
+impl CsrAccess {
+    /// Checks whether the instruction modifies the CSR; CSRRS/CSRRC with
+    /// `x0` or a zero immediate only read it.
+    pub fn writes(&self) -> bool {
+        self.op == CsrOp::Write || self.src != 0
+    }
+}
+
+/// An instruction that raised a virtual instruction exception.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum VirtualInsn {
+    /// A CSR access.
+    Csr(CsrAccess),
+    /// Wait for interrupt.
+    Wfi,
+}
+
+/// Returns the instruction that trapped at `sepc`: `stval` if the hardware
+/// reported it there, otherwise the instruction in guest memory, 16 bits
+/// for a compressed one. With `vsatp` bare the guest's virtual addresses
+/// are guest physical addresses and it is read from `mem`; otherwise it is
+/// fetched through the guest's translation with HLVX ([`crate::hlv`]), at
+/// the privilege in the guest's `sstatus.SPP`, which needs the vCPU's CSRs
+/// still loaded on the hart.
+pub fn fetch(
+    stval: usize,
+    sepc: usize,
+    vsatp: usize,
+    sstatus: usize,
+    mem: &impl GuestMemory,
+) -> Option<u32> {
+    if stval != 0 {
+        return Some(stval as u32);
+    }
+    if vsatp >> 60 != 0 {
+        return hlv::fetch_insn(sepc, hlv::GuestPriv::from_sstatus(sstatus)).ok();
+    }
+    // Halfword by halfword: a compressed instruction may end the page.
+    let low = mem.read_obj::<u16>(sepc).ok()? as u32;
+    if low & 3 != 3 {
+        return Some(low);
+    }
+    let high = mem.read_obj::<u16>(sepc + 2).ok()? as u32;
+    Some((high << 16) | low)
+}
+
+/// Decodes the trapping instruction `insn`.
+pub fn decode(insn: u32) -> Option<VirtualInsn> {
+    if insn == INSN_WFI {
+        return Some(VirtualInsn::Wfi);
+    }
+    if insn & 0x7F != OPCODE_SYSTEM {
+        return None;
+    }
+    let funct3 = (insn >> 12) & 7;
+    let op = match funct3 & 3 {
+        1 => CsrOp::Write,
+        2 => CsrOp::Set,
+        3 => CsrOp::Clear,
+        _ => return None,
+    };
+    Some(VirtualInsn::Csr(CsrAccess {
+        csr: (insn >> 20) as u16,
+        op,
+        rd: (insn >> 7) & 0x1F,
+        src: (insn >> 15) & 0x1F,
+        imm: funct3 & 4 != 0,
+    }))
+}
+
+/// Returns the value the guest reads from the counter CSR `csr`: the host
+/// hart's `cycle` and `instret`, the guest's own `time`, and zero for the
+/// hardware performance counters. `None` if `csr` is not a counter.
+pub fn counter_value(csr: u16, guest_time: u64) -> Option<u64> {
+    match csr {
+        CSR_CYCLE => Some(riscv::register::cycle::read64()),
+        CSR_TIME => Some(guest_time),
+        CSR_INSTRET => Some(riscv::register::instret::read64()),
+        c if c <= CSR_HPMCOUNTER31 && c > CSR_INSTRET => Some(0),
+        _ => None,
+    }
+}
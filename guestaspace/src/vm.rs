@@ -0,0 +1,199 @@
+//! Ownership and teardown of the resources of one VM.
+//!
+//! A [`Vm`] owns the guest address space (second-stage page table, guest
+//! RAM and its references to shared image pages), its memory map, the
+//! VMID/ASID of the VM, the observers of its lifecycle ([`crate::hooks`]),
+//! the measurement of its image ([`crate::measure`]) and the secondary
+//! serial port once the VM claims it.
+//! Devices, vCPU state and the x86_64 VMCB belong to the run loop and
+//! are dropped when it returns.
+//!
+//! Tearing a VM down ([`Vm::destroy`], or dropping it on an early return)
+//! first makes sure no translation register of this CPU still points at the
+//! VM's page table, then drops the VM's cached translations, and only then
+//! frees its RAM and page table. A VM can thus be created and destroyed
+//! over and over without leaking host memory or leaving stale mappings.
+
+use axerrno::AxResult;
+use memory_addr::{PhysAddr, VirtAddr};
+
+use crate::config::VmConfig;
+use crate::gspace::GuestSpace;
+use crate::gva::{self, Access, GuestPaging};
+use crate::hooks::VmHooks;
+use crate::measure::Digest;
+use crate::memmap::MemoryMap;
+use crate::vmid::Vmid;
+
+/// The address space and TLB tag of one VM.
+pub struct Vm {
+    /// VM id, used to tag hypervisor messages.
+    pub id: usize,
+    /// Guest physical address space.
+    pub space: GuestSpace,
+    /// Named regions of the address space, for overlap checks and
+    /// [`Vm::print_memory_map`].
+    pub map: MemoryMap,
+    /// Tag of the VM's TLB entries.
+    pub vmid: Vmid,
+    /// The observers of the VM's boot, exits and resets.
+    pub hooks: VmHooks,
+    /// SHA-256 digest of the guest image, if it was measured.
+    pub measurement: Option<Digest>,
+    /// Value the translation root register gets back if the VM's root is
+    /// still installed at teardown: the host's `TTBR0_EL1` on aarch64, zero
+    /// (`hgatp` Bare) on riscv64. Unused on x86_64, where the nested root
+    /// lives in the VMCB.
+    host_root: usize,
+}
+
+impl Vm {
+    /// Creates a VM with an empty address space covering
+    /// `[base, base + size)`, limited to the memory cap of `cfg`, W^X if
+    /// `cfg` is hardened and with its fault-around window, and a fresh
+    /// VMID/ASID (or the shared one) and the observers registered so far.
+    pub fn new(cfg: &VmConfig, base: VirtAddr, size: usize, host_root: usize) -> AxResult<Self> {
+        let mut space = GuestSpace::new(base, size)?;
+        if let Some(limit) = cfg.mem_limit {
+            space.set_mem_limit(limit);
+        }
+        space.set_hardened(cfg.harden);
+        space.set_fault_around(cfg.fault_around);
+        let vmid = Vmid::alloc();
+        // aarch64 VMs always share the tag (see `crate::vmid`).
+        if vmid.is_shared() && cfg!(not(target_arch = "aarch64")) {
+            vm_println!(
+                cfg.id,
+                "No free VMID/ASID: sharing tag {}, flushed at every switch",
+                vmid.get()
+            );
+        }
+        Ok(Self {
+            id: cfg.id,
+            space,
+            map: MemoryMap::new(cfg.id),
+            vmid,
+            hooks: VmHooks::new(cfg.id),
+            measurement: None,
+            host_root,
+        })
+    }
+
+    /// Prints the declared regions of the guest physical address space.
+    pub fn print_memory_map(&self) {
+        self.map.print();
+    }
+
+    /// Prints how the fault frame pool served the guest, if it was used.
+    pub fn print_pool_stats(&self) {
+        let stats = self.space.pool_stats();
+        if stats.hits + stats.misses + stats.copies > 0 {
+            vm_println!(
+                self.id,
+                "Page pool: {} of {} zeroed pages ready at the fault ({}%), {} copies, {} batches",
+                stats.hits,
+                stats.hits + stats.misses,
+                stats.hit_rate(),
+                stats.copies,
+                stats.batches
+            );
+        }
+    }
+
+    /// Tears the VM down and reports the guest RAM it gave back.
+    pub fn destroy(mut self) {
+        let used = self.space.mem_used();
+        self.teardown();
+        vm_println!(
+            self.id,
+            "VM destroyed, {} KiB of guest RAM freed",
+            used / 1024
+        );
+    }
+
+    /// Detaches the VM from this CPU, frees its memory and gives back the
+    /// secondary serial port if it owns it. Idempotent.
+    fn teardown(&mut self) {
+        let root = usize::from(self.space.page_table_root());
+        let irqs_were_enabled = axhal::asm::irqs_enabled();
+        axhal::asm::disable_irqs();
+        detach(root, self.host_root, self.vmid.get());
+        if irqs_were_enabled {
+            axhal::asm::enable_irqs();
+        }
+        self.space.clear();
+        debug_assert_eq!(self.space.mem_used(), 0);
+        crate::serial::release(self.id);
+    }
+}
+
+// Not used by the run loops, which borrow only the address space.
+#[allow(dead_code)]
+impl Vm {
+    /// Returns the guest physical address the guest virtual address `vaddr`
+    /// translates to for `access` under the vCPU state `paging`
+    /// ([`gva::gva_to_gpa`]).
+    pub fn gva_to_gpa(&self, vaddr: usize, access: Access, paging: &GuestPaging) -> Option<usize> {
+        gva::gva_to_gpa(&self.space, paging, vaddr, access)
+    }
+
+    /// Returns the host physical address the guest virtual address `vaddr`
+    /// translates to for `access` through both stages
+    /// ([`gva::gva_to_hpa`]).
+    pub fn gva_to_hpa(
+        &self,
+        vaddr: usize,
+        access: Access,
+        paging: &GuestPaging,
+    ) -> Option<PhysAddr> {
+        gva::gva_to_hpa(&self.space, paging, vaddr, access)
+    }
+}
+
+impl Drop for Vm {
+    fn drop(&mut self) {
+        self.teardown();
+    }
+}
+
+/// Installs `host_root` in `hgatp` if it still holds the G-stage table at
+/// `root`, and drops the translations tagged with `vmid`.
+#[cfg(target_arch = "riscv64")]
+fn detach(root: usize, host_root: usize, vmid: usize) {
+    const HGATP_PPN_MASK: usize = (1 << 44) - 1;
+    unsafe {
+        let hgatp: usize;
+        core::arch::asm!("csrr {}, hgatp", out(reg) hgatp);
+        if hgatp & HGATP_PPN_MASK == root >> 12 {
+            core::arch::asm!("csrw hgatp, {}", in(reg) host_root);
+        }
+    }
+    crate::tlb::flush_guest_all(vmid);
+}
+
+/// Installs `host_root` in `TTBR0_EL1` if it still holds the table at
+/// `root`, and drops the guest's translations.
+#[cfg(target_arch = "aarch64")]
+fn detach(root: usize, host_root: usize, vmid: usize) {
+    const TTBR_BADDR_MASK: u64 = ((1 << 48) - 1) & !1;
+    unsafe {
+        let ttbr0: u64;
+        core::arch::asm!("mrs {}, ttbr0_el1", out(reg) ttbr0);
+        if ttbr0 & TTBR_BADDR_MASK == root as u64 {
+            core::arch::asm!(
+                "msr ttbr0_el1, {}",
+                "isb",
+                "tlbi vmalle1is",
+                "dsb ish",
+                "isb",
+                in(reg) host_root as u64,
+            );
+        }
+    }
+    crate::tlb::flush_guest_all(vmid);
+}
+
+/// The nested root is only referenced by the VMCB, freed by the run loop;
+/// the ASID's translations are flushed when it is allocated again.
+#[cfg(target_arch = "x86_64")]
+fn detach(_root: usize, _host_root: usize, _vmid: usize) {}
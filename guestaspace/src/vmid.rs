@@ -0,0 +1,143 @@
+//! VMID / ASID allocation for guest translation regimes.
+//!
+//! Every VM owns a tag for the TLB entries created while it runs:
+//!
+//! - riscv64: the `hgatp.VMID` field of the G-stage translation;
+//! - x86_64: the VMCB guest ASID.
+//!
+//! With distinct tags, switching between VMs only has to install the new
+//! translation root. Entries left behind by the previous owner of a tag are
+//! flushed when the tag is handed out again. Tag 0 is reserved for the host.
+//!
+//! When the hardware has no tag left (riscv64 without VMID bits among
+//! them), a VM gets the shared tag instead, and its guest TLB entries are
+//! flushed whenever it is switched to: tag 0 on riscv64 and aarch64, the
+//! last ASID on x86_64, where VMRUN refuses ASID 0.
+//!
+//! aarch64 guests always get the shared tag. The hypervisor runs at EL1 with
+//! the guest at EL0, so the only tag would be `TTBR0_EL1.ASID`, but the guest
+//! mappings created by `page_table_multiarch` are global (nG clear) and match
+//! any ASID; installing another VM's `TTBR0_EL1` flushes the EL1&0 TLB.
+
+use alloc::vec::Vec;
+
+use axstd::sync::Mutex;
+
+/// Upper bound on the number of tags managed, whatever the hardware offers.
+const MAX_VMIDS: usize = 256;
+
+/// Allocation state of every tag; empty until the first allocation.
+static VMIDS: Mutex<Vec<bool>> = Mutex::new(Vec::new());
+
+/// A VMID/ASID owned by one VM, released on drop, or the shared tag.
+#[derive(Debug)]
+pub struct Vmid {
+    id: usize,
+    shared: bool,
+}
+
+impl Vmid {
+    /// Allocates the lowest free tag, or the shared tag if none is free.
+    ///
+    /// On riscv64 the TLB entries of an owned tag are flushed before it is
+    /// returned. On x86_64 the caller must request
+    /// `TLB_CONTROL_FLUSH_ASID` for the first VMRUN instead.
+    pub fn alloc() -> Self {
+        let mut used = VMIDS.lock();
+        if used.is_empty() {
+            used.resize(hw_vmid_count().clamp(1, MAX_VMIDS), false);
+            used[0] = true;
+            let shared = shared_tag(used.len());
+            used[shared] = true;
+        }
+        let Some(id) = used.iter().position(|&u| !u) else {
+            return Self {
+                id: shared_tag(used.len()),
+                shared: true,
+            };
+        };
+        used[id] = true;
+        drop(used);
+
+        #[cfg(target_arch = "riscv64")]
+        flush_vmid(id);
+        Self { id, shared: false }
+    }
+
+    /// Returns the tag value.
+    pub fn get(&self) -> usize {
+        self.id
+    }
+
+    /// Returns `true` for the shared tag: the VM's guest TLB entries must
+    /// be flushed whenever it is switched to.
+    pub fn is_shared(&self) -> bool {
+        self.shared
+    }
+}
+
+impl Drop for Vmid {
+    fn drop(&mut self) {
+        if !self.shared {
+            VMIDS.lock()[self.id] = false;
+        }
+    }
+}
+
+/// The shared tag among `count` tags.
+#[cfg(not(target_arch = "x86_64"))]
+fn shared_tag(_count: usize) -> usize {
+    0
+}
+
+/// The shared tag among `count` tags.
+#[cfg(target_arch = "x86_64")]
+fn shared_tag(count: usize) -> usize {
+    count - 1
+}
+
+/// Number of tags supported by the hardware, including the host's tag 0.
+#[cfg(target_arch = "riscv64")]
+fn hw_vmid_count() -> usize {
+    const HGATP_VMID_SHIFT: usize = 44;
+    const HGATP_VMID_MASK: usize = 0x3FFF << HGATP_VMID_SHIFT;
+
+    // VMIDLEN is found by writing all ones to hgatp.VMID and reading it
+    // back. No task switch may observe the probe value.
+    let irqs_were_enabled = axhal::asm::irqs_enabled();
+    axhal::asm::disable_irqs();
+    let saved: usize;
+    let probed: usize;
+    unsafe {
+        core::arch::asm!("csrr {}, hgatp", out(reg) saved);
+        core::arch::asm!("csrw hgatp, {}", in(reg) HGATP_VMID_MASK);
+        core::arch::asm!("csrr {}, hgatp", out(reg) probed);
+        core::arch::asm!("csrw hgatp, {}", in(reg) saved);
+    }
+    if irqs_were_enabled {
+        axhal::asm::enable_irqs();
+    }
+    ((probed & HGATP_VMID_MASK) >> HGATP_VMID_SHIFT) + 1
+}
+
+/// Only the shared tag: global guest mappings cannot be told apart by ASID.
+#[cfg(target_arch = "aarch64")]
+fn hw_vmid_count() -> usize {
+    1
+}
+
+/// Number of tags supported by the hardware, including the host's tag 0.
+#[cfg(target_arch = "x86_64")]
+fn hw_vmid_count() -> usize {
+    // CPUID Fn8000_000A EBX: number of ASIDs.
+    let (_, nasid, _, _) = unsafe { crate::x86_64_svm::svm::cpuid(0x8000_000A) };
+    nasid as usize
+}
+
+/// Invalidates all G-stage TLB entries tagged with `vmid` on this hart.
+#[cfg(target_arch = "riscv64")]
+pub fn flush_vmid(vmid: usize) {
+    unsafe {
+        core::arch::riscv64::hfence_gvma_vmid(vmid);
+    }
+}
@@ -0,0 +1,321 @@
+//! The SBI PMU extension of a riscv64 guest hart.
+//!
+//! The guest sees the counters OpenSBI would give it, without the
+//! firmware's help:
+//!
+//! - hardware counters 0-2 are `cycle`, `time` and `instret`, which the
+//!   guest reads directly (`hcounteren`). They count on the host hart,
+//!   including while other guests run, and cannot be written from
+//!   HS-mode, so a start value is ignored: they are good for deltas. Only
+//!   `cycle` (CPU_CYCLES) and `instret` (INSTRUCTIONS) take events;
+//! - [`NUM_FW_COUNTERS`] firmware counters count the SBI events the
+//!   hypervisor sees for the hart ([`FwEvent`]) and are read with
+//!   `FW_READ`.
+//!
+//! Counter snapshots (`SNAPSHOT_SET_SHMEM`) are not supported.
+
+#![allow(dead_code)]
+
+use crate::sbi::{
+    PmuFunction, SBI_ERR_ALREADY_STARTED, SBI_ERR_ALREADY_STOPPED, SBI_ERR_INAVLID_PARAM,
+    SBI_ERR_NOT_SUPPORTED,
+};
+
+/// `cycle`, `time` and `instret`.
+pub const NUM_HW_COUNTERS: usize = 3;
+/// Firmware counters, after the hardware counters.
+pub const NUM_FW_COUNTERS: usize = 8;
+const NUM_COUNTERS: usize = NUM_HW_COUNTERS + NUM_FW_COUNTERS;
+
+/// `cycle` and `instret`, the hardware counters that take events.
+const COUNTER_CYCLE: usize = 0;
+const COUNTER_INSTRET: usize = 2;
+/// First CSR of the hardware counters, `cycle`.
+const CSR_CYCLE: usize = 0xC00;
+
+/// Event type (bits 19:16 of the event index) of the general hardware
+/// events and their codes.
+const EVENT_TYPE_HW: usize = 0;
+const HW_CPU_CYCLES: usize = 1;
+const HW_INSTRUCTIONS: usize = 2;
+/// Event type of the firmware events.
+const EVENT_TYPE_FW: usize = 15;
+
+/// `CONFIG_MATCHING` flags.
+const CFG_FLAG_SKIP_MATCH: u64 = 1 << 0;
+const CFG_FLAG_CLEAR_VALUE: u64 = 1 << 1;
+const CFG_FLAG_AUTO_START: u64 = 1 << 2;
+/// `COUNTER_START` flag.
+const START_FLAG_SET_INIT_VALUE: u64 = 1 << 0;
+/// `COUNTER_STOP` flag.
+const STOP_FLAG_RESET: u64 = 1 << 0;
+
+/// The firmware events the hypervisor counts, by event code.
+#[repr(usize)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FwEvent {
+    SetTimer = 5,
+    IpiSent = 6,
+    IpiReceived = 7,
+    FenceISent = 8,
+    FenceIReceived = 9,
+    SfenceVmaSent = 10,
+    SfenceVmaReceived = 11,
+    SfenceVmaAsidSent = 12,
+    SfenceVmaAsidReceived = 13,
+}
+
+impl FwEvent {
+    /// The event index the guest configures a counter with.
+    fn event_idx(self) -> usize {
+        EVENT_TYPE_FW << 16 | self as usize
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+struct Counter {
+    /// The event index the counter was configured with.
+    event: Option<usize>,
+    started: bool,
+    /// The value of a firmware counter.
+    value: u64,
+}
+
+/// The PMU state of a guest hart.
+#[derive(Clone, Debug, Default)]
+pub struct Vpmu {
+    counters: [Counter; NUM_COUNTERS],
+}
+
+impl Vpmu {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Carries out a PMU call of the guest: the value for `a1`, or the SBI
+    /// error code for `a0`.
+    pub fn call(&mut self, function: PmuFunction) -> Result<usize, isize> {
+        match function {
+            PmuFunction::GetNumCounters => Ok(NUM_COUNTERS),
+            PmuFunction::GetCounterInfo(idx) => counter_info(idx as usize),
+            PmuFunction::ConfigMatching {
+                counter_index,
+                counter_mask,
+                config_flags,
+                event_index,
+                ..
+            } => self.config_matching(
+                counter_index as usize,
+                counter_mask as usize,
+                config_flags,
+                event_index as usize,
+            ),
+            PmuFunction::StartCounter {
+                counter_index,
+                counter_mask,
+                start_flags,
+                initial_value,
+            } => {
+                let selected = selected(counter_index as usize, counter_mask as usize)?;
+                for idx in selected.clone() {
+                    match self.counters[idx] {
+                        Counter { event: None, .. } => return Err(SBI_ERR_INAVLID_PARAM),
+                        Counter { started: true, .. } => return Err(SBI_ERR_ALREADY_STARTED),
+                        _ => {}
+                    }
+                }
+                for idx in selected {
+                    let counter = &mut self.counters[idx];
+                    counter.started = true;
+                    if start_flags & START_FLAG_SET_INIT_VALUE != 0 {
+                        counter.value = initial_value;
+                    }
+                }
+                Ok(0)
+            }
+            PmuFunction::StopCounter {
+                counter_index,
+                counter_mask,
+                stop_flags,
+            } => {
+                let selected = selected(counter_index as usize, counter_mask as usize)?;
+                for idx in selected.clone() {
+                    match self.counters[idx] {
+                        Counter { event: None, .. } => return Err(SBI_ERR_INAVLID_PARAM),
+                        Counter { started: false, .. } => return Err(SBI_ERR_ALREADY_STOPPED),
+                        _ => {}
+                    }
+                }
+                for idx in selected {
+                    let counter = &mut self.counters[idx];
+                    counter.started = false;
+                    if stop_flags & STOP_FLAG_RESET != 0 {
+                        counter.event = None;
+                    }
+                }
+                Ok(0)
+            }
+            PmuFunction::FwRead(idx) => match self.counters.get(idx as usize) {
+                Some(counter) if idx as usize >= NUM_HW_COUNTERS => Ok(counter.value as usize),
+                _ => Err(SBI_ERR_INAVLID_PARAM),
+            },
+            // The upper half is only read on RV32.
+            PmuFunction::FwReadHi(idx) => match idx as usize {
+                NUM_HW_COUNTERS..NUM_COUNTERS => Ok(0),
+                _ => Err(SBI_ERR_INAVLID_PARAM),
+            },
+            PmuFunction::SnapshotSetShmem { .. } => Err(SBI_ERR_NOT_SUPPORTED),
+        }
+    }
+
+    /// Counts `event` on the started firmware counters configured for it.
+    pub fn count(&mut self, event: FwEvent) {
+        for counter in &mut self.counters[NUM_HW_COUNTERS..] {
+            if counter.started && counter.event == Some(event.event_idx()) {
+                counter.value = counter.value.wrapping_add(1);
+            }
+        }
+    }
+
+    fn config_matching(
+        &mut self,
+        base: usize,
+        mask: usize,
+        flags: u64,
+        event: usize,
+    ) -> Result<usize, isize> {
+        let mut selected = selected(base, mask)?;
+        let idx = if flags & CFG_FLAG_SKIP_MATCH != 0 {
+            // The counter the guest configured before.
+            selected.next().ok_or(SBI_ERR_INAVLID_PARAM)?
+        } else {
+            selected
+                .find(|&idx| self.counters[idx].event.is_none() && counts(idx, event))
+                .ok_or(SBI_ERR_NOT_SUPPORTED)?
+        };
+        let counter = &mut self.counters[idx];
+        counter.event = Some(event);
+        if flags & CFG_FLAG_CLEAR_VALUE != 0 {
+            counter.value = 0;
+        }
+        counter.started = flags & CFG_FLAG_AUTO_START != 0;
+        Ok(idx)
+    }
+}
+
+/// Returns the counters bit `i` of `mask` selects as counter `base + i`,
+/// or an invalid parameter error if any does not exist.
+fn selected(base: usize, mask: usize) -> Result<impl Iterator<Item = usize> + Clone, isize> {
+    let bits = (0..usize::BITS as usize).filter(move |bit| mask >> bit & 1 != 0);
+    if bits
+        .clone()
+        .any(|bit| base.checked_add(bit).is_none_or(|idx| idx >= NUM_COUNTERS))
+    {
+        return Err(SBI_ERR_INAVLID_PARAM);
+    }
+    Ok(bits.map(move |bit| base + bit))
+}
+
+/// Checks whether counter `idx` can count event `event`.
+fn counts(idx: usize, event: usize) -> bool {
+    match (event >> 16, event & 0xFFFF) {
+        (EVENT_TYPE_HW, HW_CPU_CYCLES) => idx == COUNTER_CYCLE,
+        (EVENT_TYPE_HW, HW_INSTRUCTIONS) => idx == COUNTER_INSTRET,
+        (EVENT_TYPE_FW, _) => idx >= NUM_HW_COUNTERS,
+        _ => false,
+    }
+}
+
+/// `COUNTER_GET_INFO`: the CSR and width of a hardware counter, the type
+/// bit of a firmware counter.
+fn counter_info(idx: usize) -> Result<usize, isize> {
+    match idx {
+        0..NUM_HW_COUNTERS => Ok(63 << 12 | (CSR_CYCLE + idx)),
+        NUM_HW_COUNTERS..NUM_COUNTERS => Ok(1 << (usize::BITS - 1)),
+        _ => Err(SBI_ERR_INAVLID_PARAM),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(pmu: &mut Vpmu, mask: usize, flags: u64, event: usize) -> Result<usize, isize> {
+        pmu.call(PmuFunction::ConfigMatching {
+            counter_index: 0,
+            counter_mask: mask as u64,
+            config_flags: flags,
+            event_index: event as u64,
+            event_data: 0,
+        })
+    }
+
+    #[test]
+    fn get_counter_info() {
+        let mut pmu = Vpmu::new();
+        assert_eq!(pmu.call(PmuFunction::GetNumCounters), Ok(11));
+        assert_eq!(pmu.call(PmuFunction::GetCounterInfo(2)), Ok(0x3F_C02));
+        assert_eq!(
+            pmu.call(PmuFunction::GetCounterInfo(3)),
+            Ok(1 << (usize::BITS - 1))
+        );
+        assert_eq!(
+            pmu.call(PmuFunction::GetCounterInfo(11)),
+            Err(SBI_ERR_INAVLID_PARAM)
+        );
+    }
+
+    #[test]
+    fn hardware_events() {
+        let mut pmu = Vpmu::new();
+        assert_eq!(config(&mut pmu, 0x7FF, 0, HW_INSTRUCTIONS), Ok(2));
+        assert_eq!(config(&mut pmu, 0x7FF, 0, HW_CPU_CYCLES), Ok(0));
+        // Both taken, and no counter for cache events.
+        assert_eq!(
+            config(&mut pmu, 0x7FF, 0, HW_CPU_CYCLES),
+            Err(SBI_ERR_NOT_SUPPORTED)
+        );
+        assert_eq!(
+            config(&mut pmu, 0x7FF, 0, 1 << 16),
+            Err(SBI_ERR_NOT_SUPPORTED)
+        );
+        assert_eq!(
+            config(&mut pmu, 1 << 11, 0, HW_CPU_CYCLES),
+            Err(SBI_ERR_INAVLID_PARAM)
+        );
+    }
+
+    #[test]
+    fn firmware_counters() {
+        let mut pmu = Vpmu::new();
+        let event = FwEvent::IpiSent.event_idx();
+        let idx = config(&mut pmu, 0x7FF, CFG_FLAG_AUTO_START, event).unwrap();
+        assert_eq!(idx, NUM_HW_COUNTERS);
+        pmu.count(FwEvent::IpiSent);
+        pmu.count(FwEvent::IpiSent);
+        pmu.count(FwEvent::SetTimer);
+        assert_eq!(pmu.call(PmuFunction::FwRead(idx as u64)), Ok(2));
+
+        let stop = PmuFunction::StopCounter {
+            counter_index: idx as u64,
+            counter_mask: 1,
+            stop_flags: 0,
+        };
+        assert_eq!(pmu.call(stop), Ok(0));
+        assert_eq!(pmu.call(stop), Err(SBI_ERR_ALREADY_STOPPED));
+        pmu.count(FwEvent::IpiSent);
+        assert_eq!(pmu.call(PmuFunction::FwRead(idx as u64)), Ok(2));
+
+        let start = PmuFunction::StartCounter {
+            counter_index: idx as u64,
+            counter_mask: 1,
+            start_flags: START_FLAG_SET_INIT_VALUE,
+            initial_value: 100,
+        };
+        assert_eq!(pmu.call(start), Ok(0));
+        assert_eq!(pmu.call(start), Err(SBI_ERR_ALREADY_STARTED));
+        pmu.count(FwEvent::IpiSent);
+        assert_eq!(pmu.call(PmuFunction::FwRead(idx as u64)), Ok(101));
+        assert_eq!(pmu.call(PmuFunction::FwRead(0)), Err(SBI_ERR_INAVLID_PARAM));
+    }
+}
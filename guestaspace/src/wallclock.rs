@@ -0,0 +1,198 @@
+//! Host wall-clock time, for the guests' RTCs.
+//!
+//! The host platform is built without its RTC driver, so its wall clock
+//! starts at the Unix epoch. The hypervisor reads the machine's RTC itself,
+//! once, on first use: the Goldfish RTC of the riscv64 `virt` machine, the
+//! PL031 of the aarch64 one, or the CMOS RTC of an x86_64 PC. [`now`] is
+//! that time plus the host's monotonic clock since then; if the RTC cannot
+//! be read, it counts from the epoch.
+//!
+//! [`civil_from_days`] and [`days_from_civil`] convert between days since
+//! the epoch and calendar dates, for the CMOS RTC layout.
+
+#![allow(dead_code)]
+
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::time::Duration;
+
+use axhal::time::monotonic_time_nanos;
+
+/// The wall clock at monotonic time zero, in nanoseconds since the epoch.
+/// [`UNREAD`] until the RTC was read.
+static EPOCH_OFFSET_NANOS: AtomicU64 = AtomicU64::new(UNREAD);
+const UNREAD: u64 = u64::MAX;
+
+const NANOS_PER_SEC: u64 = 1_000_000_000;
+const SECS_PER_DAY: u64 = 86_400;
+
+/// Returns the host wall-clock time since the Unix epoch.
+pub fn now() -> Duration {
+    let mut offset = EPOCH_OFFSET_NANOS.load(Ordering::Acquire);
+    if offset == UNREAD {
+        let rtc = read_rtc_nanos().unwrap_or(0);
+        offset = rtc.saturating_sub(monotonic_time_nanos());
+        EPOCH_OFFSET_NANOS.store(offset, Ordering::Release);
+    }
+    Duration::from_nanos(offset + monotonic_time_nanos())
+}
+
+/// A calendar date and time of day (UTC).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct DateTime {
+    pub year: u32,
+    /// 1 to 12.
+    pub month: u32,
+    /// 1 to 31.
+    pub day: u32,
+    pub hour: u32,
+    pub minute: u32,
+    pub second: u32,
+}
+
+impl DateTime {
+    /// Returns the date and time `secs` seconds after the epoch.
+    pub fn from_unix(secs: u64) -> Self {
+        let (year, month, day) = civil_from_days(secs / SECS_PER_DAY);
+        let rem = (secs % SECS_PER_DAY) as u32;
+        Self {
+            year,
+            month,
+            day,
+            hour: rem / 3600,
+            minute: rem / 60 % 60,
+            second: rem % 60,
+        }
+    }
+
+    /// Returns the seconds since the epoch; dates before it give 0.
+    pub fn to_unix(self) -> u64 {
+        days_from_civil(self.year, self.month, self.day) * SECS_PER_DAY
+            + self.hour as u64 * 3600
+            + self.minute as u64 * 60
+            + self.second as u64
+    }
+
+    /// Returns the day of the week, 1 (Sunday) to 7.
+    pub fn weekday(&self) -> u32 {
+        // 1970-01-01 was a Thursday.
+        ((days_from_civil(self.year, self.month, self.day) + 4) % 7) as u32 + 1
+    }
+}
+
+/// Returns `(year, month, day)` of day `days` since the epoch.
+pub fn civil_from_days(days: u64) -> (u32, u32, u32) {
+    // Howard Hinnant's algorithm, on eras of 400 years from 0000-03-01.
+    let z = days + 719_468;
+    let era = z / 146_097;
+    let doe = z % 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + u64::from(month <= 2);
+    (year as u32, month as u32, day as u32)
+}
+
+/// Returns the days since the epoch of `year-month-day`; dates before it
+/// give 0.
+pub fn days_from_civil(year: u32, month: u32, day: u32) -> u64 {
+    let year = year as u64 - u64::from(month <= 2);
+    let era = year / 400;
+    let yoe = year % 400;
+    let mp = (month as u64 + 9) % 12;
+    let doy = (153 * mp + 2) / 5 + (day as u64).saturating_sub(1);
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    (era * 146_097 + doe).saturating_sub(719_468)
+}
+
+/// Goldfish RTC of the QEMU riscv64 `virt` machine: nanoseconds since the
+/// epoch, reading TIME_LOW latches TIME_HIGH.
+#[cfg(target_arch = "riscv64")]
+fn read_rtc_nanos() -> Option<u64> {
+    const GOLDFISH_RTC_PADDR: usize = 0x10_1000;
+    let base = axhal::mem::phys_to_virt(GOLDFISH_RTC_PADDR.into()).as_usize();
+    let (low, high) = unsafe {
+        let low = core::ptr::read_volatile(base as *const u32);
+        let high = core::ptr::read_volatile((base + 4) as *const u32);
+        (low, high)
+    };
+    Some((high as u64) << 32 | low as u64)
+}
+
+/// PL031 of the QEMU aarch64 `virt` machine: seconds since the epoch.
+#[cfg(target_arch = "aarch64")]
+fn read_rtc_nanos() -> Option<u64> {
+    const PL031_PADDR: usize = 0x0901_0000;
+    let base = axhal::mem::phys_to_virt(PL031_PADDR.into()).as_usize();
+    let secs = unsafe { core::ptr::read_volatile(base as *const u32) };
+    Some(secs as u64 * NANOS_PER_SEC)
+}
+
+/// CMOS RTC of the PC, read twice until it is not updating in between.
+#[cfg(target_arch = "x86_64")]
+fn read_rtc_nanos() -> Option<u64> {
+    const REG_STATUS_A: u8 = 0x0A;
+    const REG_STATUS_B: u8 = 0x0B;
+    const STATUS_A_UIP: u8 = 0x80;
+    const STATUS_B_24H: u8 = 0x02;
+    const STATUS_B_BINARY: u8 = 0x04;
+
+    let read_all = || {
+        while cmos_read(REG_STATUS_A) & STATUS_A_UIP != 0 {
+            core::hint::spin_loop();
+        }
+        [0x00, 0x02, 0x04, 0x07, 0x08, 0x09, 0x32].map(cmos_read)
+    };
+    let mut regs = read_all();
+    loop {
+        let again = read_all();
+        if again == regs {
+            break;
+        }
+        regs = again;
+    }
+    let status_b = cmos_read(REG_STATUS_B);
+    let decode = |v: u8| {
+        if status_b & STATUS_B_BINARY != 0 {
+            v as u32
+        } else {
+            (v >> 4) as u32 * 10 + (v & 0xF) as u32
+        }
+    };
+    let [sec, min, hour, day, month, year, century] = regs;
+    let mut hour24 = decode(hour & 0x7F);
+    if status_b & STATUS_B_24H == 0 {
+        // 12-hour mode: 12 is midnight or noon, bit 7 is PM.
+        hour24 %= 12;
+        if hour & 0x80 != 0 {
+            hour24 += 12;
+        }
+    }
+    let century = match decode(century) {
+        19..=99 => decode(century),
+        _ => 20,
+    };
+    let time = DateTime {
+        year: century * 100 + decode(year),
+        month: decode(month),
+        day: decode(day),
+        hour: hour24,
+        minute: decode(min),
+        second: decode(sec),
+    };
+    if !(1..=12).contains(&time.month) || !(1..=31).contains(&time.day) {
+        return None;
+    }
+    Some(time.to_unix() * NANOS_PER_SEC)
+}
+
+#[cfg(target_arch = "x86_64")]
+fn cmos_read(reg: u8) -> u8 {
+    let value: u8;
+    unsafe {
+        core::arch::asm!("out 0x70, al", in("al") reg);
+        core::arch::asm!("in al, 0x71", out("al") value);
+    }
+    value
+}
@@ -0,0 +1,103 @@
+//! Guest watchdog: hung guests are stopped instead of spinning forever.
+//!
+//! A VM with `watchdog=SECS[:ACTION]` in `vms.conf` (see
+//! [`WatchdogConfig::parse`]) must show a sign of life at least every
+//! `SECS` seconds:
+//!
+//! - a heartbeat: the WATCHDOG_PET hypercall, SBI extension
+//!   [`SBI_EXT_HYPERVISOR`] function [`SBI_FID_WATCHDOG_PET`] on riscv64
+//!   and function [`HYPERCALL_WATCHDOG_PET`] (`x8` on aarch64, `RAX[7:0]`
+//!   on x86_64) elsewhere, which returns 0;
+//! - forward progress: any exit other than a host interrupt, that is, a
+//!   hypercall, a device access, a fault the hypervisor fixes up or an
+//!   instruction it emulates;
+//! - idling: a guest waiting in WFI/HLT waits for the hypervisor, not
+//!   hung.
+//!
+//! A guest spinning without exits still ends its run at every host timer
+//! tick, so the run loop checks the watchdog at least once per time slice.
+//! On expiry the VM prints a crash dump of the vCPU and is terminated or,
+//! with the `reset` action, rebooted. Time the VM spends paused does not
+//! count.
+//!
+//! [`SBI_EXT_HYPERVISOR`]: crate::boot::SBI_EXT_HYPERVISOR
+
+#![allow(dead_code)]
+
+/// Hypercall function ID (aarch64 `x8`, x86_64 `RAX[7:0]`) of
+/// WATCHDOG_PET.
+pub const HYPERCALL_WATCHDOG_PET: u64 = hvcall_abi::WATCHDOG_PET;
+/// Function ID of WATCHDOG_PET in the riscv64 hypervisor SBI extension.
+pub const SBI_FID_WATCHDOG_PET: usize = hvcall_abi::sbi::FID_WATCHDOG_PET;
+
+/// Longest `watchdog=` timeout, in seconds.
+pub const MAX_WATCHDOG_SECS: u64 = 3600;
+
+const NANOS_PER_SEC: u64 = 1_000_000_000;
+
+/// What happens to a VM whose watchdog expires.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum WatchdogAction {
+    /// The VM is terminated with an error.
+    #[default]
+    Terminate,
+    /// The VM is rebooted.
+    Reset,
+}
+
+/// The watchdog of a VM, as configured.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct WatchdogConfig {
+    /// Seconds the guest may go without a sign of life.
+    pub secs: u64,
+    pub action: WatchdogAction,
+}
+
+impl WatchdogConfig {
+    /// Parses a `watchdog=` value: the timeout in seconds (1 to
+    /// [`MAX_WATCHDOG_SECS`]), optionally followed by `:terminate` (the
+    /// default) or `:reset`.
+    pub fn parse(spec: &str) -> Option<Self> {
+        let (secs, action) = match spec.split_once(':') {
+            None => (spec, WatchdogAction::Terminate),
+            Some((secs, "terminate")) => (secs, WatchdogAction::Terminate),
+            Some((secs, "reset")) => (secs, WatchdogAction::Reset),
+            Some(_) => return None,
+        };
+        match secs.parse() {
+            Ok(secs @ 1..=MAX_WATCHDOG_SECS) => Some(Self { secs, action }),
+            _ => None,
+        }
+    }
+}
+
+/// The watchdog of a running VM.
+pub struct Watchdog {
+    config: Option<WatchdogConfig>,
+    /// Host time of the last sign of life, in nanoseconds.
+    last_alive: u64,
+}
+
+impl Watchdog {
+    /// Creates the watchdog of a VM starting now; `None` disables it.
+    pub fn new(config: Option<WatchdogConfig>) -> Self {
+        Self {
+            config,
+            last_alive: axhal::time::monotonic_time_nanos(),
+        }
+    }
+
+    /// Records a sign of life of the guest.
+    pub fn pet(&mut self) {
+        if self.config.is_some() {
+            self.last_alive = axhal::time::monotonic_time_nanos();
+        }
+    }
+
+    /// Returns the watchdog's configuration if it has expired.
+    pub fn expired(&self) -> Option<WatchdogConfig> {
+        let config = self.config?;
+        let silent = axhal::time::monotonic_time_nanos().saturating_sub(self.last_alive);
+        (silent >= config.secs * NANOS_PER_SEC).then_some(config)
+    }
+}
@@ -0,0 +1,195 @@
+//! Working-set estimation from stage-2 accessed bits.
+//!
+//! A VM with `wss=MS` in `vms.conf` has its working set sampled every `MS`
+//! milliseconds: the run loop harvests the accessed bits of the stage-2
+//! mappings of guest RAM ([`GuestSpace::harvest_accessed`]), which counts
+//! the RAM the guest touched since the last sample and clears the bits for
+//! the next period. x86_64 NPT and hosts with hardware A/D updates set the
+//! bits again on the next access; elsewhere that access takes an
+//! access-flag fault, which sets the bit ([`GuestSpace::handle_access_fault`])
+//! without counting as a fault the guest repeats. A huge mapping counts as a
+//! whole.
+//!
+//! The estimate follows a rising working set at once and a shrinking one
+//! gradually (a quarter of the way per sample), so a short idle period does
+//! not hide the memory the guest comes back to. Each sample goes to the
+//! VM's observers ([`crate::hooks::VmObserver::on_working_set`]), and the
+//! estimate is printed when the VM exits.
+//!
+//! The estimate drives the balloon: the BALLOON_TARGET hypercall tells a
+//! cooperative guest how much of the RAM backed beyond its working set (with
+//! [`HEADROOM_PERCENT`] on top) it should give back through
+//! BALLOON_RELEASE.
+//!
+//! [`GuestSpace::harvest_accessed`]: crate::gspace::GuestSpace::harvest_accessed
+//! [`GuestSpace::handle_access_fault`]: crate::gspace::GuestSpace::handle_access_fault
+
+#![allow(dead_code)]
+
+/// Shortest and longest `wss=` sampling periods, in milliseconds.
+pub const MIN_PERIOD_MS: u64 = 10;
+pub const MAX_PERIOD_MS: u64 = 60_000;
+
+/// RAM kept on top of the working set by the balloon target, in percent of
+/// the estimate.
+pub const HEADROOM_PERCENT: usize = 25;
+
+const NANOS_PER_MS: u64 = 1_000_000;
+const PAGE_SIZE: usize = 0x1000;
+
+/// One sample of a VM's working set, in bytes.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct WssSample {
+    /// RAM the guest accessed during the last period.
+    pub accessed: usize,
+    /// The working set estimate after this sample.
+    pub estimate: usize,
+    /// RAM backed by host frames when the sample was taken.
+    pub backed: usize,
+}
+
+/// The working set estimator of a VM.
+pub struct WorkingSet {
+    vm_id: usize,
+    /// Sampling period, `None` if the VM is not sampled.
+    period_ns: Option<u64>,
+    /// Host time the next sample is due, in nanoseconds.
+    next_ns: u64,
+    /// Bytes in the working set estimate.
+    estimate: usize,
+    /// Largest estimate so far.
+    peak: usize,
+    samples: usize,
+}
+
+impl WorkingSet {
+    /// Parses a `wss=` value: the sampling period in milliseconds, from
+    /// [`MIN_PERIOD_MS`] to [`MAX_PERIOD_MS`].
+    pub fn parse_period(spec: &str) -> Option<u64> {
+        spec.parse()
+            .ok()
+            .filter(|ms| (MIN_PERIOD_MS..=MAX_PERIOD_MS).contains(ms))
+    }
+
+    /// Creates the estimator of VM `vm_id`, sampling every `period_ms`
+    /// milliseconds from `now_ns` on; `None` disables sampling.
+    pub fn new(vm_id: usize, period_ms: Option<u64>, now_ns: u64) -> Self {
+        let period_ns = period_ms.map(|ms| ms * NANOS_PER_MS);
+        Self {
+            vm_id,
+            period_ns,
+            next_ns: now_ns.saturating_add(period_ns.unwrap_or(0)),
+            estimate: 0,
+            peak: 0,
+            samples: 0,
+        }
+    }
+
+    /// Checks whether a sample is due at `now_ns`.
+    pub fn due(&self, now_ns: u64) -> bool {
+        self.period_ns.is_some() && now_ns >= self.next_ns
+    }
+
+    /// Records a sample taken at `now_ns`: `accessed` bytes of RAM were
+    /// accessed in the period, `backed` bytes are backed.
+    pub fn record(&mut self, now_ns: u64, accessed: usize, backed: usize) -> WssSample {
+        self.estimate = if self.samples == 0 || accessed >= self.estimate {
+            accessed
+        } else {
+            self.estimate - (self.estimate - accessed) / 4
+        };
+        self.peak = self.peak.max(self.estimate);
+        self.samples += 1;
+        // A late sample does not make the next ones come in a burst.
+        self.next_ns = now_ns.saturating_add(self.period_ns.unwrap_or(0));
+        WssSample {
+            accessed,
+            estimate: self.estimate,
+            backed,
+        }
+    }
+
+    /// Returns the working set estimate in bytes, if a sample was taken.
+    pub fn estimate(&self) -> Option<usize> {
+        (self.samples > 0).then_some(self.estimate)
+    }
+
+    /// Returns the bytes the guest should give back through the balloon
+    /// while `backed` bytes of its RAM are backed: what exceeds the
+    /// estimate and its headroom, in whole pages. 0 before the first
+    /// sample.
+    pub fn balloon_target(&self, backed: usize) -> usize {
+        let Some(estimate) = self.estimate() else {
+            return 0;
+        };
+        let keep = estimate.saturating_add(estimate / 100 * HEADROOM_PERCENT);
+        backed.saturating_sub(keep) & !(PAGE_SIZE - 1)
+    }
+
+    /// Prints the estimate, if the VM was sampled.
+    #[cfg(feature = "axstd")]
+    pub fn report(&self) {
+        if self.samples > 0 {
+            vm_println!(
+                self.vm_id,
+                "Working set: {} KB estimated, {} KB at most, {} samples",
+                self.estimate / 1024,
+                self.peak / 1024,
+                self.samples
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MB: usize = 1 << 20;
+
+    #[test]
+    fn parse_period() {
+        assert_eq!(WorkingSet::parse_period("500"), Some(500));
+        assert_eq!(WorkingSet::parse_period("10"), Some(MIN_PERIOD_MS));
+        assert_eq!(WorkingSet::parse_period("9"), None);
+        assert_eq!(WorkingSet::parse_period("60001"), None);
+        assert_eq!(WorkingSet::parse_period("1s"), None);
+    }
+
+    #[test]
+    fn sampling_schedule() {
+        let off = WorkingSet::new(0, None, 0);
+        assert!(!off.due(u64::MAX));
+        let mut wss = WorkingSet::new(0, Some(100), 1_000);
+        assert!(!wss.due(1_000 + 99 * NANOS_PER_MS));
+        assert!(wss.due(1_000 + 100 * NANOS_PER_MS));
+        // Late by several periods: the next sample is one period away.
+        wss.record(1_000 + 450 * NANOS_PER_MS, 0, 0);
+        assert!(!wss.due(1_000 + 500 * NANOS_PER_MS));
+        assert!(wss.due(1_000 + 550 * NANOS_PER_MS));
+    }
+
+    #[test]
+    fn estimate_rises_at_once_and_decays() {
+        let mut wss = WorkingSet::new(0, Some(100), 0);
+        assert_eq!(wss.estimate(), None);
+        assert_eq!(wss.record(0, 8 * MB, 16 * MB).estimate, 8 * MB);
+        assert_eq!(wss.record(0, 12 * MB, 16 * MB).estimate, 12 * MB);
+        // Shrinking: a quarter of the way to the sample each time.
+        assert_eq!(wss.record(0, 4 * MB, 16 * MB).estimate, 10 * MB);
+        assert_eq!(wss.record(0, 2 * MB, 16 * MB).estimate, 8 * MB);
+        assert_eq!(wss.estimate(), Some(8 * MB));
+        assert_eq!(wss.peak, 12 * MB);
+    }
+
+    #[test]
+    fn balloon_target() {
+        let mut wss = WorkingSet::new(0, Some(100), 0);
+        assert_eq!(wss.balloon_target(64 * MB), 0);
+        wss.record(0, 8 * MB, 64 * MB);
+        // 8 MB plus 25% headroom stay.
+        assert_eq!(wss.balloon_target(64 * MB), 54 * MB);
+        assert_eq!(wss.balloon_target(10 * MB), 0);
+        assert_eq!(wss.balloon_target(10 * MB + 0x1800), 0x1000);
+    }
+}
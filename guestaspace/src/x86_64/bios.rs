@@ -0,0 +1,388 @@
+//! Legacy BIOS services for x86_64 guests that boot in real mode.
+//!
+//! A 512-byte boot sector ending in the `0x55 0xAA` signature is loaded at
+//! [`BOOT_SECTOR_GPA`] and entered in real mode at `0000:7C00` with
+//! DL = 0x80, the way a PC BIOS starts a stage-1 bootloader.
+//! [`Bios::install`] lays out the conventional memory such a bootloader
+//! expects:
+//!
+//! - the interrupt vector table at 0. Every vector points to a stub in the
+//!   BIOS segment 0xF000 that executes `out BIOS_PORT, al; iret`; the
+//!   intercepted OUT tells the hypervisor which service was called
+//!   ([`Bios::call`]). Vectors without a service return untouched;
+//! - the BIOS data area at 0x400 (COM1, equipment word, 639 KB of base
+//!   memory, 80x25 text mode) and a 1 KB EBDA below 640 KB;
+//! - the VGA text buffer at 0xB8000, rendered on the host console row by
+//!   row ([`Bios::poll`]).
+//!
+//! Services: INT 10h teletype output (AH=0Eh) to the VM console, INT 11h,
+//! INT 12h, INT 15h E820/E801/88h memory sizes and A20 functions
+//! 2400h-2403h, INT 16h without keystrokes, and the INT 1Ah tick count.
+//! Disk services (INT 13h) fail with CF set.
+//!
+//! A20 is on at boot, like on current BIOSes. The gate (INT 15h and the
+//! fast A20 bit of port 0x92) is tracked and reported back, but turning it
+//! off does not make addresses wrap at 1 MB.
+
+#![allow(dead_code)]
+
+use alloc::vec;
+use alloc::vec::Vec;
+
+use axerrno::AxResult;
+
+use super::svm::SvmGuestGprs;
+use super::vmcb::*;
+use crate::boot::{E820_RAM, E820_RESERVED, E820Entry};
+use crate::console::VmConsole;
+use crate::devices::mmio::MmioAccess;
+use crate::gmem::GuestMemory;
+use crate::gspace::SharedPages;
+
+/// Where the boot sector is loaded and entered.
+pub const BOOT_SECTOR_GPA: usize = 0x7C00;
+/// Size of a boot sector.
+pub const BOOT_SECTOR_SIZE: usize = 512;
+/// DL at entry: the first hard disk.
+pub const BOOT_DRIVE: u64 = 0x80;
+/// Port written by the interrupt stubs.
+pub const BIOS_PORT: usize = 0xE2;
+/// System control port A; bit 1 is the fast A20 gate.
+pub const PORT_A20: usize = 0x92;
+
+const BDA: usize = 0x400;
+const EBDA: usize = 0x9_FC00;
+const BASE_MEM_KB: u16 = (EBDA / 1024) as u16;
+const EXT_MEM_START: usize = 0x10_0000;
+const ROM_SEGMENT: u16 = 0xF000;
+const ROM_BASE: usize = 0xF_0000;
+const STUB_SIZE: usize = 4;
+const VECTORS: usize = 256;
+/// Equipment word: one serial port, 80x25 color text.
+const EQUIPMENT: u16 = 0x0220;
+const COM1: u16 = 0x3F8;
+const SMAP: u64 = 0x534D_4150;
+
+const VGA_TEXT: usize = 0xB_8000;
+const VGA_COLS: usize = 80;
+const VGA_ROWS: usize = 25;
+const VGA_POLL_NS: u64 = 100_000_000;
+
+const FLAGS_CF: u16 = 1 << 0;
+const FLAGS_ZF: u16 = 1 << 6;
+
+/// Checks whether `image` is a boot sector.
+pub fn is_boot_sector(image: &SharedPages) -> bool {
+    let mut sig = [0u8; 2];
+    image.len() == BOOT_SECTOR_SIZE && {
+        image.read(BOOT_SECTOR_SIZE - 2, &mut sig);
+        sig == [0x55, 0xAA]
+    }
+}
+
+/// Returns the e820 map of a real-mode guest with `ram_size` bytes of RAM
+/// at 0: base memory, the EBDA/video/BIOS hole and extended memory.
+pub fn e820_map(ram_size: usize) -> [E820Entry; 3] {
+    [
+        E820Entry {
+            addr: 0,
+            size: EBDA as u64,
+            kind: E820_RAM,
+        },
+        E820Entry {
+            addr: EBDA as u64,
+            size: (EXT_MEM_START - EBDA) as u64,
+            kind: E820_RESERVED,
+        },
+        E820Entry {
+            addr: EXT_MEM_START as u64,
+            size: (ram_size - EXT_MEM_START) as u64,
+            kind: E820_RAM,
+        },
+    ]
+}
+
+/// Sets the low 16 bits of `reg`.
+fn set16(reg: &mut u64, value: u16) {
+    *reg = *reg & !0xFFFF | value as u64;
+}
+
+/// Sets bits 15:8 of `reg`.
+fn set_high8(reg: &mut u64, value: u8) {
+    *reg = *reg & !0xFF00 | (value as u64) << 8;
+}
+
+/// Sets the low 8 bits of `reg`.
+fn set_low8(reg: &mut u64, value: u8) {
+    *reg = *reg & !0xFF | value as u64;
+}
+
+/// The BIOS of one real-mode VM.
+pub struct Bios {
+    vm_id: usize,
+    ram_size: usize,
+    a20: bool,
+    /// Host time at boot, for the tick count.
+    boot_ns: u64,
+    /// The VGA text rows last rendered.
+    screen: Vec<[u8; VGA_COLS]>,
+    next_poll_ns: u64,
+}
+
+impl Bios {
+    /// Creates the BIOS of VM `vm_id` with `ram_size` bytes of RAM at 0.
+    pub fn new(vm_id: usize, ram_size: usize) -> Self {
+        Self {
+            vm_id,
+            ram_size,
+            a20: true,
+            boot_ns: axhal::time::monotonic_time_nanos(),
+            screen: vec![[b' '; VGA_COLS]; VGA_ROWS],
+            next_poll_ns: 0,
+        }
+    }
+
+    /// Returns the ports to intercept.
+    pub fn ports() -> impl Iterator<Item = usize> {
+        [BIOS_PORT, PORT_A20].into_iter()
+    }
+
+    /// Checks whether `port` is a BIOS port.
+    pub fn contains(&self, port: usize) -> bool {
+        port == BIOS_PORT || port == PORT_A20
+    }
+
+    /// Returns the guest memory the BIOS writes in [`install`](Self::install)
+    /// as `(name, start, size)`.
+    pub fn regions() -> [(&'static str, usize, usize); 3] {
+        [
+            ("IVT and BDA", 0, BDA + 0x100),
+            ("EBDA", EBDA, 0x400),
+            ("BIOS ROM", ROM_BASE, VECTORS * STUB_SIZE),
+        ]
+    }
+
+    /// Writes the interrupt vector table, the interrupt stubs, the BIOS
+    /// data area and a blank text screen into guest memory.
+    pub fn install(&self, mem: &mut impl GuestMemory) -> AxResult {
+        let mut stubs = Vec::with_capacity(VECTORS * STUB_SIZE);
+        for vector in 0..VECTORS {
+            mem.write_obj(vector * 4, &[(vector * STUB_SIZE) as u16, ROM_SEGMENT])?;
+            stubs.extend_from_slice(&[0xE6, BIOS_PORT as u8, 0xCF, 0x90]);
+        }
+        mem.copy_to_guest(ROM_BASE, &stubs)?;
+
+        mem.write_obj(BDA, &COM1)?;
+        mem.write_obj(BDA + 0x0E, &((EBDA >> 4) as u16))?;
+        mem.write_obj(BDA + 0x10, &EQUIPMENT)?;
+        mem.write_obj(BDA + 0x13, &BASE_MEM_KB)?;
+        // Text mode 3: 80 columns, 25 rows (stored as the last row).
+        mem.write_obj(BDA + 0x49, &3u8)?;
+        mem.write_obj(BDA + 0x4A, &(VGA_COLS as u16))?;
+        mem.write_obj(BDA + 0x84, &(VGA_ROWS as u8 - 1))?;
+        // The EBDA starts with its size in KB.
+        mem.write_obj(EBDA, &1u8)?;
+
+        // Blanks in light grey on black.
+        mem.write_obj(VGA_TEXT, &[0x0720u16; VGA_COLS * VGA_ROWS])
+    }
+
+    /// Emulates an IN or OUT on port 0x92. Returns the value of an IN.
+    pub fn emulate_port(&mut self, access: &MmioAccess, rax: u64) -> Option<u64> {
+        if access.is_write {
+            // Bit 0 (fast reset) is ignored.
+            self.a20 = rax & 2 != 0;
+            None
+        } else {
+            Some((self.a20 as u64) << 1)
+        }
+    }
+
+    /// Serves the BIOS call behind an OUT to [`BIOS_PORT`]. Returns `false`
+    /// if the OUT did not come from an interrupt stub; it is then ignored.
+    pub fn call(
+        &mut self,
+        vmcb: &mut Vmcb,
+        gprs: &mut SvmGuestGprs,
+        mem: &mut impl GuestMemory,
+        console: &mut VmConsole,
+    ) -> bool {
+        let pc = vmcb.read_u64(SAVE_CS + 8) + vmcb.guest_rip();
+        let offset = pc.wrapping_sub(ROM_BASE as u64) as usize;
+        if offset >= VECTORS * STUB_SIZE || !offset.is_multiple_of(STUB_SIZE) {
+            return false;
+        }
+        let mut rax = vmcb.guest_rax();
+        let ah = (rax >> 8) as u8;
+        // New CF and ZF for the FLAGS the stub's IRET restores.
+        let mut cf = None;
+        let mut zf = None;
+        match offset / STUB_SIZE {
+            0x10 => match ah {
+                0x0E => console.putchar(rax as u8),
+                0x03 => {
+                    // Cursor at the top left, default shape.
+                    set16(&mut gprs.rcx, 0x0607);
+                    set16(&mut gprs.rdx, 0);
+                }
+                0x0F => {
+                    set16(&mut rax, (VGA_COLS as u16) << 8 | 3);
+                    set_high8(&mut gprs.rbx, 0);
+                }
+                _ => {}
+            },
+            0x11 => set16(&mut rax, EQUIPMENT),
+            0x12 => set16(&mut rax, BASE_MEM_KB),
+            0x13 => {
+                set_high8(&mut rax, 0x01);
+                cf = Some(true);
+            }
+            0x15 => cf = Some(self.int15(&mut rax, gprs, vmcb, mem)),
+            0x16 => match ah {
+                0x01 | 0x11 => zf = Some(true),
+                0x02 | 0x12 => set_low8(&mut rax, 0),
+                _ => set16(&mut rax, 0),
+            },
+            0x1A if ah == 0 => {
+                // 18.2 Hz ticks since boot, no midnight rollover.
+                let ns = axhal::time::monotonic_time_nanos() - self.boot_ns;
+                let ticks = (ns as u128 * 1_193_182 / 65_536 / 1_000_000_000) as u32;
+                set16(&mut gprs.rcx, (ticks >> 16) as u16);
+                set16(&mut gprs.rdx, ticks as u16);
+                set_low8(&mut rax, 0);
+            }
+            _ => {}
+        }
+        vmcb.write_u64(SAVE_RAX, rax);
+
+        if cf.is_some() || zf.is_some() {
+            // The stub's IRET pops IP, CS and FLAGS.
+            let ss_base = vmcb.read_u64(SAVE_SS + 8) as usize;
+            let sp = vmcb.read_u64(SAVE_RSP) as u16;
+            let addr = ss_base + sp.wrapping_add(4) as usize;
+            // A stack outside guest RAM gets no flags.
+            if let Ok(mut flags) = mem.read_obj::<u16>(addr) {
+                for (bit, value) in [(FLAGS_CF, cf), (FLAGS_ZF, zf)] {
+                    match value {
+                        Some(true) => flags |= bit,
+                        Some(false) => flags &= !bit,
+                        None => {}
+                    }
+                }
+                mem.write_obj(addr, &flags).ok();
+            }
+        }
+        true
+    }
+
+    /// Serves INT 15h. Returns CF.
+    fn int15(
+        &mut self,
+        rax: &mut u64,
+        gprs: &mut SvmGuestGprs,
+        vmcb: &Vmcb,
+        mem: &mut impl GuestMemory,
+    ) -> bool {
+        let ext_kb = (self.ram_size - EXT_MEM_START) / 1024;
+        match *rax as u16 {
+            0xE820 if gprs.rdx as u32 as u64 == SMAP => {
+                let map = e820_map(self.ram_size);
+                let index = gprs.rbx as u32 as usize;
+                let es_base = vmcb.read_u64(SAVE_ES + 8) as usize;
+                let buf = es_base + (gprs.rdi as u16) as usize;
+                let written = map.get(index).is_some_and(|entry| {
+                    (gprs.rcx as u32) >= 20
+                        && mem.write_obj(buf, &entry.addr).is_ok()
+                        && mem.write_obj(buf + 8, &entry.size).is_ok()
+                        && mem.write_obj(buf + 16, &entry.kind).is_ok()
+                });
+                if !written {
+                    set_high8(rax, 0x86);
+                    return true;
+                }
+                *rax = SMAP;
+                gprs.rcx = 20;
+                // EBX = 0 after the last entry.
+                gprs.rbx = if index + 1 < map.len() {
+                    index as u64 + 1
+                } else {
+                    0
+                };
+                false
+            }
+            0xE801 => {
+                // KB between 1 MB and 16 MB, 64 KB blocks above 16 MB.
+                let low = ext_kb.min(0x3C00) as u16;
+                let high = (ext_kb.saturating_sub(0x3C00) / 64).min(0xFFFF) as u16;
+                set16(rax, low);
+                set16(&mut gprs.rcx, low);
+                set16(&mut gprs.rbx, high);
+                set16(&mut gprs.rdx, high);
+                false
+            }
+            0x2400 | 0x2401 => {
+                self.a20 = *rax as u16 == 0x2401;
+                set_high8(rax, 0);
+                false
+            }
+            0x2402 => {
+                set16(rax, self.a20 as u16);
+                false
+            }
+            0x2403 => {
+                // Supported through port 0x92.
+                set_high8(rax, 0);
+                set16(&mut gprs.rbx, 2);
+                false
+            }
+            ax if ax >> 8 == 0x88 => {
+                set16(rax, ext_kb.min(0xFFFF) as u16);
+                false
+            }
+            _ => {
+                set_high8(rax, 0x86);
+                true
+            }
+        }
+    }
+
+    /// Prints the VGA text rows that changed since the last call, at most
+    /// every 100 ms. A screen that scrolled only prints its new rows; a row
+    /// being written is printed again every time it changes.
+    pub fn poll(&mut self, mem: &impl GuestMemory, now: u64) {
+        if now < self.next_poll_ns {
+            return;
+        }
+        self.next_poll_ns = now + VGA_POLL_NS;
+        let Ok(cells) = mem.read_obj::<[u16; VGA_COLS * VGA_ROWS]>(VGA_TEXT) else {
+            return;
+        };
+        let rows: Vec<[u8; VGA_COLS]> = cells
+            .chunks(VGA_COLS)
+            .map(|row| core::array::from_fn(|i| row[i] as u8))
+            .collect();
+        // Old row `i + k` is new row `i` after a scroll by `k` rows: take
+        // the `k` that leaves the fewest rows to print.
+        let changed = |k: usize, i: usize| i + k >= VGA_ROWS || rows[i] != self.screen[i + k];
+        let scroll = (0..=VGA_ROWS)
+            .min_by_key(|&k| (0..VGA_ROWS).filter(|&i| changed(k, i)).count())
+            .unwrap_or(0);
+        for (i, row) in rows.iter().enumerate() {
+            if !changed(scroll, i) || row.iter().all(|&ch| ch == b' ' || ch == 0) {
+                continue;
+            }
+            let text: alloc::string::String = row
+                .iter()
+                .map(|&ch| {
+                    if (0x20..0x7F).contains(&ch) {
+                        ch as char
+                    } else {
+                        ' '
+                    }
+                })
+                .collect();
+            vm_println!(self.vm_id, "vga: {}", text.trim_end());
+        }
+        self.screen = rows;
+    }
+}
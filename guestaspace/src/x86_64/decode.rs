@@ -0,0 +1,259 @@
+//! Decoding of the guest instructions behind x86_64 exits.
+//!
+//! Only the bytes are looked at: [`super::insn::fetch`] gets them from the
+//! VMCB or guest memory, and the exit handlers apply the result to the
+//! vCPU. Nothing here depends on the hypervisor, so the fuzz targets build
+//! it for the host.
+
+#![allow(dead_code)]
+
+/// A decoded `MOV CRn, reg` or `MOV reg, CRn`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct MovCr {
+    /// The control register is written (`0f 22`), otherwise read (`0f 20`).
+    pub to_cr: bool,
+    /// Control register number.
+    pub cr: usize,
+    /// General-purpose register number (see [`super::insn::read_gpr`]).
+    pub gpr: usize,
+    /// Instruction length.
+    pub len: u64,
+}
+
+/// Decodes a move to or from a control register, with an optional REX
+/// prefix.
+pub fn decode_mov_cr(bytes: &[u8]) -> Option<MovCr> {
+    let rex = match bytes.first() {
+        Some(&b) if b & 0xF0 == 0x40 => b,
+        _ => 0,
+    };
+    let start = (rex != 0) as usize;
+    match *bytes.get(start..start + 3)? {
+        [0x0F, op @ (0x20 | 0x22), modrm] => Some(MovCr {
+            to_cr: op == 0x22,
+            cr: ((modrm >> 3) & 7 | (rex & 4) << 1) as usize,
+            gpr: (modrm & 7 | (rex & 1) << 3) as usize,
+            len: start as u64 + 3,
+        }),
+        _ => None,
+    }
+}
+
+/// Returns the length of an `INVLPG m` (`0f 01 /7`), with its legacy and
+/// REX prefixes and memory operand.
+pub fn invlpg_len(bytes: &[u8]) -> Option<u64> {
+    let prefix = Prefixes::parse(bytes)?;
+    let i = prefix.len;
+    let [0x0F, 0x01, modrm] = *bytes.get(i..i + 3)? else {
+        return None;
+    };
+    if (modrm >> 3) & 7 != 7 {
+        return None;
+    }
+    Some((i + 2 + mem_operand_len(&bytes[i + 2..])?) as u64)
+}
+
+/// A decoded MOV, MOVZX or STOS between memory and a register or an
+/// immediate.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct MovMem {
+    /// Memory is written (`88`, `89`, `c6`, `c7`, `aa`, `ab`), otherwise
+    /// read (`8a`, `8b`, `0f b6`, `0f b7`).
+    pub is_write: bool,
+    /// Access size in bytes: 1, 2, 4 or 8.
+    pub width: usize,
+    /// General-purpose register read or written (see
+    /// [`super::insn::read_gpr`]).
+    pub reg: usize,
+    /// The register is AH, CH, DH or BH: bits 15:8 of register `reg`.
+    pub high_byte: bool,
+    /// Size in bytes of the register a load writes: the access size, or
+    /// 2, 4 or 8 for MOVZX.
+    pub reg_width: usize,
+    /// The value stored by `c6`/`c7`, sign-extended.
+    pub imm: Option<u64>,
+    /// STOS, which stores RAX at RDI and advances RDI: `Some(true)` with a
+    /// REP prefix.
+    pub stos: Option<bool>,
+    /// Instruction length.
+    pub len: u64,
+}
+
+impl MovMem {
+    /// Returns the new value of the register `old` after a load of `value`:
+    /// a 32-bit register is zero-extended, an 8 or 16-bit one keeps the
+    /// other bits, and MOVZX zero-extends the access into the register.
+    pub fn load_value(&self, old: u64, value: u64) -> u64 {
+        let value = value & width_mask(self.width);
+        match self.reg_width {
+            1 if self.high_byte => old & !0xFF00 | value << 8,
+            1 => old & !0xFF | value,
+            2 => old & !0xFFFF | value,
+            // A 32-bit register is zero-extended, as by MOVZX.
+            _ => value,
+        }
+    }
+
+    /// Returns the value a store of register value `reg` writes.
+    pub fn store_value(&self, reg: u64) -> u64 {
+        let reg = if self.high_byte { reg >> 8 } else { reg };
+        reg & width_mask(self.width)
+    }
+}
+
+fn width_mask(width: usize) -> u64 {
+    if width < 8 {
+        (1 << (width * 8)) - 1
+    } else {
+        u64::MAX
+    }
+}
+
+/// Decodes an instruction with a memory operand of the forms a guest uses
+/// for device registers:
+///
+/// - `MOV r/m, r` (`88`, `89`), `MOV r, r/m` (`8a`, `8b`) and
+///   `MOV r/m, imm` (`c6 /0`, `c7 /0`), 8 to 64 bits;
+/// - `MOVZX r, r/m8` (`0f b6`) and `MOVZX r, r/m16` (`0f b7`);
+/// - `STOS` (`aa`, `ab`), with or without REP.
+pub fn decode_mov_mem(bytes: &[u8]) -> Option<MovMem> {
+    let prefix = Prefixes::parse(bytes)?;
+    let i = prefix.len;
+    let op_width = if prefix.rex & 8 != 0 {
+        8
+    } else if prefix.opsize {
+        2
+    } else {
+        4
+    };
+    let (&opcode, rest) = bytes.get(i..)?.split_first()?;
+    if let 0xAA | 0xAB = opcode {
+        let width = if opcode == 0xAA { 1 } else { op_width };
+        return Some(MovMem {
+            is_write: true,
+            width,
+            reg: 0,
+            high_byte: false,
+            reg_width: width,
+            imm: None,
+            stos: Some(prefix.rep),
+            len: i as u64 + 1,
+        });
+    }
+    // MOVZX has a two-byte opcode.
+    let (opcode, rest, opcode_len) = match (opcode, rest) {
+        (0x0F, [op @ (0xB6 | 0xB7), rest @ ..]) => (0x0F00 | *op as u16, rest, 2),
+        _ => (opcode as u16, rest, 1),
+    };
+    let modrm = *rest.first()?;
+    if modrm >> 6 == 3 {
+        return None;
+    }
+    let width = match opcode {
+        0x88 | 0x8A | 0xC6 | 0x0FB6 => 1,
+        0x0FB7 => 2,
+        _ => op_width,
+    };
+    let reg_width = match opcode {
+        0x0FB6 | 0x0FB7 => op_width,
+        _ => width,
+    };
+    let operand = mem_operand_len(rest)?;
+    let reg = ((modrm >> 3) & 7 | (prefix.rex & 4) << 1) as usize;
+    let imm_start = i + opcode_len + operand;
+    let (is_write, imm, imm_len) = match opcode {
+        0x88 | 0x89 => (true, None, 0),
+        0x8A | 0x8B | 0x0FB6 | 0x0FB7 => (false, None, 0),
+        0xC6 | 0xC7 if reg & 7 == 0 => {
+            let imm_len = width.min(4);
+            let raw = bytes.get(imm_start..imm_start + imm_len)?;
+            let imm = raw.iter().rev().fold(0u64, |v, &b| v << 8 | b as u64);
+            // imm8 and imm16 fill the store; imm32 is sign-extended.
+            let imm = if imm_len == 4 {
+                imm as i32 as i64 as u64
+            } else {
+                imm
+            };
+            (true, Some(imm), imm_len)
+        }
+        _ => return None,
+    };
+    // Without REX, byte registers 4 to 7 are AH, CH, DH and BH.
+    let high_byte = reg_width == 1 && imm.is_none() && prefix.rex == 0 && reg >= 4;
+    Some(MovMem {
+        is_write,
+        width,
+        reg: if high_byte { reg - 4 } else { reg },
+        high_byte,
+        reg_width,
+        imm,
+        stos: None,
+        len: (imm_start + imm_len) as u64,
+    })
+}
+
+/// The prefixes of an instruction.
+struct Prefixes {
+    /// Bytes of prefixes.
+    len: usize,
+    /// Operand size override (`66`).
+    opsize: bool,
+    /// REP (`f3`).
+    rep: bool,
+    /// The REX prefix, 0 if none.
+    rex: u8,
+}
+
+impl Prefixes {
+    /// Skips operand size, address size, segment override and REP
+    /// prefixes, then a REX prefix.
+    fn parse(bytes: &[u8]) -> Option<Self> {
+        let mut len = bytes.iter().position(|b| {
+            !matches!(
+                b,
+                0x66 | 0x67 | 0x26 | 0x2E | 0x36 | 0x3E | 0x64 | 0x65 | 0xF3
+            )
+        })?;
+        let opsize = bytes[..len].contains(&0x66);
+        let rep = bytes[..len].contains(&0xF3);
+        let rex = if bytes[len] & 0xF0 == 0x40 {
+            len += 1;
+            bytes[len - 1]
+        } else {
+            0
+        };
+        Some(Self {
+            len,
+            opsize,
+            rep,
+            rex,
+        })
+    }
+}
+
+/// Returns the length of a memory operand in `bytes`, which start at its
+/// ModRM byte: ModRM, SIB and displacement.
+fn mem_operand_len(bytes: &[u8]) -> Option<usize> {
+    let modrm = *bytes.first()?;
+    let (md, rm) = (modrm >> 6, modrm & 7);
+    if md == 3 {
+        return None;
+    }
+    let mut len = 1;
+    let mut disp = match md {
+        1 => 1,
+        2 => 4,
+        _ if rm == 5 => 4, // RIP-relative
+        _ => 0,
+    };
+    if rm == 4 {
+        // SIB byte; base 5 without displacement means disp32 only.
+        let sib = *bytes.get(1)?;
+        len += 1;
+        if md == 0 && sib & 7 == 5 {
+            disp = 4;
+        }
+    }
+    len += disp;
+    (len <= bytes.len()).then_some(len)
+}
@@ -0,0 +1,106 @@
+//! x87/SSE state switching between x86_64 guests.
+//!
+//! The hypervisor is built for a soft-float target and never touches the
+//! x87/SSE registers itself, so only guests clobber each other's state. The
+//! registers of each host CPU are switched lazily: they stay with the guest
+//! that last ran there until another guest is about to enter on that CPU,
+//! which then saves them (FXSAVE) into their owner's [`GuestFpu`] and loads
+//! its own (FXRSTOR). A VM that
+//! runs alone never switches.
+//!
+//! Trapping the guest's first FP instruction with a forced `CR0.TS` and an
+//! `#NM` intercept would also need the guest's CR0 accesses intercepted to
+//! hide the forced bit, so guests always run with their own CR0.
+
+#![allow(dead_code)]
+
+use core::ptr::null_mut;
+use core::sync::atomic::{AtomicPtr, Ordering};
+
+use crate::hostcpu::MAX_HOST_CPUS;
+
+/// The [`GuestFpu`] whose state is in the registers of each host CPU (null:
+/// none).
+static FPU_OWNER: [AtomicPtr<GuestFpu>; MAX_HOST_CPUS] =
+    [const { AtomicPtr::new(null_mut()) }; MAX_HOST_CPUS];
+
+/// Offset of the x87 control word in the FXSAVE area.
+const FXSAVE_FCW: usize = 0;
+/// Offset of MXCSR in the FXSAVE area.
+const FXSAVE_MXCSR: usize = 24;
+
+/// The x87/SSE state of one guest, in the FXSAVE format.
+///
+/// Must stay at the same address (boxed) from the first [`load`] until it
+/// is dropped, while its registers may be in the hardware.
+///
+/// [`load`]: GuestFpu::load
+#[repr(C, align(16))]
+pub struct GuestFpu {
+    area: [u8; 512],
+}
+
+impl GuestFpu {
+    /// Creates the state the processor has after reset: x87 exceptions
+    /// masked (FCW 0x37F), SSE exceptions masked (MXCSR 0x1F80), all
+    /// registers zero.
+    pub fn new() -> Self {
+        let mut area = [0u8; 512];
+        area[FXSAVE_FCW..FXSAVE_FCW + 2].copy_from_slice(&0x037Fu16.to_le_bytes());
+        area[FXSAVE_MXCSR..FXSAVE_MXCSR + 4].copy_from_slice(&0x1F80u32.to_le_bytes());
+        Self { area }
+    }
+
+    /// Puts this guest's state into the registers, saving the previous
+    /// owner's first. Call with interrupts disabled, right before VMRUN.
+    pub fn load(&mut self) {
+        let this: *mut GuestFpu = self;
+        let owner = FPU_OWNER[axhal::percpu::this_cpu_id()].swap(this, Ordering::Relaxed);
+        if owner == this {
+            return;
+        }
+        unsafe {
+            if !owner.is_null() {
+                core::arch::asm!("fxsave64 [{}]", in(reg) (*owner).area.as_mut_ptr());
+            }
+            core::arch::asm!("fxrstor64 [{}]", in(reg) self.area.as_ptr());
+        }
+    }
+}
+
+impl Default for GuestFpu {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Drop for GuestFpu {
+    fn drop(&mut self) {
+        // Nobody may save into a freed state.
+        let this: *mut GuestFpu = self;
+        for owner in &FPU_OWNER {
+            let _ = owner.compare_exchange(this, null_mut(), Ordering::Relaxed, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Lets the hypervisor save and load the SSE state of guests: sets
+/// `CR4.OSFXSR` and clears `CR0.EM`/`CR0.TS` on the current CPU. Called
+/// on each host CPU before its first guest runs.
+pub fn enable() {
+    unsafe {
+        core::arch::asm!(
+            "mov {tmp}, cr4",
+            "or {tmp}, {osfxsr}",
+            "mov cr4, {tmp}",
+            "mov {tmp}, cr0",
+            "and {tmp}, {em_ts}",
+            "or {tmp}, {mp}",
+            "mov cr0, {tmp}",
+            tmp = out(reg) _,
+            osfxsr = in(reg) 1u64 << 9,
+            em_ts = in(reg) !((1u64 << 2) | (1 << 3)),
+            mp = in(reg) 1u64 << 1,
+        );
+    }
+}
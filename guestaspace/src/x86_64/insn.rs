@@ -0,0 +1,175 @@
+//! The guest instruction that caused an exit.
+//!
+//! With the SVM NRIP_SAVE feature, the VMCB holds the RIP of the next
+//! instruction after an instruction intercept (VMMCALL, HLT, ...), so the
+//! hypervisor need not know the instruction's length. With DecodeAssists,
+//! it also holds up to 15 bytes of the instruction that caused a nested page
+//! fault or #PF. On CPUs without them, [`Vmcb::skip_insn`] falls back to the
+//! length of the intercepted instruction, known or decoded, and [`fetch`]
+//! reads the bytes from guest memory through the guest's own page tables.
+//!
+//! [`Vmcb::skip_insn`]: super::vmcb::Vmcb::skip_insn
+
+#![allow(dead_code)]
+
+use super::svm::{SvmFeatures, SvmGuestGprs};
+use super::vmcb::*;
+use crate::gmem::GuestMemory;
+
+pub use super::decode::{decode_mov_cr, decode_mov_mem, invlpg_len};
+
+/// Longest x86 instruction, in bytes.
+pub const MAX_INSN_LEN: usize = 15;
+
+/// Length of VMMCALL (`0f 01 d9`).
+pub const VMMCALL_LEN: u64 = 3;
+/// Length of HLT (`f4`).
+pub const HLT_LEN: u64 = 1;
+
+/// Bytes of a guest instruction; fewer than [`MAX_INSN_LEN`] may be valid.
+#[derive(Clone, Copy, Debug)]
+pub struct InsnBytes {
+    pub bytes: [u8; MAX_INSN_LEN],
+    pub len: usize,
+}
+
+impl InsnBytes {
+    /// Returns the valid bytes.
+    pub fn as_slice(&self) -> &[u8] {
+        &self.bytes[..self.len]
+    }
+}
+
+/// Returns the bytes of the instruction at the guest RIP: after a nested
+/// page fault or #PF, the ones the CPU fetched if it has DecodeAssists,
+/// otherwise
+/// the ones read from guest memory through the guest page tables at `cr3`
+/// (the VMCB holds the shadow root instead under shadow paging). Returns
+/// `None` if the RIP is not mapped by the guest or the stage-2 table.
+pub fn fetch(
+    vmcb: &Vmcb,
+    features: &SvmFeatures,
+    mem: &impl GuestMemory,
+    cr3: u64,
+) -> Option<InsnBytes> {
+    let mut insn = InsnBytes {
+        bytes: [0; MAX_INSN_LEN],
+        len: 0,
+    };
+    // The CPU only saves the bytes on nested page faults and #PF
+    // intercepts; otherwise they are stale.
+    if features.decode_assists && matches!(vmcb.exit_code(), VMEXIT_NPF | VMEXIT_EXCP_PF) {
+        let len = (vmcb.data[CTRL_INSN_LEN] as usize).min(MAX_INSN_LEN);
+        if len != 0 {
+            insn.bytes[..len].copy_from_slice(&vmcb.data[CTRL_INSN_BYTES..CTRL_INSN_BYTES + len]);
+            insn.len = len;
+            return Some(insn);
+        }
+    }
+    // Byte by page: the instruction may cross into a page mapped elsewhere.
+    let (cr0, cr4) = (vmcb.read_u64(SAVE_CR0), vmcb.read_u64(SAVE_CR4));
+    let rip = vmcb.guest_rip();
+    while insn.len < MAX_INSN_LEN {
+        let gva = rip + insn.len as u64;
+        let Some(gpa) = guest_translate(mem, cr0, cr4, cr3, gva) else {
+            break;
+        };
+        let chunk = (MAX_INSN_LEN - insn.len).min(0x1000 - (gpa & 0xFFF));
+        if mem
+            .copy_from_guest(gpa, &mut insn.bytes[insn.len..insn.len + chunk])
+            .is_err()
+        {
+            break;
+        }
+        insn.len += chunk;
+    }
+    (insn.len != 0).then_some(insn)
+}
+
+/// Reads general-purpose register `n`, in ModRM numbering (0 RAX, 1 RCX,
+/// 2 RDX, 3 RBX, 4 RSP, 5 RBP, 6 RSI, 7 RDI, 8-15 R8-R15).
+pub fn read_gpr(vmcb: &Vmcb, gprs: &SvmGuestGprs, n: usize) -> u64 {
+    match n {
+        0 => vmcb.guest_rax(),
+        1 => gprs.rcx,
+        2 => gprs.rdx,
+        3 => gprs.rbx,
+        4 => vmcb.read_u64(SAVE_RSP),
+        5 => gprs.rbp,
+        6 => gprs.rsi,
+        7 => gprs.rdi,
+        8 => gprs.r8,
+        9 => gprs.r9,
+        10 => gprs.r10,
+        11 => gprs.r11,
+        12 => gprs.r12,
+        13 => gprs.r13,
+        14 => gprs.r14,
+        _ => gprs.r15,
+    }
+}
+
+/// Writes general-purpose register `n`, numbered as in [`read_gpr`].
+pub fn write_gpr(vmcb: &mut Vmcb, gprs: &mut SvmGuestGprs, n: usize, value: u64) {
+    let reg = match n {
+        0 => return vmcb.write_u64(SAVE_RAX, value),
+        4 => return vmcb.write_u64(SAVE_RSP, value),
+        1 => &mut gprs.rcx,
+        2 => &mut gprs.rdx,
+        3 => &mut gprs.rbx,
+        5 => &mut gprs.rbp,
+        6 => &mut gprs.rsi,
+        7 => &mut gprs.rdi,
+        8 => &mut gprs.r8,
+        9 => &mut gprs.r9,
+        10 => &mut gprs.r10,
+        11 => &mut gprs.r11,
+        12 => &mut gprs.r12,
+        13 => &mut gprs.r13,
+        14 => &mut gprs.r14,
+        _ => &mut gprs.r15,
+    };
+    *reg = value;
+}
+
+/// Translates the guest virtual address `gva` of an x86_64 guest with its
+/// own page tables: identity without paging (`CR0.PG` clear), otherwise a
+/// 4-level walk from `cr3`. Returns `None` for other paging modes and for
+/// unmapped addresses.
+pub fn guest_translate(
+    mem: &impl GuestMemory,
+    cr0: u64,
+    cr4: u64,
+    cr3: u64,
+    gva: u64,
+) -> Option<usize> {
+    const CR0_PG: u64 = 1 << 31;
+    const CR4_PAE: u64 = 1 << 5;
+    const CR4_LA57: u64 = 1 << 12;
+    const PTE_P: u64 = 1 << 0;
+    const PTE_PS: u64 = 1 << 7;
+    const PTE_ADDR: u64 = 0x000F_FFFF_FFFF_F000;
+
+    if cr0 & CR0_PG == 0 {
+        return Some(gva as usize);
+    }
+    if cr4 & CR4_PAE == 0 || cr4 & CR4_LA57 != 0 {
+        return None;
+    }
+    let mut table = cr3 & PTE_ADDR;
+    for level in (0..4).rev() {
+        let shift = 12 + 9 * level;
+        let index = (gva >> shift) & 0x1FF;
+        let pte: u64 = mem.read_obj((table + index * 8) as usize).ok()?;
+        if pte & PTE_P == 0 {
+            return None;
+        }
+        // 1GB and 2MB pages end the walk at levels 2 and 1.
+        if level == 0 || (level <= 2 && pte & PTE_PS != 0) {
+            let offset = gva & ((1 << shift) - 1);
+            return Some(((pte & PTE_ADDR & !((1 << shift) - 1)) | offset) as usize);
+        }
+        table = pte & PTE_ADDR;
+    }
+    None
+}
@@ -0,0 +1,402 @@
+//! A minimal virtual local APIC (xAPIC) for x86_64 guests.
+//!
+//! The register page at [`LAPIC_BASE`] is left out of the NPT, so every
+//! guest access to it is a nested page fault; the run loop decodes the MOV
+//! and calls [`VLapic::read`] or [`VLapic::write`]. Emulated:
+//!
+//! - ID, version, TPR/PPR, the spurious vector register (software enable)
+//!   and the ISR/TMR/IRR bitmaps;
+//! - EOI, which retires the highest in-service vector;
+//! - the ICR, for fixed IPIs to the vCPU itself (self shorthand, all
+//!   including self, or its own APIC ID); other IPIs are dropped as there
+//!   is a single vCPU;
+//! - the LVT timer in one-shot and periodic mode, counting at
+//!   [`BUS_FREQ_HZ`] through the divide configuration register;
+//! - the other LVT entries, stored but never fired.
+//!
+//! The highest deliverable vector is offered to the guest as the VMCB
+//! virtual interrupt ([`VLapic::land`]); the CPU clears V_IRQ when the guest
+//! takes it, which moves the vector from the IRR to the ISR
+//! ([`VLapic::sync`]).
+//!
+//! AVIC, which lets the CPU do this without exits, is detected
+//! ([`SvmFeatures::avic`](super::svm::SvmFeatures::avic)) but not used.
+
+#![allow(dead_code)]
+
+use super::insn::{decode_mov_mem, fetch, read_gpr, write_gpr};
+use super::svm::{SvmFeatures, SvmGuestGprs};
+use super::vmcb::*;
+use crate::error::VmError;
+use crate::gmem::GuestMemory;
+
+/// Guest physical address of the register page.
+pub const LAPIC_BASE: usize = 0xFEE0_0000;
+/// Size of the register page.
+pub const LAPIC_SIZE: usize = 0x1000;
+
+/// Frequency the timer counts at before division: one tick per nanosecond.
+pub const BUS_FREQ_HZ: u64 = 1_000_000_000;
+
+const REG_ID: usize = 0x020;
+const REG_VERSION: usize = 0x030;
+const REG_TPR: usize = 0x080;
+const REG_PPR: usize = 0x0A0;
+const REG_EOI: usize = 0x0B0;
+const REG_LDR: usize = 0x0D0;
+const REG_DFR: usize = 0x0E0;
+const REG_SVR: usize = 0x0F0;
+const REG_ISR: usize = 0x100;
+const REG_TMR: usize = 0x180;
+const REG_IRR: usize = 0x200;
+const REG_ESR: usize = 0x280;
+const REG_ICR_LOW: usize = 0x300;
+const REG_ICR_HIGH: usize = 0x310;
+const REG_LVT_TIMER: usize = 0x320;
+const REG_LVT_LINT1: usize = 0x360;
+const REG_LVT_ERROR: usize = 0x370;
+const REG_TIMER_ICR: usize = 0x380;
+const REG_TIMER_CCR: usize = 0x390;
+const REG_TIMER_DCR: usize = 0x3E0;
+
+/// Version 0x14 (integrated APIC), 6 LVT entries.
+const VERSION: u32 = 0x0005_0014;
+const SVR_ENABLE: u32 = 1 << 8;
+const LVT_MASKED: u32 = 1 << 16;
+const LVT_TIMER_MODE_SHIFT: u32 = 17;
+const TIMER_MODE_PERIODIC: u32 = 1;
+const ICR_DELIVERY_MODE_SHIFT: u32 = 8;
+const ICR_SHORTHAND_SHIFT: u32 = 18;
+const SHORTHAND_SELF: u32 = 1;
+const SHORTHAND_ALL: u32 = 2;
+
+/// A 256-bit vector bitmap.
+#[derive(Clone, Copy, Default)]
+struct VectorSet([u64; 4]);
+
+impl VectorSet {
+    fn set(&mut self, vector: u8) {
+        self.0[vector as usize / 64] |= 1 << (vector % 64);
+    }
+
+    fn clear(&mut self, vector: u8) {
+        self.0[vector as usize / 64] &= !(1 << (vector % 64));
+    }
+
+    fn contains(&self, vector: u8) -> bool {
+        self.0[vector as usize / 64] & (1 << (vector % 64)) != 0
+    }
+
+    fn highest(&self) -> Option<u8> {
+        (0..4)
+            .rev()
+            .find(|&i| self.0[i] != 0)
+            .map(|i| (i * 64 + 63 - self.0[i].leading_zeros() as usize) as u8)
+    }
+
+    /// Returns the 32-bit register `n` (0-7) of the bitmap.
+    fn reg(&self, n: usize) -> u32 {
+        (self.0[n / 2] >> (n % 2 * 32)) as u32
+    }
+}
+
+/// The local APIC of one vCPU.
+pub struct VLapic {
+    id: u32,
+    tpr: u32,
+    ldr: u32,
+    dfr: u32,
+    svr: u32,
+    esr: u32,
+    icr: u64,
+    /// LVT timer, thermal, performance, LINT0, LINT1 and error.
+    lvt: [u32; 6],
+    isr: VectorSet,
+    tmr: VectorSet,
+    irr: VectorSet,
+    timer: Timer,
+    /// The vector offered to the guest at the last VMRUN.
+    offered: Option<u8>,
+}
+
+/// The APIC timer.
+#[derive(Default)]
+struct Timer {
+    initial: u32,
+    dcr: u32,
+    /// Host time the count was last loaded with `initial`, in nanoseconds.
+    start_ns: u64,
+    /// Host time of the next expiry, if the timer runs.
+    deadline_ns: Option<u64>,
+}
+
+impl Timer {
+    /// Returns the nanoseconds per tick of the divided clock.
+    fn tick_ns(&self) -> u64 {
+        let shift = (self.dcr & 3) | (self.dcr >> 1) & 4;
+        let divisor = if shift == 7 { 1 } else { 2 << shift };
+        divisor * 1_000_000_000 / BUS_FREQ_HZ
+    }
+
+    fn period_ns(&self) -> u64 {
+        self.initial as u64 * self.tick_ns()
+    }
+
+    fn load(&mut self, initial: u32, now: u64) {
+        self.initial = initial;
+        self.start_ns = now;
+        self.deadline_ns = (initial != 0).then(|| now + self.period_ns());
+    }
+
+    fn current(&self, now: u64) -> u32 {
+        let Some(deadline) = self.deadline_ns else {
+            return 0;
+        };
+        (deadline.saturating_sub(now) / self.tick_ns()) as u32
+    }
+}
+
+impl VLapic {
+    /// Creates the local APIC with APIC ID `id`, in its reset state: software
+    /// disabled and all LVT entries masked.
+    pub fn new(id: u32) -> Self {
+        Self {
+            id,
+            tpr: 0,
+            ldr: 0,
+            dfr: 0xFFFF_FFFF,
+            svr: 0xFF,
+            esr: 0,
+            icr: 0,
+            lvt: [LVT_MASKED; 6],
+            isr: VectorSet::default(),
+            tmr: VectorSet::default(),
+            irr: VectorSet::default(),
+            timer: Timer::default(),
+            offered: None,
+        }
+    }
+
+    /// Checks whether `gpa` is in the register page.
+    pub fn contains(&self, gpa: usize) -> bool {
+        (LAPIC_BASE..LAPIC_BASE + LAPIC_SIZE).contains(&gpa)
+    }
+
+    /// Checks whether the guest enabled the APIC (spurious vector register).
+    pub fn is_enabled(&self) -> bool {
+        self.svr & SVR_ENABLE != 0
+    }
+
+    /// Emulates a 32-bit read of the register at `offset`; `now` is the
+    /// host time in nanoseconds.
+    pub fn read(&self, offset: usize, now: u64) -> u32 {
+        match offset & !0xF {
+            REG_ID => self.id << 24,
+            REG_VERSION => VERSION,
+            REG_TPR => self.tpr,
+            REG_PPR => self.ppr(),
+            REG_LDR => self.ldr,
+            REG_DFR => self.dfr,
+            REG_SVR => self.svr,
+            r @ REG_ISR..REG_TMR => self.isr.reg((r - REG_ISR) / 0x10),
+            r @ REG_TMR..REG_IRR => self.tmr.reg((r - REG_TMR) / 0x10),
+            r @ REG_IRR..REG_ESR => self.irr.reg((r - REG_IRR) / 0x10),
+            REG_ESR => self.esr,
+            REG_ICR_LOW => self.icr as u32,
+            REG_ICR_HIGH => (self.icr >> 32) as u32,
+            r @ REG_LVT_TIMER..=REG_LVT_ERROR => self.lvt[(r - REG_LVT_TIMER) / 0x10],
+            REG_TIMER_ICR => self.timer.initial,
+            REG_TIMER_CCR => self.timer.current(now),
+            REG_TIMER_DCR => self.timer.dcr,
+            _ => 0,
+        }
+    }
+
+    /// Emulates a 32-bit write of `value` to the register at `offset`; `now`
+    /// is the host time in nanoseconds.
+    pub fn write(&mut self, offset: usize, value: u32, now: u64) {
+        match offset & !0xF {
+            REG_ID => self.id = value >> 24,
+            REG_TPR => self.tpr = value & 0xFF,
+            REG_EOI => self.eoi(),
+            REG_LDR => self.ldr = value & 0xFF00_0000,
+            REG_DFR => self.dfr = value | 0x0FFF_FFFF,
+            REG_SVR => {
+                self.svr = value & 0x13FF;
+                if !self.is_enabled() {
+                    // A software disabled APIC masks all LVT entries.
+                    self.lvt.iter_mut().for_each(|lvt| *lvt |= LVT_MASKED);
+                }
+            }
+            // Writing the ESR latches the errors; none are reported.
+            REG_ESR => self.esr = 0,
+            REG_ICR_LOW => {
+                self.icr = self.icr & !0xFFFF_FFFF | (value & !(1 << 12)) as u64;
+                self.send_ipi();
+            }
+            REG_ICR_HIGH => {
+                self.icr = self.icr & 0xFFFF_FFFF | ((value & 0xFF00_0000) as u64) << 32
+            }
+            r @ REG_LVT_TIMER..=REG_LVT_ERROR => {
+                let mut value = value & 0x0007_A7FF;
+                if !self.is_enabled() {
+                    value |= LVT_MASKED;
+                }
+                self.lvt[(r - REG_LVT_TIMER) / 0x10] = value;
+            }
+            REG_TIMER_ICR => self.timer.load(value, now),
+            REG_TIMER_DCR => self.timer.dcr = value & 0xB,
+            _ => {}
+        }
+    }
+
+    /// Emulates the guest MOV that caused the nested page fault at `gpa` in
+    /// the register page and steps over it. `cr3` is the guest's CR3, for
+    /// fetching the instruction.
+    pub fn emulate(
+        &mut self,
+        gpa: usize,
+        vmcb: &mut Vmcb,
+        gprs: &mut SvmGuestGprs,
+        features: &SvmFeatures,
+        mem: &impl GuestMemory,
+        cr3: u64,
+    ) -> Result<(), VmError> {
+        let pc = vmcb.guest_rip() as usize;
+        let mov = fetch(vmcb, features, mem, cr3)
+            .and_then(|insn| decode_mov_mem(insn.as_slice()))
+            .ok_or(VmError::InsnFetch { pc })?;
+        // The registers are 32 bits wide and 16-byte aligned.
+        if mov.width != 4 || mov.stos.is_some() || !gpa.is_multiple_of(4) {
+            return Err(VmError::UnsupportedAccess { addr: gpa, pc });
+        }
+        let offset = gpa - LAPIC_BASE;
+        let now = axhal::time::monotonic_time_nanos();
+        if mov.is_write {
+            let value = mov.imm.unwrap_or_else(|| read_gpr(vmcb, gprs, mov.reg));
+            self.write(offset, value as u32, now);
+        } else {
+            let value = self.read(offset, now) as u64;
+            let old = read_gpr(vmcb, gprs, mov.reg);
+            write_gpr(vmcb, gprs, mov.reg, mov.load_value(old, value));
+        }
+        vmcb.skip_insn(features, mov.len);
+        Ok(())
+    }
+
+    /// Fires the timer if it expired by `now`. Called before every guest
+    /// entry.
+    pub fn poll(&mut self, now: u64) {
+        let Some(deadline) = self.timer.deadline_ns else {
+            return;
+        };
+        if now < deadline {
+            return;
+        }
+        let lvt = self.lvt[0];
+        if lvt >> LVT_TIMER_MODE_SHIFT & 3 == TIMER_MODE_PERIODIC {
+            // Expiries missed while the VM did not run are not made up.
+            let period = self.timer.period_ns();
+            self.timer.deadline_ns = Some(now + period - (now - deadline) % period);
+            self.timer.start_ns = now;
+        } else {
+            self.timer.deadline_ns = None;
+        }
+        if lvt & LVT_MASKED == 0 {
+            self.accept(lvt as u8);
+        }
+    }
+
+    /// Returns the host time of the next timer expiry, if the timer runs.
+    pub fn next_deadline(&self) -> Option<u64> {
+        self.timer.deadline_ns
+    }
+
+    /// Asserts (`level` set) a device interrupt at `vector`: it is accepted
+    /// again after its EOI for as long as the device keeps it asserted.
+    pub fn set_level(&mut self, vector: u8, level: bool) {
+        if level && !self.isr.contains(vector) {
+            self.accept(vector);
+        }
+    }
+
+    /// Returns the highest vector the guest can take now, by priority
+    /// against the ISR and TPR.
+    pub fn deliverable(&self) -> Option<u8> {
+        let vector = self.irr.highest()?;
+        (vector as u32 & 0xF0 > self.ppr() & 0xF0).then_some(vector)
+    }
+
+    /// Raises an edge-triggered interrupt at `vector`.
+    pub fn raise(&mut self, vector: u8) {
+        self.accept(vector);
+    }
+
+    /// Offers the highest deliverable vector to the guest as the virtual
+    /// interrupt, in place of the one the other events offered. Called right
+    /// before VMRUN, after the other events landed; returns whether a vector
+    /// was offered.
+    pub fn land(&mut self, vmcb: &mut Vmcb) -> bool {
+        self.offered = self.deliverable();
+        if let Some(vector) = self.offered {
+            vmcb.write_u32(
+                CTRL_V_INTR,
+                V_INTR_MASKING | V_IRQ | V_IGN_TPR | 0xF << V_INTR_PRIO_SHIFT,
+            );
+            vmcb.write_u32(CTRL_V_INTR_VECTOR, vector as u32);
+        }
+        self.offered.is_some()
+    }
+
+    /// Moves the offered vector to the ISR if the guest took it. Called
+    /// right after VMRUN.
+    pub fn sync(&mut self, vmcb: &Vmcb) {
+        if let Some(vector) = self.offered.take()
+            && vmcb.virq_taken()
+        {
+            self.irr.clear(vector);
+            self.isr.set(vector);
+        }
+    }
+
+    fn accept(&mut self, vector: u8) {
+        // Vectors 0-15 are reserved and dropped.
+        if self.is_enabled() && vector >= 16 {
+            self.irr.set(vector);
+        }
+    }
+
+    fn eoi(&mut self) {
+        if let Some(vector) = self.isr.highest() {
+            self.isr.clear(vector);
+        }
+    }
+
+    fn ppr(&self) -> u32 {
+        let isrv = self.isr.highest().map_or(0, |v| v as u32);
+        if self.tpr & 0xF0 >= isrv & 0xF0 {
+            self.tpr
+        } else {
+            isrv & 0xF0
+        }
+    }
+
+    fn send_ipi(&mut self) {
+        let low = self.icr as u32;
+        // Fixed delivery only.
+        if (low >> ICR_DELIVERY_MODE_SHIFT) & 7 != 0 {
+            return;
+        }
+        let to_self = match (low >> ICR_SHORTHAND_SHIFT) & 3 {
+            SHORTHAND_SELF | SHORTHAND_ALL => true,
+            0 => {
+                let dest = (self.icr >> 56) as u32;
+                dest == 0xFF || dest == self.id
+            }
+            _ => false,
+        };
+        if to_self {
+            self.accept(low as u8);
+        }
+    }
+}
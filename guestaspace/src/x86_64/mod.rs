@@ -0,0 +1,12 @@
+pub mod bios;
+pub mod decode;
+pub mod fpu;
+pub mod insn;
+pub mod lapic;
+pub mod mmio;
+pub mod pit;
+pub mod shadow;
+pub mod svm;
+pub mod svmcpu;
+pub mod tsc;
+pub mod vmcb;
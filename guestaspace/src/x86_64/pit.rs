@@ -0,0 +1,263 @@
+//! An emulated 8254 programmable interval timer (PIT) for x86_64 guests.
+//!
+//! The PIT ports 0x40-0x43 and port 0x61 (system control port B, which
+//! gates channel 2 and shows its output) are intercepted through the IOPM.
+//! The counters run at [`PIT_FREQ_HZ`] on host monotonic time:
+//!
+//! - channel 0 drives IRQ0: [`VPit::poll`] reports its expiries, once in
+//!   mode 0 (interrupt on terminal count) and every period in modes 2 and 3
+//!   (rate generator, square wave);
+//! - channel 2 counts while gated on by port 0x61 bit 0, and its output is
+//!   readable in port 0x61 bit 5, which is what guests calibrate their
+//!   TSC against;
+//! - channel 1 (DRAM refresh) counts but drives nothing.
+//!
+//! Modes 1, 4 and 5 count like mode 0. The read-back command and BCD
+//! counting are not emulated.
+
+#![allow(dead_code)]
+
+use crate::devices::mmio::MmioAccess;
+
+/// First port of the PIT.
+pub const PIT_PORT: usize = 0x40;
+/// System control port B.
+pub const PORT_B: usize = 0x61;
+/// Input clock of the counters.
+pub const PIT_FREQ_HZ: u64 = 1_193_182;
+
+const PORT_COMMAND: usize = PIT_PORT + 3;
+
+const ACCESS_LATCH: u8 = 0;
+const ACCESS_LSB: u8 = 1;
+const ACCESS_MSB: u8 = 2;
+const ACCESS_WORD: u8 = 3;
+
+const PORT_B_GATE2: u8 = 1 << 0;
+const PORT_B_SPEAKER: u8 = 1 << 1;
+const PORT_B_REFRESH: u8 = 1 << 4;
+const PORT_B_OUT2: u8 = 1 << 5;
+
+/// One counter.
+#[derive(Clone, Copy, Default)]
+struct Channel {
+    /// Counting mode (0-5).
+    mode: u8,
+    /// Access mode of the data port: latch, LSB, MSB or LSB then MSB.
+    access: u8,
+    /// Initial count; 0 counts 65536.
+    reload: u16,
+    /// A count was written and the counter runs.
+    loaded: bool,
+    /// Host time the count started, in nanoseconds.
+    start_ns: u64,
+    /// The LSB of a word write, waiting for the MSB.
+    write_lsb: Option<u8>,
+    /// The next word read returns the MSB.
+    read_msb: bool,
+    /// The latched count, read instead of the live one.
+    latch: Option<u16>,
+    /// Next expiry of channel 0, in host nanoseconds.
+    deadline_ns: Option<u64>,
+}
+
+impl Channel {
+    fn period_ticks(&self) -> u64 {
+        if self.reload == 0 {
+            0x1_0000
+        } else {
+            self.reload as u64
+        }
+    }
+
+    fn period_ns(&self) -> u64 {
+        self.period_ticks() * 1_000_000_000 / PIT_FREQ_HZ
+    }
+
+    fn periodic(&self) -> bool {
+        matches!(self.mode, 2 | 3 | 6 | 7)
+    }
+
+    /// Ticks counted since the count was loaded.
+    fn ticks(&self, now: u64) -> u64 {
+        now.saturating_sub(self.start_ns) * PIT_FREQ_HZ / 1_000_000_000
+    }
+
+    fn count(&self, now: u64) -> u16 {
+        if !self.loaded {
+            return self.reload;
+        }
+        let period = self.period_ticks();
+        let ticks = self.ticks(now);
+        let elapsed = if self.periodic() {
+            ticks % period
+        } else {
+            ticks
+        };
+        (period.wrapping_sub(elapsed) & 0xFFFF) as u16
+    }
+
+    fn output(&self, now: u64) -> bool {
+        if !self.loaded {
+            // Mode 0 starts low, the others high.
+            return self.mode != 0;
+        }
+        let period = self.period_ticks();
+        let ticks = self.ticks(now);
+        match self.mode {
+            2 | 6 => true,
+            // High for the first half of every period.
+            3 | 7 => ticks % period < period.div_ceil(2),
+            _ => ticks >= period,
+        }
+    }
+
+    fn load(&mut self, reload: u16, now: u64) {
+        self.reload = reload;
+        self.loaded = true;
+        self.start_ns = now;
+        self.deadline_ns = Some(now + self.period_ns());
+    }
+
+    fn write(&mut self, value: u8, now: u64) {
+        match self.access {
+            ACCESS_LSB => self.load(value as u16, now),
+            ACCESS_MSB => self.load((value as u16) << 8, now),
+            _ => match self.write_lsb.take() {
+                Some(lsb) => self.load((value as u16) << 8 | lsb as u16, now),
+                None => self.write_lsb = Some(value),
+            },
+        }
+    }
+
+    fn read(&mut self, now: u64) -> u8 {
+        let count = self.latch.unwrap_or_else(|| self.count(now));
+        match self.access {
+            ACCESS_LSB => {
+                self.latch = None;
+                count as u8
+            }
+            ACCESS_MSB => {
+                self.latch = None;
+                (count >> 8) as u8
+            }
+            _ if self.read_msb => {
+                self.read_msb = false;
+                self.latch = None;
+                (count >> 8) as u8
+            }
+            _ => {
+                self.read_msb = true;
+                count as u8
+            }
+        }
+    }
+}
+
+/// The PIT of one VM, with port B.
+#[derive(Default)]
+pub struct VPit {
+    channels: [Channel; 3],
+    /// Port B bits 0 (channel 2 gate) and 1 (speaker data).
+    port_b: u8,
+    /// Port B bit 4 toggles on every read, like the refresh clock.
+    refresh: bool,
+}
+
+impl VPit {
+    /// Creates the PIT with all counters stopped.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns the ports to intercept.
+    pub fn ports() -> impl Iterator<Item = usize> {
+        (PIT_PORT..PIT_PORT + 4).chain([PORT_B])
+    }
+
+    /// Checks whether `port` is a PIT port.
+    pub fn contains(&self, port: usize) -> bool {
+        (PIT_PORT..PIT_PORT + 4).contains(&port) || port == PORT_B
+    }
+
+    /// Emulates an IN or OUT at `now` (host nanoseconds). The ports are one
+    /// byte wide; wider accesses use the low byte. Returns the value of an
+    /// IN.
+    pub fn emulate(&mut self, access: &MmioAccess, rax: u64, now: u64) -> Option<u64> {
+        let value = rax as u8;
+        match (access.addr, access.is_write) {
+            (PORT_COMMAND, true) => self.command(value, now),
+            (PORT_COMMAND, false) => return Some(0xFF),
+            (PORT_B, true) => {
+                let gate = value & PORT_B_GATE2 != 0;
+                if gate && self.port_b & PORT_B_GATE2 == 0 && self.channels[2].loaded {
+                    // A rising gate restarts channel 2.
+                    self.channels[2].start_ns = now;
+                }
+                self.port_b = value & (PORT_B_GATE2 | PORT_B_SPEAKER);
+            }
+            (PORT_B, false) => {
+                self.refresh = !self.refresh;
+                let mut port_b = self.port_b;
+                if self.refresh {
+                    port_b |= PORT_B_REFRESH;
+                }
+                if self.port_b & PORT_B_GATE2 != 0 && self.channels[2].output(now) {
+                    port_b |= PORT_B_OUT2;
+                }
+                return Some(port_b as u64);
+            }
+            (port, true) => self.channels[port - PIT_PORT].write(value, now),
+            (port, false) => return Some(self.channels[port - PIT_PORT].read(now) as u64),
+        }
+        None
+    }
+
+    /// Reports whether channel 0 expired by `now`, which raises IRQ0.
+    /// Called before every guest entry.
+    pub fn poll(&mut self, now: u64) -> bool {
+        let ch = &mut self.channels[0];
+        let Some(deadline) = ch.deadline_ns else {
+            return false;
+        };
+        if now < deadline {
+            return false;
+        }
+        ch.deadline_ns = if ch.periodic() {
+            // Expiries missed while the VM did not run are not made up.
+            let period = ch.period_ns();
+            Some(now + period - (now - deadline) % period)
+        } else {
+            None
+        };
+        true
+    }
+
+    /// Returns the host time of the next channel 0 expiry, if it runs.
+    pub fn next_deadline(&self) -> Option<u64> {
+        self.channels[0].deadline_ns
+    }
+
+    fn command(&mut self, value: u8, now: u64) {
+        let channel = (value >> 6) as usize;
+        if channel == 3 {
+            // Read-back.
+            return;
+        }
+        let ch = &mut self.channels[channel];
+        let access = (value >> 4) & 3;
+        if access == ACCESS_LATCH {
+            if ch.latch.is_none() {
+                ch.latch = Some(ch.count(now));
+            }
+            return;
+        }
+        // A new mode stops the counter until a count is written.
+        *ch = Channel {
+            mode: (value >> 1) & 7,
+            access,
+            reload: ch.reload,
+            ..Channel::default()
+        };
+    }
+}
@@ -0,0 +1,394 @@
+//! Shadow paging, for CPUs without nested paging.
+//!
+//! Without NPT the CPU translates guest virtual addresses straight to host
+//! physical ones, through the page tables at the VMCB CR3. The guest gets a
+//! shadow of its own page tables there: each guest mapping composed with the
+//! stage-2 mapping of the [`GuestSpace`], which then only serves as the
+//! software GPA→HPA map.
+//!
+//! The shadow tables act as the guest's TLB. They start empty, and an
+//! intercepted guest #PF fills the 4K shadow entry of the faulting address
+//! from a walk of the guest tables, setting their accessed and dirty bits as
+//! the CPU would. A fault the guest tables do not allow is reflected into
+//! the guest. Like a TLB, the shadow tables are dropped whenever the guest
+//! flushes its TLB (MOV to CR3 or CR4 and INVLPG are intercepted) and
+//! whenever the hypervisor requests a guest TLB flush after changing the
+//! stage-2 map. Guest page table updates so become visible when x86 says
+//! they do.
+//!
+//! A shadow entry also carries the stage-2 permissions: it is read-only
+//! and no-execute where the stage-2 map is, and an access the stage-2 map
+//! denies is handled as a nested page fault. A clean guest page is shadowed
+//! read-only, so that its first write faults and sets the dirty bit. Guest MOV from CR3 is intercepted to return the
+//! guest's CR3 rather than the shadow root.
+//!
+//! Only 4-level long mode paging is shadowed: the guest must start with
+//! paging on and keep it on (CR0 writes are not intercepted).
+
+#![allow(dead_code)]
+
+use alloc::vec::Vec;
+
+use axerrno::{AxError, AxResult};
+use axhal::mem::phys_to_virt;
+use axhal::paging::{MappingFlags, PageSize};
+use memory_addr::{PAGE_SIZE_4K, PhysAddr};
+
+use super::insn::{decode_mov_cr, fetch, invlpg_len, read_gpr, write_gpr};
+use super::svm::{SvmFeatures, SvmGuestGprs};
+use super::vmcb::*;
+use crate::error::VmError;
+use crate::gmem::GuestMemory;
+use crate::gspace::{GuestSpace, alloc_frame, dealloc_frame};
+
+const PTE_P: u64 = 1 << 0;
+const PTE_RW: u64 = 1 << 1;
+const PTE_US: u64 = 1 << 2;
+const PTE_PWT: u64 = 1 << 3;
+const PTE_PCD: u64 = 1 << 4;
+const PTE_A: u64 = 1 << 5;
+const PTE_D: u64 = 1 << 6;
+const PTE_PS: u64 = 1 << 7;
+const PTE_NX: u64 = 1 << 63;
+const PTE_ADDR: u64 = 0x000F_FFFF_FFFF_F000;
+
+/// #PF error code bits.
+const PF_P: u64 = 1 << 0;
+const PF_W: u64 = 1 << 1;
+const PF_U: u64 = 1 << 2;
+const PF_I: u64 = 1 << 4;
+
+const CR0_WP: u64 = 1 << 16;
+const CR4_PAE: u64 = 1 << 5;
+const CR4_LA57: u64 = 1 << 12;
+/// Bit 63 of a value moved to CR3 (with CR4.PCIDE): keep the TLB.
+const CR3_NOFLUSH: u64 = 1 << 63;
+const EFER_NXE: u64 = 1 << 11;
+
+/// What became of an intercepted guest #PF.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ShadowFault {
+    /// The shadow entry was filled: the guest retries the access.
+    Filled,
+    /// The guest tables do not allow the access: the guest takes the #PF,
+    /// with this error code (CR2 is already set).
+    Reflect(u64),
+    /// The guest tables allow the access but the stage-2 map does not. The
+    /// VMCB exit now describes a nested page fault on the guest physical
+    /// address.
+    Stage2,
+}
+
+/// The shadow page tables of one vCPU.
+pub struct ShadowPaging {
+    /// Root of the shadow tables, loaded as the VMCB CR3.
+    root: PhysAddr,
+    /// The other shadow tables.
+    tables: Vec<PhysAddr>,
+    /// The guest's own CR3.
+    guest_cr3: u64,
+}
+
+/// The guest mapping of a faulting address.
+struct GuestPage {
+    /// Guest physical address of the 4K page.
+    gpa: usize,
+    /// Permission and cache bits of the shadow entry.
+    bits: u64,
+    /// Setting accessed or dirty bits moved a guest page table to another
+    /// frame (copy-on-write).
+    remapped: bool,
+}
+
+impl ShadowPaging {
+    /// Creates empty shadow tables for a guest with page tables at
+    /// `guest_cr3`.
+    pub fn new(guest_cr3: u64) -> AxResult<Self> {
+        Ok(Self {
+            root: alloc_frame(PageSize::Size4K).ok_or(AxError::NoMemory)?,
+            tables: Vec::new(),
+            guest_cr3,
+        })
+    }
+
+    /// Returns the host physical address of the shadow root, for the VMCB
+    /// CR3.
+    pub fn root_pa(&self) -> u64 {
+        self.root.as_usize() as u64
+    }
+
+    /// Returns the guest's own CR3.
+    pub fn guest_cr3(&self) -> u64 {
+        self.guest_cr3
+    }
+
+    /// Drops all shadow entries.
+    pub fn flush(&mut self) {
+        for table in self.tables.drain(..) {
+            dealloc_frame(table, PageSize::Size4K);
+        }
+        table_mut(self.root).fill(0);
+    }
+
+    /// Drops the shadow entries if a guest TLB flush is requested for the
+    /// next VMRUN. Called right before every VMRUN.
+    pub fn sync_tlb(&mut self, vmcb: &Vmcb) {
+        if vmcb.read_u32(CTRL_TLB_CONTROL) != 0 {
+            self.flush();
+        }
+    }
+
+    /// Handles an intercepted guest #PF (EXITINFO1 the error code,
+    /// EXITINFO2 the address). Fails only if no host memory is left for a
+    /// shadow table.
+    pub fn handle_fault(
+        &mut self,
+        space: &mut GuestSpace,
+        vmcb: &mut Vmcb,
+    ) -> AxResult<ShadowFault> {
+        let (error, gva) = (vmcb.exit_info1(), vmcb.exit_info2());
+        let wp = vmcb.read_u64(SAVE_CR0) & CR0_WP != 0;
+        let nxe = vmcb.read_u64(SAVE_EFER) & EFER_NXE != 0;
+        let page = match self.walk_guest(space, gva, error, wp, nxe) {
+            Ok(page) => page,
+            Err(error) => {
+                vmcb.write_u64(SAVE_CR2, gva);
+                return Ok(ShadowFault::Reflect(error));
+            }
+        };
+        if page.remapped {
+            // Other shadow entries may still point to the old frame.
+            self.flush();
+            vmcb.write_u32(CTRL_TLB_CONTROL, TLB_CONTROL_FLUSH_ASID);
+        }
+
+        let write = error & PF_W != 0;
+        let exec = error & PF_I != 0;
+        let needed = if write {
+            MappingFlags::WRITE
+        } else if exec {
+            MappingFlags::EXECUTE
+        } else {
+            MappingFlags::READ
+        };
+        let stage2 = space.query(page.gpa.into()).ok();
+        // Without EFER.NXE the shadow entry cannot forbid fetches, and
+        // fetches look like reads: every access to a page stage-2 does not
+        // let the guest execute is then denied as a fetch.
+        let no_exec =
+            !nxe && stage2.is_some_and(|(_, flags, _)| !flags.contains(MappingFlags::EXECUTE));
+        match stage2 {
+            Some((hpa, flags, _)) if flags.contains(needed) && !no_exec => {
+                let mut pte = hpa.as_usize() as u64 & PTE_ADDR | PTE_P | page.bits;
+                if !flags.contains(MappingFlags::WRITE) {
+                    pte &= !PTE_RW;
+                }
+                if !flags.contains(MappingFlags::EXECUTE) {
+                    pte |= PTE_NX;
+                }
+                if self.install(gva, pte).is_err() {
+                    // Free all shadow tables and try once more.
+                    self.flush();
+                    vmcb.write_u32(CTRL_TLB_CONTROL, TLB_CONTROL_FLUSH_ASID);
+                    self.install(gva, pte)?;
+                }
+                Ok(ShadowFault::Filled)
+            }
+            _ => {
+                let mut info1 = 0;
+                if stage2.is_some() {
+                    info1 |= NPF_INFO_PRESENT;
+                }
+                if exec || no_exec {
+                    info1 |= NPF_INFO_EXEC;
+                } else if write {
+                    info1 |= NPF_INFO_WRITE;
+                }
+                vmcb.write_u64(CTRL_EXIT_CODE, VMEXIT_NPF);
+                vmcb.write_u64(CTRL_EXIT_INFO1, info1);
+                vmcb.write_u64(CTRL_EXIT_INFO2, (page.gpa as u64) | (gva & 0xFFF));
+                Ok(ShadowFault::Stage2)
+            }
+        }
+    }
+
+    /// Emulates an intercepted MOV from CR3 or MOV to CR3 or CR4 and steps
+    /// over it. A MOV to CR4 may not turn off PAE or turn on 5-level
+    /// paging.
+    pub fn emulate_mov_cr(
+        &mut self,
+        vmcb: &mut Vmcb,
+        gprs: &mut SvmGuestGprs,
+        features: &SvmFeatures,
+        mem: &impl GuestMemory,
+    ) -> Result<(), VmError> {
+        let pc = vmcb.guest_rip() as usize;
+        let exit_code = vmcb.exit_code();
+        let (cr, to_cr) = ((exit_code & 0xF) as usize, exit_code & 0x10 != 0);
+        let info1 = vmcb.exit_info1();
+        let (gpr, len) =
+            if features.decode_assists && features.nrip_save && info1 & CR_INFO_MOV != 0 {
+                ((info1 & 0xF) as usize, 0)
+            } else {
+                let insn = fetch(vmcb, features, mem, self.guest_cr3)
+                    .and_then(|insn| decode_mov_cr(insn.as_slice()))
+                    .filter(|insn| insn.cr == cr && insn.to_cr == to_cr)
+                    .ok_or(VmError::InsnFetch { pc })?;
+                (insn.gpr, insn.len)
+            };
+
+        match (cr, to_cr) {
+            (3, false) => write_gpr(vmcb, gprs, gpr, self.guest_cr3),
+            (3, true) => {
+                self.guest_cr3 = read_gpr(vmcb, gprs, gpr) & !CR3_NOFLUSH;
+                vmcb.write_u32(CTRL_TLB_CONTROL, TLB_CONTROL_FLUSH_ASID);
+            }
+            (4, true) => {
+                let cr4 = read_gpr(vmcb, gprs, gpr);
+                if cr4 & CR4_PAE == 0 || cr4 & CR4_LA57 != 0 {
+                    return Err(VmError::UnsupportedPaging { pc });
+                }
+                vmcb.write_u64(SAVE_CR4, cr4);
+                vmcb.write_u32(CTRL_TLB_CONTROL, TLB_CONTROL_FLUSH_ASID);
+            }
+            _ => {
+                return Err(VmError::UnhandledExit {
+                    code: exit_code as usize,
+                    pc,
+                    info: [info1 as usize, vmcb.exit_info2() as usize],
+                });
+            }
+        }
+        vmcb.skip_insn(features, len);
+        Ok(())
+    }
+
+    /// Emulates an intercepted INVLPG: all shadow entries are dropped.
+    pub fn emulate_invlpg(
+        &mut self,
+        vmcb: &mut Vmcb,
+        features: &SvmFeatures,
+        mem: &impl GuestMemory,
+    ) -> Result<(), VmError> {
+        let len = if features.nrip_save {
+            0
+        } else {
+            fetch(vmcb, features, mem, self.guest_cr3)
+                .and_then(|insn| invlpg_len(insn.as_slice()))
+                .ok_or(VmError::InsnFetch {
+                    pc: vmcb.guest_rip() as usize,
+                })?
+        };
+        vmcb.write_u32(CTRL_TLB_CONTROL, TLB_CONTROL_FLUSH_ASID);
+        vmcb.skip_insn(features, len);
+        Ok(())
+    }
+
+    /// Walks the guest tables for an access to `gva` with #PF error code
+    /// `error`, setting the accessed and dirty bits. Returns the error code
+    /// of the guest #PF if the access is not allowed.
+    fn walk_guest(
+        &self,
+        space: &mut GuestSpace,
+        gva: u64,
+        error: u64,
+        wp: bool,
+        nxe: bool,
+    ) -> Result<GuestPage, u64> {
+        let access = error & (PF_W | PF_U | PF_I);
+        let (write, user, exec) = (access & PF_W != 0, access & PF_U != 0, access & PF_I != 0);
+
+        // (GPA, value) of the entries read, root first.
+        let mut entries = [(0usize, 0u64); 4];
+        let mut count = 0;
+        let (mut rw, mut us, mut nx) = (true, true, false);
+        let mut table = self.guest_cr3 & PTE_ADDR;
+        let mut level = 4;
+        let (shift, leaf) = loop {
+            level -= 1;
+            let shift = 12 + 9 * level;
+            let gpa = (table + ((gva >> shift) & 0x1FF) * 8) as usize;
+            let pte: u64 = space.read_obj(gpa).map_err(|_| access)?;
+            if pte & PTE_P == 0 {
+                return Err(access);
+            }
+            entries[count] = (gpa, pte);
+            count += 1;
+            rw &= pte & PTE_RW != 0;
+            us &= pte & PTE_US != 0;
+            nx |= nxe && pte & PTE_NX != 0;
+            // 1GB and 2MB pages end the walk at levels 2 and 1.
+            if level == 0 || (level <= 2 && pte & PTE_PS != 0) {
+                break (shift, pte);
+            }
+            table = pte & PTE_ADDR;
+        };
+        if (user && !us) || (write && !rw && (user || wp)) || (exec && nx) {
+            return Err(access | PF_P);
+        }
+
+        let mut remapped = false;
+        for (i, &(gpa, pte)) in entries[..count].iter().enumerate() {
+            let set = if write && i == count - 1 {
+                PTE_A | PTE_D
+            } else {
+                PTE_A
+            };
+            if pte & set != set {
+                let before = space.query(gpa.into()).map(|m| m.0).ok();
+                let _ = space.write_obj(gpa, &(pte | set));
+                remapped |= space.query(gpa.into()).map(|m| m.0).ok() != before;
+            }
+        }
+
+        // A supervisor write to a read-only page is allowed with CR0.WP
+        // clear: the shadow page is then writable by the supervisor only.
+        let supervisor_write = write && !rw;
+        let mut bits = leaf & (PTE_PWT | PTE_PCD);
+        if (rw && (write || leaf & PTE_D != 0)) || supervisor_write {
+            bits |= PTE_RW;
+        }
+        if us && !supervisor_write {
+            bits |= PTE_US;
+        }
+        if nx {
+            bits |= PTE_NX;
+        }
+        let frame = leaf & PTE_ADDR & !((1 << shift) - 1);
+        let gpa = (frame | (gva & ((1 << shift) - 1))) as usize & !(PAGE_SIZE_4K - 1);
+        Ok(GuestPage {
+            gpa,
+            bits,
+            remapped,
+        })
+    }
+
+    /// Sets the 4K shadow entry of `gva` to `pte`, allocating the shadow
+    /// tables on the way.
+    fn install(&mut self, gva: u64, pte: u64) -> AxResult {
+        let mut table = self.root;
+        for level in (1..4).rev() {
+            let entry = &mut table_mut(table)[((gva >> (12 + 9 * level)) & 0x1FF) as usize];
+            if *entry & PTE_P == 0 {
+                let next = alloc_frame(PageSize::Size4K).ok_or(AxError::NoMemory)?;
+                self.tables.push(next);
+                *entry = next.as_usize() as u64 | PTE_P | PTE_RW | PTE_US;
+            }
+            table = PhysAddr::from((*entry & PTE_ADDR) as usize);
+        }
+        table_mut(table)[((gva >> 12) & 0x1FF) as usize] = pte;
+        Ok(())
+    }
+}
+
+impl Drop for ShadowPaging {
+    fn drop(&mut self) {
+        self.flush();
+        dealloc_frame(self.root, PageSize::Size4K);
+    }
+}
+
+/// Returns the entries of the shadow table at `paddr`.
+fn table_mut(paddr: PhysAddr) -> &'static mut [u64; 512] {
+    unsafe { &mut *(phys_to_virt(paddr).as_mut_ptr() as *mut [u64; 512]) }
+}
@@ -14,6 +14,40 @@ pub const MSR_VM_HSAVE_PA: u32 = 0xC001_0117;
 
 pub const EFER_SVME: u64 = 1 << 12;
 
+// ── SVM features (CPUID Fn8000_000A EDX) ────────────────────────
+pub const CPUID_SVM_FEATURES: u32 = 0x8000_000A;
+pub const SVM_FEATURE_NP: u32 = 1 << 0;
+pub const SVM_FEATURE_NRIP_SAVE: u32 = 1 << 3;
+pub const SVM_FEATURE_DECODE_ASSISTS: u32 = 1 << 7;
+pub const SVM_FEATURE_AVIC: u32 = 1 << 13;
+
+/// The optional SVM features the hypervisor uses.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SvmFeatures {
+    /// Nested paging.
+    pub nested_paging: bool,
+    /// The VMCB holds the next RIP after an instruction intercept.
+    pub nrip_save: bool,
+    /// The VMCB holds the bytes of the instruction that caused a nested
+    /// page fault.
+    pub decode_assists: bool,
+    /// The CPU can virtualize the local APIC (AVIC).
+    pub avic: bool,
+}
+
+impl SvmFeatures {
+    /// Reads the features of the current CPU.
+    pub fn detect() -> Self {
+        let (_, _, _, edx) = unsafe { cpuid(CPUID_SVM_FEATURES) };
+        Self {
+            nested_paging: edx & SVM_FEATURE_NP != 0,
+            nrip_save: edx & SVM_FEATURE_NRIP_SAVE != 0,
+            decode_assists: edx & SVM_FEATURE_DECODE_ASSISTS != 0,
+            avic: edx & SVM_FEATURE_AVIC != 0,
+        }
+    }
+}
+
 // ── Guest GPR save area ─────────────────────────────────────────
 
 /// Guest general-purpose registers that are NOT saved/restored by
@@ -60,8 +94,19 @@ impl SvmGuestGprs {
     }
 }
 
+impl Default for SvmGuestGprs {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 // ── Low-level helpers ───────────────────────────────────────────
 
+/// Executes CPUID leaf `func`, returning EAX, EBX, ECX and EDX.
+///
+/// # Safety
+///
+/// The CPU must implement leaf `func`.
 #[inline]
 pub unsafe fn cpuid(func: u32) -> (u32, u32, u32, u32) {
     let eax: u32;
@@ -83,6 +128,11 @@ pub unsafe fn cpuid(func: u32) -> (u32, u32, u32, u32) {
     (eax, ebx, ecx, edx)
 }
 
+/// Reads MSR `msr`.
+///
+/// # Safety
+///
+/// Runs at CPL 0 only, and `msr` must exist (else #GP).
 #[inline]
 pub unsafe fn rdmsr(msr: u32) -> u64 {
     let lo: u32;
@@ -98,6 +148,12 @@ pub unsafe fn rdmsr(msr: u32) -> u64 {
     ((hi as u64) << 32) | (lo as u64)
 }
 
+/// Writes `val` to MSR `msr`.
+///
+/// # Safety
+///
+/// Runs at CPL 0 only, `msr` must exist and accept `val` (else #GP), and
+/// the write must not break the host's state (EFER, segment bases, ...).
 #[inline]
 pub unsafe fn wrmsr(msr: u32, val: u64) {
     let lo = val as u32;
@@ -112,6 +168,13 @@ pub unsafe fn wrmsr(msr: u32, val: u64) {
     }
 }
 
+/// Returns the host physical address of a kernel-virtual pointer.
+pub fn virt_to_phys_ptr(p: *const u8) -> u64 {
+    use axhal::mem::virt_to_phys;
+    let va = memory_addr::VirtAddr::from(p as usize);
+    usize::from(virt_to_phys(va)) as u64
+}
+
 // ── VMRUN wrapper ───────────────────────────────────────────────
 //
 // `_run_guest(guest_vmcb_pa: u64, host_vmcb_pa: u64, gprs: &mut SvmGuestGprs)`
@@ -150,8 +213,11 @@ global_asm!(
     "push rsi", // [RSP+16] = host_vmcb_pa
     "push rdx", // [RSP+ 8] = gprs_ptr
     "push rdi", // [RSP+ 0] = guest_vmcb_pa
-    // ── Disable interrupts ──
-    "cli",
+    // ── Block interrupts until VMRUN (GIF = 0), then let them exit the
+    //    guest: with host IF = 1, a physical interrupt during the guest's
+    //    run causes an INTR #VMEXIT ──
+    "clgi",
+    "sti",
     // ── VMSAVE host FS/GS/TR/LDTR ──
     "mov rax, rsi", // RAX = host_vmcb_pa
     "vmsave",
@@ -208,8 +274,9 @@ global_asm!(
     // ── VMLOAD host FS/GS/TR/LDTR ──
     "mov rax, [rsp + 16]", // RAX = host_vmcb_pa
     "vmload",
-    // ── Re-enable interrupts ──
-    "sti",
+    // ── Re-enable interrupts: a host interrupt that ended the guest's run
+    //    is taken here by the host's handler ──
+    "stgi",
     // ── Clean up stack (pop the 3 saved parameters) ──
     "add rsp, 24",
     // ── Restore callee-saved host GPRs ──
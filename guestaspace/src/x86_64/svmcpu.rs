@@ -0,0 +1,105 @@
+//! SVM state of the host CPUs.
+//!
+//! VMRUN needs `EFER.SVME` set on the CPU that executes it and
+//! `MSR_VM_HSAVE_PA` pointing at a host-save area of that CPU, where the
+//! processor keeps the host state while the guest runs. Each CPU also has
+//! its own host VMCB for the state VMSAVE/VMLOAD switch (FS/GS/TR/LDTR).
+//!
+//! A VM task enters the host CPU it is pinned to ([`SvmCpu::enter`]) before
+//! its first VMRUN. The first VM on a CPU allocates the CPU's host-save area
+//! and host VMCB, kept for the later VMs there, and enables SVM and the
+//! FXSAVE state switching; the last VM to leave the CPU disables SVM again.
+//! ArceOS has no hook in the startup of the secondary CPUs, so a CPU no VM
+//! runs on keeps SVM disabled.
+
+#![allow(dead_code)]
+
+use alloc::boxed::Box;
+use axstd::sync::Mutex;
+
+use super::svm::{EFER_SVME, MSR_EFER, MSR_VM_HSAVE_PA, rdmsr, virt_to_phys_ptr, wrmsr};
+use crate::hostcpu::MAX_HOST_CPUS;
+
+#[repr(C, align(4096))]
+struct Page4K([u8; 4096]);
+
+/// SVM state of one host CPU.
+#[derive(Clone, Copy)]
+struct CpuState {
+    /// Number of VMs that entered the CPU and have not left it.
+    vms: usize,
+    /// Physical address of the host-save area (0: not allocated yet).
+    host_save_pa: u64,
+    /// Physical address of the host VMCB.
+    host_vmcb_pa: u64,
+}
+
+impl CpuState {
+    const NEW: Self = Self {
+        vms: 0,
+        host_save_pa: 0,
+        host_vmcb_pa: 0,
+    };
+}
+
+static CPUS: Mutex<[CpuState; MAX_HOST_CPUS]> = Mutex::new([CpuState::NEW; MAX_HOST_CPUS]);
+
+/// Allocates a zeroed page that lives as long as the hypervisor and returns
+/// its physical address.
+fn alloc_page() -> u64 {
+    let page = Box::leak(Box::new(Page4K([0u8; 4096])));
+    virt_to_phys_ptr(&page.0[0])
+}
+
+/// A VM on the current host CPU, which has SVM enabled while it exists.
+/// Must be dropped on the CPU it was created on.
+pub struct SvmCpu {
+    cpu: usize,
+    host_vmcb_pa: u64,
+}
+
+impl SvmCpu {
+    /// Enters the current host CPU, enabling SVM on it for the first VM.
+    pub fn enter() -> Self {
+        let cpu = axhal::percpu::this_cpu_id();
+        let mut cpus = CPUS.lock();
+        let state = &mut cpus[cpu];
+        if state.host_save_pa == 0 {
+            state.host_save_pa = alloc_page();
+            state.host_vmcb_pa = alloc_page();
+        }
+        if state.vms == 0 {
+            unsafe {
+                wrmsr(MSR_EFER, rdmsr(MSR_EFER) | EFER_SVME);
+                wrmsr(MSR_VM_HSAVE_PA, state.host_save_pa);
+            }
+            // The guests' x87/SSE state is switched with FXSAVE/FXRSTOR.
+            super::fpu::enable();
+        }
+        state.vms += 1;
+        Self {
+            cpu,
+            host_vmcb_pa: state.host_vmcb_pa,
+        }
+    }
+
+    /// Returns the CPU's host VMCB, the second operand of `_run_guest`.
+    pub fn host_vmcb_pa(&self) -> u64 {
+        self.host_vmcb_pa
+    }
+}
+
+impl Drop for SvmCpu {
+    fn drop(&mut self) {
+        debug_assert_eq!(axhal::percpu::this_cpu_id(), self.cpu);
+        let mut cpus = CPUS.lock();
+        let state = &mut cpus[self.cpu];
+        state.vms -= 1;
+        if state.vms == 0 {
+            // GIF is set again after every VMRUN, so SVM can go.
+            unsafe {
+                wrmsr(MSR_EFER, rdmsr(MSR_EFER) & !EFER_SVME);
+            }
+        }
+    }
+}
@@ -0,0 +1,169 @@
+//! The TSC seen by x86_64 guests.
+//!
+//! [`GuestTsc`] makes the guest's TSC read zero when the VM boots, in one of
+//! two modes (`tsc=` in `vms.conf`, see [`TscMode`]):
+//!
+//! - offset: RDTSC runs natively and the CPU adds the VMCB TSC offset to the
+//!   host TSC. The guest TSC ticks at the host TSC frequency;
+//! - intercept: RDTSC and RDTSCP exit, and the hypervisor returns the guest
+//!   time scaled to a fixed frequency, independent of the host CPU.
+//!
+//! In both modes the TSC can be stopped while the guest does not run (a
+//! pause, a snapshot being taken or restored) so the guest does not see the
+//! gap. Guests learn the frequency through the GET_TSC_KHZ hypercall
+//! ([`HYPERCALL_GET_TSC_KHZ`]).
+
+#![allow(dead_code)]
+
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use super::vmcb::*;
+use crate::config::TscMode;
+
+/// Hypercall function ID (`RAX[7:0]`) that returns the guest TSC frequency
+/// in kHz in RAX.
+pub const HYPERCALL_GET_TSC_KHZ: u64 = hvcall_abi::GET_TSC_KHZ;
+
+/// Length of RDTSC (`0f 31`).
+pub const RDTSC_LEN: u64 = 2;
+/// Length of RDTSCP (`0f 01 f9`).
+pub const RDTSCP_LEN: u64 = 3;
+
+/// Host TSC frequency in kHz, measured by [`calibrate`].
+static HOST_KHZ: AtomicU64 = AtomicU64::new(0);
+
+/// Measures the host TSC frequency against the host monotonic clock over
+/// `ms` milliseconds. Called once at boot.
+pub fn calibrate(ms: u64) -> u64 {
+    let start_ns = axhal::time::monotonic_time_nanos();
+    let start = rdtsc();
+    let mut now_ns = start_ns;
+    while now_ns - start_ns < ms * 1_000_000 {
+        core::hint::spin_loop();
+        now_ns = axhal::time::monotonic_time_nanos();
+    }
+    let khz = (rdtsc() - start) as u128 * 1_000_000 / (now_ns - start_ns) as u128;
+    HOST_KHZ.store(khz as u64, Ordering::Relaxed);
+    khz as u64
+}
+
+/// Returns the host TSC frequency in kHz (0 before [`calibrate`]).
+pub fn host_khz() -> u64 {
+    HOST_KHZ.load(Ordering::Relaxed)
+}
+
+fn rdtsc() -> u64 {
+    unsafe { core::arch::x86_64::_rdtsc() }
+}
+
+/// The TSC of one guest.
+pub struct GuestTsc {
+    mode: TscMode,
+    /// Offset mode: guest TSC minus host TSC. Intercept mode: host
+    /// monotonic time at guest time zero, in nanoseconds.
+    base: u64,
+    /// Guest TSC at which the TSC was paused.
+    paused_at: Option<u64>,
+}
+
+impl GuestTsc {
+    /// Creates a running TSC that reads zero now.
+    pub fn new(mode: TscMode) -> Self {
+        let mut tsc = Self {
+            mode,
+            base: 0,
+            paused_at: None,
+        };
+        tsc.set(0);
+        tsc
+    }
+
+    /// Returns the guest TSC frequency in kHz.
+    pub fn khz(&self) -> u64 {
+        match self.mode {
+            TscMode::Offset => host_khz(),
+            TscMode::Intercept { khz } => khz,
+        }
+    }
+
+    /// Returns the current guest TSC.
+    pub fn now(&self) -> u64 {
+        if let Some(t) = self.paused_at {
+            return t;
+        }
+        match self.mode {
+            TscMode::Offset => rdtsc().wrapping_add(self.base),
+            TscMode::Intercept { khz } => {
+                let ns = axhal::time::monotonic_time_nanos().saturating_sub(self.base);
+                (ns as u128 * khz as u128 / 1_000_000) as u64
+            }
+        }
+    }
+
+    /// Sets the guest TSC to `t`, e.g. the value saved in a snapshot.
+    pub fn set(&mut self, t: u64) {
+        if self.paused_at.is_some() {
+            self.paused_at = Some(t);
+            return;
+        }
+        match self.mode {
+            TscMode::Offset => self.base = t.wrapping_sub(rdtsc()),
+            TscMode::Intercept { khz } => {
+                let ns = (t as u128 * 1_000_000 / khz as u128) as u64;
+                // Guest time never runs ahead of host time.
+                self.base = axhal::time::monotonic_time_nanos().saturating_sub(ns);
+            }
+        }
+    }
+
+    /// Stops the guest TSC at its current value.
+    pub fn pause(&mut self) {
+        if self.paused_at.is_none() {
+            self.paused_at = Some(self.now());
+        }
+    }
+
+    /// Lets the guest TSC run again from where it was paused.
+    pub fn resume(&mut self) {
+        if let Some(t) = self.paused_at.take() {
+            self.set(t);
+        }
+    }
+
+    /// Sets up the VMCB: the RDTSC/RDTSCP intercepts in intercept mode.
+    pub fn init(&self, vmcb: &mut Vmcb) {
+        if let TscMode::Intercept { .. } = self.mode {
+            let misc1 = vmcb.read_u32(CTRL_INTERCEPT_MISC1);
+            vmcb.write_u32(CTRL_INTERCEPT_MISC1, misc1 | INTERCEPT_RDTSC);
+            let misc2 = vmcb.read_u32(CTRL_INTERCEPT_MISC2);
+            vmcb.write_u32(CTRL_INTERCEPT_MISC2, misc2 | INTERCEPT_RDTSCP);
+        }
+    }
+
+    /// Writes the TSC offset for the next VMRUN. Called right before every
+    /// VMRUN.
+    pub fn land(&self, vmcb: &mut Vmcb) {
+        if self.mode == TscMode::Offset {
+            vmcb.write_u64(CTRL_TSC_OFFSET, self.base);
+        }
+    }
+
+    /// Emulates an intercepted RDTSC or RDTSCP (with `TSC_AUX` zero) and
+    /// steps over it.
+    pub fn emulate(
+        &self,
+        vmcb: &mut Vmcb,
+        gprs: &mut super::svm::SvmGuestGprs,
+        features: &super::svm::SvmFeatures,
+    ) {
+        let t = self.now();
+        vmcb.write_u64(SAVE_RAX, t & 0xFFFF_FFFF);
+        gprs.rdx = t >> 32;
+        if vmcb.exit_code() == VMEXIT_RDTSCP {
+            gprs.rcx = 0;
+            vmcb.skip_insn(features, RDTSCP_LEN);
+        } else {
+            vmcb.skip_insn(features, RDTSC_LEN);
+        }
+    }
+}
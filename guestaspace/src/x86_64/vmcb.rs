@@ -0,0 +1,303 @@
+//! AMD SVM Virtual Machine Control Block (VMCB).
+//!
+//! The VMCB is a 4 KB page-aligned structure used by VMRUN/VMEXIT to
+//! describe the guest state and intercept configuration.
+
+#![allow(dead_code)]
+
+use super::svm::SvmFeatures;
+use crate::events::PendingEvents;
+
+// ── VMCB Control Area offsets (0x000 – 0x3FF) ───────────────────
+// The intercept fields use 16-bit (2-byte) widths for CR/DR:
+pub const CTRL_INTERCEPT_CR_READS: usize = 0x000; // u16
+pub const CTRL_INTERCEPT_CR_WRITES: usize = 0x002; // u16
+pub const CTRL_INTERCEPT_DR_READS: usize = 0x004; // u16
+pub const CTRL_INTERCEPT_DR_WRITES: usize = 0x006; // u16
+pub const CTRL_INTERCEPT_EXCEPTIONS: usize = 0x008; // u32
+pub const CTRL_INTERCEPT_MISC1: usize = 0x00C; // u32 (INTR, NMI, CPUID, HLT, …)
+pub const CTRL_INTERCEPT_MISC2: usize = 0x010; // u32 (VMRUN[0], VMMCALL[1], …)
+pub const CTRL_INTERCEPT_MISC3: usize = 0x014; // u32 (XSETBV, …)
+pub const CTRL_IOPM_BASE: usize = 0x040;
+pub const CTRL_MSRPM_BASE: usize = 0x048;
+pub const CTRL_TSC_OFFSET: usize = 0x050;
+pub const CTRL_GUEST_ASID: usize = 0x058;
+pub const CTRL_TLB_CONTROL: usize = 0x05C; // u32 (low byte used)
+pub const CTRL_V_INTR: usize = 0x060; // u32 (V_TPR, V_IRQ, V_INTR_PRIO, V_IGN_TPR)
+pub const CTRL_V_INTR_VECTOR: usize = 0x064; // u32 (low byte used)
+pub const CTRL_EXIT_CODE: usize = 0x070;
+pub const CTRL_EXIT_INFO1: usize = 0x078;
+pub const CTRL_EXIT_INFO2: usize = 0x080;
+pub const CTRL_NP_ENABLE: usize = 0x090;
+pub const CTRL_EVENT_INJ: usize = 0x0A8; // u64
+pub const CTRL_NCR3: usize = 0x0B0;
+pub const CTRL_NRIP: usize = 0x0C8; // u64 (with NRIP_SAVE)
+pub const CTRL_INSN_LEN: usize = 0x0D0; // u8 (with DecodeAssists)
+pub const CTRL_INSN_BYTES: usize = 0x0D1; // 15 bytes (with DecodeAssists)
+
+// ── VMCB Save Area offsets (0x400 – 0xFFF) ──────────────────────
+pub const SAVE_ES: usize = 0x400;
+pub const SAVE_CS: usize = 0x410;
+pub const SAVE_SS: usize = 0x420;
+pub const SAVE_DS: usize = 0x430;
+pub const SAVE_FS: usize = 0x440;
+pub const SAVE_GS: usize = 0x450;
+pub const SAVE_GDTR: usize = 0x460;
+pub const SAVE_LDTR: usize = 0x470;
+pub const SAVE_IDTR: usize = 0x480;
+pub const SAVE_TR: usize = 0x490;
+pub const SAVE_CPL: usize = 0x4CB; // u8
+pub const SAVE_EFER: usize = 0x4D0;
+pub const SAVE_CR4: usize = 0x548;
+pub const SAVE_CR3: usize = 0x550;
+pub const SAVE_CR0: usize = 0x558;
+pub const SAVE_DR7: usize = 0x560;
+pub const SAVE_DR6: usize = 0x568;
+pub const SAVE_RFLAGS: usize = 0x570;
+pub const SAVE_RIP: usize = 0x578;
+pub const SAVE_RSP: usize = 0x5D8;
+pub const SAVE_RAX: usize = 0x5F8;
+pub const SAVE_CR2: usize = 0x640;
+
+// ── Intercept bits ──────────────────────────────────────────────
+/// Bit in CTRL_INTERCEPT_MISC3 for VMRUN intercept (must be set).
+pub const INTERCEPT_VMRUN: u32 = 1 << 0;
+/// Bit in CTRL_INTERCEPT_MISC3 for VMMCALL intercept.
+pub const INTERCEPT_VMMCALL: u32 = 1 << 1;
+/// Bit in CTRL_INTERCEPT_MISC2 for RDTSCP intercept.
+pub const INTERCEPT_RDTSCP: u32 = 1 << 7;
+/// Bit in CTRL_INTERCEPT_MISC1 for physical maskable interrupt intercept.
+pub const INTERCEPT_INTR: u32 = 1 << 0;
+/// Bit in CTRL_INTERCEPT_MISC1 for physical NMI intercept.
+pub const INTERCEPT_NMI: u32 = 1 << 1;
+/// Bit in CTRL_INTERCEPT_MISC1 for physical SMI intercept.
+pub const INTERCEPT_SMI: u32 = 1 << 2;
+/// Bit in CTRL_INTERCEPT_MISC1 for RDTSC intercept.
+pub const INTERCEPT_RDTSC: u32 = 1 << 14;
+/// Bit in CTRL_INTERCEPT_MISC1 for CPUID intercept.
+pub const INTERCEPT_CPUID: u32 = 1 << 18;
+/// Bit in CTRL_INTERCEPT_MISC1 for INVLPG intercept.
+pub const INTERCEPT_INVLPG: u32 = 1 << 25;
+/// Bit in CTRL_INTERCEPT_MISC1 for HLT intercept.
+pub const INTERCEPT_HLT: u32 = 1 << 24;
+/// Bit in CTRL_INTERCEPT_MISC1 for IN/OUT intercept (ports selected by the IOPM).
+pub const INTERCEPT_IOIO: u32 = 1 << 27;
+/// Bit in CTRL_INTERCEPT_MISC1 for SHUTDOWN (triple fault) intercept.
+pub const INTERCEPT_SHUTDOWN: u32 = 1 << 31;
+
+/// Bit of control register `n` in CTRL_INTERCEPT_CR_READS/WRITES.
+pub const fn intercept_cr(n: u32) -> u16 {
+    1 << n
+}
+/// Bit in CTRL_INTERCEPT_EXCEPTIONS for page faults (#PF, vector 14).
+pub const INTERCEPT_EXCP_PF: u32 = 1 << 14;
+
+// ── TLB control values ──────────────────────────────────────────
+/// Flush the entire TLB (all ASIDs) on the next VMRUN.
+pub const TLB_CONTROL_FLUSH_ALL: u32 = 1;
+/// Flush this guest's ASID on the next VMRUN.
+pub const TLB_CONTROL_FLUSH_ASID: u32 = 3;
+
+// ── NPF EXITINFO1 bits ──────────────────────────────────────────
+/// The faulting nested page was present (permission fault).
+pub const NPF_INFO_PRESENT: u64 = 1 << 0;
+/// The faulting access was a write.
+pub const NPF_INFO_WRITE: u64 = 1 << 1;
+/// The faulting access was an instruction fetch.
+pub const NPF_INFO_EXEC: u64 = 1 << 4;
+
+// ── CR access EXITINFO1 bits (with DecodeAssists) ───────────────
+/// The access was a MOV; the GPR number is in bits 3:0.
+pub const CR_INFO_MOV: u64 = 1 << 63;
+
+// ── Virtual interrupt (CTRL_V_INTR) bits ────────────────────────
+/// A virtual interrupt (vector in CTRL_V_INTR_VECTOR) is pending.
+pub const V_IRQ: u32 = 1 << 8;
+/// Priority of the virtual interrupt (bits 19:16).
+pub const V_INTR_PRIO_SHIFT: u32 = 16;
+/// Deliver the virtual interrupt regardless of the guest's TPR.
+pub const V_IGN_TPR: u32 = 1 << 20;
+/// The guest's RFLAGS.IF masks only virtual interrupts; physical ones are
+/// masked by the host's RFLAGS.IF.
+pub const V_INTR_MASKING: u32 = 1 << 24;
+
+// ── Event injection (CTRL_EVENT_INJ) bits ───────────────────────
+/// The event type is an exception (vector in bits 7:0).
+pub const EVENTINJ_TYPE_EXCEPTION: u64 = 3 << 8;
+/// An error code (bits 63:32) is pushed.
+pub const EVENTINJ_EV: u64 = 1 << 11;
+/// The event is to be injected at the next VMRUN.
+pub const EVENTINJ_VALID: u64 = 1 << 31;
+
+// ── Guest RFLAGS bits ───────────────────────────────────────────
+/// Maskable interrupts are enabled.
+pub const RFLAGS_IF: u64 = 1 << 9;
+/// String instructions decrement RSI/RDI.
+pub const RFLAGS_DF: u64 = 1 << 10;
+
+// ── IOIO EXITINFO1 bits ─────────────────────────────────────────
+/// The access was an IN (otherwise OUT).
+pub const IOIO_TYPE_IN: u64 = 1 << 0;
+/// String instruction (INS/OUTS).
+pub const IOIO_STR: u64 = 1 << 2;
+/// The access size in bytes is one-hot encoded in bits 6:4 (1, 2 or 4).
+pub const IOIO_SIZE_SHIFT: u64 = 4;
+/// The port number is in bits 31:16.
+pub const IOIO_PORT_SHIFT: u64 = 16;
+
+// ── VMEXIT codes ────────────────────────────────────────────────
+pub const VMEXIT_CR3_READ: u64 = 0x03;
+pub const VMEXIT_CR3_WRITE: u64 = 0x13;
+pub const VMEXIT_CR4_WRITE: u64 = 0x14;
+pub const VMEXIT_EXCP_PF: u64 = 0x4E;
+pub const VMEXIT_INTR: u64 = 0x60;
+pub const VMEXIT_RDTSC: u64 = 0x6E;
+pub const VMEXIT_CPUID: u64 = 0x72;
+pub const VMEXIT_NMI: u64 = 0x61;
+pub const VMEXIT_SMI: u64 = 0x62;
+pub const VMEXIT_HLT: u64 = 0x78;
+pub const VMEXIT_INVLPG: u64 = 0x79;
+pub const VMEXIT_IOIO: u64 = 0x7B;
+pub const VMEXIT_SHUTDOWN: u64 = 0x7F;
+pub const VMEXIT_VMMCALL: u64 = 0x81;
+pub const VMEXIT_RDTSCP: u64 = 0x87;
+pub const VMEXIT_NPF: u64 = 0x400;
+pub const VMEXIT_INVALID: u64 = u64::MAX; // -1
+
+/// A 4 KB page-aligned VMCB accessed as a raw byte buffer.
+#[repr(C, align(4096))]
+pub struct Vmcb {
+    pub data: [u8; 4096],
+}
+
+impl Default for Vmcb {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Vmcb {
+    pub const fn new() -> Self {
+        Self { data: [0u8; 4096] }
+    }
+
+    // ── primitive accessors ──────────────────────────────────────
+
+    #[inline]
+    pub fn read_u16(&self, off: usize) -> u16 {
+        u16::from_le_bytes([self.data[off], self.data[off + 1]])
+    }
+    #[inline]
+    pub fn write_u16(&mut self, off: usize, v: u16) {
+        let b = v.to_le_bytes();
+        self.data[off] = b[0];
+        self.data[off + 1] = b[1];
+    }
+    #[inline]
+    pub fn read_u32(&self, off: usize) -> u32 {
+        u32::from_le_bytes([
+            self.data[off],
+            self.data[off + 1],
+            self.data[off + 2],
+            self.data[off + 3],
+        ])
+    }
+    #[inline]
+    pub fn write_u32(&mut self, off: usize, v: u32) {
+        let b = v.to_le_bytes();
+        self.data[off..off + 4].copy_from_slice(&b);
+    }
+    #[inline]
+    pub fn read_u64(&self, off: usize) -> u64 {
+        let mut b = [0u8; 8];
+        b.copy_from_slice(&self.data[off..off + 8]);
+        u64::from_le_bytes(b)
+    }
+    #[inline]
+    pub fn write_u64(&mut self, off: usize, v: u64) {
+        let b = v.to_le_bytes();
+        self.data[off..off + 8].copy_from_slice(&b);
+    }
+
+    // ── segment descriptor helper ───────────────────────────────
+
+    /// Write a VMCB segment descriptor (16 bytes).
+    ///
+    /// Layout at `off`: selector(u16) attrib(u16) limit(u32) base(u64)
+    pub fn set_segment(&mut self, off: usize, sel: u16, attr: u16, limit: u32, base: u64) {
+        self.write_u16(off, sel);
+        self.write_u16(off + 2, attr);
+        self.write_u32(off + 4, limit);
+        self.write_u64(off + 8, base);
+    }
+
+    // ── convenience accessors ───────────────────────────────────
+
+    pub fn exit_code(&self) -> u64 {
+        self.read_u64(CTRL_EXIT_CODE)
+    }
+    pub fn exit_info1(&self) -> u64 {
+        self.read_u64(CTRL_EXIT_INFO1)
+    }
+    pub fn exit_info2(&self) -> u64 {
+        self.read_u64(CTRL_EXIT_INFO2)
+    }
+    pub fn guest_rax(&self) -> u64 {
+        self.read_u64(SAVE_RAX)
+    }
+    pub fn guest_rip(&self) -> u64 {
+        self.read_u64(SAVE_RIP)
+    }
+    pub fn guest_cpl(&self) -> u8 {
+        self.data[SAVE_CPL]
+    }
+
+    /// Advances the guest RIP past the intercepted instruction: to the next
+    /// RIP the CPU saved if it has NRIP_SAVE, otherwise by `len`, the
+    /// length of the instruction without prefixes.
+    pub fn skip_insn(&mut self, features: &SvmFeatures, len: u64) {
+        let nrip = self.read_u64(CTRL_NRIP);
+        let next = if features.nrip_save && nrip != 0 {
+            nrip
+        } else {
+            self.guest_rip() + len
+        };
+        self.write_u64(SAVE_RIP, next);
+    }
+
+    // ── event injection ─────────────────────────────────────────
+
+    /// Lands the events pending for the guest before VMRUN: the next
+    /// exception goes to EVENTINJ, and the highest asserted line becomes
+    /// the virtual interrupt, with the line number as vector. The guest's
+    /// RFLAGS.IF masks the virtual interrupt, never the exception. Returns
+    /// the line offered as virtual interrupt.
+    pub fn land_events(&mut self, events: &mut PendingEvents) -> Option<u32> {
+        // An event injected at the previous VMRUN is not injected again.
+        let inj = events.take_exception().map_or(0, |e| {
+            let inj = EVENTINJ_VALID | EVENTINJ_TYPE_EXCEPTION | (e.vector as u64 & 0xFF);
+            match e.error {
+                Some(code) => inj | EVENTINJ_EV | code << 32,
+                None => inj,
+            }
+        });
+        self.write_u64(CTRL_EVENT_INJ, inj);
+        match events.highest_irq() {
+            Some(vector) => {
+                self.write_u32(
+                    CTRL_V_INTR,
+                    V_INTR_MASKING | V_IRQ | V_IGN_TPR | 0xF << V_INTR_PRIO_SHIFT,
+                );
+                self.write_u32(CTRL_V_INTR_VECTOR, vector);
+            }
+            None => self.write_u32(CTRL_V_INTR, V_INTR_MASKING),
+        }
+        events.highest_irq()
+    }
+
+    /// Checks whether the guest took the virtual interrupt offered at the
+    /// last VMRUN: the CPU clears V_IRQ when it delivers it.
+    pub fn virq_taken(&self) -> bool {
+        self.read_u32(CTRL_V_INTR) & V_IRQ == 0
+    }
+}
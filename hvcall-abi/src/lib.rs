@@ -0,0 +1,523 @@
+//! The hypercall ABI of the arceos-guestaspace hypervisor.
+//!
+//! The hypervisor (`guestaspace`) and its paravirt guests (the `gkernel`
+//! payload among them) both take the function IDs, argument registers and
+//! encodings from here, so the two sides cannot drift apart.
+//!
+//! A hypercall is, per architecture:
+//!
+//! - aarch64: `SVC #0` from EL0 with the function ID in `x8`, the arguments
+//!   in `x0`/`x1` and the result in `x0`. PSCI calls ([`psci`]) have their
+//!   function ID in `x0` instead.
+//! - x86_64: `VMMCALL` with the function ID in `RAX[7:0]`. [`PUTCHAR`] and
+//!   [`EXIT`] carry their argument in the upper bits of RAX (see [`x86`]),
+//!   since the VMCB only saves RAX; the other calls take their arguments in
+//!   `RBX`/`RCX` and return the result in `RAX`. RAX equal to a PSCI
+//!   function ID powers off or reboots as on aarch64.
+//! - riscv64: an SBI call of the extension [`sbi::EXT_HYPERVISOR`] with
+//!   the function IDs of [`sbi`], the arguments in `a0`/`a1`, the SBI error
+//!   in `a0` and the value in `a1`. Console output and power-off use the
+//!   standard SBI extensions (DBCN, legacy putchar, SRST with the exit code
+//!   in the reason, see [`sbi::exit_reason`]).
+//!
+//! A call that fails on aarch64 or x86_64 returns [`ERROR`].
+//!
+//! With the `guest` feature, [`guest`] has the calls themselves for guests
+//! running on the hypervisor.
+
+#![no_std]
+
+// ─── Function IDs (aarch64 `x8`, x86_64 `RAX[7:0]`) ───
+
+/// Prints the character in `x0` (aarch64) or `RAX[15:8]` (x86_64).
+pub const PUTCHAR: u64 = 1;
+/// Powers the VM off with the exit code in `x0` (aarch64) or `RAX[39:8]`
+/// (x86_64).
+pub const EXIT: u64 = 2;
+/// Copies the kernel command line (without terminating NUL) to the buffer
+/// at guest physical address `arg0` of `arg1` bytes; returns its full
+/// length, so a guest can retry with a larger buffer.
+pub const GET_CMDLINE: u64 = 3;
+/// Returns the guest TSC frequency in kHz (x86_64 only).
+pub const GET_TSC_KHZ: u64 = 4;
+/// Gives the page-aligned guest physical range `[arg0, arg0 + arg1)` back
+/// to the host; returns the bytes freed.
+pub const BALLOON_RELEASE: u64 = 5;
+/// Shares the page-aligned range `[arg0, arg0 + arg1)` with the
+/// hypervisor; returns a token naming it.
+pub const SHARE_MEM: u64 = 6;
+/// Takes back the buffer of token `arg0`.
+pub const UNSHARE_MEM: u64 = 7;
+// 8 and 9 stay unused: they are `RAX[7:0]` of the PSCI IDs in `psci`.
+/// Heartbeat for the VM's watchdog; returns 0.
+pub const WATCHDOG_PET: u64 = 10;
+/// Returns the guest physical address of the VM's boot information page
+/// ([`bootinfo`]).
+pub const GET_BOOT_INFO: u64 = 11;
+/// Copies the SHA-256 digest of the guest image, measured when it was
+/// loaded, to the buffer at guest physical address `arg0` of `arg1` bytes;
+/// returns its full length (32). Fails if the hypervisor was built without
+/// image measurement.
+pub const GET_MEASUREMENT: u64 = 12;
+/// Returns the bytes of RAM the hypervisor asks the guest to give back
+/// through [`BALLOON_RELEASE`], from the VM's working set estimate; 0 if
+/// the working set is not sampled (yet).
+pub const BALLOON_TARGET: u64 = 13;
+/// Makes the buffer of token `arg0` the console ring, or detaches the ring
+/// if `arg0` is 0.
+pub const CONSOLE_RING: u64 = 14;
+/// Prints what the guest has written to the console ring; returns the
+/// bytes printed.
+pub const CONSOLE_KICK: u64 = 15;
+
+/// Version of the hypercall ABI, in the boot information page; raised
+/// whenever a call is added.
+pub const ABI_VERSION: u32 = 4;
+
+/// Result of a failed call on aarch64 and x86_64 (-1).
+pub const ERROR: u64 = u64::MAX;
+
+/// PSCI power requests (SMC32 function IDs), accepted in `x0` on aarch64
+/// and as the whole of RAX on x86_64.
+pub mod psci {
+    /// Powers the VM off with exit code 0.
+    pub const SYSTEM_OFF: u64 = 0x8400_0008;
+    /// Reboots the VM.
+    pub const SYSTEM_RESET: u64 = 0x8400_0009;
+}
+
+/// The hypervisor's SBI extension on riscv64.
+pub mod sbi {
+    /// SBI extension ID (`a7`) of the hypervisor's own calls, from the
+    /// firmware-specific range.
+    pub const EXT_HYPERVISOR: usize = 0x0A00_0000;
+
+    /// Function ID (`a6`) of [`GET_CMDLINE`](super::GET_CMDLINE).
+    pub const FID_GET_CMDLINE: usize = 0;
+    /// Function ID of [`BALLOON_RELEASE`](super::BALLOON_RELEASE).
+    pub const FID_BALLOON_RELEASE: usize = 1;
+    /// Function ID of [`SHARE_MEM`](super::SHARE_MEM).
+    pub const FID_SHARE_MEM: usize = 2;
+    /// Function ID of [`UNSHARE_MEM`](super::UNSHARE_MEM).
+    pub const FID_UNSHARE_MEM: usize = 3;
+    /// Function ID of [`CONSOLE_RING`](super::CONSOLE_RING).
+    pub const FID_CONSOLE_RING: usize = 4;
+    /// Function ID of [`CONSOLE_KICK`](super::CONSOLE_KICK).
+    pub const FID_CONSOLE_KICK: usize = 5;
+    /// Function ID of [`WATCHDOG_PET`](super::WATCHDOG_PET).
+    pub const FID_WATCHDOG_PET: usize = 6;
+    /// Function ID of [`GET_BOOT_INFO`](super::GET_BOOT_INFO).
+    pub const FID_GET_BOOT_INFO: usize = 7;
+    /// Function ID of [`GET_MEASUREMENT`](super::GET_MEASUREMENT).
+    pub const FID_GET_MEASUREMENT: usize = 8;
+    /// Function ID of [`BALLOON_TARGET`](super::BALLOON_TARGET).
+    pub const FID_BALLOON_TARGET: usize = 9;
+
+    /// First platform-specific SRST reset reason, carrying exit code 0.
+    pub const RESET_REASON_EXIT_CODE: u32 = 0xF000_0000;
+    /// Largest exit code an SRST shutdown reason can carry.
+    pub const MAX_EXIT_CODE: u32 = u32::MAX - RESET_REASON_EXIT_CODE;
+
+    /// The SRST shutdown reason of exit code `code` (at most
+    /// [`MAX_EXIT_CODE`]).
+    #[inline]
+    pub const fn exit_reason(code: u32) -> u32 {
+        RESET_REASON_EXIT_CODE + code
+    }
+
+    /// The exit code carried by SRST reason `reason`, if it is one.
+    #[inline]
+    pub const fn exit_code(reason: u32) -> Option<u32> {
+        reason.checked_sub(RESET_REASON_EXIT_CODE)
+    }
+}
+
+/// The boot information page: what a paravirt guest needs to configure
+/// itself without parsing a device tree.
+///
+/// Every VM has one read-only page at [`GPA`] holding a [`BootInfo`], which
+/// [`GET_BOOT_INFO`](super::GET_BOOT_INFO) also returns the address of.
+/// All fields are little-endian; writes to the page are ignored.
+pub mod bootinfo {
+    /// Guest physical address of the page: a free spot of the QEMU virt
+    /// machine's device area.
+    #[cfg(target_arch = "riscv64")]
+    pub const GPA: u64 = 0x0010_2000;
+    /// Guest physical address of the page: a free spot of the QEMU virt
+    /// machine's device area.
+    #[cfg(target_arch = "aarch64")]
+    pub const GPA: u64 = 0x090A_0000;
+    /// Guest physical address of the page: below the I/O APIC, outside any
+    /// RAM the guest is given.
+    #[cfg(not(any(target_arch = "riscv64", target_arch = "aarch64")))]
+    pub const GPA: u64 = 0xFEB0_0000;
+    /// Size of the page.
+    pub const SIZE: usize = 0x1000;
+
+    /// [`BootInfo::magic`]: "GABI".
+    pub const MAGIC: u32 = u32::from_le_bytes(*b"GABI");
+    /// [`BootInfo::version`] of this layout.
+    pub const VERSION: u32 = 1;
+    /// Most devices a page lists.
+    pub const MAX_DEVICES: usize = 32;
+
+    /// Kinds of [`Device`]. `base` is a guest physical address unless the
+    /// kind says I/O ports.
+    pub mod device {
+        /// CFI flash (Intel command set).
+        pub const PFLASH: u32 = 1;
+        /// virtio-mmio (version 2) transport.
+        pub const VIRTIO_MMIO: u32 = 2;
+        /// PL031 real-time clock.
+        pub const PL031: u32 = 3;
+        /// NS16550 UART, byte registers.
+        pub const NS16550: u32 = 4;
+        /// NS16550 UART at I/O ports.
+        pub const NS16550_IO: u32 = 5;
+        /// RISC-V PLIC.
+        pub const PLIC: u32 = 6;
+        /// RISC-V APLIC (with an IMSIC guest interrupt file).
+        pub const APLIC: u32 = 7;
+        /// IMSIC guest interrupt file.
+        pub const IMSIC: u32 = 8;
+        /// x86 local APIC.
+        pub const LAPIC: u32 = 9;
+        /// PCI configuration mechanism #1 at I/O ports (`0xCF8`); the
+        /// virtio devices are legacy virtio-pci functions behind it.
+        pub const PCI_CONFIG_IO: u32 = 10;
+        /// MC146818 CMOS real-time clock at I/O ports.
+        pub const MC146818_IO: u32 = 11;
+    }
+
+    /// One device of the VM.
+    #[repr(C)]
+    #[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+    pub struct Device {
+        /// What the device is, one of [`device`].
+        pub kind: u32,
+        /// Reserved, 0.
+        pub reserved: u32,
+        /// First address (or I/O port) of its registers.
+        pub base: u64,
+        /// Size of its register window.
+        pub size: u64,
+    }
+
+    /// The contents of the page.
+    #[repr(C)]
+    #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+    pub struct BootInfo {
+        /// [`MAGIC`].
+        pub magic: u32,
+        /// [`VERSION`] of the layout.
+        pub version: u32,
+        /// [`ABI_VERSION`](super::ABI_VERSION) of the hypercalls.
+        pub abi_version: u32,
+        /// Id of the VM.
+        pub vm_id: u32,
+        /// Number of vCPUs.
+        pub cpus: u32,
+        /// Entries of `devices` in use.
+        pub num_devices: u32,
+        /// Guest physical address of RAM.
+        pub ram_base: u64,
+        /// Size of RAM in bytes.
+        pub ram_size: u64,
+        /// The devices, `num_devices` of them.
+        pub devices: [Device; MAX_DEVICES],
+    }
+
+    const _: () = assert!(core::mem::size_of::<BootInfo>() <= SIZE);
+
+    impl BootInfo {
+        /// The page of VM `vm_id` with `cpus` vCPUs and RAM at
+        /// `[ram_base, ram_base + ram_size)`, with no devices yet.
+        pub const fn new(vm_id: u32, cpus: u32, ram_base: u64, ram_size: u64) -> Self {
+            Self {
+                magic: MAGIC,
+                version: VERSION,
+                abi_version: super::ABI_VERSION,
+                vm_id,
+                cpus,
+                num_devices: 0,
+                ram_base,
+                ram_size,
+                devices: [Device {
+                    kind: 0,
+                    reserved: 0,
+                    base: 0,
+                    size: 0,
+                }; MAX_DEVICES],
+            }
+        }
+
+        /// Adds device `kind` at `[base, base + size)`; returns `false` if
+        /// the page is full.
+        pub fn add_device(&mut self, kind: u32, base: u64, size: u64) -> bool {
+            let Some(slot) = self.devices.get_mut(self.num_devices as usize) else {
+                return false;
+            };
+            *slot = Device {
+                kind,
+                reserved: 0,
+                base,
+                size,
+            };
+            self.num_devices += 1;
+            true
+        }
+
+        /// The devices listed.
+        pub fn devices(&self) -> &[Device] {
+            &self.devices[..(self.num_devices as usize).min(MAX_DEVICES)]
+        }
+
+        /// The first device of `kind`.
+        pub fn find(&self, kind: u32) -> Option<&Device> {
+            self.devices().iter().find(|dev| dev.kind == kind)
+        }
+
+        /// Checks that the page holds a layout this crate reads.
+        pub fn is_valid(&self) -> bool {
+            self.magic == MAGIC && self.version == VERSION
+        }
+
+        /// The bytes of the page contents.
+        pub fn as_bytes(&self) -> &[u8] {
+            // `repr(C)` with every field 4- or 8-byte aligned in order: no
+            // padding bytes.
+            unsafe {
+                core::slice::from_raw_parts(
+                    (self as *const Self).cast::<u8>(),
+                    core::mem::size_of::<Self>(),
+                )
+            }
+        }
+    }
+}
+
+/// The RAX encoding of VMMCALL on x86_64.
+pub mod x86 {
+    /// Bits of RAX holding the function ID.
+    pub const FUNC_MASK: u64 = 0xFF;
+    /// Shift of the argument of PUTCHAR and EXIT in RAX.
+    pub const ARG_SHIFT: u32 = 8;
+
+    /// The function ID of a VMMCALL with `rax`.
+    #[inline]
+    pub const fn func(rax: u64) -> u64 {
+        rax & FUNC_MASK
+    }
+
+    /// RAX of a call to `func` with `arg` in the upper bits.
+    #[inline]
+    pub const fn encode(func: u64, arg: u64) -> u64 {
+        (func & FUNC_MASK) | (arg << ARG_SHIFT)
+    }
+
+    /// RAX of [`PUTCHAR`](super::PUTCHAR) of `c`.
+    #[inline]
+    pub const fn putchar(c: u8) -> u64 {
+        encode(super::PUTCHAR, c as u64)
+    }
+
+    /// RAX of [`EXIT`](super::EXIT) with `code`.
+    #[inline]
+    pub const fn exit(code: u32) -> u64 {
+        encode(super::EXIT, code as u64)
+    }
+
+    /// The character of a PUTCHAR with `rax`.
+    #[inline]
+    pub const fn putchar_char(rax: u64) -> u8 {
+        (rax >> ARG_SHIFT) as u8
+    }
+
+    /// The exit code of an EXIT with `rax`.
+    #[inline]
+    pub const fn exit_code(rax: u64) -> u32 {
+        (rax >> ARG_SHIFT) as u32
+    }
+}
+
+/// The hypercalls, for guests.
+#[cfg(feature = "guest")]
+pub mod guest {
+    /// Calls `func` with `arg0` and `arg1`; returns `x0`.
+    #[cfg(target_arch = "aarch64")]
+    #[inline(always)]
+    pub fn call(func: u64, arg0: u64, arg1: u64) -> u64 {
+        let ret;
+        unsafe {
+            core::arch::asm!(
+                "svc #0",
+                inlateout("x0") arg0 => ret,
+                in("x1") arg1,
+                in("x8") func,
+                options(nostack),
+            );
+        }
+        ret
+    }
+
+    /// Prints `c` on the VM's console.
+    #[cfg(target_arch = "aarch64")]
+    #[inline(always)]
+    pub fn putchar(c: u8) {
+        call(super::PUTCHAR, c as u64, 0);
+    }
+
+    /// Powers the VM off with exit code `code`.
+    #[cfg(target_arch = "aarch64")]
+    pub fn exit(code: u32) -> ! {
+        unsafe {
+            core::arch::asm!(
+                "svc #0",
+                in("x0") code as u64,
+                in("x8") super::EXIT,
+                options(noreturn, nomem, nostack),
+            );
+        }
+    }
+
+    /// Calls `func` with `arg0` in RBX and `arg1` in RCX; returns RAX.
+    #[cfg(target_arch = "x86_64")]
+    #[inline(always)]
+    pub fn call(func: u64, arg0: u64, arg1: u64) -> u64 {
+        let ret;
+        unsafe {
+            // RBX is reserved by LLVM: swap the argument in around VMMCALL.
+            core::arch::asm!(
+                "xchg {arg0}, rbx",
+                "vmmcall",
+                "xchg {arg0}, rbx",
+                arg0 = inout(reg) arg0 => _,
+                inlateout("rax") func => ret,
+                in("rcx") arg1,
+                options(nostack),
+            );
+        }
+        ret
+    }
+
+    /// Prints `c` on the VM's console.
+    #[cfg(target_arch = "x86_64")]
+    #[inline(always)]
+    pub fn putchar(c: u8) {
+        unsafe {
+            core::arch::asm!(
+                "vmmcall",
+                in("rax") super::x86::putchar(c),
+                options(nomem, nostack),
+            );
+        }
+    }
+
+    /// Powers the VM off with exit code `code`.
+    #[cfg(target_arch = "x86_64")]
+    pub fn exit(code: u32) -> ! {
+        unsafe {
+            core::arch::asm!(
+                "vmmcall",
+                in("rax") super::x86::exit(code),
+                options(noreturn, nomem, nostack),
+            );
+        }
+    }
+
+    /// Calls function `fid` of the hypervisor's SBI extension with `arg0`
+    /// and `arg1`; returns the value, or the SBI error.
+    #[cfg(target_arch = "riscv64")]
+    #[inline(always)]
+    pub fn call(fid: usize, arg0: usize, arg1: usize) -> Result<usize, isize> {
+        let (error, value): (isize, usize);
+        unsafe {
+            core::arch::asm!(
+                "ecall",
+                inlateout("a0") arg0 => error,
+                inlateout("a1") arg1 => value,
+                in("a6") fid,
+                in("a7") super::sbi::EXT_HYPERVISOR,
+                options(nostack),
+            );
+        }
+        if error == 0 { Ok(value) } else { Err(error) }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn x86_encoding_round_trips() {
+        let rax = x86::putchar(b'A');
+        assert_eq!(rax, 0x4101);
+        assert_eq!(x86::func(rax), PUTCHAR);
+        assert_eq!(x86::putchar_char(rax), b'A');
+
+        let rax = x86::exit(0xDEAD_BEEF);
+        assert_eq!(rax, 0xDE_ADBE_EF02);
+        assert_eq!(x86::func(rax), EXIT);
+        assert_eq!(x86::exit_code(rax), 0xDEAD_BEEF);
+
+        // No function ID aliases RAX[7:0] of a PSCI ID.
+        let funcs = [
+            PUTCHAR,
+            EXIT,
+            GET_CMDLINE,
+            GET_TSC_KHZ,
+            BALLOON_RELEASE,
+            SHARE_MEM,
+            UNSHARE_MEM,
+            WATCHDOG_PET,
+            GET_BOOT_INFO,
+            GET_MEASUREMENT,
+            BALLOON_TARGET,
+            CONSOLE_RING,
+            CONSOLE_KICK,
+        ];
+        for psci in [psci::SYSTEM_OFF, psci::SYSTEM_RESET] {
+            assert!(!funcs.contains(&x86::func(psci)));
+        }
+    }
+
+    #[test]
+    fn boot_info_layout() {
+        use bootinfo::*;
+
+        let mut info = BootInfo::new(3, 2, 0x8000_0000, 0x100_0000);
+        assert!(info.add_device(device::VIRTIO_MMIO, 0x1000_1000, 0x200));
+        assert!(info.add_device(device::PL031, 0x10_1000, 0x1000));
+        assert_eq!(info.devices().len(), 2);
+        assert_eq!(
+            info.find(device::PL031).map(|dev| dev.base),
+            Some(0x10_1000)
+        );
+        assert_eq!(info.find(device::LAPIC), None);
+
+        let bytes = info.as_bytes();
+        assert_eq!(bytes.len(), 40 + 24 * MAX_DEVICES);
+        assert_eq!(&bytes[0..4], b"GABI");
+        assert_eq!(bytes[8], ABI_VERSION as u8);
+        assert_eq!(bytes[12], 3);
+        assert_eq!(bytes[20], 2);
+        assert_eq!(&bytes[24..32], &0x8000_0000u64.to_le_bytes());
+        assert_eq!(&bytes[40..44], &device::VIRTIO_MMIO.to_le_bytes());
+        assert_eq!(&bytes[48..56], &0x1000_1000u64.to_le_bytes());
+
+        while info.add_device(device::PFLASH, 0, 0) {}
+        assert_eq!(info.devices().len(), MAX_DEVICES);
+    }
+
+    #[test]
+    fn sbi_exit_reasons() {
+        assert_eq!(sbi::exit_reason(1), 0xF000_0001);
+        assert_eq!(sbi::exit_code(sbi::exit_reason(42)), Some(42));
+        assert_eq!(
+            sbi::exit_code(sbi::exit_reason(sbi::MAX_EXIT_CODE)),
+            Some(sbi::MAX_EXIT_CODE)
+        );
+        assert_eq!(sbi::exit_code(1), None);
+    }
+}
@@ -6,10 +6,839 @@
 //!   Demonstrates nested page fault handling via TTBR0 page tables.
 //! - **x86_64**: Bare-metal long-mode program using VMMCALL hypercalls.
 //!   Demonstrates nested page fault handling via SVM NPT.
+//!
+//! The guest exits with code 0 if it read the pflash magic "pfld", 1
+//! otherwise. Hypercall IDs and encodings come from `hvcall-abi`, shared
+//! with the hypervisor.
+//!
+//! With `memstress=PAGES` on its kernel command line (`cmdline=` in
+//! `vms.conf`, read with the GET_CMDLINE hypercall), the guest then also
+//! runs a memory stress test: it fills `PAGES` pages spread evenly over a
+//! large range of its RAM with a pattern, reads them back and prints the
+//! time both passes took, so that the hypervisor's demand paging (`mem=`)
+//! and fault-around (`fault_around=`) get a real workload. A read-back
+//! mismatch makes the exit code 1.
+//!
+//! With `timertest=SECS`, the guest runs a timer test for `SECS` seconds:
+//! it programs the virtualized timer to tick at [`timertest::TICK_HZ`]
+//! (on riscv64, it counts the interrupts of the ArceOS timer instead),
+//! counts the ticks it takes and prints them with the number expected.
+//! Receiving no tick at all makes the exit code 1.
+//!
+//! With `blktest=1`, the guest drives the VM's virtio-blk disk (virtio-mmio
+//! slot 0 on riscv64 and aarch64, legacy virtio-pci on x86_64) with a
+//! minimal driver: it reads sector 0, writes a pattern to it, reads it back
+//! and writes the original contents again. A failed request or a read-back
+//! mismatch makes the exit code 1. The VM must have a disk, as `xtask`
+//! gives every guest.
+//!
+//! With `smptest=1`, the guest starts its other CPUs (SBI HSM `hart_start`
+//! on riscv64, for each hart of `cpus=` in `vms.conf`; the hypervisor runs
+//! aarch64 and x86_64 guests on one vCPU) and every CPU prints a hello,
+//! waits at an atomic barrier for the others and then increments its own
+//! counter and a shared one [`smptest::ROUNDS`] times. A CPU missing at the
+//! barrier or a lost increment makes the exit code 1.
 
 #![no_std]
 #![no_main]
 
+// ══════════════════════════════════════════════════════════════
+//  Kernel command line (all architectures)
+// ══════════════════════════════════════════════════════════════
+
+mod cmdline {
+    /// Longest kernel command line read.
+    pub const CMDLINE_MAX: usize = 256;
+
+    /// The number `N` of `name=N` in `cmdline`.
+    pub fn arg(cmdline: &[u8], name: &str) -> Option<u64> {
+        cmdline
+            .split(|c| c.is_ascii_whitespace())
+            .find_map(|arg| arg.strip_prefix(name.as_bytes())?.strip_prefix(b"="))
+            .and_then(|n| core::str::from_utf8(n).ok()?.parse().ok())
+    }
+}
+
+// ══════════════════════════════════════════════════════════════
+//  Memory stress test (all architectures)
+// ══════════════════════════════════════════════════════════════
+
+mod memstress {
+    use core::fmt;
+
+    pub const PAGE_SIZE: usize = 0x1000;
+
+    /// Outcome of a stress test.
+    pub struct Report {
+        /// Pages touched.
+        pub pages: usize,
+        /// Distance between the pages, in bytes.
+        pub stride: usize,
+        /// Words that did not read back as written.
+        pub errors: usize,
+        /// Clock ticks of the write pass.
+        pub write_ticks: u64,
+        /// Clock ticks of the read-back pass.
+        pub verify_ticks: u64,
+        /// Clock frequency, 0 if unknown.
+        pub tick_hz: u64,
+    }
+
+    /// The word written at `addr`: different for every word and page.
+    fn pattern(addr: usize) -> u64 {
+        (addr as u64).wrapping_mul(0x9E37_79B9_7F4A_7C15) ^ 0xA5A5_A5A5_5A5A_5A5A
+    }
+
+    /// Fills `pages` pages spread evenly over `[base, base + size)` (at most
+    /// all of them) with a pattern and reads them back, timing both passes
+    /// with `now`, a clock of `tick_hz`.
+    ///
+    /// # Safety
+    ///
+    /// `[base, base + size)` must be mapped, writable memory the guest does
+    /// not use otherwise.
+    pub unsafe fn run(
+        base: usize,
+        size: usize,
+        pages: usize,
+        now: impl Fn() -> u64,
+        tick_hz: u64,
+    ) -> Report {
+        let pages = pages.clamp(1, size / PAGE_SIZE);
+        let stride = size / pages / PAGE_SIZE * PAGE_SIZE;
+        let words = |page: usize| {
+            let start = base + page * stride;
+            (start..start + PAGE_SIZE).step_by(8)
+        };
+
+        let start = now();
+        for page in 0..pages {
+            for addr in words(page) {
+                unsafe { core::ptr::write_volatile(addr as *mut u64, pattern(addr)) };
+            }
+        }
+        let written = now();
+        let mut errors = 0;
+        for page in 0..pages {
+            for addr in words(page) {
+                if unsafe { core::ptr::read_volatile(addr as *const u64) } != pattern(addr) {
+                    errors += 1;
+                }
+            }
+        }
+        let verified = now();
+
+        Report {
+            pages,
+            stride,
+            errors,
+            write_ticks: written - start,
+            verify_ticks: verified - written,
+            tick_hz,
+        }
+    }
+
+    impl Report {
+        fn fmt_time(&self, f: &mut fmt::Formatter, ticks: u64) -> fmt::Result {
+            match self.tick_hz {
+                0 => write!(f, "{ticks} ticks"),
+                hz => write!(f, "{} us", ticks as u128 * 1_000_000 / hz as u128),
+            }
+        }
+    }
+
+    impl fmt::Display for Report {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            write!(
+                f,
+                "memstress: {} pages, {} KB apart: {} errors, write ",
+                self.pages,
+                self.stride / 1024,
+                self.errors
+            )?;
+            self.fmt_time(f, self.write_ticks)?;
+            f.write_str(", verify ")?;
+            self.fmt_time(f, self.verify_ticks)
+        }
+    }
+}
+
+// ══════════════════════════════════════════════════════════════
+//  Timer and interrupt test (all architectures)
+// ══════════════════════════════════════════════════════════════
+
+mod timertest {
+    use core::fmt;
+
+    /// Tick frequency of the test timer (riscv64 takes the ArceOS timer's).
+    #[cfg_attr(target_arch = "riscv64", allow(dead_code))]
+    pub const TICK_HZ: u64 = 100;
+    /// Longest test.
+    pub const MAX_SECS: u64 = 60;
+
+    /// Outcome of a timer test.
+    pub struct Report {
+        /// Ticks taken.
+        pub ticks: u64,
+        /// Tick frequency of the timer.
+        pub hz: u64,
+        /// Time the test ran, in milliseconds.
+        pub elapsed_ms: u64,
+        /// Largest delay from a timer deadline to its tick being taken, in
+        /// microseconds, if measured.
+        pub max_latency_us: Option<u64>,
+    }
+
+    impl Report {
+        /// Ticks expected in the time the test ran.
+        pub fn expected(&self) -> u64 {
+            self.elapsed_ms * self.hz / 1000
+        }
+
+        /// Whether the timer ticked at all.
+        pub fn ok(&self) -> bool {
+            self.ticks > 0
+        }
+    }
+
+    impl fmt::Display for Report {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            write!(
+                f,
+                "timertest: {} ticks in {} ms (expected {} at {} Hz)",
+                self.ticks,
+                self.elapsed_ms,
+                self.expected(),
+                self.hz
+            )?;
+            if let Some(latency) = self.max_latency_us {
+                write!(f, ", max latency {latency} us")?;
+            }
+            Ok(())
+        }
+    }
+}
+
+// ══════════════════════════════════════════════════════════════
+//  virtio-blk read/write test (all architectures)
+// ══════════════════════════════════════════════════════════════
+
+mod blktest {
+    use core::fmt;
+    use core::ptr::{addr_of, addr_of_mut, read_volatile, write_volatile};
+    use core::sync::atomic::{Ordering, fence};
+
+    const SECTOR_SIZE: usize = 512;
+    /// Size of the request queue, the one of the legacy virtio-pci
+    /// interface, whose layout [`Ring`] has.
+    const QUEUE_SIZE: usize = 256;
+    /// Offset of the driver (available) ring in [`Ring`].
+    const AVAIL_OFFSET: u64 = 16 * QUEUE_SIZE as u64;
+    /// Offset of the device (used) ring in [`Ring`].
+    const USED_OFFSET: u64 = 2 * 0x1000;
+
+    const STATUS_ACKNOWLEDGE: u32 = 1;
+    const STATUS_DRIVER: u32 = 2;
+    const STATUS_DRIVER_OK: u32 = 4;
+
+    const VIRTIO_BLK_T_IN: u32 = 0;
+    const VIRTIO_BLK_T_OUT: u32 = 1;
+    const VIRTIO_BLK_S_OK: u8 = 0;
+    /// Status byte of a request the device has not completed.
+    const STATUS_PENDING: u8 = 0xFF;
+    const DESC_F_NEXT: u16 = 1;
+    const DESC_F_WRITE: u16 = 2;
+    /// Sector the test writes, and restores afterwards.
+    const TEST_SECTOR: u64 = 0;
+
+    /// How the guest reaches the device.
+    pub trait Transport {
+        /// Resets the device and brings it up with its request queue laid
+        /// out as [`Ring`] at guest physical address `ring`; returns the
+        /// capacity in sectors.
+        fn init(&mut self, ring: u64) -> Result<u64, &'static str>;
+        /// Tells the device there are new requests in the queue.
+        fn notify(&mut self);
+        /// Acknowledges the device's used buffer interrupt.
+        fn ack_interrupt(&mut self);
+    }
+
+    #[repr(C)]
+    #[derive(Clone, Copy)]
+    struct Desc {
+        addr: u64,
+        len: u32,
+        flags: u16,
+        next: u16,
+    }
+
+    #[repr(C)]
+    #[derive(Clone, Copy)]
+    struct UsedElem {
+        id: u32,
+        len: u32,
+    }
+
+    /// A split virtqueue in the legacy layout: the descriptors, the driver
+    /// ring right after them and the device ring on the next page.
+    #[repr(C, align(4096))]
+    struct Ring {
+        desc: [Desc; QUEUE_SIZE],
+        avail_flags: u16,
+        avail_idx: u16,
+        avail_ring: [u16; QUEUE_SIZE],
+        used_event: u16,
+        _pad: [u8; USED_OFFSET as usize - AVAIL_OFFSET as usize - 2 * (QUEUE_SIZE + 3)],
+        used_flags: u16,
+        used_idx: u16,
+        used_ring: [UsedElem; QUEUE_SIZE],
+        avail_event: u16,
+    }
+
+    const _: () = assert!(core::mem::offset_of!(Ring, avail_flags) == AVAIL_OFFSET as usize);
+    const _: () = assert!(core::mem::offset_of!(Ring, used_flags) == USED_OFFSET as usize);
+
+    /// A block request: header, one sector of data and the status byte.
+    #[repr(C)]
+    struct Request {
+        ty: u32,
+        reserved: u32,
+        sector: u64,
+        data: [u8; SECTOR_SIZE],
+        status: u8,
+    }
+
+    /// The queue and the request buffers, shared with the device.
+    static mut RING: Ring = Ring {
+        desc: [Desc {
+            addr: 0,
+            len: 0,
+            flags: 0,
+            next: 0,
+        }; QUEUE_SIZE],
+        avail_flags: 0,
+        avail_idx: 0,
+        avail_ring: [0; QUEUE_SIZE],
+        used_event: 0,
+        _pad: [0; USED_OFFSET as usize - AVAIL_OFFSET as usize - 2 * (QUEUE_SIZE + 3)],
+        used_flags: 0,
+        used_idx: 0,
+        used_ring: [UsedElem { id: 0, len: 0 }; QUEUE_SIZE],
+        avail_event: 0,
+    };
+    static mut REQUEST: Request = Request {
+        ty: 0,
+        reserved: 0,
+        sector: 0,
+        data: [0; SECTOR_SIZE],
+        status: 0,
+    };
+
+    /// Outcome of a block test.
+    pub struct Report {
+        /// Capacity of the disk in sectors.
+        pub capacity: u64,
+        /// First bytes of the sector as first read.
+        pub head: [u8; 4],
+        /// Bytes of the pattern that did not read back.
+        pub errors: usize,
+    }
+
+    impl fmt::Display for Report {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            write!(
+                f,
+                "blktest: {} sectors, sector {TEST_SECTOR} starts with {:02x?}, \
+                 write/read-back {}",
+                self.capacity,
+                self.head,
+                if self.errors == 0 { "ok" } else { "FAILED" }
+            )?;
+            if self.errors != 0 {
+                write!(f, " ({} bytes differ)", self.errors)?;
+            }
+            Ok(())
+        }
+    }
+
+    /// The test pattern byte at `offset`.
+    fn pattern(offset: usize) -> u8 {
+        (offset as u8).wrapping_mul(31) ^ 0xA5
+    }
+
+    /// The request queue of a block device.
+    struct Disk<T> {
+        transport: T,
+        /// Guest physical address of a pointer.
+        gpa: fn(usize) -> u64,
+        /// Waits for something to happen (the request to complete).
+        wait: fn(),
+        /// The driver ring index of the next request.
+        avail_idx: u16,
+    }
+
+    impl<T: Transport> Disk<T> {
+        /// Issues request `ty` for [`TEST_SECTOR`] with the data in
+        /// `REQUEST` and waits for it to complete.
+        unsafe fn request(&mut self, ty: u32) -> Result<(), &'static str> {
+            unsafe {
+                let ring = addr_of_mut!(RING);
+                let req = addr_of_mut!(REQUEST);
+                (*req).ty = ty;
+                (*req).sector = TEST_SECTOR;
+                write_volatile(addr_of_mut!((*req).status), STATUS_PENDING);
+                let data_flags = if ty == VIRTIO_BLK_T_IN {
+                    DESC_F_NEXT | DESC_F_WRITE
+                } else {
+                    DESC_F_NEXT
+                };
+                let chain = [
+                    (addr_of!((*req).ty) as usize, 16, DESC_F_NEXT),
+                    (addr_of!((*req).data) as usize, SECTOR_SIZE, data_flags),
+                    (addr_of!((*req).status) as usize, 1, DESC_F_WRITE),
+                ];
+                for (i, (addr, len, flags)) in chain.into_iter().enumerate() {
+                    write_volatile(
+                        addr_of_mut!((*ring).desc[i]),
+                        Desc {
+                            addr: (self.gpa)(addr),
+                            len: len as u32,
+                            flags,
+                            next: i as u16 + 1,
+                        },
+                    );
+                }
+                let slot = self.avail_idx as usize % QUEUE_SIZE;
+                write_volatile(addr_of_mut!((*ring).avail_ring[slot]), 0);
+                self.avail_idx = self.avail_idx.wrapping_add(1);
+                // The descriptors must be visible before the index.
+                fence(Ordering::SeqCst);
+                write_volatile(addr_of_mut!((*ring).avail_idx), self.avail_idx);
+                fence(Ordering::SeqCst);
+                self.transport.notify();
+
+                while read_volatile(addr_of!((*ring).used_idx)) != self.avail_idx {
+                    (self.wait)();
+                }
+                fence(Ordering::SeqCst);
+                self.transport.ack_interrupt();
+                match read_volatile(addr_of!((*req).status)) {
+                    VIRTIO_BLK_S_OK => Ok(()),
+                    _ => Err("request failed"),
+                }
+            }
+        }
+    }
+
+    /// Brings up the block device behind `transport`, reads
+    /// [`TEST_SECTOR`], writes a pattern to it, reads it back and restores
+    /// what it read first. `gpa` translates pointers into guest physical
+    /// addresses; `wait` waits for the device while a request is in flight.
+    ///
+    /// # Safety
+    ///
+    /// Must not run twice at the same time; the device must be a virtio
+    /// block device the guest has no other driver for.
+    pub unsafe fn run<T: Transport>(
+        mut transport: T,
+        gpa: fn(usize) -> u64,
+        wait: fn(),
+    ) -> Result<Report, &'static str> {
+        unsafe {
+            let capacity = transport.init(gpa(addr_of!(RING) as usize))?;
+            let mut disk = Disk {
+                transport,
+                gpa,
+                wait,
+                avail_idx: 0,
+            };
+            let data = addr_of_mut!(REQUEST.data);
+            disk.request(VIRTIO_BLK_T_IN)?;
+            let saved = read_volatile(data);
+            for (i, b) in (*data).iter_mut().enumerate() {
+                *b = pattern(i);
+            }
+            disk.request(VIRTIO_BLK_T_OUT)?;
+            write_volatile(data, [0; SECTOR_SIZE]);
+            disk.request(VIRTIO_BLK_T_IN)?;
+            let errors = (*data)
+                .iter()
+                .enumerate()
+                .filter(|&(i, &b)| b != pattern(i))
+                .count();
+            write_volatile(data, saved);
+            disk.request(VIRTIO_BLK_T_OUT)?;
+            Ok(Report {
+                capacity,
+                head: [saved[0], saved[1], saved[2], saved[3]],
+                errors,
+            })
+        }
+    }
+
+    /// The virtio-mmio (version 2) transport.
+    #[cfg(any(target_arch = "riscv64", target_arch = "aarch64"))]
+    pub struct Mmio {
+        base: usize,
+    }
+
+    #[cfg(any(target_arch = "riscv64", target_arch = "aarch64"))]
+    impl Mmio {
+        const MAGIC_VALUE: usize = 0x000;
+        const VERSION: usize = 0x004;
+        const DEVICE_ID: usize = 0x008;
+        const DEVICE_FEATURES: usize = 0x010;
+        const DEVICE_FEATURES_SEL: usize = 0x014;
+        const DRIVER_FEATURES: usize = 0x020;
+        const DRIVER_FEATURES_SEL: usize = 0x024;
+        const QUEUE_SEL: usize = 0x030;
+        const QUEUE_NUM_MAX: usize = 0x034;
+        const QUEUE_NUM: usize = 0x038;
+        const QUEUE_READY: usize = 0x044;
+        const QUEUE_NOTIFY: usize = 0x050;
+        const INTERRUPT_STATUS: usize = 0x060;
+        const INTERRUPT_ACK: usize = 0x064;
+        const STATUS: usize = 0x070;
+        const QUEUE_DESC: usize = 0x080;
+        const QUEUE_DRIVER: usize = 0x090;
+        const QUEUE_DEVICE: usize = 0x0A0;
+        const CONFIG: usize = 0x100;
+        /// "virt".
+        const MAGIC: u32 = 0x7472_6976;
+        /// virtio device ID of block devices.
+        const VIRTIO_ID_BLOCK: u32 = 2;
+        const STATUS_FEATURES_OK: u32 = 8;
+        /// `VIRTIO_F_VERSION_1`, in the upper half of the features.
+        const F_VERSION_1_HI: u32 = 1;
+
+        /// The block device whose registers are mapped at `base`, if it is
+        /// one.
+        ///
+        /// # Safety
+        ///
+        /// `base` must map a virtio-mmio register block.
+        pub unsafe fn probe(base: usize) -> Option<Self> {
+            let mmio = Self { base };
+            (mmio.read(Self::MAGIC_VALUE) == Self::MAGIC
+                && mmio.read(Self::VERSION) == 2
+                && mmio.read(Self::DEVICE_ID) == Self::VIRTIO_ID_BLOCK)
+                .then_some(mmio)
+        }
+
+        fn read(&self, reg: usize) -> u32 {
+            unsafe { read_volatile((self.base + reg) as *const u32) }
+        }
+
+        fn write(&self, reg: usize, value: u32) {
+            unsafe { write_volatile((self.base + reg) as *mut u32, value) }
+        }
+
+        fn write_addr(&self, reg: usize, addr: u64) {
+            self.write(reg, addr as u32);
+            self.write(reg + 4, (addr >> 32) as u32);
+        }
+    }
+
+    #[cfg(any(target_arch = "riscv64", target_arch = "aarch64"))]
+    impl Transport for Mmio {
+        fn init(&mut self, ring: u64) -> Result<u64, &'static str> {
+            self.write(Self::STATUS, 0);
+            let mut status = STATUS_ACKNOWLEDGE | STATUS_DRIVER;
+            self.write(Self::STATUS, status);
+            self.write(Self::DEVICE_FEATURES_SEL, 1);
+            if self.read(Self::DEVICE_FEATURES) & Self::F_VERSION_1_HI == 0 {
+                return Err("no VIRTIO_F_VERSION_1");
+            }
+            // Nothing but VIRTIO_F_VERSION_1.
+            self.write(Self::DRIVER_FEATURES_SEL, 0);
+            self.write(Self::DRIVER_FEATURES, 0);
+            self.write(Self::DRIVER_FEATURES_SEL, 1);
+            self.write(Self::DRIVER_FEATURES, Self::F_VERSION_1_HI);
+            status |= Self::STATUS_FEATURES_OK;
+            self.write(Self::STATUS, status);
+            if self.read(Self::STATUS) & Self::STATUS_FEATURES_OK == 0 {
+                return Err("features refused");
+            }
+            self.write(Self::QUEUE_SEL, 0);
+            if (self.read(Self::QUEUE_NUM_MAX) as usize) < QUEUE_SIZE {
+                return Err("queue too small");
+            }
+            self.write(Self::QUEUE_NUM, QUEUE_SIZE as u32);
+            self.write_addr(Self::QUEUE_DESC, ring);
+            self.write_addr(Self::QUEUE_DRIVER, ring + AVAIL_OFFSET);
+            self.write_addr(Self::QUEUE_DEVICE, ring + USED_OFFSET);
+            self.write(Self::QUEUE_READY, 1);
+            self.write(Self::STATUS, status | STATUS_DRIVER_OK);
+            let capacity = self.read(Self::CONFIG) as u64;
+            Ok(capacity | (self.read(Self::CONFIG + 4) as u64) << 32)
+        }
+
+        fn notify(&mut self) {
+            self.write(Self::QUEUE_NOTIFY, 0);
+        }
+
+        fn ack_interrupt(&mut self) {
+            self.write(Self::INTERRUPT_ACK, self.read(Self::INTERRUPT_STATUS));
+        }
+    }
+
+    /// The legacy virtio-pci transport: an I/O BAR behind PCI
+    /// configuration mechanism #1.
+    #[cfg(target_arch = "x86_64")]
+    pub struct LegacyPci {
+        port: u16,
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    impl LegacyPci {
+        const CONFIG_ADDRESS: u16 = 0xCF8;
+        const CONFIG_DATA: u16 = 0xCFC;
+        /// Vendor and device ID of a transitional virtio-blk device.
+        const BLK_IDS: u32 = 0x1001 << 16 | 0x1AF4;
+        const PCI_BAR0: u32 = 0x10;
+        const HOST_FEATURES: u16 = 0x00;
+        const GUEST_FEATURES: u16 = 0x04;
+        const QUEUE_PFN: u16 = 0x08;
+        const QUEUE_NUM: u16 = 0x0C;
+        const QUEUE_SEL: u16 = 0x0E;
+        const QUEUE_NOTIFY: u16 = 0x10;
+        const STATUS: u16 = 0x12;
+        const ISR: u16 = 0x13;
+        const CONFIG: u16 = 0x14;
+
+        /// The first block device on PCI bus 0, with the I/O BAR the
+        /// firmware (the hypervisor) assigned it.
+        pub fn probe() -> Option<Self> {
+            (1..32).find_map(|dev| {
+                (Self::config_read(dev, 0) == Self::BLK_IDS).then(|| Self {
+                    port: (Self::config_read(dev, Self::PCI_BAR0) & !3) as u16,
+                })
+            })
+        }
+
+        /// Reads configuration register `reg` of device `dev` on bus 0.
+        fn config_read(dev: u32, reg: u32) -> u32 {
+            outl(Self::CONFIG_ADDRESS, 1 << 31 | dev << 11 | reg);
+            inl(Self::CONFIG_DATA)
+        }
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    impl Transport for LegacyPci {
+        fn init(&mut self, ring: u64) -> Result<u64, &'static str> {
+            let port = self.port;
+            outb(port + Self::STATUS, 0);
+            let status = (STATUS_ACKNOWLEDGE | STATUS_DRIVER) as u8;
+            outb(port + Self::STATUS, status);
+            // No features; the legacy interface has no FEATURES_OK.
+            let _ = inl(port + Self::HOST_FEATURES);
+            outl(port + Self::GUEST_FEATURES, 0);
+            outw(port + Self::QUEUE_SEL, 0);
+            if inw(port + Self::QUEUE_NUM) as usize != QUEUE_SIZE {
+                return Err("unexpected queue size");
+            }
+            outl(port + Self::QUEUE_PFN, (ring >> 12) as u32);
+            outb(port + Self::STATUS, status | STATUS_DRIVER_OK as u8);
+            let capacity = inl(port + Self::CONFIG) as u64;
+            Ok(capacity | (inl(port + Self::CONFIG + 4) as u64) << 32)
+        }
+
+        fn notify(&mut self) {
+            outw(self.port + Self::QUEUE_NOTIFY, 0);
+        }
+
+        fn ack_interrupt(&mut self) {
+            // Reading the ISR clears it.
+            let _ = inb(self.port + Self::ISR);
+        }
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    fn inb(port: u16) -> u8 {
+        let value;
+        unsafe { core::arch::asm!("in al, dx", out("al") value, in("dx") port, options(nostack)) };
+        value
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    fn inw(port: u16) -> u16 {
+        let value;
+        unsafe { core::arch::asm!("in ax, dx", out("ax") value, in("dx") port, options(nostack)) };
+        value
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    fn inl(port: u16) -> u32 {
+        let value;
+        unsafe {
+            core::arch::asm!("in eax, dx", out("eax") value, in("dx") port, options(nostack))
+        };
+        value
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    fn outb(port: u16, value: u8) {
+        unsafe { core::arch::asm!("out dx, al", in("dx") port, in("al") value, options(nostack)) };
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    fn outw(port: u16, value: u16) {
+        unsafe { core::arch::asm!("out dx, ax", in("dx") port, in("ax") value, options(nostack)) };
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    fn outl(port: u16, value: u32) {
+        unsafe {
+            core::arch::asm!("out dx, eax", in("dx") port, in("eax") value, options(nostack))
+        };
+    }
+}
+
+// ══════════════════════════════════════════════════════════════
+//  SMP bring-up test (all architectures)
+// ══════════════════════════════════════════════════════════════
+
+mod smptest {
+    use core::fmt;
+    use core::hint::spin_loop;
+    use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+
+    /// Most CPUs the test runs on.
+    pub const MAX_CPUS: usize = 8;
+    /// Increments each CPU makes to its own counter and to the shared one.
+    pub const ROUNDS: u64 = 10_000;
+    /// Longest CPU 0 waits for the others.
+    const TIMEOUT_SECS: u64 = 5;
+
+    /// CPUs taking part, once CPU 0 has started the others (0 before).
+    static CPUS: AtomicUsize = AtomicUsize::new(0);
+    /// CPUs at the barrier.
+    static ARRIVED: AtomicUsize = AtomicUsize::new(0);
+    /// CPUs through with their rounds.
+    static DONE: AtomicUsize = AtomicUsize::new(0);
+    /// Counter all CPUs increment.
+    static SHARED: AtomicU64 = AtomicU64::new(0);
+    /// Counter of each CPU.
+    static COUNTERS: [AtomicU64; MAX_CPUS] = [const { AtomicU64::new(0) }; MAX_CPUS];
+
+    /// Outcome of an SMP test.
+    pub struct Report {
+        /// CPUs started, CPU 0 included.
+        pub cpus: usize,
+        /// CPUs that reached the barrier.
+        pub arrived: usize,
+        /// CPUs that finished their rounds.
+        pub done: usize,
+        /// Value of the shared counter.
+        pub shared: u64,
+        /// CPUs whose own counter is not [`ROUNDS`].
+        pub bad_counters: usize,
+    }
+
+    impl Report {
+        /// The shared counter expected.
+        pub fn expected(&self) -> u64 {
+            self.cpus as u64 * ROUNDS
+        }
+
+        /// Whether every CPU took part and no increment was lost.
+        pub fn ok(&self) -> bool {
+            self.arrived == self.cpus
+                && self.done == self.cpus
+                && self.shared == self.expected()
+                && self.bad_counters == 0
+        }
+    }
+
+    impl fmt::Display for Report {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            write!(
+                f,
+                "smptest: {} CPUs, {} at the barrier, {} done, shared counter {} \
+                 (expected {}), {} bad per-CPU counters",
+                self.cpus,
+                self.arrived,
+                self.done,
+                self.shared,
+                self.expected(),
+                self.bad_counters
+            )
+        }
+    }
+
+    /// Spins until `count` reaches the number of CPUs, or `timed_out`;
+    /// returns whether it did.
+    fn wait_all(count: &AtomicUsize, timed_out: &impl Fn() -> bool) -> bool {
+        loop {
+            let cpus = CPUS.load(Ordering::Acquire);
+            if cpus != 0 && count.load(Ordering::Acquire) >= cpus {
+                return true;
+            }
+            if timed_out() {
+                return false;
+            }
+            spin_loop();
+        }
+    }
+
+    /// The part of CPU `cpu`: says hello with `print`, waits at the
+    /// barrier until all CPUs are there, then makes its [`ROUNDS`]
+    /// increments. Gives up when `timed_out`; returns whether it finished.
+    pub fn cpu_main(cpu: usize, print: fn(fmt::Arguments), timed_out: impl Fn() -> bool) -> bool {
+        print(format_args!("smptest: hello from CPU {cpu}\n"));
+        ARRIVED.fetch_add(1, Ordering::AcqRel);
+        if !wait_all(&ARRIVED, &timed_out) {
+            return false;
+        }
+        for _ in 0..ROUNDS {
+            COUNTERS[cpu].fetch_add(1, Ordering::Relaxed);
+            SHARED.fetch_add(1, Ordering::Relaxed);
+        }
+        DONE.fetch_add(1, Ordering::Release);
+        true
+    }
+
+    /// Runs the test on CPU 0: starts CPUs 1, 2, ... with `start` until it
+    /// fails (or [`MAX_CPUS`] run), does its own part and waits for the
+    /// others, timed with `now` ticking at `tick_hz`. The started CPUs must
+    /// run [`cpu_main`].
+    pub fn run(
+        mut start: impl FnMut(usize) -> bool,
+        print: fn(fmt::Arguments),
+        now: fn() -> u64,
+        tick_hz: u64,
+    ) -> Report {
+        CPUS.store(0, Ordering::Relaxed);
+        ARRIVED.store(0, Ordering::Relaxed);
+        DONE.store(0, Ordering::Relaxed);
+        SHARED.store(0, Ordering::Relaxed);
+        for counter in &COUNTERS {
+            counter.store(0, Ordering::Relaxed);
+        }
+
+        let mut cpus = 1;
+        while cpus < MAX_CPUS && start(cpus) {
+            cpus += 1;
+        }
+        CPUS.store(cpus, Ordering::Release);
+        let deadline = now() + TIMEOUT_SECS * tick_hz;
+        let timed_out = || now() >= deadline;
+        if cpu_main(0, print, timed_out) {
+            wait_all(&DONE, &timed_out);
+        }
+        Report {
+            cpus,
+            arrived: ARRIVED.load(Ordering::Acquire),
+            done: DONE.load(Ordering::Acquire),
+            shared: SHARED.load(Ordering::Relaxed),
+            bad_counters: COUNTERS[..cpus]
+                .iter()
+                .filter(|c| c.load(Ordering::Relaxed) != ROUNDS)
+                .count(),
+        }
+    }
+}
+
 // ══════════════════════════════════════════════════════════════
 //  RISC-V 64 — Full ArceOS guest with paging
 // ══════════════════════════════════════════════════════════════
@@ -19,10 +848,256 @@
 extern crate axstd as std;
 
 #[cfg(all(feature = "axstd", target_arch = "riscv64"))]
-use std::os::arceos::modules::axhal::mem::phys_to_virt;
+extern crate alloc;
+
+#[cfg(all(feature = "axstd", target_arch = "riscv64"))]
+use std::os::arceos::modules::axhal::mem::{phys_to_virt, virt_to_phys};
 
 #[cfg(target_arch = "riscv64")]
 const PFLASH_START: usize = 0x2200_0000;
+/// Bytes of the heap buffer the memory stress test spreads its pages over
+/// (of 16 MB of guest RAM).
+#[cfg(target_arch = "riscv64")]
+const MEMSTRESS_SPAN: usize = 0x40_0000;
+/// The first virtio-mmio slot, the one of the VM's disk.
+#[cfg(target_arch = "riscv64")]
+const VIRTIO_BLK_MMIO: usize = 0x1000_1000;
+
+/// Reads the kernel command line into `buf` with the GET_CMDLINE call of
+/// the hypervisor's SBI extension.
+#[cfg(all(feature = "axstd", target_arch = "riscv64"))]
+fn read_cmdline(buf: &mut [u8]) -> &[u8] {
+    let gpa = virt_to_phys((buf.as_mut_ptr() as usize).into()).as_usize();
+    let len = hvcall_abi::guest::call(hvcall_abi::sbi::FID_GET_CMDLINE, gpa, buf.len());
+    &buf[..len.unwrap_or(0).min(buf.len())]
+}
+
+/// Runs the memory stress test on a fresh heap buffer; returns whether it
+/// read back what it wrote.
+#[cfg(all(feature = "axstd", target_arch = "riscv64"))]
+fn memstress(pages: usize) -> bool {
+    use alloc::alloc::{Layout, alloc, dealloc};
+    use std::os::arceos::modules::axhal::time::monotonic_time_nanos;
+
+    let layout = Layout::from_size_align(MEMSTRESS_SPAN, memstress::PAGE_SIZE).unwrap();
+    // Not zeroed: the pages are first touched by the test.
+    let buf = unsafe { alloc(layout) };
+    if buf.is_null() {
+        println!("memstress: cannot allocate {} KB", MEMSTRESS_SPAN / 1024);
+        return false;
+    }
+    let report = unsafe {
+        memstress::run(
+            buf as usize,
+            MEMSTRESS_SPAN,
+            pages,
+            monotonic_time_nanos,
+            1_000_000_000,
+        )
+    };
+    unsafe { dealloc(buf, layout) };
+    println!("{report}");
+    report.errors == 0
+}
+
+/// Timer interrupts taken since the timer test started.
+#[cfg(all(feature = "axstd", target_arch = "riscv64"))]
+static TIMER_TICKS: core::sync::atomic::AtomicU64 = core::sync::atomic::AtomicU64::new(0);
+
+/// Counts the interrupts of the ArceOS timer for `secs` seconds, waiting
+/// for them with WFI; returns whether there were any.
+#[cfg(all(feature = "axstd", target_arch = "riscv64"))]
+fn timertest(secs: u64) -> bool {
+    use core::sync::atomic::Ordering;
+    use std::os::arceos::modules::{axconfig::TICKS_PER_SEC, axhal};
+
+    fn count_tick(irq: usize) {
+        if irq == axhal::time::irq_num() {
+            TIMER_TICKS.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+    if !axhal::irq::register_irq_hook(count_tick) {
+        println!("timertest: IRQ hook taken");
+        return false;
+    }
+
+    let start = axhal::time::monotonic_time_nanos();
+    let end = start + secs * 1_000_000_000;
+    TIMER_TICKS.store(0, Ordering::Relaxed);
+    let mut now = start;
+    while now < end {
+        axhal::asm::wait_for_irqs();
+        now = axhal::time::monotonic_time_nanos();
+    }
+    let report = timertest::Report {
+        ticks: TIMER_TICKS.load(Ordering::Relaxed),
+        hz: TICKS_PER_SEC as u64,
+        elapsed_ms: (now - start) / 1_000_000,
+        max_latency_us: None,
+    };
+    println!("{report}");
+    report.ok()
+}
+
+/// Runs the virtio-blk test on the device in the first virtio-mmio slot,
+/// waiting for its requests with WFI; returns whether it passed.
+#[cfg(all(feature = "axstd", target_arch = "riscv64"))]
+fn blktest() -> bool {
+    use std::os::arceos::modules::axhal;
+
+    fn gpa(addr: usize) -> u64 {
+        virt_to_phys(addr.into()).as_usize() as u64
+    }
+    let base = phys_to_virt(VIRTIO_BLK_MMIO.into()).as_usize();
+    let result = match unsafe { blktest::Mmio::probe(base) } {
+        Some(mmio) => unsafe { blktest::run(mmio, gpa, axhal::asm::wait_for_irqs) },
+        None => Err("no virtio-blk device"),
+    };
+    match result {
+        Ok(report) => {
+            println!("{report}");
+            report.errors == 0
+        }
+        Err(err) => {
+            println!("blktest: {err}");
+            false
+        }
+    }
+}
+
+/// Stack of each hart of the SMP test (a power of two).
+#[cfg(all(feature = "axstd", target_arch = "riscv64"))]
+const SMP_STACK_SIZE: usize = 0x4000;
+
+/// What a secondary hart needs to turn on paging, read by it with the MMU
+/// off.
+#[cfg(all(feature = "axstd", target_arch = "riscv64"))]
+#[repr(C)]
+struct SecondaryBoot {
+    /// `satp` of hart 0.
+    satp: usize,
+    /// Virtual address of `gk_secondary_virt`.
+    entry: usize,
+    /// Virtual address of the stacks.
+    stacks: usize,
+}
+
+#[cfg(all(feature = "axstd", target_arch = "riscv64"))]
+static mut SECONDARY_BOOT: SecondaryBoot = SecondaryBoot {
+    satp: 0,
+    entry: 0,
+    stacks: 0,
+};
+
+#[cfg(all(feature = "axstd", target_arch = "riscv64"))]
+#[repr(C, align(16))]
+struct SmpStacks([[u8; SMP_STACK_SIZE]; smptest::MAX_CPUS]);
+
+#[cfg(all(feature = "axstd", target_arch = "riscv64"))]
+static mut SMP_STACKS: SmpStacks = SmpStacks([[0; SMP_STACK_SIZE]; smptest::MAX_CPUS]);
+
+// Entry of the secondary harts, at its physical address: a0 = hart id,
+// a1 = physical address of SECONDARY_BOOT, the MMU off. Turning on hart
+// 0's page table makes the next fetch from a physical address fault into
+// stvec, at the kernel's virtual address (or, if the kernel maps the
+// physical address too, `jr` gets there).
+#[cfg(all(feature = "axstd", target_arch = "riscv64"))]
+core::arch::global_asm!(
+    ".balign 4",
+    ".global gk_secondary_start",
+    "gk_secondary_start:",
+    "ld t0, 0(a1)",
+    "ld t1, 8(a1)",
+    "ld sp, 16(a1)",
+    "addi t2, a0, 1",
+    "slli t2, t2, {stack_shift}",
+    "add sp, sp, t2",
+    "csrw stvec, t1",
+    "sfence.vma",
+    "csrw satp, t0",
+    "sfence.vma",
+    "jr t1",
+    ".balign 4",
+    ".global gk_secondary_virt",
+    "gk_secondary_virt:",
+    "call {main}",
+    stack_shift = const SMP_STACK_SIZE.trailing_zeros(),
+    main = sym gk_secondary_main,
+);
+
+#[cfg(all(feature = "axstd", target_arch = "riscv64"))]
+unsafe extern "C" {
+    fn gk_secondary_start();
+    fn gk_secondary_virt();
+}
+
+/// Prints for the SMP test, with SBI legacy putchar: the secondary harts
+/// run without ArceOS and its per-CPU data.
+#[cfg(all(feature = "axstd", target_arch = "riscv64"))]
+fn smp_print(args: core::fmt::Arguments) {
+    use core::sync::atomic::{AtomicBool, Ordering};
+
+    /// Keeps the lines of the harts apart.
+    static LOCK: AtomicBool = AtomicBool::new(false);
+
+    struct Sbi;
+
+    impl core::fmt::Write for Sbi {
+        fn write_str(&mut self, s: &str) -> core::fmt::Result {
+            for b in s.bytes() {
+                // DBCN is past this sbi-rt; the hypervisor takes both.
+                #[allow(deprecated)]
+                sbi_rt::legacy::console_putchar(b as usize);
+            }
+            Ok(())
+        }
+    }
+
+    while LOCK
+        .compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed)
+        .is_err()
+    {
+        core::hint::spin_loop();
+    }
+    let _ = core::fmt::Write::write_fmt(&mut Sbi, args);
+    LOCK.store(false, Ordering::Release);
+}
+
+/// A secondary hart of the SMP test, on its stack with paging on.
+#[cfg(all(feature = "axstd", target_arch = "riscv64"))]
+extern "C" fn gk_secondary_main(hartid: usize) -> ! {
+    smptest::cpu_main(hartid, smp_print, || false);
+    sbi_rt::hart_stop();
+    loop {
+        std::os::arceos::modules::axhal::asm::wait_for_irqs();
+    }
+}
+
+/// Starts the other harts of the VM through SBI HSM for the SMP test;
+/// returns whether every hart that started took part and no increment was
+/// lost.
+#[cfg(all(feature = "axstd", target_arch = "riscv64"))]
+fn smptest() -> bool {
+    use std::os::arceos::modules::axhal::time::monotonic_time_nanos;
+
+    let boot = unsafe {
+        let boot = &mut *core::ptr::addr_of_mut!(SECONDARY_BOOT);
+        core::arch::asm!("csrr {}, satp", out(reg) boot.satp);
+        boot.entry = gk_secondary_virt as *const () as usize;
+        boot.stacks = core::ptr::addr_of!(SMP_STACKS) as usize;
+        boot as *const SecondaryBoot as usize
+    };
+    let start_pa = virt_to_phys((gk_secondary_start as *const () as usize).into()).as_usize();
+    let boot_pa = virt_to_phys(boot.into()).as_usize();
+    let report = smptest::run(
+        |hart| sbi_rt::hart_start(hart, start_pa, boot_pa).is_ok(),
+        smp_print,
+        monotonic_time_nanos,
+        1_000_000_000,
+    );
+    println!("{report}");
+    report.ok()
+}
 
 #[cfg(all(feature = "axstd", target_arch = "riscv64"))]
 #[unsafe(no_mangle)]
@@ -38,46 +1113,76 @@ fn main() {
         let magic = (*ptr).to_ne_bytes();
         println!(
             "Got pflash magic: {}",
-            core::str::from_utf8(&magic).unwrap()
+            core::str::from_utf8(&magic).unwrap_or("???")
         );
+        let mut ok = magic == *b"pfld";
+        let mut buf = [0u8; cmdline::CMDLINE_MAX];
+        let cmdline = read_cmdline(&mut buf);
+        if let Some(pages) = cmdline::arg(cmdline, "memstress") {
+            ok &= memstress(pages as usize);
+        }
+        if let Some(secs) = cmdline::arg(cmdline, "timertest") {
+            ok &= timertest(secs.min(timertest::MAX_SECS));
+        }
+        if cmdline::arg(cmdline, "blktest").is_some_and(|on| on != 0) {
+            ok &= blktest();
+        }
+        if cmdline::arg(cmdline, "smptest").is_some_and(|on| on != 0) {
+            ok &= smptest();
+        }
+        if !ok {
+            // Exit code 1 in the platform-specific SBI SRST reasons.
+            sbi_rt::system_reset(sbi_rt::Shutdown, hvcall_abi::sbi::exit_reason(1));
+        }
     }
 }
 
 // ══════════════════════════════════════════════════════════════
 //  AArch64 — Bare-metal EL0 guest, SVC hypercalls
 //
-//  Hypercall ABI (SVC #0):
+//  Hypercall ABI (SVC #0, see `hvcall_abi`):
 //    x8 = function ID:
-//      1 = putchar (x0 = character)
-//      2 = exit
+//      PUTCHAR     = putchar (x0 = character)
+//      EXIT        = exit (x0 = exit code)
+//      GET_CMDLINE = copy the command line (x0 = buffer, x1 = size)
+//
+//  Guest virtual addresses are guest physical addresses.
 // ══════════════════════════════════════════════════════════════
 
 #[cfg(target_arch = "aarch64")]
 const PFLASH_START: usize = 0x0400_0000;
+/// Guest RAM the memory stress test spreads its pages over: above the
+/// stack (0x4100_0000, 32 KB) and below the device tree in the last 2 MB
+/// of the 32 MB of guest RAM.
+#[cfg(target_arch = "aarch64")]
+const MEMSTRESS_BASE: usize = 0x4110_0000;
+#[cfg(target_arch = "aarch64")]
+const MEMSTRESS_SIZE: usize = 0xC0_0000;
+/// The first virtio-mmio slot, the one of the VM's disk.
+#[cfg(target_arch = "aarch64")]
+const VIRTIO_BLK_MMIO: usize = 0x0a00_0000;
 
 #[cfg(target_arch = "aarch64")]
 mod aarch64_guest {
-    use super::PFLASH_START;
+    use super::{
+        MEMSTRESS_BASE, MEMSTRESS_SIZE, PFLASH_START, VIRTIO_BLK_MMIO, blktest, cmdline, memstress,
+        smptest, timertest,
+    };
+    use core::arch::asm;
+    use hvcall_abi::guest::{exit as svc_exit, putchar as svc_putchar};
 
-    #[inline(always)]
-    fn svc_putchar(c: u8) {
-        unsafe {
-            core::arch::asm!(
-                "svc #0",
-                in("x0") c as u64,
-                in("x8") 1u64, // putchar
-                options(nomem, nostack),
-            );
-        }
-    }
+    /// `CNTV_CTL_EL0.ENABLE`.
+    const CNTV_CTL_ENABLE: u64 = 1 << 0;
+    /// `CNTV_CTL_EL0.ISTATUS`: the timer condition is met.
+    const CNTV_CTL_ISTATUS: u64 = 1 << 2;
 
-    fn svc_exit() -> ! {
-        unsafe {
-            core::arch::asm!(
-                "svc #0",
-                in("x8") 2u64, // exit
-                options(noreturn, nomem, nostack),
-            );
+    /// Console output for `core::fmt`.
+    struct Console;
+
+    impl core::fmt::Write for Console {
+        fn write_str(&mut self, s: &str) -> core::fmt::Result {
+            print_str(s);
+            Ok(())
         }
     }
 
@@ -100,6 +1205,123 @@ mod aarch64_guest {
         }
     }
 
+    /// Reads the kernel command line into `buf`.
+    fn read_cmdline(buf: &mut [u8]) -> &[u8] {
+        let len = hvcall_abi::guest::call(
+            hvcall_abi::GET_CMDLINE,
+            buf.as_mut_ptr() as u64,
+            buf.len() as u64,
+        );
+        match len {
+            hvcall_abi::ERROR => &[],
+            len => &buf[..(len as usize).min(buf.len())],
+        }
+    }
+
+    /// Reads the virtual counter.
+    fn cntvct() -> u64 {
+        let ticks;
+        unsafe { asm!("isb", "mrs {}, cntvct_el0", out(reg) ticks) };
+        ticks
+    }
+
+    /// Frequency of the virtual counter.
+    fn cntfrq() -> u64 {
+        let freq;
+        unsafe { asm!("mrs {}, cntfrq_el0", out(reg) freq) };
+        freq
+    }
+
+    /// Runs the memory stress test, timed with the virtual counter; returns
+    /// whether it read back what it wrote.
+    fn memstress(pages: usize) -> bool {
+        let report =
+            unsafe { memstress::run(MEMSTRESS_BASE, MEMSTRESS_SIZE, pages, cntvct, cntfrq()) };
+        let _ = core::fmt::Write::write_fmt(&mut Console, format_args!("{report}\n"));
+        report.errors == 0
+    }
+
+    /// Runs the virtual timer at [`timertest::TICK_HZ`] for `secs` seconds;
+    /// returns whether it ticked.
+    ///
+    /// EL0 takes no interrupts: the guest waits for each deadline with WFI,
+    /// which the hypervisor idles until the timer condition is met, and
+    /// polls `CNTV_CTL_EL0.ISTATUS`.
+    fn timertest(secs: u64) -> bool {
+        let freq = cntfrq();
+        let period = freq / timertest::TICK_HZ;
+        let start = cntvct();
+        let end = start + secs * freq;
+        let (mut ticks, mut max_late) = (0, 0);
+        let mut deadline = start + period;
+        unsafe {
+            asm!(
+                "msr cntv_cval_el0, {cval}",
+                "msr cntv_ctl_el0, {ctl}",
+                "isb",
+                cval = in(reg) deadline,
+                ctl = in(reg) CNTV_CTL_ENABLE,
+            );
+        }
+        while deadline <= end {
+            unsafe { asm!("wfi") };
+            let ctl: u64;
+            unsafe { asm!("mrs {}, cntv_ctl_el0", out(reg) ctl) };
+            if ctl & CNTV_CTL_ISTATUS == 0 {
+                continue;
+            }
+            max_late = max_late.max(cntvct() - deadline);
+            ticks += 1;
+            deadline += period;
+            unsafe { asm!("msr cntv_cval_el0, {}", "isb", in(reg) deadline) };
+        }
+        unsafe { asm!("msr cntv_ctl_el0, xzr", "isb") };
+
+        let report = timertest::Report {
+            ticks,
+            hz: timertest::TICK_HZ,
+            elapsed_ms: (cntvct() - start) * 1000 / freq,
+            max_latency_us: Some(max_late * 1_000_000 / freq),
+        };
+        let _ = core::fmt::Write::write_fmt(&mut Console, format_args!("{report}\n"));
+        report.ok()
+    }
+
+    /// Runs the virtio-blk test on the device in the first virtio-mmio
+    /// slot; returns whether it passed. The guest waits for its requests
+    /// with WFI, which the hypervisor idles until the device interrupt.
+    fn blktest() -> bool {
+        fn wfi() {
+            unsafe { asm!("wfi") };
+        }
+        let result = match unsafe { blktest::Mmio::probe(VIRTIO_BLK_MMIO) } {
+            Some(mmio) => unsafe { blktest::run(mmio, |addr| addr as u64, wfi) },
+            None => Err("no virtio-blk device"),
+        };
+        match result {
+            Ok(report) => {
+                let _ = core::fmt::Write::write_fmt(&mut Console, format_args!("{report}\n"));
+                report.errors == 0
+            }
+            Err(err) => {
+                let _ = core::fmt::Write::write_fmt(&mut Console, format_args!("blktest: {err}\n"));
+                false
+            }
+        }
+    }
+
+    fn print_args(args: core::fmt::Arguments) {
+        let _ = core::fmt::Write::write_fmt(&mut Console, args);
+    }
+
+    /// Runs the SMP test; returns whether it passed. The hypervisor gives
+    /// the guest a single vCPU, so there is no other CPU to start.
+    fn smptest() -> bool {
+        let report = smptest::run(|_| false, print_args, cntvct, cntfrq());
+        print_args(format_args!("{report}\n"));
+        report.ok()
+    }
+
     #[unsafe(no_mangle)]
     pub extern "C" fn _start() -> ! {
         print_str("\n       d8888                            .d88888b.   .d8888b.\n");
@@ -128,17 +1350,40 @@ mod aarch64_guest {
         }
         print_str("\n");
 
-        svc_exit();
+        let mut ok = &magic == b"pfld";
+        let mut buf = [0u8; cmdline::CMDLINE_MAX];
+        let cmdline = read_cmdline(&mut buf);
+        if let Some(pages) = cmdline::arg(cmdline, "memstress") {
+            ok &= memstress(pages as usize);
+        }
+        if let Some(secs) = cmdline::arg(cmdline, "timertest") {
+            ok &= timertest(secs.min(timertest::MAX_SECS));
+        }
+        if cmdline::arg(cmdline, "blktest").is_some_and(|on| on != 0) {
+            ok &= blktest();
+        }
+        if cmdline::arg(cmdline, "smptest").is_some_and(|on| on != 0) {
+            ok &= smptest();
+        }
+        svc_exit(if ok { 0 } else { 1 });
     }
 }
 
 // ══════════════════════════════════════════════════════════════
 //  x86_64 — Bare-metal long-mode guest, VMMCALL hypercalls
 //
-//  Hypercall ABI (VMMCALL):
+//  Hypercall ABI (VMMCALL, see `hvcall_abi::x86`):
 //    rax encoding:
-//      rax & 0xFF == 1  : putchar (char = (rax >> 8) & 0xFF)
-//      rax == 0x84000008: exit (PSCI SYSTEM_OFF convention)
+//      rax & 0xFF == PUTCHAR   : putchar (char = (rax >> 8) & 0xFF)
+//      rax & 0xFF == EXIT      : exit (code = rax >> 8)
+//      rax == psci::SYSTEM_OFF : exit with code 0 (PSCI convention)
+//      rax & 0xFF == GET_CMDLINE: copy the command line (rbx = buffer,
+//                                 rcx = size)
+//      rax & 0xFF == GET_TSC_KHZ: TSC frequency in kHz (in rax)
+//
+//  The timer test maps the local APIC at 0xFEE00000 into the boot page
+//  tables (PD at GPA 0x4000) and loads an IDT with a single gate, for the
+//  APIC timer vector.
 //
 //  We encode everything in RAX because AMD SVM only saves RAX
 //  in the VMCB; other GPRs are not accessible to the hypervisor
@@ -147,29 +1392,85 @@ mod aarch64_guest {
 
 #[cfg(target_arch = "x86_64")]
 const PFLASH_START: usize = 0xFFC0_0000;
+/// Guest RAM the memory stress test spreads its pages over: the upper
+/// half of the identity-mapped 2 MB, above the image and the stack.
+#[cfg(target_arch = "x86_64")]
+const MEMSTRESS_BASE: usize = 0x10_0000;
+#[cfg(target_arch = "x86_64")]
+const MEMSTRESS_SIZE: usize = 0x10_0000;
 
 #[cfg(target_arch = "x86_64")]
 mod x86_64_guest {
-    use super::PFLASH_START;
+    use super::{
+        MEMSTRESS_BASE, MEMSTRESS_SIZE, PFLASH_START, blktest, cmdline, memstress, smptest,
+        timertest,
+    };
+    use core::arch::{asm, global_asm};
+    use core::sync::atomic::{AtomicU64, Ordering};
+    use hvcall_abi::guest::{exit as vmmcall_exit, putchar as vmmcall_putchar};
 
-    #[inline(always)]
-    fn vmmcall_putchar(c: u8) {
-        unsafe {
-            core::arch::asm!(
-                "vmmcall",
-                in("rax") 1u64 | ((c as u64) << 8), // func=1, char in bits [15:8]
-                options(nomem, nostack),
-            );
-        }
+    /// Guest physical (and virtual) address of the local APIC.
+    const LAPIC_BASE: usize = 0xFEE0_0000;
+    const LAPIC_EOI: usize = 0x0B0;
+    const LAPIC_SVR: usize = 0x0F0;
+    const LAPIC_LVT_TIMER: usize = 0x320;
+    const LAPIC_TIMER_ICR: usize = 0x380;
+    const LAPIC_TIMER_DCR: usize = 0x3E0;
+    /// `SVR`: APIC software enable, spurious vector 0xFF.
+    const SVR_ENABLE: u32 = 1 << 8 | 0xFF;
+    /// `LVT`: the entry is masked.
+    const LVT_MASKED: u32 = 1 << 16;
+    /// `LVT timer`: periodic mode.
+    const LVT_TIMER_PERIODIC: u32 = 1 << 17;
+    /// `DCR`: divide by 1.
+    const DCR_DIV_1: u32 = 0xB;
+    /// Frequency the APIC timer counts at before division (the hypervisor's
+    /// virtual local APIC counts nanoseconds).
+    const APIC_BUS_HZ: u64 = 1_000_000_000;
+    /// Vector of the timer interrupt, the only entry of the IDT.
+    const TIMER_VECTOR: usize = 0x20;
+    /// 64-bit code segment of the boot GDT.
+    const KERNEL_CS: u16 = 0x10;
+    /// 2 MB page directory of the 3-4 GB range in the boot page tables,
+    /// with the entry of the local APIC.
+    const PD3: usize = 0x4000;
+    const PD3_LAPIC_ENTRY: usize = (LAPIC_BASE - 0xC000_0000) >> 21;
+
+    /// Timer interrupts taken since the timer test started.
+    static TIMER_TICKS: AtomicU64 = AtomicU64::new(0);
+
+    /// Interrupt descriptor table: 16-byte gates up to the timer vector.
+    static mut IDT: [u64; 2 * (TIMER_VECTOR + 1)] = [0; 2 * (TIMER_VECTOR + 1)];
+
+    // The timer interrupt: counts the tick and signals EOI to the local
+    // APIC.
+    global_asm!(
+        ".global gk_timer_irq",
+        "gk_timer_irq:",
+        "push rax",
+        "push rcx",
+        "lock inc qword ptr [rip + {ticks}]",
+        "mov rax, {eoi}",
+        "xor ecx, ecx",
+        "mov dword ptr [rax], ecx",
+        "pop rcx",
+        "pop rax",
+        "iretq",
+        ticks = sym TIMER_TICKS,
+        eoi = const LAPIC_BASE + LAPIC_EOI,
+    );
+
+    unsafe extern "C" {
+        fn gk_timer_irq();
     }
 
-    fn vmmcall_exit() -> ! {
-        unsafe {
-            core::arch::asm!(
-                "vmmcall",
-                in("rax") 0x84000008u64, // exit
-                options(noreturn, nomem, nostack),
-            );
+    /// Console output for `core::fmt`.
+    struct Console;
+
+    impl core::fmt::Write for Console {
+        fn write_str(&mut self, s: &str) -> core::fmt::Result {
+            print_str(s);
+            Ok(())
         }
     }
 
@@ -192,6 +1493,143 @@ mod x86_64_guest {
         }
     }
 
+    /// Reads the kernel command line into `buf`.
+    fn read_cmdline(buf: &mut [u8]) -> &[u8] {
+        let len = hvcall_abi::guest::call(
+            hvcall_abi::GET_CMDLINE,
+            buf.as_mut_ptr() as u64,
+            buf.len() as u64,
+        );
+        match len {
+            hvcall_abi::ERROR => &[],
+            len => &buf[..(len as usize).min(buf.len())],
+        }
+    }
+
+    fn rdtsc() -> u64 {
+        unsafe { core::arch::x86_64::_rdtsc() }
+    }
+
+    /// The TSC frequency in kHz, from GET_TSC_KHZ.
+    fn tsc_khz() -> u64 {
+        hvcall_abi::guest::call(hvcall_abi::GET_TSC_KHZ, 0, 0)
+    }
+
+    /// Runs the memory stress test, timed with the TSC; returns whether it
+    /// read back what it wrote.
+    fn memstress(pages: usize) -> bool {
+        let report = unsafe {
+            memstress::run(
+                MEMSTRESS_BASE,
+                MEMSTRESS_SIZE,
+                pages,
+                rdtsc,
+                tsc_khz() * 1000,
+            )
+        };
+        let _ = core::fmt::Write::write_fmt(&mut Console, format_args!("{report}\n"));
+        report.errors == 0
+    }
+
+    fn lapic_write(reg: usize, value: u32) {
+        unsafe { core::ptr::write_volatile((LAPIC_BASE + reg) as *mut u32, value) };
+    }
+
+    /// Maps the local APIC (uncached) into the boot page tables and loads
+    /// an IDT with the timer interrupt.
+    fn setup_interrupts() {
+        const PTE_P_RW_PCD_PS: u64 = 1 | 1 << 1 | 1 << 4 | 1 << 7;
+        unsafe {
+            let pde = (PD3 + PD3_LAPIC_ENTRY * 8) as *mut u64;
+            core::ptr::write_volatile(pde, LAPIC_BASE as u64 | PTE_P_RW_PCD_PS);
+            asm!("mov {tmp}, cr3", "mov cr3, {tmp}", tmp = out(reg) _);
+
+            // 64-bit interrupt gate: present, DPL 0, type 0xE.
+            let handler = gk_timer_irq as *const () as u64;
+            let idt = &mut *core::ptr::addr_of_mut!(IDT);
+            idt[2 * TIMER_VECTOR] = (handler & 0xFFFF)
+                | (KERNEL_CS as u64) << 16
+                | 0x8E00 << 32
+                | (handler >> 16 & 0xFFFF) << 48;
+            idt[2 * TIMER_VECTOR + 1] = handler >> 32;
+            let idtr: [u16; 5] = {
+                let base = idt.as_ptr() as u64;
+                [
+                    (core::mem::size_of_val(idt) - 1) as u16,
+                    base as u16,
+                    (base >> 16) as u16,
+                    (base >> 32) as u16,
+                    (base >> 48) as u16,
+                ]
+            };
+            asm!("lidt [{}]", in(reg) idtr.as_ptr(), options(readonly, nostack));
+        }
+    }
+
+    /// Runs the local APIC timer periodically at [`timertest::TICK_HZ`] for
+    /// `secs` seconds, halting until each interrupt, and counts the
+    /// interrupts; returns whether there were any.
+    fn timertest(secs: u64) -> bool {
+        setup_interrupts();
+        let khz = tsc_khz();
+        TIMER_TICKS.store(0, Ordering::Relaxed);
+        lapic_write(LAPIC_SVR, SVR_ENABLE);
+        lapic_write(LAPIC_TIMER_DCR, DCR_DIV_1);
+        lapic_write(LAPIC_LVT_TIMER, LVT_TIMER_PERIODIC | TIMER_VECTOR as u32);
+        let start = rdtsc();
+        lapic_write(LAPIC_TIMER_ICR, (APIC_BUS_HZ / timertest::TICK_HZ) as u32);
+
+        let end = start + secs * khz * 1000;
+        unsafe { asm!("sti") };
+        while rdtsc() < end {
+            unsafe { asm!("hlt") };
+        }
+        unsafe { asm!("cli") };
+        lapic_write(LAPIC_LVT_TIMER, LVT_MASKED);
+        lapic_write(LAPIC_TIMER_ICR, 0);
+
+        let report = timertest::Report {
+            ticks: TIMER_TICKS.load(Ordering::Relaxed),
+            hz: timertest::TICK_HZ,
+            elapsed_ms: (rdtsc() - start) / khz.max(1),
+            max_latency_us: None,
+        };
+        let _ = core::fmt::Write::write_fmt(&mut Console, format_args!("{report}\n"));
+        report.ok()
+    }
+
+    /// Runs the virtio-blk test on the first block device on the PCI bus;
+    /// returns whether it passed. Interrupts stay off, so the guest spins
+    /// while its requests are in flight.
+    fn blktest() -> bool {
+        let result = match blktest::LegacyPci::probe() {
+            Some(pci) => unsafe { blktest::run(pci, |addr| addr as u64, core::hint::spin_loop) },
+            None => Err("no virtio-blk device"),
+        };
+        match result {
+            Ok(report) => {
+                let _ = core::fmt::Write::write_fmt(&mut Console, format_args!("{report}\n"));
+                report.errors == 0
+            }
+            Err(err) => {
+                let _ = core::fmt::Write::write_fmt(&mut Console, format_args!("blktest: {err}\n"));
+                false
+            }
+        }
+    }
+
+    fn print_args(args: core::fmt::Arguments) {
+        let _ = core::fmt::Write::write_fmt(&mut Console, args);
+    }
+
+    /// Runs the SMP test; returns whether it passed. The hypervisor gives
+    /// the guest a single vCPU, so there is no other CPU to start.
+    fn smptest() -> bool {
+        let report = smptest::run(|_| false, print_args, rdtsc, tsc_khz() * 1000);
+        print_args(format_args!("{report}\n"));
+        report.ok()
+    }
+
     #[unsafe(no_mangle)]
     pub extern "C" fn _start() -> ! {
         print_str("\n       d8888                            .d88888b.   .d8888b.\n");
@@ -220,7 +1658,22 @@ mod x86_64_guest {
         }
         print_str("\n");
 
-        vmmcall_exit();
+        let mut ok = &magic == b"pfld";
+        let mut buf = [0u8; cmdline::CMDLINE_MAX];
+        let cmdline = read_cmdline(&mut buf);
+        if let Some(pages) = cmdline::arg(cmdline, "memstress") {
+            ok &= memstress(pages as usize);
+        }
+        if let Some(secs) = cmdline::arg(cmdline, "timertest") {
+            ok &= timertest(secs.min(timertest::MAX_SECS));
+        }
+        if cmdline::arg(cmdline, "blktest").is_some_and(|on| on != 0) {
+            ok &= blktest();
+        }
+        if cmdline::arg(cmdline, "smptest").is_some_and(|on| on != 0) {
+            ok &= smptest();
+        }
+        vmmcall_exit(if ok { 0 } else { 1 });
     }
 }
 
@@ -1,3 +0,0 @@
-pub mod hvc;
-pub mod regs;
-pub mod vcpu;
@@ -1,186 +0,0 @@
-use core::arch::global_asm;
-use core::mem::size_of;
-
-use super::regs::{GeneralPurposeRegisters, GprIndex};
-use memoffset::offset_of;
-
-/// Hypervisor GPR and CSR state which must be saved/restored when entering/exiting virtualization.
-#[derive(Default)]
-#[repr(C)]
-struct HypervisorCpuState {
-    gprs: GeneralPurposeRegisters,
-    sstatus: usize,
-    scounteren: usize,
-    stvec: usize,
-    sscratch: usize,
-}
-
-/// Guest GPR and CSR state which must be saved/restored when exiting/entering virtualization.
-#[derive(Default)]
-#[repr(C)]
-pub struct GuestCpuState {
-    pub gprs: GeneralPurposeRegisters,
-    pub sstatus: usize,
-    pub hstatus: usize,
-    pub scounteren: usize,
-    pub sepc: usize,
-}
-
-/// The CSRs that are only in effect when virtualization is enabled (V=1) and must be saved and
-/// restored whenever we switch between VMs.
-#[derive(Default)]
-#[repr(C)]
-pub struct GuestVsCsrs {
-    htimedelta: usize,
-    vsstatus: usize,
-    vsie: usize,
-    vstvec: usize,
-    vsscratch: usize,
-    vsepc: usize,
-    vscause: usize,
-    vstval: usize,
-    vsatp: usize,
-    vstimecmp: usize,
-}
-
-/// Virtualized HS-level CSRs that are used to emulate (part of) the hypervisor extension for the
-/// guest.
-#[derive(Default)]
-#[repr(C)]
-pub struct GuestVirtualHsCsrs {
-    hie: usize,
-    hgeie: usize,
-    hgatp: usize,
-}
-
-/// CSRs written on an exit from virtualization that are used by the hypervisor to determine the cause
-/// of the trap.
-#[derive(Default, Clone)]
-#[repr(C)]
-pub struct VmCpuTrapState {
-    pub scause: usize,
-    pub stval: usize,
-    pub htval: usize,
-    pub htinst: usize,
-}
-
-/// (v)CPU register state that must be saved or restored when entering/exiting a VM or switching
-/// between VMs.
-#[derive(Default)]
-#[repr(C)]
-pub struct VmCpuRegisters {
-    // CPU state that's shared between our's and the guest's execution environment. Saved/restored
-    // when entering/exiting a VM.
-    hyp_regs: HypervisorCpuState,
-    pub guest_regs: GuestCpuState,
-
-    // CPU state that only applies when V=1, e.g. the VS-level CSRs. Saved/restored on activation of
-    // the vCPU.
-    vs_csrs: GuestVsCsrs,
-
-    // Virtualized HS-level CPU state.
-    virtual_hs_csrs: GuestVirtualHsCsrs,
-
-    // Read on VM exit.
-    pub trap_csrs: VmCpuTrapState,
-}
-
-#[allow(dead_code)]
-const fn hyp_gpr_offset(index: GprIndex) -> usize {
-    offset_of!(VmCpuRegisters, hyp_regs)
-        + offset_of!(HypervisorCpuState, gprs)
-        + (index as usize) * size_of::<u64>()
-}
-
-#[allow(dead_code)]
-const fn guest_gpr_offset(index: GprIndex) -> usize {
-    offset_of!(VmCpuRegisters, guest_regs)
-        + offset_of!(GuestCpuState, gprs)
-        + (index as usize) * size_of::<u64>()
-}
-
-#[allow(unused_macros)]
-macro_rules! hyp_csr_offset {
-    ($reg:tt) => {
-        offset_of!(VmCpuRegisters, hyp_regs) + offset_of!(HypervisorCpuState, $reg)
-    };
-}
-
-#[allow(unused_macros)]
-macro_rules! guest_csr_offset {
-    ($reg:tt) => {
-        offset_of!(VmCpuRegisters, guest_regs) + offset_of!(GuestCpuState, $reg)
-    };
-}
-
-global_asm!(
-    include_str!("guest.S"),
-    hyp_ra = const hyp_gpr_offset(GprIndex::RA),
-    hyp_gp = const hyp_gpr_offset(GprIndex::GP),
-    hyp_tp = const hyp_gpr_offset(GprIndex::TP),
-    hyp_s0 = const hyp_gpr_offset(GprIndex::S0),
-    hyp_s1 = const hyp_gpr_offset(GprIndex::S1),
-    hyp_a1 = const hyp_gpr_offset(GprIndex::A1),
-    hyp_a2 = const hyp_gpr_offset(GprIndex::A2),
-    hyp_a3 = const hyp_gpr_offset(GprIndex::A3),
-    hyp_a4 = const hyp_gpr_offset(GprIndex::A4),
-    hyp_a5 = const hyp_gpr_offset(GprIndex::A5),
-    hyp_a6 = const hyp_gpr_offset(GprIndex::A6),
-    hyp_a7 = const hyp_gpr_offset(GprIndex::A7),
-    hyp_s2 = const hyp_gpr_offset(GprIndex::S2),
-    hyp_s3 = const hyp_gpr_offset(GprIndex::S3),
-    hyp_s4 = const hyp_gpr_offset(GprIndex::S4),
-    hyp_s5 = const hyp_gpr_offset(GprIndex::S5),
-    hyp_s6 = const hyp_gpr_offset(GprIndex::S6),
-    hyp_s7 = const hyp_gpr_offset(GprIndex::S7),
-    hyp_s8 = const hyp_gpr_offset(GprIndex::S8),
-    hyp_s9 = const hyp_gpr_offset(GprIndex::S9),
-    hyp_s10 = const hyp_gpr_offset(GprIndex::S10),
-    hyp_s11 = const hyp_gpr_offset(GprIndex::S11),
-    hyp_sp = const hyp_gpr_offset(GprIndex::SP),
-    hyp_sstatus = const hyp_csr_offset!(sstatus),
-    hyp_scounteren = const hyp_csr_offset!(scounteren),
-    hyp_stvec = const hyp_csr_offset!(stvec),
-    hyp_sscratch = const hyp_csr_offset!(sscratch),
-    guest_ra = const guest_gpr_offset(GprIndex::RA),
-    guest_gp = const guest_gpr_offset(GprIndex::GP),
-    guest_tp = const guest_gpr_offset(GprIndex::TP),
-    guest_s0 = const guest_gpr_offset(GprIndex::S0),
-    guest_s1 = const guest_gpr_offset(GprIndex::S1),
-    guest_a0 = const guest_gpr_offset(GprIndex::A0),
-    guest_a1 = const guest_gpr_offset(GprIndex::A1),
-    guest_a2 = const guest_gpr_offset(GprIndex::A2),
-    guest_a3 = const guest_gpr_offset(GprIndex::A3),
-    guest_a4 = const guest_gpr_offset(GprIndex::A4),
-    guest_a5 = const guest_gpr_offset(GprIndex::A5),
-    guest_a6 = const guest_gpr_offset(GprIndex::A6),
-    guest_a7 = const guest_gpr_offset(GprIndex::A7),
-    guest_s2 = const guest_gpr_offset(GprIndex::S2),
-    guest_s3 = const guest_gpr_offset(GprIndex::S3),
-    guest_s4 = const guest_gpr_offset(GprIndex::S4),
-    guest_s5 = const guest_gpr_offset(GprIndex::S5),
-    guest_s6 = const guest_gpr_offset(GprIndex::S6),
-    guest_s7 = const guest_gpr_offset(GprIndex::S7),
-    guest_s8 = const guest_gpr_offset(GprIndex::S8),
-    guest_s9 = const guest_gpr_offset(GprIndex::S9),
-    guest_s10 = const guest_gpr_offset(GprIndex::S10),
-    guest_s11 = const guest_gpr_offset(GprIndex::S11),
-    guest_t0 = const guest_gpr_offset(GprIndex::T0),
-    guest_t1 = const guest_gpr_offset(GprIndex::T1),
-    guest_t2 = const guest_gpr_offset(GprIndex::T2),
-    guest_t3 = const guest_gpr_offset(GprIndex::T3),
-    guest_t4 = const guest_gpr_offset(GprIndex::T4),
-    guest_t5 = const guest_gpr_offset(GprIndex::T5),
-    guest_t6 = const guest_gpr_offset(GprIndex::T6),
-    guest_sp = const guest_gpr_offset(GprIndex::SP),
-
-    guest_sstatus = const guest_csr_offset!(sstatus),
-    guest_hstatus = const guest_csr_offset!(hstatus),
-    guest_scounteren = const guest_csr_offset!(scounteren),
-    guest_sepc = const guest_csr_offset!(sepc),
-
-);
-
-unsafe extern "C" {
-    pub fn _run_guest(state: *mut VmCpuRegisters);
-}
@@ -1,2 +0,0 @@
-pub mod svm;
-pub mod vmcb;
@@ -1,7 +1,18 @@
-use clap::{Parser, Subcommand};
-use std::io::Write;
+extern crate alloc;
+
+// The exit trace format, shared with the hypervisor.
+#[path = "../../guestaspace/src/exitlog.rs"]
+mod exitlog;
+// The guest package format, shared with the hypervisor.
+#[path = "../../guestaspace/src/package.rs"]
+mod package;
+
+use clap::{Args, Parser, Subcommand};
+use std::io::{BufRead, BufReader, Read, Seek, SeekFrom, Write};
 use std::path::{Path, PathBuf};
-use std::process::{self, Command};
+use std::process::{self, Command, Stdio};
+use std::sync::mpsc;
+use std::time::{Duration, Instant};
 
 /// ArceOS Guest Address Space — multi-architecture build & run tool
 #[derive(Parser)]
@@ -21,13 +32,341 @@ enum Cmd {
         /// Target architecture: riscv64, aarch64, x86_64
         #[arg(long, default_value = "riscv64")]
         arch: String,
+        #[command(flatten)]
+        payloads: PayloadOpts,
+        #[command(flatten)]
+        build: BuildOpts,
     },
     /// Build and run the kernel in QEMU
     Run {
         /// Target architecture: riscv64, aarch64, x86_64
         #[arg(long, default_value = "riscv64")]
         arch: String,
+        #[command(flatten)]
+        machine: MachineOpts,
+        #[command(flatten)]
+        payloads: PayloadOpts,
+        #[command(flatten)]
+        build: BuildOpts,
+        /// Build everything, then print the QEMU command instead of running it
+        #[arg(long)]
+        dry_run: bool,
+    },
+    /// Run the demo in QEMU and check its serial output for the expected markers
+    Test {
+        /// Target architectures: riscv64, aarch64, x86_64 (repeatable)
+        #[arg(long, default_value = "riscv64", conflicts_with = "all")]
+        arch: Vec<String>,
+        /// Test all architectures
+        #[arg(long)]
+        all: bool,
+        /// Seconds QEMU may run before the test fails
+        #[arg(long, default_value_t = 300)]
+        timeout: u64,
+        #[command(flatten)]
+        machine: MachineOpts,
+        #[command(flatten)]
+        build: BuildOpts,
+    },
+    /// Build, start QEMU halted with a GDB stub on :1234 and write a .gdbinit
+    Gdb {
+        /// Target architecture: riscv64, aarch64, x86_64
+        #[arg(long, default_value = "riscv64")]
+        arch: String,
+        /// Also launch GDB (in the foreground, QEMU in the background)
+        #[arg(long)]
+        launch: bool,
+        /// GDB executable used with --launch
+        #[arg(long, default_value = "gdb-multiarch")]
+        gdb: String,
+        #[command(flatten)]
+        machine: MachineOpts,
+        #[command(flatten)]
+        build: BuildOpts,
+    },
+    /// Manage a FAT32 disk image without rebuilding it
+    Disk {
+        #[command(subcommand)]
+        cmd: DiskCmd,
+    },
+    /// Print an exit trace a VM recorded (record=PATH in vms.conf)
+    Exits {
+        /// Disk image path
+        image: PathBuf,
+        /// Path of the trace in the disk image
+        #[arg(default_value = "/vm0.exits")]
+        path: String,
+        /// Print every exit, not only the count per class
+        #[arg(long)]
+        all: bool,
+    },
+    /// Package a guest ELF file with its entry point, segments and RAM size
+    /// for the hypervisor to load
+    Package {
+        /// Guest ELF file
+        elf: PathBuf,
+        /// Target architecture, which decides the load address: riscv64,
+        /// aarch64, x86_64
+        #[arg(long, default_value = "riscv64")]
+        arch: String,
+        /// Package path (the ELF path with a .gapk extension by default, .bin
+        /// with --flat)
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+        /// Guest RAM the image needs (bytes, or with a K, M or G suffix; 0
+        /// for the hypervisor's default)
+        #[arg(long, default_value = "0", value_parser = parse_size, conflicts_with = "flat")]
+        ram: u64,
+        /// Write a flat binary and its load descriptor (the output path with
+        /// .load appended) instead of a package
+        #[arg(long)]
+        flat: bool,
+    },
+}
+
+#[derive(Subcommand)]
+enum DiskCmd {
+    /// Create an empty FAT32 disk image
+    Create {
+        /// Disk image path
+        image: PathBuf,
+        /// Image size (bytes, or with a K, M or G suffix)
+        #[arg(long, default_value = "64M", value_parser = parse_size)]
+        size: u64,
+        /// Replace an existing image
+        #[arg(long)]
+        force: bool,
+    },
+    /// Copy host files or directories into a disk image, replacing files of
+    /// the same name
+    Add {
+        /// Disk image path
+        image: PathBuf,
+        /// Host file or directory as SOURCE[:DEST] (/<name> by default)
+        #[arg(required = true, value_name = "SOURCE[:DEST]")]
+        files: Vec<String>,
     },
+    /// List the files of a disk image with their sizes
+    Ls {
+        /// Disk image path
+        image: PathBuf,
+        /// Directory to list
+        #[arg(default_value = "/")]
+        path: String,
+    },
+    /// Resize a disk image, keeping its files
+    Resize {
+        /// Disk image path
+        image: PathBuf,
+        /// New image size (bytes, or with a K, M or G suffix)
+        #[arg(value_parser = parse_size)]
+        size: u64,
+    },
+}
+
+/// The emulated machine, used for both the QEMU command line and the
+/// hypervisor's platform config.
+#[derive(Args, Clone)]
+struct MachineOpts {
+    /// Guest RAM of the QEMU machine (bytes, or with a K, M or G suffix)
+    #[arg(long, default_value = "128M", value_parser = parse_size)]
+    mem: u64,
+    /// Number of CPUs of the QEMU machine
+    #[arg(long, default_value_t = 1, value_parser = clap::value_parser!(u32).range(1..))]
+    smp: u32,
+    /// QEMU accelerator (QEMU's default if not given)
+    #[arg(long, value_parser = ["kvm", "hvf", "tcg"])]
+    accel: Option<String>,
+    /// QEMU CPU model, instead of the architecture's default
+    #[arg(long)]
+    cpu: Option<String>,
+    /// Give each hart of the QEMU machine an IMSIC with N guest interrupt
+    /// files (riscv64 only), which single-hart VMs then use
+    #[arg(long, value_name = "N", value_parser = clap::value_parser!(u32).range(1..=7))]
+    aia_guests: Option<u32>,
+    /// QEMU character device of the second serial port, which the VM with
+    /// `serial=on` owns (x86_64 only), e.g. --guest-serial pty
+    #[arg(long, value_name = "CHARDEV")]
+    guest_serial: Option<String>,
+    /// Extra QEMU options, appended to the generated ones (repeatable; split
+    /// at whitespace outside quotes), e.g. --qemu-args "-d int -D qemu.log"
+    #[arg(long, value_name = "ARGS", allow_hyphen_values = true, value_parser = split_args)]
+    qemu_args: Vec<Vec<String>>,
+}
+
+/// Split `text` into arguments at whitespace outside single or double
+/// quotes, removing the quotes.
+fn split_args(text: &str) -> Result<Vec<String>, String> {
+    let mut args = Vec::new();
+    let mut arg: Option<String> = None;
+    let mut quote = None;
+    for c in text.chars() {
+        match (quote, c) {
+            (Some(q), c) if c == q => quote = None,
+            (Some(_), c) => arg.get_or_insert_default().push(c),
+            (None, '\'' | '"') => {
+                quote = Some(c);
+                arg.get_or_insert_default();
+            }
+            (None, c) if c.is_whitespace() => args.extend(arg.take()),
+            (None, c) => arg.get_or_insert_default().push(c),
+        }
+    }
+    if quote.is_some() {
+        return Err(format!("unterminated quote in '{text}'"));
+    }
+    args.extend(arg);
+    Ok(args)
+}
+
+/// How the hypervisor and the payloads are compiled.
+#[derive(Args, Clone)]
+struct BuildOpts {
+    /// Cargo profile of the hypervisor and payload builds
+    #[arg(long, default_value = "release", value_parser = ["release", "debug"])]
+    profile: String,
+    /// Hypervisor log level, compiled in through AX_LOG (info if not given)
+    #[arg(long, value_parser = ["off", "error", "warn", "info", "debug", "trace"])]
+    log: Option<String>,
+    /// Build the hypervisor with the `measure` feature: guest images are
+    /// hashed with SHA-256 as they are loaded
+    #[arg(long)]
+    measure: bool,
+    /// Build the hypervisor with the `qemu-exit` feature (set by `test`).
+    #[arg(skip)]
+    qemu_exit: bool,
+    /// Build the hypervisor with the `smp` feature (set by `run` and `test`
+    /// for a machine with more than one CPU).
+    #[arg(skip)]
+    smp: bool,
+}
+
+impl BuildOpts {
+    /// The cargo options selecting the profile.
+    fn cargo_args(&self) -> &'static [&'static str] {
+        match self.profile.as_str() {
+            "release" => &["--release"],
+            _ => &[],
+        }
+    }
+
+    /// The directory of the profile's artifacts under `target/<triple>`.
+    fn out_dir(&self) -> &'static str {
+        match self.profile.as_str() {
+            "release" => "release",
+            _ => "debug",
+        }
+    }
+}
+
+/// The guests placed on the disk image, one VM each.
+#[derive(Args, Clone)]
+struct PayloadOpts {
+    /// Guest image as SOURCE[:DEST] (repeatable, in VM id order): a payload
+    /// binary of this package (gkernel) or the path of a prebuilt image,
+    /// stored at DEST on the disk image (/sbin/<name> by default).
+    /// Without it (and without --kernel), two gkernel guests run.
+    #[arg(long = "payload", value_name = "SOURCE[:DEST]", value_parser = parse_payload)]
+    payloads: Vec<Payload>,
+    /// Run a single guest from this image instead, stored as the disk
+    /// image's boot specification (/boot/kernel), like kvmtool's --kernel
+    #[arg(long, value_name = "PATH", conflicts_with = "payloads")]
+    kernel: Option<PathBuf>,
+    /// Initial ramdisk of the --kernel guest (/boot/initrd)
+    #[arg(long, value_name = "PATH", requires = "kernel")]
+    initrd: Option<PathBuf>,
+    /// Kernel command line of the --kernel guest (/boot/cmdline), or of
+    /// every --payload guest (cmdline= in /etc/vms.conf), e.g.
+    /// memstress=PAGES for gkernel
+    #[arg(long, value_name = "ARGS", allow_hyphen_values = true)]
+    append: Option<String>,
+}
+
+/// A single guest given with --kernel, --initrd and --append.
+struct BootSpec {
+    kernel: PathBuf,
+    initrd: Option<PathBuf>,
+    cmdline: Option<String>,
+}
+
+/// A guest image given with `--payload`.
+#[derive(Clone)]
+struct Payload {
+    /// Payload binary name or path of a prebuilt image.
+    source: String,
+    /// Absolute path on the disk image, if given.
+    dest: Option<String>,
+}
+
+fn parse_payload(text: &str) -> Result<Payload, String> {
+    let (source, dest) = match text.split_once(":/") {
+        Some((source, dest)) => (source, Some(format!("/{dest}"))),
+        None => (text, None),
+    };
+    if source.is_empty() {
+        return Err(format!("missing payload source in '{text}'"));
+    }
+    Ok(Payload {
+        source: source.into(),
+        dest,
+    })
+}
+
+/// Guests when no `--payload` is given: two copies of the test kernel.
+const DEFAULT_PAYLOADS: [&str; 2] = ["gkernel", "gkernel"];
+
+fn default_payloads() -> Vec<Payload> {
+    DEFAULT_PAYLOADS
+        .iter()
+        .map(|name| Payload {
+            source: name.to_string(),
+            dest: None,
+        })
+        .collect()
+}
+
+impl PayloadOpts {
+    /// The --kernel guest, if one was given.
+    fn boot_spec(&self) -> Option<BootSpec> {
+        Some(BootSpec {
+            kernel: self.kernel.clone()?,
+            initrd: self.initrd.clone(),
+            cmdline: self.append.clone(),
+        })
+    }
+
+    /// The guests in VM id order, with the default ones if none were given.
+    fn payloads(&self) -> Vec<Payload> {
+        if self.payloads.is_empty() {
+            default_payloads()
+        } else {
+            self.payloads.clone()
+        }
+    }
+}
+
+impl Payload {
+    /// The prebuilt image, if `source` names an existing file rather than a
+    /// payload binary.
+    fn prebuilt(&self) -> Option<&Path> {
+        let path = Path::new(&self.source);
+        path.is_file().then_some(path)
+    }
+}
+
+/// Parse a size in bytes with an optional K, M or G suffix.
+fn parse_size(text: &str) -> Result<u64, String> {
+    let (digits, shift) = match text.as_bytes().last() {
+        Some(b'K' | b'k') => (&text[..text.len() - 1], 10),
+        Some(b'M' | b'm') => (&text[..text.len() - 1], 20),
+        Some(b'G' | b'g') => (&text[..text.len() - 1], 30),
+        _ => (text, 0),
+    };
+    digits
+        .parse::<u64>()
+        .ok()
+        .and_then(|n| n.checked_mul(1 << shift))
+        .ok_or_else(|| format!("invalid size '{text}'"))
 }
 
 #[derive(Clone)]
@@ -36,6 +375,15 @@ struct ArchInfo {
     #[allow(dead_code)]
     platform: &'static str,
     objcopy_arch: &'static str,
+    /// GDB `set architecture` name.
+    gdb_arch: &'static str,
+    /// Guest physical address the hypervisor loads the guest image at.
+    guest_load_addr: u64,
+    /// Size of the QEMU machine's pflash1, attached with the "pfld" image
+    /// for the hypervisor's passthrough. None on x86_64: the pc machine's
+    /// flash holds the firmware, so the guests only get the flash emulated
+    /// from the disk's `/etc/pflash.img`.
+    host_pflash_size: Option<usize>,
 }
 
 fn arch_info(arch: &str) -> ArchInfo {
@@ -44,16 +392,25 @@ fn arch_info(arch: &str) -> ArchInfo {
             target: "riscv64gc-unknown-none-elf",
             platform: "riscv64-qemu-virt",
             objcopy_arch: "riscv64",
+            gdb_arch: "riscv:rv64",
+            guest_load_addr: 0x8020_0000,
+            host_pflash_size: Some(32 * 1024 * 1024),
         },
         "aarch64" => ArchInfo {
             target: "aarch64-unknown-none-softfloat",
             platform: "aarch64-qemu-virt",
             objcopy_arch: "aarch64",
+            gdb_arch: "aarch64",
+            guest_load_addr: 0x4020_0000,
+            host_pflash_size: Some(64 * 1024 * 1024),
         },
         "x86_64" => ArchInfo {
             target: "x86_64-unknown-none",
             platform: "x86-pc",
             objcopy_arch: "x86_64",
+            gdb_arch: "i386:x86-64",
+            guest_load_addr: 0x10000,
+            host_pflash_size: None,
         },
         _ => {
             eprintln!(
@@ -70,15 +427,38 @@ fn project_root() -> PathBuf {
     PathBuf::from(env!("CARGO_MANIFEST_DIR"))
 }
 
-fn install_config(root: &Path, arch: &str) {
+/// Install `configs/<arch>.toml` as `.axconfig.toml`, with the physical
+/// memory size and CPU count of `machine` if given.
+fn install_config(root: &Path, arch: &str, machine: Option<&MachineOpts>) {
     let src = root.join("configs").join(format!("{arch}.toml"));
     let dst = root.join(".axconfig.toml");
-    if !src.exists() {
-        eprintln!("Error: config file not found: {}", src.display());
+    let text = std::fs::read_to_string(&src).unwrap_or_else(|e| {
+        eprintln!("Error: failed to read config {}: {}", src.display(), e);
         process::exit(1);
-    }
-    std::fs::copy(&src, &dst).unwrap_or_else(|e| {
-        eprintln!("Error: failed to copy config: {}", e);
+    });
+    let text = match machine {
+        Some(machine) => {
+            let mut out = String::new();
+            for line in text.lines() {
+                let key = line.split('=').next().unwrap_or_default().trim();
+                let line = match key {
+                    "phys-memory-size" => format!("{key} = {:#x} # uint", machine.mem),
+                    "cpu-num" => format!("{key} = {} # uint", machine.smp),
+                    "max-cpu-num" => {
+                        let max = axconfig_value(line).unwrap_or(0);
+                        format!("{key} = {} # uint", max.max(machine.smp.into()))
+                    }
+                    _ => line.to_string(),
+                };
+                out.push_str(&line);
+                out.push('\n');
+            }
+            out
+        }
+        None => text,
+    };
+    std::fs::write(&dst, text).unwrap_or_else(|e| {
+        eprintln!("Error: failed to write config: {}", e);
         process::exit(1);
     });
     println!("Installed config: {} -> .axconfig.toml", src.display());
@@ -103,14 +483,21 @@ fn install_payload_config(root: &Path, arch: &str) {
     );
 }
 
-/// Build the guest payload (gkernel = readpflash) for the target architecture.
+/// Build the guest payload binary `name` (gkernel = readpflash) for the
+/// target architecture.
 ///
 /// The payload is a full ArceOS application built with the `axstd` feature.
-fn build_payload(root: &Path, info: &ArchInfo, arch: &str) -> PathBuf {
+fn build_payload(
+    root: &Path,
+    info: &ArchInfo,
+    arch: &str,
+    name: &str,
+    build: &BuildOpts,
+) -> PathBuf {
     let payload_dir = root.join("payload").join("gkernel");
     let manifest = root.join("Cargo.toml");
 
-    println!("Building payload (gkernel) for {arch} ...");
+    println!("Building payload ({name}) for {arch} ...");
 
     let mut cmd = Command::new("cargo");
 
@@ -126,16 +513,16 @@ fn build_payload(root: &Path, info: &ArchInfo, arch: &str) -> PathBuf {
         cmd.env("AX_CONFIG_PATH", axconfig_path.to_str().unwrap());
     }
 
-    let mut build_args = vec![
-        "build".to_string(),
-        "--release".into(),
+    let mut build_args: Vec<String> = vec!["build".into()];
+    build_args.extend(build.cargo_args().iter().map(|arg| arg.to_string()));
+    build_args.extend([
         "--manifest-path".into(),
         manifest.to_str().unwrap().to_string(),
         "--target".into(),
         info.target.to_string(),
         "--bin".into(),
-        "gkernel".into(),
-    ];
+        name.into(),
+    ]);
 
     // All architectures use axstd (full ArceOS guest with multitasking)
     // Always add guest-kernel feature
@@ -155,43 +542,14 @@ fn build_payload(root: &Path, info: &ArchInfo, arch: &str) -> PathBuf {
     let payload_elf = root
         .join("target")
         .join(info.target)
-        .join("release")
-        .join("gkernel");
-
-    let payload_bin = payload_elf.with_extension("bin");
-
-    // Convert ELF to flat binary
-    let mut objcopy_args = vec![
-        format!("--binary-architecture={}", info.objcopy_arch),
-        payload_elf.to_str().unwrap().to_string(),
-        "--strip-all".into(),
-        "-O".into(),
-        "binary".into(),
-        payload_bin.to_str().unwrap().to_string(),
-    ];
-
-    // For x86_64, we don't pass --binary-architecture (not needed for ELF→bin)
-    if info.objcopy_arch == "x86_64" {
-        objcopy_args = vec![
-            payload_elf.to_str().unwrap().to_string(),
-            "--strip-all".into(),
-            "-O".into(),
-            "binary".into(),
-            payload_bin.to_str().unwrap().to_string(),
-        ];
-    }
+        .join(build.out_dir())
+        .join(name);
 
-    let status = Command::new("rust-objcopy")
-        .args(&objcopy_args)
-        .status()
-        .expect("failed to execute rust-objcopy for payload");
+    // Package the ELF's loadable segments with its entry point
+    let payload_bin = payload_elf.with_extension("gapk");
+    do_package(&payload_elf, &payload_bin, info.guest_load_addr, 0);
 
-    if !status.success() {
-        eprintln!("Error: rust-objcopy for payload failed");
-        process::exit(status.code().unwrap_or(1));
-    }
-
-    // Print binary size
+    // Print package size
     if let Ok(meta) = std::fs::metadata(&payload_bin) {
         println!(
             "Payload built: {} ({} bytes, {} KB)",
@@ -204,20 +562,123 @@ fn build_payload(root: &Path, info: &ArchInfo, arch: &str) -> PathBuf {
     payload_bin
 }
 
-/// Create a 64MB FAT32 disk image containing `/sbin/gkernel`.
-fn create_fat_disk_image(path: &Path, payload_bin: &Path) {
-    const DISK_SIZE: u64 = 64 * 1024 * 1024;
+/// Size of each guest's virtio-blk disk.
+const GUEST_DISK_SIZE: usize = 1024 * 1024;
 
-    let payload_data = std::fs::read(payload_bin).unwrap_or_else(|e| {
-        eprintln!(
-            "Error: failed to read payload {}: {}",
-            payload_bin.display(),
-            e
-        );
+/// Path of the guests' pflash contents on the disk image.
+const PFLASH_DISK_IMAGE: &str = "/etc/pflash.img";
+
+/// A guest image to place on the disk image.
+struct GuestImage {
+    /// Payload binary name or prebuilt image path it comes from.
+    source: String,
+    /// The image on the host.
+    host_path: PathBuf,
+    /// Absolute path on the disk image.
+    dest: String,
+}
+
+/// Build the payload binaries among `payloads` (each one once) and choose
+/// the disk image paths of all guests: `/sbin/<name>` unless given, with a
+/// numeric suffix for repeated names (`/sbin/gkernel`, `/sbin/gkernel2`).
+fn build_guest_images(
+    root: &Path,
+    info: &ArchInfo,
+    arch: &str,
+    payloads: &[Payload],
+    build: &BuildOpts,
+) -> Vec<GuestImage> {
+    let mut built: Vec<(String, PathBuf)> = Vec::new();
+    let mut images: Vec<GuestImage> = Vec::new();
+    for payload in payloads {
+        let host_path = match payload.prebuilt() {
+            Some(path) => path.to_path_buf(),
+            None => match built.iter().find(|(name, _)| *name == payload.source) {
+                Some((_, bin)) => bin.clone(),
+                None => {
+                    let bin = build_payload(root, info, arch, &payload.source, build);
+                    built.push((payload.source.clone(), bin.clone()));
+                    bin
+                }
+            },
+        };
+        let dest = match &payload.dest {
+            Some(dest) => dest.clone(),
+            None => {
+                let name = Path::new(&payload.source)
+                    .file_name()
+                    .and_then(|name| name.to_str())
+                    .unwrap_or("guest");
+                let base = format!("/sbin/{name}");
+                (1..)
+                    .map(|n| match n {
+                        1 => base.clone(),
+                        n => format!("{base}{n}"),
+                    })
+                    .find(|dest| images.iter().all(|image| image.dest != *dest))
+                    .unwrap()
+            }
+        };
+        if images.iter().any(|image| image.dest == dest) {
+            eprintln!("Error: two payloads stored at {dest}");
+            process::exit(1);
+        }
+        images.push(GuestImage {
+            source: payload.source.clone(),
+            host_path,
+            dest,
+        });
+    }
+    images
+}
+
+/// Path of the virtio-blk disk of guest `vm` on the disk image.
+fn guest_disk(vm: usize) -> String {
+    format!("/vm{vm}.img")
+}
+
+/// Describe the guests of a disk image (the generated `vm.toml`).
+fn vm_manifest(images: &[GuestImage], sizes: &[u64]) -> String {
+    let mut manifest = String::from(
+        "# Guests on the disk image, generated by `cargo xtask`; see /etc/vms.conf.\n",
+    );
+    for (vm, (image, size)) in images.iter().zip(sizes).enumerate() {
+        manifest.push_str(&format!(
+            "\n[[vm]]\nid = {vm}\nsource = {:?}\nimage = {:?}\nsize = {size}\ndisk = {:?}\n",
+            image.source,
+            image.dest,
+            guest_disk(vm)
+        ));
+    }
+    manifest
+}
+
+/// Create `path` (absolute) in the FAT filesystem, with its parent
+/// directories.
+fn create_fat_file<'a, IO: fatfs::ReadWriteSeek>(
+    root_dir: &fatfs::Dir<'a, IO>,
+    path: &str,
+) -> fatfs::File<'a, IO> {
+    let path = path.trim_start_matches('/');
+    let mut dir = root_dir.clone();
+    if let Some((parent, _)) = path.rsplit_once('/') {
+        for name in parent.split('/').filter(|name| !name.is_empty()) {
+            dir = dir.create_dir(name).unwrap_or_else(|e| {
+                eprintln!("Error: failed to create directory for /{}: {}", path, e);
+                process::exit(1);
+            });
+        }
+    }
+    let mut file = root_dir.create_file(path).unwrap_or_else(|e| {
+        eprintln!("Error: failed to create /{}: {}", path, e);
         process::exit(1);
     });
-    println!("Payload binary size: {} bytes", payload_data.len());
+    file.truncate().unwrap();
+    file
+}
 
+/// Format a new FAT32 disk image of `size` bytes at `path`.
+fn format_disk_image(path: &Path, size: u64) -> std::fs::File {
     let file = std::fs::OpenOptions::new()
         .read(true)
         .write(true)
@@ -228,56 +689,591 @@ fn create_fat_disk_image(path: &Path, payload_bin: &Path) {
             eprintln!("Error: failed to create disk image: {}", e);
             process::exit(1);
         });
-    file.set_len(DISK_SIZE).unwrap();
+    file.set_len(size).unwrap();
 
     let format_opts = fatfs::FormatVolumeOptions::new().fat_type(fatfs::FatType::Fat32);
     fatfs::format_volume(&file, format_opts).unwrap_or_else(|e| {
         eprintln!("Error: failed to format FAT32: {}", e);
         process::exit(1);
     });
+    file
+}
 
+/// Open the existing FAT disk image at `path`.
+fn open_disk_image(path: &Path) -> std::fs::File {
+    std::fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(path)
+        .unwrap_or_else(|e| {
+            eprintln!("Error: failed to open disk image {}: {}", path.display(), e);
+            process::exit(1);
+        })
+}
+
+/// Open the FAT filesystem on a disk image.
+fn open_fat(file: &std::fs::File) -> fatfs::FileSystem<&std::fs::File> {
+    fatfs::FileSystem::new(file, fatfs::FsOptions::new()).unwrap_or_else(|e| {
+        eprintln!("Error: failed to open FAT filesystem: {}", e);
+        process::exit(1);
+    })
+}
+
+/// Open the disk image at `path` to update it in place, or format a new
+/// one of `size` bytes if there is none, it is smaller or it holds no FAT
+/// filesystem.
+fn open_or_format_disk_image(path: &Path, size: u64) -> std::fs::File {
+    if let Ok(file) = std::fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(path)
     {
-        let fs = fatfs::FileSystem::new(&file, fatfs::FsOptions::new()).unwrap_or_else(|e| {
-            eprintln!("Error: failed to open FAT filesystem: {}", e);
+        let len = file.metadata().map_or(0, |meta| meta.len());
+        if len >= size && fatfs::FileSystem::new(&file, fatfs::FsOptions::new()).is_ok() {
+            println!("Updating disk image: {}", path.display());
+            return file;
+        }
+    }
+    format_disk_image(path, size)
+}
+
+/// Size of the file at `path` (absolute) in the FAT filesystem, if it exists.
+fn fat_file_len<IO: fatfs::ReadWriteSeek>(
+    root_dir: &fatfs::Dir<'_, IO>,
+    path: &str,
+) -> Option<u64> {
+    let mut file = root_dir.open_file(path.trim_start_matches('/')).ok()?;
+    file.seek(SeekFrom::End(0)).ok()
+}
+
+/// Create a FAT32 disk image (64MB, or larger for big guests), or update
+/// the existing one in place, keeping other files on it. It gets the guest
+/// images, a 1MB virtio-blk disk for each that does not have one yet
+/// (`/vm0.img`, `/vm1.img`, ...), `/etc/vms.conf` listing the guests, so the hypervisor
+/// runs them concurrently, each with `cmdline` if given, `/etc/vm.toml` describing them (also written next
+/// to the disk image as `<disk>.vm.toml`), and the head of the pflash image
+/// the hypervisor emulates for them (`/etc/pflash.img`).
+fn create_fat_disk_image(path: &Path, images: &[GuestImage], cmdline: Option<&str>) {
+    const MIN_DISK_SIZE: u64 = 64 * 1024 * 1024;
+
+    let contents: Vec<Vec<u8>> = images
+        .iter()
+        .map(|image| {
+            let data = std::fs::read(&image.host_path).unwrap_or_else(|e| {
+                eprintln!(
+                    "Error: failed to read payload {}: {}",
+                    image.host_path.display(),
+                    e
+                );
+                process::exit(1);
+            });
+            println!("Payload {}: {} bytes", image.dest, data.len());
+            data
+        })
+        .collect();
+    let sizes: Vec<u64> = contents.iter().map(|data| data.len() as u64).collect();
+    let needed = sizes.iter().sum::<u64>() + (images.len() * GUEST_DISK_SIZE) as u64;
+    let disk_size = MIN_DISK_SIZE.max((needed * 2).next_multiple_of(1024 * 1024));
+
+    let file = open_or_format_disk_image(path, disk_size);
+    let disk_size = file.metadata().map_or(disk_size, |meta| meta.len());
+
+    let manifest = vm_manifest(images, &sizes);
+    {
+        let fs = open_fat(&file);
+        let root_dir = fs.root_dir();
+
+        for (image, data) in images.iter().zip(&contents) {
+            let mut f = create_fat_file(&root_dir, &image.dest);
+            f.write_all(data).unwrap();
+            f.flush().unwrap();
+        }
+
+        // Guest disks keep their contents from earlier runs.
+        for vm in 0..images.len() {
+            if fat_file_len(&root_dir, &guest_disk(vm)) == Some(GUEST_DISK_SIZE as u64) {
+                continue;
+            }
+            let mut f = create_fat_file(&root_dir, &guest_disk(vm));
+            f.write_all(&vec![0u8; GUEST_DISK_SIZE]).unwrap();
+            f.flush().unwrap();
+        }
+
+        let mut f = create_fat_file(&root_dir, "/etc/vms.conf");
+        writeln!(
+            f,
+            "# One guest per line: image [virtio-blk disk] [cpus=N] [initrd=PATH] [cmdline=ARGS...]; the line order gives the VM id."
+        )
+        .unwrap();
+        for (vm, image) in images.iter().enumerate() {
+            match cmdline {
+                Some(cmdline) => {
+                    writeln!(f, "{} {} cmdline={cmdline}", image.dest, guest_disk(vm))
+                }
+                None => writeln!(f, "{} {}", image.dest, guest_disk(vm)),
+            }
+            .unwrap();
+        }
+        f.flush().unwrap();
+
+        let mut f = create_fat_file(&root_dir, "/etc/vm.toml");
+        f.write_all(manifest.as_bytes()).unwrap();
+        f.flush().unwrap();
+
+        // Only the first page: the rest of the emulated flash reads as erased.
+        let mut f = create_fat_file(&root_dir, PFLASH_DISK_IMAGE);
+        f.write_all(&pflash_contents(4096)).unwrap();
+        f.flush().unwrap();
+    }
+
+    let manifest_path = path.with_extension("vm.toml");
+    std::fs::write(&manifest_path, &manifest).unwrap_or_else(|e| {
+        eprintln!("Error: failed to write {}: {}", manifest_path.display(), e);
+        process::exit(1);
+    });
+
+    let dests: Vec<&str> = images.iter().map(|image| image.dest.as_str()).collect();
+    println!(
+        "Disk image ready: {} ({}MB) with {}, their disks, /etc/vms.conf, /etc/vm.toml and {}",
+        path.display(),
+        disk_size / (1024 * 1024),
+        dests.join(", "),
+        PFLASH_DISK_IMAGE
+    );
+    println!("Guest manifest: {}", manifest_path.display());
+}
+
+/// Paths of the boot specification on the disk image, read by the
+/// hypervisor when there is no /etc/vms.conf.
+const BOOT_KERNEL: &str = "/boot/kernel";
+const BOOT_INITRD: &str = "/boot/initrd";
+const BOOT_CMDLINE: &str = "/boot/cmdline";
+const BOOT_DISK: &str = "/boot/disk";
+
+/// Create a FAT32 disk image, or update the existing one in place, that
+/// boots the single guest of `spec`: its image, initrd and command line go
+/// to the boot specification paths (/boot/kernel, /boot/initrd,
+/// /boot/cmdline) with a 1MB virtio-blk disk (/boot/disk), and
+/// /etc/vms.conf is removed so the hypervisor reads them.
+fn create_boot_disk_image(path: &Path, spec: &BootSpec) {
+    const MIN_DISK_SIZE: u64 = 64 * 1024 * 1024;
+
+    let read = |host_path: &Path| {
+        std::fs::read(host_path).unwrap_or_else(|e| {
+            eprintln!("Error: failed to read {}: {}", host_path.display(), e);
             process::exit(1);
-        });
+        })
+    };
+    let kernel = read(&spec.kernel);
+    let initrd = spec.initrd.as_deref().map(read);
+    let needed = (kernel.len() + initrd.as_ref().map_or(0, Vec::len) + GUEST_DISK_SIZE) as u64;
+    let disk_size = MIN_DISK_SIZE.max((needed * 2).next_multiple_of(1024 * 1024));
+
+    let file = open_or_format_disk_image(path, disk_size);
+    {
+        let fs = open_fat(&file);
         let root_dir = fs.root_dir();
+        let write = |dest: &str, data: &[u8]| {
+            let mut f = create_fat_file(&root_dir, dest);
+            f.write_all(data).unwrap_or_else(|e| {
+                eprintln!("Error: failed to write {}: {}", dest, e);
+                process::exit(1);
+            });
+            f.flush().unwrap();
+        };
+
+        write(BOOT_KERNEL, &kernel);
+        println!("Kernel {}: {} bytes", BOOT_KERNEL, kernel.len());
+        // Optional parts left from an earlier run must not leak into this one.
+        match &initrd {
+            Some(data) => write(BOOT_INITRD, data),
+            None => {
+                let _ = root_dir.remove(BOOT_INITRD.trim_start_matches('/'));
+            }
+        }
+        match &spec.cmdline {
+            Some(cmdline) => write(BOOT_CMDLINE, cmdline.as_bytes()),
+            None => {
+                let _ = root_dir.remove(BOOT_CMDLINE.trim_start_matches('/'));
+            }
+        }
+        // The disk keeps its contents from earlier runs.
+        if fat_file_len(&root_dir, BOOT_DISK) != Some(GUEST_DISK_SIZE as u64) {
+            write(BOOT_DISK, &vec![0u8; GUEST_DISK_SIZE]);
+        }
+        let _ = root_dir.remove("etc/vms.conf");
+
+        write(PFLASH_DISK_IMAGE, &pflash_contents(4096));
+    }
+    println!(
+        "Disk image ready: {} with boot specification {}{}{}",
+        path.display(),
+        BOOT_KERNEL,
+        if initrd.is_some() {
+            ", /boot/initrd"
+        } else {
+            ""
+        },
+        if spec.cmdline.is_some() {
+            ", /boot/cmdline"
+        } else {
+            ""
+        }
+    );
+}
+
+/// A file or directory in a FAT filesystem, with its contents.
+enum FatNode {
+    Dir(String),
+    File(String, Vec<u8>),
+}
 
-        root_dir.create_dir("sbin").unwrap_or_else(|e| {
-            eprintln!("Error: failed to create /sbin: {}", e);
+/// Collect everything below `dir`, whose absolute path is `prefix`, with
+/// directories before their contents.
+fn read_fat_tree<IO: fatfs::ReadWriteSeek>(
+    dir: &fatfs::Dir<'_, IO>,
+    prefix: &str,
+    nodes: &mut Vec<FatNode>,
+) {
+    for entry in dir.iter() {
+        let entry = entry.unwrap_or_else(|e| {
+            eprintln!("Error: failed to read directory {}: {}", prefix, e);
             process::exit(1);
         });
+        let name = entry.file_name();
+        if name == "." || name == ".." {
+            continue;
+        }
+        let path = format!("{}/{}", prefix.trim_end_matches('/'), name);
+        if entry.is_dir() {
+            nodes.push(FatNode::Dir(path.clone()));
+            read_fat_tree(&entry.to_dir(), &path, nodes);
+        } else {
+            let mut data = Vec::new();
+            entry.to_file().read_to_end(&mut data).unwrap_or_else(|e| {
+                eprintln!("Error: failed to read {}: {}", path, e);
+                process::exit(1);
+            });
+            nodes.push(FatNode::File(path, data));
+        }
+    }
+}
 
-        let mut f = root_dir.create_file("sbin/gkernel").unwrap_or_else(|e| {
-            eprintln!("Error: failed to create /sbin/gkernel: {}", e);
+/// Copy the host file or directory `src` to `dest` (absolute) in the FAT
+/// filesystem.
+fn add_to_fat<IO: fatfs::ReadWriteSeek>(root_dir: &fatfs::Dir<'_, IO>, src: &Path, dest: &str) {
+    if src.is_dir() {
+        let entries = std::fs::read_dir(src).unwrap_or_else(|e| {
+            eprintln!("Error: failed to read {}: {}", src.display(), e);
             process::exit(1);
         });
-        f.write_all(&payload_data).unwrap();
-        f.flush().unwrap();
+        for entry in entries {
+            let entry = entry.unwrap();
+            let name = entry.file_name();
+            let dest = format!("{}/{}", dest.trim_end_matches('/'), name.to_string_lossy());
+            add_to_fat(root_dir, &entry.path(), &dest);
+        }
+        return;
     }
+    let data = std::fs::read(src).unwrap_or_else(|e| {
+        eprintln!("Error: failed to read {}: {}", src.display(), e);
+        process::exit(1);
+    });
+    let mut f = create_fat_file(root_dir, dest);
+    f.write_all(&data).unwrap_or_else(|e| {
+        eprintln!("Error: failed to write {}: {}", dest, e);
+        process::exit(1);
+    });
+    f.flush().unwrap();
+    println!("Added {} -> {} ({} bytes)", src.display(), dest, data.len());
+}
 
+/// Run a `disk` subcommand.
+fn do_disk(cmd: &DiskCmd) {
+    match cmd {
+        DiskCmd::Create { image, size, force } => {
+            if image.exists() && !force {
+                eprintln!(
+                    "Error: {} exists (use --force to replace it)",
+                    image.display()
+                );
+                process::exit(1);
+            }
+            format_disk_image(image, *size);
+            println!(
+                "Created FAT32 disk image: {} ({} bytes)",
+                image.display(),
+                size
+            );
+        }
+        DiskCmd::Add { image, files } => {
+            let file = open_disk_image(image);
+            let fs = open_fat(&file);
+            let root_dir = fs.root_dir();
+            for spec in files {
+                let (src, dest) = match spec.split_once(":/") {
+                    Some((src, dest)) => (Path::new(src), format!("/{dest}")),
+                    None => {
+                        let src = Path::new(spec.as_str());
+                        let name = src.file_name().unwrap_or_else(|| {
+                            eprintln!("Error: no file name in '{}', give a DEST", spec);
+                            process::exit(1);
+                        });
+                        (src, format!("/{}", name.to_string_lossy()))
+                    }
+                };
+                add_to_fat(&root_dir, src, &dest);
+            }
+        }
+        DiskCmd::Ls { image, path } => {
+            let file = open_disk_image(image);
+            let fs = open_fat(&file);
+            let root_dir = fs.root_dir();
+            let dir = match path.trim_matches('/') {
+                "" => root_dir,
+                path => root_dir.open_dir(path).unwrap_or_else(|e| {
+                    eprintln!("Error: failed to open directory /{}: {}", path, e);
+                    process::exit(1);
+                }),
+            };
+            let mut nodes = Vec::new();
+            read_fat_tree(&dir, path, &mut nodes);
+            for node in &nodes {
+                match node {
+                    FatNode::Dir(path) => println!("{:>10}  {}/", "", path),
+                    FatNode::File(path, data) => println!("{:>10}  {}", data.len(), path),
+                }
+            }
+            let stats = fs.stats().unwrap();
+            let cluster = u64::from(stats.cluster_size());
+            println!(
+                "{} KB free of {} KB",
+                u64::from(stats.free_clusters()) * cluster / 1024,
+                u64::from(stats.total_clusters()) * cluster / 1024
+            );
+        }
+        DiskCmd::Resize { image, size } => {
+            // FAT cannot be resized in place: rebuild the image at the new
+            // size and copy the files over.
+            let mut nodes = Vec::new();
+            {
+                let file = open_disk_image(image);
+                let fs = open_fat(&file);
+                read_fat_tree(&fs.root_dir(), "/", &mut nodes);
+            }
+            let tmp = image.with_extension("resize.tmp");
+            {
+                let file = format_disk_image(&tmp, *size);
+                let fs = open_fat(&file);
+                let root_dir = fs.root_dir();
+                for node in &nodes {
+                    match node {
+                        FatNode::Dir(path) => {
+                            root_dir
+                                .create_dir(path.trim_start_matches('/'))
+                                .unwrap_or_else(|e| {
+                                    eprintln!("Error: failed to create {}: {}", path, e);
+                                    let _ = std::fs::remove_file(&tmp);
+                                    process::exit(1);
+                                });
+                        }
+                        FatNode::File(path, data) => {
+                            let mut f = create_fat_file(&root_dir, path);
+                            f.write_all(data).unwrap_or_else(|e| {
+                                eprintln!("Error: {} does not fit: {}", path, e);
+                                let _ = std::fs::remove_file(&tmp);
+                                process::exit(1);
+                            });
+                            f.flush().unwrap();
+                        }
+                    }
+                }
+            }
+            std::fs::rename(&tmp, image).unwrap_or_else(|e| {
+                eprintln!("Error: failed to replace {}: {}", image.display(), e);
+                process::exit(1);
+            });
+            println!("Resized {} to {} bytes", image.display(), size);
+        }
+    }
+}
+
+/// Print the exit trace at `path` in the disk image: every exit with
+/// `all`, then the number of exits per class.
+/// Write the package of the guest ELF `elf` loaded at `load_addr` that
+/// needs `ram` bytes of guest RAM to `out`.
+fn do_package(elf: &Path, out: &Path, load_addr: u64, ram: u64) {
+    let data = std::fs::read(elf).unwrap_or_else(|e| {
+        eprintln!("Error: failed to read {}: {}", elf.display(), e);
+        process::exit(1);
+    });
+    let package = package::Package::from_elf(&data, load_addr, ram).unwrap_or_else(|e| {
+        eprintln!("Error: {}: {}", elf.display(), e);
+        process::exit(1);
+    });
+    std::fs::write(out, &package).unwrap_or_else(|e| {
+        eprintln!("Error: failed to write {}: {}", out.display(), e);
+        process::exit(1);
+    });
+    let header = package::Package::parse(&package, package.len()).unwrap();
     println!(
-        "Created FAT32 disk image: {} ({}MB) with /sbin/gkernel",
-        path.display(),
-        DISK_SIZE / (1024 * 1024)
+        "Packaged {} -> {}: entry {:#x}, {} segments",
+        elf.display(),
+        out.display(),
+        header.entry,
+        header.segments.len()
+    );
+    for segment in &header.segments {
+        let perms: String = [
+            (package::SEGMENT_READ, 'r'),
+            (package::SEGMENT_WRITE, 'w'),
+            (package::SEGMENT_EXEC, 'x'),
+        ]
+        .iter()
+        .map(|&(bit, c)| if segment.flags & bit != 0 { c } else { '-' })
+        .collect();
+        println!(
+            "  {:#x}: {} bytes of {} in memory, {}",
+            segment.gpa, segment.file_size, segment.mem_size, perms
+        );
+    }
+}
+
+/// Write the flat binary of the guest ELF `elf` loaded at `load_addr` to
+/// `out` and its load descriptor next to it.
+fn do_flat_binary(elf: &Path, out: &Path, load_addr: u64) {
+    let data = std::fs::read(elf).unwrap_or_else(|e| {
+        eprintln!("Error: failed to read {}: {}", elf.display(), e);
+        process::exit(1);
+    });
+    let (binary, desc) =
+        package::LoadDescriptor::flat_binary(&data, load_addr).unwrap_or_else(|e| {
+            eprintln!("Error: {}: {}", elf.display(), e);
+            process::exit(1);
+        });
+    let mut load = out.as_os_str().to_owned();
+    load.push(".load");
+    let load = PathBuf::from(load);
+    for (path, contents) in [(out, &binary[..]), (&load, &desc.to_bytes()[..])] {
+        std::fs::write(path, contents).unwrap_or_else(|e| {
+            eprintln!("Error: failed to write {}: {}", path.display(), e);
+            process::exit(1);
+        });
+    }
+    println!(
+        "Flat binary {} -> {} (descriptor {}): entry {:#x}, text {} bytes, data {} bytes, bss {} bytes",
+        elf.display(),
+        out.display(),
+        load.display(),
+        desc.entry,
+        desc.text_size,
+        desc.data_size,
+        desc.bss_size
     );
 }
 
-/// Create a pflash image with magic "pfld" at offset 0 (for NPF passthrough test).
-fn create_pflash_image(root: &Path, arch: &str) -> PathBuf {
-    let size: usize = match arch {
-        "riscv64" => 32 * 1024 * 1024, // 32MB - QEMU virt pflash1
-        "aarch64" => 64 * 1024 * 1024, // 64MB - QEMU virt pflash1
-        _ => 4 * 1024 * 1024,
+fn do_exits(image: &Path, path: &str, all: bool) {
+    let data = {
+        let file = open_disk_image(image);
+        let fs = open_fat(&file);
+        let mut data = Vec::new();
+        fs.root_dir()
+            .open_file(path.trim_start_matches('/'))
+            .and_then(|mut f| f.read_to_end(&mut data))
+            .unwrap_or_else(|e| {
+                eprintln!("Error: failed to read {}: {}", path, e);
+                process::exit(1);
+            });
+        data
     };
+    let mut reader = exitlog::TraceReader::new(&data).unwrap_or_else(|e| {
+        eprintln!("Error: {}: {}", path, e);
+        process::exit(1);
+    });
+    let header = reader.header().clone();
+    let regs = header.arch.key_regs();
+    println!("Exit trace of VM {} ({})", header.vm, header.arch.name());
+    let mut counts = vec![0usize; header.classes.len().max(1)];
+    let mut error = None;
+    for entry in reader.by_ref() {
+        let entry = match entry {
+            Ok(entry) => entry,
+            Err(e) => {
+                error = Some(e);
+                break;
+            }
+        };
+        let class = header
+            .classes
+            .get(entry.class as usize)
+            .map_or("?", String::as_str);
+        if let Some(count) = counts.get_mut(entry.class as usize) {
+            *count += 1;
+        }
+        if !all {
+            continue;
+        }
+        let mut line = format!(
+            "{:>6}.{:06} cpu{} {:<9} code={:#x} info=({:#x}, {:#x}) pc={:#x}",
+            entry.time_ns / 1_000_000_000,
+            entry.time_ns % 1_000_000_000 / 1000,
+            entry.cpu,
+            class,
+            entry.code,
+            entry.info[0],
+            entry.info[1],
+            entry.before.pc
+        );
+        if entry.after.pc != entry.before.pc {
+            line += &format!("->{:#x}", entry.after.pc);
+        }
+        for (i, name) in regs.iter().enumerate() {
+            let (before, after) = (entry.before.regs[i], entry.after.regs[i]);
+            if after != before {
+                line += &format!(" {name}={before:#x}->{after:#x}");
+            }
+        }
+        for io in &entry.io {
+            let dir = if io.is_write { 'w' } else { 'r' };
+            line += &format!(" {dir}{}[{:#x}]={:#x}", io.width, io.addr, io.value);
+        }
+        if let Some((vector, error)) = entry.after.exception {
+            line += &format!(" exception={vector}");
+            if let Some(error) = error {
+                line += &format!("/{error:#x}");
+            }
+        }
+        if entry.after.irqs != entry.before.irqs {
+            line += &format!(" irqs={:#x}", entry.after.irqs);
+        }
+        println!("{line}");
+    }
+    let total: usize = counts.iter().sum();
+    println!("{total} exits");
+    for (name, count) in header.classes.iter().zip(&counts) {
+        if *count > 0 {
+            println!("{:>10}  {}", count, name);
+        }
+    }
+    if let Some(e) = error {
+        eprintln!("Error: {}: {} after {} exits", path, e, total);
+        process::exit(1);
+    }
+}
 
-    let pflash_path = root.join("target").join(format!("pflash-{arch}.img"));
+/// Erased (all ones) flash contents of `size` bytes with magic "pfld" at
+/// offset 0 (consistent with h_2_0 format).
+fn pflash_contents(size: usize) -> Vec<u8> {
     let mut image = vec![0xFFu8; size];
-
-    // Write magic "pfld" at offset 0 (consistent with h_2_0 format)
     image[0..4].copy_from_slice(b"pfld");
+    image
+}
 
-    std::fs::write(&pflash_path, &image).unwrap_or_else(|e| {
+/// Create a pflash image of `size` bytes with magic "pfld" at offset 0 (for
+/// NPF passthrough test).
+fn create_pflash_image(root: &Path, arch: &str, size: usize) -> PathBuf {
+    let pflash_path = root.join("target").join(format!("pflash-{arch}.img"));
+    std::fs::write(&pflash_path, pflash_contents(size)).unwrap_or_else(|e| {
         eprintln!("Error: failed to write pflash image: {}", e);
         process::exit(1);
     });
@@ -289,19 +1285,34 @@ fn create_pflash_image(root: &Path, arch: &str) -> PathBuf {
     pflash_path
 }
 
-/// Build the hypervisor kernel.
-fn do_build(root: &Path, info: &ArchInfo) {
+/// Build the hypervisor kernel. Returns its ELF.
+fn do_build(root: &Path, info: &ArchInfo, build: &BuildOpts) -> PathBuf {
     let manifest = root.join("Cargo.toml");
     let axconfig_path = root.join(".axconfig.toml");
-    let status = Command::new("cargo")
-        .env("AX_CONFIG_PATH", axconfig_path.to_str().unwrap())
+    let mut features = vec!["hypervisor"];
+    if build.qemu_exit {
+        features.push("qemu-exit");
+    }
+    if build.smp {
+        features.push("smp");
+    }
+    if build.measure {
+        features.push("measure");
+    }
+    let mut cmd = Command::new("cargo");
+    cmd.env("AX_CONFIG_PATH", axconfig_path.to_str().unwrap());
+    // Read by axruntime at compile time.
+    if let Some(level) = &build.log {
+        cmd.env("AX_LOG", level);
+    }
+    let status = cmd
+        .arg("build")
+        .args(build.cargo_args())
         .args([
-            "build",
-            "--release",
             "--target",
             info.target,
             "--features",
-            "hypervisor",
+            &features.join(","),
             "--manifest-path",
             manifest.to_str().unwrap(),
         ])
@@ -311,6 +1322,10 @@ fn do_build(root: &Path, info: &ArchInfo) {
         eprintln!("Error: cargo build failed");
         process::exit(status.code().unwrap_or(1));
     }
+    root.join("target")
+        .join(info.target)
+        .join(build.out_dir())
+        .join("arceos-guestaspace")
 }
 
 /// Convert ELF to raw binary.
@@ -332,30 +1347,61 @@ fn do_objcopy(elf: &Path, bin: &Path, objcopy_arch: &str) {
     }
 }
 
-/// Run QEMU with VirtIO block device.
-fn do_run_qemu(arch: &str, elf: &Path, bin: &Path, disk: &Path, pflash: Option<&Path>) {
-    let mem = "128M";
-    let smp = "1";
+/// Build the QEMU command line with VirtIO block device.
+///
+/// With `gdb_stub`, QEMU waits for a debugger on TCP port 1234 (`-s -S`)
+/// before running the first instruction.
+fn qemu_command(
+    arch: &str,
+    machine: &MachineOpts,
+    elf: &Path,
+    bin: &Path,
+    disk: &Path,
+    pflash: Option<&Path>,
+    gdb_stub: bool,
+) -> Command {
     let qemu = format!("qemu-system-{arch}");
 
     let mut args: Vec<String> = vec![
         "-m".into(),
-        mem.into(),
+        format!("{}K", machine.mem / 1024),
         "-smp".into(),
-        smp.into(),
+        machine.smp.to_string(),
         "-nographic".into(),
     ];
+    if let Some(accel) = &machine.accel {
+        args.extend(["-accel".into(), accel.clone()]);
+    }
+    // The first serial port stays on the terminal (what -nographic gives
+    // without any -serial).
+    if let Some(chardev) = &machine.guest_serial {
+        args.extend([
+            "-serial".into(),
+            "mon:stdio".into(),
+            "-serial".into(),
+            chardev.clone(),
+        ]);
+    }
+    // Architecture default CPU model, unless --cpu overrides it.
+    let cpu = |default: &str| machine.cpu.clone().unwrap_or_else(|| default.into());
 
     match arch {
         "riscv64" => {
+            let virt = match machine.aia_guests {
+                Some(n) => format!("virt,aia=aplic-imsic,aia-guests={n}"),
+                None => "virt".into(),
+            };
             args.extend([
                 "-machine".into(),
-                "virt".into(),
+                virt,
                 "-bios".into(),
                 "default".into(),
                 "-kernel".into(),
                 bin.to_str().unwrap().into(),
             ]);
+            if let Some(cpu) = &machine.cpu {
+                args.extend(["-cpu".into(), cpu.clone()]);
+            }
             // Attach pflash1 for pflash NPF test
             if let Some(pf) = pflash {
                 args.extend([
@@ -370,7 +1416,7 @@ fn do_run_qemu(arch: &str, elf: &Path, bin: &Path, disk: &Path, pflash: Option<&
         "aarch64" => {
             args.extend([
                 "-cpu".into(),
-                "max".into(),
+                cpu("max"),
                 "-machine".into(),
                 "virt,virtualization=on".into(),
                 "-kernel".into(),
@@ -392,7 +1438,7 @@ fn do_run_qemu(arch: &str, elf: &Path, bin: &Path, disk: &Path, pflash: Option<&
                 "-machine".into(),
                 "q35".into(),
                 "-cpu".into(),
-                "EPYC".into(),
+                cpu("EPYC"),
                 "-kernel".into(),
                 elf.to_str().unwrap().into(),
             ]);
@@ -408,67 +1454,429 @@ fn do_run_qemu(arch: &str, elf: &Path, bin: &Path, disk: &Path, pflash: Option<&
         "virtio-blk-pci,drive=disk0".into(),
     ]);
 
-    println!("Running: {} {}", qemu, args.join(" "));
-    let status = Command::new(&qemu)
-        .args(&args)
-        .status()
+    if gdb_stub {
+        args.extend(["-s".into(), "-S".into()]);
+    }
+    args.extend(machine.qemu_args.iter().flatten().cloned());
+
+    let mut cmd = Command::new(&qemu);
+    cmd.args(&args);
+    cmd
+}
+
+/// The command line of `cmd`, quoted for a POSIX shell where needed.
+fn shell_line(cmd: &Command) -> String {
+    std::iter::once(cmd.get_program())
+        .chain(cmd.get_args())
+        .map(|arg| {
+            let arg = arg.to_string_lossy();
+            let plain = !arg.is_empty()
+                && arg
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || "-_./,=:+@%".contains(c));
+            if plain {
+                arg.into_owned()
+            } else {
+                format!("'{}'", arg.replace('\'', "'\\''"))
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Run QEMU in the foreground and exit with its status if it fails.
+fn do_run_qemu(mut qemu: Command) {
+    println!("Running: {}", shell_line(&qemu));
+    let status = qemu.status().unwrap_or_else(|e| {
+        eprintln!("Error: failed to run {:?}: {}", qemu.get_program(), e);
+        process::exit(1);
+    });
+    if !status.success() {
+        process::exit(status.code().unwrap_or(1));
+    }
+}
+
+/// Architectures the demo supports.
+const ARCHES: [&str; 3] = ["riscv64", "aarch64", "x86_64"];
+
+/// Lines every successful run prints: per guest, its pflash check and
+/// shutdown, then the hypervisor's own exit message.
+fn expected_markers() -> Vec<String> {
+    let mut markers = Vec::new();
+    for vm in 0..DEFAULT_PAYLOADS.len() {
+        markers.push(format!("[vm{vm}] Got pflash magic: pfld"));
+        markers.push(format!("[vm{vm}] Shutdown vm normally!"));
+    }
+    markers.push("Hypervisor ok!".into());
+    markers
+}
+
+/// QEMU options for the device through which a hypervisor built with the
+/// `qemu-exit` feature ends QEMU with a failing status. riscv64 uses the
+/// virt machine's built-in `sifive_test` device.
+fn qemu_exit_args(arch: &str) -> Vec<String> {
+    match arch {
+        "aarch64" => vec!["-semihosting".into()],
+        "x86_64" => vec![
+            "-device".into(),
+            "isa-debug-exit,iobase=0xf4,iosize=0x04".into(),
+        ],
+        _ => Vec::new(),
+    }
+}
+
+/// The hypervisor's exit status (the first nonzero guest exit code) from
+/// QEMU's: `isa-debug-exit` on x86_64 exits with `status << 1 | 1`.
+fn hypervisor_status(arch: &str, qemu_status: i32) -> i32 {
+    match arch {
+        "x86_64" if qemu_status & 1 != 0 => qemu_status >> 1,
+        _ => qemu_status,
+    }
+}
+
+/// Run QEMU, echoing its serial output, until it exits or `timeout` has
+/// elapsed. Returns the expected markers that never appeared and QEMU's
+/// exit status (None if it was killed at the timeout).
+fn run_qemu_checked(mut qemu: Command, timeout: Duration) -> (Vec<String>, Option<i32>) {
+    let mut missing = expected_markers();
+    println!("Running: {}", shell_line(&qemu));
+    let mut child = qemu
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .spawn()
         .unwrap_or_else(|e| {
-            eprintln!("Error: failed to run {}: {}", qemu, e);
+            eprintln!("Error: failed to start QEMU: {}", e);
             process::exit(1);
         });
-    if !status.success() {
-        process::exit(status.code().unwrap_or(1));
+
+    // The serial console may carry arbitrary bytes: read raw lines.
+    let (tx, rx) = mpsc::channel();
+    let stdout = child.stdout.take().unwrap();
+    std::thread::spawn(move || {
+        let mut reader = BufReader::new(stdout);
+        let mut line = Vec::new();
+        while matches!(reader.read_until(b'\n', &mut line), Ok(n) if n > 0) {
+            if tx
+                .send(String::from_utf8_lossy(&line).into_owned())
+                .is_err()
+            {
+                break;
+            }
+            line.clear();
+        }
+    });
+
+    // The hypervisor powers the machine off once all guests are done.
+    let deadline = Instant::now() + timeout;
+    let timed_out = loop {
+        let left = deadline.saturating_duration_since(Instant::now());
+        match rx.recv_timeout(left) {
+            Ok(line) => {
+                print!("{line}");
+                missing.retain(|marker| !line.contains(marker.as_str()));
+            }
+            Err(mpsc::RecvTimeoutError::Timeout) => break true,
+            // QEMU exited and its output is drained.
+            Err(mpsc::RecvTimeoutError::Disconnected) => break false,
+        }
+    };
+    if timed_out {
+        let _ = child.kill();
+    }
+    let status = child.wait().ok().and_then(|status| status.code());
+    (missing, if timed_out { None } else { status })
+}
+
+/// Read an integer entry (`key = 0x1234_5678 # uint`) from an axconfig file.
+fn axconfig_uint(config: &Path, key: &str) -> Option<u64> {
+    let text = std::fs::read_to_string(config).ok()?;
+    text.lines()
+        .filter(|line| line.split('=').next().map(str::trim) == Some(key))
+        .find_map(axconfig_value)
+}
+
+/// Parse the unsigned integer value of a `key = value # uint` config line.
+fn axconfig_value(line: &str) -> Option<u64> {
+    let (_, v) = line.split_once('=')?;
+    let v = v
+        .split('#')
+        .next()?
+        .trim()
+        .trim_matches('"')
+        .replace('_', "");
+    match v.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(hex, 16).ok(),
+        None => v.parse().ok(),
     }
 }
 
+/// Write `.gdbinit` in the project root: connect to QEMU's GDB stub and load
+/// the symbols of the hypervisor and of the guest payload.
+///
+/// The payload is linked for `kernel-base-paddr` (and the matching virtual
+/// address) of its config, but the hypervisor loads it at the guest physical
+/// address `guest_load_addr`, so its symbols are shifted by the difference.
+fn write_gdbinit(root: &Path, info: &ArchInfo, elf: &Path) -> PathBuf {
+    // Built with the same profile as the hypervisor.
+    let payload_elf = elf.with_file_name("gkernel");
+    let payload_config = root.join("payload").join("gkernel").join(".axconfig.toml");
+    let link_base = axconfig_uint(&payload_config, "kernel-base-paddr").unwrap_or_else(|| {
+        eprintln!(
+            "Error: kernel-base-paddr not found in {}",
+            payload_config.display()
+        );
+        process::exit(1);
+    });
+    let offset = info.guest_load_addr as i64 - link_base as i64;
+    let offset = if offset < 0 {
+        format!("-{:#x}", -offset)
+    } else {
+        format!("{:#x}", offset)
+    };
+
+    let gdbinit = root.join(".gdbinit");
+    let script = format!(
+        "set architecture {}\n\
+         file {}\n\
+         add-symbol-file {} -o {}\n\
+         target remote localhost:1234\n",
+        info.gdb_arch,
+        elf.display(),
+        payload_elf.display(),
+        offset
+    );
+    std::fs::write(&gdbinit, script).unwrap_or_else(|e| {
+        eprintln!("Error: failed to write {}: {}", gdbinit.display(), e);
+        process::exit(1);
+    });
+    println!(
+        "Wrote {} (guest symbols at offset {})",
+        gdbinit.display(),
+        offset
+    );
+    gdbinit
+}
+
+/// Build the payload, the disk and pflash images and the hypervisor for
+/// `arch`. Returns the hypervisor ELF and raw binary, the disk image and the
+/// pflash image, if the architecture uses one. `cmdline` is the kernel
+/// command line of the payload guests.
+fn prepare_run(
+    root: &Path,
+    arch: &str,
+    machine: &MachineOpts,
+    payloads: &[Payload],
+    boot: Option<&BootSpec>,
+    cmdline: Option<&str>,
+    build: &BuildOpts,
+) -> (PathBuf, PathBuf, PathBuf, Option<PathBuf>) {
+    let info = arch_info(arch);
+    install_config(root, arch, Some(machine));
+
+    // 1-2. Build the payloads (gkernel/readpflash) and create the disk image
+    // with them, or with the boot specification of a --kernel guest
+    let disk = root.join("target").join(format!("disk-{arch}.img"));
+    match boot {
+        Some(spec) => create_boot_disk_image(&disk, spec),
+        None => {
+            install_payload_config(root, arch);
+            let images = build_guest_images(root, &info, arch, payloads, build);
+            create_fat_disk_image(&disk, &images, cmdline);
+        }
+    }
+
+    // 3. Create pflash image (for riscv64/aarch64 NPF passthrough test)
+    let pflash = info
+        .host_pflash_size
+        .map(|size| create_pflash_image(root, arch, size));
+
+    // 4. Build hypervisor kernel, booting the other CPUs of the machine
+    let build = BuildOpts {
+        smp: machine.smp > 1,
+        ..build.clone()
+    };
+    let elf = do_build(root, &info, &build);
+    let bin = elf.with_extension("bin");
+
+    if arch != "x86_64" {
+        do_objcopy(&elf, &bin, info.objcopy_arch);
+    }
+    (elf, bin, disk, pflash)
+}
+
 fn main() {
     let cli = Cli::parse();
     let root = project_root();
 
     match cli.command {
-        Cmd::Build { ref arch } => {
+        Cmd::Disk { ref cmd } => do_disk(cmd),
+        Cmd::Exits {
+            ref image,
+            ref path,
+            all,
+        } => do_exits(image, path, all),
+        Cmd::Package {
+            ref elf,
+            ref arch,
+            ref output,
+            ram,
+            flat,
+        } => {
             let info = arch_info(arch);
-            install_config(&root, arch);
-            install_payload_config(&root, arch);
-            let _payload = build_payload(&root, &info, arch);
-            do_build(&root, &info);
-            println!("Build complete for {arch} ({})", info.target);
+            let ext = if flat { "bin" } else { "gapk" };
+            let out = output.clone().unwrap_or_else(|| elf.with_extension(ext));
+            if flat {
+                do_flat_binary(elf, &out, info.guest_load_addr);
+            } else {
+                do_package(elf, &out, info.guest_load_addr, ram);
+            }
         }
-        Cmd::Run { ref arch } => {
+        Cmd::Build {
+            ref arch,
+            ref payloads,
+            ref build,
+        } => {
             let info = arch_info(arch);
-            install_config(&root, arch);
-
-            // 1. Install payload config and build payload (gkernel/readpflash)
-            install_payload_config(&root, arch);
-            let payload_bin = build_payload(&root, &info, arch);
-
-            // 2. Create disk image with payload
-            let disk = root.join("target").join(format!("disk-{arch}.img"));
-            create_fat_disk_image(&disk, &payload_bin);
+            install_config(&root, arch, None);
+            if payloads.kernel.is_none() {
+                install_payload_config(&root, arch);
+                let _images = build_guest_images(&root, &info, arch, &payloads.payloads(), build);
+            }
+            let elf = do_build(&root, &info, build);
+            println!(
+                "Build complete for {arch} ({}): {}",
+                info.target,
+                elf.display()
+            );
+        }
+        Cmd::Run {
+            ref arch,
+            ref machine,
+            ref payloads,
+            ref build,
+            dry_run,
+        } => {
+            let (elf, bin, disk, pflash) = prepare_run(
+                &root,
+                arch,
+                machine,
+                &payloads.payloads(),
+                payloads.boot_spec().as_ref(),
+                payloads.append.as_deref(),
+                build,
+            );
 
-            // 3. Create pflash image (for riscv64/aarch64 NPF passthrough test)
-            let pflash = if arch == "riscv64" || arch == "aarch64" {
-                Some(create_pflash_image(&root, arch))
+            // 5. Run QEMU
+            let qemu = qemu_command(arch, machine, &elf, &bin, &disk, pflash.as_deref(), false);
+            if dry_run {
+                println!("{}", shell_line(&qemu));
+            } else {
+                do_run_qemu(qemu);
+            }
+        }
+        Cmd::Test {
+            ref arch,
+            all,
+            timeout,
+            ref machine,
+            ref build,
+        } => {
+            let arches: Vec<&str> = if all {
+                ARCHES.to_vec()
             } else {
-                None
+                arch.iter().map(String::as_str).collect()
             };
+            // Guest exit codes become QEMU's exit status.
+            let build = BuildOpts {
+                qemu_exit: true,
+                ..build.clone()
+            };
+            let mut results = Vec::new();
+            for arch in arches {
+                let mut machine = machine.clone();
+                machine.qemu_args.insert(0, qemu_exit_args(arch));
+                let (elf, bin, disk, pflash) = prepare_run(
+                    &root,
+                    arch,
+                    &machine,
+                    &default_payloads(),
+                    None,
+                    None,
+                    &build,
+                );
+                let qemu =
+                    qemu_command(arch, &machine, &elf, &bin, &disk, pflash.as_deref(), false);
+                let (missing, status) = run_qemu_checked(qemu, Duration::from_secs(timeout));
+                results.push((arch, missing, status));
+            }
 
-            // 4. Build hypervisor kernel
-            do_build(&root, &info);
-
-            let elf = root
-                .join("target")
-                .join(info.target)
-                .join("release")
-                .join("arceos-guestaspace");
-            let bin = elf.with_extension("bin");
-
-            if arch != "x86_64" {
-                do_objcopy(&elf, &bin, info.objcopy_arch);
+            println!();
+            let mut failed = false;
+            for (arch, missing, status) in &results {
+                if missing.is_empty() && *status == Some(0) {
+                    println!("test {arch}: ok");
+                    continue;
+                }
+                failed = true;
+                match status {
+                    None => println!("test {arch}: FAILED, timed out"),
+                    Some(0) => println!("test {arch}: FAILED"),
+                    Some(status) => println!(
+                        "test {arch}: FAILED, exit status {}",
+                        hypervisor_status(arch, *status)
+                    ),
+                }
+                if !missing.is_empty() {
+                    println!("  missing output:");
+                    for marker in missing {
+                        println!("    {marker}");
+                    }
+                }
+            }
+            if failed {
+                process::exit(1);
+            }
+        }
+        Cmd::Gdb {
+            ref arch,
+            launch,
+            ref gdb,
+            ref machine,
+            ref build,
+        } => {
+            let (elf, bin, disk, pflash) =
+                prepare_run(&root, arch, machine, &default_payloads(), None, None, build);
+            let gdbinit = write_gdbinit(&root, &arch_info(arch), &elf);
+            let mut qemu = qemu_command(arch, machine, &elf, &bin, &disk, pflash.as_deref(), true);
+            if !launch {
+                println!(
+                    "QEMU waits for a debugger; attach with: {} -x {}",
+                    gdb,
+                    gdbinit.display()
+                );
+                do_run_qemu(qemu);
+                return;
             }
 
-            // 5. Run QEMU
-            do_run_qemu(arch, &elf, &bin, &disk, pflash.as_deref());
+            println!("Running: {}", shell_line(&qemu));
+            let mut qemu = qemu.spawn().unwrap_or_else(|e| {
+                eprintln!("Error: failed to start QEMU: {}", e);
+                process::exit(1);
+            });
+            let status = Command::new(gdb)
+                .arg("-x")
+                .arg(&gdbinit)
+                .status()
+                .unwrap_or_else(|e| {
+                    eprintln!("Error: failed to run {}: {}", gdb, e);
+                    let _ = qemu.kill();
+                    process::exit(1);
+                });
+            let _ = qemu.kill();
+            let _ = qemu.wait();
+            if !status.success() {
+                process::exit(status.code().unwrap_or(1));
+            }
         }
     }
 }